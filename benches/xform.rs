@@ -0,0 +1,170 @@
+//! Benchmarks of geometry compilation, of the read parsing loop over a set
+//! of representative geometries, and of whole transformations of input
+//! files.  Run with `cargo bench`.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use seq_geom_parser::FragmentGeomDesc;
+use seq_geom_xform::output::OutputFormat;
+use seq_geom_xform::sim::{self, SimConfig};
+use seq_geom_xform::{
+    xform_read_pairs_to_file_with_options, FragmentGeomDescExt, RegexOptions, SeqPair, XformOptions,
+};
+
+/// The number of simulated read pairs parsed per iteration.
+const NUM_PAIRS: usize = 10_000;
+/// The number of simulated read pairs of the input files transformed per
+/// iteration.
+const NUM_FILE_PAIRS: usize = 200_000;
+
+/// Representative geometries, from the simplest to the most complex.
+const GEOMETRIES: &[(&str, &str)] = &[
+    ("fixed", "1{b[16]u[12]x:}2{r:}"),
+    ("anchored", "1{b[10]f[CAGAGC]u[8]b[10]}2{r:}"),
+    ("variable_len", "1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}"),
+    (
+        "unbounded",
+        "1{b[8-10]f[GTACTCTGCGTTGATACCACTGCTT]b[8]u[6]x:}2{r:}",
+    ),
+];
+
+fn simulated_reads(geo: &FragmentGeomDesc) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut rng = StdRng::seed_from_u64(1);
+    let cfg = SimConfig {
+        unbounded_len: 90,
+        ..SimConfig::default()
+    };
+    sim::generate_pairs_with_config(geo, NUM_PAIRS, &mut rng, &cfg)
+        .into_iter()
+        .map(|(r1, r2)| (r1.seq, r2.seq))
+        .collect()
+}
+
+fn bench_as_regex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("as_regex");
+    for (name, gd) in GEOMETRIES {
+        let geo = FragmentGeomDesc::try_from(*gd).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(name), &geo, |b, geo| {
+            b.iter(|| geo.as_regex().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_parse_into(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_into");
+    group.throughput(Throughput::Elements(NUM_PAIRS as u64));
+    for (name, gd) in GEOMETRIES {
+        let geo = FragmentGeomDesc::try_from(*gd).unwrap();
+        let mut geo_re = geo.as_regex().unwrap();
+        let reads = simulated_reads(&geo);
+        let mut sp = SeqPair::new();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let mut parsed = 0;
+                for (r1, r2) in &reads {
+                    parsed += geo_re.parse_into(r1, r2, &mut sp) as usize;
+                }
+                assert_eq!(parsed, NUM_PAIRS);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Compares parsing the reads of the geometry whose pieces all lie at fixed
+/// offsets by slicing them (the default) and with the regex engine.
+fn bench_fixed_offsets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fixed_offsets");
+    group.throughput(Throughput::Elements(NUM_PAIRS as u64));
+    let geo = FragmentGeomDesc::try_from(GEOMETRIES[0].1).unwrap();
+    let reads = simulated_reads(&geo);
+    let mut sp = SeqPair::new();
+    for (name, fixed_offsets) in [("sliced", true), ("regex", false)] {
+        let mut geo_re = geo
+            .as_regex_with_options(&RegexOptions {
+                fixed_offsets,
+                ..RegexOptions::default()
+            })
+            .unwrap();
+        assert_eq!(geo_re.bypasses_regex(), [fixed_offsets; 2]);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let mut parsed = 0;
+                for (r1, r2) in &reads {
+                    parsed += geo_re.parse_into(r1, r2, &mut sp) as usize;
+                }
+                assert_eq!(parsed, NUM_PAIRS);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Measures the throughput of transforming large `FASTQ` input files into
+/// `FASTA` and `FASTQ` outputs, from reading the records to writing the
+/// transformed ones.
+fn bench_xform_files(c: &mut Criterion) {
+    let mut group = c.benchmark_group("xform_files");
+    group.sample_size(10);
+    let geo = FragmentGeomDesc::try_from(GEOMETRIES[2].1).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let p = |name: &str| dir.path().join(name);
+    let mut rng = StdRng::seed_from_u64(1);
+    let cfg = SimConfig {
+        unbounded_len: 90,
+        ..SimConfig::default()
+    };
+    let mut w1 = BufWriter::new(File::create(p("r1.fq")).unwrap());
+    let mut w2 = BufWriter::new(File::create(p("r2.fq")).unwrap());
+    for (r1, r2) in sim::pairs_iter(&geo, &mut rng, &cfg).take(NUM_FILE_PAIRS) {
+        r1.write_fastq(&mut w1).unwrap();
+        r2.write_fastq(&mut w2).unwrap();
+    }
+    w1.flush().unwrap();
+    w2.flush().unwrap();
+    drop((w1, w2));
+    let input_bytes = [p("r1.fq"), p("r2.fq")]
+        .iter()
+        .map(|f| std::fs::metadata(f).unwrap().len())
+        .sum();
+    group.throughput(Throughput::Bytes(input_bytes));
+    let geo_re = geo.as_regex().unwrap();
+    for (name, format) in [
+        ("fasta", OutputFormat::Fasta),
+        ("fastq", OutputFormat::Fastq),
+    ] {
+        let opts = XformOptions {
+            format,
+            ..XformOptions::default()
+        };
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let stats = xform_read_pairs_to_file_with_options(
+                    geo_re.clone(),
+                    &[p("r1.fq")],
+                    &[p("r2.fq")],
+                    Some(p("o1.txt")),
+                    Some(p("o2.txt")),
+                    &opts,
+                )
+                .unwrap();
+                assert_eq!(stats.total_fragments, NUM_FILE_PAIRS as u64);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_as_regex,
+    bench_parse_into,
+    bench_fixed_offsets,
+    bench_xform_files
+);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+//! Feeds arbitrary geometry strings through `as_regex` and the
+//! simplification routines; none of these should ever panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use seq_geom_parser::FragmentGeomDesc;
+use seq_geom_xform::FragmentGeomDescExt;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(geo) = FragmentGeomDesc::try_from(s) else {
+        return;
+    };
+    if let Ok(geo_re) = geo.as_regex() {
+        let _ = geo_re.get_simplified_description_string();
+        let _ = geo_re.get_simplified_geo_desc();
+    }
+});
@@ -0,0 +1,87 @@
+//! Feeds arbitrary byte reads through `parse_into` for a set of
+//! geometries covering fixed, anchored, variable-length and unbounded
+//! pieces.  Parsing must never panic, and every succesful parse must
+//! produce nucleotide-only output whose length agrees with the
+//! simplified geometry.
+#![no_main]
+
+use std::cell::RefCell;
+
+use libfuzzer_sys::fuzz_target;
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece};
+use seq_geom_xform::{FragmentGeomDescExt, FragmentRegexDesc, SeqPair};
+
+const GEOMETRIES: &[&str] = &[
+    "1{b[16]u[12]}2{r:}",
+    "1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}",
+    "1{b[7-10]u[4-6]x:}2{r[50]}",
+    "1{x[1-4]b[8]f[GAGTGATTGCTTGTGACGCCTT]b[8]u[6]}2{r:}",
+    "1{r:}2{b[5-8]f[ACG]u[1-4]r:}",
+];
+
+/// The length of the transformed read, or `None` if it is not fixed.
+fn expected_len(pieces: &[GeomPiece]) -> Option<usize> {
+    let mut len = 0;
+    for p in pieces {
+        match p {
+            GeomPiece::Barcode(GeomLen::FixedLen(x))
+            | GeomPiece::Umi(GeomLen::FixedLen(x))
+            | GeomPiece::ReadSeq(GeomLen::FixedLen(x)) => len += *x as usize,
+            GeomPiece::Barcode(GeomLen::Unbounded)
+            | GeomPiece::Umi(GeomLen::Unbounded)
+            | GeomPiece::ReadSeq(GeomLen::Unbounded) => return None,
+            _ => {}
+        }
+    }
+    Some(len)
+}
+
+thread_local! {
+    /// The compiled geometries (and their simplified forms), built once
+    /// since compiling the regexes dominates the cost of a single run.
+    static COMPILED: RefCell<Vec<(FragmentRegexDesc, FragmentGeomDesc)>> = RefCell::new(
+        GEOMETRIES
+            .iter()
+            .map(|g| {
+                let geo = FragmentGeomDesc::try_from(*g).expect("valid geometry");
+                let geo_re = geo.as_regex().expect("convertible geometry");
+                let simplified = geo_re.get_simplified_geo_desc();
+                (geo_re, simplified)
+            })
+            .collect()
+    );
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    COMPILED.with(|c| {
+        check_parse(
+            &mut c.borrow_mut()[data[0] as usize % GEOMETRIES.len()],
+            data,
+        )
+    });
+});
+
+fn check_parse((geo_re, simplified): &mut (FragmentRegexDesc, FragmentGeomDesc), data: &[u8]) {
+    let rest = &data[2..];
+    let split = (data[1] as usize).min(rest.len());
+    let (r1, r2) = rest.split_at(split);
+
+    let mut sp = SeqPair::new();
+    // parse the pair in both orders to exercise reuse of the capture locations
+    for (a, b) in [(r1, r2), (r2, r1)] {
+        if geo_re.parse_into(a, b, &mut sp) {
+            for (s, pieces) in [
+                (&sp.s1, &simplified.read1_desc),
+                (&sp.s2, &simplified.read2_desc),
+            ] {
+                assert!(s.iter().all(|c| b"ACGTN".contains(c)));
+                if let Some(l) = expected_len(pieces) {
+                    assert_eq!(s.len(), l);
+                }
+            }
+        }
+    }
+}
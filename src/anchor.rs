@@ -0,0 +1,167 @@
+//! Searching for the fixed pieces of a geometry at variable offsets.
+//!
+//! The regex built for a geometry matches each fixed (`f`) piece at the
+//! position implied by the pieces before it.  In some chemistries, an anchor
+//! is preceded by a linker of unknown length, so that a geometry with exact
+//! positions rejects many reads.  An [`AnchorSearch`] rewrites a geometry so
+//! that each (selected) anchor may be found up to
+//! [`AnchorSearch::max_offset`] bases after its expected position: a
+//! discarded piece of variable length is inserted before it (or, if the
+//! anchor is already preceded by a discarded piece, the length range of that
+//! piece is widened).  The captured pieces, and so the simplified geometry,
+//! are unchanged.  As with any variable length piece, the regex prefers the
+//! longest pieces, so the last occurrence of the anchor within the window is
+//! used.
+
+use anyhow::{bail, Result};
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece, NucStr};
+
+/// Which anchors of a geometry to search for, and how far.
+#[derive(Debug, Clone)]
+pub struct AnchorSearch {
+    /// The maximum number of bases by which an anchor may be shifted from
+    /// its expected position.
+    pub max_offset: u32,
+    /// The sequences of the anchors to search for, or `None` to search for
+    /// all of the anchors of the geometry.
+    pub anchors: Option<Vec<String>>,
+}
+
+impl AnchorSearch {
+    /// Returns `geo` rewritten so that the selected anchors may be found up
+    /// to [`AnchorSearch::max_offset`] bases after their expected position.
+    /// Fails if no anchor is selected, or if one of the given anchor
+    /// sequences is not in the geometry.
+    pub fn apply(&self, geo: &FragmentGeomDesc) -> Result<FragmentGeomDesc> {
+        if self.max_offset == 0 {
+            bail!("searching for the anchors requires a maximum offset of at least 1");
+        }
+        let selected = |s: &str| {
+            self.anchors
+                .as_ref()
+                .is_none_or(|a| a.iter().any(|a| a.eq_ignore_ascii_case(s)))
+        };
+        if let Some(anchors) = &self.anchors {
+            for a in anchors {
+                let found = geo.read1_desc.iter().chain(&geo.read2_desc).any(
+                    |gp| matches!(gp, GeomPiece::Fixed(NucStr::Seq(s)) if s.eq_ignore_ascii_case(a)),
+                );
+                if !found {
+                    bail!("the geometry has no fixed (f) piece {}", a);
+                }
+            }
+        }
+
+        let mut searched = 0;
+        let mut widen = |pieces: &[GeomPiece]| {
+            let mut out: Vec<GeomPiece> = Vec::with_capacity(pieces.len() + 1);
+            for gp in pieces {
+                if let GeomPiece::Fixed(NucStr::Seq(s)) = gp {
+                    if selected(s) {
+                        searched += 1;
+                        let m = self.max_offset;
+                        match out.last_mut() {
+                            Some(GeomPiece::Discard(gl)) => {
+                                *gl = match *gl {
+                                    GeomLen::FixedLen(x) => GeomLen::LenRange(x, x + m),
+                                    GeomLen::LenRange(l, h) => GeomLen::LenRange(l, h + m),
+                                    GeomLen::Unbounded => GeomLen::Unbounded,
+                                }
+                            }
+                            _ => out.push(GeomPiece::Discard(GeomLen::LenRange(0, m))),
+                        }
+                    }
+                }
+                out.push(gp.clone());
+            }
+            out
+        };
+        let searched_geo = FragmentGeomDesc {
+            read1_desc: widen(&geo.read1_desc),
+            read2_desc: widen(&geo.read2_desc),
+        };
+        if searched == 0 {
+            bail!("searching for the anchors requires the geometry to have a fixed (f) piece");
+        }
+        Ok(searched_geo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FragmentGeomDescExt, SeqPair};
+
+    /// Searches for `anchors` (all of them if `None`) up to `max_offset`
+    /// bases after their position.
+    fn search(max_offset: u32, anchors: Option<&[&str]>) -> AnchorSearch {
+        AnchorSearch {
+            max_offset,
+            anchors: anchors.map(|a| a.iter().map(|s| String::from(*s)).collect()),
+        }
+    }
+
+    #[test]
+    fn linkers_are_inserted_before_the_anchors() {
+        let geo = FragmentGeomDesc::try_from("1{b[4]f[ACGT]u[2]x[1]f[GG]x:}2{r:}").unwrap();
+        let searched = search(3, Some(&["acgt"])).apply(&geo).unwrap();
+        assert!(matches!(
+            searched.read1_desc[1],
+            GeomPiece::Discard(GeomLen::LenRange(0, 3))
+        ));
+        assert_eq!(searched.read1_desc.len(), geo.read1_desc.len() + 1);
+        // a discarded piece before an anchor is widened
+        assert!(matches!(
+            search(3, None).apply(&geo).unwrap().read1_desc[4],
+            GeomPiece::Discard(GeomLen::LenRange(1, 4))
+        ));
+        // the captured pieces are unchanged
+        assert_eq!(
+            searched
+                .as_regex()
+                .unwrap()
+                .get_simplified_description_string(),
+            geo.as_regex().unwrap().get_simplified_description_string()
+        );
+
+        // anchors of read 2, after a variable length discarded piece
+        let geo = FragmentGeomDesc::try_from("1{b[4]}2{x[1-2]f[TT]r:}").unwrap();
+        let searched = search(2, None).apply(&geo).unwrap();
+        assert!(matches!(
+            searched.read2_desc[0],
+            GeomPiece::Discard(GeomLen::LenRange(1, 4))
+        ));
+    }
+
+    #[test]
+    fn anchors_must_be_selected() {
+        let geo = FragmentGeomDesc::try_from("1{b[4]f[ACGT]u[2]}2{r:}").unwrap();
+        assert!(search(0, None).apply(&geo).is_err());
+        assert!(search(3, Some(&["TTTT"])).apply(&geo).is_err());
+        assert!(search(3, Some(&[])).apply(&geo).is_err());
+        let no_anchor = FragmentGeomDesc::try_from("1{b[4]u[2]}2{r:}").unwrap();
+        assert!(search(3, None).apply(&no_anchor).is_err());
+    }
+
+    #[test]
+    fn anchors_are_found_after_linkers() {
+        let geo = FragmentGeomDesc::try_from("1{b[4]f[ACGT]u[2]x[1]f[GG]x:}2{r:}").unwrap();
+        let mut geo_re = search(3, Some(&["ACGT"]))
+            .apply(&geo)
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut sp = SeqPair::new();
+        // linkers of 0, 2 and 3 bases before the anchor
+        for (r1, transformed) in [
+            (&b"AACCACGTTTAGG"[..], &b"AACCTT"[..]),
+            (b"GGTTCCACGTAAAGG", b"GGTTAA"),
+            (b"GGTTCCCACGTAAAGG", b"GGTTAA"),
+        ] {
+            assert!(geo_re.parse_into(r1, b"TTTT", &mut sp));
+            assert_eq!(sp.s1, transformed);
+        }
+        // but a linker of 5 bases is too long
+        assert!(!geo_re.parse_into(b"ACGTCCCCCACGTGGAGG", b"TTTT", &mut sp));
+    }
+}
@@ -0,0 +1,137 @@
+//! Transforming reads from asynchronous code.
+//!
+//! With the `async` feature, [`xform_read_pairs_to_async_writer`] writes the
+//! transformed fragments into a [`tokio::io::AsyncWrite`], so that the
+//! transformation can be embedded in an async service (e.g. one normalizing
+//! uploaded `FASTQ` files into the response of the upload) without blocking
+//! the threads of its runtime.  The input files are read and their fragments
+//! transformed by an [`XformReader`] on the blocking thread pool of the
+//! runtime, which hands the transformed records over in chunks to be written
+//! asynchronously.  At most [`MAX_PENDING_CHUNKS`] chunks wait to be written,
+//! so that a slow writer holds back the transformation rather than letting
+//! the records pile up in memory.
+
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::stream::XformReader;
+use crate::{FragmentRegexDesc, XformStats};
+
+/// The size of the chunks of transformed records handed over to the writer.
+pub const CHUNK_SIZE: usize = 1 << 16;
+/// The number of chunks of transformed records that may be waiting to be
+/// written.
+pub const MAX_PENDING_CHUNKS: usize = 4;
+
+/// Transforms the fragments of the pairs of input files (`r1[i]`, `r2[i]`)
+/// with the geometries `geo_res` (see [`XformReader::new`]), and writes them
+/// as interleaved `FASTA` records (as read from an [`XformReader`]) into
+/// `writer`, which is flushed at the end.  If `strict_pairing` is true, the
+/// transformation fails if the inputs are not paired (see the
+/// [`crate::pairing`] module).  Returns the statistics of the fragments read.
+/// This must be awaited within a tokio runtime, whose blocking thread pool
+/// reads and transforms the fragments.
+pub async fn xform_read_pairs_to_async_writer<W>(
+    geo_res: Vec<FragmentRegexDesc>,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    strict_pairing: bool,
+    writer: &mut W,
+) -> Result<XformStats>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let (r1, r2) = (r1.to_vec(), r2.to_vec());
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MAX_PENDING_CHUNKS);
+    let worker = tokio::task::spawn_blocking(move || -> Result<XformStats> {
+        let mut reader = XformReader::new(geo_res, &r1, &r2)?.with_strict_pairing(strict_pairing);
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            reader
+                .by_ref()
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            // the receiver is only dropped once writing failed, in which
+            // case the error of the writer is returned
+            if chunk.is_empty() || tx.blocking_send(chunk).is_err() {
+                break;
+            }
+        }
+        Ok(reader.into_stats())
+    });
+
+    let mut written = Ok(());
+    while let Some(chunk) = rx.recv().await {
+        written = writer.write_all(&chunk).await;
+        if written.is_err() {
+            break;
+        }
+    }
+    // stop the transformation if writing failed
+    drop(rx);
+    let stats = worker
+        .await
+        .context("the transformation of the reads panicked")?;
+    written.context("couldn't write the transformed records")?;
+    let stats = stats?;
+    writer
+        .flush()
+        .await
+        .context("couldn't write the transformed records")?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn records_are_written_asynchronously() {
+        let dir = tempdir().unwrap();
+        let (p1, p2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&p1, ">a\nACGTACGTTT\n>b\nACG\n>c\nTTTTACGTGG\n").unwrap();
+        std::fs::write(&p2, ">a\nCCCC\n>b\nGGGG\n>c\nAAAA\n").unwrap();
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let mut out = Vec::new();
+        let stats = rt
+            .block_on(xform_read_pairs_to_async_writer(
+                vec![geo_re.clone()],
+                std::slice::from_ref(&p1),
+                std::slice::from_ref(&p2),
+                false,
+                &mut out,
+            ))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ">a\nACGTACGT\n>a\nCCCC\n>c\nTTTTACGT\n>c\nAAAA\n"
+        );
+        assert_eq!((stats.total_fragments, stats.failed_parsing), (3, 1));
+
+        let missing = dir.path().join("missing.fa");
+        let mut out = Vec::new();
+        assert!(rt
+            .block_on(xform_read_pairs_to_async_writer(
+                vec![geo_re],
+                &[missing],
+                &[p2],
+                false,
+                &mut out,
+            ))
+            .is_err());
+    }
+}
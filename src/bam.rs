@@ -0,0 +1,763 @@
+//! Reading the input fragments from, and writing the transformed fragments
+//! to, unaligned BAM.
+//!
+//! Pipelines such as STARsolo or those compatible with Cell Ranger can read
+//! the barcode and UMI of each read from the tags of an unaligned BAM, rather
+//! than from a read of a FASTQ pair.  When [`crate::XformOptions::bam`] is
+//! set, the transformed fragments are written to such a BAM instead of the
+//! read 1 and read 2 outputs: the read sequence (`r`) piece becomes the
+//! sequence of the record, the barcode pieces (concatenated) are stored in
+//! the `CR` and `CB` tags and the UMI pieces in the `UR` and `UB` tags.  As the
+//! barcode and UMI are written as they appear in the transformed fragment,
+//! i.e. after correction against the whitelist and UMI correction if these
+//! are enabled, the raw (`CR`, `UR`) and corrected (`CB`, `UB`) tags hold the
+//! same sequences.  The tags appended to the headers of the transformed
+//! records (e.g. `feature=CD3`) are kept in the `CO` (comment) tag.
+//!
+//! If only one of the reads has an `r` piece, each fragment is written as a
+//! single unmapped record; if both reads have one, each fragment is written
+//! as a pair of unmapped records.  The transformed sequences carry no
+//! qualities, so the qualities of the records are missing (`0xff`).  The BAM
+//! is compressed with BGZF, so that it can be read by samtools or htslib.
+//!
+//! Conversely, many archived datasets are stored as unaligned BAM (or CRAM)
+//! holding both reads of each fragment.  The read 1 inputs are read as such
+//! files, with no read 2 input, if they all have the `.bam`, `.ubam` or
+//! `.cram` extension, or if [`crate::XformOptions::input_format`] is
+//! [`crate::InputFormat::Bam`] (e.g. when reading BAM from the standard
+//! input).  A read 1 input without such an extension is otherwise read as
+//! `FASTA` or `FASTQ`, so that a read 2 input must be given.  In these files
+//! (see [`BamReader`]), the two records of each fragment (flagged as the
+//! first and last segments of the template) must follow each other, as in an
+//! unaligned BAM or a BAM sorted (or collated) by name, and secondary and
+//! supplementary records are skipped.  CRAM files are decoded with `samtools view -u` (see
+//! [`cram_decoder`]), which must be on the `PATH`.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use seq_geom_parser::GeomPiece;
+
+use crate::bgzf::BgzfWriter;
+use crate::decode::ExternalDecoder;
+use crate::hook::RecordLayout;
+use crate::output::Compression;
+use crate::{reverse_complement_into, FragmentRegexDesc, SeqPair};
+
+/// Options for writing the transformed fragments as unaligned BAM.
+#[derive(Debug, Clone)]
+pub struct BamOptions {
+    /// The BAM file to write.
+    pub output: PathBuf,
+    /// The BGZF (deflate) compression level, in [0, 9].
+    pub level: u32,
+}
+
+impl BamOptions {
+    /// Create a new `BamOptions` writing to `output` at the default
+    /// compression level.
+    pub fn new(output: PathBuf) -> Self {
+        Self {
+            output,
+            level: Compression::DEFAULT_GZIP_LEVEL,
+        }
+    }
+}
+
+/// The codes of the bases in the 4-bit encoding of BAM sequences.
+const BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// The flags of an unpaired unmapped record, and of the first and second
+/// records of an unmapped pair.
+const FLAG_UNPAIRED: u16 = 0x4;
+const FLAG_FIRST: u16 = 0x1 | 0x4 | 0x8 | 0x40;
+const FLAG_SECOND: u16 = 0x1 | 0x4 | 0x8 | 0x80;
+
+/// The spans (offset and length, `None` if extending to the end of the read)
+/// of pieces in the read 1 and read 2 of the transformed fragments.
+type ReadSpans = [Vec<(usize, Option<usize>)>; 2];
+
+/// Writes the transformed fragments as unaligned BAM records.
+pub(crate) struct BamWriter {
+    w: BgzfWriter<BufWriter<File>>,
+    output: PathBuf,
+    barcodes: ReadSpans,
+    umis: ReadSpans,
+    /// The reads holding an `r` piece, and the span of this piece.
+    seqs: Vec<(usize, (usize, Option<usize>))>,
+    /// The encoded record being written.
+    rec: Vec<u8>,
+    barcode: Vec<u8>,
+    umi: Vec<u8>,
+}
+
+impl BamWriter {
+    /// Create a new `BamWriter` for the fragments transformed by `geo_re`,
+    /// creating the BAM of `opts` and writing its header.
+    pub(crate) fn new(geo_re: &FragmentRegexDesc, opts: &BamOptions) -> Result<Self> {
+        if opts.level > 9 {
+            bail!(
+                "the BAM compression level must be in [0, 9], but got {}",
+                opts.level
+            );
+        }
+        let layout = RecordLayout::new(geo_re);
+        let spans = |select: fn(&GeomPiece) -> bool| -> ReadSpans {
+            [layout.spans_where(0, select), layout.spans_where(1, select)]
+        };
+        let read_seqs = spans(|gp| matches!(gp, GeomPiece::ReadSeq(_)));
+        let mut seqs = vec![];
+        for (read, s) in read_seqs.iter().enumerate() {
+            match s.as_slice() {
+                [] => {}
+                [span] => seqs.push((read, *span)),
+                _ => bail!(
+                    "writing unaligned BAM requires each read of the geometry to have at most \
+                     one read sequence (r) piece"
+                ),
+            }
+        }
+        if seqs.is_empty() {
+            bail!("writing unaligned BAM requires the geometry to have a read sequence (r) piece");
+        }
+
+        let f = File::create(&opts.output)
+            .with_context(|| format!("could not create {}", opts.output.display()))?;
+        let mut w = BgzfWriter::new(BufWriter::new(f), flate2::Compression::new(opts.level));
+        let text = format!(
+            "@HD\tVN:1.6\tSO:unsorted\n@PG\tID:seq_xformer\tPN:seq_xformer\tVN:{}\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        w.write_all(b"BAM\x01")?;
+        w.write_all(&(text.len() as i32).to_le_bytes())?;
+        w.write_all(text.as_bytes())?;
+        // no reference sequences
+        w.write_all(&0_i32.to_le_bytes())?;
+        Ok(Self {
+            w,
+            output: opts.output.clone(),
+            barcodes: spans(|gp| matches!(gp, GeomPiece::Barcode(_))),
+            umis: spans(|gp| matches!(gp, GeomPiece::Umi(_))),
+            seqs,
+            rec: Vec::new(),
+            barcode: Vec::new(),
+            umi: Vec::new(),
+        })
+    }
+
+    /// Writes the records of the transformed fragment `frag`, given the
+    /// headers (ids and comments) of its read 1 and read 2 records.
+    pub(crate) fn write(&mut self, headers: [(&[u8], &[u8]); 2], frag: &SeqPair) -> Result<()> {
+        let reads = [frag.s1.as_slice(), frag.s2.as_slice()];
+        extract(&reads, &self.barcodes, &mut self.barcode);
+        extract(&reads, &self.umis, &mut self.umi);
+        let paired = self.seqs.len() == 2;
+        for &(read, span) in &self.seqs {
+            let (id, comment) = headers[read];
+            let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+            if name.len() > 254 {
+                bail!(
+                    "the read name {} is too long to be written to BAM (at most 254 characters)",
+                    String::from_utf8_lossy(name)
+                );
+            }
+            let flag = match (paired, read) {
+                (false, _) => FLAG_UNPAIRED,
+                (true, 0) => FLAG_FIRST,
+                (true, _) => FLAG_SECOND,
+            };
+            let seq = slice(reads[read], span);
+            let comment = &comment[comment.iter().take_while(|&&c| c == b' ').count()..];
+            self.rec.clear();
+            encode_record(&mut self.rec, name, flag, seq);
+            for (tag, value) in [
+                (b"CR", &self.barcode[..]),
+                (b"CB", &self.barcode[..]),
+                (b"UR", &self.umi[..]),
+                (b"UB", &self.umi[..]),
+                (b"CO", comment),
+            ] {
+                if !value.is_empty() {
+                    self.rec.extend_from_slice(tag);
+                    self.rec.push(b'Z');
+                    self.rec.extend_from_slice(value);
+                    self.rec.push(0);
+                }
+            }
+            self.w
+                .write_all(&(self.rec.len() as u32).to_le_bytes())
+                .and_then(|_| self.w.write_all(&self.rec))
+                .with_context(|| format!("couldn't write to {}", self.output.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end of file marker of the BAM and flushes it.
+    pub(crate) fn finish(self) -> Result<()> {
+        let mut inner = self.w.finish()?;
+        inner.flush()?;
+        Ok(())
+    }
+}
+
+/// The part of `s` in `span`, clamped to the length of `s`.
+fn slice(s: &[u8], (offset, len): (usize, Option<usize>)) -> &[u8] {
+    let end = len.map_or(s.len(), |l| (offset + l).min(s.len()));
+    &s[offset.min(end)..end]
+}
+
+/// Concatenates the parts of `reads` in `spans` into `out`.
+fn extract(reads: &[&[u8]; 2], spans: &ReadSpans, out: &mut Vec<u8>) {
+    out.clear();
+    for (read, spans) in spans.iter().enumerate() {
+        for &span in spans {
+            out.extend_from_slice(slice(reads[read], span));
+        }
+    }
+}
+
+/// Appends the fields of an unmapped BAM record (without its length and
+/// tags) named `name`, with the flag `flag` and the sequence `seq`, to `out`.
+fn encode_record(out: &mut Vec<u8>, name: &[u8], flag: u16, seq: &[u8]) {
+    // the reference, position and length of the name
+    out.extend_from_slice(&(-1_i32).to_le_bytes());
+    out.extend_from_slice(&(-1_i32).to_le_bytes());
+    out.push(name.len() as u8 + 1);
+    // the mapping quality, the bin of unmapped reads and the CIGAR length
+    out.push(255);
+    out.extend_from_slice(&4680_u16.to_le_bytes());
+    out.extend_from_slice(&0_u16.to_le_bytes());
+    out.extend_from_slice(&flag.to_le_bytes());
+    out.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    // the reference and position of the mate, and the template length
+    out.extend_from_slice(&(-1_i32).to_le_bytes());
+    out.extend_from_slice(&(-1_i32).to_le_bytes());
+    out.extend_from_slice(&0_i32.to_le_bytes());
+    out.extend_from_slice(name);
+    out.push(0);
+    let code = |c: u8| {
+        BASES
+            .iter()
+            .position(|&b| b == c.to_ascii_uppercase())
+            .unwrap_or(15) as u8
+    };
+    for pair in seq.chunks(2) {
+        let lo = pair.get(1).map_or(0, |&c| code(c));
+        out.push((code(pair[0]) << 4) | lo);
+    }
+    // missing qualities
+    out.resize(out.len() + seq.len(), 0xff);
+}
+
+/// Returns true if `p` names a BAM or CRAM file (i.e. has the `.bam`,
+/// `.ubam` or `.cram` extension).
+pub fn is_bam(p: &Path) -> bool {
+    p.extension().and_then(OsStr::to_str).is_some_and(|e| {
+        ["bam", "ubam", "cram"]
+            .iter()
+            .any(|x| e.eq_ignore_ascii_case(x))
+    })
+}
+
+/// Returns true if `p` names a CRAM file (i.e. has the `.cram` extension).
+pub fn is_cram(p: &Path) -> bool {
+    p.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|e| e.eq_ignore_ascii_case("cram"))
+}
+
+/// The decoder of CRAM files into (uncompressed) BAM, `samtools view -u`.
+pub fn cram_decoder() -> ExternalDecoder {
+    ExternalDecoder {
+        command: ["samtools", "view", "-u"].map(String::from).to_vec(),
+    }
+}
+
+/// A record read from a BAM.
+#[derive(Debug, Default)]
+pub(crate) struct BamRecord {
+    pub(crate) name: Vec<u8>,
+    pub(crate) seq: Vec<u8>,
+    /// The (phred+33) qualities of the bases, if the record has any.
+    pub(crate) qual: Option<Vec<u8>>,
+    flag: u16,
+}
+
+/// Reads the pairs of records of the fragments of a BAM.
+pub(crate) struct BamReader {
+    r: BufReader<flate2::read::MultiGzDecoder<Box<dyn Read + Send>>>,
+    block: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl BamReader {
+    /// Create a new `BamReader` reading the (BGZF compressed) BAM `r`, whose
+    /// header is read and skipped.
+    pub(crate) fn new(r: Box<dyn Read + Send>) -> Result<Self> {
+        let mut r = BufReader::new(flate2::read::MultiGzDecoder::new(r));
+        let mut magic = [0_u8; 4];
+        r.read_exact(&mut magic)
+            .context("could not read the BAM header")?;
+        if &magic != b"BAM\x01" {
+            bail!("not a BAM file");
+        }
+        let skip = |r: &mut BufReader<_>, n: u64| -> io::Result<()> {
+            io::copy(&mut Read::take(r, n), &mut io::sink()).and_then(|copied| {
+                if copied == n {
+                    Ok(())
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                }
+            })
+        };
+        let l_text = read_u32(&mut r)?;
+        skip(&mut r, l_text as u64)?;
+        // the names and lengths of the reference sequences
+        for _ in 0..read_u32(&mut r)? {
+            let l_name = read_u32(&mut r)?;
+            skip(&mut r, l_name as u64 + 4)?;
+        }
+        Ok(Self {
+            r,
+            block: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    /// Reads the next primary record into `rec`, returning false at the end
+    /// of the BAM.
+    fn read_record(&mut self, rec: &mut BamRecord) -> Result<bool> {
+        loop {
+            let mut len = [0_u8; 4];
+            let mut n = 0;
+            while n < 4 {
+                match self.r.read(&mut len[n..])? {
+                    0 if n == 0 => return Ok(false),
+                    0 => bail!("truncated BAM record"),
+                    k => n += k,
+                }
+            }
+            self.block.resize(u32::from_le_bytes(len) as usize, 0);
+            self.r
+                .read_exact(&mut self.block)
+                .context("truncated BAM record")?;
+            let b = &self.block;
+            if b.len() < 32 {
+                bail!("truncated BAM record");
+            }
+            let u16_at = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]) as usize;
+            let l_name = b[8] as usize;
+            let n_cigar = u16_at(12);
+            let flag = u16_at(14) as u16;
+            let l_seq = u32::from_le_bytes(b[16..20].try_into().unwrap()) as usize;
+            // secondary and supplementary alignments repeat a primary record
+            if flag & 0x900 != 0 {
+                continue;
+            }
+            let seq_start = 32 + l_name + 4 * n_cigar;
+            let qual_start = seq_start + l_seq.div_ceil(2);
+            if b.len() < qual_start + l_seq || l_name == 0 {
+                bail!("truncated BAM record");
+            }
+            rec.flag = flag;
+            rec.name.clear();
+            rec.name.extend_from_slice(&b[32..32 + l_name - 1]);
+            rec.seq.clear();
+            rec.seq.extend(
+                (0..l_seq)
+                    .map(|i| BASES[((b[seq_start + i / 2] >> (4 * (1 - i % 2))) & 0xf) as usize]),
+            );
+            let qual = &b[qual_start..qual_start + l_seq];
+            rec.qual = match qual.first() {
+                Some(0xff) | None => None,
+                _ => {
+                    let mut q = rec.qual.take().unwrap_or_default();
+                    q.clear();
+                    q.extend(qual.iter().map(|&q| q.saturating_add(33)));
+                    Some(q)
+                }
+            };
+            // the reads stored reverse complemented are restored
+            if flag & 0x10 != 0 {
+                reverse_complement_into(&rec.seq, &mut self.buf);
+                std::mem::swap(&mut rec.seq, &mut self.buf);
+                if let Some(q) = rec.qual.as_mut() {
+                    q.reverse();
+                }
+            }
+            return Ok(true);
+        }
+    }
+
+    /// Reads the records of the next fragment into `rec1` and `rec2`,
+    /// returning false at the end of the BAM.  Fails if a record is not
+    /// paired, or is not followed (or preceded) by its mate.
+    pub(crate) fn read_pair(&mut self, rec1: &mut BamRecord, rec2: &mut BamRecord) -> Result<bool> {
+        if !self.read_record(rec1)? {
+            return Ok(false);
+        }
+        let name = || String::from_utf8_lossy(&rec1.name).into_owned();
+        if rec1.flag & 0x1 == 0 {
+            bail!(
+                "the BAM record {} is not paired, but both reads of each fragment are expected",
+                name()
+            );
+        }
+        if !self.read_record(rec2)? || rec2.name != rec1.name {
+            bail!(
+                "the BAM record {} is not followed by its mate; the BAM must be sorted by name \
+                 (e.g. with samtools collate)",
+                name()
+            );
+        }
+        match (rec1.flag & 0xc0, rec2.flag & 0xc0) {
+            (0x40, 0x80) => {}
+            (0x80, 0x40) => std::mem::swap(rec1, rec2),
+            _ => bail!(
+                "the BAM records {} are not the first and last segments of their template",
+                name()
+            ),
+        }
+        Ok(true)
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0_u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        xform_read_pairs_to_file_with_options, FragmentGeomDescExt, InputFormat, XformOptions,
+    };
+    use flate2::read::MultiGzDecoder;
+    use seq_geom_parser::FragmentGeomDesc;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    #[test]
+    fn records_are_encoded() {
+        let mut rec = vec![];
+        encode_record(&mut rec, b"a", FLAG_UNPAIRED, b"ACGTA");
+        assert_eq!(rec.len(), 32 + 2 + 3 + 5);
+        assert_eq!(rec[8], 2);
+        assert_eq!(rec[14..16], FLAG_UNPAIRED.to_le_bytes());
+        assert_eq!(rec[16..20], 5_u32.to_le_bytes());
+        assert_eq!(&rec[32..34], b"a\0");
+        assert_eq!(&rec[34..38], &[0x12, 0x48, 0x10, 0xff]);
+        // lowercase bases are encoded as uppercase ones, and others as N
+        rec.clear();
+        encode_record(&mut rec, b"b", FLAG_FIRST, b"acX");
+        assert_eq!(&rec[34..36], &[0x12, 0xf0]);
+    }
+
+    #[test]
+    fn bam_outputs_need_a_read_sequence() {
+        let dir = tempdir().unwrap();
+        let writer = |geom: &str, level: u32| {
+            let geo_re = FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap();
+            let opts = BamOptions {
+                output: dir.path().join("out.bam"),
+                level,
+            };
+            BamWriter::new(&geo_re, &opts)
+        };
+        assert!(writer("1{b[4]u[2]}2{x:}", 6).is_err());
+        assert!(writer("1{b[4]u[2]x:}2{r:}", 10).is_err());
+        // without any fragment, only the header is written
+        writer("1{b[4]u[2]x:}2{r:}", 0).unwrap().finish().unwrap();
+        let file = File::open(dir.path().join("out.bam")).unwrap();
+        let mut reader = BamReader::new(Box::new(file)).unwrap();
+        let (mut rec1, mut rec2) = (BamRecord::default(), BamRecord::default());
+        assert!(!reader.read_pair(&mut rec1, &mut rec2).unwrap());
+
+        let mut writer = writer("1{b[2]x:}2{r:}", 6).unwrap();
+        let long_name = vec![b'a'; 255];
+        assert!(writer
+            .write(
+                [(&long_name, b""), (&long_name, b"")],
+                &SeqPair {
+                    s1: b"AA".to_vec(),
+                    s2: b"CC".to_vec()
+                }
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn fragments_with_two_read_sequences_are_written_as_pairs() {
+        let dir = tempdir().unwrap();
+        let p = dir.path().join("out.bam");
+        let geo_re = FragmentGeomDesc::try_from("1{b[2]r:}2{u[2]r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut writer = BamWriter::new(&geo_re, &BamOptions::new(p.clone())).unwrap();
+        let frag = SeqPair {
+            s1: b"AACCG".to_vec(),
+            s2: b"TTGGA".to_vec(),
+        };
+        writer
+            .write(
+                [(b"a 1:N", b" feature=x"), (b"a 2:N", b" feature=x")],
+                &frag,
+            )
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = BamReader::new(Box::new(File::open(&p).unwrap())).unwrap();
+        let (mut rec1, mut rec2) = (BamRecord::default(), BamRecord::default());
+        assert!(reader.read_pair(&mut rec1, &mut rec2).unwrap());
+        assert_eq!((&rec1.name[..], &rec2.name[..]), (&b"a"[..], &b"a"[..]));
+        // the barcode and UMI are left out of the sequences
+        assert_eq!((&rec1.seq[..], &rec2.seq[..]), (&b"CCG"[..], &b"GGA"[..]));
+        assert_eq!((rec1.flag, rec2.flag), (FLAG_FIRST, FLAG_SECOND));
+        assert_eq!(rec1.qual, None);
+        assert!(!reader.read_pair(&mut rec1, &mut rec2).unwrap());
+
+        let mut bam = vec![];
+        MultiGzDecoder::new(File::open(&p).unwrap())
+            .read_to_end(&mut bam)
+            .unwrap();
+        let tags = b"CRZAA\0CBZAA\0URZTT\0UBZTT\0COZfeature=x\0";
+        assert_eq!(bam.windows(tags.len()).filter(|w| w == tags).count(), 2);
+    }
+
+    #[test]
+    fn fragments_are_written_as_unaligned_bam() {
+        let geo = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        std::fs::write(p("r1.fa"), ">a 1:N\nAACCTTG\n>b\nAC\n>c\nGGTTCAA\n").unwrap();
+        std::fs::write(p("r2.fa"), ">a\nACGTA\n>b\nGGGG\n>c\nCCNC\n").unwrap();
+        let opts = XformOptions {
+            bam: Some(BamOptions::new(p("out.bam"))),
+            ..XformOptions::default()
+        };
+        let stats = xform_read_pairs_to_file_with_options(
+            geo.as_regex().unwrap(),
+            &[p("r1.fa")],
+            &[p("r2.fa")],
+            None,
+            None,
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(stats.failed_parsing, 1);
+
+        let mut bam = vec![];
+        MultiGzDecoder::new(File::open(p("out.bam")).unwrap())
+            .read_to_end(&mut bam)
+            .unwrap();
+        assert!(bam.starts_with(b"BAM\x01"));
+        let l_text = i32::from_le_bytes(bam[4..8].try_into().unwrap()) as usize;
+        assert!(bam[8..8 + l_text].starts_with(b"@HD\tVN:1.6"));
+        let mut rest = &bam[8 + l_text + 4..];
+        let mut records = vec![];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            records.push(&rest[4..4 + len]);
+            rest = &rest[4 + len..];
+        }
+        assert_eq!(records.len(), 2);
+
+        let mut expected = vec![];
+        encode_record(&mut expected, b"a", FLAG_UNPAIRED, b"ACGTA");
+        expected.extend_from_slice(b"CRZAACC\0CBZAACC\0URZTT\0UBZTT\0");
+        assert_eq!(records[0], &expected[..]);
+        assert!(records[1].ends_with(b"URZCA\0UBZCA\0"));
+        assert_eq!(records[1][14..16], FLAG_UNPAIRED.to_le_bytes());
+    }
+
+    /// Writes an unaligned BAM holding the records `recs` (name, flag and
+    /// sequence) to `p`.
+    fn write_records(p: &Path, recs: &[Vec<u8>]) {
+        let mut w = BgzfWriter::new(File::create(p).unwrap(), flate2::Compression::default());
+        w.write_all(b"BAM\x01\x00\x00\x00\x00\x00\x00\x00\x00")
+            .unwrap();
+        for rec in recs {
+            w.write_all(&(rec.len() as u32).to_le_bytes()).unwrap();
+            w.write_all(rec).unwrap();
+        }
+        w.finish().unwrap();
+    }
+
+    fn write_bam(p: &Path, recs: &[(&[u8], u16, &[u8])]) {
+        let recs: Vec<_> = recs
+            .iter()
+            .map(|&(name, flag, seq)| {
+                let mut rec = vec![];
+                encode_record(&mut rec, name, flag, seq);
+                rec
+            })
+            .collect();
+        write_records(p, &recs);
+    }
+
+    fn reader(p: &Path) -> Result<BamReader> {
+        BamReader::new(Box::new(File::open(p).unwrap()))
+    }
+
+    #[test]
+    fn records_are_read_in_pairs() {
+        let dir = tempdir().unwrap();
+        let p = dir.path().join("in.bam");
+        let mut recs = vec![vec![]; 4];
+        encode_record(&mut recs[0], b"a", FLAG_SECOND | 0x10, b"AACG");
+        encode_record(&mut recs[1], b"a", FLAG_FIRST, b"TTG");
+        // a supplementary record is skipped
+        encode_record(&mut recs[2], b"a", FLAG_FIRST | 0x800, b"GGGG");
+        encode_record(&mut recs[3], b"b", FLAG_FIRST, b"CC");
+        let n = recs[0].len();
+        recs[0][n - 4..].copy_from_slice(&[10, 20, 30, 40]);
+        recs.push(recs[3].clone());
+        recs[4][14..16].copy_from_slice(&FLAG_SECOND.to_le_bytes());
+        write_records(&p, &recs);
+
+        let mut reader = reader(&p).unwrap();
+        let (mut rec1, mut rec2) = (BamRecord::default(), BamRecord::default());
+        assert!(reader.read_pair(&mut rec1, &mut rec2).unwrap());
+        assert_eq!((&rec1.seq[..], rec1.flag), (&b"TTG"[..], FLAG_FIRST));
+        assert_eq!(rec1.qual, None);
+        // read 2 was stored reverse complemented, with its qualities
+        assert_eq!(&rec2.seq[..], b"CGTT");
+        assert_eq!(rec2.qual.as_deref(), Some(&b"I?5+"[..]));
+        assert!(reader.read_pair(&mut rec1, &mut rec2).unwrap());
+        assert_eq!((&rec1.name[..], &rec2.name[..]), (&b"b"[..], &b"b"[..]));
+        assert_eq!((&rec1.seq[..], rec2.qual.as_ref()), (&b"CC"[..], None));
+        assert!(!reader.read_pair(&mut rec1, &mut rec2).unwrap());
+    }
+
+    #[test]
+    fn malformed_bams_are_rejected() {
+        let dir = tempdir().unwrap();
+        let p = dir.path().join("in.bam");
+        let read_pair = |recs: &[(&[u8], u16, &[u8])]| {
+            write_bam(&p, recs);
+            let (mut rec1, mut rec2) = (BamRecord::default(), BamRecord::default());
+            reader(&p)?.read_pair(&mut rec1, &mut rec2)
+        };
+        let err = |recs: &[(&[u8], u16, &[u8])]| format!("{:#}", read_pair(recs).unwrap_err());
+
+        assert!(err(&[(b"a", FLAG_UNPAIRED, b"AC")]).contains("not paired"));
+        assert!(err(&[(b"a", FLAG_FIRST, b"AC")]).contains("not followed by its mate"));
+        assert!(
+            err(&[(b"a", FLAG_FIRST, b"AC"), (b"b", FLAG_SECOND, b"AC")])
+                .contains("not followed by its mate")
+        );
+        assert!(err(&[(b"a", FLAG_FIRST, b"AC"), (b"a", FLAG_FIRST, b"AC")])
+            .contains("not the first and last segments"));
+
+        // a record cut short of its sequence
+        let mut rec = vec![];
+        encode_record(&mut rec, b"a", FLAG_FIRST, b"ACGT");
+        rec.truncate(rec.len() - 3);
+        write_records(&p, &[rec]);
+        let mut rec1 = BamRecord::default();
+        let err = reader(&p).unwrap().read_record(&mut rec1).unwrap_err();
+        assert_eq!(err.to_string(), "truncated BAM record");
+
+        std::fs::write(&p, b"").unwrap();
+        assert!(reader(&p).is_err());
+        let mut w = BgzfWriter::new(File::create(&p).unwrap(), flate2::Compression::default());
+        w.write_all(b"CRAM").unwrap();
+        w.finish().unwrap();
+        assert_eq!(reader(&p).err().unwrap().to_string(), "not a BAM file");
+    }
+
+    #[test]
+    fn fragments_are_read_from_unaligned_bam() {
+        let geo = FragmentGeomDesc::try_from("1{b[2]u[2]x:}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        write_bam(
+            &p("in.bam"),
+            &[
+                (b"a", FLAG_FIRST, b"AACCGT"),
+                (b"a", FLAG_SECOND, b"TTAG"),
+                // a secondary record, and a reversed read 2 preceding read 1
+                (b"b", FLAG_FIRST | 0x100, b"GGGG"),
+                (b"b", FLAG_SECOND | 0x10, b"ACCA"),
+                (b"b", FLAG_FIRST, b"GGTTC"),
+            ],
+        );
+        xform_read_pairs_to_file_with_options(
+            geo.as_regex().unwrap(),
+            &[p("in.bam")],
+            &[],
+            Some(p("o1.fa")),
+            Some(p("o2.fa")),
+            &XformOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(p("o1.fa")).unwrap(),
+            ">a\nAACC\n>b\nGGTT\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(p("o2.fa")).unwrap(),
+            ">a\nTTAG\n>b\nTGGT\n"
+        );
+    }
+
+    #[test]
+    fn unaligned_bam_is_read_by_extension_or_explicitly() {
+        let geo = FragmentGeomDesc::try_from("1{b[2]u[2]x:}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        write_bam(
+            &p("in.dat"),
+            &[(b"a", FLAG_FIRST, b"AACCGT"), (b"a", FLAG_SECOND, b"TTAG")],
+        );
+        let run = |r1: &[PathBuf], r2: &[PathBuf], input_format| {
+            xform_read_pairs_to_file_with_options(
+                geo.as_regex().unwrap(),
+                r1,
+                r2,
+                Some(p("o1.fa")),
+                Some(p("o2.fa")),
+                &XformOptions {
+                    input_format,
+                    ..XformOptions::default()
+                },
+            )
+        };
+
+        // a BAM without the extension of one is only read as told
+        let err = run(&[p("in.dat")], &[], InputFormat::FromExtension).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("no read 2 input"),
+            "{:#}",
+            err
+        );
+        run(&[p("in.dat")], &[], InputFormat::Bam).unwrap();
+        assert_eq!(std::fs::read_to_string(p("o2.fa")).unwrap(), ">a\nTTAG\n");
+
+        // nor is a FASTA read 1 without a read 2 mistaken for a BAM
+        std::fs::write(p("r1.fa"), ">a\nAACCGT\n").unwrap();
+        let err = run(&[p("r1.fa")], &[], InputFormat::FromExtension).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("not unaligned BAM"),
+            "{:#}",
+            err
+        );
+
+        // and the BAM inputs hold both reads
+        let err = run(&[p("in.dat")], &[p("r1.fa")], InputFormat::Bam).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("hold both reads"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Counting of the cell barcodes of the transformed fragments.
+//!
+//! When barcode counting is enabled (through
+//! [`crate::XformOptions::count_barcodes`]), the barcodes of every written
+//! fragment are extracted from the transformed reads (so variable length
+//! barcodes include their padding, exactly as they appear in the output)
+//! and counted.  The counts can be written as a TSV, or as an unfiltered
+//! permit list for alevin-fry.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use anyhow::{anyhow, bail, Result};
+use seq_geom_parser::GeomPiece;
+
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// The number of transformed fragments observed with each barcode.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BarcodeCounts {
+    counts: HashMap<Vec<u8>, u64>,
+}
+
+impl BarcodeCounts {
+    /// Create a new (empty) `BarcodeCounts`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one occurrence of the barcode `bc`.
+    pub fn add(&mut self, bc: &[u8]) {
+        match self.counts.get_mut(bc) {
+            Some(c) => *c += 1,
+            None => {
+                self.counts.insert(bc.to_vec(), 1);
+            }
+        }
+    }
+
+    /// The number of distinct barcodes.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Returns true if no barcode was observed.
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// The number of fragments observed with the barcode `bc`.
+    pub fn get(&self, bc: &[u8]) -> u64 {
+        self.counts.get(bc).copied().unwrap_or(0)
+    }
+
+    /// The barcodes and their counts, from the most to the least frequent
+    /// (ties are broken by barcode, so that the order is deterministic).
+    pub fn sorted(&self) -> Vec<(&[u8], u64)> {
+        let mut v: Vec<(&[u8], u64)> = self
+            .counts
+            .iter()
+            .map(|(bc, c)| (bc.as_slice(), *c))
+            .collect();
+        v.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        v
+    }
+
+    /// Writes the counts as a TSV with a `barcode` and a `count` column,
+    /// from the most to the least frequent barcode.
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "barcode\tcount")?;
+        for (bc, c) in self.sorted() {
+            w.write_all(bc)?;
+            writeln!(w, "\t{}", c)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the observed barcodes as an unfiltered permit list, as expected
+    /// by the `--unfiltered-pl` option of `alevin-fry generate-permit-list`:
+    /// a plain text file with one barcode per line and no header.  As
+    /// alevin-fry only represents barcodes made up of `ACGT`, barcodes
+    /// containing any other base are left out.  The barcodes are written in
+    /// lexicographic order.
+    pub fn write_permit_list<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut bcs: Vec<&Vec<u8>> = self
+            .counts
+            .keys()
+            .filter(|bc| bc.iter().all(|b| b"ACGT".contains(b)))
+            .collect();
+        bcs.sort_unstable();
+        for bc in bcs {
+            w.write_all(bc)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the (concatenated) barcodes of the fragments transformed by a
+/// given geometry, and counts them.
+#[derive(Debug)]
+pub(crate) struct BarcodeCounter {
+    spans: Vec<PieceSpan>,
+    buf: Vec<u8>,
+}
+
+impl BarcodeCounter {
+    /// Create a new `BarcodeCounter` for the fragments transformed by
+    /// `geo_re`, whose barcode pieces must lie at fixed offsets in the
+    /// transformed fragments.
+    pub(crate) fn new(geo_re: &FragmentRegexDesc) -> Result<Self> {
+        let spans = geo_re
+            .piece_spans(|gp| matches!(gp, GeomPiece::Barcode(_)))
+            .map_err(|gp| {
+                anyhow!(
+                    "counting barcodes requires the barcode pieces of the geometry to be \
+                     of bounded length and preceded only by bounded pieces, but found {:?}",
+                    gp
+                )
+            })?;
+        if spans.is_empty() {
+            bail!("counting barcodes requires the geometry to have a barcode (b) piece");
+        }
+        Ok(Self { spans, buf: vec![] })
+    }
+
+    /// Counts the barcode of the transformed fragment `frag` in `counts`.
+    pub(crate) fn count(&mut self, frag: &SeqPair, counts: &mut BarcodeCounts) {
+        extract_spans(frag, &self.spans, &mut self.buf);
+        counts.add(&self.buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn barcode_counts_and_permit_list() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]}2{b[2]r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut counter = BarcodeCounter::new(&geo_re).unwrap();
+        let mut counts = BarcodeCounts::new();
+        for (s1, s2) in [
+            ("ACGTAA", "TTCCCC"),
+            ("ACGTCC", "TTGGGG"),
+            ("ACGTAA", "GGCCCC"),
+            ("NCGTAA", "GGCCCC"),
+        ] {
+            let frag = SeqPair {
+                s1: s1.as_bytes().to_vec(),
+                s2: s2.as_bytes().to_vec(),
+            };
+            counter.count(&frag, &mut counts);
+        }
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts.get(b"ACGTTT"), 2);
+
+        let mut tsv = vec![];
+        counts.write_tsv(&mut tsv).unwrap();
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "barcode\tcount\nACGTTT\t2\nACGTGG\t1\nNCGTGG\t1\n"
+        );
+        let mut pl = vec![];
+        counts.write_permit_list(&mut pl).unwrap();
+        assert_eq!(String::from_utf8(pl).unwrap(), "ACGTGG\nACGTTT\n");
+
+        let geo_re = FragmentGeomDesc::try_from("1{u[4]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        assert!(BarcodeCounter::new(&geo_re).is_err());
+    }
+}
@@ -0,0 +1,633 @@
+//! Reading of the input fragments on a dedicated thread.
+//!
+//! Decoding (and decompressing) the records of each input file is done by a
+//! reader thread of its own, which fills a batch of records while the
+//! previous batch is being transformed, so that the latency of the input
+//! (e.g. decompression, or slow spinning-disk or network storage) is hidden
+//! behind the matching.  Batches are handed back to the reader threads once
+//! they have been processed, so that their buffers are reused.
+
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use needletail::{FastxReader, Sequence};
+
+use crate::bam::{BamReader, BamRecord};
+use crate::malformed::MALFORMED_ID;
+
+/// The number of fragments in a full batch, unless another capacity is
+/// given.
+pub(crate) const DEFAULT_CAPACITY: usize = 4096;
+
+/// A batch of records of one input file, whose ids, sequences and qualities
+/// are stored contiguously.
+#[derive(Debug, Clone, Default)]
+struct ReadBatch {
+    data: Vec<u8>,
+    /// For each record, the ends (in `data`) of its id, of its sequence and
+    /// of its qualities (if it has any).
+    ends: Vec<RecordEnds>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RecordEnds {
+    id: usize,
+    seq: usize,
+    qual: Option<usize>,
+}
+
+/// A record of a [`ReadBatch`]: its id (the whole header line), sequence
+/// and qualities (for a `FASTQ` record).
+type BatchRecord<'a> = (&'a [u8], &'a [u8], Option<&'a [u8]>);
+
+impl ReadBatch {
+    fn clear(&mut self) {
+        self.data.clear();
+        self.ends.clear();
+    }
+
+    fn push(&mut self, id: &[u8], seq: &[u8], qual: Option<&[u8]>) {
+        self.data.extend_from_slice(id);
+        let id_end = self.data.len();
+        self.data.extend_from_slice(seq);
+        let seq_end = self.data.len();
+        let qual_end = qual.map(|q| {
+            self.data.extend_from_slice(q);
+            self.data.len()
+        });
+        self.ends.push(RecordEnds {
+            id: id_end,
+            seq: seq_end,
+            qual: qual_end,
+        });
+    }
+
+    /// The sequence of the `i`-th record of the batch.
+    fn seq(&self, i: usize) -> &[u8] {
+        let e = &self.ends[i];
+        &self.data[e.id..e.seq]
+    }
+
+    fn iter(&self) -> impl Iterator<Item = BatchRecord<'_>> {
+        let mut start = 0;
+        self.ends.iter().map(move |e| {
+            let end = e.qual.unwrap_or(e.seq);
+            let rec = (
+                &self.data[start..e.id],
+                &self.data[e.id..e.seq],
+                e.qual.map(|q| &self.data[e.seq..q]),
+            );
+            start = end;
+            rec
+        })
+    }
+}
+
+/// A batch of fragments: the records of read 1 and read 2 (and of the index
+/// reads, if any) read together.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FragmentBatch {
+    batches: [ReadBatch; 2],
+    /// The records of read 3 and read 4 (see the [`crate::index`] module).
+    index: [Option<ReadBatch>; 2],
+    /// The (increasing) indices of the fragments that are left out, as they
+    /// hold a malformed record (see the [`crate::malformed`] module).
+    dropped: Vec<usize>,
+}
+
+/// A fragment of a [`FragmentBatch`].
+pub(crate) struct BatchFragment<'a> {
+    pub(crate) ids: [&'a [u8]; 2],
+    pub(crate) seqs: [&'a [u8]; 2],
+    /// The qualities of the reads of a `FASTQ` input.
+    pub(crate) quals: [Option<&'a [u8]>; 2],
+    /// The sequences of read 3 and read 4, which are empty if they are not
+    /// read.
+    pub(crate) index: [&'a [u8]; 2],
+}
+
+impl FragmentBatch {
+    /// The number of fragments of the batch.
+    pub(crate) fn len(&self) -> usize {
+        let [b1, b2] = &self.batches;
+        b1.ends.len().min(b2.ends.len()) - self.dropped.len()
+    }
+
+    /// Adds a fragment of `FASTA` records, whose read 1 record is named `id`
+    /// (and whose read 2 record is unnamed), and which has no index reads.
+    pub(crate) fn push(&mut self, id: &[u8], seqs: [&[u8]; 2]) {
+        self.batches[0].push(id, seqs[0], None);
+        self.batches[1].push(&[], seqs[1], None);
+    }
+
+    /// Removes all the fragments, keeping the allocations.
+    pub(crate) fn clear(&mut self) {
+        for b in self
+            .batches
+            .iter_mut()
+            .chain(self.index.iter_mut().flatten())
+        {
+            b.clear();
+        }
+        self.dropped.clear();
+    }
+
+    /// The fragments of the batch.  If one of the input files has fewer
+    /// records than the other, the batch ends with the shorter one.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = BatchFragment<'_>> {
+        let [b1, b2] = &self.batches;
+        let mut dropped = self.dropped.iter().peekable();
+        b1.iter()
+            .zip(b2.iter())
+            .enumerate()
+            .filter(move |(i, _)| dropped.next_if_eq(&i).is_none())
+            .map(
+                move |(i, ((id1, seq1, qual1), (id2, seq2, qual2)))| BatchFragment {
+                    ids: [id1, id2],
+                    seqs: [seq1, seq2],
+                    quals: [qual1, qual2],
+                    index: [self.index_seq(0, i), self.index_seq(1, i)],
+                },
+            )
+    }
+
+    /// Leaves out the fragments holding a placeholder of a malformed record,
+    /// returning their number.
+    fn drop_malformed(&mut self) -> u64 {
+        let records = self.batches.iter().chain(self.index.iter().flatten());
+        for b in records {
+            for (i, (id, _, _)) in b.iter().enumerate() {
+                if id == MALFORMED_ID {
+                    self.dropped.push(i);
+                }
+            }
+        }
+        let fragments = self.batches[0].ends.len().min(self.batches[1].ends.len());
+        self.dropped.retain(|&i| i < fragments);
+        self.dropped.sort_unstable();
+        self.dropped.dedup();
+        self.dropped.len() as u64
+    }
+
+    /// The sequence of the index read `read` (`0` for read 3 and `1` for
+    /// read 4) of the `i`-th fragment, which is empty if it is not read.
+    fn index_seq(&self, read: usize, i: usize) -> &[u8] {
+        match &self.index[read] {
+            Some(b) => b.seq(i),
+            None => &[],
+        }
+    }
+}
+
+/// Reads the records of one or more input files, one file after the other.
+struct ConcatReader {
+    readers: std::vec::IntoIter<Box<dyn FastxReader>>,
+    current: Option<Box<dyn FastxReader>>,
+}
+
+impl ConcatReader {
+    fn new(readers: Vec<Box<dyn FastxReader>>) -> Self {
+        let mut readers = readers.into_iter();
+        let current = readers.next();
+        Self { readers, current }
+    }
+
+    /// Adds records to `batch` until it holds `capacity` records or all of
+    /// the files have been read.
+    fn fill(&mut self, batch: &mut ReadBatch, capacity: usize) -> Result<()> {
+        while batch.ends.len() < capacity {
+            let Some(reader) = self.current.as_mut() else {
+                break;
+            };
+            let exhausted = match reader.next() {
+                Some(Ok(rec)) => {
+                    batch.push(rec.id(), rec.sequence(), rec.qual());
+                    false
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => true,
+            };
+            if exhausted {
+                self.current = self.readers.next();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The reader thread of one input file (or of several input files read
+/// one after the other).
+struct RecordReader {
+    rx: Option<Receiver<Result<ReadBatch>>>,
+    recycle: Sender<ReadBatch>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl RecordReader {
+    /// Spawns a thread reading the records of `readers`, one reader after
+    /// the other, in batches of `capacity` records.
+    fn spawn(readers: Vec<Box<dyn FastxReader>>, capacity: usize) -> Self {
+        // a single batch may wait in the channel while the next one is being
+        // filled and the previous one is being processed
+        let (tx, rx) = sync_channel(1);
+        let (recycle, recycled) = channel();
+        let reader = ConcatReader::new(readers);
+        // the reader thread logs (e.g. the retried reads) to the subscriber
+        // of the thread creating it
+        let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+        let handle = thread::spawn(move || {
+            tracing::dispatcher::with_default(&dispatch, || {
+                read_batches(reader, capacity, tx, recycled)
+            })
+        });
+        Self {
+            rx: Some(rx),
+            recycle,
+            handle: Some(handle),
+        }
+    }
+
+    /// Spawns a single thread reading the fragments of the BAM readers
+    /// `readers`, one after the other, and returns the readers of their read
+    /// 1 and read 2 records, whose batches hold the same `capacity`
+    /// fragments.
+    fn spawn_pairs(readers: Vec<BamReader>, capacity: usize) -> [Self; 2] {
+        let (tx1, rx1) = sync_channel(1);
+        let (tx2, rx2) = sync_channel(1);
+        let (recycle1, recycled1) = channel();
+        let (recycle2, recycled2) = channel();
+        let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+        let handle = thread::spawn(move || {
+            tracing::dispatcher::with_default(&dispatch, || {
+                read_pair_batches(readers, capacity, [tx1, tx2], [recycled1, recycled2])
+            })
+        });
+        // the thread is joined by the read 2 reader, which is dropped last, so
+        // that it cannot be blocked on either channel by then
+        [
+            Self {
+                rx: Some(rx1),
+                recycle: recycle1,
+                handle: None,
+            },
+            Self {
+                rx: Some(rx2),
+                recycle: recycle2,
+                handle: Some(handle),
+            },
+        ]
+    }
+
+    fn next_batch(&mut self) -> Option<Result<ReadBatch>> {
+        self.rx.as_ref().and_then(|rx| rx.recv().ok())
+    }
+}
+
+impl Drop for RecordReader {
+    fn drop(&mut self) {
+        // dropping the receiver makes the reader thread stop at its next batch
+        self.rx.take();
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+/// Reads the fragments of a pair of input files in batches, decoding each
+/// of the files on its own thread.
+pub(crate) struct BatchReader {
+    readers: [RecordReader; 2],
+    /// The readers of read 3 and read 4, which must hold as many records as
+    /// read 1.
+    index: [Option<RecordReader>; 2],
+    /// If true, read 1 and read 2 must hold the same number of records.
+    check_counts: bool,
+    /// The number of records of read 1 and of read 2 read after the other
+    /// read had ended, which are not part of any fragment.
+    unpaired: [u64; 2],
+    /// If true, the fragments holding a malformed record are left out.
+    drop_malformed: bool,
+    /// The number of fragments left out as they hold a malformed record.
+    malformed: u64,
+    /// The number of fragments of a full batch.
+    capacity: usize,
+}
+
+impl BatchReader {
+    /// Starts reading the fragments of the pair of readers `reader1` and
+    /// `reader2` in batches of `capacity` fragments, stopping at the end of
+    /// the shorter of the two.
+    pub(crate) fn spawn(
+        reader1: Box<dyn FastxReader>,
+        reader2: Box<dyn FastxReader>,
+        capacity: usize,
+    ) -> Self {
+        Self::new(
+            [
+                RecordReader::spawn(vec![reader1], capacity),
+                RecordReader::spawn(vec![reader2], capacity),
+            ],
+            false,
+            capacity,
+        )
+    }
+
+    /// Starts reading the fragments whose read 1 records are those of
+    /// `readers1`, one reader after the other, and whose read 2 records are
+    /// those of `readers2`.  Reading fails if the read 1 readers do not hold
+    /// as many records as the read 2 readers.
+    pub(crate) fn spawn_concatenated(
+        readers1: Vec<Box<dyn FastxReader>>,
+        readers2: Vec<Box<dyn FastxReader>>,
+        capacity: usize,
+    ) -> Self {
+        Self::new(
+            [
+                RecordReader::spawn(readers1, capacity),
+                RecordReader::spawn(readers2, capacity),
+            ],
+            true,
+            capacity,
+        )
+    }
+
+    /// Starts reading the fragments of the BAM readers `readers`, one after
+    /// the other, each of which holds both reads of its fragments, in
+    /// batches of `capacity` fragments.
+    pub(crate) fn spawn_pairs(readers: Vec<BamReader>, capacity: usize) -> Self {
+        Self::new(RecordReader::spawn_pairs(readers, capacity), true, capacity)
+    }
+
+    fn new(readers: [RecordReader; 2], check_counts: bool, capacity: usize) -> Self {
+        Self {
+            readers,
+            index: [None, None],
+            check_counts,
+            unpaired: [0, 0],
+            drop_malformed: false,
+            malformed: 0,
+            capacity,
+        }
+    }
+
+    /// Also reads the records of read 3 and read 4 from `index1` and
+    /// `index2` (one reader after the other), if given, along with those of
+    /// read 1 and read 2.
+    pub(crate) fn with_index_reads(
+        mut self,
+        index1: Option<Vec<Box<dyn FastxReader>>>,
+        index2: Option<Vec<Box<dyn FastxReader>>>,
+    ) -> Self {
+        let capacity = self.capacity;
+        self.index = [index1, index2].map(|r| r.map(|r| RecordReader::spawn(r, capacity)));
+        self
+    }
+
+    /// Fails if read 1 and read 2 do not hold the same number of records,
+    /// rather than stopping at the end of the shorter one.
+    pub(crate) fn with_equal_counts(mut self) -> Self {
+        self.check_counts = true;
+        self
+    }
+
+    /// The number of records of read 1 and of read 2 that were read after
+    /// the other read had ended, which are not part of any fragment.
+    pub(crate) fn unpaired(&self) -> [u64; 2] {
+        self.unpaired
+    }
+
+    /// Leaves out the fragments holding the placeholder of a malformed record
+    /// (see the [`crate::malformed`] module).
+    pub(crate) fn dropping_malformed(mut self) -> Self {
+        self.drop_malformed = true;
+        self
+    }
+
+    /// The number of fragments left out as they hold a malformed record.
+    pub(crate) fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    /// Returns the next batch of fragments, `None` once all of them have
+    /// been read, or the error encountered when reading the next record.
+    pub(crate) fn next_batch(&mut self) -> Option<Result<FragmentBatch>> {
+        let [r1, r2] = &mut self.readers;
+        let (b1, b2) = match (r1.next_batch(), r2.next_batch()) {
+            (Some(b1), Some(b2)) => (b1, b2),
+            (None, None) => return None,
+            (Some(Err(e)), None) | (None, Some(Err(e))) => return Some(Err(e)),
+            _ if self.check_counts => return Some(Err(unequal_counts())),
+            (Some(Ok(b)), None) => return self.drain(0, b.ends.len()),
+            (None, Some(Ok(b))) => return self.drain(1, b.ends.len()),
+        };
+        let (b1, b2) = match (b1, b2) {
+            (Ok(b1), Ok(b2)) => (b1, b2),
+            (Err(e), _) | (_, Err(e)) => return Some(Err(e)),
+        };
+        if b1.ends.len() != b2.ends.len() {
+            if self.check_counts {
+                return Some(Err(unequal_counts()));
+            }
+            // the fragments of the batch end with the shorter read
+            let read = usize::from(b2.ends.len() > b1.ends.len());
+            self.unpaired[read] += b1.ends.len().abs_diff(b2.ends.len()) as u64;
+        }
+        let mut index = [None, None];
+        for (reader, batch) in self.index.iter_mut().zip(index.iter_mut()) {
+            let Some(reader) = reader else {
+                continue;
+            };
+            match reader.next_batch() {
+                Some(Ok(b)) if b.ends.len() == b1.ends.len() => *batch = Some(b),
+                Some(Err(e)) => return Some(Err(e)),
+                _ => {
+                    return Some(Err(anyhow!(
+                        "The index read input files hold a different number of records than the read 1 files"
+                    )))
+                }
+            }
+        }
+        let mut batch = FragmentBatch {
+            batches: [b1, b2],
+            index,
+            dropped: vec![],
+        };
+        if self.drop_malformed {
+            self.malformed += batch.drop_malformed();
+        }
+        Some(Ok(batch))
+    }
+
+    /// Reads the remaining records of `read` once the other read has ended,
+    /// counting them (along with the `first` records already read) as
+    /// unpaired.
+    fn drain(&mut self, read: usize, first: usize) -> Option<Result<FragmentBatch>> {
+        self.unpaired[read] += first as u64;
+        while let Some(b) = self.readers[read].next_batch() {
+            match b {
+                Ok(b) => self.unpaired[read] += b.ends.len() as u64,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    /// Hands a processed batch back to the reader threads, for reuse.
+    pub(crate) fn recycle(&self, batch: FragmentBatch) {
+        for (r, b) in self.readers.iter().zip(batch.batches) {
+            // the reader thread may already be done
+            let _ = r.recycle.send(b);
+        }
+        for (r, b) in self.index.iter().zip(batch.index) {
+            if let (Some(r), Some(b)) = (r, b) {
+                let _ = r.recycle.send(b);
+            }
+        }
+    }
+}
+
+fn unequal_counts() -> anyhow::Error {
+    anyhow!("The read 1 and read 2 input files hold different numbers of records")
+}
+
+fn read_batches(
+    mut reader: ConcatReader,
+    capacity: usize,
+    tx: SyncSender<Result<ReadBatch>>,
+    recycled: Receiver<ReadBatch>,
+) {
+    loop {
+        let mut batch = recycled.try_recv().unwrap_or_default();
+        batch.clear();
+        let err = reader.fill(&mut batch, capacity).err();
+        let done = err.is_some() || batch.ends.len() < capacity;
+        // the records read before an error are still processed
+        if !batch.ends.is_empty() && tx.send(Ok(batch)).is_err() {
+            return;
+        }
+        if let Some(e) = err {
+            let _ = tx.send(Err(e));
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+fn read_pair_batches(
+    readers: Vec<BamReader>,
+    capacity: usize,
+    tx: [SyncSender<Result<ReadBatch>>; 2],
+    recycled: [Receiver<ReadBatch>; 2],
+) {
+    let (mut rec1, mut rec2) = (BamRecord::default(), BamRecord::default());
+    let mut readers = readers.into_iter();
+    let mut current = readers.next();
+    loop {
+        let mut batches = [&recycled[0], &recycled[1]].map(|r| {
+            let mut b = r.try_recv().unwrap_or_default();
+            b.clear();
+            b
+        });
+        let mut err = None;
+        while batches[0].ends.len() < capacity {
+            let Some(reader) = current.as_mut() else {
+                break;
+            };
+            match reader.read_pair(&mut rec1, &mut rec2) {
+                Ok(true) => {
+                    for (b, rec) in batches.iter_mut().zip([&rec1, &rec2]) {
+                        b.push(&rec.name, &rec.seq, rec.qual.as_deref());
+                    }
+                }
+                Ok(false) => current = readers.next(),
+                Err(e) => {
+                    err = Some(e);
+                    break;
+                }
+            }
+        }
+        let done = err.is_some() || batches[0].ends.len() < capacity;
+        // the records read before an error are still processed
+        if !batches[0].ends.is_empty() {
+            for (tx, b) in tx.iter().zip(batches) {
+                if tx.send(Ok(b)).is_err() {
+                    return;
+                }
+            }
+        }
+        if let Some(e) = err {
+            // the read 2 reader ends, so that the error of read 1 is reported
+            let _ = tx[0].send(Err(e));
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use needletail::parse_fastx_reader;
+
+    #[test]
+    fn batches_hold_all_fragments() {
+        let n = DEFAULT_CAPACITY + 10;
+        let fa = |c: char| -> Vec<u8> {
+            (0..n)
+                .map(|i| format!(">r{}\n{}\n", i, c.to_string().repeat(i % 7 + 1)))
+                .collect::<String>()
+                .into_bytes()
+        };
+        let reader1 = parse_fastx_reader(std::io::Cursor::new(fa('A'))).unwrap();
+        let reader2 = parse_fastx_reader(std::io::Cursor::new(fa('C'))).unwrap();
+        let mut batches = BatchReader::spawn(reader1, reader2, DEFAULT_CAPACITY);
+        let mut seen = 0;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch.unwrap();
+            for frag in batch.iter() {
+                assert_eq!(frag.ids[0], format!("r{}", seen).as_bytes());
+                assert_eq!(frag.ids[1], frag.ids[0]);
+                assert_eq!(frag.seqs[0], "A".repeat(seen % 7 + 1).as_bytes());
+                assert_eq!(frag.seqs[1], "C".repeat(seen % 7 + 1).as_bytes());
+                seen += 1;
+            }
+            batches.recycle(batch);
+        }
+        assert_eq!(seen, n);
+    }
+
+    #[test]
+    fn concatenated_inputs() {
+        let fa = |range: std::ops::Range<usize>| {
+            let recs: String = range.map(|i| format!(">r{}\nACGT\n", i)).collect();
+            parse_fastx_reader(std::io::Cursor::new(recs.into_bytes())).unwrap()
+        };
+        let n = DEFAULT_CAPACITY + 10;
+        let mut batches = BatchReader::spawn_concatenated(
+            vec![fa(0..3), fa(3..n)],
+            vec![fa(0..n - 5), fa(n - 5..n - 1), fa(n - 1..n)],
+            DEFAULT_CAPACITY,
+        );
+        let mut seen = 0;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch.unwrap();
+            for frag in batch.iter() {
+                assert_eq!(frag.ids[0], format!("r{}", seen).as_bytes());
+                assert_eq!(frag.ids[1], frag.ids[0]);
+                seen += 1;
+            }
+            batches.recycle(batch);
+        }
+        assert_eq!(seen, n);
+
+        let mut batches = BatchReader::spawn_concatenated(
+            vec![fa(0..3), fa(3..5)],
+            vec![fa(0..4)],
+            DEFAULT_CAPACITY,
+        );
+        assert!(batches.next_batch().unwrap().is_err());
+    }
+}
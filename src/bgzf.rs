@@ -0,0 +1,158 @@
+//! A writer of the blocked gzip format (BGZF) used by samtools and htslib.
+//!
+//! A BGZF file is a series of gzip members (blocks) holding at most 64KiB of
+//! data each, whose compressed size is recorded in an extra header field, and
+//! is terminated by an empty block.  It can be read by any gzip decoder, but
+//! also allows random access and parallel decompression by tools aware of
+//! the format.
+
+use std::io::{self, Write};
+
+use flate2::write::DeflateEncoder;
+use flate2::Crc;
+
+/// The maximum number of (uncompressed) bytes held in a block, as used by
+/// htslib, which leaves room for the block to grow when compressed.
+const BLOCK_DATA_LEN: usize = 0xff00;
+/// The maximum size of a (compressed) block.
+const MAX_BLOCK_LEN: usize = 1 << 16;
+/// The size of the header of a block.
+const HEADER_LEN: usize = 18;
+/// The size of the footer (the CRC32 and the size of the data) of a block.
+const FOOTER_LEN: usize = 8;
+/// The empty block terminating a BGZF file.
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compresses the data written to it into BGZF blocks, which are written to
+/// the wrapped writer.
+#[derive(Debug)]
+pub(crate) struct BgzfWriter<W: Write> {
+    inner: W,
+    level: flate2::Compression,
+    /// The data of the block being filled.
+    buf: Vec<u8>,
+    /// The compressed data of the block being written.
+    cdata: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub(crate) fn new(inner: W, level: flate2::Compression) -> Self {
+        Self {
+            inner,
+            level,
+            buf: Vec::with_capacity(BLOCK_DATA_LEN),
+            cdata: Vec::with_capacity(MAX_BLOCK_LEN),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The number of bytes of the block being filled, i.e. the offset of the
+    /// next byte written in the uncompressed data of its block.
+    pub(crate) fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Compresses the buffered data into `self.cdata` with the compression
+    /// level `level`.
+    fn deflate(&mut self, level: flate2::Compression) -> io::Result<()> {
+        self.cdata.clear();
+        let mut enc = DeflateEncoder::new(&mut self.cdata, level);
+        enc.write_all(&self.buf)?;
+        enc.finish()?;
+        Ok(())
+    }
+
+    /// Writes the data buffered so far as a block.
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.deflate(self.level)?;
+        if HEADER_LEN + self.cdata.len() + FOOTER_LEN > MAX_BLOCK_LEN {
+            // incompressible data; stored (level 0) blocks always fit
+            self.deflate(flate2::Compression::none())?;
+        }
+        let block_len = HEADER_LEN + self.cdata.len() + FOOTER_LEN;
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+
+        let mut header = [
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00, 0x00, 0x00,
+        ];
+        header[16..].copy_from_slice(&((block_len - 1) as u16).to_le_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&self.cdata)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner
+            .write_all(&(self.buf.len() as u32).to_le_bytes())?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes any buffered data and the terminating empty block, returning
+    /// the wrapped writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        self.inner.write_all(&EOF_BLOCK)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_DATA_LEN - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == BLOCK_DATA_LEN {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn blocks_decode_as_gzip() {
+        let data: Vec<u8> = (0..200_000_u32)
+            .map(|i| b"ACGT"[(i.wrapping_mul(2_654_435_761) >> 30) as usize])
+            .collect();
+        let mut w = BgzfWriter::new(vec![], flate2::Compression::default());
+        w.write_all(&data).unwrap();
+        let out = w.finish().unwrap();
+        assert_eq!(&out[..4], &[0x1f, 0x8b, 0x08, 0x04]);
+        assert!(out.ends_with(&EOF_BLOCK));
+
+        let mut decoded = vec![];
+        MultiGzDecoder::new(&out[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        // every block records its own size
+        let mut pos = 0;
+        let mut blocks = 0;
+        while pos < out.len() {
+            let bsize = u16::from_le_bytes([out[pos + 16], out[pos + 17]]) as usize + 1;
+            pos += bsize;
+            blocks += 1;
+        }
+        assert_eq!(pos, out.len());
+        assert_eq!(blocks, 200_000 / BLOCK_DATA_LEN + 2);
+    }
+}
@@ -1,14 +1,63 @@
-use std::path::PathBuf;
-use std::time::Instant;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, Parser, Subcommand};
 
+use needletail::errors::ParseError;
 use seq_geom_parser::FragmentGeomDesc; // PiscemGeomDesc, SalmonSeparateGeomDesc};
-use seq_geom_xform::FragmentGeomDescExt;
+use seq_geom_xform::anchor::AnchorSearch;
+use seq_geom_xform::bam::{self, BamOptions};
+use seq_geom_xform::calibrate::{self, CalibrationReport};
+use seq_geom_xform::consistency::{ConsistencyMode, ConsistencyOptions};
+use seq_geom_xform::control::{ControlOptions, ControlRef};
+use seq_geom_xform::correction::CorrectionStrategy;
+use seq_geom_xform::decode::ExternalDecoder;
+use seq_geom_xform::dedup::{DedupMode, DedupOptions};
+use seq_geom_xform::demux::{self, DemuxOptions, SampleSheet};
+use seq_geom_xform::failure::push_json_string;
+use seq_geom_xform::feature::{FeatureOptions, FeatureRef};
+use seq_geom_xform::guide::GuideOptions;
+use seq_geom_xform::index::{self, IndexReadOptions};
+use seq_geom_xform::jitter::{self, JitterReport};
+use seq_geom_xform::lengths;
+use seq_geom_xform::malformed::OnError;
+use seq_geom_xform::name::NameCapture;
+use seq_geom_xform::naming::IdFormat;
+use seq_geom_xform::offsets::OffsetIndexOptions;
+use seq_geom_xform::output::{
+    compression_from_extension, detect_compression, parse_byte_size, Compression, OutputFormat,
+    SinkConfig, SplitPolicy,
+};
+use seq_geom_xform::presets;
+use seq_geom_xform::preview::{self, PreviewedFragment};
+use seq_geom_xform::probe::{ProbeOptions, ProbePanel};
+use seq_geom_xform::progress::XformStatsHandle;
+use seq_geom_xform::provenance::{self, IfDone, RecordedRun};
+use seq_geom_xform::retry::RetryOptions;
+use seq_geom_xform::route::{self, Modality, RoutingOptions};
+use seq_geom_xform::rules::PieceRule;
+#[cfg(feature = "serve")]
+use seq_geom_xform::serve::XformServer;
+use seq_geom_xform::sim::{self, SimConfig};
+use seq_geom_xform::sort::SortOptions;
+use seq_geom_xform::spatial::{CoordinateTable, SpatialOptions};
+use seq_geom_xform::swap;
+use seq_geom_xform::umi::UmiOptions;
+use seq_geom_xform::verify::{self, VerifyOptions};
+use seq_geom_xform::whitelist::{DiskWhitelistOptions, Whitelist};
+use seq_geom_xform::{
+    is_stdin, FragmentGeomDescExt, FragmentRegexDesc, InputFormat, PaddingScheme, RegexOptions,
+    XformOptions, XformStats,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
@@ -16,80 +65,2554 @@ use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 /// into a simpler (normalized) form.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+struct Cli {
+    /// TOML file from which to read options; keys are the long option
+    /// names (e.g. `geom`, `read1`, `out1`), and options given on the
+    /// command line take precedence over those in the file
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// log more details: debug messages with -v, and trace messages with -vv
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// the seed from which the random choices of the run are made (e.g. the
+    /// reads generated by simulate); it is recorded in the --stats-json
+    /// report, and runs with the same seed, inputs and options have
+    /// bit-identical outputs
+    #[arg(long, global = true, default_value_t = 0)]
+    seed: u64,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Transform the input reads into the simplified geometry
+    #[command(visible_alias = "transform")]
+    Xform(Box<XformArgs>),
+    /// Check that a geometry is valid and, if reads are given,
+    /// how well it describes them
+    #[command(visible_alias = "validate")]
+    Check(CheckArgs),
+    /// Describe the regular expressions and the simplified geometry
+    /// derived from a geometry
+    Describe(DescribeArgs),
+    /// Detect which of several candidate geometries best describes
+    /// the input reads
+    Detect(DetectArgs),
+    /// Simulate read pairs conforming to a geometry
+    Simulate(SimulateArgs),
+    /// Compute transformation statistics without writing any output
+    Stats(StatsArgs),
+    /// Measure the length of the pieces preceding the anchors of a geometry
+    /// and suggest length ranges fitting the input reads
+    Jitter(JitterArgs),
+    /// Measure how many more fragments each number of mismatches allowed in
+    /// the anchors of a geometry recovers, and how many false matches it
+    /// lets in, and suggest the number to allow
+    Calibrate(CalibrateArgs),
+    /// Show the first fragments of the input reads next to their
+    /// transformation, along with the statistics of the first fragments
+    Preview(PreviewArgs),
+    /// Check the outputs of a transformation against its inputs: that each
+    /// output record comes from the input fragment of its name, that its
+    /// captured pieces are found in the input reads, and that they are
+    /// padded correctly
+    Verify(VerifyArgs),
+    /// Route the fragments of a multi-modal pool (e.g. gene expression and
+    /// antibody-derived tags) to the outputs of the modality whose geometry
+    /// they match, reading the inputs once
+    Route(RouteArgs),
+    /// Serve the transformation over HTTP: interleaved reads POSTed to
+    /// /xform are streamed back transformed, and /stats reports the
+    /// statistics of the requests served
+    #[cfg(feature = "serve")]
+    Serve(ServeArgs),
+}
+
+#[derive(Args, Debug)]
+struct GeomArgs {
     /// Expected input read geometry specification
     #[arg(short, long)]
     geom: String,
+}
+
+#[derive(Args, Debug)]
+struct SearchArgs {
+    /// search for the pieces of the geometry anywhere within each read, on
+    /// either strand, rather than matching the whole read (e.g. for Nanopore
+    /// single-cell reads); the geometry should start with a fixed (f) piece
+    #[arg(long)]
+    unanchored: bool,
+
+    /// if a read does not match the geometry, try to match its reverse
+    /// complement before counting the fragment as failing to parse (this is
+    /// always done with --unanchored)
+    #[arg(long)]
+    try_revcomp: bool,
+
+    /// the number of mismatches allowed in each fixed (f) piece of the
+    /// geometry when a fragment does not match it exactly (at most 3)
+    #[arg(long, default_value_t = 0, conflicts_with = "unanchored")]
+    anchor_mismatches: u32,
+
+    /// let an N in the reads match any base of the fixed (f) pieces of the
+    /// geometry (whose IUPAC codes, such as R in f[CAGRGC], always match the
+    /// bases they stand for)
+    #[arg(long)]
+    n_tolerant_anchors: bool,
 
-    /// read 1 files, comma delimited
+    /// allow the fixed (f) pieces of the geometry to be found up to this many
+    /// bases after their expected position (e.g. after a linker of unknown
+    /// length); the bases skipped are discarded
+    #[arg(long, conflicts_with = "unanchored")]
+    search_anchors: Option<u32>,
+
+    /// only search for this fixed (f) piece with --search-anchors, rather than
+    /// for all of them (may be given multiple times)
+    #[arg(long, requires = "search_anchors")]
+    search_anchor: Vec<String>,
+
+    /// also accept these characters, besides ACGTN, in the pieces of the
+    /// geometry other than its fixed (f) pieces (e.g. U for direct RNA reads,
+    /// or IUPAC codes such as RYKM)
+    #[arg(long)]
+    extra_bases: Option<String>,
+
+    /// the length to which the variable length pieces (e.g. b[9-10]) are
+    /// padded: h+1 (a distinct padding for each length) or h (padded with
+    /// As, with the captured lengths recorded as a lens= tag in the headers)
+    #[arg(long, default_value_t = PaddingScheme::Distinct)]
+    padding: PaddingScheme,
+
+    /// a regex matched against the name of read 1 of each fragment, whose
+    /// capture groups are appended as the pieces given with --name-pieces to
+    /// the transformed reads (e.g. '^\S+:(\d+):' to capture the lane)
+    #[arg(long, requires = "name_pieces")]
+    name_regex: Option<String>,
+
+    /// the pieces made of the capture groups of --name-regex, one per group
+    /// (e.g. b[2]b[8]); nucleotides are copied, and numbers are written in
+    /// base 4 with the digits ACGT
+    #[arg(long, requires = "name_regex")]
+    name_pieces: Option<String>,
+
+    /// the read (1 or 2) to which the pieces captured from the read names are
+    /// appended
+    #[arg(long, default_value_t = 1, requires = "name_regex")]
+    name_read: usize,
+
+    /// transform a captured piece of the geometry, written as
+    /// <read>.<piece>:<op>[,<op>...] where <piece> is the (1-based) position
+    /// of the piece in its read, and each <op> is revcomp, reverse,
+    /// complement, mask, trim-left=<n> or trim-right=<n> (e.g.
+    /// 1.1:revcomp); may be given multiple times
+    #[arg(long, conflicts_with = "search_anchors")]
+    piece_rule: Vec<PieceRule>,
+}
+
+#[derive(Args, Debug)]
+struct InputArgs {
+    /// read 1 files, comma delimited, or unaligned BAM (.bam, .ubam) or CRAM
+    /// (.cram) files holding both reads; one of the read 1 or read 2 files
+    /// may be - to read it from the standard input
     #[arg(short = '1', long, value_delimiter = ',')]
     read1: Vec<PathBuf>,
 
-    /// read 2 files, comma delimited
+    /// read 2 files, comma delimited; one of the read 1 or read 2 files may
+    /// be - to read it from the standard input
     #[arg(short = '2', long, value_delimiter = ',')]
     read2: Vec<PathBuf>,
 
-    /// where output r1 should be written (currently uncompressed)
+    /// read the read 1 files as unaligned BAM (or CRAM) files holding both
+    /// reads of each fragment, whatever their extension (e.g. when reading
+    /// BAM from the standard input)
+    #[arg(long, conflicts_with = "read2")]
+    from_bam: bool,
+
+    /// the command decoding ORA compressed (.ora) input files to its standard
+    /// output, to which the path of each file is appended [default: orad -c]
+    #[arg(long)]
+    ora_decoder: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// where output r1 should be written; may be omitted if the
+    /// geometry discards all of read 1
     #[arg(short = 'o', long)]
-    out1: PathBuf,
+    out1: Option<PathBuf>,
 
-    /// where output r2 should be written (currently uncompressed)
+    /// where output r2 should be written; may be omitted if the
+    /// geometry discards all of read 2
     #[arg(short = 'w', long)]
-    out2: PathBuf,
+    out2: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ReportArgs {
+    /// write the key statistics of the run as a single-row TSV
+    /// (with a header) to this file
+    #[arg(long)]
+    stats_tsv: Option<PathBuf>,
+    /// write the statistics of the run, with the number of fragments
+    /// failing to parse for each read, piece and cause, as JSON to this file
+    #[arg(long)]
+    stats_json: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct SampleArgs {
+    /// the maximum number of fragments to examine
+    #[arg(short = 'n', long, default_value_t = 10_000)]
+    max_fragments: u64,
+}
+
+#[derive(Args, Debug)]
+struct XformArgs {
+    /// Expected input read geometry specification; may instead be given
+    /// once per pair of input files, to use a different geometry for each
+    /// pair (all of which must have the same simplified geometry)
+    #[arg(short, long, required_unless_present = "chemistry")]
+    geom: Vec<String>,
+
+    /// use the geometry of this chemistry rather than giving it with --geom
+    /// (one of sciseq3, 10xv2, 10xv3, splitseq, indrop or dropseq)
+    #[arg(long, conflicts_with = "geom")]
+    chemistry: Option<String>,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+
+    /// write the outputs that are not given explicitly into this directory,
+    /// with a fixed layout: xformed_R1.fa and xformed_R2.fa (with the
+    /// extensions of the output format and compression), stats.json,
+    /// unmatched_R1.fq and unmatched_R2.fq (the fragments failing to parse,
+    /// as they were read) and run_info.json (the command line, inputs,
+    /// outputs and outcome of the run)
+    #[arg(long)]
+    outdir: Option<PathBuf>,
+
+    /// what to do if run_info.json in the --outdir directory records a
+    /// successful run with the same version, options, geometry and inputs
+    /// (unmodified since) whose outputs are all still there: run again
+    /// (rerun), leave the outputs as they are and succeed (skip), or fail
+    #[arg(long, requires = "outdir", default_value_t = IfDone::Rerun)]
+    if_done: IfDone,
+
+    /// write the read 1 records of the fragments that fail to parse, as they
+    /// were read, to this file (compressed as implied by its extension)
+    #[arg(long)]
+    unmatched1: Option<PathBuf>,
+
+    /// write the read 2 records of the fragments that fail to parse, as they
+    /// were read, to this file (compressed as implied by its extension)
+    #[arg(long)]
+    unmatched2: Option<PathBuf>,
+
+    /// fail if the read 1 and read 2 records of a fragment have different
+    /// names (ignoring /1 and /2 suffixes), or if one of the inputs holds more
+    /// records than the other, rather than warning about it
+    #[arg(long)]
+    strict_pairing: bool,
+
+    /// what to do with a malformed FASTQ record: abort the run, or drop the
+    /// fragment holding it and carry on, either with a warning for each
+    /// (skip) or only counting them (count)
+    #[arg(long, default_value_t = OnError::Abort)]
+    on_error: OnError,
+
+    /// index read 1 (I1) files, comma delimited, one per read 1 file; these
+    /// are read 3 of the geometry (e.g. 1{b[16]u[12]x:}2{r:}3{b[8]})
+    #[arg(long, value_delimiter = ',')]
+    index1: Vec<PathBuf>,
+
+    /// index read 2 (I2) files, comma delimited, one per read 1 file; these
+    /// are read 4 of the geometry
+    #[arg(long, value_delimiter = ',')]
+    index2: Vec<PathBuf>,
+
+    /// where the transformed index read 1 (read 3) should be written
+    /// (compressed as implied by its extension)
+    #[arg(long)]
+    out3: Option<PathBuf>,
+
+    /// where the transformed index read 2 (read 4) should be written
+    /// (compressed as implied by its extension)
+    #[arg(long)]
+    out4: Option<PathBuf>,
+
+    /// the number of threads matching the reads against the geometry; the
+    /// output is the same, and in the same order, whatever the number
+    #[arg(short = 't', long, default_value_t = 1)]
+    threads: usize,
+
+    /// the framing of the output records: fasta, fastq (with a quality of I
+    /// for every base, and # for the padding) or raw (two lines per record: the read name followed
+    /// by the tab separated tags, and the sequence)
+    #[arg(long, default_value_t = OutputFormat::Fasta)]
+    output_format: OutputFormat,
+
+    /// name the output records after this template rather than after the
+    /// input headers, with the fields {name}, {comment}, {header}, {index},
+    /// {read}, {bc} and {umi} (e.g. '{name}_{bc}_{umi}'), or after one of
+    /// the templates name, umi-tools or bcl2fastq
+    #[arg(long)]
+    id_format: Option<IdFormat>,
+
+    /// compression of both outputs, as codec[:level] where codec is one of
+    /// none, gzip, bgzf or zstd (e.g. bgzf:9); by default, the compression
+    /// implied by the extension of each output (.gz, .bgz, .zst) is used or,
+    /// failing that, the compression of the corresponding (first) input
+    #[arg(long)]
+    compress: Option<Compression>,
+
+    /// compression of the r1 output, as codec[:level] where codec is
+    /// one of none, gzip, bgzf or zstd (e.g. zstd:19), overriding --compress
+    #[arg(long)]
+    out1_compression: Option<Compression>,
+
+    /// compression of the r2 output, as codec[:level] where codec is
+    /// one of none, gzip, bgzf or zstd (e.g. gzip:1), overriding --compress
+    #[arg(long)]
+    out2_compression: Option<Compression>,
+
+    /// write the (uncompressed) outputs as chunks of records checked by a
+    /// CRC32, so that a consumer reading them from a fifo can detect a
+    /// truncated or corrupted stream; the compression of the inputs is then
+    /// not mirrored into the outputs
+    #[arg(long)]
+    framed: bool,
+
+    /// rotate the output into numbered parts, starting a new part after
+    /// this many fragments; a manifest of the parts is written alongside
+    /// the read 1 output
+    #[arg(long, conflicts_with = "split_output_size")]
+    split_output_every: Option<u64>,
+
+    /// rotate the output into numbered parts, starting a new part once
+    /// an output part reaches this size (e.g. 500M, 50G); a manifest of
+    /// the parts is written alongside the read 1 output
+    #[arg(long, value_parser = parse_byte_size)]
+    split_output_size: Option<u64>,
+
+    /// CSV or TSV file of feature barcodes (e.g. ADTs or HTOs) against which
+    /// the read sequence (r) piece is matched; the name of the matching
+    /// feature is appended to the header of each output record
+    #[arg(long)]
+    feature_ref: Option<PathBuf>,
+
+    /// the maximum number of mismatches allowed when matching feature barcodes
+    #[arg(long, default_value_t = 1, requires = "feature_ref")]
+    feature_mismatches: u32,
+
+    /// write the number of fragments matching each feature as a TSV to this file
+    #[arg(long, requires = "feature_ref")]
+    feature_counts: Option<PathBuf>,
+
+    /// CSV or TSV file of the probes of a targeted panel (e.g. a 10x probe
+    /// set) against which the read sequence (r) piece is matched; the id of
+    /// the matching probe is appended to the headers of the output records
+    #[arg(long)]
+    probe_set: Option<PathBuf>,
+
+    /// the offset of the probe in the read sequence (r) piece
+    #[arg(long, default_value_t = 0, requires = "probe_set")]
+    probe_offset: usize,
+
+    /// the maximum number of mismatches (0 or 1) allowed when matching probes
+    #[arg(long, default_value_t = 1, requires = "probe_set")]
+    probe_mismatches: u32,
+
+    /// FASTA file of control sequences (e.g. ERCC spike-ins or guide
+    /// scaffolds) against which the read sequence (r) piece is matched; the
+    /// name of the matching control is appended to the header of each output
+    /// record
+    #[arg(long)]
+    controls: Option<PathBuf>,
+
+    /// the length of the k-mers a read sequence must share with a control to
+    /// match it
+    #[arg(long, default_value_t = ControlRef::DEFAULT_KMER_LEN, requires = "controls")]
+    control_kmer_len: usize,
+
+    /// write the read 1 records of the fragments matching a control to this
+    /// file (compressed as implied by its extension) rather than to the r1
+    /// output
+    #[arg(long, requires_all = ["controls", "control_out2"])]
+    control_out1: Option<PathBuf>,
+
+    /// write the read 2 records of the fragments matching a control to this
+    /// file (compressed as implied by its extension) rather than to the r2
+    /// output
+    #[arg(long, requires_all = ["controls", "control_out1"])]
+    control_out2: Option<PathBuf>,
+
+    /// write the number of fragments matching each control as a TSV to this file
+    #[arg(long, requires = "controls")]
+    control_counts: Option<PathBuf>,
+
+    /// CSV or TSV file of the barcodes of the spots of a spatial array and
+    /// their x and y coordinates (e.g. Slide-seq bead locations or Visium
+    /// tissue positions), in which the barcode of each fragment is looked up
+    #[arg(long)]
+    spatial_coords: Option<PathBuf>,
+
+    /// append the coordinates of the spot of each fragment to the headers of
+    /// its records (as x=<X> y=<Y>)
+    #[arg(long, requires = "spatial_coords")]
+    spatial_tags: bool,
+
+    /// write the name, barcode and coordinates of each fragment placed on the
+    /// array as a TSV to this file
+    #[arg(long, requires = "spatial_coords")]
+    spatial_sidecar: Option<PathBuf>,
+
+    /// CSV or TSV sample sheet mapping sample names to barcodes; each
+    /// fragment is written to the outputs of the sample its barcode
+    /// identifies (named after the main outputs, e.g. r1.A01.fq for sample
+    /// A01), and the fragments of no sample to the main outputs
+    #[arg(long)]
+    samples: Option<PathBuf>,
+
+    /// the barcode (b) piece identifying the sample, as its position among
+    /// the barcode pieces of the geometry (those of read 1 first)
+    #[arg(long, default_value_t = 1, requires = "samples")]
+    demux_piece: usize,
+
+    /// the maximum number of mismatches allowed when matching sample barcodes
+    #[arg(long, default_value_t = 1, requires = "samples")]
+    demux_mismatches: u32,
+
+    /// write the number of fragments of each sample as a TSV to this file
+    #[arg(long, requires = "samples")]
+    demux_counts: Option<PathBuf>,
+
+    /// detect fragments that duplicate an earlier fragment (same barcodes, UMIs
+    /// and read sequence prefix), and either flag them in the record header
+    /// (flag) or leave them out of the output (drop)
+    #[arg(long)]
+    dedup: Option<DedupMode>,
+
+    /// the number of bases of the read sequence compared when detecting duplicates
+    #[arg(long, default_value_t = DedupOptions::DEFAULT_PREFIX_LEN, requires = "dedup")]
+    dedup_prefix_len: usize,
+
+    /// the memory used to record the fragments seen when detecting duplicates
+    /// (e.g. 1G); false positives become more frequent as the number of
+    /// fragments approaches one per byte
+    #[arg(long, value_parser = parse_byte_size, requires = "dedup")]
+    dedup_memory: Option<u64>,
+
+    /// correct UMI sequencing errors by clustering the UMIs of each barcode
+    /// (with the directional method of UMI-tools) within windows of fragments
+    #[arg(long)]
+    correct_umis: bool,
+
+    /// the number of fragments within which UMIs are clustered
+    #[arg(long, default_value_t = UmiOptions::DEFAULT_WINDOW, requires = "correct_umis")]
+    umi_correction_window: usize,
+
+    /// write the number of fragments observed with each (transformed) barcode
+    /// as a TSV to this file; if no file is given (or if only --unfiltered-pl
+    /// is), the counts are written to counts.tsv alongside the read 1 output
+    #[arg(long, num_args = 0..=1)]
+    barcode_counts: Option<Option<PathBuf>>,
+
+    /// write the observed (transformed) barcodes to this file, one per line,
+    /// for use as the unfiltered permit list of alevin-fry
+    /// (`generate-permit-list --unfiltered-pl`)
+    #[arg(long)]
+    unfiltered_pl: Option<PathBuf>,
+
+    /// file of permitted barcodes, one per line (optionally gzipped), against
+    /// which the (transformed) barcodes are corrected within 1 mismatch; the
+    /// fragments whose barcode cannot be corrected fail to parse
+    #[arg(long)]
+    whitelist: Option<PathBuf>,
+
+    /// compare the barcodes against the whitelist by their captured
+    /// (unpadded) sequence, only ever correcting a barcode to one of the same
+    /// length, so that the whitelist may hold barcodes of several lengths
+    /// (e.g. the 9 and 10 base RT barcodes of sci-RNA-seq3)
+    #[arg(long, requires = "whitelist")]
+    length_aware_whitelist: bool,
+
+    /// keep the whitelist barcodes sorted in a temporary file in this
+    /// directory rather than in memory, for whitelists of hundreds of
+    /// millions of barcodes (which must then all have the same length, of at
+    /// most 32 bases)
+    #[arg(long, requires = "whitelist")]
+    whitelist_tmp_dir: Option<PathBuf>,
+
+    /// how the barcodes are corrected against the whitelist: exact (no
+    /// correction), 1mm (within 1 mismatch of a single whitelist barcode),
+    /// frequency:<counts.tsv> (within 1 mismatch, to the barcode most
+    /// frequent in a barcode/count TSV such as that of --barcode-counts) or
+    /// external:<command> (by a program answering each barcode on its
+    /// standard input with the corrected barcode, or -, on its output)
+    #[arg(long, requires = "whitelist", default_value_t = CorrectionStrategy::OneMismatch)]
+    barcode_correction: CorrectionStrategy,
+
+    /// cross-check the barcodes captured from read 1 against those captured
+    /// from read 2 (for chemistries repeating the barcode on both reads), and
+    /// either flag the fragments whose barcodes differ in the record headers
+    /// (flag) or leave them out of the output (drop)
+    #[arg(long)]
+    check_barcodes: Option<ConsistencyMode>,
+
+    /// the number of mismatches allowed between the read 1 and read 2
+    /// barcodes of a fragment
+    #[arg(long, default_value_t = 0, requires = "check_barcodes")]
+    check_barcodes_mismatches: u32,
+
+    /// the sequence immediately upstream of the guide protospacer in the read
+    /// sequence (r) piece (e.g. CACCG); the read sequence of each output
+    /// record is replaced by the protospacer
+    #[arg(long)]
+    guide_upstream: Option<String>,
+
+    /// the sequence immediately downstream of the guide protospacer in the
+    /// read sequence (r) piece (e.g. the start of the scaffold, GTTTTAGA)
+    #[arg(long)]
+    guide_downstream: Option<String>,
+
+    /// the length of the guide protospacer, required unless both
+    /// --guide-upstream and --guide-downstream are given
+    #[arg(long)]
+    guide_len: Option<usize>,
+
+    /// the number of mismatches allowed in each of the guide anchors
+    #[arg(long, default_value_t = 1)]
+    guide_mismatches: u32,
+
+    /// write the read 1 and read 2 records of each fragment one after the
+    /// other into this single output (e.g. a fifo read by a tool accepting
+    /// interleaved pairs), rather than into --out1 and --out2
+    #[arg(long, conflicts_with_all = ["out1", "out2"])]
+    interleaved_out: Option<PathBuf>,
+
+    /// write the transformed fragments as unaligned BAM to this file, rather
+    /// than into --out1 and --out2: the read sequence (r) piece becomes the
+    /// sequence of each record, and the barcode and UMI are stored in the CR
+    /// and CB, and UR and UB tags (e.g. for STARsolo)
+    #[arg(long, conflicts_with_all = ["out1", "out2", "interleaved_out", "split_output_every", "split_output_size", "offset_index"])]
+    bam: Option<PathBuf>,
+
+    /// write the transformed fragments sorted by (corrected) barcode rather
+    /// than in the order of the input, sorting chunks of fragments in memory
+    /// and merging them from temporary files
+    #[arg(long)]
+    sort_by_barcode: bool,
+
+    /// the number of fragments sorted in memory when sorting by barcode
+    #[arg(long, default_value_t = SortOptions::DEFAULT_CHUNK_SIZE, requires = "sort_by_barcode")]
+    sort_chunk_size: usize,
+
+    /// the directory holding the temporary files when sorting by barcode
+    /// (by default, the system temporary directory)
+    #[arg(long, requires = "sort_by_barcode")]
+    sort_tmp_dir: Option<PathBuf>,
+
+    /// write an index of the offsets of the records in the (uncompressed or
+    /// bgzf compressed) outputs next to the read 1 output (`<OFILE1>.idx.tsv`),
+    /// holding the offsets of the first record of each barcode when sorting
+    /// by barcode
+    #[arg(long)]
+    offset_index: bool,
+
+    /// the number of records between the entries of the offset index
+    #[arg(long, default_value_t = OffsetIndexOptions::DEFAULT_EVERY, requires = "offset_index")]
+    offset_index_every: u64,
+
+    /// retry reads and writes failing with a transient error (e.g. EIO or
+    /// ESTALE on NFS) up to this many times, rather than failing the run
+    #[arg(long)]
+    io_retries: Option<u32>,
+
+    /// the delay (in milliseconds) before the first retry of a failing read
+    /// or write, which doubles with every further retry
+    #[arg(long, default_value_t = RetryOptions::DEFAULT_BACKOFF.as_millis() as u64, requires = "io_retries")]
+    io_retry_backoff: u64,
+
+    /// before transforming the reads, measure the length of the pieces
+    /// preceding the anchors of the geometry in this many fragments, and
+    /// adjust their length ranges to fit them (see the jitter subcommand)
+    #[arg(long)]
+    adjust_ranges: Option<u64>,
+
+    /// before transforming the reads, match this many fragments against the
+    /// geometry allowing 0 to 2 mismatches in its anchors, and allow the
+    /// number of mismatches chosen under --max-false-fraction (see the
+    /// calibrate subcommand)
+    #[arg(long, conflicts_with_all = ["anchor_mismatches", "unanchored"])]
+    calibrate_mismatches: Option<u64>,
+
+    #[command(flatten)]
+    budget: BudgetArgs,
+
+    /// only transform the first N fragments, and show the first of them next
+    /// to their transformation, to check the geometry before a long run
+    #[arg(long, value_name = "N")]
+    preview: Option<u64>,
+
+    /// before transforming the reads, read them once to find the longest
+    /// length of each variable length piece, and pad the pieces to that
+    /// length rather than to the upper bound of their declared range (the
+    /// inputs must be regular files, as they are read twice)
+    #[arg(long)]
+    pad_to_observed: bool,
+
+    /// before transforming the reads, match this many fragments against the
+    /// geometry with their read 1 and read 2 exchanged, and warn if they fit
+    /// it far better that way (i.e. if the -1 and -2 inputs look swapped);
+    /// 0 disables the check, which is skipped unless the inputs are regular
+    /// files
+    #[arg(long, default_value_t = 10_000)]
+    swap_check: u64,
+
+    /// exchange the read 1 and read 2 inputs if they look swapped (see
+    /// --swap-check)
+    #[arg(long)]
+    auto_swap: bool,
+
+    /// log the number of fragments transformed so far, the throughput and
+    /// the estimated time remaining every this many fragments
+    #[arg(long)]
+    progress_every: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+struct CheckArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    sample: SampleArgs,
+}
+
+#[derive(Args, Debug)]
+struct DescribeArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    /// print the description as JSON (the geometry, the regular expression
+    /// of each read, the simplified geometry, the padding and the length of
+    /// each transformed read, null if it varies), for pipeline tools
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct DetectArgs {
+    /// candidate geometry specifications (may be given multiple times)
+    #[arg(short, long, required = true)]
+    geom: Vec<String>,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    sample: SampleArgs,
+}
+
+#[derive(Args, Debug)]
+struct SimulateArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// the number of fragments to simulate
+    #[arg(short = 'n', long, default_value_t = 1_000)]
+    num_fragments: usize,
+
+    /// the length of the sequence generated for unbounded pieces
+    #[arg(long, default_value_t = 50)]
+    unbounded_len: u32,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+
+    /// only examine (at most) this many fragments
+    #[arg(short = 'n', long)]
+    max_fragments: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+struct BudgetArgs {
+    /// the largest fraction of the fragments recovered by each additional
+    /// mismatch allowed in the anchors that may be estimated to be false
+    /// matches for that mismatch to be allowed
+    #[arg(long, default_value_t = calibrate::DEFAULT_MAX_FALSE_FRACTION)]
+    max_false_fraction: f64,
+}
+
+#[derive(Args, Debug)]
+struct CalibrateArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    /// (--anchor-mismatches is ignored, as each number of mismatches is tried)
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    sample: SampleArgs,
+
+    #[command(flatten)]
+    budget: BudgetArgs,
+}
+
+#[derive(Args, Debug)]
+struct PreviewArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    sample: SampleArgs,
+
+    /// the number of fragments shown next to their transformation
+    #[arg(long, default_value_t = 5)]
+    show: usize,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    /// (only the padding of the simplified geometry matters)
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// the outputs of the transformation of the inputs
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// the number of mismatches allowed between each captured piece and the
+    /// input read (e.g. for barcodes corrected against a permit list)
+    #[arg(long, default_value_t = 0)]
+    max_mismatches: u32,
 }
 
-fn process_reads(args: Args) -> Result<()> {
-    let gd = args.geom;
-    let geo = FragmentGeomDesc::try_from(gd.as_str()).unwrap();
+#[derive(Args, Debug)]
+struct RouteArgs {
+    /// a modality of the pool, as NAME=GEOMETRY (e.g. ADT=1{b[16]u[12]}2{...});
+    /// may be given multiple times, from the most to the least specific
+    /// geometry, as each fragment is routed to the first modality whose
+    /// geometry it matches
+    #[arg(short, long, required = true)]
+    modality: Vec<String>,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
 
-    match geo.as_regex() {
-        Ok(geo_re) => {
-            let start = Instant::now();
+    /// the outputs of each modality are named after these, with the name of
+    /// the modality inserted before their extension (e.g. r1.ADT.fq for
+    /// modality ADT)
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// the framing of the output records: fasta, fastq or raw (as for xform)
+    #[arg(long, default_value_t = OutputFormat::Fasta)]
+    output_format: OutputFormat,
+
+    /// compression of the outputs, as codec[:level] where codec is one of
+    /// none, gzip, bgzf or zstd; by default, the compression implied by the
+    /// extension of the outputs, or failing that that of the inputs
+    #[arg(long)]
+    compress: Option<Compression>,
+
+    /// fail if the read 1 and read 2 records of a fragment have different
+    /// names, or if one of the inputs holds more records than the other
+    #[arg(long)]
+    strict_pairing: bool,
+
+    /// what to do with a malformed FASTQ record: abort, skip or count (as
+    /// for xform)
+    #[arg(long, default_value_t = OnError::Abort)]
+    on_error: OnError,
+
+    /// write the number of fragments routed to each modality as a TSV to
+    /// this file
+    #[arg(long)]
+    counts: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct JitterArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    sample: SampleArgs,
+}
+
+#[cfg(feature = "serve")]
+#[derive(Args, Debug)]
+struct ServeArgs {
+    #[command(flatten)]
+    geom: GeomArgs,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    /// the address on which to listen for requests
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// the number of connections served at once; further connections wait
+    /// until one is done
+    #[arg(long, default_value_t = XformServer::DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+
+    /// drop the connections on which nothing could be read or written for
+    /// this many seconds (0 to never drop them)
+    #[arg(long, default_value_t = XformServer::DEFAULT_TIMEOUT.as_secs())]
+    timeout: u64,
+}
+
+/// The classes of failure given a distinct exit status, so that workflow
+/// managers can branch on them without parsing the logs.  Other failures
+/// exit with status 1, and invalid command lines with status 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// The geometry (or chemistry) is invalid.
+    Geometry = 3,
+    /// An input could not be read, or is malformed.
+    Input = 4,
+    /// An output could not be written.
+    Output = 5,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Failure::Geometry => "the geometry is invalid",
+            Failure::Input => "could not read the input",
+            Failure::Output => "could not write the output",
+        })
+    }
+}
+
+impl Failure {
+    /// The class of the failure `e`: the class it was tagged with, or else
+    /// the class implied by its causes, if any.
+    fn of(e: &anyhow::Error) -> Option<Self> {
+        if let Some(&f) = e.downcast_ref::<Failure>() {
+            return Some(f);
+        }
+        e.chain().find_map(|cause| {
+            if cause.is::<ParseError>() {
+                return Some(Failure::Input);
+            }
+            match cause.downcast_ref::<io::Error>()?.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Some(Failure::Input),
+                io::ErrorKind::StorageFull
+                | io::ErrorKind::ReadOnlyFilesystem
+                | io::ErrorKind::WriteZero => Some(Failure::Output),
+                _ => None,
+            }
+        })
+    }
+
+    /// The exit status of the failure `e`.
+    fn exit_code(e: &anyhow::Error) -> ExitCode {
+        ExitCode::from(Failure::of(e).map_or(1, |f| f as u8))
+    }
+}
+
+fn parse_geom(gd: &str) -> Result<FragmentGeomDesc> {
+    FragmentGeomDesc::try_from(gd).map_err(|e| anyhow!("could not parse geometry {}: {:?}", gd, e))
+}
+
+fn compile_geom(gd: &str, search: &SearchArgs) -> Result<FragmentRegexDesc> {
+    build_geom(gd, search).context(Failure::Geometry)
+}
+
+fn build_geom(gd: &str, search: &SearchArgs) -> Result<FragmentRegexDesc> {
+    // the index reads (3 and 4) are not part of a `FragmentGeomDesc`
+    let (main, index_reads) = index::split_geometry(gd)?;
+    let mut geo = parse_geom(&main)?;
+    if let Some(max_offset) = search.search_anchors {
+        let anchor_search = AnchorSearch {
+            max_offset,
+            anchors: (!search.search_anchor.is_empty()).then(|| search.search_anchor.clone()),
+        };
+        geo = anchor_search
+            .apply(&geo)
+            .with_context(|| format!("could not search for the anchors of geometry {}", gd))?;
+    }
+    let mut opts = RegexOptions {
+        unanchored: search.unanchored,
+        anchor_mismatches: search.anchor_mismatches,
+        n_tolerant_anchors: search.n_tolerant_anchors,
+        piece_rules: search.piece_rule.clone(),
+        ..RegexOptions::default()
+    };
+    if let Some(extra) = &search.extra_bases {
+        opts.extend_alphabet(&extra.to_ascii_uppercase());
+    }
+    let mut geo_re = geo
+        .as_regex_with_options(&opts)
+        .with_context(|| format!("could not convert geometry {} to a regex", gd))?;
+    if search.try_revcomp {
+        geo_re.set_try_revcomp(true);
+    }
+    for (read, pieces) in &index_reads {
+        geo_re.add_index_read(*read, pieces)?;
+    }
+    geo_re.set_padding(search.padding);
+    if let (Some(regex), Some(pieces)) = (&search.name_regex, &search.name_pieces) {
+        geo_re.set_name_capture(NameCapture::from_description(
+            regex,
+            pieces,
+            search.name_read,
+        )?)?;
+    }
+    Ok(geo_re)
+}
+
+/// Returns true if one of the inputs is read from the standard input.
+fn reads_stdin(input: &InputArgs) -> bool {
+    input.read1.iter().chain(&input.read2).any(|p| is_stdin(p))
+}
+
+/// The format of the read 1 files of `input`.
+fn input_format(input: &InputArgs) -> InputFormat {
+    if input.from_bam {
+        InputFormat::Bam
+    } else {
+        InputFormat::FromExtension
+    }
+}
+
+fn check_inputs(input: &InputArgs) -> Result<()> {
+    let from_bam = input.from_bam || input.read1.iter().all(|p| bam::is_bam(p));
+    if input.read1.is_empty() {
+        bail!("at least one read 1 file must be provided");
+    }
+    if input.read2.is_empty() && !from_bam {
+        bail!(
+            "no read 2 file was given, but the read 1 files are not BAM (or CRAM) files \
+             (with the .bam, .ubam or .cram extension, or read with --from-bam)"
+        );
+    }
+    if from_bam && !input.read2.is_empty() {
+        bail!("the BAM (or CRAM) inputs hold both reads, so no read 2 file can be given");
+    }
+    for p in input.read1.iter().chain(&input.read2) {
+        if !is_stdin(p) {
+            File::open(p)
+                .with_context(|| format!("could not open {}", p.display()))
+                .context(Failure::Input)?;
+        }
+    }
+    Ok(())
+}
+
+fn ora_decoder(input: &InputArgs) -> Result<ExternalDecoder> {
+    match &input.ora_decoder {
+        Some(cmd) => ExternalDecoder::from_command_line(cmd),
+        None => Ok(ExternalDecoder::default()),
+    }
+}
+
+/// Creates the report or count file `p`.
+fn create_output(p: &Path) -> Result<BufWriter<File>> {
+    let f = File::create(p)
+        .with_context(|| format!("could not create {}", p.display()))
+        .context(Failure::Output)?;
+    Ok(BufWriter::new(f))
+}
+
+fn write_report(report: &ReportArgs, geom: &str, stats: &XformStats, start: Instant) -> Result<()> {
+    if let Some(p) = &report.stats_tsv {
+        let mut w = create_output(p)?;
+        stats.write_tsv(&mut w, geom, start.elapsed())?;
+        w.flush()?;
+        info!("wrote statistics to {}", p.display());
+    }
+    if let Some(p) = &report.stats_json {
+        let mut w = create_output(p)?;
+        stats.write_json(&mut w, geom, start.elapsed())?;
+        w.flush()?;
+        info!("wrote statistics to {}", p.display());
+    }
+    Ok(())
+}
+
+/// Returns the compression of the output `out`: `given` if it was explicitly
+/// requested, otherwise the compression implied by the extension of `out`,
+/// and otherwise the compression of the `input` it is derived from.  The
+/// input compression is not mirrored into outputs that already exist and are
+/// not regular files (e.g. fifos), which are usually read by programs
+/// expecting uncompressed input.
+fn output_compression(
+    given: Option<Compression>,
+    input: Option<&PathBuf>,
+    out: Option<&PathBuf>,
+) -> Result<Compression> {
+    if let Some(c) = given {
+        return Ok(c);
+    }
+    if let Some(c) = out.and_then(|o| compression_from_extension(o)) {
+        return Ok(c);
+    }
+    let (Some(input), Some(out)) = (input, out) else {
+        return Ok(Compression::None);
+    };
+    if std::fs::metadata(out).is_ok_and(|m| !m.is_file()) {
+        return Ok(Compression::None);
+    }
+    let c = detect_compression(input)?;
+    if c != Compression::None {
+        info!(
+            "writing {} with the compression of {} ({})",
+            out.display(),
+            input.display(),
+            c
+        );
+    }
+    Ok(c)
+}
+
+/// The options locating the guides, if an anchor was given.
+fn guide_options(args: &XformArgs) -> Result<Option<GuideOptions>> {
+    let anchor = |a: &Option<String>| {
+        a.as_deref()
+            .map(|a| a.to_ascii_uppercase().into_bytes())
+            .unwrap_or_default()
+    };
+    if args.guide_upstream.is_none() && args.guide_downstream.is_none() {
+        if args.guide_len.is_some() {
+            bail!("--guide-len requires --guide-upstream or --guide-downstream");
+        }
+        return Ok(None);
+    }
+    Ok(Some(GuideOptions {
+        upstream: anchor(&args.guide_upstream),
+        downstream: anchor(&args.guide_downstream),
+        guide_len: args.guide_len,
+        max_mismatches: args.guide_mismatches,
+    }))
+}
+
+/// Returns the file to which the barcode counts are written, if barcodes
+/// are counted: the one given with `--barcode-counts` or, by default,
+/// `counts.tsv` in the directory of the read 1 (or, if it is omitted, of the
+/// read 2 or BAM) output.
+fn barcode_counts_path(args: &XformArgs) -> Option<PathBuf> {
+    if let Some(Some(p)) = &args.barcode_counts {
+        return Some(p.clone());
+    }
+    if args.barcode_counts.is_none() && args.unfiltered_pl.is_none() {
+        return None;
+    }
+    let out = args
+        .output
+        .out1
+        .as_ref()
+        .or(args.output.out2.as_ref())
+        .or(args.bam.as_ref());
+    let dir = out.and_then(|o| o.parent()).unwrap_or(Path::new(""));
+    Some(dir.join("counts.tsv"))
+}
+
+/// The extension conventionally given to the records of the input `p` as
+/// they were read: `fa` if its name (without any compression extension)
+/// ends with a `FASTA` extension, and `fq` otherwise.
+fn input_format_extension(p: &Path) -> &'static str {
+    let name = p
+        .file_name()
+        .map(|n| n.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let name = [".gz", ".bgz", ".zst", ".ora"]
+        .iter()
+        .find_map(|e| name.strip_suffix(e))
+        .unwrap_or(&name);
+    if [".fa", ".fasta", ".fna"].iter().any(|e| name.ends_with(e)) {
+        "fa"
+    } else {
+        "fq"
+    }
+}
+
+/// Fills in the outputs that were not given explicitly with their location
+/// in the output directory `dir` (see `--outdir`): the transformed reads
+/// (unless they are written interleaved or as BAM), the statistics and the
+/// fragments failing to parse, named with the extensions of their format
+/// and of their compression (by default, that of the inputs).
+fn apply_outdir(args: &mut XformArgs, geo_re: &FragmentRegexDesc, dir: &Path) -> Result<()> {
+    let path = |name: String, c: Compression| match c.extension() {
+        Some(ext) => dir.join(format!("{}.{}", name, ext)),
+        None => dir.join(name),
+    };
+    let mirrored = |given: Option<Compression>, input: Option<&PathBuf>| -> Result<Compression> {
+        Ok(match (given, input) {
+            (Some(c), _) => c,
+            (None, Some(p)) if !is_stdin(p) => detect_compression(p)?,
+            _ => Compression::None,
+        })
+    };
+    let (read1, read2) = (args.input.read1.first(), args.input.read2.first());
+    let ext = args.output_format.extension();
+    if args.interleaved_out.is_none() && args.bam.is_none() {
+        if args.output.out1.is_none() && !geo_re.discards_read1() {
+            let c = mirrored(args.out1_compression.or(args.compress), read1)?;
+            args.output.out1 = Some(path(format!("xformed_R1.{}", ext), c));
+        }
+        if args.output.out2.is_none() && !geo_re.discards_read2() {
+            let c = mirrored(args.out2_compression.or(args.compress), read2.or(read1))?;
+            args.output.out2 = Some(path(format!("xformed_R2.{}", ext), c));
+        }
+    }
+    if args.report.stats_json.is_none() {
+        args.report.stats_json = Some(dir.join("stats.json"));
+    }
+    if args.unmatched1.is_none() && args.unmatched2.is_none() {
+        let ext = read1.map_or("fq", |p| input_format_extension(p));
+        let c = mirrored(None, read1)?;
+        args.unmatched1 = Some(path(format!("unmatched_R1.{}", ext), c));
+        args.unmatched2 = Some(path(format!("unmatched_R2.{}", ext), c));
+    }
+    Ok(())
+}
+
+/// The outputs of a run with the arguments `args` that are written, with
+/// the names they are listed under in `run_info.json`.
+fn output_paths(args: &XformArgs) -> Vec<(&'static str, PathBuf)> {
+    [
+        ("out1", &args.output.out1),
+        ("out2", &args.output.out2),
+        ("interleaved", &args.interleaved_out),
+        ("bam", &args.bam),
+        ("stats_json", &args.report.stats_json),
+        ("stats_tsv", &args.report.stats_tsv),
+        ("unmatched1", &args.unmatched1),
+        ("unmatched2", &args.unmatched2),
+        ("out3", &args.out3),
+        ("out4", &args.out4),
+        ("feature_counts", &args.feature_counts),
+        ("control_counts", &args.control_counts),
+        ("demux_counts", &args.demux_counts),
+        ("barcode_counts", &barcode_counts_path(args)),
+        ("unfiltered_pl", &args.unfiltered_pl),
+    ]
+    .into_iter()
+    .filter_map(|(name, p)| Some((name, p.clone()?)))
+    .collect()
+}
+
+/// Checks that the directory of each output of `args` exists, so that a
+/// missing one is reported (as an output failure) before any read is read.
+fn check_outputs(args: &XformArgs) -> Result<()> {
+    for (_, p) in output_paths(args) {
+        let dir = p.parent().filter(|d| !d.as_os_str().is_empty());
+        if dir.is_some_and(|d| !d.is_dir()) {
+            return Err(anyhow!(
+                "the directory of the output {} does not exist",
+                p.display()
+            ))
+            .context(Failure::Output);
+        }
+    }
+    Ok(())
+}
+
+/// Writes the description of a run with the arguments `args` started at
+/// `started`, and of its outcome, as JSON to `p`.
+fn write_run_info(
+    p: &Path,
+    args: &XformArgs,
+    seed: u64,
+    started: SystemTime,
+    fingerprint: Option<&str>,
+    outcome: &Result<()>,
+) -> Result<()> {
+    let json_list = |out: &mut String, items: &mut dyn Iterator<Item = String>| {
+        out.push('[');
+        for (i, item) in items.enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            push_json_string(out, &item);
+        }
+        out.push(']');
+    };
+    let paths = |ps: &[PathBuf]| {
+        ps.iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+    };
+    let mut out = String::from("{\n  \"version\": ");
+    push_json_string(&mut out, env!("CARGO_PKG_VERSION"));
+    out += ",\n  \"command_line\": ";
+    json_list(
+        &mut out,
+        &mut std::env::args_os().map(|a| a.to_string_lossy().into_owned()),
+    );
+    out += ",\n  \"geometry\": ";
+    push_json_string(&mut out, &args.geom.join(";"));
+    out += ",\n  \"fingerprint\": ";
+    match fingerprint {
+        Some(f) => push_json_string(&mut out, f),
+        None => out += "null",
+    }
+    out += &format!(
+        ",\n  \"seed\": {},\n  \"started\": {},\n  \"runtime_secs\": {:.3}",
+        seed,
+        started
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        started.elapsed().unwrap_or_default().as_secs_f64()
+    );
+    out += ",\n  \"inputs\": {\"read1\": ";
+    json_list(&mut out, &mut paths(&args.input.read1).into_iter());
+    out += ", \"read2\": ";
+    json_list(&mut out, &mut paths(&args.input.read2).into_iter());
+    out += "},\n  \"outputs\": {";
+    let outputs = output_paths(args);
+    let mut first = true;
+    for (name, p) in outputs {
+        out += if first { "\n    " } else { ",\n    " };
+        first = false;
+        push_json_string(&mut out, name);
+        out += ": ";
+        push_json_string(&mut out, &p.display().to_string());
+    }
+    out += if first { "}" } else { "\n  }" };
+    match outcome {
+        Ok(()) => out += ",\n  \"status\": \"succeeded\",\n  \"exit_code\": 0,\n  \"error\": null",
+        Err(e) => {
+            out += ",\n  \"status\": \"failed\",\n  \"exit_code\": ";
+            out += &Failure::of(e).map_or(1, |f| f as u8).to_string();
+            out += ",\n  \"error\": ";
+            push_json_string(&mut out, &format!("{:#}", e));
+        }
+    }
+    out += "\n}\n";
+    let mut w = create_output(p)?;
+    w.write_all(out.as_bytes()).context(Failure::Output)?;
+    w.flush().context(Failure::Output)?;
+    info!("wrote the description of the run to {}", p.display());
+    Ok(())
+}
+
+/// Warns if the read 1 and read 2 inputs look swapped (see the `swap` module),
+/// exchanging them with --auto-swap.  The check reads the start of the
+/// inputs, so it is skipped unless they are all regular files.
+fn check_swap(args: &mut XformArgs) -> Result<()> {
+    if args.geom.len() != 1 {
+        info!("not checking for swapped inputs, as each pair of input files has its own geometry");
+        return Ok(());
+    }
+    let input = &args.input;
+    if input.read2.is_empty()
+        || input
+            .read1
+            .iter()
+            .chain(&input.read2)
+            .any(|p| !std::fs::metadata(p).is_ok_and(|m| m.is_file()))
+    {
+        info!("not checking for swapped inputs, as they are not all regular files");
+        return Ok(());
+    }
+    let mut geo_re = compile_geom(&args.geom[0], &args.search)?;
+    let report = swap::detect_swap(
+        &mut geo_re,
+        &input.read1,
+        &input.read2,
+        args.swap_check,
+        args.on_error,
+        input_format(input),
+        &ora_decoder(input)?,
+    )?;
+    if !report.is_swapped() {
+        return Ok(());
+    }
+    warn!(
+        "THE READ 1 AND READ 2 INPUTS LOOK SWAPPED: {:.1}% of the first {} fragments match the geometry {} \
+         with their reads exchanged, but only {:.1}% as given",
+        100.0 * report.swapped_match_rate(),
+        report.fragments,
+        args.geom[0],
+        100.0 * report.match_rate()
+    );
+    if args.auto_swap {
+        std::mem::swap(&mut args.input.read1, &mut args.input.read2);
+        warn!("exchanged the read 1 and read 2 inputs (--auto-swap)");
+    } else {
+        warn!("check the order of the -1 and -2 inputs, or exchange them with --auto-swap");
+    }
+    Ok(())
+}
+
+fn process_reads(mut args: XformArgs, seed: u64) -> Result<()> {
+    let Some(dir) = args.outdir.clone() else {
+        return transform_reads(&mut args, seed);
+    };
+    let started = SystemTime::now();
+    let run_info = dir.join("run_info.json");
+    // the policy is left out of the fingerprint, so that a run may be
+    // skipped whatever the policy of the run that completed it
+    let if_done = std::mem::take(&mut args.if_done);
+    let fingerprint = run_fingerprint(&args, seed);
+    if if_done != IfDone::Rerun {
+        match &fingerprint {
+            None => {
+                info!("not looking for a completed run, as the inputs are not all regular files")
+            }
+            Some(f) if RecordedRun::read(&run_info)?.is_some_and(|r| r.is_complete(f)) => {
+                if if_done == IfDone::Fail {
+                    bail!(
+                        "{} records a completed run with the same inputs and options (--if-done fail)",
+                        run_info.display()
+                    );
+                }
+                info!(
+                    "skipping the run, as {} records a completed run with the same inputs and options",
+                    run_info.display()
+                );
+                return Ok(());
+            }
+            Some(_) => {}
+        }
+    }
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("could not create the output directory {}", dir.display()))
+        .context(Failure::Output)?;
+    let outcome = transform_reads(&mut args, seed);
+    write_run_info(
+        &run_info,
+        &args,
+        seed,
+        started,
+        fingerprint.as_deref(),
+        &outcome,
+    )?;
+    outcome
+}
+
+/// The fingerprint of the run with the arguments `args` and the seed `seed`
+/// (see the `provenance` module), before any of its options is adjusted.
+fn run_fingerprint(args: &XformArgs, seed: u64) -> Option<String> {
+    let options = format!("{} seed={} {:?}", env!("CARGO_PKG_VERSION"), seed, args);
+    let input = &args.input;
+    let inputs: Vec<PathBuf> = [&input.read1, &input.read2, &args.index1, &args.index2]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    provenance::fingerprint(&options, &inputs)
+}
+
+fn transform_reads(args: &mut XformArgs, seed: u64) -> Result<()> {
+    check_inputs(&args.input)?;
+    if let Some(name) = &args.chemistry {
+        let chem = presets::find(name).context(Failure::Geometry)?;
+        info!("using the {} geometry {}", chem.name, chem.geometry);
+        args.geom = vec![chem.geometry.to_owned()];
+    }
+    if args.swap_check > 0 {
+        check_swap(args)?;
+    } else if args.auto_swap {
+        bail!("--auto-swap requires the check for swapped inputs, which --swap-check 0 disables");
+    }
+    if let Some(n) = args.adjust_ranges {
+        if args.geom.len() != 1 {
+            bail!("--adjust-ranges cannot be used with a geometry per pair of input files");
+        }
+        check_inputs(&args.input)?;
+        if reads_stdin(&args.input) {
+            bail!("--adjust-ranges reads the start of the inputs twice, but the standard input can only be read once");
+        }
+        let (main, index_reads) = index::split_geometry(&args.geom[0])?;
+        let geo = parse_geom(&main)?;
+        let report = jitter::measure_jitter(
+            &geo,
+            &args.input.read1,
+            &args.input.read2,
+            n,
+            args.on_error,
+            input_format(&args.input),
+            &ora_decoder(&args.input)?,
+        )?;
+        let mut adjusted = jitter::geometry_string(&report.adjusted_geometry(&geo));
+        for (read, pieces) in &index_reads {
+            adjusted += &format!("{}{{{}}}", read, jitter::read_string(pieces));
+        }
+        info!(
+            "adjusted the geometry {} to {} to fit the first {} fragments",
+            args.geom[0], adjusted, report.fragments
+        );
+        args.geom[0] = adjusted;
+    }
+    if let Some(n) = args.calibrate_mismatches {
+        if args.geom.len() != 1 {
+            bail!("--calibrate-mismatches cannot be used with a geometry per pair of input files");
+        }
+        if reads_stdin(&args.input) {
+            bail!("--calibrate-mismatches reads the start of the inputs twice, but the standard input can only be read once");
+        }
+        let report = calibrate_budget(
+            &args.geom[0],
+            &mut args.search,
+            &args.input,
+            n,
+            seed,
+            args.on_error,
+        )?;
+        let chosen = report.chosen_budget(args.budget.max_false_fraction);
+        info!(
+            "allowing {} mismatches in the anchors, which matches {:.2}% of the first {} fragments ({} recovered, an estimated {:.1} of them falsely)",
+            chosen,
+            report.trials[chosen as usize].matched as f64 * 100_f64 / report.fragments.max(1) as f64,
+            report.fragments,
+            report.recovered(chosen),
+            report.estimated_false(chosen)
+        );
+        args.search.anchor_mismatches = chosen;
+    }
+    let mut geo_res = args
+        .geom
+        .iter()
+        .map(|g| compile_geom(g, &args.search))
+        .collect::<Result<Vec<_>>>()?;
+    if args.pad_to_observed {
+        if geo_res.len() != 1 {
+            bail!("--pad-to-observed cannot be used with a geometry per pair of input files");
+        }
+        check_inputs(&args.input)?;
+        for p in args.input.read1.iter().chain(&args.input.read2) {
+            if !std::fs::metadata(p).is_ok_and(|m| m.is_file()) {
+                bail!(
+                    "--pad-to-observed reads the inputs twice, but {} is not a regular file",
+                    p.display()
+                );
+            }
+        }
+        let obs = lengths::observe_lengths(
+            &mut geo_res[0],
+            &args.input.read1,
+            &args.input.read2,
+            args.on_error,
+            input_format(&args.input),
+            &ora_decoder(&args.input)?,
+        )?;
+        for pl in &obs.pieces {
+            match (pl.lengths.keys().next(), pl.max()) {
+                (Some(l), Some(h)) => info!(
+                    "captured piece {} of read {} (declared length {}-{}) has lengths {}-{} in the {} transformed fragments",
+                    pl.piece + 1,
+                    pl.read + 1,
+                    pl.declared.0,
+                    pl.declared.1,
+                    l,
+                    h,
+                    obs.transformed
+                ),
+                _ => warn!(
+                    "captured piece {} of read {} was not observed; it is padded to its declared length",
+                    pl.piece + 1,
+                    pl.read + 1
+                ),
+            }
+        }
+        obs.apply(&mut geo_res[0]);
+    }
+    let start = Instant::now();
+    for geo_re in &geo_res {
+        info!(
+            "geometry as regex = Read1 : {:?}, Read2 : {:?}",
+            geo_re.r1_re, geo_re.r2_re
+        );
+    }
+
+    let simp_desc = geo_res[0].get_simplified_description_string();
+    info!(
+        "description the simplified version of this geometry is {} (variable length pieces padded to {})",
+        simp_desc,
+        geo_res[0].padding()
+    );
+    if let Some(dir) = args.outdir.clone() {
+        apply_outdir(args, &geo_res[0], &dir)?;
+    }
+    check_outputs(args)?;
+
+    let split_output = match (args.split_output_every, args.split_output_size) {
+        (Some(0), _) => bail!("--split-output-every must be greater than 0"),
+        (Some(n), _) => Some(SplitPolicy::Records(n)),
+        (None, Some(n)) => Some(SplitPolicy::Bytes(n)),
+        (None, None) => None,
+    };
+    // the interleaved records are written as the read 1 output
+    let interleaved = args.interleaved_out.is_some();
+    if let Some(p) = args.interleaved_out.take() {
+        args.output.out1 = Some(p);
+    }
+    let opts = XformOptions {
+        split_output,
+        interleaved,
+        out1: SinkConfig {
+            compression: output_compression(
+                args.out1_compression.or(args.compress),
+                args.input.read1.first().filter(|_| !args.framed),
+                args.output.out1.as_ref(),
+            )?,
+            framed: args.framed,
+            ..SinkConfig::default()
+        },
+        out2: SinkConfig {
+            compression: output_compression(
+                args.out2_compression.or(args.compress),
+                args.input.read2.first().filter(|_| !args.framed),
+                args.output.out2.as_ref(),
+            )?,
+            framed: args.framed,
+            ..SinkConfig::default()
+        },
+        features: args
+            .feature_ref
+            .as_ref()
+            .map(|p| -> Result<FeatureOptions> {
+                let reference = FeatureRef::from_path(p)?;
+                info!("loaded {} features from {}", reference.len(), p.display());
+                Ok(FeatureOptions {
+                    reference,
+                    max_mismatches: args.feature_mismatches,
+                })
+            })
+            .transpose()?,
+        probes: args
+            .probe_set
+            .as_ref()
+            .map(|p| -> Result<ProbeOptions> {
+                let panel = ProbePanel::from_path(p)?;
+                info!("loaded {} probes from {}", panel.len(), p.display());
+                Ok(ProbeOptions {
+                    panel,
+                    offset: args.probe_offset,
+                    max_mismatches: args.probe_mismatches,
+                })
+            })
+            .transpose()?,
+        controls: args
+            .controls
+            .as_ref()
+            .map(|p| -> Result<ControlOptions> {
+                let reference = ControlRef::from_path(p, args.control_kmer_len)?;
+                info!("loaded {} controls from {}", reference.len(), p.display());
+                Ok(ControlOptions {
+                    reference,
+                    outputs: args
+                        .control_out1
+                        .clone()
+                        .zip(args.control_out2.clone())
+                        .map(|(c1, c2)| [c1, c2]),
+                })
+            })
+            .transpose()?,
+        spatial: args
+            .spatial_coords
+            .as_ref()
+            .map(|p| -> Result<SpatialOptions> {
+                let table = CoordinateTable::from_path(p)?;
+                info!(
+                    "loaded the coordinates of {} spots from {}",
+                    table.len(),
+                    p.display()
+                );
+                Ok(SpatialOptions {
+                    table,
+                    tag_headers: args.spatial_tags,
+                    sidecar: args.spatial_sidecar.clone(),
+                })
+            })
+            .transpose()?,
+        demux: args
+            .samples
+            .as_ref()
+            .map(|p| -> Result<DemuxOptions> {
+                let sheet = SampleSheet::from_path(p)?;
+                info!("loaded {} samples from {}", sheet.len(), p.display());
+                Ok(DemuxOptions {
+                    sheet,
+                    piece: args.demux_piece,
+                    max_mismatches: args.demux_mismatches,
+                })
+            })
+            .transpose()?,
+        dedup: args.dedup.map(|mode| DedupOptions {
+            mode,
+            prefix_len: args.dedup_prefix_len,
+            memory: args.dedup_memory.unwrap_or(DedupOptions::DEFAULT_MEMORY),
+        }),
+        umi_correction: args.correct_umis.then_some(UmiOptions {
+            window: args.umi_correction_window,
+        }),
+        count_barcodes: args.barcode_counts.is_some() || args.unfiltered_pl.is_some(),
+        retry: args.io_retries.map(|max_retries| RetryOptions {
+            max_retries,
+            backoff: Duration::from_millis(args.io_retry_backoff),
+        }),
+        input_format: input_format(&args.input),
+        ora_decoder: ora_decoder(&args.input)?,
+        format: args.output_format,
+        id_format: args.id_format.clone(),
+        num_threads: args.threads,
+        seed,
+        unmatched1: args.unmatched1.clone(),
+        unmatched2: args.unmatched2.clone(),
+        strict_pairing: args.strict_pairing,
+        on_error: args.on_error,
+        diagnose_failures: args.report.stats_json.is_some(),
+        whitelist: args
+            .whitelist
+            .as_ref()
+            .map(|p| -> Result<Whitelist> {
+                let wl = match &args.whitelist_tmp_dir {
+                    Some(d) => {
+                        let opts = DiskWhitelistOptions {
+                            tmp_dir: Some(d.clone()),
+                            ..DiskWhitelistOptions::default()
+                        };
+                        Whitelist::from_path_on_disk(p, &opts)?
+                    }
+                    None => Whitelist::from_path(p)?,
+                };
+                info!(
+                    "loaded {} whitelist barcodes from {}",
+                    wl.len(),
+                    p.display()
+                );
+                Ok(wl)
+            })
+            .transpose()?,
+        length_aware_whitelist: args.length_aware_whitelist,
+        barcode_corrector: match &args.barcode_correction {
+            CorrectionStrategy::OneMismatch => None,
+            strategy => Some(strategy.build()?),
+        },
+        max_records: args.preview,
+        barcode_consistency: args.check_barcodes.map(|mode| ConsistencyOptions {
+            mode,
+            max_mismatches: args.check_barcodes_mismatches,
+        }),
+        guides: guide_options(args)?,
+        progress: args.progress_every.map(XformStatsHandle::with_logging),
+        bam: args.bam.clone().map(BamOptions::new),
+        sort_by_barcode: args.sort_by_barcode.then(|| SortOptions {
+            chunk_size: args.sort_chunk_size,
+            tmp_dir: args.sort_tmp_dir.clone(),
+        }),
+        offset_index: args.offset_index.then_some(OffsetIndexOptions {
+            every: args.offset_index_every,
+        }),
+        index_reads: (!args.index1.is_empty() || !args.index2.is_empty()).then(|| {
+            let cfg = |p: &Option<PathBuf>| SinkConfig {
+                compression: p
+                    .as_deref()
+                    .and_then(compression_from_extension)
+                    .unwrap_or_default(),
+                ..SinkConfig::default()
+            };
+            IndexReadOptions {
+                inputs: [args.index1.clone(), args.index2.clone()],
+                out: [cfg(&args.out3), cfg(&args.out4)],
+                outputs: [args.out3.clone(), args.out4.clone()],
+            }
+        }),
+        ..XformOptions::default()
+    };
+
+    if args.preview.is_some() {
+        if reads_stdin(&args.input) {
+            bail!("--preview reads the start of the inputs twice, but the standard input can only be read once");
+        }
+        let previewed = preview::preview_fragments(
+            &mut geo_res[0].clone(),
+            &args.input.read1,
+            &args.input.read2,
+            PREVIEW_SHOWN,
+            input_format(&args.input),
+            &ora_decoder(&args.input)?,
+        )?;
+        print_preview(&previewed);
+    }
+
+    let xform_stats = seq_geom_xform::xform_read_pairs_to_file_with_geometries(
+        geo_res,
+        &args.input.read1,
+        &args.input.read2,
+        args.output.out1.clone(),
+        args.output.out2.clone(),
+        &opts,
+    )?;
+
+    info!("fragment transformation statistics\n{}", &xform_stats);
+    if xform_stats.input_pairs.len() > 1 {
+        for p in &xform_stats.input_pairs {
             info!(
-                "geometry as regex = Read1 : {:?}, Read2 : {:?}",
-                geo_re.r1_re, geo_re.r2_re
+                "{} / {} (geometry {}): {} fragments, {} ({:.2}%) failed to parse",
+                p.r1.display(),
+                p.r2.display(),
+                args.geom[p.geometry],
+                p.total_fragments,
+                p.failed_parsing,
+                p.fail_rate() * 100_f64
             );
+        }
+    }
+    write_report(&args.report, &args.geom.join(";"), &xform_stats, start)?;
+    if let (Some(p), Some(fc)) = (&args.feature_counts, &xform_stats.feature_counts) {
+        let mut w = create_output(p)?;
+        fc.write_tsv(&mut w)?;
+        w.flush()?;
+        info!("wrote feature counts to {}", p.display());
+    }
+    if let (Some(p), Some(cc)) = (&args.control_counts, &xform_stats.control_counts) {
+        let mut w = create_output(p)?;
+        cc.write_tsv(&mut w)?;
+        w.flush()?;
+        info!("wrote control counts to {}", p.display());
+    }
+    if let (Some(p), Some(dc)) = (&args.demux_counts, &xform_stats.demux) {
+        let mut w = create_output(p)?;
+        dc.write_tsv(&mut w)?;
+        w.flush()?;
+        info!("wrote sample counts to {}", p.display());
+    }
+    if let Some(bc) = &xform_stats.barcode_counts {
+        if let Some(p) = barcode_counts_path(args) {
+            let mut w = create_output(&p)?;
+            bc.write_tsv(&mut w)?;
+            w.flush()?;
+            info!("wrote barcode counts to {}", p.display());
+        }
+        if let Some(p) = &args.unfiltered_pl {
+            let mut w = create_output(p)?;
+            bc.write_permit_list(&mut w)?;
+            w.flush()?;
+            info!("wrote unfiltered permit list to {}", p.display());
+        }
+    }
+    let total = xform_stats.total_fragments;
+    let failed = xform_stats.failed_parsing;
+    info!(
+        "Observed {} input fragments. {} ({:.2}%) of them failed to parse and were not transformed",
+        total,
+        failed,
+        xform_stats.fail_rate() * 100_f64
+    );
 
-            let simp_desc = geo_re.get_simplified_description_string();
-            info!(
-                "description the simplified version of this geometry is {}",
-                simp_desc
+    let duration = start.elapsed();
+    info!("tranformation completed in {:.2}s", duration.as_secs_f32());
+    Ok(())
+}
+
+fn check(args: CheckArgs) -> Result<()> {
+    let mut geo_re = compile_geom(&args.geom.geom, &args.search)?;
+    println!("geometry {} is valid", args.geom.geom);
+    if !args.input.read1.is_empty() || !args.input.read2.is_empty() {
+        check_inputs(&args.input)?;
+        let stats = seq_geom_xform::compute_xform_stats_with_decoder(
+            &mut geo_re,
+            &args.input.read1,
+            &args.input.read2,
+            Some(args.sample.max_fragments),
+            input_format(&args.input),
+            &ora_decoder(&args.input)?,
+        )?;
+        println!(
+            "{} of the first {} fragments ({:.2}%) match the geometry",
+            stats.total_fragments - stats.failed_parsing,
+            stats.total_fragments,
+            stats.match_rate() * 100_f64
+        );
+    }
+    Ok(())
+}
+
+fn describe(args: DescribeArgs) -> Result<()> {
+    let geo_re = compile_geom(&args.geom.geom, &args.search)?;
+    if args.json {
+        print!("{}", describe_json(&args.geom.geom, &geo_re));
+        return Ok(());
+    }
+    println!("geometry: {}", args.geom.geom);
+    println!("read 1 regex: {}", geo_re.r1_re);
+    println!("read 2 regex: {}", geo_re.r2_re);
+    println!(
+        "simplified geometry: {}",
+        geo_re.get_simplified_description_string()
+    );
+    Ok(())
+}
+
+/// The description of the geometry `geom`, compiled into `geo_re`, as JSON.
+fn describe_json(geom: &str, geo_re: &FragmentRegexDesc) -> String {
+    let mut out = String::from("{\n  \"geometry\": ");
+    push_json_string(&mut out, geom);
+    out += ",\n  \"simplified_geometry\": ";
+    push_json_string(&mut out, &geo_re.get_simplified_description_string());
+    out += ",\n  \"padding\": ";
+    push_json_string(&mut out, &geo_re.padding().to_string());
+    out += ",\n  \"regexes\": {";
+    let regexes = [(1, &geo_re.r1_re), (2, &geo_re.r2_re)]
+        .into_iter()
+        .chain(geo_re.index_reads().iter().map(|ir| (ir.read, &ir.re)));
+    for (i, (read, re)) in regexes.enumerate() {
+        out += if i == 0 { "\n    \"" } else { ",\n    \"" };
+        out += &format!("read{}\": ", read);
+        push_json_string(&mut out, re.as_str());
+    }
+    out += "\n  },\n  \"output_read_lengths\": {";
+    for (i, (read, len)) in geo_re.output_read_lens().into_iter().enumerate() {
+        out += if i == 0 { "\n    \"" } else { ",\n    \"" };
+        out += &format!(
+            "read{}\": {}",
+            read,
+            len.map_or_else(|| String::from("null"), |l| l.to_string())
+        );
+    }
+    out += "\n  }\n}\n";
+    out
+}
+
+fn detect(args: DetectArgs) -> Result<()> {
+    check_inputs(&args.input)?;
+    let decoder = ora_decoder(&args.input)?;
+    let mut best: Option<(usize, f64)> = None;
+    for (i, gd) in args.geom.iter().enumerate() {
+        let mut geo_re = compile_geom(gd, &args.search)?;
+        let stats = seq_geom_xform::compute_xform_stats_with_decoder(
+            &mut geo_re,
+            &args.input.read1,
+            &args.input.read2,
+            Some(args.sample.max_fragments),
+            input_format(&args.input),
+            &decoder,
+        )?;
+        let rate = stats.match_rate();
+        println!("{}\t{:.2}%", gd, rate * 100_f64);
+        if best.is_none_or(|(_, r)| rate > r) {
+            best = Some((i, rate));
+        }
+    }
+    if let Some((i, rate)) = best {
+        if rate > 0_f64 {
+            println!("best matching geometry: {}", args.geom[i]);
+        } else {
+            println!("none of the candidate geometries match the reads");
+        }
+    }
+    Ok(())
+}
+
+fn simulate(args: SimulateArgs, seed: u64) -> Result<()> {
+    let geo = parse_geom(&args.geom.geom)?;
+    let mut rng = sim::seeded_rng(seed);
+    let cfg = SimConfig {
+        unbounded_len: args.unbounded_len,
+        ..SimConfig::default()
+    };
+    // an omitted output is simply not written
+    let open = |p: &Option<PathBuf>| -> Result<Option<BufWriter<File>>> {
+        p.as_ref()
+            .map(|p| {
+                File::create(p)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("could not create {}", p.display()))
+            })
+            .transpose()
+    };
+    let (mut o1, mut o2) = (open(&args.output.out1)?, open(&args.output.out2)?);
+    if o1.is_none() && o2.is_none() {
+        bail!("at least one of --out1 and --out2 must be given");
+    }
+    for (r1, r2) in sim::pairs_iter(&geo, &mut rng, &cfg).take(args.num_fragments) {
+        if let Some(o1) = o1.as_mut() {
+            r1.write_fastq(o1)?;
+        }
+        if let Some(o2) = o2.as_mut() {
+            r2.write_fastq(o2)?;
+        }
+    }
+    for o in [o1, o2].iter_mut().flatten() {
+        o.flush()?;
+    }
+    info!(
+        "simulated {} fragments with geometry {}",
+        args.num_fragments, args.geom.geom
+    );
+    Ok(())
+}
+
+fn stats(args: StatsArgs) -> Result<()> {
+    check_inputs(&args.input)?;
+    let start = Instant::now();
+    let mut geo_re = compile_geom(&args.geom.geom, &args.search)?;
+    let stats = seq_geom_xform::compute_xform_stats_with_decoder(
+        &mut geo_re,
+        &args.input.read1,
+        &args.input.read2,
+        args.max_fragments,
+        input_format(&args.input),
+        &ora_decoder(&args.input)?,
+    )?;
+    println!("{}", stats);
+    write_report(&args.report, &args.geom.geom, &stats, start)?;
+    Ok(())
+}
+
+/// The number of fragments shown next to their transformation by `xform
+/// --preview`.
+const PREVIEW_SHOWN: usize = 5;
+
+/// Prints each of the `previewed` fragments, with each input read next to its
+/// transformation.
+fn print_preview(previewed: &[PreviewedFragment]) {
+    for pf in previewed {
+        println!("{}", String::from_utf8_lossy(&pf.ids[0]));
+        let width = pf.reads[0].len().max(pf.reads[1].len());
+        for (i, read) in pf.reads.iter().enumerate() {
+            let transformed = match &pf.transformed {
+                Some(sp) if i == 0 => String::from_utf8_lossy(&sp.s1),
+                Some(sp) => String::from_utf8_lossy(&sp.s2),
+                None if i == 0 => "(does not match the geometry)".into(),
+                None => "".into(),
+            };
+            let line = format!(
+                "  R{}  {:<width$}  =>  {}",
+                i + 1,
+                String::from_utf8_lossy(read),
+                transformed,
+                width = width
             );
+            println!("{}", line.trim_end_matches([' ', '=', '>']));
+        }
+    }
+}
 
-            let xform_stats = seq_geom_xform::xform_read_pairs_to_file(
-                geo_re,
-                &args.read1,
-                &args.read2,
-                args.out1,
-                args.out2,
-            )?;
-
-            info!("fragment transformation statistics\n{}", &xform_stats);
-            let total = xform_stats.total_fragments;
-            let failed = xform_stats.failed_parsing;
-            info!(
-                "Observed {} input fragments. {} ({:.2}%) of them failed to parse and were not transformed",
-                total, failed, if total > 0 { (failed as f64) / (total as f64) } else { 0_f64 } * 100_f64
+fn preview(args: PreviewArgs) -> Result<()> {
+    check_inputs(&args.input)?;
+    let mut geo_re = compile_geom(&args.geom.geom, &args.search)?;
+    let decoder = ora_decoder(&args.input)?;
+    let previewed = preview::preview_fragments(
+        &mut geo_re,
+        &args.input.read1,
+        &args.input.read2,
+        args.show,
+        input_format(&args.input),
+        &decoder,
+    )?;
+    print_preview(&previewed);
+    if !reads_stdin(&args.input) {
+        let stats = seq_geom_xform::compute_xform_stats_with_decoder(
+            &mut geo_re,
+            &args.input.read1,
+            &args.input.read2,
+            Some(args.sample.max_fragments),
+            input_format(&args.input),
+            &decoder,
+        )?;
+        println!(
+            "\nstatistics of the first {} fragments",
+            stats.total_fragments
+        );
+        println!("{}", stats);
+    }
+    Ok(())
+}
+
+fn verify(args: VerifyArgs) -> Result<()> {
+    check_inputs(&args.input)?;
+    let geo_re = compile_geom(&args.geom.geom, &args.search)?;
+    let opts = VerifyOptions {
+        max_mismatches: args.max_mismatches,
+        input_format: input_format(&args.input),
+        ora_decoder: ora_decoder(&args.input)?,
+    };
+    let report = verify::verify_transformation(
+        &geo_re,
+        &args.input.read1,
+        &args.input.read2,
+        args.output.out1.as_deref(),
+        args.output.out2.as_deref(),
+        &opts,
+    )?;
+    println!("input fragments\t{}", report.input_fragments);
+    println!("output records\t{}", report.output_records);
+    println!("failed records\t{}", report.failed_records);
+    for failure in &report.failures {
+        println!("  {}", failure);
+    }
+    if report.failed_records > report.failures.len() as u64 {
+        println!(
+            "  ... and {} more",
+            report.failed_records - report.failures.len() as u64
+        );
+    }
+    if !report.passed() {
+        println!("FAIL");
+        bail!(
+            "{} output records failed verification",
+            report.failed_records
+        );
+    }
+    println!("PASS");
+    Ok(())
+}
+
+fn route(args: RouteArgs) -> Result<()> {
+    check_inputs(&args.input)?;
+    let start = Instant::now();
+    let mut modalities = vec![];
+    for m in &args.modality {
+        let Some((name, gd)) = m.split_once('=') else {
+            bail!("expected a modality as NAME=GEOMETRY, but got {}", m);
+        };
+        let geo_re = compile_geom(gd, &args.search)?;
+        let path = |p: &Option<PathBuf>| p.as_deref().map(|p| demux::sample_path(p, name));
+        modalities.push(Modality {
+            name: name.to_owned(),
+            r1_ofile: path(&args.output.out1).filter(|_| !geo_re.discards_read1()),
+            r2_ofile: path(&args.output.out2).filter(|_| !geo_re.discards_read2()),
+            geo_re,
+        });
+    }
+    let compression = |input: Option<&PathBuf>, out: Option<&PathBuf>| -> Result<SinkConfig> {
+        Ok(SinkConfig {
+            compression: output_compression(args.compress, input, out)?,
+            ..SinkConfig::default()
+        })
+    };
+    let opts = RoutingOptions {
+        out1: compression(args.input.read1.first(), args.output.out1.as_ref())?,
+        out2: compression(args.input.read2.first(), args.output.out2.as_ref())?,
+        format: args.output_format,
+        strict_pairing: args.strict_pairing,
+        on_error: args.on_error,
+        input_format: input_format(&args.input),
+        ora_decoder: ora_decoder(&args.input)?,
+    };
+    let stats =
+        route::route_read_pairs_to_files(modalities, &args.input.read1, &args.input.read2, &opts)?;
+    info!("{}", stats);
+    if let Some(p) = &args.counts {
+        let mut w = create_output(p)?;
+        stats.write_tsv(&mut w)?;
+        w.flush()?;
+        info!("wrote the modality counts to {}", p.display());
+    }
+    info!("routing completed in {:.2}s", start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+fn print_jitter(report: &JitterReport) {
+    for pj in &report.pieces {
+        let (l, h) = pj.expected;
+        println!(
+            "read {}: piece {} before anchor {} (found in {} of {} reads)",
+            pj.read + 1,
+            pj.piece + 1,
+            pj.anchor,
+            pj.found(),
+            pj.found() + pj.missing_anchor
+        );
+        println!("length\treads\tpercent");
+        for (len, c) in &pj.lengths {
+            println!(
+                "{}\t{}\t{:.2}%",
+                len,
+                c,
+                pj.fraction_within((*len, *len)) * 100_f64
+            );
+        }
+        println!(
+            "the expected length range [{}-{}] fits {:.2}% of these reads",
+            l,
+            h,
+            pj.fraction_within(pj.expected) * 100_f64
+        );
+        if let Some((sl, sh)) = pj.suggested_range() {
+            println!(
+                "the suggested length range [{}-{}] fits {:.2}% of these reads",
+                sl,
+                sh,
+                pj.fraction_within((sl, sh)) * 100_f64
             );
+        }
+    }
+}
+
+fn measure_jitter(args: JitterArgs) -> Result<()> {
+    check_inputs(&args.input)?;
+    let geo = parse_geom(&args.geom.geom)?;
+    let report = jitter::measure_jitter(
+        &geo,
+        &args.input.read1,
+        &args.input.read2,
+        args.sample.max_fragments,
+        OnError::Abort,
+        input_format(&args.input),
+        &ora_decoder(&args.input)?,
+    )?;
+    print_jitter(&report);
+    println!(
+        "suggested geometry: {}",
+        jitter::geometry_string(&report.adjusted_geometry(&geo))
+    );
+    Ok(())
+}
+
+/// Matches the first `max_fragments` fragments of `input`, and their decoys,
+/// against the geometry `gd` allowing each number of mismatches in its
+/// anchors (see [`calibrate::calibrate_anchor_mismatches`]).
+fn calibrate_budget(
+    gd: &str,
+    search: &mut SearchArgs,
+    input: &InputArgs,
+    max_fragments: u64,
+    seed: u64,
+    on_error: OnError,
+) -> Result<CalibrationReport> {
+    check_inputs(input)?;
+    calibrate::calibrate_anchor_mismatches(
+        |k| {
+            search.anchor_mismatches = k;
+            compile_geom(gd, search)
+        },
+        &input.read1,
+        &input.read2,
+        max_fragments,
+        seed,
+        on_error,
+        input_format(input),
+        &ora_decoder(input)?,
+    )
+}
+
+fn calibrate(mut args: CalibrateArgs, seed: u64) -> Result<()> {
+    let report = calibrate_budget(
+        &args.geom.geom,
+        &mut args.search,
+        &args.input,
+        args.sample.max_fragments,
+        seed,
+        OnError::Abort,
+    )?;
+    println!("mismatches	matched	recovered	estimated_false");
+    for t in &report.trials {
+        println!(
+            "{}	{}	{}	{:.1}",
+            t.mismatches,
+            t.matched,
+            report.recovered(t.mismatches),
+            report.estimated_false(t.mismatches)
+        );
+    }
+    println!(
+        "suggested: --anchor-mismatches {} (of {} fragments, at most {:.0}% of those recovered by each mismatch estimated to be false matches)",
+        report.chosen_budget(args.budget.max_false_fraction),
+        report.fragments,
+        args.budget.max_false_fraction * 100_f64
+    );
+    Ok(())
+}
+
+#[cfg(feature = "serve")]
+fn serve(args: ServeArgs) -> Result<()> {
+    let geo_re = compile_geom(&args.geom.geom, &args.search)?;
+    let server = XformServer::bind(geo_re, args.listen.as_str())
+        .with_context(|| format!("could not listen on {}", args.listen))?
+        .with_description(&args.geom.geom)
+        .with_max_connections(args.max_connections)
+        .with_timeout(Some(Duration::from_secs(args.timeout)));
+    info!(
+        "transforming reads with geometry {} on http://{}/xform",
+        args.geom.geom,
+        server.local_addr()?
+    );
+    server.run()
+}
+
+/// Converts a scalar TOML value from a configuration file into the
+/// textual form in which it would be given on the command line.
+fn config_scalar_to_arg(key: &str, v: &toml::Value) -> Result<String> {
+    Ok(match v {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        _ => bail!("unsupported value {} for config key {}", v, key),
+    })
+}
+
+/// Reads the configuration file at `path` and returns the equivalent command
+/// line arguments for every option it sets that is accepted by the subcommand
+/// `sub`, except for those options that are present in `cli` (i.e. were
+/// explicitly given on the command line).  Options accepted only by other
+/// subcommands are ignored, so that one file can serve several subcommands.
+fn config_args(path: &Path, sub: &str, cli: &clap::ArgMatches) -> Result<Vec<OsString>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read config file {}", path.display()))?;
+    let table: toml::Table = contents
+        .parse()
+        .with_context(|| format!("could not parse config file {}", path.display()))?;
+
+    let cmd = Cli::command();
+    let subcmd = cmd
+        .find_subcommand(sub)
+        .with_context(|| format!("unknown subcommand {}", sub))?;
+    let mut args = vec![];
+    for (key, value) in &table {
+        let id = key.replace('-', "_");
+        if id == "config" {
+            bail!("config files cannot include other config files");
+        }
+        let find_arg = |c: &clap::Command| {
+            c.get_arguments()
+                .find(|a| a.get_id() == id.as_str() && a.get_long().is_some())
+                .cloned()
+        };
+        let arg = match find_arg(subcmd) {
+            Some(a) => a,
+            None => {
+                if cmd.get_subcommands().any(|c| find_arg(c).is_some()) {
+                    continue;
+                }
+                bail!("unknown option {} in config file", key);
+            }
+        };
+        if cli.value_source(&id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = OsString::from(format!("--{}", arg.get_long().unwrap()));
+        match value {
+            // flags are given (or not) without a value
+            toml::Value::Boolean(true) => args.push(flag),
+            toml::Value::Boolean(false) => {}
+            // lists are given by repeating the option
+            toml::Value::Array(a) => {
+                for e in a {
+                    args.push(flag.clone());
+                    args.push(OsString::from(config_scalar_to_arg(key, e)?));
+                }
+            }
+            v => {
+                args.push(flag);
+                args.push(OsString::from(config_scalar_to_arg(key, v)?));
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Whether the command line `argv` is that of the deprecated invocation
+/// without a subcommand (e.g. `seq_xformer -g 1{b[16]u[12]x:}2{r:} -1 ...`),
+/// which stands for `xform`: an option of `xform` comes before any
+/// subcommand, or only global options (among which `--config`) are given.
+fn is_flat_invocation(argv: &[OsString]) -> bool {
+    let cmd = Cli::command();
+    let globals: Vec<&clap::Arg> = cmd.get_arguments().filter(|a| a.is_global_set()).collect();
+    let mut config = false;
+    let mut args = argv.iter().skip(1);
+    while let Some(a) = args.next() {
+        // a subcommand, or an argument that clap will report
+        let Some(a) = a.to_str().filter(|a| a.starts_with('-') && a.len() > 1) else {
+            return false;
+        };
+        if ["-h", "--help", "-V", "--version"].contains(&a) {
+            return false;
+        }
+        // the global option, and whether its value is the next argument
+        let (global, next_value) = match a.strip_prefix("--") {
+            Some(long) => {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, _)) => (name, true),
+                    None => (long, false),
+                };
+                let g = globals.iter().find(|g| g.get_long() == Some(name));
+                (
+                    g,
+                    g.is_some_and(|g| g.get_action().takes_values() && !value),
+                )
+            }
+            None => {
+                // a cluster of short flags (e.g. -vv), of which the last
+                // may take a value, either attached (-cfile) or next
+                let shorts: Vec<char> = a[1..].chars().collect();
+                let mut found = None;
+                let mut next_value = false;
+                for (i, c) in shorts.iter().enumerate() {
+                    found = globals.iter().find(|g| g.get_short() == Some(*c));
+                    match found {
+                        Some(g) if g.get_action().takes_values() => {
+                            next_value = i + 1 == shorts.len();
+                            break;
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                (found, next_value)
+            }
+        };
+        let Some(global) = global else {
+            return true;
+        };
+        config |= global.get_id() == "config";
+        if next_value {
+            args.next();
+        }
+    }
+    config
+}
 
-            let duration = start.elapsed();
-            info!("tranformation completed in {:.2}s", duration.as_secs_f32());
-            Ok(())
+/// Parses the command line `argv`, filling in any options not given there
+/// from the configuration file passed with `--config` (if any).  The
+/// returned flag is true if the deprecated invocation without a
+/// subcommand was used (in which case `xform` is assumed).
+fn parse_args(mut argv: Vec<OsString>) -> Result<(Cli, bool)> {
+    let flat = is_flat_invocation(&argv);
+    if flat {
+        argv.insert(1, OsString::from("xform"));
+    }
+
+    // A first, lenient, pass to find the config file and to learn
+    // which options were explicitly given on the command line.
+    let cli = match Cli::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&argv)
+    {
+        Ok(m) => m,
+        Err(_) => return Ok((Cli::parse_from(argv), flat)),
+    };
+
+    let sub = cli.subcommand();
+    let cfg = sub.and_then(|(_, m)| m.get_one::<PathBuf>("config").cloned());
+    match (sub, cfg) {
+        (Some((name, sub_matches)), Some(cfg)) => {
+            // Explicitly given options are skipped, so the config
+            // options can simply be appended to the command line.
+            argv.extend(config_args(&cfg, name, sub_matches)?);
+            Ok((Cli::parse_from(argv), flat))
         }
-        Err(e) => Err(e),
+        _ => Ok((Cli::parse_from(argv), flat)),
     }
 }
 
-fn main() -> Result<()> {
+/// Installs the global subscriber logging the messages of the level selected
+/// by `--quiet` and `--verbose` (unless `RUST_LOG` says otherwise).  Colors
+/// are only used on a terminal, and not if `NO_COLOR` is set (see
+/// <https://no-color.org>).
+fn init_logging(cli: &Cli) {
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let ansi = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && std::io::stdout().is_terminal();
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(fmt::layer().with_ansi(ansi))
         .with(
             EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
+                .with_default_directive(level.into())
                 .from_env_lossy(),
         )
         .init();
+}
+
+fn main() -> ExitCode {
+    // errors in the configuration file are errors of the command line
+    let (cli, flat) = match parse_args(std::env::args_os().collect()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return ExitCode::from(2);
+        }
+    };
+    init_logging(&cli);
+    if flat {
+        warn!("invoking seq_xformer without a subcommand is deprecated; use `seq_xformer xform` instead");
+    }
+    if let Some(cfg) = &cli.config {
+        info!("read options from config file {}", cfg.display());
+    }
+    let res = match cli.command {
+        Commands::Xform(args) => process_reads(*args, cli.seed),
+        Commands::Check(args) => check(args),
+        Commands::Describe(args) => describe(args),
+        Commands::Detect(args) => detect(args),
+        Commands::Simulate(args) => simulate(args, cli.seed),
+        Commands::Stats(args) => stats(args),
+        Commands::Jitter(args) => measure_jitter(args),
+        Commands::Calibrate(args) => calibrate(args, cli.seed),
+        Commands::Preview(args) => preview(args),
+        Commands::Verify(args) => verify(args),
+        Commands::Route(args) => route(args),
+        #[cfg(feature = "serve")]
+        Commands::Serve(args) => serve(args),
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            Failure::exit_code(&e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let args = Args::parse();
-    process_reads(args)
+    fn argv(args: &[&str]) -> Vec<OsString> {
+        std::iter::once("seq_xformer")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect()
+    }
+
+    #[test]
+    fn global_options_may_precede_the_subcommand() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("run.toml");
+        std::fs::write(&config, "geom = \"1{b[4]x:}2{r:}\"\nthreads = 2\n").unwrap();
+        let config = config.to_str().unwrap();
+
+        let (cli, flat) = parse_args(argv(&["--config", config, "xform", "-1", "r1.fa"])).unwrap();
+        assert!(!flat);
+        let Commands::Xform(args) = cli.command else {
+            panic!("expected xform, got {:?}", cli.command);
+        };
+        assert_eq!(
+            (&args.geom[..], args.threads),
+            (&["1{b[4]x:}2{r:}".to_string()][..], 2)
+        );
+
+        for args in [
+            &["-v", "describe", "-g", "1{b[4]}2{r:}"][..],
+            &["-vv", "--seed=3", "describe", "-g", "1{b[4]}2{r:}"],
+            &["--seed", "3", "-q", "describe", "-g", "1{b[4]}2{r:}"],
+        ] {
+            let (cli, flat) = parse_args(argv(args)).unwrap();
+            assert!(!flat);
+            assert!(matches!(cli.command, Commands::Describe(_)), "{:?}", args);
+        }
+        let (cli, _) =
+            parse_args(argv(&["--seed", "3", "transform", "-g", "1{b[4]}2{r:}"])).unwrap();
+        assert_eq!(cli.seed, 3);
+        assert!(matches!(cli.command, Commands::Xform(_)));
+
+        // the deprecated invocation without a subcommand
+        assert!(is_flat_invocation(&argv(&[
+            "-g",
+            "1{b[4]}2{r:}",
+            "-1",
+            "r1.fa"
+        ])));
+        assert!(is_flat_invocation(&argv(&["-v", "-g", "1{b[4]}2{r:}"])));
+        assert!(is_flat_invocation(&argv(&["-vg", "1{b[4]}2{r:}"])));
+        assert!(is_flat_invocation(&argv(&["-c", config])));
+        assert!(!is_flat_invocation(&argv(&["-c", config, "check"])));
+        assert!(!is_flat_invocation(&argv(&["-v"])));
+        assert!(!is_flat_invocation(&argv(&["--help"])));
+        let (cli, flat) = parse_args(argv(&["--config", config, "-1", "r1.fa"])).unwrap();
+        assert!(flat && matches!(cli.command, Commands::Xform(_)));
+    }
+
+    #[test]
+    fn options_are_read_from_a_config_file_given_before_the_subcommand() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        std::fs::write(p("r1.fa"), ">a\nACGTTT\n>b\nACG\n").unwrap();
+        std::fs::write(p("r2.fa"), ">a\nGGCC\n>b\nTTTT\n").unwrap();
+        let mut config = String::from("geom = \"1{b[4]x:}2{r:}\"\n");
+        for (key, name) in [("read1", "r1.fa"), ("read2", "r2.fa"), ("out1", "o1.fa")] {
+            config += &format!("{} = {:?}\n", key, p(name).to_str().unwrap());
+        }
+        std::fs::write(p("run.toml"), config).unwrap();
+        let run_toml = p("run.toml");
+        let run_toml = run_toml.to_str().unwrap();
+
+        // the options given on the command line take precedence
+        for (args, out2) in [
+            (&["--config", run_toml, "xform", "-w"][..], "o2.fa"),
+            (&["-c", run_toml, "--out2"], "flat.fa"),
+        ] {
+            let mut argv = argv(args);
+            argv.push(p(out2).into_os_string());
+            let (cli, _) = parse_args(argv).unwrap();
+            assert_eq!(cli.config.as_deref(), Some(Path::new(run_toml)));
+            let Commands::Xform(args) = cli.command else {
+                panic!("expected xform, got {:?}", cli.command);
+            };
+            process_reads(*args, cli.seed).unwrap();
+            assert_eq!(std::fs::read_to_string(p("o1.fa")).unwrap(), ">a\nACGT\n");
+            assert_eq!(std::fs::read_to_string(p(out2)).unwrap(), ">a\nGGCC\n");
+        }
+    }
 }
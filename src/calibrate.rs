@@ -0,0 +1,240 @@
+//! Choosing the number of mismatches allowed in the anchors.
+//!
+//! Allowing mismatches in the fixed (`f`) pieces of a geometry (see
+//! [`crate::RegexOptions::anchor_mismatches`]) recovers the fragments whose
+//! anchors hold sequencing errors, but also lets some fragments without the
+//! anchor match by chance.  [`calibrate_anchor_mismatches`] matches a sample
+//! of the fragments against the geometry with each budget of mismatches, from
+//! 0 to [`MAX_BUDGET`], and estimates how many of the recovered fragments are
+//! false matches from a decoy of each fragment, whose reads have their bases
+//! shuffled (so that they hold no anchor, but have the composition of the
+//! real reads).  The decoys matching with a budget but not without
+//! mismatches give the rate at which fragments lacking the anchor match by
+//! chance, which applies to the fragments that do not match exactly.
+//! [`CalibrationReport::chosen_budget`] then picks the largest budget whose
+//! additional mismatch recovers enough fragments for the false matches it
+//! lets in.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use rand::seq::SliceRandom;
+
+use crate::decode::ExternalDecoder;
+use crate::malformed::OnError;
+use crate::sim::seeded_rng;
+use crate::{input_groups, read_input_group, FragmentRegexDesc, InputFormat, SeqPair};
+
+/// The largest number of mismatches allowed in each anchor that is tried.
+pub const MAX_BUDGET: u32 = 2;
+/// The default largest fraction of the fragments recovered by each
+/// additional mismatch that may be estimated to be false matches.
+pub const DEFAULT_MAX_FALSE_FRACTION: f64 = 0.1;
+
+/// The fragments matching the geometry with a budget of mismatches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetTrial {
+    /// The number of mismatches allowed in each anchor.
+    pub mismatches: u32,
+    /// The number of fragments matching the geometry.
+    pub matched: u64,
+    /// The number of decoy fragments matching the geometry.
+    pub decoys_matched: u64,
+}
+
+/// The trade-off between the fragments recovered and the false matches let
+/// in by each budget of mismatches, as measured on a sample of fragments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationReport {
+    /// The number of fragments examined.
+    pub fragments: u64,
+    /// The trials of the budgets, from 0 to [`MAX_BUDGET`] mismatches.
+    pub trials: Vec<BudgetTrial>,
+}
+
+impl CalibrationReport {
+    /// The number of fragments matching the geometry with `mismatches`
+    /// mismatches, but not without any.
+    pub fn recovered(&self, mismatches: u32) -> u64 {
+        let t = &self.trials[mismatches as usize];
+        t.matched.saturating_sub(self.trials[0].matched)
+    }
+
+    /// The estimated number of false matches among the fragments recovered
+    /// with `mismatches` mismatches (see the module documentation).
+    pub fn estimated_false(&self, mismatches: u32) -> f64 {
+        let t = &self.trials[mismatches as usize];
+        let chance = t
+            .decoys_matched
+            .saturating_sub(self.trials[0].decoys_matched) as f64
+            / self.fragments.max(1) as f64;
+        chance * self.fragments.saturating_sub(self.trials[0].matched) as f64
+    }
+
+    /// The largest budget such that each additional mismatch, up to it,
+    /// recovers fragments of which at most `max_false_fraction` are
+    /// estimated to be false matches.
+    pub fn chosen_budget(&self, max_false_fraction: f64) -> u32 {
+        let mut chosen = 0;
+        for k in 1..self.trials.len() as u32 {
+            let gained = self.recovered(k).saturating_sub(self.recovered(k - 1));
+            let false_gained = self.estimated_false(k) - self.estimated_false(k - 1);
+            if gained == 0 || false_gained > max_false_fraction * gained as f64 {
+                break;
+            }
+            chosen = k;
+        }
+        chosen
+    }
+}
+
+/// Matches the first `max_fragments` fragments of the input files `r1` and
+/// `r2`, and a decoy of each of them, against the geometry allowing each
+/// number of mismatches from 0 to [`MAX_BUDGET`] in its anchors, which is
+/// built by `build` (see the module documentation).  The bases of the decoys
+/// are shuffled by the random number generator seeded with `seed`.  The read
+/// 1 inputs are in the format `input_format`, and the ORA compressed input
+/// files are decoded by running `ora_decoder` on them.  Unless `on_error` is
+/// [`OnError::Abort`], the fragments holding a malformed record are left out.
+#[allow(clippy::too_many_arguments)]
+pub fn calibrate_anchor_mismatches(
+    mut build: impl FnMut(u32) -> Result<FragmentRegexDesc>,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    max_fragments: u64,
+    seed: u64,
+    on_error: OnError,
+    input_format: InputFormat,
+    ora_decoder: &ExternalDecoder,
+) -> Result<CalibrationReport> {
+    let mut geo_res = (0..=MAX_BUDGET)
+        .map(&mut build)
+        .collect::<Result<Vec<_>>>()?;
+    if !geo_res[1].tolerates_anchor_mismatches() {
+        bail!("The geometry has no anchor (f piece) in which to allow mismatches");
+    }
+
+    let mut rng = seeded_rng(seed);
+    let mut trials: Vec<BudgetTrial> = (0..=MAX_BUDGET)
+        .map(|mismatches| BudgetTrial {
+            mismatches,
+            matched: 0,
+            decoys_matched: 0,
+        })
+        .collect();
+    let mut fragments = 0_u64;
+    let mut parsed = SeqPair::new();
+    let mut decoy = [vec![], vec![]];
+    'files: for (files1, files2) in input_groups(r1, r2, input_format)? {
+        let mut batches = read_input_group(files1, files2, None, None, on_error, ora_decoder)?;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                if fragments >= max_fragments {
+                    break 'files;
+                }
+                fragments += 1;
+                for (d, s) in decoy.iter_mut().zip(frag.seqs) {
+                    d.clear();
+                    d.extend_from_slice(s);
+                    d.shuffle(&mut rng);
+                }
+                for (geo_re, t) in geo_res.iter_mut().zip(&mut trials) {
+                    if geo_re.parse_into(frag.seqs[0], frag.seqs[1], &mut parsed) {
+                        t.matched += 1;
+                    }
+                    if geo_re.parse_into(&decoy[0], &decoy[1], &mut parsed) {
+                        t.decoys_matched += 1;
+                    }
+                }
+            }
+            batches.recycle(batch);
+        }
+    }
+    Ok(CalibrationReport { fragments, trials })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FragmentGeomDescExt, RegexOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn budgets_are_calibrated() {
+        let geo = FragmentGeomDesc::try_from("1{b[4]f[ACGTACGTAC]u[4]}2{r:}").unwrap();
+        let build = |anchor_mismatches| {
+            geo.as_regex_with_options(&RegexOptions {
+                anchor_mismatches,
+                ..RegexOptions::default()
+            })
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        // exact, 1 mismatch, 2 mismatches, and no anchor
+        std::fs::write(
+            &r1,
+            ">a\nGGGGACGTACGTACTTTT\n>b\nGGGGACGAACGTACTTTT\n>c\nGGGGACGAACGTTCTTTT\n>d\nGGGGGGGGGGGGGGGGGG\n",
+        )
+        .unwrap();
+        std::fs::write(&r2, ">a\nCC\n>b\nCC\n>c\nCC\n>d\nCC\n").unwrap();
+        let decoder = ExternalDecoder::default();
+        let report = calibrate_anchor_mismatches(
+            build,
+            &[r1],
+            &[r2],
+            10,
+            7,
+            OnError::Abort,
+            InputFormat::default(),
+            &decoder,
+        )
+        .unwrap();
+        assert_eq!(report.fragments, 4);
+        let matched: Vec<u64> = report.trials.iter().map(|t| t.matched).collect();
+        assert_eq!(matched, [1, 2, 3]);
+        assert_eq!((report.recovered(1), report.recovered(2)), (1, 2));
+        assert_eq!(report.chosen_budget(DEFAULT_MAX_FALSE_FRACTION), 2);
+
+        // the second mismatch lets in as many false matches as it recovers
+        let report = CalibrationReport {
+            fragments: 100,
+            trials: vec![
+                BudgetTrial {
+                    mismatches: 0,
+                    matched: 50,
+                    decoys_matched: 0,
+                },
+                BudgetTrial {
+                    mismatches: 1,
+                    matched: 80,
+                    decoys_matched: 2,
+                },
+                BudgetTrial {
+                    mismatches: 2,
+                    matched: 90,
+                    decoys_matched: 22,
+                },
+            ],
+        };
+        assert_eq!(report.estimated_false(1), 1.0);
+        assert_eq!(report.estimated_false(2), 11.0);
+        assert_eq!(report.chosen_budget(DEFAULT_MAX_FALSE_FRACTION), 1);
+
+        let geo = FragmentGeomDesc::try_from("1{b[4]u[4]}2{r:}").unwrap();
+        let decoder = ExternalDecoder::default();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        let build = |_| geo.as_regex();
+        assert!(calibrate_anchor_mismatches(
+            build,
+            &[r1],
+            &[r2],
+            10,
+            7,
+            OnError::Abort,
+            InputFormat::default(),
+            &decoder
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! The pieces captured from a fragment, with their boundaries.
+//!
+//! [`crate::FragmentRegexDesc::parse_into`] gives back the transformed reads,
+//! in which the captured pieces are concatenated, so that the boundaries of
+//! the pieces are lost.  [`crate::FragmentRegexDesc::parse_pieces_into`]
+//! instead fills a [`ParsedFragment`], which also exposes each captured
+//! piece: the piece of the geometry it matched (a barcode, a UMI or a read
+//! sequence), its coordinates in the input read, and its sequence with and
+//! without its padding, so that downstream libraries can process each piece
+//! on its own (e.g. correct a barcode against a whitelist, or extract the
+//! UMI).
+
+use std::ops::Range;
+
+use seq_geom_parser::GeomPiece;
+
+use crate::SeqPair;
+
+/// A piece captured from a read of a fragment.
+#[derive(Debug, Clone)]
+pub struct CapturedPiece {
+    /// The read (`1` or `2`) from which the piece was captured.
+    pub read: usize,
+    /// The piece of the geometry matched by the captured sequence.
+    pub piece: GeomPiece,
+    /// The range of the input read holding the piece.  If the read matched
+    /// the geometry as its reverse complement (see
+    /// [`ParsedFragment::reversed`]), this is a range of the reverse
+    /// complement.
+    pub range: Range<usize>,
+    /// The range of the transformed read holding the piece, including its
+    /// padding if it is of variable length.
+    pub padded_range: Range<usize>,
+}
+
+/// A fragment parsed by [`crate::FragmentRegexDesc::parse_pieces_into`]: its
+/// transformed reads, and the pieces captured from them.
+#[derive(Debug, Default)]
+pub struct ParsedFragment {
+    pub(crate) seqs: SeqPair,
+    pub(crate) pieces: Vec<CapturedPiece>,
+    pub(crate) reversed: [bool; 2],
+}
+
+impl ParsedFragment {
+    /// Create a new, empty, `ParsedFragment`, which can be reused to parse
+    /// any number of fragments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The transformed reads, as returned by
+    /// [`crate::FragmentRegexDesc::parse_into`].
+    pub fn seqs(&self) -> &SeqPair {
+        &self.seqs
+    }
+
+    /// The captured pieces, those of read 1 followed by those of read 2, in
+    /// the order in which they appear in the transformed reads.
+    pub fn pieces(&self) -> &[CapturedPiece] {
+        &self.pieces
+    }
+
+    /// Whether read 1 and read 2 matched the geometry as their reverse
+    /// complement.
+    pub fn reversed(&self) -> [bool; 2] {
+        self.reversed
+    }
+
+    /// The sequence of the `i`-th captured piece, as it was captured
+    /// (without its padding), or `None` if there is no such piece.
+    pub fn seq(&self, i: usize) -> Option<&[u8]> {
+        let p = self.pieces.get(i)?;
+        let start = p.padded_range.start;
+        self.transformed(p.read).get(start..start + p.range.len())
+    }
+
+    /// The sequence of the `i`-th captured piece in the transformed read,
+    /// including its padding, or `None` if there is no such piece.
+    pub fn padded_seq(&self, i: usize) -> Option<&[u8]> {
+        let p = self.pieces.get(i)?;
+        self.transformed(p.read).get(p.padded_range.clone())
+    }
+
+    /// Concatenates the captured sequences (without their padding) of the
+    /// pieces for which `select` is true into `out`, e.g. to extract the
+    /// barcode or the UMI of the fragment.
+    pub fn extract_into<F: Fn(&GeomPiece) -> bool>(&self, select: F, out: &mut Vec<u8>) {
+        out.clear();
+        for i in 0..self.pieces.len() {
+            if select(&self.pieces[i].piece) {
+                out.extend_from_slice(self.seq(i).unwrap_or_default());
+            }
+        }
+    }
+
+    fn transformed(&self, read: usize) -> &[u8] {
+        if read == 1 {
+            &self.seqs.s1
+        } else {
+            &self.seqs.s2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::{FragmentGeomDesc, GeomLen};
+
+    #[test]
+    fn pieces_are_captured_with_their_boundaries() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[2-3]f[ACG]u[2]}2{x[2]r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut frag = ParsedFragment::new();
+        assert!(geo_re.parse_pieces_into(b"CCACGGA", b"TTACGT", &mut frag));
+        assert_eq!(frag.seqs().s1, b"CCACGA");
+        let bc = &frag.pieces()[0];
+        assert!(matches!(
+            bc.piece,
+            GeomPiece::Barcode(GeomLen::LenRange(2, 3))
+        ));
+        assert_eq!(
+            (bc.read, bc.range.clone(), bc.padded_range.clone()),
+            (1, 0..2, 0..4)
+        );
+        assert_eq!(
+            (frag.seq(0), frag.padded_seq(0)),
+            (Some(&b"CC"[..]), Some(&b"CCAC"[..]))
+        );
+        assert_eq!(frag.pieces()[1].range, 5..7);
+        assert_eq!(frag.seq(1), Some(&b"GA"[..]));
+        assert_eq!(frag.pieces()[2].read, 2);
+        assert_eq!(frag.pieces()[2].range, 2..6);
+        assert_eq!(frag.seq(2), Some(&b"ACGT"[..]));
+        assert_eq!(frag.seq(3), None);
+        let mut umi = Vec::new();
+        frag.extract_into(|gp| matches!(gp, GeomPiece::Umi(_)), &mut umi);
+        assert_eq!(umi, b"GA");
+
+        assert!(!geo_re.parse_pieces_into(b"AAAAAAAA", b"TTACGT", &mut frag));
+        assert!(frag.pieces().is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+//! Batches of read pairs for parallelism driven by the caller.
+//!
+//! Crates that schedule their own work (e.g. on a rayon pool, or across the
+//! nodes of a cluster) read the input fragments as [`RecordBatch`]es of a
+//! chosen size with [`RecordBatches`], hand the batches to their workers, and
+//! transform each with [`RecordBatch::transform_batch`].  The inputs are read
+//! as the transformation reads them (see [`RecordBatches::new`]), each input
+//! file being decoded on a thread of its own.  The geometry is
+//! only borrowed, so that one `FragmentRegexDesc` can be shared by all the
+//! workers: the regex scratch space (the `CaptureLocations`) a parse needs is
+//! that of the copy of the geometry held by the [`BatchOutput`] of the
+//! calling worker, so that no two threads ever use the same.  Cloning the
+//! geometry into the output once per batch costs little next to matching the
+//! fragments of the batch.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use crate::batch::{BatchReader, FragmentBatch};
+use crate::retry::RetryPolicy;
+use crate::{read_input_group_in_batches, reads_bam, FragmentRegexDesc, SeqPair, XformOptions};
+
+/// A batch of raw read pairs, whose names and sequences are stored
+/// contiguously.
+#[derive(Debug, Clone, Default)]
+pub struct RecordBatch {
+    fragments: FragmentBatch,
+}
+
+/// A read pair of a [`RecordBatch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawPair<'a> {
+    /// The name of the read 1 record (its whole header line).
+    pub id: &'a [u8],
+    pub r1: &'a [u8],
+    pub r2: &'a [u8],
+}
+
+impl RecordBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of read pairs in the batch.
+    pub fn len(&self) -> usize {
+        self.fragments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all the read pairs, keeping the allocations.
+    pub fn clear(&mut self) {
+        self.fragments.clear();
+    }
+
+    /// Adds the read pair (`r1`, `r2`), whose read 1 record is named `id`.
+    pub fn push(&mut self, id: &[u8], r1: &[u8], r2: &[u8]) {
+        self.fragments.push(id, [r1, r2]);
+    }
+
+    /// The read pairs of the batch, in order.
+    pub fn iter(&self) -> impl Iterator<Item = RawPair<'_>> {
+        self.fragments.iter().map(|frag| RawPair {
+            id: frag.ids[0],
+            r1: frag.seqs[0],
+            r2: frag.seqs[1],
+        })
+    }
+
+    /// Transforms the read pairs of the batch with the geometry `geo_re`
+    /// (see [`FragmentRegexDesc::parse_into`], the pieces captured from the
+    /// read names included) into `out`, replacing its previous contents.  The
+    /// parse is made with the copy of `geo_re` held by `out`, so that
+    /// batches may be transformed with the same `geo_re` on any number of
+    /// threads at once, each with its own `out`.  Index reads, if any, are
+    /// not parsed.
+    pub fn transform_batch(&self, geo_re: &FragmentRegexDesc, out: &mut BatchOutput) {
+        let worker = out.geo_re.insert(geo_re.clone());
+        out.seqs.truncate(self.len());
+        out.seqs.resize_with(self.len(), || None);
+        // the pair of a fragment failing to parse is reused for the next one
+        let mut spare = None;
+        for (pair, slot) in self.iter().zip(out.seqs.iter_mut()) {
+            let mut sp = slot.take().or_else(|| spare.take()).unwrap_or_default();
+            if worker.parse_into(pair.r1, pair.r2, &mut sp)
+                && worker.parse_name_into(pair.id, &mut sp)
+            {
+                *slot = Some(sp);
+            } else {
+                spare = Some(sp);
+            }
+        }
+    }
+}
+
+/// The transformed fragments of a [`RecordBatch`] (see
+/// [`RecordBatch::transform_batch`]), along with the copy of the geometry
+/// they were parsed with.  Reusing a `BatchOutput` from one batch to the
+/// next amortizes its allocations.
+#[derive(Debug, Clone, Default)]
+pub struct BatchOutput {
+    geo_re: Option<FragmentRegexDesc>,
+    /// For each read pair of the batch, its transformed fragment if it
+    /// matched the geometry.
+    seqs: Vec<Option<SeqPair>>,
+}
+
+impl BatchOutput {
+    /// Creates an empty output.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of read pairs of the batch, whether or not they matched
+    /// the geometry.
+    pub fn fragments(&self) -> usize {
+        self.seqs.len()
+    }
+
+    /// The number of read pairs of the batch that did not match the
+    /// geometry.
+    pub fn failed(&self) -> usize {
+        self.seqs.iter().filter(|s| s.is_none()).count()
+    }
+
+    /// The transformed fragment of the `i`-th read pair of the batch, if it
+    /// matched the geometry.
+    pub fn get(&self, i: usize) -> Option<&SeqPair> {
+        self.seqs.get(i)?.as_ref()
+    }
+
+    /// The read pairs of the batch that matched the geometry, in order, as
+    /// their index in the batch and their transformed fragment.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &SeqPair)> {
+        self.seqs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| Some((i, s.as_ref()?)))
+    }
+}
+
+/// Reads the read pairs of input files as [`RecordBatch`]es of a given size
+/// (the last batch holding the remaining pairs).  Reading fails if the read 1
+/// and read 2 input files hold different numbers of records.  Once an error
+/// is returned, the iterator should not be used any further.
+pub struct RecordBatches {
+    reader: BatchReader,
+}
+
+impl RecordBatches {
+    /// Creates a `RecordBatches` reading the read 1 input files `r1` and the
+    /// read 2 input files `r2`, each one file after the other, in batches of
+    /// `batch_size` read pairs.  The files `r1` are read as unaligned BAM (and
+    /// `r2` must then be empty) as told by [`XformOptions::input_format`].
+    /// The inputs are read as
+    /// [`crate::xform_read_pairs_to_file_with_options`] reads them with the
+    /// options `opts`: their reads are retried in accordance with
+    /// [`XformOptions::retry`], ORA compressed inputs are decoded with
+    /// [`XformOptions::ora_decoder`], and the malformed records are handled
+    /// in accordance with [`XformOptions::on_error`].  The index reads of
+    /// `opts` are not read.
+    pub fn new(
+        r1: &[PathBuf],
+        r2: &[PathBuf],
+        batch_size: usize,
+        opts: &XformOptions,
+    ) -> Result<Self> {
+        if batch_size == 0 {
+            bail!("The batches must hold at least one read pair");
+        }
+        reads_bam(r1, r2, opts.input_format)?;
+        let retry = opts.retry.clone().map(RetryPolicy::new);
+        let reader = read_input_group_in_batches(
+            r1,
+            r2,
+            batch_size,
+            retry.as_ref(),
+            None,
+            opts.on_error,
+            &opts.ora_decoder,
+        )?;
+        Ok(Self {
+            reader: reader.with_equal_counts(),
+        })
+    }
+
+    /// Fills `batch` with the next read pairs, returning false (with `batch`
+    /// cleared) once all of the input has been read.  The allocations of
+    /// `batch` are handed back to the reader threads for reuse, unlike with
+    /// [`Iterator::next`].
+    pub fn next_into(&mut self, batch: &mut RecordBatch) -> Result<bool> {
+        match self.reader.next_batch() {
+            Some(fragments) => {
+                let used = std::mem::replace(&mut batch.fragments, fragments?);
+                self.reader.recycle(used);
+                Ok(true)
+            }
+            None => {
+                batch.clear();
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl Iterator for RecordBatches {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let fragments = self.reader.next_batch()?;
+        Some(fragments.map(|fragments| RecordBatch { fragments }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn batches_are_transformed_on_several_threads() {
+        let dir = tempdir().unwrap();
+        let (p1, p2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&p1, ">a\nACGTACGTTT\n>b\nACG\n>c\nTTTTACGTGG\n").unwrap();
+        std::fs::write(&p2, ">a\nCCCC\n>b\nGGGG\n>c\nAAAA\n").unwrap();
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+
+        let opts = XformOptions::default();
+        let batches = RecordBatches::new(
+            std::slice::from_ref(&p1),
+            std::slice::from_ref(&p2),
+            2,
+            &opts,
+        )
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(
+            batches.iter().map(RecordBatch::len).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        assert_eq!(
+            batches[0].iter().nth(1).unwrap(),
+            RawPair {
+                id: b"b",
+                r1: b"ACG",
+                r2: b"GGGG"
+            }
+        );
+
+        let outputs: Vec<BatchOutput> = std::thread::scope(|s| {
+            let workers: Vec<_> = batches
+                .iter()
+                .map(|batch| {
+                    let geo_re = &geo_re;
+                    s.spawn(move || {
+                        let mut out = BatchOutput::new();
+                        batch.transform_batch(geo_re, &mut out);
+                        out
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        assert_eq!((outputs[0].fragments(), outputs[0].failed()), (2, 1));
+        assert_eq!(outputs[0].get(0).unwrap().s1, b"ACGTACGT");
+        assert_eq!(outputs[0].get(1), None);
+        let (i, sp) = outputs[1].iter().next().unwrap();
+        assert_eq!(
+            (i, &sp.s1[..], &sp.s2[..]),
+            (0, &b"TTTTACGT"[..], &b"AAAA"[..])
+        );
+
+        std::fs::write(&p2, ">a\nCCCC\n").unwrap();
+        let mut batches = RecordBatches::new(&[p1], &[p2], 2, &opts).unwrap();
+        let mut batch = RecordBatch::new();
+        assert!(batches.next_into(&mut batch).is_err());
+    }
+}
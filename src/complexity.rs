@@ -0,0 +1,147 @@
+//! Metrics describing the complexity of a compiled geometry.
+//!
+//! Tools embedding the library may choose how to run a transformation from
+//! the geometry alone: e.g. a geometry whose reads all have fixed length
+//! pieces is parsed by slicing the reads (see
+//! [`crate::RegexOptions::fixed_offsets`]) and is cheap enough that a
+//! single thread keeps up with decompression, whereas a geometry with
+//! variable length pieces and anchors runs a regex on every read, and
+//! benefits from more threads.  [`crate::FragmentRegexDesc::metrics`]
+//! returns the [`GeometryMetrics`] of a compiled geometry.
+
+use seq_geom_parser::{GeomLen, GeomPiece, NucStr};
+
+/// The metrics of the geometry of a single read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadMetrics {
+    /// The number of the read (`1` or `2`, or `3` and `4` for the index
+    /// reads).
+    pub read: usize,
+    /// The number of captured (`b`, `u` and `r`) pieces, i.e. of capture
+    /// groups of the regex of the read.
+    pub capture_groups: usize,
+    /// The number of fixed (`f`) pieces.
+    pub anchors: usize,
+    /// The number of pieces, captured or discarded, whose length is a range
+    /// or unbounded.
+    pub variable_len_pieces: usize,
+    /// The smallest number of bases consumed by the pieces of the read,
+    /// which is the length of the shortest read matching the geometry.
+    pub min_len: usize,
+    /// The largest number of bases consumed by the pieces of the read, or
+    /// `None` if a piece is unbounded.  Reads may be longer if the last
+    /// piece has a fixed length, as the bases following it are ignored.
+    pub max_len: Option<usize>,
+}
+
+impl ReadMetrics {
+    /// Computes the metrics of read `read` from the pieces of its geometry.
+    pub(crate) fn new(read: usize, pieces: &[GeomPiece]) -> Self {
+        let mut m = Self {
+            read,
+            capture_groups: 0,
+            anchors: 0,
+            variable_len_pieces: 0,
+            min_len: 0,
+            max_len: Some(0),
+        };
+        for gp in pieces {
+            let (lo, hi) = match gp {
+                GeomPiece::Fixed(NucStr::Seq(s)) => {
+                    m.anchors += 1;
+                    (s.len(), Some(s.len()))
+                }
+                GeomPiece::Discard(gl)
+                | GeomPiece::Barcode(gl)
+                | GeomPiece::Umi(gl)
+                | GeomPiece::ReadSeq(gl) => {
+                    if !matches!(gp, GeomPiece::Discard(_)) {
+                        m.capture_groups += 1;
+                    }
+                    match gl {
+                        GeomLen::FixedLen(x) => (*x as usize, Some(*x as usize)),
+                        GeomLen::LenRange(l, h) => {
+                            m.variable_len_pieces += 1;
+                            (*l as usize, Some(*h as usize))
+                        }
+                        GeomLen::Unbounded => {
+                            m.variable_len_pieces += 1;
+                            (0, None)
+                        }
+                    }
+                }
+            };
+            m.min_len += lo;
+            m.max_len = m.max_len.zip(hi).map(|(m, h)| m + h);
+        }
+        m
+    }
+}
+
+/// The metrics of a compiled geometry (see the module documentation).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeometryMetrics {
+    /// The metrics of read 1, read 2, then of any index read.
+    pub reads: Vec<ReadMetrics>,
+    /// Whether read 1 and read 2 are parsed by slicing their pieces out of
+    /// the reads rather than with their regex (see
+    /// [`crate::FragmentRegexDesc::bypasses_regex`]).
+    pub bypasses_regex: [bool; 2],
+    /// Whether the reads that do not match are matched again as their
+    /// reverse complement (see [`crate::FragmentRegexDesc::try_revcomp`]).
+    pub tries_revcomp: bool,
+    /// Whether the fragments that do not match are parsed again allowing
+    /// mismatches in the anchors (see
+    /// [`crate::FragmentRegexDesc::tolerates_anchor_mismatches`]).
+    pub tolerates_anchor_mismatches: bool,
+}
+
+impl GeometryMetrics {
+    /// The total number of captured pieces of all of the reads.
+    pub fn capture_groups(&self) -> usize {
+        self.reads.iter().map(|r| r.capture_groups).sum()
+    }
+
+    /// Whether any read of the geometry has a fixed (`f`) piece.
+    pub fn has_anchors(&self) -> bool {
+        self.reads.iter().any(|r| r.anchors > 0)
+    }
+
+    /// Whether any read of the geometry has a piece whose length is a range
+    /// or unbounded.
+    pub fn has_variable_len(&self) -> bool {
+        self.reads.iter().any(|r| r.variable_len_pieces > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn geometry_metrics() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let m = geo_re.metrics();
+        assert_eq!(m.reads.len(), 2);
+        assert_eq!((m.reads[0].capture_groups, m.reads[0].anchors), (3, 1));
+        assert_eq!((m.reads[0].min_len, m.reads[0].max_len), (33, Some(34)));
+        assert_eq!((m.reads[1].min_len, m.reads[1].max_len), (0, None));
+        assert_eq!(m.capture_groups(), 4);
+        assert!(m.has_anchors() && m.has_variable_len());
+        assert_eq!(m.bypasses_regex, [false, true]);
+
+        let m = FragmentGeomDesc::try_from("1{b[16]u[12]x:}2{x[2]r[50]}")
+            .unwrap()
+            .as_regex()
+            .unwrap()
+            .metrics();
+        assert_eq!((m.reads[1].min_len, m.reads[1].max_len), (52, Some(52)));
+        assert_eq!(m.reads[1].capture_groups, 1);
+        assert!(!m.has_anchors());
+        assert_eq!(m.bypasses_regex, [true, true]);
+    }
+}
@@ -0,0 +1,254 @@
+//! Cross-checking the barcodes of chemistries that repeat them on both reads.
+//!
+//! Some chemistries sequence the same barcode construct from both ends of
+//! the fragment, so that the geometry has barcode pieces in both reads (e.g.
+//! `1{b[16]u[12]}2{b[16]r:}`).  When [`crate::XformOptions::barcode_consistency`]
+//! is set, the barcodes captured from read 1 (concatenated, as they appear in
+//! the output) are compared with those captured from read 2, and the pairs
+//! whose barcodes differ by more than
+//! [`ConsistencyOptions::max_mismatches`] are either flagged in the headers
+//! of their records (with `barcode_mismatch=1`) or fail to parse.  The
+//! outcomes are counted in [`crate::XformStats::barcode_consistency`].
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use seq_geom_parser::GeomPiece;
+
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// What to do with a fragment whose read 1 and read 2 barcodes differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsistencyMode {
+    /// Write the fragment, marking its records as having inconsistent
+    /// barcodes.
+    Flag,
+    /// Do not write the fragment, which fails to parse.
+    Drop,
+}
+
+impl FromStr for ConsistencyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "flag" => Ok(ConsistencyMode::Flag),
+            "drop" => Ok(ConsistencyMode::Drop),
+            _ => bail!(
+                "unknown handling of inconsistent barcodes {} (expected flag or drop)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ConsistencyMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConsistencyMode::Flag => write!(f, "flag"),
+            ConsistencyMode::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Options controlling the cross-check of the read 1 and read 2 barcodes.
+#[derive(Debug, Clone)]
+pub struct ConsistencyOptions {
+    /// What to do with the fragments whose barcodes differ.
+    pub mode: ConsistencyMode,
+    /// The number of mismatches allowed between the read 1 and read 2
+    /// barcodes of a consistent fragment.
+    pub max_mismatches: u32,
+}
+
+/// The number of transformed fragments whose read 1 and read 2 barcodes
+/// agree or differ.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyCounts {
+    /// Fragments whose barcodes agree (up to the allowed mismatches).
+    pub consistent: u64,
+    /// Fragments whose barcodes differ, which were flagged or dropped.
+    pub inconsistent: u64,
+}
+
+/// Extracts the read 1 and read 2 barcodes of the fragments transformed by
+/// a given geometry, and compares them.
+#[derive(Debug)]
+pub(crate) struct BarcodeChecker {
+    spans: [Vec<PieceSpan>; 2],
+    bufs: [Vec<u8>; 2],
+    max_mismatches: u32,
+}
+
+impl BarcodeChecker {
+    /// Create a new `BarcodeChecker` for the fragments transformed by
+    /// `geo_re`, whose barcode pieces must lie at fixed offsets in the
+    /// transformed fragments, and add up to the same length in both reads.
+    pub(crate) fn new(geo_re: &FragmentRegexDesc, opts: &ConsistencyOptions) -> Result<Self> {
+        let spans = geo_re
+            .piece_spans(|gp| matches!(gp, GeomPiece::Barcode(_)))
+            .map_err(|gp| {
+                anyhow!(
+                    "checking the consistency of the barcodes requires the barcode pieces of \
+                     the geometry to be of bounded length and preceded only by bounded pieces, \
+                     but found {:?}",
+                    gp
+                )
+            })?;
+        let (spans1, spans2): (Vec<PieceSpan>, Vec<PieceSpan>) =
+            spans.into_iter().partition(|&(read, _, _)| read == 0);
+        if spans1.is_empty() || spans2.is_empty() {
+            bail!(
+                "checking the consistency of the barcodes requires the geometry to have \
+                 barcode (b) pieces in both reads"
+            );
+        }
+        let len = |s: &[PieceSpan]| s.iter().map(|&(_, _, len)| len).sum::<usize>();
+        if len(&spans1) != len(&spans2) {
+            bail!(
+                "the read 1 barcodes have length {}, but the read 2 barcodes have length {}",
+                len(&spans1),
+                len(&spans2)
+            );
+        }
+        Ok(Self {
+            spans: [spans1, spans2],
+            bufs: Default::default(),
+            max_mismatches: opts.max_mismatches,
+        })
+    }
+
+    /// Returns true if the read 1 and read 2 barcodes of `frag` differ by at
+    /// most the allowed number of mismatches, recording the outcome in
+    /// `counts`.
+    pub(crate) fn check(&mut self, frag: &SeqPair, counts: &mut ConsistencyCounts) -> bool {
+        for (spans, buf) in self.spans.iter().zip(self.bufs.iter_mut()) {
+            extract_spans(frag, spans, buf);
+        }
+        let [bc1, bc2] = &self.bufs;
+        let mismatches = bc1.iter().zip(bc2).filter(|(a, b)| a != b).count();
+        let consistent = mismatches <= self.max_mismatches as usize;
+        if consistent {
+            counts.consistent += 1;
+        } else {
+            counts.inconsistent += 1;
+        }
+        consistent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    /// A `BarcodeChecker` of the geometry `geom` allowing `max_mismatches`.
+    fn checker(geom: &str, max_mismatches: u32) -> Result<BarcodeChecker> {
+        BarcodeChecker::new(
+            &FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap(),
+            &ConsistencyOptions {
+                mode: ConsistencyMode::Flag,
+                max_mismatches,
+            },
+        )
+    }
+
+    #[test]
+    fn consistency_modes_are_parsed() {
+        for mode in [ConsistencyMode::Flag, ConsistencyMode::Drop] {
+            assert_eq!(mode.to_string().parse::<ConsistencyMode>().unwrap(), mode);
+        }
+        assert_eq!(
+            "DROP".parse::<ConsistencyMode>().unwrap(),
+            ConsistencyMode::Drop
+        );
+        assert!("keep".parse::<ConsistencyMode>().is_err());
+    }
+
+    #[test]
+    fn barcodes_are_compared_across_reads() {
+        // the barcodes must be in both reads, and as long in both
+        assert!(checker("1{b[4]u[2]}2{r:}", 0).is_err());
+        assert!(checker("1{u[2]}2{b[4]r:}", 0).is_err());
+        assert!(checker("1{b[4]u[2]}2{b[3]r:}", 0).is_err());
+        assert!(checker("1{b[4]u[2]}2{b:}", 0).is_err());
+
+        // the barcode pieces of each read are concatenated
+        let mut checker = checker("1{b[4]u[2]}2{b[1]x[1]b[3]r:}", 1).unwrap();
+        let mut counts = ConsistencyCounts::default();
+        let frag = |s2: &[u8]| SeqPair {
+            s1: b"AACCGG".to_vec(),
+            s2: s2.to_vec(),
+        };
+        assert!(checker.check(&frag(b"AACCTT"), &mut counts));
+        assert!(checker.check(&frag(b"AACGTT"), &mut counts));
+        assert!(!checker.check(&frag(b"TTCGTT"), &mut counts));
+        assert_eq!(
+            counts,
+            ConsistencyCounts {
+                consistent: 2,
+                inconsistent: 1
+            }
+        );
+    }
+
+    #[test]
+    fn inconsistent_barcodes_are_flagged_or_dropped() {
+        let geo = FragmentGeomDesc::try_from("1{b[4]u[2]}2{b[4]r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let mut outputs = vec![];
+        for (mode, max_mismatches) in [(ConsistencyMode::Flag, 0), (ConsistencyMode::Drop, 1)] {
+            let opts = XformOptions {
+                barcode_consistency: Some(ConsistencyOptions {
+                    mode,
+                    max_mismatches,
+                }),
+                ..XformOptions::default()
+            };
+            let out = xform_in_dir(
+                dir.path(),
+                geo.as_regex().unwrap(),
+                ">a\nAACCGG\n>b\nAACCGG\n>c\nAACCGG\n",
+                ">a\nAACCTT\n>b\nAACGTT\n>c\nTTCGTT\n",
+                &opts,
+            )
+            .unwrap();
+            outputs.push((
+                out.stats.barcode_consistency.unwrap(),
+                out.stats.failed_parsing,
+                out.out1,
+            ));
+        }
+        assert_eq!(
+            outputs[0],
+            (
+                ConsistencyCounts {
+                    consistent: 1,
+                    inconsistent: 2
+                },
+                0,
+                String::from(
+                    ">a\nAACCGG\n>b barcode_mismatch=1\nAACCGG\n>c barcode_mismatch=1\nAACCGG\n"
+                )
+            )
+        );
+        assert_eq!(
+            outputs[1],
+            (
+                ConsistencyCounts {
+                    consistent: 2,
+                    inconsistent: 1
+                },
+                1,
+                String::from(">a\nAACCGG\n>b\nAACCGG\n")
+            )
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Tagging of the fragments coming from spike-in or control constructs.
+//!
+//! Libraries often contain reads from known control sequences (e.g. the
+//! ERCC spike-ins, or the scaffold of the guides of a CRISPR screen).  When a
+//! [`ControlRef`] is given (through [`crate::XformOptions::controls`]), the
+//! biological piece (the `r` piece of the geometry) of each transformed
+//! fragment is compared with the controls: a fragment sharing a k-mer (of
+//! [`ControlRef::kmer_len`] bases, on either strand) with a single control
+//! matches it.  The name of the control is appended to the headers of the
+//! records of the fragment (as `control=<name>`), the fragment is optionally
+//! written to separate outputs rather than to the main ones (see
+//! [`ControlOptions::outputs`]), and the number of fragments matching each
+//! control is reported in [`crate::XformStats::control_counts`].
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use needletail::parse_fastx_file;
+
+use crate::reverse_complement_into;
+
+/// A reference of named control sequences, indexed by their k-mers.
+#[derive(Debug, Clone)]
+pub struct ControlRef {
+    names: Vec<String>,
+    kmer_len: usize,
+    /// The control containing each k-mer (on either strand), or `None` if
+    /// several controls contain it.
+    kmers: HashMap<Vec<u8>, Option<usize>>,
+}
+
+impl ControlRef {
+    /// The default length of the k-mers shared by a fragment and a control.
+    pub const DEFAULT_KMER_LEN: usize = 20;
+
+    /// Create a new `ControlRef` from pairs of control names and sequences,
+    /// indexing the k-mers of length `kmer_len` of the sequences.  The
+    /// sequences must consist only of `ACGTN` (case-insensitive) and be at
+    /// least `kmer_len` bases long.  The k-mers containing an `N` are not
+    /// indexed.
+    pub fn new(controls: Vec<(String, Vec<u8>)>, kmer_len: usize) -> Result<Self> {
+        if kmer_len == 0 {
+            bail!("the k-mers of the control sequences must have at least one base");
+        }
+        let mut cref = Self {
+            names: Vec::with_capacity(controls.len()),
+            kmer_len,
+            kmers: HashMap::new(),
+        };
+        let mut rc = vec![];
+        for (name, mut seq) in controls {
+            seq.make_ascii_uppercase();
+            if !seq.iter().all(|c| b"ACGTN".contains(c)) {
+                bail!("invalid sequence for control {}", name);
+            }
+            if seq.len() < kmer_len {
+                bail!(
+                    "control {} has {} bases, fewer than the k-mer length {}",
+                    name,
+                    seq.len(),
+                    kmer_len
+                );
+            }
+            let i = cref.names.len();
+            reverse_complement_into(&seq, &mut rc);
+            for s in [&seq, &rc] {
+                for kmer in s.windows(kmer_len).filter(|k| !k.contains(&b'N')) {
+                    cref.kmers
+                        .entry(kmer.to_vec())
+                        .and_modify(|c| {
+                            if *c != Some(i) {
+                                *c = None
+                            }
+                        })
+                        .or_insert(Some(i));
+                }
+            }
+            cref.names.push(name);
+        }
+        if cref.names.is_empty() {
+            bail!("the control reference contains no sequences");
+        }
+        Ok(cref)
+    }
+
+    /// Reads the control sequences from the FASTA (or FASTQ) file `p`,
+    /// naming each control after the id of its record (up to the first
+    /// space).
+    pub fn from_path<P: AsRef<Path>>(p: P, kmer_len: usize) -> Result<Self> {
+        let p = p.as_ref();
+        let mut reader = parse_fastx_file(p)
+            .with_context(|| format!("could not open control sequences {}", p.display()))?;
+        let mut controls = vec![];
+        while let Some(rec) = reader.next() {
+            let rec =
+                rec.with_context(|| format!("could not parse control sequences {}", p.display()))?;
+            let id = String::from_utf8_lossy(rec.id());
+            let name = id.split_whitespace().next().unwrap_or_default().to_owned();
+            controls.push((name, rec.seq().into_owned()));
+        }
+        Self::new(controls, kmer_len)
+            .with_context(|| format!("invalid control sequences {}", p.display()))
+    }
+
+    /// The number of controls in the reference.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Returns true if the reference contains no controls.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The name of the `i`-th control.
+    pub fn name(&self, i: usize) -> &str {
+        &self.names[i]
+    }
+
+    /// The length of the indexed k-mers.
+    pub fn kmer_len(&self) -> usize {
+        self.kmer_len
+    }
+
+    /// Returns the index of the control sharing the first k-mer of `s` found
+    /// in a single control, if any.
+    pub fn find(&self, s: &[u8]) -> Option<usize> {
+        s.windows(self.kmer_len)
+            .find_map(|k| self.kmers.get(k).copied().flatten())
+    }
+}
+
+/// Options for tagging the fragments matching control sequences.
+#[derive(Debug, Clone)]
+pub struct ControlOptions {
+    /// The reference of control sequences.
+    pub reference: ControlRef,
+    /// If set, the transformed fragments matching a control are written to
+    /// these read 1 and read 2 outputs (compressed as implied by their
+    /// extension) rather than to the main outputs.
+    pub outputs: Option<[PathBuf; 2]>,
+}
+
+/// The number of fragments matching each control of a [`ControlRef`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlCounts {
+    pub names: Vec<String>,
+    pub counts: Vec<u64>,
+}
+
+impl ControlCounts {
+    /// Create a new `ControlCounts` with a zero count for each of the
+    /// controls of `cref`.
+    pub fn new(cref: &ControlRef) -> Self {
+        Self {
+            names: cref.names.clone(),
+            counts: vec![0; cref.len()],
+        }
+    }
+
+    /// The total number of fragments matching some control.
+    pub fn matched(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Writes the counts as a TSV with a `control` and a `count` column,
+    /// with one row per control.
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "control\tcount")?;
+        for (n, c) in self.names.iter().zip(&self.counts) {
+            writeln!(w, "{}\t{}", n, c)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    /// The named controls `controls`.
+    fn controls(controls: &[(&str, &[u8])]) -> Vec<(String, Vec<u8>)> {
+        controls
+            .iter()
+            .map(|(n, s)| (String::from(*n), s.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn fragments_are_matched_to_controls_by_kmer() {
+        let cref = ControlRef::new(
+            controls(&[
+                ("ERCC-1", b"AACCGGTTACGTAGCTAGCA"),
+                ("scaffold", b"GTTTTAGAGCTAGAAATAGC"),
+            ]),
+            8,
+        )
+        .unwrap();
+        assert_eq!(
+            (cref.len(), cref.name(1), cref.kmer_len()),
+            (2, "scaffold", 8)
+        );
+        assert_eq!(cref.find(b"TTTTCCGGTTACGTTTT"), Some(0));
+        // the reverse complement of GAGCTAGAAA
+        assert_eq!(cref.find(b"TTTCTAGCTC"), Some(1));
+        // shares no 8-mer with either control
+        assert_eq!(cref.find(b"CCCCAGCTAGCCCC"), None);
+        // shorter than a k-mer
+        assert_eq!(cref.find(b"AACCGGT"), None);
+
+        // the k-mers of several controls, or holding an N, are not indexed,
+        // and the sequences are made uppercase
+        let cref = ControlRef::new(
+            controls(&[("a", b"aaaaccccggggNtttt"), ("b", b"CCCCGGGGAAAA")]),
+            4,
+        )
+        .unwrap();
+        assert_eq!(cref.find(b"CCGG"), None);
+        assert_eq!(cref.find(b"CCGGGGAAAA"), Some(1));
+        assert_eq!(cref.find(b"NAACCN"), Some(0));
+        assert_eq!(cref.find(b"GGNT"), None);
+    }
+
+    #[test]
+    fn invalid_controls_are_rejected() {
+        assert!(ControlRef::new(controls(&[("short", b"ACGT")]), 8).is_err());
+        assert!(ControlRef::new(controls(&[("iupac", b"ACGTRY")]), 4).is_err());
+        assert!(ControlRef::new(controls(&[("a", b"ACGT")]), 0).is_err());
+        assert!(ControlRef::new(vec![], 4).is_err());
+    }
+
+    #[test]
+    fn controls_are_read_and_counted() {
+        let dir = tempdir().unwrap();
+        let p = dir.path().join("controls.fa");
+        std::fs::write(&p, ">ERCC-1 spike-in\nAACCGGTT\n>phix\nGGGGTTTT\n").unwrap();
+        let cref = ControlRef::from_path(&p, 4).unwrap();
+        assert_eq!((cref.name(0), cref.name(1)), ("ERCC-1", "phix"));
+        std::fs::write(&p, ">ERCC-1\nAAC\n").unwrap();
+        assert!(ControlRef::from_path(&p, 4).is_err());
+
+        let mut counts = ControlCounts::new(&cref);
+        counts.counts[1] += 2;
+        assert_eq!(counts.matched(), 2);
+        let mut tsv = vec![];
+        counts.write_tsv(&mut tsv).unwrap();
+        assert_eq!(tsv, b"control\tcount\nERCC-1\t0\nphix\t2\n");
+    }
+
+    #[test]
+    fn control_fragments_are_tagged_and_routed() {
+        let cref = ControlRef::new(
+            controls(&[
+                ("ERCC-1", b"AACCGGTTACGTAGCTAGCA"),
+                ("scaffold", b"GTTTTAGAGCTAGAAATAGC"),
+            ]),
+            8,
+        )
+        .unwrap();
+        let geo = FragmentGeomDesc::try_from("1{b[2]x:}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        let mut outputs = vec![];
+        for routed in [false, true] {
+            let opts = XformOptions {
+                controls: Some(ControlOptions {
+                    reference: cref.clone(),
+                    outputs: routed.then(|| [p("c1.fa"), p("c2.fa")]),
+                }),
+                ..XformOptions::default()
+            };
+            let out = xform_in_dir(
+                dir.path(),
+                geo.as_regex().unwrap(),
+                ">a\nAAT\n>b\nCCT\n>c\nGGT\n",
+                ">a\nCCGGTTACGT\n>b\nTTTTTTTTTT\n>c\nTTTCTAGCTC\n",
+                &opts,
+            )
+            .unwrap();
+            assert_eq!(out.stats.control_counts.unwrap().counts, [1, 1]);
+            outputs.push(out.out1);
+        }
+        assert_eq!(
+            outputs[0],
+            ">a control=ERCC-1\nAA\n>b\nCC\n>c control=scaffold\nGG\n"
+        );
+        assert_eq!(outputs[1], ">b\nCC\n");
+        assert_eq!(
+            std::fs::read_to_string(p("c2.fa")).unwrap(),
+            ">a control=ERCC-1\nCCGGTTACGT\n>c control=scaffold\nTTTCTAGCTC\n"
+        );
+    }
+}
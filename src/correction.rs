@@ -0,0 +1,419 @@
+//! Strategies for correcting barcodes against a whitelist.
+//!
+//! The barcode of each transformed fragment is corrected against the
+//! whitelist of [`crate::XformOptions::whitelist`] (see the
+//! [`crate::whitelist`] module) by a [`BarcodeCorrector`], which decides
+//! whether the barcode is in the list, which barcode of the list it is
+//! corrected to, or that it cannot be corrected.  By default, a barcode at
+//! Hamming distance 1 from a single barcode of the list is corrected to it
+//! ([`OneMismatchCorrector`]).  The other built-in strategies are
+//! [`ExactCorrector`], which corrects nothing, [`FrequencyWeightedCorrector`],
+//! which settles the barcodes at distance 1 from several barcodes of the list
+//! by how often each was seen (e.g. in the barcode counts of an earlier run),
+//! and [`ExternalCorrector`], which asks an external program.  Any other
+//! strategy implements [`BarcodeCorrector`] and is registered in
+//! [`crate::XformOptions::barcode_corrector`], so that correction strategies
+//! can be tried without changing the transformation itself.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::whitelist::{BarcodeMatch, Whitelist};
+
+/// The default share of the weight of the candidate barcodes that the most
+/// frequent one must hold to be chosen by a [`FrequencyWeightedCorrector`].
+pub const DEFAULT_MIN_SHARE: f64 = 0.975;
+
+/// A strategy correcting the barcodes of the transformed fragments against
+/// a whitelist (see the module documentation).
+pub trait BarcodeCorrector: Send {
+    /// Looks up the barcode `bc` of a transformed fragment in `whitelist`,
+    /// returning whether it is in the list, the barcode of the list it is
+    /// corrected to (which must be as long as `bc`), or that it cannot be
+    /// corrected.  An error fails the transformation.
+    fn correct(&mut self, whitelist: &Whitelist, bc: &[u8]) -> Result<BarcodeMatch>;
+}
+
+impl<F> BarcodeCorrector for F
+where
+    F: FnMut(&Whitelist, &[u8]) -> Result<BarcodeMatch> + Send,
+{
+    fn correct(&mut self, whitelist: &Whitelist, bc: &[u8]) -> Result<BarcodeMatch> {
+        self(whitelist, bc)
+    }
+}
+
+/// Only keeps the barcodes that are in the whitelist.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExactCorrector;
+
+impl BarcodeCorrector for ExactCorrector {
+    fn correct(&mut self, whitelist: &Whitelist, bc: &[u8]) -> Result<BarcodeMatch> {
+        Ok(match whitelist.lookup(bc)? {
+            BarcodeMatch::Exact => BarcodeMatch::Exact,
+            _ => BarcodeMatch::Uncorrectable,
+        })
+    }
+}
+
+/// Corrects a barcode at Hamming distance 1 from a single barcode of the
+/// whitelist to that barcode (see [`Whitelist::lookup`]).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OneMismatchCorrector;
+
+impl BarcodeCorrector for OneMismatchCorrector {
+    fn correct(&mut self, whitelist: &Whitelist, bc: &[u8]) -> Result<BarcodeMatch> {
+        whitelist.lookup(bc)
+    }
+}
+
+/// Corrects a barcode at Hamming distance 1 from barcodes of the whitelist
+/// to the most frequent of them, if it holds at least `min_share` of their
+/// weight, each barcode weighing its count plus one (so that the barcodes
+/// never seen may still be chosen).  A barcode at distance 1 from a single
+/// barcode of the list is thus always corrected to it, as by
+/// [`OneMismatchCorrector`].
+#[derive(Debug, Clone, Default)]
+pub struct FrequencyWeightedCorrector {
+    counts: HashMap<Vec<u8>, u64>,
+    min_share: f64,
+    neighbor: Vec<u8>,
+}
+
+impl FrequencyWeightedCorrector {
+    /// Create a new `FrequencyWeightedCorrector` weighing the barcodes of
+    /// the whitelist by the counts `counts`.
+    pub fn new(counts: HashMap<Vec<u8>, u64>, min_share: f64) -> Self {
+        Self {
+            counts,
+            min_share,
+            neighbor: Vec::new(),
+        }
+    }
+
+    /// Create a new `FrequencyWeightedCorrector` weighing the barcodes of
+    /// the whitelist by the counts of the `barcode`/`count` TSV `p` (with a
+    /// header line, as written by `--barcode-counts`).
+    pub fn from_counts_path<P: AsRef<Path>>(p: P, min_share: f64) -> Result<Self> {
+        let p = p.as_ref();
+        let f = std::fs::File::open(p)
+            .with_context(|| format!("couldn't open the barcode counts {}", p.display()))?;
+        let mut counts = HashMap::new();
+        for (i, line) in BufReader::new(f).lines().enumerate().skip(1) {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = line
+                .split_once('\t')
+                .and_then(|(bc, n)| Some((bc, n.trim().parse::<u64>().ok()?)));
+            let Some((bc, n)) = parsed else {
+                bail!(
+                    "line {} of the barcode counts {} is not a barcode and a count",
+                    i + 1,
+                    p.display()
+                );
+            };
+            *counts
+                .entry(bc.to_ascii_uppercase().into_bytes())
+                .or_insert(0) += n;
+        }
+        Ok(Self::new(counts, min_share))
+    }
+}
+
+impl BarcodeCorrector for FrequencyWeightedCorrector {
+    fn correct(&mut self, whitelist: &Whitelist, bc: &[u8]) -> Result<BarcodeMatch> {
+        if whitelist.contains(bc)? {
+            return Ok(BarcodeMatch::Exact);
+        }
+        let (mut best, mut best_weight, mut total) = (None, 0, 0);
+        self.neighbor.clear();
+        self.neighbor.extend_from_slice(bc);
+        for (i, &orig) in bc.iter().enumerate() {
+            for &b in b"ACGT" {
+                if b == orig {
+                    continue;
+                }
+                self.neighbor[i] = b;
+                if !whitelist.contains(&self.neighbor)? {
+                    continue;
+                }
+                let weight = self.counts.get(&self.neighbor).copied().unwrap_or(0) + 1;
+                total += weight;
+                if weight > best_weight {
+                    (best, best_weight) = (Some(self.neighbor.clone()), weight);
+                }
+            }
+            self.neighbor[i] = orig;
+        }
+        Ok(match best {
+            Some(best) if best_weight as f64 >= self.min_share * total as f64 => {
+                BarcodeMatch::Corrected(best)
+            }
+            _ => BarcodeMatch::Uncorrectable,
+        })
+    }
+}
+
+/// Asks an external program (e.g. a client of a correction service) to
+/// correct the barcodes that are not in the whitelist.  The program is
+/// started once, and is sent each such barcode as a line on its standard
+/// input, to which it answers with a line on its standard output: the
+/// barcode it is corrected to, or `-` if it cannot be corrected.
+pub struct ExternalCorrector {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    command: String,
+    line: String,
+}
+
+impl fmt::Debug for ExternalCorrector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExternalCorrector")
+            .field("command", &self.command)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExternalCorrector {
+    /// Starts the program of the command line `cmd`, whose words are
+    /// separated by whitespace.
+    pub fn spawn(cmd: &str) -> Result<Self> {
+        let words: Vec<&str> = cmd.split_whitespace().collect();
+        let Some((program, args)) = words.split_first() else {
+            bail!("the barcode correction command must not be empty");
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("could not run the barcode corrector {:?}", cmd))?;
+        let stdin = BufWriter::new(child.stdin.take().unwrap());
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+            command: cmd.to_owned(),
+            line: String::new(),
+        })
+    }
+}
+
+impl BarcodeCorrector for ExternalCorrector {
+    fn correct(&mut self, whitelist: &Whitelist, bc: &[u8]) -> Result<BarcodeMatch> {
+        if whitelist.contains(bc)? {
+            return Ok(BarcodeMatch::Exact);
+        }
+        let context = || format!("the barcode corrector {:?} failed", self.command);
+        self.stdin.write_all(bc).with_context(context)?;
+        self.stdin.write_all(b"\n").with_context(context)?;
+        self.stdin.flush().with_context(context)?;
+        self.line.clear();
+        if self
+            .stdout
+            .read_line(&mut self.line)
+            .with_context(context)?
+            == 0
+        {
+            return Err(anyhow!("it exited without answering")).with_context(context);
+        }
+        let answer = self.line.trim_end().as_bytes();
+        if answer == b"-" {
+            return Ok(BarcodeMatch::Uncorrectable);
+        }
+        if answer.len() != bc.len() {
+            return Err(anyhow!(
+                "it corrected {} to {}, of another length",
+                String::from_utf8_lossy(bc),
+                self.line.trim_end()
+            ))
+            .with_context(context);
+        }
+        Ok(BarcodeMatch::Corrected(answer.to_ascii_uppercase()))
+    }
+}
+
+impl Drop for ExternalCorrector {
+    fn drop(&mut self) {
+        // the program may wait for more barcodes, which will never come
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A shareable handle on a [`BarcodeCorrector`], as registered in
+/// [`crate::XformOptions::barcode_corrector`].
+#[derive(Clone)]
+pub struct BarcodeCorrectorHandle {
+    corrector: Arc<Mutex<dyn BarcodeCorrector>>,
+}
+
+impl fmt::Debug for BarcodeCorrectorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BarcodeCorrectorHandle")
+            .finish_non_exhaustive()
+    }
+}
+
+impl BarcodeCorrectorHandle {
+    /// Create a new `BarcodeCorrectorHandle` correcting with `corrector`.
+    pub fn new<C: BarcodeCorrector + 'static>(corrector: C) -> Self {
+        Self {
+            corrector: Arc::new(Mutex::new(corrector)),
+        }
+    }
+
+    /// Locks the corrector for the duration of a transformation.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, dyn BarcodeCorrector + 'static> {
+        match self.corrector.lock() {
+            Ok(c) => c,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// A built-in correction strategy, as chosen on the command line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum CorrectionStrategy {
+    /// See [`ExactCorrector`].
+    Exact,
+    /// See [`OneMismatchCorrector`].
+    #[default]
+    OneMismatch,
+    /// See [`FrequencyWeightedCorrector::from_counts_path`], with the
+    /// [`DEFAULT_MIN_SHARE`].
+    FrequencyWeighted(PathBuf),
+    /// See [`ExternalCorrector::spawn`].
+    External(String),
+}
+
+impl CorrectionStrategy {
+    /// Builds the corrector of the strategy.
+    pub fn build(&self) -> Result<BarcodeCorrectorHandle> {
+        Ok(match self {
+            CorrectionStrategy::Exact => BarcodeCorrectorHandle::new(ExactCorrector),
+            CorrectionStrategy::OneMismatch => BarcodeCorrectorHandle::new(OneMismatchCorrector),
+            CorrectionStrategy::FrequencyWeighted(p) => BarcodeCorrectorHandle::new(
+                FrequencyWeightedCorrector::from_counts_path(p, DEFAULT_MIN_SHARE)?,
+            ),
+            CorrectionStrategy::External(cmd) => {
+                BarcodeCorrectorHandle::new(ExternalCorrector::spawn(cmd)?)
+            }
+        })
+    }
+}
+
+impl std::str::FromStr for CorrectionStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.split_once(':') {
+            None if s == "exact" => CorrectionStrategy::Exact,
+            None if s == "1mm" => CorrectionStrategy::OneMismatch,
+            Some(("frequency", p)) if !p.is_empty() => {
+                CorrectionStrategy::FrequencyWeighted(PathBuf::from(p))
+            }
+            Some(("external", cmd)) if !cmd.trim().is_empty() => {
+                CorrectionStrategy::External(cmd.to_owned())
+            }
+            _ => bail!(
+                "unknown correction strategy {}; expected exact, 1mm, frequency:<counts.tsv> \
+                 or external:<command>",
+                s
+            ),
+        })
+    }
+}
+
+impl fmt::Display for CorrectionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CorrectionStrategy::Exact => f.write_str("exact"),
+            CorrectionStrategy::OneMismatch => f.write_str("1mm"),
+            CorrectionStrategy::FrequencyWeighted(p) => write!(f, "frequency:{}", p.display()),
+            CorrectionStrategy::External(cmd) => write!(f, "external:{}", cmd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategies_correct_barcodes() {
+        let wl = Whitelist::new(vec![b"AAAA".to_vec(), b"AACC".to_vec()]).unwrap();
+        let corrected = |bc: &[u8]| BarcodeMatch::Corrected(bc.to_vec());
+
+        assert_eq!(
+            ExactCorrector.correct(&wl, b"AAAA").unwrap(),
+            BarcodeMatch::Exact
+        );
+        assert_eq!(
+            ExactCorrector.correct(&wl, b"AAAT").unwrap(),
+            BarcodeMatch::Uncorrectable
+        );
+        assert_eq!(
+            OneMismatchCorrector.correct(&wl, b"AAAT").unwrap(),
+            corrected(b"AAAA")
+        );
+        // AACA is at distance 1 from both barcodes
+        assert_eq!(
+            OneMismatchCorrector.correct(&wl, b"AACA").unwrap(),
+            BarcodeMatch::Uncorrectable
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let counts = dir.path().join("counts.tsv");
+        std::fs::write(&counts, "barcode\tcount\nAACC\t1000\nAAAA\t3\n").unwrap();
+        let mut fw =
+            FrequencyWeightedCorrector::from_counts_path(&counts, DEFAULT_MIN_SHARE).unwrap();
+        assert_eq!(fw.correct(&wl, b"AACA").unwrap(), corrected(b"AACC"));
+        assert_eq!(fw.correct(&wl, b"AAAT").unwrap(), corrected(b"AAAA"));
+        let mut even = FrequencyWeightedCorrector::new(HashMap::new(), DEFAULT_MIN_SHARE);
+        assert_eq!(
+            even.correct(&wl, b"AACA").unwrap(),
+            BarcodeMatch::Uncorrectable
+        );
+
+        let mut closure = |_: &Whitelist, _: &[u8]| Ok(BarcodeMatch::Uncorrectable);
+        assert_eq!(
+            closure.correct(&wl, b"AAAA").unwrap(),
+            BarcodeMatch::Uncorrectable
+        );
+
+        let mut ext = ExternalCorrector::spawn("sed -u s/.*/AAAA/").unwrap();
+        assert_eq!(ext.correct(&wl, b"AACC").unwrap(), BarcodeMatch::Exact);
+        assert_eq!(ext.correct(&wl, b"TTTT").unwrap(), corrected(b"AAAA"));
+        assert_eq!(
+            ext.correct(&wl, b"TTTTT").unwrap_err().to_string(),
+            "the barcode corrector \"sed -u s/.*/AAAA/\" failed"
+        );
+        let mut ext = ExternalCorrector::spawn("sed -u s/.*/-/").unwrap();
+        assert_eq!(
+            ext.correct(&wl, b"TTTT").unwrap(),
+            BarcodeMatch::Uncorrectable
+        );
+        assert!(ExternalCorrector::spawn("").is_err());
+
+        for s in [
+            "exact",
+            "1mm",
+            "frequency:counts.tsv",
+            "external:corrector --fast",
+        ] {
+            assert_eq!(s.parse::<CorrectionStrategy>().unwrap().to_string(), s);
+        }
+        assert!("frequency:".parse::<CorrectionStrategy>().is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Decoding of input files through an external program.
+//!
+//! Some input formats cannot be decoded by the crate itself, most notably
+//! the ORA compressed `FASTQ` files produced by Illumina DRAGEN, which many
+//! sequencing providers now deliver by default.  Such files are decoded by
+//! running an [`ExternalDecoder`] (by default, Illumina's `orad`) on them and
+//! reading the decoded records from its standard output, so that they need
+//! not be converted to gzip beforehand.
+
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+/// An external program decoding an input file to its standard output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalDecoder {
+    /// The program and its arguments; the path of the file to decode is
+    /// appended to them.
+    pub command: Vec<String>,
+}
+
+impl ExternalDecoder {
+    /// Create a new `ExternalDecoder` from a command line, whose words are
+    /// separated by whitespace (e.g. `orad -c --ora-reference /refs`).
+    pub fn from_command_line(cmd: &str) -> Result<Self> {
+        let command: Vec<String> = cmd.split_whitespace().map(String::from).collect();
+        if command.is_empty() {
+            bail!("the decoder command must not be empty");
+        }
+        Ok(Self { command })
+    }
+
+    /// Starts decoding the file `p`, returning a reader over the decoded
+    /// bytes.  Reading fails if the decoder exits unsuccessfully.
+    pub fn open(&self, p: &Path) -> Result<DecoderOutput> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .arg(p)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| {
+                format!(
+                    "could not run the decoder {:?} on {}",
+                    self.command.join(" "),
+                    p.display()
+                )
+            })?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(DecoderOutput {
+            child,
+            stdout,
+            command: self.command.join(" "),
+        })
+    }
+}
+
+impl Default for ExternalDecoder {
+    /// The decoder of ORA files, `orad -c` (which must be on the `PATH`).
+    fn default() -> Self {
+        Self {
+            command: vec![String::from("orad"), String::from("-c")],
+        }
+    }
+}
+
+/// Returns true if `p` names an ORA compressed file (i.e. has the `.ora`
+/// extension).
+pub fn is_ora(p: &Path) -> bool {
+    p.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|e| e.eq_ignore_ascii_case("ora"))
+}
+
+/// The decoded output of an [`ExternalDecoder`].
+#[derive(Debug)]
+pub struct DecoderOutput {
+    child: Child,
+    stdout: ChildStdout,
+    command: String,
+}
+
+impl Read for DecoderOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // a decoder failing midway may have written a truncated output
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "the decoder {:?} exited unsuccessfully ({})",
+                    self.command, status
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for DecoderOutput {
+    fn drop(&mut self) {
+        // the output may not have been read to its end
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn external_decoding() {
+        let dir = tempdir().unwrap();
+        let p = dir.path().join("reads.fastq.ora");
+        std::fs::write(&p, "@r\nACGT\n+\nIIII\n").unwrap();
+        assert!(is_ora(&p));
+        assert!(!is_ora(&dir.path().join("reads.fastq.gz")));
+
+        let mut out = String::new();
+        let cat = ExternalDecoder::from_command_line("cat").unwrap();
+        cat.open(&p).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "@r\nACGT\n+\nIIII\n");
+
+        let failing = ExternalDecoder::from_command_line("false").unwrap();
+        let mut out = vec![];
+        assert!(failing.open(&p).unwrap().read_to_end(&mut out).is_err());
+        assert!(ExternalDecoder::from_command_line(" ").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Streaming detection of exact duplicate fragments.
+//!
+//! Two transformed fragments are considered duplicates if they have the
+//! same barcodes and UMIs and the same prefix of their read sequence.  The
+//! fragments seen so far are recorded in a Bloom filter of a fixed size, so
+//! that the memory used does not grow with the size of the input.  As a
+//! consequence, a (small) fraction of unique fragments may be reported as
+//! duplicates, and this fraction grows as the number of fragments approaches
+//! the capacity of the filter (roughly one fragment per byte of memory).
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::Hasher;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use seq_geom_parser::{GeomLen, GeomPiece};
+
+use crate::{FragmentRegexDesc, PaddingScheme};
+
+/// What to do with a duplicate fragment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupMode {
+    /// Write the fragment, marking its records as duplicates.
+    Flag,
+    /// Do not write the fragment.
+    Drop,
+}
+
+impl FromStr for DedupMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "flag" => Ok(DedupMode::Flag),
+            "drop" => Ok(DedupMode::Drop),
+            _ => bail!("unknown duplicate handling {} (expected flag or drop)", s),
+        }
+    }
+}
+
+impl fmt::Display for DedupMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DedupMode::Flag => write!(f, "flag"),
+            DedupMode::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Options controlling the detection of duplicate fragments.
+#[derive(Debug, Clone)]
+pub struct DedupOptions {
+    /// What to do with duplicate fragments.
+    pub mode: DedupMode,
+    /// The number of bases of the read sequence that are compared.
+    pub prefix_len: usize,
+    /// The size (in bytes) of the filter recording the fragments seen so far.
+    pub memory: u64,
+}
+
+impl DedupOptions {
+    /// The default number of bases of the read sequence that are compared.
+    pub const DEFAULT_PREFIX_LEN: usize = 20;
+    /// The default size (in bytes) of the filter.
+    pub const DEFAULT_MEMORY: u64 = 256 << 20;
+}
+
+/// The part of a transformed read that contributes to the key of a fragment.
+#[derive(Debug, Clone, Copy)]
+enum KeyPart {
+    /// The bases `[start, start + len)`.
+    Span(usize, usize),
+    /// The bases from `start` on, up to at most `max` of them.
+    Tail(usize, Option<usize>),
+}
+
+/// Determines which parts of a transformed read with the (captured) pieces
+/// `pieces` make up its key: all of the barcodes and UMIs, and the first
+/// `prefix_len` bases of any read sequence.  Once an unbounded piece is
+/// reached, the offsets of subsequent pieces are unknown, so the rest of the
+/// read is used as is (unless the unbounded piece is the last one).
+fn key_layout(pieces: &[GeomPiece], padding: PaddingScheme, prefix_len: usize) -> Vec<KeyPart> {
+    let mut parts = vec![];
+    let mut offset = 0;
+    for (i, gp) in pieces.iter().enumerate() {
+        let is_last = i + 1 == pieces.len();
+        let (gl, is_read_seq) = match gp {
+            GeomPiece::Barcode(gl) | GeomPiece::Umi(gl) => (gl, false),
+            GeomPiece::ReadSeq(gl) => (gl, true),
+            _ => continue,
+        };
+        let len = match gl {
+            GeomLen::FixedLen(x) => *x as usize,
+            GeomLen::LenRange(l, h) => padding.padded_len(*l, *h) as usize,
+            GeomLen::Unbounded => {
+                let max = if is_read_seq && is_last {
+                    Some(prefix_len)
+                } else {
+                    None
+                };
+                parts.push(KeyPart::Tail(offset, max));
+                break;
+            }
+        };
+        let klen = if is_read_seq {
+            len.min(prefix_len)
+        } else {
+            len
+        };
+        parts.push(KeyPart::Span(offset, klen));
+        offset += len;
+    }
+    parts
+}
+
+/// A streaming filter of duplicate fragments.
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    layout1: Vec<KeyPart>,
+    layout2: Vec<KeyPart>,
+    bits: Vec<u64>,
+    num_bits: u64,
+}
+
+impl DuplicateFilter {
+    /// The number of bits set in the filter for each fragment.
+    const NUM_HASHES: u64 = 4;
+
+    /// Create a new `DuplicateFilter` for the fragments transformed by
+    /// `geo_re`, in accordance with `opts`.
+    pub fn new(geo_re: &FragmentRegexDesc, opts: &DedupOptions) -> Result<Self> {
+        if opts.memory < 8 {
+            bail!("the duplicate filter must use at least 8 bytes of memory");
+        }
+        let words = (opts.memory / 8) as usize;
+        Ok(Self {
+            layout1: key_layout(&geo_re.r1_cginfo, geo_re.padding(), opts.prefix_len),
+            layout2: key_layout(&geo_re.r2_cginfo, geo_re.padding(), opts.prefix_len),
+            bits: vec![0; words],
+            num_bits: words as u64 * 64,
+        })
+    }
+
+    /// Records the transformed fragment (`s1`, `s2`), returning true if an
+    /// identical fragment was (probably) seen before.
+    pub fn check_and_insert(&mut self, s1: &[u8], s2: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        for (s, layout) in [(s1, &self.layout1), (s2, &self.layout2)] {
+            for part in layout {
+                let k = match *part {
+                    KeyPart::Span(start, len) => s.get(start..(start + len).min(s.len())),
+                    KeyPart::Tail(start, max) => s
+                        .get(start..)
+                        .map(|t| &t[..max.map_or(t.len(), |m| m.min(t.len()))]),
+                };
+                hasher.write(k.unwrap_or_default());
+                // separate the parts, so that their boundaries are significant
+                hasher.write_u8(b'|');
+            }
+        }
+        let h = hasher.finish();
+        // derive the bit positions by double hashing
+        let (h1, h2) = (h, h.rotate_left(32) | 1);
+        let mut seen = true;
+        for i in 0..Self::NUM_HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            let (w, mask) = ((bit / 64) as usize, 1u64 << (bit % 64));
+            seen &= self.bits[w] & mask != 0;
+            self.bits[w] |= mask;
+        }
+        seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn duplicates_are_detected() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let opts = DedupOptions {
+            mode: DedupMode::Drop,
+            prefix_len: 6,
+            memory: 1 << 16,
+        };
+        let mut filter = DuplicateFilter::new(&geo_re, &opts).unwrap();
+        assert!(!filter.check_and_insert(b"ACGTTTTT", b"AAAAAACCCC"));
+        // only the first 6 bases of the read sequence are compared
+        assert!(filter.check_and_insert(b"ACGTTTTT", b"AAAAAAGGGG"));
+        assert!(!filter.check_and_insert(b"ACGTTTTA", b"AAAAAACCCC"));
+        assert!(!filter.check_and_insert(b"ACGTTTTT", b"AAAAATCCCC"));
+        assert_eq!("Drop".parse::<DedupMode>().unwrap(), DedupMode::Drop);
+        assert!("keep".parse::<DedupMode>().is_err());
+    }
+}
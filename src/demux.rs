@@ -0,0 +1,551 @@
+//! Demultiplexing of the transformed fragments by sample.
+//!
+//! In plate-based protocols, the fragments of many samples are sequenced
+//! together, and the sample of each fragment is identified by one of its
+//! barcodes (e.g. the well barcode of read 1, or the sample index captured
+//! from the read names, see the [`crate::name`] module).  When a
+//! [`SampleSheet`] mapping the barcodes to the samples is given (through
+//! [`crate::XformOptions::demux`]), the designated barcode piece of each
+//! transformed fragment is looked up in the sheet, and the fragment is
+//! written to the outputs of its sample rather than to the main outputs.
+//! The outputs of a sample are named after the main outputs, with the name
+//! of the sample inserted before their extension (see [`sample_path`]), so
+//! that `out/r1.fastq.gz` becomes `out/r1.A01.fastq.gz` for sample `A01`;
+//! a fifo created at that path beforehand is written to like a file.  The
+//! fragments whose barcode matches no sample (or several samples equally
+//! well) are written to the main outputs, and the number of fragments of
+//! each sample is reported in [`crate::XformStats::demux`].
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use seq_geom_parser::GeomPiece;
+
+use crate::feature::hamming_within;
+use crate::output::{PairedWriter, SinkConfig, SplitPolicy};
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// The name under which the fragments matching no sample are reported.
+pub const UNDETERMINED: &str = "undetermined";
+
+/// A mapping of sample barcodes to sample names.  A sample may have several
+/// barcodes (e.g. the four oligos of a 10x sample index set).
+#[derive(Debug, Clone)]
+pub struct SampleSheet {
+    names: Vec<String>,
+    /// The barcodes, and the sample of each.
+    barcodes: Vec<(Vec<u8>, usize)>,
+    exact: HashMap<Vec<u8>, usize>,
+}
+
+impl SampleSheet {
+    /// Create a new `SampleSheet` from pairs of sample names and barcodes.
+    /// The barcodes must consist only of `ACGTN` (case-insensitive), all
+    /// have the same length, and each map to a single sample.  As they name
+    /// output files, the sample names may only contain ASCII letters, digits,
+    /// `_`, `-` and `.` (but not start with `.`), and must not be
+    /// [`UNDETERMINED`].
+    pub fn new(samples: Vec<(String, Vec<u8>)>) -> Result<Self> {
+        let mut sheet = Self {
+            names: vec![],
+            barcodes: Vec::with_capacity(samples.len()),
+            exact: HashMap::with_capacity(samples.len()),
+        };
+        for (name, mut bc) in samples {
+            if name.is_empty()
+                || name.starts_with('.')
+                || name == UNDETERMINED
+                || !name
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || b"_-.".contains(&c))
+            {
+                bail!("invalid sample name {:?}", name);
+            }
+            bc.make_ascii_uppercase();
+            if bc.is_empty() || !bc.iter().all(|c| b"ACGTN".contains(c)) {
+                bail!("invalid barcode for sample {}", name);
+            }
+            if let Some((first, _)) = sheet.barcodes.first() {
+                if first.len() != bc.len() {
+                    bail!(
+                        "the barcodes of the samples must all have the same length, but sample {} has a barcode of length {} rather than {}",
+                        name,
+                        bc.len(),
+                        first.len()
+                    );
+                }
+            }
+            let i = match sheet.names.iter().position(|n| *n == name) {
+                Some(i) => i,
+                None => {
+                    sheet.names.push(name);
+                    sheet.names.len() - 1
+                }
+            };
+            if let Some(prev) = sheet.exact.insert(bc.clone(), i) {
+                bail!(
+                    "barcode {} is listed for both sample {} and sample {}",
+                    String::from_utf8_lossy(&bc),
+                    sheet.names[prev],
+                    sheet.names[i]
+                );
+            }
+            sheet.barcodes.push((bc, i));
+        }
+        if sheet.names.is_empty() {
+            bail!("the sample sheet contains no samples");
+        }
+        Ok(sheet)
+    }
+
+    /// Reads a sample sheet from the CSV or TSV file `p`.  See
+    /// [`SampleSheet::from_reader`] for the accepted formats.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let f = File::open(p)
+            .with_context(|| format!("could not open sample sheet {}", p.display()))?;
+        Self::from_reader(BufReader::new(f))
+            .with_context(|| format!("could not parse sample sheet {}", p.display()))
+    }
+
+    /// Reads a sample sheet with one barcode per line, with comma or tab
+    /// separated fields.  If the first line is a header containing a
+    /// `sample` (or `sample_id`) column, the barcodes are read from the
+    /// `barcode` (or `index`) column.  Otherwise, each line must consist of
+    /// a sample name followed by one of its barcodes.  Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn from_reader<R: BufRead>(r: R) -> Result<Self> {
+        let mut lines = r.lines().filter(|l| {
+            l.as_ref()
+                .map_or(true, |l| !l.trim().is_empty() && !l.starts_with('#'))
+        });
+        let Some(first) = lines.next().transpose()? else {
+            bail!("the sample sheet contains no samples");
+        };
+        let sep = if first.contains('\t') { '\t' } else { ',' };
+        let split = |l: &str| {
+            l.split(sep)
+                .map(|f| f.trim().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let header = split(&first);
+        let col = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|n| header.iter().position(|h| h.eq_ignore_ascii_case(n)))
+        };
+        let (cols, mut rows) = match col(&["sample", "sample_id"]) {
+            Some(name_col) => {
+                let bc_col = col(&["barcode", "index"])
+                    .ok_or_else(|| anyhow!("the header has no barcode column"))?;
+                ([name_col, bc_col], vec![])
+            }
+            None => ([0, 1], vec![header]),
+        };
+        for l in lines {
+            rows.push(split(&l?));
+        }
+        let samples = rows
+            .into_iter()
+            .map(|row| match cols.map(|c| row.get(c)) {
+                [Some(name), Some(bc)] => Ok((name.clone(), bc.as_bytes().to_vec())),
+                _ => Err(anyhow!("missing field in line {:?}", row.join(","))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(samples)
+    }
+
+    /// The number of samples in the sheet.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Returns true if the sheet contains no samples.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The name of the `i`-th sample.
+    pub fn name(&self, i: usize) -> &str {
+        &self.names[i]
+    }
+
+    /// The length of the barcodes of the samples.
+    pub fn barcode_len(&self) -> usize {
+        self.barcodes[0].0.len()
+    }
+
+    /// Returns the index of the sample of the barcode `bc`, along with the
+    /// number of mismatches between `bc` and the barcode of the sample.  An
+    /// exact match is preferred; otherwise, the sample with a barcode at the
+    /// smallest Hamming distance from `bc`, if this distance is at most
+    /// `max_mismatches` and no other sample is equally close.
+    pub fn find(&self, bc: &[u8], max_mismatches: u32) -> Option<(usize, u32)> {
+        if let Some(&i) = self.exact.get(bc) {
+            return Some((i, 0));
+        }
+        if max_mismatches == 0 || bc.len() != self.barcode_len() {
+            return None;
+        }
+        // (distance, sample) of the best match, and the distance of the
+        // best match to another sample
+        let mut best: Option<(u32, usize)> = None;
+        let mut second = u32::MAX;
+        for (b, i) in &self.barcodes {
+            let bound = best.map_or(max_mismatches, |(d, _)| d.min(max_mismatches));
+            let Some(d) = hamming_within(b, bc, bound) else {
+                continue;
+            };
+            match best {
+                Some((bd, bi)) if d >= bd => {
+                    if bi != *i {
+                        second = second.min(d);
+                    }
+                }
+                Some((bd, bi)) => {
+                    if bi != *i {
+                        second = bd;
+                    }
+                    best = Some((d, *i));
+                }
+                None => best = Some((d, *i)),
+            }
+        }
+        best.filter(|(d, _)| *d < second).map(|(d, i)| (i, d))
+    }
+}
+
+/// Options for demultiplexing the transformed fragments by sample.
+#[derive(Debug, Clone)]
+pub struct DemuxOptions {
+    /// The barcodes of the samples.
+    pub sheet: SampleSheet,
+    /// The barcode (`b`) piece identifying the sample, as its (1-based)
+    /// position among the barcode pieces of the transformed fragments
+    /// (those of read 1 first).
+    pub piece: usize,
+    /// The maximum number of mismatches allowed between the barcode of a
+    /// fragment and the barcode of its sample.
+    pub max_mismatches: u32,
+}
+
+/// The number of transformed fragments written to the outputs of each
+/// sample of a [`SampleSheet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DemuxCounts {
+    pub names: Vec<String>,
+    pub counts: Vec<u64>,
+    /// The number of fragments of each sample whose barcode had mismatches.
+    pub corrected: Vec<u64>,
+    /// The number of fragments matching no sample, which were written to the
+    /// main outputs.
+    pub undetermined: u64,
+}
+
+impl DemuxCounts {
+    /// Create a new `DemuxCounts` with a zero count for each of the samples
+    /// of `sheet`.
+    pub fn new(sheet: &SampleSheet) -> Self {
+        Self {
+            names: sheet.names.clone(),
+            counts: vec![0; sheet.len()],
+            corrected: vec![0; sheet.len()],
+            undetermined: 0,
+        }
+    }
+
+    /// The total number of fragments assigned to a sample.
+    pub fn assigned(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Writes the counts as a TSV with a `sample`, a `count` and a
+    /// `corrected` column, with one row per sample, followed by a row for
+    /// the [`UNDETERMINED`] fragments.
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "sample\tcount\tcorrected")?;
+        for ((n, c), x) in self.names.iter().zip(&self.counts).zip(&self.corrected) {
+            writeln!(w, "{}\t{}\t{}", n, c, x)?;
+        }
+        writeln!(w, "{}\t{}\t0", UNDETERMINED, self.undetermined)
+    }
+}
+
+/// Returns the path of the output `base` of the sample `sample`.  The name
+/// of the sample is inserted before the first extension of the file name,
+/// so that `out/r1.fastq.gz` becomes `out/r1.A01.fastq.gz`.
+pub fn sample_path(base: &Path, sample: &str) -> PathBuf {
+    let fname = base
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // a leading '.' (hidden file) is not the start of an extension
+    let split_at = fname
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| *c == '.')
+        .map(|(i, _)| i)
+        .unwrap_or(fname.len());
+    let (stem, ext) = fname.split_at(split_at);
+    base.with_file_name(format!("{}.{}{}", stem, sample, ext))
+}
+
+/// Assigns the transformed fragments to the samples of a [`SampleSheet`],
+/// and holds the outputs of the samples.
+#[derive(Debug)]
+pub(crate) struct Demultiplexer {
+    span: PieceSpan,
+    buf: Vec<u8>,
+    writers: Vec<PairedWriter>,
+}
+
+impl Demultiplexer {
+    /// Create a new `Demultiplexer` for the fragments transformed by
+    /// `geo_re`, opening the outputs of each sample next to the main
+    /// outputs `bases` (see [`sample_path`]), configured by `cfgs` and
+    /// `split` as the main outputs are.  If `interleaved`, the records of
+    /// each sample are interleaved in its read 1 output.
+    pub(crate) fn new(
+        geo_re: &FragmentRegexDesc,
+        opts: &DemuxOptions,
+        bases: [Option<&Path>; 2],
+        cfgs: [&SinkConfig; 2],
+        interleaved: bool,
+        split: Option<SplitPolicy>,
+    ) -> Result<Self> {
+        let spans = geo_re
+            .piece_spans(|gp| matches!(gp, GeomPiece::Barcode(_)))
+            .map_err(|gp| {
+                anyhow!(
+                    "demultiplexing requires the barcode pieces of the geometry to be of bounded \
+                     length and preceded only by bounded pieces, but found {:?}",
+                    gp
+                )
+            })?;
+        let Some(&span) = opts.piece.checked_sub(1).and_then(|i| spans.get(i)) else {
+            bail!(
+                "demultiplexing by barcode piece {}, but the geometry has {} barcode pieces",
+                opts.piece,
+                spans.len()
+            );
+        };
+        if span.2 != opts.sheet.barcode_len() {
+            bail!(
+                "the barcodes of the samples have length {}, but barcode piece {} has length {}",
+                opts.sheet.barcode_len(),
+                opts.piece,
+                span.2
+            );
+        }
+        let mut writers = Vec::with_capacity(opts.sheet.len());
+        for name in &opts.sheet.names {
+            let [p1, p2] = bases.map(|b| b.map(|b| sample_path(b, name)));
+            let w = match (interleaved, p1) {
+                (true, Some(p1)) => PairedWriter::new_interleaved(p1, cfgs[0], split),
+                (_, p1) => PairedWriter::new(p1, p2, cfgs[0], cfgs[1], split),
+            };
+            writers
+                .push(w.with_context(|| format!("could not open the outputs of sample {}", name))?);
+        }
+        Ok(Self {
+            span,
+            buf: vec![],
+            writers,
+        })
+    }
+
+    /// Returns the index of the sample of the transformed fragment `frag`,
+    /// if any, recording it in `counts`.
+    pub(crate) fn assign(
+        &mut self,
+        opts: &DemuxOptions,
+        frag: &SeqPair,
+        counts: &mut DemuxCounts,
+    ) -> Option<usize> {
+        extract_spans(frag, std::slice::from_ref(&self.span), &mut self.buf);
+        match opts.sheet.find(&self.buf, opts.max_mismatches) {
+            Some((i, d)) => {
+                counts.counts[i] += 1;
+                if d > 0 {
+                    counts.corrected[i] += 1;
+                }
+                Some(i)
+            }
+            None => {
+                counts.undetermined += 1;
+                None
+            }
+        }
+    }
+
+    /// The outputs of the `i`-th sample.
+    pub(crate) fn writer(&mut self, i: usize) -> &mut PairedWriter {
+        &mut self.writers[i]
+    }
+
+    /// Flushes and closes the outputs of all of the samples.
+    pub(crate) fn finish(self) -> Result<()> {
+        for w in self.writers {
+            w.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    fn sheet(s: &str) -> Result<SampleSheet> {
+        SampleSheet::from_reader(s.as_bytes())
+    }
+
+    #[test]
+    fn sample_sheets_are_parsed() {
+        let s = sheet("# plate 1\nlane,Sample_ID,index\n1,A01,acgt\n\n1,B01,GGTT\n").unwrap();
+        assert_eq!((s.len(), s.name(1), s.barcode_len()), (2, "B01", 4));
+        assert_eq!(s.find(b"ACGT", 0), Some((0, 0)));
+        let s = sheet("A01\tAAAA\nA01\tCCCC\n").unwrap();
+        assert_eq!((s.len(), s.find(b"CCCC", 0)), (1, Some((0, 0))));
+
+        for (bad, err) in [
+            ("", "no samples"),
+            ("# only a comment\n", "no samples"),
+            ("sample,lane\nA01,1\n", "no barcode column"),
+            ("A01,AAAA\nB01\n", "missing field"),
+            ("A01,AAAA\nB01,CCC\n", "same length"),
+            ("A01,AAAA\nB01,aaaa\n", "both sample A01 and sample B01"),
+            ("A01,AAXA\n", "invalid barcode"),
+            ("A01,\n", "invalid barcode"),
+            (".A01,AAAA\n", "invalid sample name"),
+            ("undetermined,AAAA\n", "invalid sample name"),
+            ("a/b,AAAA\n", "invalid sample name"),
+        ] {
+            let msg = format!("{:#}", sheet(bad).unwrap_err());
+            assert!(msg.contains(err), "{:?}: {}", bad, msg);
+        }
+    }
+
+    #[test]
+    fn barcodes_are_matched_to_their_sample() {
+        let sheet = sheet("sample,barcode\nA01,AAAA\nA01,CCCC\nB01,GGGG\nC01,GGTT\n").unwrap();
+        assert_eq!(sheet.len(), 3);
+        assert_eq!(sheet.find(b"CCCC", 0), Some((0, 0)));
+        assert_eq!(sheet.find(b"CCCA", 0), None);
+        assert_eq!(sheet.find(b"CCCA", 1), Some((0, 1)));
+        assert_eq!(sheet.find(b"ACCA", 1), None);
+        assert_eq!(sheet.find(b"ACCA", 2), Some((0, 2)));
+        // as close to B01 as to C01
+        assert_eq!(sheet.find(b"GGTG", 1), None);
+        // but two barcodes of the same sample are no tie
+        assert_eq!(sheet.find(b"AACC", 2), Some((0, 2)));
+        assert_eq!(sheet.find(b"GGGGA", 1), None);
+
+        assert_eq!(
+            sample_path(Path::new("out/r1.fastq.gz"), "A01"),
+            Path::new("out/r1.A01.fastq.gz")
+        );
+        assert_eq!(sample_path(Path::new("r1"), "A01"), Path::new("r1.A01"));
+        assert_eq!(
+            sample_path(Path::new(".r1.fa"), "A01"),
+            Path::new(".r1.A01.fa")
+        );
+    }
+
+    #[test]
+    fn fragments_are_assigned_to_their_sample() {
+        let dir = tempdir().unwrap();
+        let base = dir.path().join("o1.fa");
+        let opts = |piece| DemuxOptions {
+            sheet: sheet("A01,AAAA\nB01,GGGG\n").unwrap(),
+            piece,
+            max_mismatches: 1,
+        };
+        let demux = |geom: &str, piece| {
+            let geo_re = FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap();
+            let cfg = SinkConfig::default();
+            Demultiplexer::new(
+                &geo_re,
+                &opts(piece),
+                [Some(&base), None],
+                [&cfg, &cfg],
+                false,
+                None,
+            )
+        };
+        assert!(demux("1{b[2]b[4]}2{r:}", 0).is_err());
+        assert!(demux("1{b[2]b[4]}2{r:}", 3).is_err());
+        assert!(demux("1{b[2]b[4]}2{r:}", 1).is_err());
+        assert!(demux("1{u[2]b:}2{r:}", 1).is_err());
+
+        // the barcode piece may be in read 2
+        let mut d = demux("1{b[2]u[4]}2{b[4]r:}", 2).unwrap();
+        let mut counts = DemuxCounts::new(&opts(2).sheet);
+        for (s2, sample) in [
+            (&b"AAAAC"[..], Some(0)),
+            (b"GGCGC", Some(1)),
+            (b"AAGGC", None),
+        ] {
+            let frag = SeqPair {
+                s1: b"TTAAAA".to_vec(),
+                s2: s2.to_vec(),
+            };
+            assert_eq!(d.assign(&opts(2), &frag, &mut counts), sample);
+        }
+        d.finish().unwrap();
+        assert_eq!((counts.counts, counts.corrected), (vec![1, 1], vec![0, 1]));
+        assert_eq!(counts.undetermined, 1);
+        assert!(dir.path().join("o1.A01.fa").exists());
+        assert!(dir.path().join("o1.B01.fa").exists());
+    }
+
+    #[test]
+    fn fragments_are_demultiplexed_by_sample() {
+        let sheet = sheet("sample,barcode\nA01,AAAA\nA01,CCCC\nB01,GGGG\nC01,GGTT\n").unwrap();
+        let geo = FragmentGeomDesc::try_from("1{b[2]b[4]x:}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let out = xform_in_dir(
+            dir.path(),
+            geo.as_regex().unwrap(),
+            ">a\nTTAAAA\n>b\nTTGGGA\n>c\nTTGGTG\n>d\nTTCCCC\n",
+            ">a\nA\n>b\nC\n>c\nG\n>d\nT\n",
+            &XformOptions {
+                demux: Some(DemuxOptions {
+                    sheet,
+                    piece: 2,
+                    max_mismatches: 1,
+                }),
+                ..XformOptions::default()
+            },
+        )
+        .unwrap();
+        let counts = out.stats.demux.unwrap();
+        assert_eq!(counts.counts, [2, 1, 0]);
+        assert_eq!(counts.corrected, [0, 1, 0]);
+        assert_eq!(counts.undetermined, 1);
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("o1.A01.fa"), ">a\nTTAAAA\n>d\nTTCCCC\n");
+        assert_eq!(read("o2.A01.fa"), ">a\nA\n>d\nT\n");
+        assert_eq!(read("o1.B01.fa"), ">b\nTTGGGA\n");
+        assert_eq!(read("o1.C01.fa"), "");
+        assert_eq!(out.out1, ">c\nTTGGTG\n");
+
+        let mut tsv = vec![];
+        counts.write_tsv(&mut tsv).unwrap();
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "sample\tcount\tcorrected\nA01\t2\t0\nB01\t1\t1\nC01\t0\t0\nundetermined\t1\t0\n"
+        );
+    }
+}
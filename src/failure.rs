@@ -0,0 +1,187 @@
+//! Why fragments fail to parse.
+//!
+//! When [`crate::XformOptions::diagnose_failures`] is set, each fragment
+//! that fails to parse is examined again (see
+//! [`crate::FragmentRegexDesc::diagnose_failure`]) to find the read, and the
+//! piece of its geometry, responsible for the failure.  The failures are
+//! tallied in [`crate::XformStats::failures`], and reported in the JSON
+//! statistics (see [`crate::XformStats::write_json`]).
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The reason why a read does not match its part of the geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FailureCause {
+    /// The read is shorter than the minimum length of the geometry.
+    TooShort,
+    /// The read is longer than the maximum length of the geometry (which
+    /// only exists if the geometry ends with a variable length piece).
+    TooLong,
+    /// The read contains characters other than `A`, `C`, `G`, `T` and `N`.
+    InvalidBase,
+    /// The fixed piece (anchor) with this (0-based) index among the pieces of
+    /// the read's geometry was not found where it is expected.
+    AnchorNotFound { piece: usize },
+    /// The read passes the checks above, but does not match the geometry
+    /// (e.g. the lengths of the variable length pieces cannot fit between
+    /// its anchors).
+    NoMatch,
+    /// The read matches the geometry, but the barcode of the fragment is not
+    /// in the whitelist and cannot be corrected (see the
+    /// [`crate::whitelist`] module).
+    NotInWhitelist,
+    /// The reads match the geometry, but the barcodes captured from read 1
+    /// and read 2 differ (see the [`crate::consistency`] module).
+    InconsistentBarcodes,
+    /// The reads match the geometry, but the guide cannot be found in the
+    /// read sequence (see the [`crate::guide`] module).
+    GuideNotFound,
+    /// The reads match the geometry, but the read name does not match the
+    /// regex of the pieces captured from it (see the [`crate::name`]
+    /// module).
+    NameNotMatched,
+}
+
+impl FailureCause {
+    /// The name of the cause in the JSON statistics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailureCause::TooShort => "too_short",
+            FailureCause::TooLong => "too_long",
+            FailureCause::InvalidBase => "invalid_base",
+            FailureCause::AnchorNotFound { .. } => "anchor_not_found",
+            FailureCause::NoMatch => "no_match",
+            FailureCause::NotInWhitelist => "not_in_whitelist",
+            FailureCause::InconsistentBarcodes => "inconsistent_barcodes",
+            FailureCause::GuideNotFound => "guide_not_found",
+            FailureCause::NameNotMatched => "name_not_matched",
+        }
+    }
+
+    /// The index of the piece responsible for the failure, if it is due to
+    /// a specific piece.
+    pub fn piece(&self) -> Option<usize> {
+        match self {
+            FailureCause::AnchorNotFound { piece } => Some(*piece),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FailureCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.piece() {
+            Some(p) => write!(f, "{} (piece {})", self.name(), p + 1),
+            None => f.write_str(self.name()),
+        }
+    }
+}
+
+/// The first reason found for a fragment failing to parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ParseFailure {
+    /// The read (`1` to `4`, for the index reads) that does not match the
+    /// geometry.
+    pub read: usize,
+    pub cause: FailureCause,
+}
+
+/// The number of fragments failing to parse for each [`ParseFailure`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FailureCounts {
+    counts: BTreeMap<ParseFailure, u64>,
+}
+
+impl FailureCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, failure: ParseFailure) {
+        *self.counts.entry(failure).or_insert(0) += 1;
+    }
+
+    /// The failures observed and their counts, by read and then by cause.
+    pub fn iter(&self) -> impl Iterator<Item = (&ParseFailure, &u64)> {
+        self.counts.iter()
+    }
+
+    /// The total number of failures observed.
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Adds the counts of `other` to these counts.
+    pub fn merge(&mut self, other: &FailureCounts) {
+        for (f, c) in other.iter() {
+            *self.counts.entry(*f).or_insert(0) += c;
+        }
+    }
+}
+
+/// Writes `s` as a JSON string (with its quotes) to `out`.
+pub fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn failures_are_attributed() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[4]f[ACG]u[2-3]}2{b[2]f[TT]r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let diagnose = |geo_re: &mut crate::FragmentRegexDesc, r1: &[u8], r2: &[u8]| {
+            let f = geo_re.diagnose_failure(r1, r2);
+            (f.read, f.cause)
+        };
+        assert_eq!(
+            diagnose(&mut geo_re, b"AAAAACG", b"CCTTA"),
+            (1, FailureCause::TooShort)
+        );
+        assert_eq!(
+            diagnose(&mut geo_re, b"AAAAACGCCCC", b"CCTTA"),
+            (1, FailureCause::TooLong)
+        );
+        assert_eq!(
+            diagnose(&mut geo_re, b"AAAAAGGCC", b"CCTTA"),
+            (1, FailureCause::AnchorNotFound { piece: 1 })
+        );
+        assert_eq!(
+            diagnose(&mut geo_re, b"AAAAACGCX", b"CCTTA"),
+            (1, FailureCause::InvalidBase)
+        );
+        assert_eq!(
+            diagnose(&mut geo_re, b"AAAAACGCC", b"CCGTA"),
+            (2, FailureCause::AnchorNotFound { piece: 1 })
+        );
+
+        let mut counts = FailureCounts::new();
+        counts.record(geo_re.diagnose_failure(b"AAAAACG", b"CCTTA"));
+        counts.record(geo_re.diagnose_failure(b"AAAAACG", b"CCTTA"));
+        counts.record(geo_re.diagnose_failure(b"AAAAACGCC", b"CCGTA"));
+        assert_eq!(counts.total(), 3);
+        assert_eq!(counts.iter().next().map(|(_, &c)| c), Some(2));
+
+        let mut json = String::new();
+        push_json_string(&mut json, "1{f[\"A\\]}");
+        assert_eq!(json, r#""1{f[\"A\\]}""#);
+    }
+}
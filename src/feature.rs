@@ -0,0 +1,328 @@
+//! Matching of feature barcodes, for feature-barcoding libraries such as
+//! the antibody-derived tag (ADT) libraries of CITE-seq or the hashtag
+//! oligo (HTO) libraries of cell hashing.
+//!
+//! In these libraries, the "biological" read (the `r` piece of the
+//! geometry) is itself a short barcode identifying the feature.  When a
+//! [`FeatureRef`] is given (through [`crate::XformOptions::features`]),
+//! the transformed fragments are matched against it, the name of the
+//! matching feature is appended to the header of each output record, and
+//! the number of fragments matching each feature is reported in
+//! [`crate::XformStats::feature_counts`].
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use seq_geom_parser::{GeomLen, GeomPiece};
+
+use crate::FragmentRegexDesc;
+
+/// A reference of named feature barcodes.
+#[derive(Debug, Clone)]
+pub struct FeatureRef {
+    names: Vec<String>,
+    seqs: Vec<Vec<u8>>,
+    /// The distinct lengths of the feature barcodes, used for exact lookups.
+    lens: Vec<usize>,
+    exact: HashMap<Vec<u8>, usize>,
+}
+
+impl FeatureRef {
+    /// Create a new `FeatureRef` from pairs of feature names and barcode
+    /// sequences.  The barcodes must be non-empty, distinct and consist only
+    /// of `ACGTN` (case-insensitive).
+    pub fn new(features: Vec<(String, Vec<u8>)>) -> Result<Self> {
+        let mut fref = Self {
+            names: Vec::with_capacity(features.len()),
+            seqs: Vec::with_capacity(features.len()),
+            lens: vec![],
+            exact: HashMap::with_capacity(features.len()),
+        };
+        for (name, mut seq) in features {
+            seq.make_ascii_uppercase();
+            if seq.is_empty() || !seq.iter().all(|c| b"ACGTN".contains(c)) {
+                bail!(
+                    "invalid barcode {:?} for feature {}",
+                    String::from_utf8_lossy(&seq),
+                    name
+                );
+            }
+            if let Some(&i) = fref.exact.get(&seq) {
+                bail!(
+                    "features {} and {} have the same barcode {}",
+                    fref.names[i],
+                    name,
+                    String::from_utf8_lossy(&seq)
+                );
+            }
+            if !fref.lens.contains(&seq.len()) {
+                fref.lens.push(seq.len());
+            }
+            fref.exact.insert(seq.clone(), fref.names.len());
+            fref.names.push(name);
+            fref.seqs.push(seq);
+        }
+        if fref.names.is_empty() {
+            bail!("the feature reference contains no features");
+        }
+        Ok(fref)
+    }
+
+    /// Reads a feature reference from the CSV or TSV file `p`.  See
+    /// [`FeatureRef::from_reader`] for the accepted formats.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let f = File::open(p)
+            .with_context(|| format!("could not open feature reference {}", p.display()))?;
+        Self::from_reader(BufReader::new(f))
+            .with_context(|| format!("could not parse feature reference {}", p.display()))
+    }
+
+    /// Reads a feature reference with one feature per line, with comma or
+    /// tab separated fields.  If the first line is a header containing a
+    /// `sequence` column (as in the feature references of Cell Ranger), the
+    /// barcodes are read from that column and the names from the `id` (or
+    /// else the `name`) column.  Otherwise, each line must consist of the
+    /// name of a feature followed by its barcode.  Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn from_reader<R: BufRead>(r: R) -> Result<Self> {
+        let mut lines = r.lines().filter(|l| {
+            l.as_ref()
+                .map_or(true, |l| !l.trim().is_empty() && !l.starts_with('#'))
+        });
+        let Some(first) = lines.next().transpose()? else {
+            bail!("the feature reference contains no features");
+        };
+        let sep = if first.contains('\t') { '\t' } else { ',' };
+        let split = |l: &str| {
+            l.split(sep)
+                .map(|f| f.trim().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let header = split(&first);
+        let col = |c: &str| header.iter().position(|h| h.eq_ignore_ascii_case(c));
+        let (name_col, seq_col, mut features) = match col("sequence") {
+            Some(seq_col) => (col("id").or(col("name")).unwrap_or(0), seq_col, vec![]),
+            None => (0, 1, vec![header]),
+        };
+        for l in lines {
+            features.push(split(&l?));
+        }
+        let features = features
+            .into_iter()
+            .map(|f| match (f.get(name_col), f.get(seq_col)) {
+                (Some(n), Some(s)) => Ok((n.clone(), s.clone().into_bytes())),
+                _ => bail!(
+                    "expected a feature name and barcode, but got {:?}",
+                    f.join(",")
+                ),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(features)
+    }
+
+    /// The number of features in the reference.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Returns true if the reference contains no features.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The name of the `i`-th feature.
+    pub fn name(&self, i: usize) -> &str {
+        &self.names[i]
+    }
+
+    /// Returns the index of the feature whose barcode matches the start of
+    /// `s`.  An exact match is preferred; otherwise, the feature whose
+    /// barcode is at the smallest Hamming distance from `s`, if this
+    /// distance is at most `max_mismatches` and no other feature is equally
+    /// close.
+    pub fn find(&self, s: &[u8], max_mismatches: u32) -> Option<usize> {
+        for l in &self.lens {
+            if let Some(&i) = s.get(..*l).and_then(|p| self.exact.get(p)) {
+                return Some(i);
+            }
+        }
+        if max_mismatches == 0 {
+            return None;
+        }
+
+        // (distance, index) of the best match, and the distance of the
+        // second best match
+        let mut best: Option<(u32, usize)> = None;
+        let mut second = u32::MAX;
+        for (i, f) in self.seqs.iter().enumerate() {
+            let Some(p) = s.get(..f.len()) else {
+                continue;
+            };
+            let bound = best.map_or(max_mismatches, |(d, _)| d.min(max_mismatches));
+            if let Some(d) = hamming_within(f, p, bound) {
+                match best {
+                    Some((bd, _)) if d >= bd => second = second.min(d),
+                    _ => {
+                        second = best.map_or(second, |(bd, _)| bd);
+                        best = Some((d, i));
+                    }
+                }
+            }
+        }
+        best.filter(|(d, _)| *d < second).map(|(_, i)| i)
+    }
+}
+
+/// The Hamming distance between `a` and `b` (with `N` mismatching any
+/// base), or `None` if it exceeds `bound`.
+pub(crate) fn hamming_within(a: &[u8], b: &[u8], bound: u32) -> Option<u32> {
+    let mut d = 0;
+    for (x, y) in a.iter().zip(b) {
+        if x != y || *x == b'N' {
+            d += 1;
+            if d > bound {
+                return None;
+            }
+        }
+    }
+    Some(d)
+}
+
+/// Options for matching the transformed fragments against a [`FeatureRef`].
+#[derive(Debug, Clone)]
+pub struct FeatureOptions {
+    /// The reference of feature barcodes.
+    pub reference: FeatureRef,
+    /// The maximum number of mismatches allowed between a feature
+    /// barcode and the read.
+    pub max_mismatches: u32,
+}
+
+/// The number of fragments matching each feature of a [`FeatureRef`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureCounts {
+    pub names: Vec<String>,
+    pub counts: Vec<u64>,
+    /// The number of (transformed) fragments matching no feature.
+    pub unmatched: u64,
+}
+
+impl FeatureCounts {
+    /// Create a new `FeatureCounts` with a zero count for each of
+    /// the features of `fref`.
+    pub fn new(fref: &FeatureRef) -> Self {
+        Self {
+            names: fref.names.clone(),
+            counts: vec![0; fref.len()],
+            unmatched: 0,
+        }
+    }
+
+    /// The total number of fragments matching some feature.
+    pub fn matched(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Writes the counts as a TSV with a `feature` and a `count` column,
+    /// with one row per feature.
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "feature\tcount")?;
+        for (n, c) in self.names.iter().zip(&self.counts) {
+            writeln!(w, "{}\t{}", n, c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Determines where the feature barcode lies in the transformed fragments
+/// produced by `geo_re`.  The barcode is expected at the start of the
+/// (unique) `r` piece of the geometry; this returns the read (`0` for read
+/// 1 and `1` for read 2) containing it and its offset in the transformed
+/// read.
+pub(crate) fn feature_location(geo_re: &FragmentRegexDesc) -> Result<(usize, usize)> {
+    read_seq_location(geo_re, "matching features")
+}
+
+/// Returns the read (`0` for read 1 and `1` for read 2) containing the
+/// (unique) `r` piece of the transformed fragments produced by `geo_re`, and
+/// the offset of this piece in the transformed read.  `what` describes the
+/// processing requiring it, in the errors.
+pub(crate) fn read_seq_location(geo_re: &FragmentRegexDesc, what: &str) -> Result<(usize, usize)> {
+    let is_read_seq = |gp: &GeomPiece| matches!(gp, GeomPiece::ReadSeq(_));
+    let read = match (
+        geo_re.r1_cginfo.iter().any(is_read_seq),
+        geo_re.r2_cginfo.iter().any(is_read_seq),
+    ) {
+        (true, false) => 0,
+        (false, true) => 1,
+        (true, true) => bail!(
+            "{} requires the geometry to have a read sequence (r) piece in only one of the reads",
+            what
+        ),
+        (false, false) => {
+            bail!(
+                "{} requires the geometry to have a read sequence (r) piece",
+                what
+            )
+        }
+    };
+    let pieces = if read == 0 {
+        &geo_re.r1_cginfo
+    } else {
+        &geo_re.r2_cginfo
+    };
+    let mut offset = 0;
+    for gp in pieces.iter().take_while(|gp| !is_read_seq(gp)) {
+        match gp {
+            GeomPiece::Barcode(GeomLen::FixedLen(x)) | GeomPiece::Umi(GeomLen::FixedLen(x)) => {
+                offset += *x as usize
+            }
+            GeomPiece::Barcode(GeomLen::LenRange(l, h))
+            | GeomPiece::Umi(GeomLen::LenRange(l, h)) => {
+                offset += geo_re.padding().padded_len(*l, *h) as usize
+            }
+            _ => bail!(
+                "{} requires the pieces preceding the read sequence (r) piece to be bounded, \
+                 but found {:?}",
+                what,
+                gp
+            ),
+        }
+    }
+    Ok((read, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_ref_matching() {
+        let csv = "id,name,read,pattern,sequence,feature_type\n\
+                   CD3,CD3_TotalSeqB,R2,5PNNNNNNNNNN(BC),AACAAGACCCTTGAG,Antibody Capture\n\
+                   CD4,CD4_TotalSeqB,R2,5PNNNNNNNNNN(BC),TACCCGTAATAGCGT,Antibody Capture\n";
+        let fref = FeatureRef::from_reader(csv.as_bytes()).unwrap();
+        assert_eq!(fref.len(), 2);
+        assert_eq!(fref.name(1), "CD4");
+        assert_eq!(fref.find(b"AACAAGACCCTTGAGTTTT", 0), Some(0));
+        assert_eq!(fref.find(b"AACAAGACCCTTGAT", 0), None);
+        assert_eq!(fref.find(b"AACAAGACCCTTGAT", 1), Some(0));
+        assert_eq!(fref.find(b"TACCCGTAATAGCNN", 1), None);
+        assert_eq!(fref.find(b"TACCCGTAATAGCNN", 2), Some(1));
+        assert_eq!(fref.find(b"AACAAG", 2), None);
+
+        let tsv = "# hashtags\nHTO1\tGTCAACTCTTTAGCG\nHTO2\tgtcaactctttagct\n";
+        let fref = FeatureRef::from_reader(tsv.as_bytes()).unwrap();
+        assert_eq!(fref.find(b"GTCAACTCTTTAGCT", 0), Some(1));
+        // equally close to both features
+        assert_eq!(fref.find(b"GTCAACTCTTTAGCA", 1), None);
+
+        assert!(FeatureRef::from_reader("A,ACGT\nB,ACGT\n".as_bytes()).is_err());
+        assert!(FeatureRef::from_reader("A,ACGX\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//! Framing of the records written into a stream.
+//!
+//! A consumer reading the transformed records from a fifo cannot tell a
+//! stream cut short (e.g. because the transformation failed, or was killed)
+//! from a complete one, and would parse a partial last record as if it were
+//! whole.  With [`crate::output::SinkConfig::framed`], an (uncompressed)
+//! output is instead written as a series of chunks, each holding only
+//! complete records, and checked by a CRC32:
+//!
+//! - the stream starts with the 4 bytes [`MAGIC`] (`SGXF`);
+//! - each chunk is written as its length and the CRC32 of its data (both as
+//!   little-endian `u32`s), followed by its data;
+//! - the stream ends with an empty chunk (whose length and CRC32 are 0).
+//!
+//! A [`FrameReader`] reads back the records of a framed stream, checking each
+//! chunk before any of its data is returned, and fails if the stream is
+//! truncated (i.e. if it ends before its empty chunk) or corrupted, so that a
+//! consumer never sees a partial record.  Compressed outputs are not framed,
+//! as the gzip, BGZF and zstd formats already carry their own checksums and
+//! end markers.
+
+use std::io::{self, Read, Write};
+
+use flate2::Crc;
+
+/// The bytes starting a framed stream.
+pub const MAGIC: [u8; 4] = *b"SGXF";
+/// The number of bytes of records buffered before they are written as a
+/// chunk; a chunk is only written at the end of a record, so it may be
+/// longer.
+const CHUNK_DATA_LEN: usize = 1 << 16;
+/// The maximum length of a chunk, beyond which the length of a chunk is
+/// taken to be corrupted rather than allocated.
+const MAX_CHUNK_LEN: usize = 1 << 30;
+
+/// The CRC32 of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Writes the records written to it into chunks, written to the wrapped
+/// writer.  The end of each record is marked with
+/// [`FrameWriter::end_record`].
+#[derive(Debug)]
+pub(crate) struct FrameWriter<W: Write> {
+    inner: W,
+    /// The records of the chunk being filled, followed by the record being
+    /// written.
+    buf: Vec<u8>,
+    /// The number of bytes of the complete records in `buf`.
+    complete: usize,
+    /// Whether the magic bytes were written.
+    started: bool,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(CHUNK_DATA_LEN),
+            complete: 0,
+            started: false,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Marks the end of a record, writing the complete records as a chunk
+    /// once there are enough of them.
+    pub(crate) fn end_record(&mut self) -> io::Result<()> {
+        self.complete = self.buf.len();
+        if self.complete >= CHUNK_DATA_LEN {
+            self.write_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the first `data_len` bytes buffered as a chunk (the empty
+    /// chunk ending the stream if `data_len` is 0).
+    fn write_frame(&mut self, data_len: usize) -> io::Result<()> {
+        if !self.started {
+            self.inner.write_all(&MAGIC)?;
+            self.started = true;
+        }
+        if data_len > MAX_CHUNK_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a record of {} bytes is too long to be framed", data_len),
+            ));
+        }
+        let data = &self.buf[..data_len];
+        self.inner.write_all(&(data_len as u32).to_le_bytes())?;
+        self.inner.write_all(&crc32(data).to_le_bytes())?;
+        self.inner.write_all(data)?;
+        self.buf.drain(..data_len);
+        self.complete = 0;
+        Ok(())
+    }
+
+    /// Writes the complete records buffered so far as a chunk.
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.complete == 0 {
+            return Ok(());
+        }
+        self.write_frame(self.complete)
+    }
+
+    /// Writes any buffered records and the empty chunk ending the stream,
+    /// returning the wrapped writer.  Any bytes written after the end of
+    /// the last record are taken as a record.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.complete = self.buf.len();
+        self.write_chunk()?;
+        self.write_frame(0)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Writes the complete records as a chunk; the record being written, if
+    /// any, is left for the next chunk.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+/// Reads the records of a framed stream (see the module documentation),
+/// e.g. `FrameReader::new(File::open(&data.r1_fifo)?)`, which can be handed
+/// to any parser of the records.  Reading fails with an error of kind
+/// [`io::ErrorKind::UnexpectedEof`] if the stream is truncated, or of kind
+/// [`io::ErrorKind::InvalidData`] if it is not framed or a chunk is
+/// corrupted, in both cases before any data of the faulty chunk is returned.
+#[derive(Debug)]
+pub struct FrameReader<R: Read> {
+    inner: R,
+    /// The data of the current chunk.
+    chunk: Vec<u8>,
+    /// The number of bytes of the current chunk already read.
+    pos: usize,
+    /// The number of chunks read so far.
+    chunks: u64,
+    started: bool,
+    /// Whether the empty chunk ending the stream was read.
+    ended: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            chunk: vec![],
+            pos: 0,
+            chunks: 0,
+            started: false,
+            ended: false,
+        }
+    }
+
+    /// Reads the next chunk, returning its records, or `None` once the end
+    /// of the stream was read.  The records of a chunk are always complete.
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        if self.ended {
+            return Ok(None);
+        }
+        if !self.started {
+            let mut magic = [0; 4];
+            self.read_exact_or_truncated(&mut magic)?;
+            if magic != MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the stream is not framed (it does not start with SGXF)",
+                ));
+            }
+            self.started = true;
+        }
+        let mut header = [0; 8];
+        self.read_exact_or_truncated(&mut header)?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if len == 0 {
+            if crc != 0 {
+                return Err(self.corrupted());
+            }
+            self.ended = true;
+            return Ok(None);
+        }
+        if len > MAX_CHUNK_LEN {
+            return Err(self.corrupted());
+        }
+        // the chunk is only kept (and then returned by `read`) once checked
+        let mut chunk = std::mem::take(&mut self.chunk);
+        chunk.resize(len, 0);
+        self.pos = 0;
+        self.read_exact_or_truncated(&mut chunk)?;
+        if crc32(&chunk) != crc {
+            return Err(self.corrupted());
+        }
+        self.chunk = chunk;
+        self.chunks += 1;
+        Ok(Some(&self.chunk))
+    }
+
+    /// The number of chunks read so far.
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Fills `buf`, failing with an error of kind
+    /// [`io::ErrorKind::UnexpectedEof`] if the stream ends first.
+    fn read_exact_or_truncated(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the framed stream is truncated after {} chunks",
+                    self.chunks
+                ),
+            ),
+            _ => e,
+        })
+    }
+
+    fn corrupted(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "chunk {} of the framed stream is corrupted",
+                self.chunks + 1
+            ),
+        )
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            self.chunk.clear();
+            self.pos = 0;
+            if self.next_chunk()?.is_none() {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{Compression, SinkConfig};
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn framed_streams_are_checked() {
+        let mut w = FrameWriter::new(vec![]);
+        let mut records = vec![];
+        for i in 0..5000 {
+            let rec = format!(">r{}\nACGTACGTACGTACGTACGT\n", i);
+            w.write_all(rec.as_bytes()).unwrap();
+            w.end_record().unwrap();
+            records.extend_from_slice(rec.as_bytes());
+        }
+        // a record left unfinished by a flush is written in the next chunk
+        w.write_all(b">last\n").unwrap();
+        w.flush().unwrap();
+        w.write_all(b"ACGT\n").unwrap();
+        records.extend_from_slice(b">last\nACGT\n");
+        let out = w.finish().unwrap();
+
+        let mut r = FrameReader::new(&out[..]);
+        while let Some(chunk) = r.next_chunk().unwrap() {
+            assert!(chunk.starts_with(b">") && chunk.ends_with(b"\n"));
+        }
+        assert!(r.chunks() > 1);
+        let mut read = vec![];
+        FrameReader::new(&out[..]).read_to_end(&mut read).unwrap();
+        assert_eq!(read, records);
+
+        // truncated in the middle of a chunk, or before the end chunk
+        for len in [out.len() / 2, out.len() - 8] {
+            let err = FrameReader::new(&out[..len])
+                .read_to_end(&mut vec![])
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        let mut corrupted = out.clone();
+        corrupted[100] ^= 1;
+        let err = FrameReader::new(&corrupted[..])
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = FrameReader::new(&records[..])
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn chunks_hold_whole_records() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        // an empty stream is only its end
+        let out = FrameWriter::new(vec![]).finish().unwrap();
+        assert_eq!(out, b"SGXF\0\0\0\0\0\0\0\0");
+        let mut r = FrameReader::new(&out[..]);
+        assert!(r.next_chunk().unwrap().is_none());
+        assert!(r.next_chunk().unwrap().is_none());
+        assert_eq!(r.chunks(), 0);
+
+        // a record longer than a chunk is not split, and the bytes written
+        // after the last record are taken as one
+        let long = vec![b'A'; CHUNK_DATA_LEN + 10];
+        let mut w = FrameWriter::new(vec![]);
+        w.write_all(&long).unwrap();
+        w.end_record().unwrap();
+        assert_eq!(w.get_ref().len(), 4 + 8 + long.len());
+        w.write_all(b"tail").unwrap();
+        let out = w.finish().unwrap();
+        let mut r = FrameReader::new(&out[..]);
+        assert_eq!(r.next_chunk().unwrap().unwrap(), &long[..]);
+        assert_eq!(r.next_chunk().unwrap().unwrap(), b"tail");
+        assert!(r.next_chunk().unwrap().is_none());
+        assert_eq!(r.chunks(), 2);
+
+        // nor is a chunk length beyond the maximum allocated, or an end
+        // chunk with a CRC32 accepted
+        let mut corrupted = MAGIC.to_vec();
+        corrupted.extend_from_slice(&u32::MAX.to_le_bytes());
+        corrupted.extend_from_slice(&[0; 4]);
+        let err = FrameReader::new(&corrupted[..]).next_chunk().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let end = [&MAGIC[..], &[0; 4], &[1, 0, 0, 0]].concat();
+        let err = FrameReader::new(&end[..]).next_chunk().unwrap_err();
+        assert_eq!(err.to_string(), "chunk 1 of the framed stream is corrupted");
+        let err = FrameReader::new(&MAGIC[..2]).next_chunk().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn transformed_reads_are_framed() {
+        let dir = tempfile::tempdir().unwrap();
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let framed = SinkConfig {
+            framed: true,
+            ..SinkConfig::default()
+        };
+        let run = |out1: SinkConfig| {
+            let opts = XformOptions {
+                out1,
+                out2: framed.clone(),
+                ..XformOptions::default()
+            };
+            xform_in_dir(
+                dir.path(),
+                geo_re.clone(),
+                ">a\nACGTTT\n>b\nGGGGAA\n",
+                ">a\nCCCC\n>b\nTTTT\n",
+                &opts,
+            )
+        };
+        run(framed.clone()).unwrap();
+        let unframed = |name: &str| {
+            let mut out = String::new();
+            FrameReader::new(std::fs::File::open(dir.path().join(name)).unwrap())
+                .read_to_string(&mut out)
+                .unwrap();
+            out
+        };
+        assert_eq!(unframed("o1.fa"), ">a\nACGTTT\n>b\nGGGGAA\n");
+        assert_eq!(unframed("o2.fa"), ">a\nCCCC\n>b\nTTTT\n");
+
+        // compressed outputs carry their own checksums
+        assert!(run(SinkConfig {
+            compression: Compression::Gzip(1),
+            ..framed.clone()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Extraction of the protospacers of CRISPR guide-capture libraries.
+//!
+//! In the guide-capture libraries of CRISPR screens (e.g. Perturb-seq), the
+//! "biological" read (the `r` piece of the geometry) covers the guide RNA:
+//! its protospacer lies between known sequences, such as the end of the U6
+//! promoter upstream and the guide scaffold downstream, at an offset that
+//! varies from read to read.  When [`crate::XformOptions::guides`] is set,
+//! the protospacer is located between these anchors (allowing up to
+//! [`GuideOptions::max_mismatches`] mismatches in each, and looking on the
+//! reverse strand if it is not found on the forward one), and the `r` piece
+//! of the transformed fragment is replaced by the protospacer, in the
+//! orientation of the guide.  The fragments in which it cannot be found fail
+//! to parse.  The outcomes are counted in [`crate::XformStats::guides`].
+//!
+//! The guides can then be counted directly downstream, or within this crate
+//! by passing them as features (see the [`crate::feature`] module).
+
+use anyhow::{bail, Result};
+use seq_geom_parser::{GeomLen, GeomPiece};
+
+use crate::{feature, reverse_complement_into, FragmentRegexDesc, SeqPair};
+
+/// Options describing where the protospacer lies in the read sequence.
+#[derive(Debug, Clone)]
+pub struct GuideOptions {
+    /// The sequence immediately upstream of the protospacer (e.g. the end
+    /// of the U6 promoter, `CACCG`), or an empty sequence if the read does
+    /// not cover it.
+    pub upstream: Vec<u8>,
+    /// The sequence immediately downstream of the protospacer (e.g. the
+    /// start of the scaffold, `GTTTTAGA`), or an empty sequence if the read
+    /// does not cover it.
+    pub downstream: Vec<u8>,
+    /// The length of the protospacer, which must be given unless both
+    /// anchors are.  With both anchors, the protospacers of other lengths
+    /// are not found.
+    pub guide_len: Option<usize>,
+    /// The number of mismatches allowed in each anchor.
+    pub max_mismatches: u32,
+}
+
+impl GuideOptions {
+    /// Checks that the anchors consist only of `ACGT`, and that they locate
+    /// the protospacer.
+    pub fn validate(&self) -> Result<()> {
+        for anchor in [&self.upstream, &self.downstream] {
+            if !anchor.iter().all(|c| b"ACGT".contains(c)) {
+                bail!(
+                    "invalid guide anchor {} (expected only A, C, G and T)",
+                    String::from_utf8_lossy(anchor)
+                );
+            }
+        }
+        if self.upstream.is_empty() && self.downstream.is_empty() {
+            bail!("locating the guides requires an upstream or a downstream anchor");
+        }
+        match self.guide_len {
+            Some(0) => bail!("the guides must have at least one base"),
+            None if self.upstream.is_empty() || self.downstream.is_empty() => {
+                bail!("locating the guides with a single anchor requires their length")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the start and end of the protospacer in `s`, on the forward
+    /// strand, if found.  Each anchor is matched at its leftmost position
+    /// (after the upstream anchor, for the downstream one) with at most
+    /// [`GuideOptions::max_mismatches`] mismatches.
+    pub fn locate(&self, s: &[u8]) -> Option<(usize, usize)> {
+        let start = if self.upstream.is_empty() {
+            None
+        } else {
+            Some(find_anchor(s, &self.upstream, self.max_mismatches)? + self.upstream.len())
+        };
+        if self.downstream.is_empty() {
+            let start = start?;
+            let end = start + self.guide_len?;
+            return (end <= s.len()).then_some((start, end));
+        }
+        let from = start.unwrap_or(0) + self.guide_len.unwrap_or(0);
+        let end = from + find_anchor(s.get(from..)?, &self.downstream, self.max_mismatches)?;
+        match (start, self.guide_len) {
+            (Some(start), Some(len)) => (end - start == len).then_some((start, end)),
+            (Some(start), None) => (end > start).then_some((start, end)),
+            (None, len) => Some((end - len?, end)),
+        }
+    }
+}
+
+/// Returns the leftmost position of `anchor` in `s` with at most
+/// `max_mismatches` mismatches.
+fn find_anchor(s: &[u8], anchor: &[u8], max_mismatches: u32) -> Option<usize> {
+    s.windows(anchor.len()).position(|w| {
+        w.iter().zip(anchor).filter(|(a, b)| a != b).count() <= max_mismatches as usize
+    })
+}
+
+/// The number of transformed fragments in which the protospacer was found
+/// on each strand, or not found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuideCounts {
+    /// Fragments whose read sequence holds the guide on the forward strand.
+    pub forward: u64,
+    /// Fragments whose read sequence holds the reverse complement of the
+    /// guide.
+    pub reverse: u64,
+    /// Fragments in which the guide was not found, which failed to parse.
+    pub not_found: u64,
+}
+
+/// Replaces the read sequence of the transformed fragments with the
+/// protospacer it holds.
+#[derive(Debug)]
+pub(crate) struct GuideExtractor {
+    opts: GuideOptions,
+    /// The read (`0` for read 1 and `1` for read 2) containing the `r` piece,
+    /// its offset, and its length (`None` if it extends to the end of the
+    /// read).
+    read: usize,
+    offset: usize,
+    len: Option<usize>,
+    rc: Vec<u8>,
+}
+
+impl GuideExtractor {
+    /// Create a new `GuideExtractor` for the fragments transformed by
+    /// `geo_re`, whose `r` piece must lie at a fixed offset in the
+    /// transformed fragments.
+    pub(crate) fn new(geo_re: &FragmentRegexDesc, opts: &GuideOptions) -> Result<Self> {
+        opts.validate()?;
+        let (read, offset) = feature::read_seq_location(geo_re, "extracting guides")?;
+        let pieces = if read == 0 {
+            &geo_re.r1_cginfo
+        } else {
+            &geo_re.r2_cginfo
+        };
+        let len = match pieces.iter().find(|gp| matches!(gp, GeomPiece::ReadSeq(_))) {
+            Some(GeomPiece::ReadSeq(GeomLen::FixedLen(x))) => Some(*x as usize),
+            Some(GeomPiece::ReadSeq(GeomLen::LenRange(l, h))) => {
+                Some(geo_re.padding().padded_len(*l, *h) as usize)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            opts: opts.clone(),
+            read,
+            offset,
+            len,
+            rc: Vec::new(),
+        })
+    }
+
+    /// The read (`1` or `2`) holding the guide.
+    pub(crate) fn read(&self) -> usize {
+        self.read + 1
+    }
+
+    /// Locates the guide in the read sequence of `frag`, recording the
+    /// outcome in `counts`.  Returns `guided` (set to `frag` with its read
+    /// sequence replaced by the guide) if it was found, or `None`.
+    pub(crate) fn extract<'a>(
+        &mut self,
+        frag: &SeqPair,
+        guided: &'a mut SeqPair,
+        counts: &mut GuideCounts,
+    ) -> Option<&'a SeqPair> {
+        let s = if self.read == 0 { &frag.s1 } else { &frag.s2 };
+        let end = self.len.map_or(s.len(), |l| (self.offset + l).min(s.len()));
+        let r = s.get(self.offset..end).unwrap_or_default();
+        let guide = match self.opts.locate(r) {
+            Some((start, end)) => {
+                counts.forward += 1;
+                &r[start..end]
+            }
+            None => {
+                reverse_complement_into(r, &mut self.rc);
+                match self.opts.locate(&self.rc) {
+                    Some((start, end)) => {
+                        counts.reverse += 1;
+                        &self.rc[start..end]
+                    }
+                    None => {
+                        counts.not_found += 1;
+                        return None;
+                    }
+                }
+            }
+        };
+        guided.s1.clone_from(&frag.s1);
+        guided.s2.clone_from(&frag.s2);
+        let s = if self.read == 0 {
+            &mut guided.s1
+        } else {
+            &mut guided.s2
+        };
+        s.splice(self.offset.min(end)..end, guide.iter().copied());
+        Some(guided)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    /// The guides of 8 bases upstream of the start of the scaffold.
+    fn scaffold() -> GuideOptions {
+        GuideOptions {
+            upstream: vec![],
+            downstream: b"GTTTTAGA".to_vec(),
+            guide_len: Some(8),
+            max_mismatches: 1,
+        }
+    }
+
+    #[test]
+    fn guides_are_located_by_their_anchors() {
+        let scaffold = scaffold();
+        assert_eq!(scaffold.locate(b"ACGTAAAACCCCGTTTTAGA"), Some((4, 12)));
+        assert_eq!(scaffold.locate(b"AAAACCCCGTTTAAGA"), Some((0, 8)));
+        assert_eq!(scaffold.locate(b"AAAACCGTTTTAGA"), None);
+        assert_eq!(scaffold.locate(b"GTTT"), None);
+
+        // after the end of the promoter
+        let promoter = GuideOptions {
+            upstream: b"CACCG".to_vec(),
+            downstream: vec![],
+            ..scaffold.clone()
+        };
+        assert_eq!(promoter.locate(b"TCACCGAAAACCCCTT"), Some((6, 14)));
+        assert_eq!(promoter.locate(b"TCACCGAAAACCC"), None);
+
+        // between both, of any length unless it is given
+        let both = GuideOptions {
+            upstream: b"CACCG".to_vec(),
+            guide_len: None,
+            ..scaffold.clone()
+        };
+        assert_eq!(both.locate(b"CACCGAAAGTTTTAGA"), Some((5, 8)));
+        assert_eq!(both.locate(b"CACCGGTTTTAGA"), None);
+        let both = GuideOptions {
+            guide_len: Some(8),
+            ..both
+        };
+        assert_eq!(both.locate(b"CACCGAAAGTTTTAGA"), None);
+        assert_eq!(both.locate(b"CACCGAAAACCCCGTTTTAGA"), Some((5, 13)));
+    }
+
+    #[test]
+    fn guide_anchors_are_validated() {
+        scaffold().validate().unwrap();
+        let invalid = |opts: GuideOptions| opts.validate().is_err();
+        assert!(invalid(GuideOptions {
+            guide_len: None,
+            ..scaffold()
+        }));
+        assert!(invalid(GuideOptions {
+            guide_len: Some(0),
+            ..scaffold()
+        }));
+        assert!(invalid(GuideOptions {
+            downstream: vec![],
+            ..scaffold()
+        }));
+        assert!(invalid(GuideOptions {
+            downstream: b"GTTNTAGA".to_vec(),
+            ..scaffold()
+        }));
+    }
+
+    #[test]
+    fn guides_replace_the_read_sequence() {
+        let extractor = |geom: &str| {
+            let geo_re = FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap();
+            GuideExtractor::new(&geo_re, &scaffold())
+        };
+        assert!(extractor("1{b[2]u[2]}2{x:}").is_err());
+        assert!(extractor("1{b[2]r[4]}2{r:}").is_err());
+
+        // the read sequence lies between the barcode and the UMI
+        let mut extractor = extractor("1{b[2]r[20]u[2]}2{x:}").unwrap();
+        assert_eq!(extractor.read(), 1);
+        let mut counts = GuideCounts::default();
+        let mut guided = SeqPair::new();
+        let frag = |s1: &[u8]| SeqPair {
+            s1: s1.to_vec(),
+            s2: vec![],
+        };
+        let f = frag(b"AAACGTAAAACCCCGTTTTAGATT");
+        let g = extractor.extract(&f, &mut guided, &mut counts).unwrap();
+        assert_eq!(g.s1, b"AAAAAACCCCTT");
+        // the reverse complement of the read sequence holds the guide
+        let f = frag(b"AATCTAAAACGGGGTTTTACGTTT");
+        let g = extractor.extract(&f, &mut guided, &mut counts).unwrap();
+        assert_eq!(g.s1, b"AAAAAACCCCTT");
+        // nor does the read sequence hold the scaffold on either strand
+        let f = frag(b"AAACGTACGTACGTACGTACGTGT");
+        assert!(extractor.extract(&f, &mut guided, &mut counts).is_none());
+        assert_eq!(
+            counts,
+            GuideCounts {
+                forward: 1,
+                reverse: 1,
+                not_found: 1
+            }
+        );
+    }
+
+    #[test]
+    fn guides_are_extracted_in_their_orientation() {
+        let geo = FragmentGeomDesc::try_from("1{b[2]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let opts = XformOptions {
+            guides: Some(GuideOptions {
+                upstream: b"CACCG".to_vec(),
+                guide_len: None,
+                ..scaffold()
+            }),
+            ..XformOptions::default()
+        };
+        // the guide of b is reversed, and its scaffold has a mismatch
+        let out = xform_in_dir(
+            dir.path(),
+            geo.as_regex().unwrap(),
+            ">a\nAACC\n>b\nGGTT\n>c\nACGT\n",
+            ">a\nTTCACCGAAAACCCCGTTTTAGAGC\n>b\nGCTCTTAAACAAAACCCCCGGTGA\n>c\nACGTACGTACGTACGT\n",
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(
+            out.stats.guides,
+            Some(GuideCounts {
+                forward: 1,
+                reverse: 1,
+                not_found: 1
+            })
+        );
+        assert_eq!(out.stats.failed_parsing, 1);
+        assert_eq!(out.out2, ">a\nAAAACCCC\n>b\nGGGGTTTT\n");
+    }
+}
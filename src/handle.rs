@@ -0,0 +1,188 @@
+//! Inputs and outputs that are already open.
+//!
+//! The functions transforming reads take the paths of their inputs and
+//! outputs.  An orchestration layer that has already opened them (e.g. pipes
+//! inherited from a parent process, or a `File` made from a file descriptor
+//! with `File::from(OwnedFd)`) instead registers them with [`input`] or
+//! [`output`], and passes the [`Handle::path`] of the returned [`Handle`] in
+//! place of a path.  Opening that path takes the registered reader or writer
+//! rather than opening a file, so that a handle is used as a single input or
+//! output, which is closed once it has been read or written.  A handle that
+//! is never used is closed when its [`Handle`] is dropped.
+//!
+//! As with the standard input, the compression of an input handle is
+//! detected from its first bytes, and an output handle is written with the
+//! compression of its [`crate::output::SinkConfig`].  The inputs of a handle
+//! are not measured for the progress reports.  The files whose names are
+//! derived from those of the outputs (the parts of a split output, the
+//! offset index, and the outputs of the demultiplexed samples) cannot be
+//! derived from output handles, nor can the unaligned BAM output be a
+//! handle.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The start of the paths of the handles.
+const PREFIX: &str = "<handle ";
+
+/// A reader or writer registered as a handle.
+enum Registered {
+    Input(Box<dyn Read + Send>),
+    Output(Box<dyn Write + Send>),
+}
+
+/// The handles that were registered but not opened yet.
+static REGISTERED: Mutex<Vec<(PathBuf, Registered)>> = Mutex::new(Vec::new());
+
+/// The number of handles registered so far, from which their paths are made.
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+/// An input or output registered by [`input`] or [`output`].
+#[derive(Debug)]
+pub struct Handle {
+    path: PathBuf,
+}
+
+impl Handle {
+    fn register(r: Registered) -> Self {
+        let n = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+        let path = PathBuf::from(format!("{}{}>", PREFIX, n));
+        REGISTERED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((path.clone(), r));
+        Self { path }
+    }
+
+    /// The path designating the handle, given in place of the path of an
+    /// input or output (e.g. `<handle 1>`).
+    pub fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        drop(take(&self.path));
+    }
+}
+
+/// Registers `r`, already open, to be read as an input.
+pub fn input<R: Read + Send + 'static>(r: R) -> Handle {
+    Handle::register(Registered::Input(Box::new(r)))
+}
+
+/// Registers `w`, already open, to be written as an output.
+pub fn output<W: Write + Send + 'static>(w: W) -> Handle {
+    Handle::register(Registered::Output(Box::new(w)))
+}
+
+/// Returns true if `p` is the path of a handle (see [`Handle::path`]),
+/// whether or not it was used already.
+pub fn is_handle(p: &Path) -> bool {
+    p.to_str()
+        .and_then(|s| s.strip_prefix(PREFIX))
+        .and_then(|s| s.strip_suffix('>'))
+        .is_some_and(|n| n.parse::<u64>().is_ok())
+}
+
+fn take(p: &Path) -> Option<Registered> {
+    let mut registered = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+    let i = registered.iter().position(|(q, _)| q == p)?;
+    Some(registered.swap_remove(i).1)
+}
+
+/// Takes the reader of the input handle `p`, or returns `None` if `p` is not
+/// the path of a handle.
+pub(crate) fn open_read(p: &Path) -> Option<io::Result<Box<dyn Read + Send>>> {
+    if !is_handle(p) {
+        return None;
+    }
+    Some(match take(p) {
+        Some(Registered::Input(r)) => Ok(r),
+        Some(Registered::Output(_)) => Err(misused(p, "an output")),
+        None => Err(misused(p, "already used, or dropped")),
+    })
+}
+
+/// Takes the writer of the output handle `p`, or returns `None` if `p` is
+/// not the path of a handle.
+pub(crate) fn open_write(p: &Path) -> Option<io::Result<Box<dyn Write + Send>>> {
+    if !is_handle(p) {
+        return None;
+    }
+    Some(match take(p) {
+        Some(Registered::Output(w)) => Ok(w),
+        Some(Registered::Input(_)) => Err(misused(p, "an input")),
+        None => Err(misused(p, "already used, or dropped")),
+    })
+}
+
+fn misused(p: &Path, why: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} is {}", p.display(), why),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{xform_read_pairs_to_file_with_options, FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer whose bytes can be read once it has been dropped.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handles_are_read_and_written() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let r1 = input(&b">a\nACGTTTC\n>b\nNN\n"[..]);
+        let r2 = input(&b">a\nCCCC\n>b\nTTTT\n"[..]);
+        let (o1, o2) = (Shared::default(), Shared::default());
+        let (h1, h2) = (output(o1.clone()), output(o2.clone()));
+        assert!(is_handle(&h1.path()));
+        assert!(!is_handle(Path::new("<handle x>")));
+        let stats = xform_read_pairs_to_file_with_options(
+            geo_re.clone(),
+            &[r1.path()],
+            &[r2.path()],
+            Some(h1.path()),
+            Some(h2.path()),
+            &XformOptions::default(),
+        )
+        .unwrap();
+        assert_eq!((stats.total_fragments, stats.failed_parsing), (2, 1));
+        assert_eq!(*o1.0.lock().unwrap(), b">a\nACGTTT\n");
+        assert_eq!(*o2.0.lock().unwrap(), b">a\nCCCC\n");
+
+        // a handle is used once
+        let res = xform_read_pairs_to_file_with_options(
+            geo_re,
+            &[r1.path()],
+            &[r2.path()],
+            Some(h1.path()),
+            Some(h2.path()),
+            &XformOptions::default(),
+        );
+        assert!(format!("{:?}", res.unwrap_err()).contains("already used"));
+    }
+}
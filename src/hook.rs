@@ -0,0 +1,325 @@
+//! A hook customizing the transformed records before they are written.
+//!
+//! Simple customizations of the output (e.g. appending lab-specific tags to
+//! the headers, changing the case of the sequences, or recoding the
+//! barcodes) need not patch the transformation loop: a [`RecordHook`]
+//! registered in [`crate::XformOptions::record_hook`] is called with each
+//! transformed fragment just before it is written to the main outputs, and
+//! may modify its sequences and the comments of its headers in place.  The
+//! pieces captured by the geometry can be located in the transformed reads
+//! with [`OutputRecord::piece_range`].
+
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::Result;
+use seq_geom_parser::{GeomLen, GeomPiece};
+
+use crate::{FragmentRegexDesc, SeqPair};
+
+/// A transformed fragment about to be written.
+pub struct OutputRecord<'a> {
+    /// The names of the read 1 and read 2 records.
+    pub ids: [&'a [u8]; 2],
+    /// The comments of the read 1 and read 2 headers (the tags following the
+    /// name, each preceded by a space), to which tags may be appended.
+    pub comments: [&'a mut Vec<u8>; 2],
+    /// The transformed read 1 and read 2 sequences.
+    pub seqs: &'a mut SeqPair,
+    layout: &'a RecordLayout,
+}
+
+impl OutputRecord<'_> {
+    /// The pieces captured from read `read` (`1` or `2`), in the order in
+    /// which they appear in the transformed read.
+    pub fn pieces(&self, read: usize) -> &[GeomPiece] {
+        match read {
+            1 | 2 => &self.layout.pieces[read - 1],
+            _ => &[],
+        }
+    }
+
+    /// The range of the transformed read `read` (`1` or `2`) holding its
+    /// `i`-th captured piece (including its padding, if it is of variable
+    /// length), or `None` if there is no such piece.  The range reflects the
+    /// sequences as they were transformed, so it should be looked up before
+    /// changing their length.
+    pub fn piece_range(&self, read: usize, i: usize) -> Option<Range<usize>> {
+        let (offset, len) = *self.layout.spans.get(read.checked_sub(1)?)?.get(i)?;
+        let s = if read == 1 {
+            &self.seqs.s1
+        } else {
+            &self.seqs.s2
+        };
+        let end = len.map_or(s.len(), |l| (offset + l).min(s.len()));
+        Some(offset.min(end)..end)
+    }
+
+    /// The sequence of the `i`-th piece captured from read `read` (`1` or
+    /// `2`), if any (see [`OutputRecord::piece_range`]).
+    pub fn piece(&self, read: usize, i: usize) -> Option<&[u8]> {
+        let r = self.piece_range(read, i)?;
+        let s = if read == 1 {
+            &self.seqs.s1
+        } else {
+            &self.seqs.s2
+        };
+        s.get(r)
+    }
+}
+
+/// A function called with each transformed fragment just before it is
+/// written.  Any closure `FnMut(&mut OutputRecord) -> Result<()>` is a
+/// `RecordHook`.
+pub trait RecordHook: Send {
+    /// Modifies the fragment `rec` in place.  An error fails the
+    /// transformation.
+    fn on_record(&mut self, rec: &mut OutputRecord<'_>) -> Result<()>;
+}
+
+impl<F> RecordHook for F
+where
+    F: FnMut(&mut OutputRecord<'_>) -> Result<()> + Send,
+{
+    fn on_record(&mut self, rec: &mut OutputRecord<'_>) -> Result<()> {
+        self(rec)
+    }
+}
+
+/// A shareable handle on a [`RecordHook`], as registered in
+/// [`crate::XformOptions::record_hook`].
+#[derive(Clone)]
+pub struct RecordHookHandle {
+    hook: Arc<Mutex<dyn RecordHook>>,
+}
+
+impl fmt::Debug for RecordHookHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RecordHookHandle").finish_non_exhaustive()
+    }
+}
+
+impl RecordHookHandle {
+    /// Create a new `RecordHookHandle` calling `hook`.
+    pub fn new<H: RecordHook + 'static>(hook: H) -> Self {
+        Self {
+            hook: Arc::new(Mutex::new(hook)),
+        }
+    }
+
+    /// Locks the hook for the duration of a transformation.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, dyn RecordHook + 'static> {
+        match self.hook.lock() {
+            Ok(h) => h,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Where the captured pieces lie in the transformed reads.
+#[derive(Debug, Clone)]
+pub(crate) struct RecordLayout {
+    pieces: [Vec<GeomPiece>; 2],
+    /// The offset of each piece and its length (`None` if it extends to the
+    /// end of the read).
+    spans: [Vec<(usize, Option<usize>)>; 2],
+}
+
+impl RecordLayout {
+    /// Create the layout of the fragments transformed by `geo_re`.
+    pub(crate) fn new(geo_re: &FragmentRegexDesc) -> Self {
+        let spans = |pieces: &[GeomPiece]| {
+            let mut offset = 0;
+            let mut spans = Vec::with_capacity(pieces.len());
+            for gp in pieces {
+                let len = match gp {
+                    GeomPiece::Barcode(gl) | GeomPiece::Umi(gl) | GeomPiece::ReadSeq(gl) => {
+                        match gl {
+                            GeomLen::FixedLen(x) => Some(*x as usize),
+                            GeomLen::LenRange(l, h) => {
+                                Some(geo_re.padding().padded_len(*l, *h) as usize)
+                            }
+                            GeomLen::Unbounded => None,
+                        }
+                    }
+                    _ => Some(0),
+                };
+                spans.push((offset, len));
+                // an unbounded piece extends to the end of the read
+                offset += len.unwrap_or(0);
+            }
+            spans
+        };
+        Self {
+            spans: [spans(&geo_re.r1_cginfo), spans(&geo_re.r2_cginfo)],
+            pieces: [geo_re.r1_cginfo.clone(), geo_re.r2_cginfo.clone()],
+        }
+    }
+
+    /// The spans (offset and length, `None` if extending to the end of the
+    /// read) of the pieces of read `read` (`0` for read 1 and `1` for read 2)
+    /// for which `select` is true.
+    pub(crate) fn spans_where<F: Fn(&GeomPiece) -> bool>(
+        &self,
+        read: usize,
+        select: F,
+    ) -> Vec<(usize, Option<usize>)> {
+        self.pieces[read]
+            .iter()
+            .zip(&self.spans[read])
+            .filter(|(gp, _)| select(gp))
+            .map(|(_, &span)| span)
+            .collect()
+    }
+
+    /// Calls `hook` with the fragment `frag`, copied into `out`.
+    pub(crate) fn apply(
+        &self,
+        hook: &mut dyn RecordHook,
+        ids: [&[u8]; 2],
+        comments: [&mut Vec<u8>; 2],
+        frag: &SeqPair,
+        out: &mut SeqPair,
+    ) -> Result<()> {
+        out.s1.clone_from(&frag.s1);
+        out.s2.clone_from(&frag.s2);
+        hook.on_record(&mut OutputRecord {
+            ids,
+            comments,
+            seqs: out,
+            layout: self,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use anyhow::bail;
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    /// The layout of the fragments transformed by the geometry `geom`.
+    fn layout(geom: &str) -> RecordLayout {
+        RecordLayout::new(
+            &FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn pieces_are_located_in_the_transformed_reads() {
+        let layout = layout("1{b[2-3]x[2]u[2]}2{u[1]r:}");
+        let frag = SeqPair {
+            s1: b"AACAGG".to_vec(),
+            s2: b"TACGT".to_vec(),
+        };
+        let (mut c1, mut c2) = (vec![], vec![]);
+        let mut out = SeqPair::new();
+        let mut check = |rec: &mut OutputRecord| {
+            assert_eq!(rec.ids, [&b"a/1"[..], b"a/2"]);
+            assert_eq!(rec.pieces(1).len(), 2);
+            assert!(rec.pieces(3).is_empty());
+            // the padded barcode, and the UMI after it
+            assert_eq!(rec.piece_range(1, 0), Some(0..4));
+            assert_eq!(rec.piece(1, 1), Some(&b"GG"[..]));
+            assert_eq!(rec.piece(1, 2), None);
+            // the read sequence extends to the end of the read
+            assert_eq!(rec.piece(2, 1), Some(&b"ACGT"[..]));
+            assert_eq!(rec.piece(0, 0), None);
+            rec.seqs.s1.truncate(3);
+            // the ranges are clamped to the sequences
+            assert_eq!(rec.piece_range(1, 0), Some(0..3));
+            assert_eq!(rec.piece_range(1, 1), Some(3..3));
+            rec.comments[1].extend_from_slice(b" x=1");
+            Ok(())
+        };
+        layout
+            .apply(
+                &mut check,
+                [b"a/1", b"a/2"],
+                [&mut c1, &mut c2],
+                &frag,
+                &mut out,
+            )
+            .unwrap();
+        // the hook modifies a copy of the fragment
+        assert_eq!((&out.s1[..], &frag.s1[..]), (&b"AAC"[..], &b"AACAGG"[..]));
+        assert_eq!((c1, c2), (vec![], b" x=1".to_vec()));
+
+        let umis = layout.spans_where(0, |gp| matches!(gp, GeomPiece::Umi(_)));
+        assert_eq!(umis, [(4, Some(2))]);
+        let reads = layout.spans_where(1, |gp| matches!(gp, GeomPiece::ReadSeq(_)));
+        assert_eq!(reads, [(1, None)]);
+    }
+
+    #[test]
+    fn hook_errors_fail_the_transformation() {
+        let layout = layout("1{b[2]}2{r:}");
+        let mut fail = |_: &mut OutputRecord| -> Result<()> { bail!("rejected") };
+        let frag = SeqPair::new();
+        let (mut c1, mut c2) = (vec![], vec![]);
+        let err = layout
+            .apply(
+                &mut fail,
+                [b"a", b"a"],
+                [&mut c1, &mut c2],
+                &frag,
+                &mut SeqPair::new(),
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "rejected");
+
+        let dir = tempdir().unwrap();
+        let opts = XformOptions {
+            record_hook: Some(RecordHookHandle::new(fail)),
+            ..XformOptions::default()
+        };
+        let geo_re = FragmentGeomDesc::try_from("1{b[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        assert!(xform_in_dir(dir.path(), geo_re, ">a\nAA\n", ">a\nTT\n", &opts).is_err());
+    }
+
+    #[test]
+    fn hook_modifies_records_before_writing() {
+        let geo = FragmentGeomDesc::try_from("1{b[2-3]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        fn tag(rec: &mut OutputRecord) -> Result<()> {
+            assert_eq!(rec.pieces(1).len(), 2);
+            // the barcode, with its padding, and the UMI
+            let bc = rec.piece(1, 0).unwrap().to_owned();
+            let umi = rec.piece(1, 1).unwrap().to_owned();
+            for comment in rec.comments.iter_mut() {
+                for (tag, s) in [(&b" CB:Z:"[..], &bc), (b" UB:Z:", &umi)] {
+                    comment.extend_from_slice(tag);
+                    comment.extend_from_slice(s);
+                }
+            }
+            rec.seqs.s2.make_ascii_lowercase();
+            Ok(())
+        }
+        let opts = XformOptions {
+            record_hook: Some(RecordHookHandle::new(tag)),
+            ..XformOptions::default()
+        };
+        let out = xform_in_dir(
+            dir.path(),
+            geo.as_regex().unwrap(),
+            ">a\nAACCG\n>b\nGGTT\n",
+            ">a\nTTAG\n>b\nGGCA\n",
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(
+            out.out2,
+            ">a CB:Z:AACA UB:Z:CG\nttag\n>b CB:Z:GGAC UB:Z:TT\nggca\n"
+        );
+    }
+}
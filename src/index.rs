@@ -0,0 +1,457 @@
+//! Index reads (I1 and I2).
+//!
+//! Some chemistries encode (part of) the cell barcode in the index reads of
+//! the sequencing run, which are delivered in their own I1 and I2 files.  In a
+//! geometry, the index reads are reads 3 and 4 (e.g.
+//! `1{b[16]u[12]x:}2{r:}3{b[8]}`).  As a `FragmentGeomDesc` only describes
+//! reads 1 and 2, [`split_geometry`] takes the descriptions of the index
+//! reads out of a geometry, and
+//! [`crate::FragmentRegexDesc::add_index_read`] adds them to the regex
+//! description of reads 1 and 2.  When transforming the fragments (see
+//! [`IndexReadOptions`]), the index reads are read along with reads 1 and 2,
+//! a fragment whose index reads do not match their geometry fails to parse,
+//! and the transformed index reads are written to outputs of their own, with
+//! their own simplified geometry (e.g. `3{b[8]}`).
+//!
+//! The index reads are always matched as a whole, as they are, and without
+//! mismatches in their anchors.  Duplicate detection, feature matching and
+//! barcode counting only consider the transformed reads 1 and 2.
+
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use regex::bytes::{CaptureLocations, Regex};
+use seq_geom_parser::{FragmentGeomDesc, GeomPiece};
+
+use crate::complexity::ReadMetrics;
+use crate::failure::FailureCause;
+use crate::output::SinkConfig;
+use crate::scan::ReadPrefilter;
+use crate::{
+    check_capture_groups, parse_single_read, read_regex, FragmentRegexDesc, PaddingScheme,
+    XformOptions, DEFAULT_ALPHABET,
+};
+
+/// The read numbers of the index reads (I1 and I2) in a geometry.
+pub const INDEX_READS: [usize; 2] = [3, 4];
+
+/// The regex description of an index read.
+#[derive(Debug, Clone)]
+pub struct IndexReadDesc {
+    /// The number of the read (`3` for I1 or `4` for I2).
+    pub read: usize,
+    /// The pieces captured from the read.
+    pub cginfo: Vec<GeomPiece>,
+    /// The regular expression expected to match the read.
+    pub re: Regex,
+    clocs: CaptureLocations,
+    prefilter: ReadPrefilter,
+    /// The captured lengths of the variable length pieces of the last read
+    /// parsed.
+    lens: Vec<u32>,
+    /// The ranges of the padding of the variable length pieces of the last
+    /// read parsed.
+    pads: Vec<Range<u32>>,
+    /// The metrics of the geometry of the read.
+    pub(crate) metrics: ReadMetrics,
+}
+
+impl IndexReadDesc {
+    /// Builds the regex description of the index read `read` with the
+    /// geometry pieces `pieces`.
+    pub(crate) fn new(read: usize, pieces: &[GeomPiece]) -> Result<Self> {
+        if !INDEX_READS.contains(&read) {
+            bail!(
+                "read {} cannot be an index read; the index reads are reads 3 (I1) and 4 (I2)",
+                read
+            );
+        }
+        let (re, cginfo) = read_regex(pieces, false, 0, false, DEFAULT_ALPHABET)?;
+        check_capture_groups(read, &re, &cginfo)?;
+        Ok(Self {
+            read,
+            clocs: re.capture_locations(),
+            re,
+            cginfo,
+            prefilter: ReadPrefilter::new(pieces),
+            lens: Vec::new(),
+            pads: Vec::new(),
+            metrics: ReadMetrics::new(read, pieces),
+        })
+    }
+
+    /// Parses the index read `r`, appending the captured pieces (padded in
+    /// accordance with `padding`) to `out`.  Returns false if the read does
+    /// not match.
+    pub(crate) fn parse_into(
+        &mut self,
+        r: &[u8],
+        padding: PaddingScheme,
+        out: &mut Vec<u8>,
+    ) -> bool {
+        self.prefilter.may_match(r)
+            && self.re.captures_read(&mut self.clocs, r).is_some()
+            && parse_single_read(
+                &self.clocs,
+                &self.cginfo,
+                &[],
+                r,
+                padding,
+                out,
+                &mut self.lens,
+                &mut self.pads,
+            )
+    }
+
+    /// The ranges of the padding in the last read successfully parsed.
+    pub(crate) fn last_padding(&self) -> &[Range<u32>] {
+        &self.pads
+    }
+
+    /// Finds why the index read `r` does not match (see
+    /// [`crate::FragmentRegexDesc::diagnose_failure`]), or returns `None`
+    /// if it does.
+    pub(crate) fn diagnose(&mut self, r: &[u8], padding: PaddingScheme) -> Option<FailureCause> {
+        if let Some(cause) = self.prefilter.diagnose(r) {
+            return Some(cause);
+        }
+        let mut scratch = Vec::new();
+        if self.parse_into(r, padding, &mut scratch) {
+            None
+        } else {
+            Some(FailureCause::NoMatch)
+        }
+    }
+}
+
+/// The index reads of a transformation.
+#[derive(Debug, Clone, Default)]
+pub struct IndexReadOptions {
+    /// The input files of read 3 (I1) and of read 4 (I2), with one file for
+    /// each read 1 file.  The files of an index read that is not part of the
+    /// geometry are left empty.
+    pub inputs: [Vec<PathBuf>; 2],
+    /// Where the transformed read 3 and read 4 are written.  The output of
+    /// an index read that is not part of the geometry, or whose pieces are
+    /// all discarded, may be omitted.
+    pub outputs: [Option<PathBuf>; 2],
+    /// Configuration of the read 3 and read 4 outputs.
+    pub out: [SinkConfig; 2],
+}
+
+/// Checks that the index reads of the geometry `geo_re` and those given in
+/// `opts` agree (with as many files for each index read as the `num_files`
+/// read 1 files), and that the other options of `opts` support index reads.
+pub(crate) fn check_index_reads(
+    geo_re: &FragmentRegexDesc,
+    opts: &XformOptions,
+    num_files: usize,
+) -> Result<()> {
+    for (k, read) in INDEX_READS.into_iter().enumerate() {
+        let desc = geo_re.index_reads().iter().find(|ir| ir.read == read);
+        let (inputs, output) = match &opts.index_reads {
+            Some(io) => (&io.inputs[k][..], io.outputs[k].as_ref()),
+            None => (&[][..], None),
+        };
+        match desc {
+            Some(_) if inputs.is_empty() => bail!(
+                "The geometry describes read {} (I{}), but no input files were given for it",
+                read,
+                k + 1
+            ),
+            Some(ir) if !ir.cginfo.is_empty() && output.is_none() => bail!(
+                "No read {} output was given, but the geometry does not discard all of read {}",
+                read,
+                read
+            ),
+            None if !inputs.is_empty() => bail!(
+                "Input files were given for read {} (I{}), but the geometry does not describe it",
+                read,
+                k + 1
+            ),
+            _ => {}
+        }
+        if !inputs.is_empty() && inputs.len() != num_files {
+            bail!(
+                "As many read {} (I{}) input files as read 1 input files ({}) must be given, but got {}",
+                read,
+                k + 1,
+                num_files,
+                inputs.len()
+            );
+        }
+    }
+    if !geo_re.index_reads().is_empty() {
+        if opts.split_output.is_some() {
+            bail!("The output cannot be split into parts when transforming index reads");
+        }
+        if opts.umi_correction.is_some() {
+            bail!("UMIs cannot be corrected when transforming index reads");
+        }
+        if opts.sort_by_barcode.is_some() {
+            bail!("The output cannot be sorted by barcode when transforming index reads");
+        }
+        if opts.demux.is_some() {
+            bail!("The fragments cannot be demultiplexed when transforming index reads");
+        }
+        if opts
+            .controls
+            .as_ref()
+            .is_some_and(|co| co.outputs.is_some())
+        {
+            bail!(
+                "The fragments matching control sequences cannot be written to their own \
+                 outputs when transforming index reads"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The number of an index read (3 or 4) and the pieces describing it.
+pub type IndexReadPieces = (usize, Vec<GeomPiece>);
+
+/// Takes the descriptions of the index reads (reads 3 and 4) out of the
+/// geometry `geom`, returning the description of reads 1 and 2 (which may be
+/// parsed into a `FragmentGeomDesc`) along with the number and the pieces of
+/// each index read, in the order of the reads.
+pub fn split_geometry(geom: &str) -> Result<(String, Vec<IndexReadPieces>)> {
+    let mut main = String::new();
+    let mut index: Vec<IndexReadPieces> = vec![];
+    let mut rest = geom.trim();
+    while !rest.is_empty() {
+        let (Some(open), Some(close)) = (rest.find('{'), rest.find('}')) else {
+            bail!(
+                "could not parse geometry {}: unterminated read description",
+                geom
+            );
+        };
+        let read: usize = rest[..open]
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("could not parse geometry {}: invalid read number", geom))?;
+        if read <= 2 {
+            main.push_str(&rest[..=close]);
+        } else {
+            if index.iter().any(|(r, _)| *r == read) {
+                bail!(
+                    "could not parse geometry {}: read {} is described twice",
+                    geom,
+                    read
+                );
+            }
+            let body = &rest[open + 1..close];
+            // the pieces are parsed as those of a read 1
+            let desc = FragmentGeomDesc::try_from(format!("1{{{}}}2{{r:}}", body).as_str())
+                .map_err(|e| anyhow!("could not parse the geometry of read {}: {:?}", read, e))?;
+            index.push((read, desc.read1_desc));
+        }
+        rest = rest[close + 1..].trim_start();
+    }
+    index.sort_by_key(|(r, _)| *r);
+    Ok((main, index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{ControlOptions, ControlRef};
+    use crate::demux::{DemuxOptions, SampleSheet};
+    use crate::test_utils::xform_in_dir;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::GeomLen;
+    use tempfile::tempdir;
+
+    /// The regex description of the geometry `geom`, with its index reads.
+    fn geometry(geom: &str) -> FragmentRegexDesc {
+        let (main, index) = split_geometry(geom).unwrap();
+        let mut geo_re = FragmentGeomDesc::try_from(main.as_str())
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        for (read, pieces) in &index {
+            geo_re.add_index_read(*read, pieces).unwrap();
+        }
+        geo_re
+    }
+
+    #[test]
+    fn index_reads_are_split_from_the_geometry() {
+        let (main, index) = split_geometry("4{b[2]} 1{b[4]u[2]x:}2{r:}3{b[3-4]}").unwrap();
+        assert_eq!(main, "1{b[4]u[2]x:}2{r:}");
+        assert_eq!(index.iter().map(|(r, _)| *r).collect::<Vec<_>>(), [3, 4]);
+        assert!(matches!(
+            index[0].1[..],
+            [GeomPiece::Barcode(GeomLen::LenRange(3, 4))]
+        ));
+
+        let (_, bad) = split_geometry("1{b[4]}2{r:}5{b[8]}").unwrap();
+        assert!(IndexReadDesc::new(bad[0].0, &bad[0].1).is_err());
+        assert!(split_geometry("1{b[4]}2{r:}3{b[8]}3{b[2]}").is_err());
+        assert!(split_geometry("1{b[4]}2{r:}3{b[8]").is_err());
+        assert!(split_geometry("1{b[4]}2{r:}I1{b[8]}").is_err());
+        assert!(split_geometry("1{b[4]}2{r:}3{q[8]}").is_err());
+
+        assert_eq!(
+            geometry("1{b[4]u[2]x:}2{r:}3{b[3-4]}").get_simplified_description_string(),
+            "1{b[4]u[2]}2{r:}3{b[5]}"
+        );
+    }
+
+    #[test]
+    fn index_reads_are_parsed_and_padded() {
+        let (_, index) = split_geometry("1{b[4]}2{r:}3{f[AC]b[3-4]}").unwrap();
+        let mut ir = IndexReadDesc::new(3, &index[0].1).unwrap();
+        let mut out = b"ACGT".to_vec();
+        assert!(ir.parse_into(b"ACTTTT", PaddingScheme::Distinct, &mut out));
+        assert_eq!(out, b"ACGTTTTTA");
+        out.clear();
+        assert!(ir.parse_into(b"ACTTT", PaddingScheme::Distinct, &mut out));
+        assert_eq!(out, b"TTTAC");
+        assert_eq!(ir.last_padding().len(), 1);
+        assert_eq!(ir.last_padding()[0], 3..5);
+        assert_eq!(ir.diagnose(b"ACTTT", PaddingScheme::Distinct), None);
+
+        // the reads are matched as a whole, and without mismatches
+        for r in [&b"ACTT"[..], b"ACTTTTT", b"AGTTT"] {
+            assert!(!ir.parse_into(r, PaddingScheme::Distinct, &mut out));
+            assert!(ir.diagnose(r, PaddingScheme::Distinct).is_some());
+        }
+    }
+
+    #[test]
+    fn index_reads_follow_their_fragments() {
+        let geo_re = geometry("1{b[4]x:}2{r:}3{b[4]}");
+        let opts = XformOptions {
+            index_reads: Some(IndexReadOptions {
+                inputs: [vec![PathBuf::from("i1.fa")], vec![]],
+                outputs: [Some(PathBuf::from("o3.fa")), None],
+                ..IndexReadOptions::default()
+            }),
+            ..XformOptions::default()
+        };
+        check_index_reads(&geo_re, &opts, 1).unwrap();
+        assert!(check_index_reads(&geo_re, &opts, 2).is_err());
+
+        let reference =
+            ControlRef::new(vec![(String::from("phix"), b"ACGTACGT".to_vec())], 4).unwrap();
+        // the fragments matching a control are only annotated
+        let annotated = XformOptions {
+            controls: Some(ControlOptions {
+                reference: reference.clone(),
+                outputs: None,
+            }),
+            ..opts.clone()
+        };
+        check_index_reads(&geo_re, &annotated, 1).unwrap();
+        // the index reads would not follow their fragments into the outputs
+        // of the controls
+        let routed = XformOptions {
+            controls: Some(ControlOptions {
+                reference,
+                outputs: Some([PathBuf::from("c1.fa"), PathBuf::from("c2.fa")]),
+            }),
+            ..opts.clone()
+        };
+        assert!(check_index_reads(&geo_re, &routed, 1).is_err());
+        // nor into the outputs of the samples
+        let demux = XformOptions {
+            demux: Some(DemuxOptions {
+                sheet: SampleSheet::new(vec![(String::from("A01"), b"ACGT".to_vec())]).unwrap(),
+                piece: 1,
+                max_mismatches: 0,
+            }),
+            ..opts
+        };
+        assert!(check_index_reads(&geo_re, &demux, 1).is_err());
+    }
+
+    #[test]
+    fn index_reads_agree_with_their_files() {
+        let geo_re = geometry("1{b[4]x:}2{r:}3{b[4]}4{x[2]}");
+        let io = |inputs: [usize; 2], outputs: [bool; 2]| XformOptions {
+            index_reads: Some(IndexReadOptions {
+                inputs: inputs.map(|n| vec![PathBuf::from("i.fa"); n]),
+                outputs: outputs.map(|o| o.then(|| PathBuf::from("o.fa"))),
+                ..IndexReadOptions::default()
+            }),
+            ..XformOptions::default()
+        };
+        // read 4 is discarded as a whole, so that it needs no output
+        check_index_reads(&geo_re, &io([1, 1], [true, false]), 1).unwrap();
+        check_index_reads(&geo_re, &io([2, 2], [true, true]), 2).unwrap();
+        // the inputs of both index reads must be given
+        assert!(check_index_reads(&geo_re, &io([1, 0], [true, false]), 1).is_err());
+        assert!(check_index_reads(&geo_re, &XformOptions::default(), 1).is_err());
+        // along with the output of read 3
+        assert!(check_index_reads(&geo_re, &io([1, 1], [false, false]), 1).is_err());
+        // and one file of each for each read 1 file
+        assert!(check_index_reads(&geo_re, &io([1, 2], [true, false]), 2).is_err());
+
+        // no index read may be given for a geometry without them
+        let main = geometry("1{b[4]x:}2{r:}");
+        check_index_reads(&main, &XformOptions::default(), 1).unwrap();
+        assert!(check_index_reads(&main, &io([1, 0], [true, false]), 1).is_err());
+    }
+
+    #[test]
+    fn index_reads_are_transformed() {
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        std::fs::write(p("i1.fa"), ">a\nTTT\n>b\nTTTTTTT\n>c\nAAAA\n").unwrap();
+        let opts = XformOptions {
+            index_reads: Some(IndexReadOptions {
+                inputs: [vec![p("i1.fa")], vec![]],
+                outputs: [Some(p("o3.fa")), None],
+                ..IndexReadOptions::default()
+            }),
+            ..XformOptions::default()
+        };
+        let out = xform_in_dir(
+            dir.path(),
+            geometry("1{b[4]u[2]x:}2{r:}3{b[3-4]}"),
+            ">a\nACGTCCTT\n>b\nACGTCCTT\n>c\nACGTCCTT\n",
+            ">a\nGGGG\n>b\nGGGG\n>c\nGGGG\n",
+            &opts,
+        )
+        .unwrap();
+        // the fragment whose index read does not match fails to parse
+        assert_eq!(
+            (out.stats.total_fragments, out.stats.failed_parsing),
+            (3, 1)
+        );
+        assert_eq!(out.out1, ">a\nACGTCC\n>c\nACGTCC\n");
+        assert_eq!(
+            std::fs::read_to_string(p("o3.fa")).unwrap(),
+            ">a\nTTTAC\n>c\nAAAAA\n"
+        );
+    }
+
+    #[test]
+    fn index_records_are_named_as_the_main_records() {
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        std::fs::write(p("i1.fa"), ">a\nTT\n").unwrap();
+        std::fs::write(p("i2.fa"), ">a\nCC\n").unwrap();
+        let opts = XformOptions {
+            index_reads: Some(IndexReadOptions {
+                inputs: [vec![p("i1.fa")], vec![p("i2.fa")]],
+                outputs: [Some(p("o3.fa")), Some(p("o4.fa"))],
+                ..IndexReadOptions::default()
+            }),
+            ..XformOptions::default()
+        };
+        let out = xform_in_dir(
+            dir.path(),
+            geometry("1{b[4]x:}2{r:}3{b[2]}4{b[2]}"),
+            ">a/1\nACGTT\n",
+            ">a/2\nGG\n",
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(out.out2, ">a/2\nGG\n");
+        assert_eq!(std::fs::read_to_string(p("o3.fa")).unwrap(), ">a/1\nTT\n");
+        assert_eq!(std::fs::read_to_string(p("o4.fa")).unwrap(), ">a/2\nCC\n");
+    }
+}
@@ -0,0 +1,90 @@
+//! IUPAC ambiguity codes in the fixed pieces of a geometry.
+//!
+//! The anchors of some library designs are degenerate, such as the
+//! `CAGRGC` linker whose fourth base is either `A` or `G`.  Such anchors are
+//! written with the IUPAC codes in the fixed (`f`) pieces of the geometry,
+//! and each code matches any of the bases it stands for (`N` matching any
+//! base).  Optionally (see [`crate::RegexOptions::n_tolerant_anchors`]), an
+//! `N` in the reads also matches any base of the anchors, so that the
+//! fragments whose anchors hold a no-call are not lost.
+
+/// The bases matched by the IUPAC ambiguity code `code`, or `None` if
+/// `code` is not an ambiguity code (e.g. a plain base).
+pub fn ambiguous_bases(code: u8) -> Option<&'static [u8]> {
+    Some(match code {
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => b"ACGTN",
+        _ => return None,
+    })
+}
+
+/// Whether the anchor `anchor` holds no ambiguity code, and so only matches
+/// itself.
+pub fn is_literal(anchor: &[u8]) -> bool {
+    anchor.iter().all(|&c| ambiguous_bases(c).is_none())
+}
+
+/// Whether the base `b` of a read matches the character `code` of an
+/// anchor, an `N` matching any base of the anchor if `n_tolerant` is true.
+pub fn base_matches(code: u8, b: u8, n_tolerant: bool) -> bool {
+    match ambiguous_bases(code) {
+        Some(bases) => bases.contains(&b),
+        None => code == b || (n_tolerant && b == b'N'),
+    }
+}
+
+/// Whether the read sequence `seq` matches the anchor `anchor`, which must
+/// be as long (see [`base_matches`]).
+pub fn matches(anchor: &[u8], seq: &[u8], n_tolerant: bool) -> bool {
+    anchor.len() == seq.len()
+        && anchor
+            .iter()
+            .zip(seq)
+            .all(|(&c, &b)| base_matches(c, b, n_tolerant))
+}
+
+/// Returns the regex matching the character `c` of an anchor: `c` itself if
+/// it is a plain base, or the class of the bases it stands for, along with
+/// `N` if `n_tolerant` is true.
+pub(crate) fn anchor_char_regex(c: char, n_tolerant: bool) -> String {
+    let mut bases = match u8::try_from(c).ok().and_then(ambiguous_bases) {
+        Some(bases) => String::from_utf8_lossy(bases).into_owned(),
+        None if n_tolerant => c.to_string(),
+        None => return regex::escape(&c.to_string()),
+    };
+    if n_tolerant && !bases.contains('N') {
+        bases.push('N');
+    }
+    format!("[{}]", regex::escape(&bases))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ambiguity_codes_match_their_bases() {
+        assert!(matches(b"CAGRGC", b"CAGAGC", false));
+        assert!(matches(b"CAGRGC", b"CAGGGC", false));
+        assert!(!matches(b"CAGRGC", b"CAGTGC", false));
+        assert!(!matches(b"CAGRGC", b"CNGAGC", false));
+        assert!(matches(b"CAGRGC", b"CNGAGC", true));
+        assert!(matches(b"ACNT", b"ACNT", false));
+        assert!(is_literal(b"CAGAGC") && !is_literal(b"CAGNGC"));
+
+        assert_eq!(anchor_char_regex('A', false), "A");
+        assert_eq!(anchor_char_regex('A', true), "[AN]");
+        assert_eq!(anchor_char_regex('R', false), "[AG]");
+        assert_eq!(anchor_char_regex('R', true), "[AGN]");
+        assert_eq!(anchor_char_regex('N', true), "[ACGTN]");
+    }
+}
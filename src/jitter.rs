@@ -0,0 +1,346 @@
+//! Measuring the length of the pieces preceding anchors.
+//!
+//! In geometries with an anchor (a fixed `f` piece), the piece preceding the
+//! anchor often varies in length from read to read (e.g. the `b[9-10]` of
+//! `1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}`).  If the length range given for that
+//! piece does not fit the data, many fragments fail to parse.
+//! [`measure_jitter`] finds the anchor in a sample of the reads and tallies
+//! the length of the sequence found before it, from which an adjusted length
+//! range is suggested (see [`PieceJitter::suggested_range`]), which may in
+//! turn be applied to the geometry (see [`JitterReport::adjusted_geometry`]).
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece, NucStr};
+
+use crate::decode::ExternalDecoder;
+use crate::iupac;
+use crate::malformed::OnError;
+use crate::{input_groups, read_input_group, InputFormat};
+
+/// How much longer than the upper bound of its length range the piece
+/// preceding an anchor may be found to be.
+const MAX_EXTRA_LEN: usize = 8;
+/// The maximum number of distinct lengths spanned by a suggested range, so
+/// that the pieces are padded with a single base (see
+/// [`crate::PaddingScheme::Distinct`]).
+const SUGGESTED_RANGE_LIMIT: u32 = 4;
+
+/// The observed lengths of the piece preceding the anchor of one read.
+#[derive(Debug, Clone)]
+pub struct PieceJitter {
+    /// The read holding the anchor (`0` for read 1 and `1` for read 2).
+    pub read: usize,
+    /// The index of the piece preceding the anchor in the geometry of the read.
+    pub piece: usize,
+    /// The sequence of the anchor.
+    pub anchor: String,
+    /// The length range of the piece according to the geometry.
+    pub expected: (u32, u32),
+    /// The number of reads in which the piece was found to have each length.
+    pub lengths: BTreeMap<u32, u64>,
+    /// The number of reads in which the anchor was not found.
+    pub missing_anchor: u64,
+}
+
+impl PieceJitter {
+    /// The number of reads in which the anchor was found.
+    pub fn found(&self) -> u64 {
+        self.lengths.values().sum()
+    }
+
+    /// The fraction of the reads in which the anchor was found, for which
+    /// the length of the piece lies in `[l, h]`.
+    pub fn fraction_within(&self, (l, h): (u32, u32)) -> f64 {
+        let found = self.found();
+        if found == 0 {
+            return 0_f64;
+        }
+        let within: u64 = self.lengths.range(l..=h).map(|(_, c)| c).sum();
+        within as f64 / found as f64
+    }
+
+    /// The length range, spanning at most 4 distinct lengths, covering the
+    /// most reads in which the anchor was found (the narrowest such range if
+    /// there are several).  Returns `None` if the anchor was never found.
+    pub fn suggested_range(&self) -> Option<(u32, u32)> {
+        let mut best: Option<((u32, u32), u64)> = None;
+        for &l in self.lengths.keys() {
+            let window = self.lengths.range(l..l + SUGGESTED_RANGE_LIMIT);
+            let h = *window.clone().next_back().unwrap().0;
+            let count: u64 = window.map(|(_, c)| c).sum();
+            let better = match best {
+                None => true,
+                Some(((bl, bh), bc)) => count > bc || (count == bc && h - l < bh - bl),
+            };
+            if better {
+                best = Some(((l, h), count));
+            }
+        }
+        best.map(|(r, _)| r)
+    }
+}
+
+/// The lengths of the pieces preceding the anchors of a geometry, as
+/// observed in a sample of reads.
+#[derive(Debug, Clone)]
+pub struct JitterReport {
+    /// The number of fragments examined.
+    pub fragments: u64,
+    /// The measured pieces, at most one per read.
+    pub pieces: Vec<PieceJitter>,
+}
+
+impl JitterReport {
+    /// Returns `geo` with the length range of each measured piece replaced
+    /// by its suggested range (see [`PieceJitter::suggested_range`]).
+    pub fn adjusted_geometry(&self, geo: &FragmentGeomDesc) -> FragmentGeomDesc {
+        let mut adjusted = FragmentGeomDesc {
+            read1_desc: geo.read1_desc.clone(),
+            read2_desc: geo.read2_desc.clone(),
+        };
+        for pj in &self.pieces {
+            let Some((l, h)) = pj.suggested_range() else {
+                continue;
+            };
+            let gl = if l == h {
+                GeomLen::FixedLen(l)
+            } else {
+                GeomLen::LenRange(l, h)
+            };
+            let pieces = if pj.read == 0 {
+                &mut adjusted.read1_desc
+            } else {
+                &mut adjusted.read2_desc
+            };
+            pieces[pj.piece] = match &pieces[pj.piece] {
+                GeomPiece::Discard(_) => GeomPiece::Discard(gl),
+                GeomPiece::Barcode(_) => GeomPiece::Barcode(gl),
+                GeomPiece::Umi(_) => GeomPiece::Umi(gl),
+                GeomPiece::ReadSeq(_) => GeomPiece::ReadSeq(gl),
+                gp => gp.clone(),
+            };
+        }
+        adjusted
+    }
+}
+
+/// The piece of a read whose length can be measured: the piece immediately
+/// preceding the first anchor, so long as its length is bounded and all of
+/// the pieces before it have a fixed length.
+struct JitterTarget {
+    piece: usize,
+    /// The total length of the pieces before the measured piece.
+    offset: usize,
+    expected: (u32, u32),
+    anchor: String,
+}
+
+fn jitter_target(pieces: &[GeomPiece]) -> Option<JitterTarget> {
+    let (a, anchor) = pieces.iter().enumerate().find_map(|(i, gp)| match gp {
+        GeomPiece::Fixed(NucStr::Seq(s)) => Some((i, s)),
+        _ => None,
+    })?;
+    let piece = a.checked_sub(1)?;
+    let expected = match &pieces[piece] {
+        GeomPiece::Discard(gl)
+        | GeomPiece::Barcode(gl)
+        | GeomPiece::Umi(gl)
+        | GeomPiece::ReadSeq(gl) => match gl {
+            GeomLen::FixedLen(x) => (*x, *x),
+            GeomLen::LenRange(l, h) => (*l, *h),
+            GeomLen::Unbounded => return None,
+        },
+        GeomPiece::Fixed(_) => return None,
+    };
+    let mut offset = 0;
+    for gp in &pieces[..piece] {
+        offset += match gp {
+            GeomPiece::Discard(GeomLen::FixedLen(x))
+            | GeomPiece::Barcode(GeomLen::FixedLen(x))
+            | GeomPiece::Umi(GeomLen::FixedLen(x))
+            | GeomPiece::ReadSeq(GeomLen::FixedLen(x)) => *x as usize,
+            GeomPiece::Fixed(NucStr::Seq(s)) => s.len(),
+            _ => return None,
+        };
+    }
+    Some(JitterTarget {
+        piece,
+        offset,
+        expected,
+        anchor: anchor.clone(),
+    })
+}
+
+/// Returns the length of the sequence between `target.offset` and the first
+/// occurrence of the anchor in `r`, if the anchor is found within
+/// [`MAX_EXTRA_LEN`] bases of where the geometry allows it to be.
+fn measure_read(target: &JitterTarget, r: &[u8]) -> Option<u32> {
+    let anchor = target.anchor.as_bytes();
+    let end =
+        (target.offset + target.expected.1 as usize + MAX_EXTRA_LEN + anchor.len()).min(r.len());
+    let start = target.offset + 1;
+    r.get(start..end)?
+        .windows(anchor.len())
+        .position(|w| iupac::matches(anchor, w, false))
+        .map(|p| (p + 1) as u32)
+}
+
+/// Examines (at most) the first `max_fragments` fragments of the input files
+/// `r1` and `r2`, and measures the length of the piece preceding the anchor
+/// of each read of the geometry `geo` (see the module documentation).  The
+/// read 1 inputs are in the format `input_format`, and the ORA compressed
+/// input files are decoded by running `ora_decoder` on them.  Unless
+/// `on_error` is [`OnError::Abort`], the fragments holding a malformed record
+/// are left out.
+pub fn measure_jitter(
+    geo: &FragmentGeomDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    max_fragments: u64,
+    on_error: OnError,
+    input_format: InputFormat,
+    ora_decoder: &ExternalDecoder,
+) -> Result<JitterReport> {
+    let targets = [
+        jitter_target(&geo.read1_desc),
+        jitter_target(&geo.read2_desc),
+    ];
+    let mut pieces: Vec<PieceJitter> = targets
+        .iter()
+        .enumerate()
+        .filter_map(|(read, t)| {
+            t.as_ref().map(|t| PieceJitter {
+                read,
+                piece: t.piece,
+                anchor: t.anchor.clone(),
+                expected: t.expected,
+                lengths: BTreeMap::new(),
+                missing_anchor: 0,
+            })
+        })
+        .collect();
+    if pieces.is_empty() {
+        bail!(
+            "The geometry has no anchor (f piece) preceded by a piece whose length can be measured"
+        );
+    }
+
+    let mut fragments = 0_u64;
+    'files: for (files1, files2) in input_groups(r1, r2, input_format)? {
+        let mut batches = read_input_group(files1, files2, None, None, on_error, ora_decoder)?;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                if fragments >= max_fragments {
+                    break 'files;
+                }
+                fragments += 1;
+                for pj in pieces.iter_mut() {
+                    let target = targets[pj.read].as_ref().unwrap();
+                    match measure_read(target, frag.seqs[pj.read]) {
+                        Some(len) => *pj.lengths.entry(len).or_insert(0) += 1,
+                        None => pj.missing_anchor += 1,
+                    }
+                }
+            }
+            batches.recycle(batch);
+        }
+    }
+    Ok(JitterReport { fragments, pieces })
+}
+
+/// Returns the textual description of the geometry `geo`, e.g.
+/// `1{b[9-10]f[CAGAGC]u[8]}2{r:}`.
+pub fn geometry_string(geo: &FragmentGeomDesc) -> String {
+    format!(
+        "1{{{}}}2{{{}}}",
+        read_string(&geo.read1_desc),
+        read_string(&geo.read2_desc)
+    )
+}
+
+/// Returns the textual description of the pieces of a read, e.g.
+/// `b[9-10]f[CAGAGC]u[8]`.
+pub fn read_string(pieces: &[GeomPiece]) -> String {
+    let mut rep = String::new();
+    for gp in pieces {
+        let (kind, gl) = match gp {
+            GeomPiece::Discard(gl) => ('x', gl),
+            GeomPiece::Barcode(gl) => ('b', gl),
+            GeomPiece::Umi(gl) => ('u', gl),
+            GeomPiece::ReadSeq(gl) => ('r', gl),
+            GeomPiece::Fixed(NucStr::Seq(s)) => {
+                rep += &format!("f[{}]", s);
+                continue;
+            }
+        };
+        rep += &match gl {
+            GeomLen::FixedLen(x) => format!("{}[{}]", kind, x),
+            GeomLen::LenRange(l, h) => format!("{}[{}-{}]", kind, l, h),
+            GeomLen::Unbounded => format!("{}:", kind),
+        };
+    }
+    rep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn suggested_ranges() {
+        let geo = FragmentGeomDesc::try_from("1{b[9-10]f[CAGAGC]u[8]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        let mut reads1 = String::new();
+        let mut reads2 = String::new();
+        for (i, bc) in [
+            "ACGTACGT",
+            "ACGTACGTA",
+            "ACGTACGTAC",
+            "ACGTACGTACG",
+            "ACGTACGTA",
+        ]
+        .iter()
+        .enumerate()
+        {
+            reads1 += &format!(">r{}\n{}CAGAGCTTTTTTTT\n", i, bc);
+            reads2 += &format!(">r{}\nACGT\n", i);
+        }
+        reads1 += ">r5\nTTTTTTTTTTTTTTTTTTTTTT\n";
+        reads2 += ">r5\nACGT\n";
+        std::fs::write(&r1, reads1).unwrap();
+        std::fs::write(&r2, reads2).unwrap();
+
+        let report = measure_jitter(
+            &geo,
+            &[r1],
+            &[r2],
+            100,
+            OnError::Abort,
+            InputFormat::default(),
+            &ExternalDecoder::default(),
+        )
+        .unwrap();
+        assert_eq!(report.fragments, 6);
+        assert_eq!(report.pieces.len(), 1);
+        let pj = &report.pieces[0];
+        assert_eq!((pj.piece, pj.expected), (0, (9, 10)));
+        assert_eq!(pj.missing_anchor, 1);
+        assert_eq!(pj.found(), 5);
+        assert_eq!(pj.fraction_within(pj.expected), 0.6);
+        assert_eq!(pj.suggested_range(), Some((8, 11)));
+        assert_eq!(
+            geometry_string(&report.adjusted_geometry(&geo)),
+            "1{b[8-11]f[CAGAGC]u[8]}2{r:}"
+        );
+
+        // without an anchor, there is nothing to measure
+        let plain = FragmentGeomDesc::try_from("1{b[16]u[12]x:}2{r:}").unwrap();
+        assert!(jitter_target(&plain.read1_desc).is_none());
+    }
+}
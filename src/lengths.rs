@@ -0,0 +1,262 @@
+//! Padding variable length pieces to their observed, rather than declared,
+//! maximum length.
+//!
+//! A variable length piece with the length range `[l, h]` is padded to a
+//! length derived from `h` (see [`crate::PaddingScheme`]).  When the declared
+//! range is wider than the lengths actually found in the reads (e.g. `b[9-12]`
+//! for barcodes of 9 or 10 bases), every transformed piece is longer than it
+//! needs to be.  [`observe_lengths`] makes a first pass over the input,
+//! tallying the captured length of each variable length piece, and
+//! [`ObservedLengths::apply`] then lowers the upper bound used to pad each
+//! piece to the longest length observed, for a second pass transforming the
+//! reads.  The declared ranges are still used to match the reads, so the
+//! first pass must see all of the input for every piece to fit its padding.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use seq_geom_parser::{GeomLen, GeomPiece};
+
+use crate::decode::ExternalDecoder;
+use crate::malformed::OnError;
+use crate::{input_groups, read_input_group, FragmentRegexDesc, InputFormat, SeqPair};
+
+/// The observed lengths of one variable length piece.
+#[derive(Debug, Clone)]
+pub struct PieceLengths {
+    /// The read holding the piece (`0` for read 1 and `1` for read 2).
+    pub read: usize,
+    /// The index of the piece among the captured pieces of the read.
+    pub piece: usize,
+    /// The length range of the piece according to the geometry.
+    pub declared: (u32, u32),
+    /// The number of transformed fragments in which the piece was found to
+    /// have each length.
+    pub lengths: BTreeMap<u32, u64>,
+}
+
+impl PieceLengths {
+    /// The longest length observed, if the piece was observed at all.
+    pub fn max(&self) -> Option<u32> {
+        self.lengths.keys().next_back().copied()
+    }
+
+    /// The number of transformed fragments in which the piece was observed.
+    pub fn observed(&self) -> u64 {
+        self.lengths.values().sum()
+    }
+
+    /// Adds the lengths observed in `other`, for the same piece, to these.
+    pub fn add(&mut self, other: &PieceLengths) {
+        for (&len, &c) in &other.lengths {
+            *self.lengths.entry(len).or_insert(0) += c;
+        }
+    }
+}
+
+/// The lengths of the variable length pieces of a geometry, as observed in
+/// the transformed fragments of an input.
+#[derive(Debug, Clone)]
+pub struct ObservedLengths {
+    /// The number of fragments examined.
+    pub fragments: u64,
+    /// The number of fragments that were transformed, and so observed.
+    pub transformed: u64,
+    /// The variable length pieces, in the order of the reads and pieces.
+    pub pieces: Vec<PieceLengths>,
+}
+
+impl ObservedLengths {
+    /// Pads each variable length piece of `geo_re` to the longest length
+    /// observed for it (see the module documentation).  Pieces that were
+    /// never observed keep their declared upper bound.
+    pub fn apply(&self, geo_re: &mut FragmentRegexDesc) {
+        for pl in &self.pieces {
+            let Some(m) = pl.max() else {
+                continue;
+            };
+            narrow_piece(geo_re, pl, m);
+            if let Some(rescue) = geo_re.rescue.as_mut() {
+                narrow_piece(rescue, pl, m);
+            }
+        }
+    }
+}
+
+/// Lowers the upper bound of the length range of the piece `pl` of `geo_re`
+/// to `m`.
+fn narrow_piece(geo_re: &mut FragmentRegexDesc, pl: &PieceLengths, m: u32) {
+    let pieces = if pl.read == 0 {
+        &mut geo_re.r1_cginfo
+    } else {
+        &mut geo_re.r2_cginfo
+    };
+    if let GeomPiece::Barcode(GeomLen::LenRange(_, h))
+    | GeomPiece::Umi(GeomLen::LenRange(_, h))
+    | GeomPiece::ReadSeq(GeomLen::LenRange(_, h)) = &mut pieces[pl.piece]
+    {
+        *h = m;
+    }
+}
+
+/// Returns the (empty) tallies of the variable length pieces of `geo_re`, in
+/// the order of the reads and pieces.
+pub(crate) fn ranged_pieces(geo_re: &FragmentRegexDesc) -> Vec<PieceLengths> {
+    let mut pieces = vec![];
+    for (read, cginfo) in [&geo_re.r1_cginfo, &geo_re.r2_cginfo]
+        .into_iter()
+        .enumerate()
+    {
+        for (piece, gp) in cginfo.iter().enumerate() {
+            if let GeomPiece::Barcode(GeomLen::LenRange(l, h))
+            | GeomPiece::Umi(GeomLen::LenRange(l, h))
+            | GeomPiece::ReadSeq(GeomLen::LenRange(l, h)) = gp
+            {
+                pieces.push(PieceLengths {
+                    read,
+                    piece,
+                    declared: (*l, *h),
+                    lengths: BTreeMap::new(),
+                });
+            }
+        }
+    }
+    pieces
+}
+
+/// Tallies the captured lengths `lens` of the variable length pieces of a
+/// transformed fragment (see [`FragmentRegexDesc::last_lens`]) in `pieces`.
+pub(crate) fn record_lens(pieces: &mut [PieceLengths], lens: [&[u32]; 2]) {
+    // the captured lengths are in the order of the variable length
+    // pieces, as are the pieces of each read in `pieces`
+    let mut next = [0, 0];
+    for pl in pieces.iter_mut() {
+        if let Some(&len) = lens[pl.read].get(next[pl.read]) {
+            *pl.lengths.entry(len).or_insert(0) += 1;
+        }
+        next[pl.read] += 1;
+    }
+}
+
+/// Transforms the fragments of the input files `r1` and `r2` with `geo_re`,
+/// and tallies the captured length of each of its variable length pieces (see
+/// the module documentation).  The read 1 inputs are in the format
+/// `input_format`, and the ORA compressed input files are decoded by running
+/// `ora_decoder` on them.  Unless `on_error` is [`OnError::Abort`], the
+/// fragments holding a malformed record are left out.
+pub fn observe_lengths(
+    geo_re: &mut FragmentRegexDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    on_error: OnError,
+    input_format: InputFormat,
+    ora_decoder: &ExternalDecoder,
+) -> Result<ObservedLengths> {
+    let mut obs = ObservedLengths {
+        fragments: 0,
+        transformed: 0,
+        pieces: ranged_pieces(geo_re),
+    };
+    let mut parsed = SeqPair::new();
+    for (files1, files2) in input_groups(r1, r2, input_format)? {
+        let mut batches = read_input_group(files1, files2, None, None, on_error, ora_decoder)?;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                obs.fragments += 1;
+                if !geo_re.parse_into(frag.seqs[0], frag.seqs[1], &mut parsed) {
+                    continue;
+                }
+                obs.transformed += 1;
+                record_lens(&mut obs.pieces, geo_re.last_lens());
+            }
+            batches.recycle(batch);
+        }
+    }
+    Ok(obs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn pieces_are_padded_to_the_observed_maximum() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[4-7]f[ACG]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&r1, ">a\nTTTTACGCC\n>b\nGGGGGACGCC\n>c\nTTTTTTTTTTTT\n").unwrap();
+        std::fs::write(&r2, ">a\nAA\n>b\nAA\n>c\nAA\n").unwrap();
+
+        let obs = observe_lengths(
+            &mut geo_re,
+            &[r1],
+            &[r2],
+            OnError::Abort,
+            InputFormat::default(),
+            &ExternalDecoder::default(),
+        )
+        .unwrap();
+        assert_eq!((obs.fragments, obs.transformed), (3, 2));
+        assert_eq!(obs.pieces.len(), 1);
+        assert_eq!(obs.pieces[0].declared, (4, 7));
+        assert_eq!(obs.pieces[0].max(), Some(5));
+
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[8]u[2]}2{r:}"
+        );
+        obs.apply(&mut geo_re);
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[6]u[2]}2{r:}"
+        );
+        let mut sp = SeqPair::new();
+        assert!(geo_re.parse_into(b"TTTTACGCC", b"AA", &mut sp));
+        assert_eq!(sp.s1, b"TTTTACCC");
+        assert!(geo_re.parse_into(b"GGGGGACGCC", b"AA", &mut sp));
+        assert_eq!(sp.s1, b"GGGGGACC");
+    }
+
+    #[test]
+    fn padding_usage_is_tallied() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[9-10]f[CAGAGC]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        let (mut s1, mut s2) = (String::new(), String::new());
+        for i in 0..10 {
+            let bc = if i < 3 { "ACGTACGTA" } else { "ACGTACGTAC" };
+            s1 += &format!(">r{}\n{}CAGAGCTT\n", i, bc);
+            s2 += &format!(">r{}\nGG\n", i);
+        }
+        std::fs::write(&r1, s1).unwrap();
+        std::fs::write(&r2, s2).unwrap();
+
+        let stats = crate::xform_read_pairs_to_file_with_options(
+            geo_re,
+            &[r1],
+            &[r2],
+            Some(dir.path().join("o1.fa")),
+            Some(dir.path().join("o2.fa")),
+            &crate::XformOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.padding_usage.len(), 1);
+        let pl = &stats.padding_usage[0];
+        assert_eq!((pl.read, pl.piece, pl.declared), (0, 0, (9, 10)));
+        assert_eq!(pl.lengths, BTreeMap::from([(9, 3), (10, 7)]));
+        assert!(stats
+            .to_string()
+            .contains("piece 1 of read 1 (9-10): 9: 3 (30.00%) 10: 7 (70.00%),"));
+    }
+}
@@ -3,25 +3,123 @@
 //! `seq_geom_xform` is a crate for transforming complex fragment library geometries
 //! from single-cell sequencing data into simple fragment library geometries.
 
+use std::any::Any;
 use std::fmt;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
 use regex::bytes::{CaptureLocations, Regex};
 use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece, NucStr};
 
-use needletail::{parse_fastx_file, Sequence};
+use needletail::{parse_fastx_file, parse_fastx_reader, FastxReader};
 use thousands::Separable;
 use tracing::info;
 
-use nix::sys::stat;
-use nix::unistd;
 use tempfile::tempdir;
 
-#[derive(Debug)]
+pub mod anchor;
+#[cfg(feature = "async")]
+pub mod async_xform;
+pub mod bam;
+pub mod barcode;
+mod batch;
+mod bgzf;
+pub mod calibrate;
+pub mod capture;
+pub mod chunked;
+pub mod complexity;
+pub mod consistency;
+pub mod control;
+pub mod correction;
+pub mod decode;
+pub mod dedup;
+pub mod demux;
+pub mod failure;
+pub mod feature;
+mod fixed;
+pub mod frame;
+pub mod guide;
+pub mod handle;
+pub mod hook;
+pub mod index;
+pub mod iupac;
+pub mod jitter;
+pub mod lengths;
+pub mod malformed;
+pub mod name;
+pub mod naming;
+pub mod offsets;
+pub mod output;
+pub mod pairing;
+mod parallel;
+mod pipe;
+pub mod presets;
+pub mod preview;
+pub mod probe;
+pub mod progress;
+pub mod provenance;
+pub mod retry;
+pub mod route;
+pub mod rules;
+pub mod scan;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod sim;
+pub mod sort;
+pub mod spatial;
+mod stages;
+pub mod stream;
+pub mod swap;
+#[cfg(any(test, feature = "test_utils"))]
+pub mod test_utils;
+pub mod umi;
+pub mod verify;
+pub mod whitelist;
+use bam::{BamOptions, BamReader, BamWriter};
+use barcode::BarcodeCounts;
+use batch::BatchReader;
+use capture::{CapturedPiece, ParsedFragment};
+use complexity::{GeometryMetrics, ReadMetrics};
+use consistency::{ConsistencyCounts, ConsistencyOptions};
+use control::{ControlCounts, ControlOptions};
+use correction::BarcodeCorrectorHandle;
+use decode::ExternalDecoder;
+use dedup::DedupOptions;
+use demux::{DemuxCounts, DemuxOptions};
+use failure::{FailureCause, FailureCounts, ParseFailure};
+use feature::{FeatureCounts, FeatureOptions};
+use fixed::FixedOffsets;
+use guide::{GuideCounts, GuideOptions};
+use hook::RecordHookHandle;
+use index::{IndexReadDesc, IndexReadOptions};
+use lengths::{ranged_pieces, record_lens, PieceLengths};
+use malformed::OnError;
+use name::NameCapture;
+use naming::IdFormat;
+use offsets::{offset_index_path, OffsetIndex, OffsetIndexOptions};
+use output::{Compression, OutputFormat, PairedWriter, SinkConfig, SplitPolicy};
+use pairing::{PairingChecker, PairingCounts};
+use parallel::BatchParser;
+use probe::{ProbeCounts, ProbeOptions};
+use progress::{CountingReader, InputProgress, StatsSnapshot, XformStatsHandle};
+use retry::{RetryOptions, RetryPolicy};
+use rules::{PieceRule, PieceXform};
+use scan::ReadPrefilter;
+use sort::SortOptions;
+use spatial::{SpatialCounts, SpatialOptions};
+use stages::FragmentStages;
+use umi::{UmiCorrector, UmiOptions};
+use whitelist::{Whitelist, WhitelistCounts};
+
+#[derive(Debug, Clone)]
 pub struct FragmentRegexDesc {
     pub r1_cginfo: Vec<GeomPiece>,
     pub r2_cginfo: Vec<GeomPiece>,
@@ -37,19 +135,71 @@ pub struct FragmentRegexDesc {
     /// for read 2. This is re-used between parsing calls to
     /// increase performance.
     r2_clocs: CaptureLocations,
+    /// Cheap checks used to reject reads that cannot
+    /// match `r1_re` without running the regex.
+    r1_prefilter: ReadPrefilter,
+    /// Cheap checks used to reject reads that cannot
+    /// match `r2_re` without running the regex.
+    r2_prefilter: ReadPrefilter,
+    /// The offsets of the captured pieces of read 1, if
+    /// they are all fixed, in which case read 1 is parsed
+    /// without running `r1_re` (see the `fixed` module).
+    r1_offsets: Option<FixedOffsets>,
+    /// The offsets of the captured pieces of read 2, if
+    /// they are all fixed.
+    r2_offsets: Option<FixedOffsets>,
+    /// The transformation of each captured piece of read 1
+    /// and read 2, in order, or none if no piece of the read
+    /// is transformed (see the [`rules`] module).
+    xforms: [Vec<PieceXform>; 2],
+    /// If true, a read that does not match its regex is
+    /// reverse complemented and matched again.
+    revcomp: bool,
+    /// Holds the reverse complement of the read being matched.
+    rc_buf: Vec<u8>,
+    /// Whether read 1 and read 2 of the last pair parsed
+    /// were matched as their reverse complement.
+    reversed: [bool; 2],
+    /// The same geometry, with mismatches allowed in its fixed
+    /// anchors, with which the pairs that do not match exactly
+    /// are parsed again.
+    rescue: Option<Box<FragmentRegexDesc>>,
+    /// Whether the last pair parsed only matched with
+    /// mismatches in its anchors.
+    rescued: bool,
+    /// How the variable length pieces are padded.
+    padding: PaddingScheme,
+    /// The captured lengths of the variable length pieces
+    /// of read 1 and read 2 of the last pair parsed.
+    lens: [Vec<u32>; 2],
+    /// The ranges of the padding of the variable length
+    /// pieces of read 1 and read 2 of the last pair parsed.
+    pads: [Vec<Range<u32>>; 2],
+    /// The index reads (reads 3 and 4) of the geometry, in the
+    /// order of the reads (see the [`index`] module).
+    index_reads: Vec<IndexReadDesc>,
+    /// The pieces captured from the read names, if any (see
+    /// the [`name`] module).
+    name_capture: Option<NameCapture>,
+    /// The metrics of the geometry of read 1 and read 2 (see
+    /// the [`complexity`] module).
+    read_metrics: [ReadMetrics; 2],
 }
 
-#[derive(Debug)]
+/// The transformed read 1 and read 2 of a fragment.  The sequences are kept
+/// as bytes, as they are read and written, so that no UTF-8 validation or
+/// conversion takes place along the way.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SeqPair {
-    pub s1: String,
-    pub s2: String,
+    pub s1: Vec<u8>,
+    pub s2: Vec<u8>,
 }
 
 impl SeqPair {
     pub fn new() -> Self {
         SeqPair {
-            s1: String::new(),
-            s2: String::new(),
+            s1: Vec::new(),
+            s2: Vec::new(),
         }
     }
 
@@ -65,25 +215,167 @@ impl Default for SeqPair {
     }
 }
 
-/// The maximum width for a `RangedLength` that can be handled with our
-/// current padding scheme.  That is, if we have a piece of geometry like
-/// Umi(BoundedRange(x, y)), we must have that y-x <= 4.
-const BOUNDED_RANGE_LIMIT: u32 = 4;
-/// The padding that we will append to each possible length in a variable
-/// length geometry piece.
-const VAR_LEN_BC_PADDING: &[&str] = &["A", "AC", "AAG", "AAAT"];
+/// The maximum number of mismatches that may be allowed in the fixed anchors
+/// of a geometry (see [`FragmentGeomDescExt::as_regex_with_anchor_mismatches`]).
+pub const MAX_ANCHOR_MISMATCHES: u32 = 3;
+/// The characters matched by the pieces of a geometry other than its fixed
+/// anchors, unless extended (see [`RegexOptions::alphabet`]).
+pub const DEFAULT_ALPHABET: &str = "ACGTN";
+/// The nucleotides encoding the digits (in base 4) of the suffix identifying
+/// the captured length of a variable length piece with
+/// [`PaddingScheme::Distinct`].
+pub(crate) const LEN_CODE_DIGITS: &[u8; 4] = b"ACGT";
+
+/// How the variable length pieces of a geometry (e.g. `b[9-10]`) are padded
+/// to a fixed length in the transformed reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PaddingScheme {
+    /// A piece with the length range `[l, h]` is padded to a length of
+    /// `h + 1`, with a different suffix for each captured length, so that
+    /// pieces of different lengths never collide.  A piece shorter than `h`
+    /// by `i` bases is padded with `i` `A`s followed by `i` written in base 4
+    /// (with the digits `ACGT`), so that `b[9-10]` captured with 10 bases is
+    /// padded with `A`, and with 9 bases with `AC`.  The suffix takes a
+    /// single base for ranges of up to 4 distinct lengths, and one more base
+    /// for every 4-fold increase in the number of lengths (e.g. `b[2-9]` is
+    /// padded to a length of `h + 2`).
+    #[default]
+    Distinct,
+    /// A piece with the length range `[l, h]` is padded to a length of `h`
+    /// with `A`s, so that pieces of the full length are left as they are
+    /// (e.g. to match a permit list of length `h` barcodes).  As pieces of
+    /// different lengths may then collide, the captured lengths of the
+    /// variable length pieces of each read are recorded in its header, as
+    /// the tag `lens=...` (e.g. `lens=9,10`).
+    MaxLen,
+}
+
+impl PaddingScheme {
+    /// The length to which a piece with the length range `[l, h]` is padded.
+    pub fn padded_len(&self, l: u32, h: u32) -> u32 {
+        match self {
+            PaddingScheme::Distinct => h + len_code_width(l, h),
+            PaddingScheme::MaxLen => h,
+        }
+    }
+
+    /// Appends to `out` the padding of a piece with the length range
+    /// `[l, h]` whose captured length is `len`.  Returns false if `len` is
+    /// not within range.
+    #[inline(always)]
+    fn push_padding(&self, l: usize, h: usize, len: usize, out: &mut Vec<u8>) -> bool {
+        let Some(i) = h.checked_sub(len).filter(|_| len >= l) else {
+            return false;
+        };
+        out.resize(out.len() + i, b'A');
+        if let PaddingScheme::Distinct = self {
+            for d in (0..len_code_width(l as u32, h as u32)).rev() {
+                out.push(LEN_CODE_DIGITS[(i >> (2 * d)) & 3]);
+            }
+        }
+        true
+    }
+}
+
+/// The number of bases (base 4 digits) of the suffix identifying the captured
+/// length of a piece with the length range `[l, h]` with
+/// [`PaddingScheme::Distinct`], which is at least 1 (even if `l == h`).
+fn len_code_width(l: u32, h: u32) -> u32 {
+    let lengths = u64::from(h - l) + 1;
+    let mut width = 1;
+    while 4_u64.pow(width) < lengths {
+        width += 1;
+    }
+    width
+}
+
+impl std::str::FromStr for PaddingScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "h+1" | "distinct" => Ok(PaddingScheme::Distinct),
+            "h" | "max" => Ok(PaddingScheme::MaxLen),
+            _ => bail!("unknown padding scheme {}; expected h+1 or h", s),
+        }
+    }
+}
+
+impl fmt::Display for PaddingScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PaddingScheme::Distinct => "h+1",
+            PaddingScheme::MaxLen => "h",
+        })
+    }
+}
+
+/// Writes the reverse complement of `s` into `out`.  Characters other than
+/// (upper or lower case) nucleotides are left as they are.
+pub(crate) fn reverse_complement_into(s: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.extend(s.iter().rev().map(|c| match c {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        _ => *c,
+    }));
+}
+
+/// Matches the read `r` against `re`, filling `clocs`, and returns the
+/// sequence that matched: `r` itself or, if `r` does not match and `revcomp`
+/// is true, its reverse complement (which is written into `rc_buf`), along
+/// with whether it is the reverse complement.  Returns `None` if neither
+/// matches.
+#[inline(always)]
+fn match_read<'a>(
+    re: &Regex,
+    clocs: &mut CaptureLocations,
+    prefilter: &ReadPrefilter,
+    r: &'a [u8],
+    revcomp: bool,
+    rc_buf: &'a mut Vec<u8>,
+) -> Option<(&'a [u8], bool)> {
+    if prefilter.may_match(r) && re.captures_read(clocs, r).is_some() {
+        return Some((r, false));
+    }
+    if revcomp {
+        reverse_complement_into(r, rc_buf);
+        if prefilter.may_match(rc_buf) && re.captures_read(clocs, rc_buf).is_some() {
+            return Some((rc_buf, true));
+        }
+    }
+    None
+}
 
 /// Builds the parsed output string `s` given the `CaptureLocations` `clocs`,
-/// the expected captured `GeomPiece`s `gpieces` and the input string `r`.  This function
+/// the expected captured `GeomPiece`s `gpieces` and the input read `r`.  This function
 /// returns true if the parse was succesful (the captured groups are what is expected)
-/// and false otherwise.
+/// and false otherwise.  The variable length pieces are padded according to
+/// `padding`, and their captured lengths are written into `lens` and the
+/// ranges of their padding (relative to the start of the parsed read) into
+/// `pads`.  The captured pieces are transformed by `xforms` (which is either
+/// empty or holds the transformation of each piece) before being padded.
 #[inline(always)]
+#[allow(clippy::too_many_arguments)]
 fn parse_single_read(
     clocs: &CaptureLocations,
-    gpieces: &Vec<GeomPiece>,
-    r: &str,
-    outstr: &mut String,
+    gpieces: &[GeomPiece],
+    xforms: &[PieceXform],
+    r: &[u8],
+    padding: PaddingScheme,
+    outstr: &mut Vec<u8>,
+    lens: &mut Vec<u32>,
+    pads: &mut Vec<Range<u32>>,
 ) -> bool {
+    lens.clear();
+    pads.clear();
+    let start = outstr.len();
     // process each capture group:
     // we start at 1 here because the first group is always the match of the whole string
     // and doesn't correspond to any *explicit* capture.  That is, if we explicilty
@@ -92,21 +384,35 @@ fn parse_single_read(
     // match of the whole string, and iterate over the remaining capture locations.
     for cl in 1..clocs.len() {
         if let Some(g) = clocs.get(cl) {
-            outstr.push_str(r.get(g.0..g.1).unwrap());
+            match (r.get(g.0..g.1), xforms.get(cl - 1)) {
+                (Some(p), Some(xf)) if !xf.is_identity() => xf.push(p, outstr),
+                (Some(p), _) => outstr.extend_from_slice(p),
+                _ => return false,
+            }
 
             match gpieces.get(cl - 1) {
                 // if we captured some variable length piece of geometry
                 // then we have to apply the appropriate padding so that
                 // we can pass the result to a non-variable length parser.
-                Some(GeomPiece::Barcode(GeomLen::LenRange(_l, h)))
-                | Some(GeomPiece::Umi(GeomLen::LenRange(_l, h)))
-                | Some(GeomPiece::ReadSeq(GeomLen::LenRange(_l, h))) => {
+                Some(GeomPiece::Barcode(GeomLen::LenRange(l, h)))
+                | Some(GeomPiece::Umi(GeomLen::LenRange(l, h)))
+                | Some(GeomPiece::ReadSeq(GeomLen::LenRange(l, h))) => {
                     let captured_len = g.1 - g.0;
-                    outstr.push_str(VAR_LEN_BC_PADDING[(*h as usize) - (captured_len)]);
+                    let pad_start = (outstr.len() - start) as u32;
+                    if !padding.push_padding(*l as usize, *h as usize, captured_len, outstr) {
+                        return false;
+                    }
+                    lens.push(captured_len as u32);
+                    let pad_end = (outstr.len() - start) as u32;
+                    if pad_end > pad_start {
+                        pads.push(pad_start..pad_end);
+                    }
                 }
-                _ => {
+                Some(_) => {
                     // fixed length, do nothing
                 }
+                // every capture group has a piece (see `check_capture_groups`)
+                None => return false,
             }
         } else {
             return false;
@@ -115,7 +421,7 @@ fn parse_single_read(
     true
 }
 
-fn get_simplified_piscem_string(geo_pieces: &[GeomPiece]) -> String {
+fn get_simplified_piscem_string(geo_pieces: &[GeomPiece], padding: PaddingScheme) -> String {
     let mut rep = String::new();
     for gp in geo_pieces {
         match gp {
@@ -131,21 +437,17 @@ fn get_simplified_piscem_string(geo_pieces: &[GeomPiece]) -> String {
             GeomPiece::ReadSeq(GeomLen::FixedLen(x)) => {
                 rep += &format!("r[{}]", x);
             }
-            // NOTE: the + 1 in the rules below assumes we will
-            // only ever have variable width of geometry pieces
-            // of at most 4 bases. If we need to every move
-            // beyond that, this code will have to be generalized.
-            GeomPiece::Discard(GeomLen::LenRange(_l, h)) => {
-                rep += &format!("x[{}]", h + 1);
+            GeomPiece::Discard(GeomLen::LenRange(l, h)) => {
+                rep += &format!("x[{}]", padding.padded_len(*l, *h));
             }
-            GeomPiece::Barcode(GeomLen::LenRange(_l, h)) => {
-                rep += &format!("b[{}]", h + 1);
+            GeomPiece::Barcode(GeomLen::LenRange(l, h)) => {
+                rep += &format!("b[{}]", padding.padded_len(*l, *h));
             }
-            GeomPiece::Umi(GeomLen::LenRange(_l, h)) => {
-                rep += &format!("u[{}]", h + 1);
+            GeomPiece::Umi(GeomLen::LenRange(l, h)) => {
+                rep += &format!("u[{}]", padding.padded_len(*l, *h));
             }
-            GeomPiece::ReadSeq(GeomLen::LenRange(_l, h)) => {
-                rep += &format!("r[{}]", h + 1);
+            GeomPiece::ReadSeq(GeomLen::LenRange(l, h)) => {
+                rep += &format!("r[{}]", padding.padded_len(*l, *h));
             }
             GeomPiece::Discard(GeomLen::Unbounded) => {
                 rep += "x:";
@@ -167,52 +469,535 @@ fn get_simplified_piscem_string(geo_pieces: &[GeomPiece]) -> String {
     rep
 }
 
-fn get_simplified_geo(gp: &GeomPiece) -> GeomPiece {
+fn get_simplified_geo(gp: &GeomPiece, padding: PaddingScheme) -> GeomPiece {
     match gp {
-        // NOTE: the + 1 in the rules below assumes we will
-        // only ever have variable width of geometry pieces
-        // of at most 4 bases. If we need to every move
-        // beyond that, this code will have to be generalized.
-        GeomPiece::Discard(GeomLen::LenRange(_l, h)) => {
-            GeomPiece::Discard(GeomLen::FixedLen(h + 1))
+        GeomPiece::Discard(GeomLen::LenRange(l, h)) => {
+            GeomPiece::Discard(GeomLen::FixedLen(padding.padded_len(*l, *h)))
+        }
+        GeomPiece::Barcode(GeomLen::LenRange(l, h)) => {
+            GeomPiece::Barcode(GeomLen::FixedLen(padding.padded_len(*l, *h)))
         }
-        GeomPiece::Barcode(GeomLen::LenRange(_l, h)) => {
-            GeomPiece::Barcode(GeomLen::FixedLen(h + 1))
+        GeomPiece::Umi(GeomLen::LenRange(l, h)) => {
+            GeomPiece::Umi(GeomLen::FixedLen(padding.padded_len(*l, *h)))
         }
-        GeomPiece::Umi(GeomLen::LenRange(_l, h)) => GeomPiece::Umi(GeomLen::FixedLen(h + 1)),
-        GeomPiece::ReadSeq(GeomLen::LenRange(_l, h)) => {
-            GeomPiece::ReadSeq(GeomLen::FixedLen(h + 1))
+        GeomPiece::ReadSeq(GeomLen::LenRange(l, h)) => {
+            GeomPiece::ReadSeq(GeomLen::FixedLen(padding.padded_len(*l, *h)))
         }
         _ => gp.clone(),
     }
 }
 
+/// The location of a captured piece in a transformed fragment: the read
+/// (`0` for read 1 and `1` for read 2), the offset and the length.
+pub(crate) type PieceSpan = (usize, usize, usize);
+
+/// Writes the bytes of `frag` covered by `spans`, concatenated, into `out`.
+pub(crate) fn extract_spans(frag: &SeqPair, spans: &[PieceSpan], out: &mut Vec<u8>) {
+    out.clear();
+    for &(read, start, len) in spans {
+        let s = if read == 0 { &frag.s1 } else { &frag.s2 };
+        out.extend_from_slice(&s[start..start + len]);
+    }
+}
+
 impl FragmentRegexDesc {
+    /// Returns the locations, in the transformed fragments, of the captured
+    /// pieces for which `select` is true.  If one of these pieces is not at a
+    /// fixed location of a fixed length (because it, or a piece preceding it,
+    /// is unbounded), that piece is returned as the error.
+    pub(crate) fn piece_spans<F: Fn(&GeomPiece) -> bool>(
+        &self,
+        select: F,
+    ) -> std::result::Result<Vec<PieceSpan>, GeomPiece> {
+        let mut spans = vec![];
+        for (read, pieces) in [&self.r1_cginfo, &self.r2_cginfo].into_iter().enumerate() {
+            let mut offset = Some(0_usize);
+            for gp in pieces {
+                let len = match gp {
+                    GeomPiece::Barcode(GeomLen::FixedLen(x))
+                    | GeomPiece::Umi(GeomLen::FixedLen(x))
+                    | GeomPiece::ReadSeq(GeomLen::FixedLen(x)) => Some(*x as usize),
+                    // variable length pieces are padded (see `PaddingScheme`)
+                    GeomPiece::Barcode(GeomLen::LenRange(l, h))
+                    | GeomPiece::Umi(GeomLen::LenRange(l, h))
+                    | GeomPiece::ReadSeq(GeomLen::LenRange(l, h)) => {
+                        Some(self.padding.padded_len(*l, *h) as usize)
+                    }
+                    _ => None,
+                };
+                if select(gp) {
+                    match (offset, len) {
+                        (Some(o), Some(l)) => spans.push((read, o, l)),
+                        _ => return Err(gp.clone()),
+                    }
+                }
+                offset = offset.zip(len).map(|(o, l)| o + l);
+            }
+        }
+        Ok(spans)
+    }
+
     /// Parses the read pair `r1` and `r2` in accordance with the geometry specified
     /// in `self`.  The resulting parse, if successful, is placed into the output
     /// `sp`. This function returns true if the entire *pair* of reads was parsed succesfully,
     /// and false otherwise. If the parse is not successful, nothing can be assumed about
     /// the contents of `sp`.
     pub fn parse_into(&mut self, r1: &[u8], r2: &[u8], sp: &mut SeqPair) -> bool {
-        sp.clear();
-        let m1 = self.r1_re.captures_read(&mut self.r1_clocs, r1);
-        let m2 = self.r2_re.captures_read(&mut self.r2_clocs, r2);
+        self.rescued = false;
+        if self.parse_exact(r1, r2, sp) {
+            return true;
+        }
+        let Some(rescue) = self.rescue.as_mut() else {
+            return false;
+        };
+        if !rescue.parse_exact(r1, r2, sp) {
+            return false;
+        }
+        self.reversed = rescue.reversed;
+        self.lens.clone_from(&rescue.lens);
+        self.pads.clone_from(&rescue.pads);
+        self.rescued = true;
+        true
+    }
+
+    /// Parses the read pair `r1` and `r2` like
+    /// [`FragmentRegexDesc::parse_into`], placing the transformed reads and
+    /// the boundaries of each captured piece into `frag` (see the
+    /// [`capture`] module).  Returns true if the pair was parsed
+    /// successfully; otherwise, `frag` holds no piece.
+    pub fn parse_pieces_into(&mut self, r1: &[u8], r2: &[u8], frag: &mut ParsedFragment) -> bool {
+        frag.pieces.clear();
+        if !self.parse_into(r1, r2, &mut frag.seqs) {
+            return false;
+        }
+        frag.reversed = self.reversed;
+        // the captures of a rescued pair are those of the rescue regexes
+        let desc = match &self.rescue {
+            Some(rescue) if self.rescued => rescue,
+            _ => &*self,
+        };
+        let reads = [
+            (&desc.r1_clocs, &desc.r1_offsets, &desc.r1_cginfo, r1.len()),
+            (&desc.r2_clocs, &desc.r2_offsets, &desc.r2_cginfo, r2.len()),
+        ];
+        for (read, (clocs, offsets, cginfo, len)) in reads.into_iter().enumerate() {
+            // the reads parsed at fixed offsets leave their capture
+            // locations untouched
+            let captures: Vec<Option<(usize, usize)>> = match offsets {
+                Some(o) if !desc.revcomp => o.captures(len).map(Some).collect(),
+                _ => (1..clocs.len()).map(|cl| clocs.get(cl)).collect(),
+            };
+            let mut offset = 0;
+            for (capture, gp) in captures.into_iter().zip(cginfo) {
+                let Some((start, end)) = capture else {
+                    continue;
+                };
+                let padded_len = match gp {
+                    GeomPiece::Barcode(GeomLen::LenRange(l, h))
+                    | GeomPiece::Umi(GeomLen::LenRange(l, h))
+                    | GeomPiece::ReadSeq(GeomLen::LenRange(l, h)) => {
+                        desc.padding.padded_len(*l, *h) as usize
+                    }
+                    _ => end - start,
+                };
+                frag.pieces.push(CapturedPiece {
+                    read: read + 1,
+                    piece: gp.clone(),
+                    range: start..end,
+                    padded_range: offset..offset + padded_len,
+                });
+                offset += padded_len;
+            }
+        }
+        true
+    }
+
+    /// Parses each of the in-memory read pairs `pairs` (see
+    /// [`FragmentRegexDesc::parse_into`]) into the corresponding element of
+    /// `out`, which is resized to the number of pairs: `Some` transformed
+    /// fragment if the pair matched the geometry, and `None` otherwise.  No
+    /// I/O is done, and the `SeqPair`s already in `out` are reused, so that
+    /// calling this repeatedly with the same `out` amortizes the allocations
+    /// over the batches.  Index reads, if any, are not parsed.
+    pub fn transform_batch(&mut self, pairs: &[(&[u8], &[u8])], out: &mut Vec<Option<SeqPair>>) {
+        out.truncate(pairs.len());
+        out.resize_with(pairs.len(), || None);
+        // the pair of a fragment failing to parse is reused for the next one
+        let mut spare = None;
+        for (&(r1, r2), slot) in pairs.iter().zip(out.iter_mut()) {
+            let mut sp = slot.take().or_else(|| spare.take()).unwrap_or_default();
+            if self.parse_into(r1, r2, &mut sp) {
+                *slot = Some(sp);
+            } else {
+                spare = Some(sp);
+            }
+        }
+    }
+
+    /// Parses the single read `r` in accordance with the part of the
+    /// geometry describing read `read` (`1` or `2`), for single-end
+    /// geometries or for pairing the reads by other means.  The transformed
+    /// read, if the parse is successful, is placed into `out`.  Returns true
+    /// if the read was parsed successfully (and false for any other `read`),
+    /// in which case [`FragmentRegexDesc::last_lens`],
+    /// [`FragmentRegexDesc::last_reversed`] and
+    /// [`FragmentRegexDesc::last_rescued`] describe it in the entry of this
+    /// read.  Index reads cannot be parsed in this way.
+    pub fn parse_read_into(&mut self, read: usize, r: &[u8], out: &mut Vec<u8>) -> bool {
+        let i = match read {
+            1 | 2 => read - 1,
+            _ => return false,
+        };
+        self.rescued = false;
+        out.clear();
+        self.reversed[i] = false;
+        if self.parse_read_exact(i, r, out) {
+            return true;
+        }
+        match self.rescue.as_mut() {
+            Some(rescue) => {
+                out.clear();
+                rescue.reversed[i] = false;
+                if !rescue.parse_read_exact(i, r, out) {
+                    return false;
+                }
+                self.reversed[i] = rescue.reversed[i];
+                self.lens[i].clone_from(&rescue.lens[i]);
+                self.pads[i].clone_from(&rescue.pads[i]);
+                self.rescued = true;
+                true
+            }
+            None => false,
+        }
+    }
 
-        // if the overall match was not obtained for
-        // both of the reads, then don't attempt extraction.
-        if m1.or(m2).is_none() {
+    /// Parses the read pair `r1` and `r2` into `sp` like
+    /// [`FragmentRegexDesc::parse_into`], but without trying `self.rescue`.
+    fn parse_exact(&mut self, r1: &[u8], r2: &[u8], sp: &mut SeqPair) -> bool {
+        sp.clear();
+        self.reversed = [false; 2];
+        // unless its reverse complement may also be tried, read 2 can be
+        // rejected by its (cheap) prefilter before running any regex
+        if !self.revcomp && !self.r2_prefilter.may_match(r2) {
             return false;
         }
+        self.parse_read_exact(0, r1, &mut sp.s1) && self.parse_read_exact(1, r2, &mut sp.s2)
+    }
+
+    /// Parses the read `r` as read `read` (`0` for read 1 and `1` for read
+    /// 2), appending the transformed read to `out`, without trying
+    /// `self.rescue`.
+    fn parse_read_exact(&mut self, read: usize, r: &[u8], out: &mut Vec<u8>) -> bool {
+        let offsets = if read == 0 {
+            &self.r1_offsets
+        } else {
+            &self.r2_offsets
+        };
+        if let (Some(offsets), false) = (offsets, self.revcomp) {
+            // no piece is of variable length
+            self.lens[read].clear();
+            self.pads[read].clear();
+            let prefilter = if read == 0 {
+                &self.r1_prefilter
+            } else {
+                &self.r2_prefilter
+            };
+            return prefilter.may_match(r) && offsets.parse_into(r, out);
+        }
+        let (re, clocs, prefilter, cginfo) = if read == 0 {
+            (
+                &self.r1_re,
+                &mut self.r1_clocs,
+                &self.r1_prefilter,
+                &self.r1_cginfo,
+            )
+        } else {
+            (
+                &self.r2_re,
+                &mut self.r2_clocs,
+                &self.r2_prefilter,
+                &self.r2_cginfo,
+            )
+        };
+        match match_read(re, clocs, prefilter, r, self.revcomp, &mut self.rc_buf) {
+            Some((r, rev)) => {
+                self.reversed[read] = rev;
+                let (lens, pads) = (&mut self.lens[read], &mut self.pads[read]);
+                let xforms = &self.xforms[read];
+                parse_single_read(clocs, cginfo, xforms, r, self.padding, out, lens, pads)
+            }
+            None => false,
+        }
+    }
+
+    /// Finds why the read pair `r1` and `r2`, which failed to parse, does not
+    /// match the geometry: the first read (in order) that does not match its
+    /// part of the geometry, and why (see the [`failure`] module).  Only the
+    /// reads as they are (and not their reverse complements) are examined.
+    pub fn diagnose_failure(&mut self, r1: &[u8], r2: &[u8]) -> ParseFailure {
+        let mut scratch = Vec::new();
+        let (mut lens, mut pads) = (Vec::new(), Vec::new());
+        for (read, r) in [r1, r2].into_iter().enumerate() {
+            let (re, clocs, prefilter, cginfo) = if read == 0 {
+                (
+                    &self.r1_re,
+                    &mut self.r1_clocs,
+                    &self.r1_prefilter,
+                    &self.r1_cginfo,
+                )
+            } else {
+                (
+                    &self.r2_re,
+                    &mut self.r2_clocs,
+                    &self.r2_prefilter,
+                    &self.r2_cginfo,
+                )
+            };
+            let cause = match prefilter.diagnose(r) {
+                Some(cause) => Some(cause),
+                None if re.captures_read(clocs, r).is_none() => Some(FailureCause::NoMatch),
+                None if !parse_single_read(
+                    clocs,
+                    cginfo,
+                    &self.xforms[read],
+                    r,
+                    self.padding,
+                    &mut scratch,
+                    &mut lens,
+                    &mut pads,
+                ) =>
+                {
+                    Some(FailureCause::NoMatch)
+                }
+                None => None,
+            };
+            if let Some(cause) = cause {
+                return ParseFailure {
+                    read: read + 1,
+                    cause,
+                };
+            }
+        }
+        // both reads match on their own (e.g. only in their reverse
+        // complement orientation, or with mismatches in their anchors)
+        ParseFailure {
+            read: 1,
+            cause: FailureCause::NoMatch,
+        }
+    }
+
+    /// Adds the index read `read` (`3` for I1 or `4` for I2), with the
+    /// geometry pieces `pieces`, to the geometry (see the [`index`] module).
+    pub fn add_index_read(&mut self, read: usize, pieces: &[GeomPiece]) -> Result<()> {
+        if self.index_reads.iter().any(|ir| ir.read == read) {
+            bail!("The geometry already describes read {}", read);
+        }
+        self.index_reads.push(IndexReadDesc::new(read, pieces)?);
+        self.index_reads.sort_by_key(|ir| ir.read);
+        Ok(())
+    }
+
+    /// The index reads of the geometry, in the order of the reads.
+    pub fn index_reads(&self) -> &[IndexReadDesc] {
+        &self.index_reads
+    }
+
+    /// Parses the index reads `reads` (read 3 and read 4, either of which is
+    /// ignored if it is not part of the geometry) of a fragment whose reads
+    /// 1 and 2 were parsed, placing the transformed read 3 in `sp.s1` and the
+    /// transformed read 4 in `sp.s2`.  Returns true if all of the index reads
+    /// of the geometry were parsed successfully.
+    pub fn parse_index_into(&mut self, reads: [&[u8]; 2], sp: &mut SeqPair) -> bool {
+        sp.clear();
+        for ir in self.index_reads.iter_mut() {
+            let (r, out) = if ir.read == index::INDEX_READS[0] {
+                (reads[0], &mut sp.s1)
+            } else {
+                (reads[1], &mut sp.s2)
+            };
+            if !ir.parse_into(r, self.padding, out) {
+                return false;
+            }
+        }
+        true
+    }
 
-        let s1 = unsafe { std::str::from_utf8_unchecked(r1) };
-        let s2 = unsafe { std::str::from_utf8_unchecked(r2) };
+    /// Appends the pieces captured from the name `name` of read 1 to the
+    /// transformed fragment `sp` of a pair that was parsed (see
+    /// [`FragmentRegexDesc::set_name_capture`]).  Returns false if the name
+    /// does not match; always returns true if no piece is captured from the
+    /// read names.
+    pub fn parse_name_into(&self, name: &[u8], sp: &mut SeqPair) -> bool {
+        match &self.name_capture {
+            Some(nc) => {
+                let out = if nc.read() == 1 {
+                    &mut sp.s1
+                } else {
+                    &mut sp.s2
+                };
+                nc.capture_into(name, out)
+            }
+            None => true,
+        }
+    }
 
-        let parsed_r1 = parse_single_read(&self.r1_clocs, &self.r1_cginfo, s1, &mut sp.s1);
-        if parsed_r1 {
-            parse_single_read(&self.r2_clocs, &self.r2_cginfo, s2, &mut sp.s2)
+    /// Appends the pieces captured from the read names by `name_capture` to
+    /// the pieces captured from its read, so that they are part of the
+    /// transformed fragments parsed with
+    /// [`FragmentRegexDesc::parse_name_into`] and of the simplified geometry.
+    /// Fails if pieces are already captured from the read names, or if the
+    /// read ends with an unbounded piece, after which the captured pieces
+    /// would have no fixed location.
+    pub fn set_name_capture(&mut self, name_capture: NameCapture) -> Result<()> {
+        if self.name_capture.is_some() {
+            bail!("pieces are already captured from the read names");
+        }
+        let read = name_capture.read();
+        let cginfo = if read == 1 {
+            &mut self.r1_cginfo
         } else {
-            false
+            &mut self.r2_cginfo
+        };
+        if cginfo.last().is_some_and(|gp| {
+            matches!(
+                gp,
+                GeomPiece::Barcode(GeomLen::Unbounded)
+                    | GeomPiece::Umi(GeomLen::Unbounded)
+                    | GeomPiece::ReadSeq(GeomLen::Unbounded)
+            )
+        }) {
+            bail!(
+                "the pieces captured from the read names cannot be added to read {}, which ends \
+                 with an unbounded piece",
+                read
+            );
+        }
+        cginfo.extend_from_slice(name_capture.pieces());
+        self.name_capture = Some(name_capture);
+        Ok(())
+    }
+
+    /// Like [`FragmentRegexDesc::diagnose_failure`], for the index reads
+    /// `reads` of a fragment whose reads 1 and 2 were parsed.  Returns
+    /// `None` if all of the index reads match.
+    pub fn diagnose_index_failure(&mut self, reads: [&[u8]; 2]) -> Option<ParseFailure> {
+        for ir in self.index_reads.iter_mut() {
+            let r = if ir.read == index::INDEX_READS[0] {
+                reads[0]
+            } else {
+                reads[1]
+            };
+            if let Some(cause) = ir.diagnose(r, self.padding) {
+                return Some(ParseFailure {
+                    read: ir.read,
+                    cause,
+                });
+            }
+        }
+        None
+    }
+
+    /// Returns true if a read that does not match the geometry is reverse
+    /// complemented and matched again before the parse fails.  This is
+    /// always the case for an unanchored geometry (see
+    /// [`FragmentGeomDescExt::as_unanchored_regex`]), unless disabled with
+    /// [`FragmentRegexDesc::set_try_revcomp`].
+    pub fn try_revcomp(&self) -> bool {
+        self.revcomp
+    }
+
+    /// Sets whether a read that does not match the geometry is reverse
+    /// complemented and matched again before the parse fails, for protocols
+    /// producing reads in mixed orientations.
+    pub fn set_try_revcomp(&mut self, try_revcomp: bool) {
+        self.revcomp = try_revcomp;
+        if let Some(rescue) = self.rescue.as_mut() {
+            rescue.revcomp = try_revcomp;
+        }
+    }
+
+    /// Whether read 1 and read 2 of the last successfully parsed pair were
+    /// matched as their reverse complement (which is only ever the case if
+    /// [`FragmentRegexDesc::try_revcomp`] is true).
+    pub fn last_reversed(&self) -> [bool; 2] {
+        self.reversed
+    }
+
+    /// Returns true if pairs that do not match the geometry exactly are
+    /// parsed again allowing mismatches in its fixed anchors (see
+    /// [`FragmentGeomDescExt::as_regex_with_anchor_mismatches`]).
+    pub fn tolerates_anchor_mismatches(&self) -> bool {
+        self.rescue.is_some()
+    }
+
+    /// Whether the last successfully parsed pair only matched the geometry
+    /// with mismatches in its fixed anchors.
+    pub fn last_rescued(&self) -> bool {
+        self.rescued
+    }
+
+    /// Whether read 1 and read 2 are parsed by slicing their pieces, which
+    /// all lie at fixed offsets, out of the reads rather than with their
+    /// regex (see [`RegexOptions::fixed_offsets`]), as long as their reverse
+    /// complement is not tried.
+    pub fn bypasses_regex(&self) -> [bool; 2] {
+        [self.r1_offsets.is_some(), self.r2_offsets.is_some()]
+    }
+
+    /// How the variable length pieces are padded in the transformed reads.
+    pub fn padding(&self) -> PaddingScheme {
+        self.padding
+    }
+
+    /// Sets how the variable length pieces are padded in the transformed
+    /// reads (which also changes the simplified geometry).
+    pub fn set_padding(&mut self, padding: PaddingScheme) {
+        self.padding = padding;
+        if let Some(rescue) = self.rescue.as_mut() {
+            rescue.padding = padding;
+        }
+    }
+
+    /// The captured lengths of the variable length pieces of read 1 and
+    /// read 2 of the last successfully parsed pair, in the order of the
+    /// pieces.
+    pub fn last_lens(&self) -> [&[u32]; 2] {
+        [&self.lens[0], &self.lens[1]]
+    }
+
+    /// The ranges of the padding (see [`PaddingScheme`]) in the transformed
+    /// read 1 and read 2 of the last successfully parsed pair, i.e. of the
+    /// bases that are not part of the reads.
+    pub fn last_padding(&self) -> [&[Range<u32>]; 2] {
+        [&self.pads[0], &self.pads[1]]
+    }
+
+    /// The ranges of the padding in the transformed read 3 and read 4 of the
+    /// last fragment whose index reads were successfully parsed (see
+    /// [`FragmentRegexDesc::last_padding`]).
+    pub(crate) fn last_index_padding(&self) -> [&[Range<u32>]; 2] {
+        let mut pads: [&[Range<u32>]; 2] = [&[], &[]];
+        for ir in &self.index_reads {
+            let i = if ir.read == index::INDEX_READS[0] {
+                0
+            } else {
+                1
+            };
+            pads[i] = ir.last_padding();
         }
+        pads
+    }
+
+    /// Returns true if no part of read 1 is retained in the transformed
+    /// output (i.e. the geometry of read 1 captures no pieces).
+    pub fn discards_read1(&self) -> bool {
+        self.r1_cginfo.is_empty()
+    }
+
+    /// Returns true if no part of read 2 is retained in the transformed
+    /// output (i.e. the geometry of read 2 captures no pieces).
+    pub fn discards_read2(&self) -> bool {
+        self.r2_cginfo.is_empty()
     }
 
     pub fn get_simplified_geo_desc(&self) -> FragmentGeomDesc {
@@ -220,12 +1005,12 @@ impl FragmentRegexDesc {
             read1_desc: self
                 .r1_cginfo
                 .iter()
-                .map(get_simplified_geo)
+                .map(|gp| get_simplified_geo(gp, self.padding))
                 .collect::<Vec<GeomPiece>>(),
             read2_desc: self
                 .r2_cginfo
                 .iter()
-                .map(get_simplified_geo)
+                .map(|gp| get_simplified_geo(gp, self.padding))
                 .collect::<Vec<GeomPiece>>(),
         }
     }
@@ -233,15 +1018,64 @@ impl FragmentRegexDesc {
     pub fn get_simplified_description_string(&self) -> String {
         let mut rep = String::from("");
         if !self.r1_cginfo.is_empty() {
-            let d = get_simplified_piscem_string(&self.r1_cginfo);
+            let d = get_simplified_piscem_string(&self.r1_cginfo, self.padding);
             rep += &format!("1{{{}}}", d);
         }
         if !self.r2_cginfo.is_empty() {
-            let d = get_simplified_piscem_string(&self.r2_cginfo);
+            let d = get_simplified_piscem_string(&self.r2_cginfo, self.padding);
             rep += &format!("2{{{}}}", d);
         }
+        for ir in &self.index_reads {
+            if !ir.cginfo.is_empty() {
+                let d = get_simplified_piscem_string(&ir.cginfo, self.padding);
+                rep += &format!("{}{{{}}}", ir.read, d);
+            }
+        }
         rep
     }
+
+    /// Returns the length of each read of the transformed fragments (read 1,
+    /// read 2, then any index read), along with its number, or `None` for a
+    /// read holding an unbounded piece, whose length depends on the input.  A
+    /// read whose geometry captures no pieces has a length of 0.
+    pub fn output_read_lens(&self) -> Vec<(usize, Option<u32>)> {
+        let reads = [(1, &self.r1_cginfo), (2, &self.r2_cginfo)]
+            .into_iter()
+            .chain(self.index_reads.iter().map(|ir| (ir.read, &ir.cginfo)));
+        reads
+            .map(|(read, pieces)| {
+                let len = pieces
+                    .iter()
+                    .map(|gp| match get_simplified_geo(gp, self.padding) {
+                        GeomPiece::Discard(GeomLen::FixedLen(x))
+                        | GeomPiece::Barcode(GeomLen::FixedLen(x))
+                        | GeomPiece::Umi(GeomLen::FixedLen(x))
+                        | GeomPiece::ReadSeq(GeomLen::FixedLen(x)) => Some(x),
+                        _ => None,
+                    })
+                    .sum();
+                (read, len)
+            })
+            .collect()
+    }
+
+    /// Returns the metrics of the geometry (the number of captured pieces,
+    /// of anchors, and the range of lengths consumed by each read, ...), from
+    /// which to choose how to run a transformation (see the [`complexity`]
+    /// module).
+    pub fn metrics(&self) -> GeometryMetrics {
+        GeometryMetrics {
+            reads: self
+                .read_metrics
+                .iter()
+                .cloned()
+                .chain(self.index_reads.iter().map(|ir| ir.metrics.clone()))
+                .collect(),
+            bypasses_regex: self.bypasses_regex(),
+            tries_revcomp: self.revcomp,
+            tolerates_anchor_mismatches: self.tolerates_anchor_mismatches(),
+        }
+    }
 }
 
 /// Extension methods for FragmentGeomDesc
@@ -251,135 +1085,483 @@ pub trait FragmentGeomDescExt {
     /// `Ok(FragmentRegexDesc)` if the `FragmentRegexDesc` could be
     /// succesfully created and an `Err(anyhow::Error)` otherwise.
     fn as_regex(&self) -> Result<FragmentRegexDesc, anyhow::Error>;
+
+    /// Like [`FragmentGeomDescExt::as_regex`], but rather than matching the
+    /// whole of each read, the pieces of the geometry are searched for
+    /// anywhere within the read, first on the forward strand and then on the
+    /// reverse strand, and the captured pieces are extracted relative to
+    /// where they are found.  This is meant for long reads (e.g. Nanopore
+    /// single-cell reads), in which the barcode construct may occur at
+    /// variable positions and in either orientation; the geometry should
+    /// then start with a fixed (`f`) piece anchoring the construct.
+    fn as_unanchored_regex(&self) -> Result<FragmentRegexDesc, anyhow::Error>;
+
+    /// Like [`FragmentGeomDescExt::as_regex`], but a pair that does not match
+    /// the geometry is parsed again allowing up to `max_mismatches`
+    /// mismatches (substitutions) in each fixed (`f`) piece, so that reads
+    /// with sequencing errors in their anchors are not lost (see
+    /// [`FragmentRegexDesc::last_rescued`]).  At most
+    /// [`MAX_ANCHOR_MISMATCHES`] mismatches may be allowed, and each fixed
+    /// piece must be longer than `max_mismatches`.
+    fn as_regex_with_anchor_mismatches(
+        &self,
+        max_mismatches: u32,
+    ) -> Result<FragmentRegexDesc, anyhow::Error>;
+
+    /// Return the `FragmentRegexDesc` built in accordance with `opts`,
+    /// which generalizes the other conversions (e.g. to extend the alphabet
+    /// of the reads, see [`RegexOptions::alphabet`]).
+    fn as_regex_with_options(
+        &self,
+        opts: &RegexOptions,
+    ) -> Result<FragmentRegexDesc, anyhow::Error>;
+}
+
+/// Options for converting a `FragmentGeomDesc` into a `FragmentRegexDesc`
+/// (see [`FragmentGeomDescExt::as_regex_with_options`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexOptions {
+    /// Search for the pieces anywhere within the reads, on either strand
+    /// (see [`FragmentGeomDescExt::as_unanchored_regex`]).
+    pub unanchored: bool,
+    /// The number of mismatches allowed in each fixed piece of the pairs
+    /// that do not match exactly (see
+    /// [`FragmentGeomDescExt::as_regex_with_anchor_mismatches`]); must be 0
+    /// when `unanchored` is true.
+    pub anchor_mismatches: u32,
+    /// The characters matched by the pieces other than the fixed anchors
+    /// (and by the mismatched positions of the anchors), [`DEFAULT_ALPHABET`]
+    /// by default.  Extending it (e.g. with `U` for direct RNA reads, or
+    /// with IUPAC ambiguity codes) keeps reads holding such characters from
+    /// failing to parse.  The characters must be ASCII letters.
+    pub alphabet: String,
+    /// Parse the reads whose pieces all lie at fixed offsets (with no anchor
+    /// and no variable length piece, but possibly an unbounded last piece)
+    /// by slicing them, rather than with their regex, which is much faster
+    /// and matches the same reads.  This is only ever disabled to compare
+    /// the two (see [`FragmentRegexDesc::bypasses_regex`]).
+    pub fixed_offsets: bool,
+    /// Let an `N` in the reads match any base of the fixed anchors, so that
+    /// a no-call within an anchor does not keep the fragment from matching
+    /// (see the [`iupac`] module).
+    pub n_tolerant_anchors: bool,
+    /// The transformations (reverse complement, trimming, masking, ...) of
+    /// single captured pieces (see the [`rules`] module).
+    pub piece_rules: Vec<PieceRule>,
+}
+
+impl Default for RegexOptions {
+    fn default() -> Self {
+        Self {
+            unanchored: false,
+            anchor_mismatches: 0,
+            alphabet: String::from(DEFAULT_ALPHABET),
+            fixed_offsets: true,
+            n_tolerant_anchors: false,
+            piece_rules: vec![],
+        }
+    }
+}
+
+impl RegexOptions {
+    /// Adds the characters of `extra` that are not already in the alphabet
+    /// to it.
+    pub fn extend_alphabet(&mut self, extra: &str) {
+        for c in extra.chars() {
+            if !self.alphabet.contains(c) {
+                self.alphabet.push(c);
+            }
+        }
+    }
+
+    /// Checks that the alphabet is a non-empty set of ASCII letters, and
+    /// that mismatched anchors are not combined with unanchored matching.
+    pub fn validate(&self) -> Result<()> {
+        if self.alphabet.is_empty() || !self.alphabet.chars().all(|c| c.is_ascii_alphabetic()) {
+            bail!(
+                "invalid alphabet {:?} (expected a non-empty set of ASCII letters)",
+                self.alphabet
+            );
+        }
+        if self.unanchored && self.anchor_mismatches > 0 {
+            bail!(
+                "mismatches in the anchors cannot be allowed when the pieces are searched for \
+                 anywhere within the reads"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Ensures that the length range `[l, h]` of the piece `gp` is well-formed.
+fn check_len_range(gp: &GeomPiece, l: u32, h: u32) -> Result<()> {
+    if l > h {
+        bail!(
+            "The current element {:?} has a length range whose lower bound exceeds its upper bound.",
+            &gp
+        );
+    }
+    Ok(())
 }
 
-fn geom_piece_as_regex_string(gp: &GeomPiece) -> Result<(String, Option<GeomPiece>)> {
+/// Returns the regex matching the fixed sequence `s` with up to
+/// `max_mismatches` substitutions, as an alternation of the variants of `s`
+/// in which any `max_mismatches` of its positions match any character of the
+/// class `class`.  The other positions match the bases of their IUPAC code,
+/// along with `N` if `n_tolerant` is true (see the [`iupac`] module).
+fn fuzzy_anchor_regex_string(
+    s: &str,
+    max_mismatches: u32,
+    class: &str,
+    n_tolerant: bool,
+) -> Result<String> {
+    let k = max_mismatches as usize;
+    if k == 0 {
+        if !n_tolerant && iupac::is_literal(s.as_bytes()) {
+            return Ok(s.to_string());
+        }
+        return Ok(s
+            .chars()
+            .map(|c| iupac::anchor_char_regex(c, n_tolerant))
+            .collect());
+    }
+    if max_mismatches > MAX_ANCHOR_MISMATCHES {
+        bail!(
+            "At most {} mismatches may be allowed in the fixed pieces, but got {}",
+            MAX_ANCHOR_MISMATCHES,
+            max_mismatches
+        );
+    }
+    if k >= s.len() {
+        bail!(
+            "The fixed piece {} is too short to allow {} mismatches in it",
+            s,
+            max_mismatches
+        );
+    }
+    // the positions of the wildcards of each variant, in lexicographic order
+    let mut positions: Vec<usize> = (0..k).collect();
+    let mut variants = Vec::new();
+    loop {
+        let mut v = String::new();
+        for (i, c) in s.chars().enumerate() {
+            if positions.contains(&i) {
+                v.push_str(class);
+            } else {
+                v.push_str(&iupac::anchor_char_regex(c, n_tolerant));
+            }
+        }
+        variants.push(v);
+        // advance to the next combination of positions
+        let Some(j) = (0..k).rev().find(|&j| positions[j] < s.len() - k + j) else {
+            break;
+        };
+        positions[j] += 1;
+        for l in j + 1..k {
+            positions[l] = positions[l - 1] + 1;
+        }
+    }
+    Ok(format!("(?:{})", variants.join("|")))
+}
+
+/// Returns the regex matching the piece `gp`, whose non-fixed pieces match
+/// the characters of the class `class` (e.g. `[ACGTN]`) and whose fixed
+/// pieces match with up to `anchor_mismatches` substitutions (and with `N`
+/// if `n_tolerant` is true), along with the piece if it is captured.
+fn geom_piece_as_regex_string(
+    gp: &GeomPiece,
+    anchor_mismatches: u32,
+    class: &str,
+    n_tolerant: bool,
+) -> Result<(String, Option<GeomPiece>)> {
     let mut rep = String::from("");
     let mut geo = None;
     match gp {
         // single lengths
         GeomPiece::Discard(GeomLen::FixedLen(x)) => {
-            rep.push_str(&format!(r#"[ACGTN]{{{}}}"#, x));
+            rep.push_str(&format!(r#"{}{{{}}}"#, class, x));
             // don't need to capture
         }
         GeomPiece::Barcode(GeomLen::FixedLen(x))
         | GeomPiece::Umi(GeomLen::FixedLen(x))
         | GeomPiece::ReadSeq(GeomLen::FixedLen(x)) => {
-            rep.push_str(&format!(r#"([ACGTN]{{{}}})"#, x));
+            rep.push_str(&format!(r#"({}{{{}}})"#, class, x));
             geo = Some(gp.clone());
         }
         // length ranges
         GeomPiece::Discard(GeomLen::LenRange(l, h)) => {
-            if h - l > BOUNDED_RANGE_LIMIT {
-                bail!("Bounded range can have variable width at most {} but the current element {:?} has variable width {}.",
-                    BOUNDED_RANGE_LIMIT, &gp, h-l);
-            }
-            rep.push_str(&format!(r#"[ACGTN]{{{},{}}}"#, l, h));
+            check_len_range(gp, *l, *h)?;
+            rep.push_str(&format!(r#"{}{{{},{}}}"#, class, l, h));
             // don't need to capture
         }
         GeomPiece::Barcode(GeomLen::LenRange(l, h))
         | GeomPiece::Umi(GeomLen::LenRange(l, h))
         | GeomPiece::ReadSeq(GeomLen::LenRange(l, h)) => {
-            if h - l > BOUNDED_RANGE_LIMIT {
-                bail!("Bounded range can have variable width at most {} but the current element {:?} has variable width {}.",
-                    BOUNDED_RANGE_LIMIT, &gp, h-l);
-            }
-            rep.push_str(&format!(r#"([ACGTN]{{{},{}}})"#, l, h));
+            check_len_range(gp, *l, *h)?;
+            rep.push_str(&format!(r#"({}{{{},{}}})"#, class, l, h));
             geo = Some(gp.clone());
         }
         // fixed sequence
         GeomPiece::Fixed(NucStr::Seq(s)) => {
             // no caputre group because no need to capture this
             // right now
-            rep.push_str(s);
+            rep.push_str(&fuzzy_anchor_regex_string(
+                s,
+                anchor_mismatches,
+                class,
+                n_tolerant,
+            )?);
         }
         // unbounded pieces
         GeomPiece::Discard(GeomLen::Unbounded) => {
-            rep += r#"[ACGTN]*"#;
+            rep += &format!("{}*", class);
         }
         GeomPiece::Barcode(GeomLen::Unbounded)
         | GeomPiece::Umi(GeomLen::Unbounded)
         | GeomPiece::ReadSeq(GeomLen::Unbounded) => {
-            rep += r#"([ACGTN]*)"#;
+            rep += &format!("({}*)", class);
             geo = Some(gp.clone());
         }
     }
     Ok((rep, geo))
 }
 
-impl FragmentGeomDescExt for FragmentGeomDesc {
-    /// Return a `FragmentRegexDesc` corresponding to the current
-    /// `FragmentGeomDesc`.  This function returns a `Result` that is
-    /// `Ok(FragmentRegexDesc)` if the `FragmentRegexDesc` could be
-    /// succesfully created and an `Err(anyhow::Error)` otherwise.
-    fn as_regex(&self) -> Result<FragmentRegexDesc, anyhow::Error> {
-        let mut r1_re_str = String::from("^");
-        let mut r1_cginfo = Vec::<GeomPiece>::new();
-        for geo_piece in &self.read1_desc {
-            let (str_piece, geo_len) = geom_piece_as_regex_string(geo_piece)?;
-            r1_re_str.push_str(&str_piece);
-            if let Some(elem) = geo_len {
-                r1_cginfo.push(elem);
-            }
+/// Builds the regular expression matching a read with the geometry pieces
+/// `pieces`, returning it along with the pieces it captures.  Unless
+/// `unanchored` is true, the regex must match the whole read; otherwise, the
+/// pieces may be found anywhere within the read.  The fixed pieces match with
+/// up to `anchor_mismatches` substitutions (and with `N` if `n_tolerant` is
+/// true), and the other pieces match the characters of `alphabet`.
+fn read_regex(
+    pieces: &[GeomPiece],
+    unanchored: bool,
+    anchor_mismatches: u32,
+    n_tolerant: bool,
+    alphabet: &str,
+) -> Result<(Regex, Vec<GeomPiece>)> {
+    let class = format!("[{}]", alphabet);
+    let mut re_str = String::from(if unanchored { "" } else { "^" });
+    let mut cginfo = Vec::<GeomPiece>::new();
+    for geo_piece in pieces {
+        let (str_piece, geo_len) =
+            geom_piece_as_regex_string(geo_piece, anchor_mismatches, &class, n_tolerant)?;
+        re_str.push_str(&str_piece);
+        if let Some(elem) = geo_len {
+            cginfo.push(elem);
         }
+    }
 
+    if !unanchored {
         // This seems to lead to a slight performance improvement, but consider if
         // we really want to do this.  This checks if the last GeomPiece in the
         // current description is of fixed length or not.  If so (i.e. if it is a fixed
         // length piece), then we add an unbounded `Discard` GeomPiece to the end followed by the
         // end of string anchor.  This anchoring of the regex (seemingly) makes matching a
         // little bit faster.
-        if let Some(geo_piece) = &self.read1_desc.last() {
+        if let Some(geo_piece) = pieces.last() {
             if geo_piece.is_fixed_len() {
-                let (str_piece, _geo_len) =
-                    geom_piece_as_regex_string(&GeomPiece::Discard(GeomLen::Unbounded))?;
-                r1_re_str.push_str(&str_piece);
+                let (str_piece, _geo_len) = geom_piece_as_regex_string(
+                    &GeomPiece::Discard(GeomLen::Unbounded),
+                    0,
+                    &class,
+                    false,
+                )?;
+                re_str.push_str(&str_piece);
             }
         }
-        r1_re_str.push('$');
+        re_str.push('$');
+    }
 
-        let mut r2_re_str = String::from("^");
-        let mut r2_cginfo = Vec::<GeomPiece>::new();
-        for geo_piece in &self.read2_desc {
-            let (str_piece, geo_len) = geom_piece_as_regex_string(geo_piece)?;
-            r2_re_str.push_str(&str_piece);
-            if let Some(elem) = geo_len {
-                r2_cginfo.push(elem);
-            }
+    let re = Regex::new(&re_str)
+        .with_context(|| format!("Could not compile {} into regex description", re_str))?;
+    Ok((re, cginfo))
+}
+
+/// The error returned when the regex built for the geometry of a read does
+/// not have exactly one capture group for each of the pieces captured from
+/// the read, in which case the captured sequences could not be attributed to
+/// the pieces of the geometry.
+#[derive(Debug, Clone)]
+pub struct CaptureGroupMismatch {
+    /// The read (`1` to `4`) whose regex is inconsistent.
+    pub read: usize,
+    /// The regex built for the read.
+    pub regex: String,
+    /// The number of (explicit) capture groups of the regex.
+    pub capture_groups: usize,
+    /// The number of pieces captured from the read.
+    pub captured_pieces: usize,
+}
+
+impl fmt::Display for CaptureGroupMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the regex {} of read {} has {} capture groups, but {} pieces are captured from the read",
+            self.regex, self.read, self.capture_groups, self.captured_pieces
+        )
+    }
+}
+
+impl std::error::Error for CaptureGroupMismatch {}
+
+/// Checks that the regex `re` of read `read` has one capture group for
+/// each of the captured pieces `cginfo`.
+fn check_capture_groups(read: usize, re: &Regex, cginfo: &[GeomPiece]) -> Result<()> {
+    // the first group is always the match of the whole read
+    let capture_groups = re.captures_len() - 1;
+    if capture_groups != cginfo.len() {
+        return Err(CaptureGroupMismatch {
+            read,
+            regex: re.as_str().to_string(),
+            capture_groups,
+            captured_pieces: cginfo.len(),
         }
+        .into());
+    }
+    Ok(())
+}
 
-        // This seems to lead to a slight performance improvement, but consider if
-        // we really want to do this.  This checks if the last GeomPiece in the
-        // current description is of fixed length or not.  If so (i.e. if it is a fixed
-        // length piece), then we add an unbounded `Discard` GeomPiece to the end followed by the
-        // end of string anchor.  This anchoring of the regex (seemingly) makes matching a
-        // little bit faster.
-        if let Some(geo_piece) = &self.read2_desc.last() {
-            if geo_piece.is_fixed_len() {
-                let (str_piece, _geo_len) =
-                    geom_piece_as_regex_string(&GeomPiece::Discard(GeomLen::Unbounded))?;
-                r2_re_str.push_str(&str_piece);
-            }
+/// Builds the `FragmentRegexDesc` of `desc`, searching for the pieces of each
+/// read anywhere within it (and on either strand) if `unanchored` is true.
+/// The fixed pieces match with up to `anchor_mismatches` substitutions (and
+/// with `N` if `n_tolerant` is true), and the other pieces match the
+/// characters of `alphabet`.  Unless `fixed_offsets` is false, the reads
+/// whose pieces all lie at fixed offsets are parsed without their regex.
+fn build_regex_desc(
+    desc: &FragmentGeomDesc,
+    unanchored: bool,
+    anchor_mismatches: u32,
+    n_tolerant: bool,
+    alphabet: &str,
+    fixed_offsets: bool,
+    xforms: &[Vec<PieceXform>; 2],
+) -> Result<FragmentRegexDesc> {
+    let regex_of = |pieces| read_regex(pieces, unanchored, anchor_mismatches, n_tolerant, alphabet);
+    let (r1_re, r1_cginfo) = regex_of(&desc.read1_desc)?;
+    let (r2_re, r2_cginfo) = regex_of(&desc.read2_desc)?;
+    check_capture_groups(1, &r1_re, &r1_cginfo)?;
+    check_capture_groups(2, &r2_re, &r2_cginfo)?;
+
+    let cloc1 = r1_re.capture_locations();
+    let cloc2 = r2_re.capture_locations();
+
+    let prefilter = |pieces: &[GeomPiece]| {
+        let pf = if unanchored {
+            ReadPrefilter::unanchored(pieces)
+        } else if anchor_mismatches > 0 || n_tolerant {
+            ReadPrefilter::without_anchors(pieces)
+        } else {
+            ReadPrefilter::new(pieces)
+        };
+        // the vectorized alphabet check only knows the default alphabet
+        if alphabet == DEFAULT_ALPHABET {
+            pf
+        } else {
+            pf.without_alphabet_check()
         }
-        r2_re_str.push('$');
+    };
+    // the prefilter only checks the default alphabet, and the pieces of
+    // unanchored regexes lie anywhere within the reads
+    let bypass = fixed_offsets && !unanchored && alphabet == DEFAULT_ALPHABET;
+    // the sliced pieces are not transformed
+    let offsets = |pieces: &[GeomPiece], xforms: &[PieceXform]| {
+        FixedOffsets::new(pieces).filter(|_| bypass && xforms.iter().all(|x| x.is_identity()))
+    };
+    Ok(FragmentRegexDesc {
+        r1_offsets: offsets(&desc.read1_desc, &xforms[0]),
+        r2_offsets: offsets(&desc.read2_desc, &xforms[1]),
+        xforms: xforms.clone(),
+        r1_cginfo,
+        r2_cginfo,
+        r1_re,
+        r2_re,
+        r1_clocs: cloc1,
+        r2_clocs: cloc2,
+        r1_prefilter: prefilter(&desc.read1_desc),
+        r2_prefilter: prefilter(&desc.read2_desc),
+        revcomp: unanchored,
+        rc_buf: Vec::new(),
+        reversed: [false; 2],
+        rescue: None,
+        rescued: false,
+        padding: PaddingScheme::default(),
+        lens: [Vec::new(), Vec::new()],
+        pads: [Vec::new(), Vec::new()],
+        index_reads: vec![],
+        name_capture: None,
+        read_metrics: [
+            ReadMetrics::new(1, &desc.read1_desc),
+            ReadMetrics::new(2, &desc.read2_desc),
+        ],
+    })
+}
 
-        let r1_re = Regex::new(&r1_re_str)
-            .with_context(|| format!("Could not compile {} into regex description", r1_re_str))?;
-        let r2_re = Regex::new(&r2_re_str)
-            .with_context(|| format!("Could not compile {} into regex description", r2_re_str))?;
+impl FragmentGeomDescExt for FragmentGeomDesc {
+    /// Return a `FragmentRegexDesc` corresponding to the current
+    /// `FragmentGeomDesc`.  This function returns a `Result` that is
+    /// `Ok(FragmentRegexDesc)` if the `FragmentRegexDesc` could be
+    /// succesfully created and an `Err(anyhow::Error)` otherwise.
+    fn as_regex(&self) -> Result<FragmentRegexDesc, anyhow::Error> {
+        self.as_regex_with_options(&RegexOptions::default())
+    }
 
-        let cloc1 = r1_re.capture_locations();
-        let cloc2 = r2_re.capture_locations();
+    fn as_unanchored_regex(&self) -> Result<FragmentRegexDesc, anyhow::Error> {
+        self.as_regex_with_options(&RegexOptions {
+            unanchored: true,
+            ..RegexOptions::default()
+        })
+    }
 
-        Ok(FragmentRegexDesc {
-            r1_cginfo,
-            r2_cginfo,
-            r1_re,
-            r2_re,
-            r1_clocs: cloc1,
-            r2_clocs: cloc2,
+    fn as_regex_with_anchor_mismatches(
+        &self,
+        max_mismatches: u32,
+    ) -> Result<FragmentRegexDesc, anyhow::Error> {
+        self.as_regex_with_options(&RegexOptions {
+            anchor_mismatches: max_mismatches,
+            ..RegexOptions::default()
         })
     }
+
+    fn as_regex_with_options(
+        &self,
+        opts: &RegexOptions,
+    ) -> Result<FragmentRegexDesc, anyhow::Error> {
+        opts.validate()?;
+        let alphabet = opts.alphabet.as_str();
+        // the trimmed bases of the pieces are discarded
+        let (desc, xforms) = rules::apply_rules(self, &opts.piece_rules)?;
+        if opts.unanchored {
+            let n_tolerant = opts.n_tolerant_anchors;
+            return build_regex_desc(&desc, true, 0, n_tolerant, alphabet, false, &xforms);
+        }
+        let mut geo_re = build_regex_desc(
+            &desc,
+            false,
+            0,
+            opts.n_tolerant_anchors,
+            alphabet,
+            opts.fixed_offsets,
+            &xforms,
+        )?;
+        let has_anchor = self
+            .read1_desc
+            .iter()
+            .chain(self.read2_desc.iter())
+            .any(|gp| matches!(gp, GeomPiece::Fixed(_)));
+        if opts.anchor_mismatches > 0 && has_anchor {
+            geo_re.rescue = Some(Box::new(build_regex_desc(
+                &desc,
+                false,
+                opts.anchor_mismatches,
+                opts.n_tolerant_anchors,
+                alphabet,
+                opts.fixed_offsets,
+                &xforms,
+            )?));
+        }
+        Ok(geo_re)
+    }
 }
 
 /// The information we get back from an xform function
@@ -389,24 +1571,515 @@ impl FragmentGeomDescExt for FragmentGeomDesc {
 pub struct FifoXFormData {
     pub r1_fifo: PathBuf,
     pub r2_fifo: PathBuf,
+    /// The compression of the bytes written into the read 1 and read 2
+    /// fifos, whose names end with the matching extension (e.g.
+    /// `r1.pipe.gz`) when they are compressed.
+    pub compression: [Compression; 2],
     pub join_handle: thread::JoinHandle<Result<XformStats>>,
+    /// The live statistics of the transformation, which can be polled
+    /// while the thread runs.
+    pub stats: XformStatsHandle,
 }
 
-/// This struct holds some basic statistics about
-/// the transformation of a stream of reads.
+/// The fifo into which [`xform_read_pairs_to_interleaved_fifo`] writes the
+/// read 1 and read 2 records of each fragment one after the other.
 #[derive(Debug)]
-pub struct XformStats {
-    pub total_fragments: u64,
-    pub failed_parsing: u64,
+pub struct InterleavedFifoXFormData {
+    pub fifo: PathBuf,
+    /// The compression of the bytes written into the fifo, whose name ends
+    /// with the matching extension (e.g. `interleaved.pipe.gz`) when they
+    /// are compressed.
+    pub compression: Compression,
+    pub join_handle: thread::JoinHandle<Result<XformStats>>,
+    /// The live statistics of the transformation, which can be polled
+    /// while the thread runs.
+    pub stats: XformStatsHandle,
 }
 
-impl XformStats {
-    /// Create a new (empty) XformStats
-    pub fn new() -> Self {
-        Self {
-            total_fragments: 0u64,
-            failed_parsing: 0u64,
+/// The error returned through [`FifoXFormData::join_handle`] when the thread
+/// transforming the reads panicked, rather than the panic being propagated
+/// to the thread joining it.
+#[derive(Debug, Clone)]
+pub struct WorkerPanic {
+    /// The message of the panic (if it was a string).
+    pub message: String,
+}
+
+impl WorkerPanic {
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(s) => *s,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(s) => s.to_string(),
+                Err(_) => String::from("(non-string panic payload)"),
+            },
+        };
+        Self { message }
+    }
+}
+
+impl fmt::Display for WorkerPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the read transformation thread panicked: {}",
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for WorkerPanic {}
+
+/// This struct holds some basic statistics about
+/// the transformation of a stream of reads.
+#[derive(Debug)]
+pub struct XformStats {
+    pub total_fragments: u64,
+    pub failed_parsing: u64,
+    /// The number of transformed fragments matching each feature, if
+    /// features were matched (see [`XformOptions::features`]).
+    pub feature_counts: Option<FeatureCounts>,
+    /// The number of transformed fragments matching a probe exactly, with a
+    /// mismatch, or matching none, if probes were matched (see
+    /// [`XformOptions::probes`]).
+    pub probes: Option<ProbeCounts>,
+    /// The number of transformed fragments matching each control sequence,
+    /// if controls were given (see [`XformOptions::controls`]).
+    pub control_counts: Option<ControlCounts>,
+    /// The statistics of each pair of input files, in the order in
+    /// which they were processed.
+    pub input_pairs: Vec<InputPairStats>,
+    /// The problems found in the pairing of the read 1 and read 2 records
+    /// of all of the input files (see the [`pairing`] module).
+    pub pairing: PairingCounts,
+    /// The number of transformed fragments that were duplicates of an earlier
+    /// fragment, if duplicates were detected (see [`XformOptions::dedup`]).
+    pub duplicate_fragments: Option<u64>,
+    /// The number of transformed fragments whose UMI was corrected, if UMIs
+    /// were corrected (see [`XformOptions::umi_correction`]).
+    pub corrected_umis: Option<u64>,
+    /// The number of written fragments observed with each barcode, if
+    /// barcodes were counted (see [`XformOptions::count_barcodes`]).
+    pub barcode_counts: Option<BarcodeCounts>,
+    /// The number of times a read or write failing with a transient error
+    /// was retried, if I/O operations were retried (see
+    /// [`XformOptions::retry`]).
+    pub io_retries: Option<u64>,
+    /// The number of transformed fragments by the orientation in which
+    /// their reads matched the geometry, if reverse complemented reads were
+    /// tried (see [`FragmentRegexDesc::set_try_revcomp`]).
+    pub orientations: Option<OrientationCounts>,
+    /// The number of transformed fragments that only matched the geometry
+    /// with mismatches in its fixed anchors, if such mismatches were allowed
+    /// (see [`FragmentGeomDescExt::as_regex_with_anchor_mismatches`]).
+    pub rescued_fragments: Option<u64>,
+    /// The number of fragments dropped because one of their records was
+    /// malformed, if malformed records were skipped (see
+    /// [`XformOptions::on_error`]).  They are not counted among the
+    /// `total_fragments`.
+    pub malformed_fragments: Option<u64>,
+    /// How the variable length pieces of the transformed fragments were
+    /// padded (see [`FragmentRegexDesc::set_padding`]).
+    pub padding: PaddingScheme,
+    /// The number of fragments failing to parse for each read and cause, if
+    /// the failures were diagnosed (see [`XformOptions::diagnose_failures`]).
+    pub failures: Option<FailureCounts>,
+    /// The number of transformed fragments in which each variable length
+    /// piece was captured with each length (and so received the padding
+    /// for that length).
+    pub padding_usage: Vec<PieceLengths>,
+    /// The number of transformed fragments whose barcode was found in, was
+    /// corrected to, or could not be corrected to the whitelist, if barcodes
+    /// were corrected (see [`XformOptions::whitelist`]).
+    pub whitelist: Option<WhitelistCounts>,
+    /// The number of transformed fragments whose read 1 and read 2 barcodes
+    /// agree or differ, if they were cross-checked (see
+    /// [`XformOptions::barcode_consistency`]).
+    pub barcode_consistency: Option<ConsistencyCounts>,
+    /// The number of transformed fragments in which the guide was found on
+    /// each strand, or not found, if guides were extracted (see
+    /// [`XformOptions::guides`]).
+    pub guides: Option<GuideCounts>,
+    /// The number of transformed fragments whose barcode was, or was not,
+    /// found in the coordinate table, if barcodes were translated into
+    /// coordinates (see [`XformOptions::spatial`]).
+    pub spatial: Option<SpatialCounts>,
+    /// The number of transformed fragments assigned to each sample, or to
+    /// none, if the fragments were demultiplexed (see [`XformOptions::demux`]).
+    pub demux: Option<DemuxCounts>,
+    /// The seed of the run (see [`XformOptions::seed`]).
+    pub seed: u64,
+}
+
+/// The number of transformed fragments by the orientation in which their
+/// read 1 and read 2 matched the geometry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrientationCounts {
+    /// Fragments whose reads both matched as they are.
+    pub forward: u64,
+    /// Fragments whose read 1 (only) matched as its reverse complement.
+    pub read1_reversed: u64,
+    /// Fragments whose read 2 (only) matched as its reverse complement.
+    pub read2_reversed: u64,
+    /// Fragments whose reads both matched as their reverse complement.
+    pub both_reversed: u64,
+}
+
+impl OrientationCounts {
+    /// Records a fragment whose reads matched in the orientations `reversed`
+    /// (see [`FragmentRegexDesc::last_reversed`]).
+    pub fn record(&mut self, reversed: [bool; 2]) {
+        match reversed {
+            [false, false] => self.forward += 1,
+            [true, false] => self.read1_reversed += 1,
+            [false, true] => self.read2_reversed += 1,
+            [true, true] => self.both_reversed += 1,
+        }
+    }
+
+    /// The number of fragments with at least one reverse complemented read.
+    pub fn reversed(&self) -> u64 {
+        self.read1_reversed + self.read2_reversed + self.both_reversed
+    }
+}
+
+/// The statistics of the transformation of a single pair of input files.
+#[derive(Debug, Clone)]
+pub struct InputPairStats {
+    pub r1: PathBuf,
+    pub r2: PathBuf,
+    /// The index of the geometry with which this pair was parsed.
+    pub geometry: usize,
+    pub total_fragments: u64,
+    pub failed_parsing: u64,
+    /// The problems found in the pairing of the read 1 and read 2 records.
+    pub pairing: PairingCounts,
+}
+
+impl InputPairStats {
+    /// The fraction of the fragments of this pair that failed to parse (0 if
+    /// no fragments were read), e.g. to spot a single bad lane.
+    pub fn fail_rate(&self) -> f64 {
+        if self.total_fragments > 0 {
+            self.failed_parsing as f64 / self.total_fragments as f64
+        } else {
+            0_f64
+        }
+    }
+}
+
+impl XformStats {
+    /// Create a new (empty) XformStats
+    pub fn new() -> Self {
+        Self {
+            total_fragments: 0u64,
+            failed_parsing: 0u64,
+            feature_counts: None,
+            probes: None,
+            control_counts: None,
+            input_pairs: vec![],
+            pairing: PairingCounts::default(),
+            duplicate_fragments: None,
+            corrected_umis: None,
+            barcode_counts: None,
+            io_retries: None,
+            orientations: None,
+            rescued_fragments: None,
+            malformed_fragments: None,
+            padding: PaddingScheme::default(),
+            failures: None,
+            padding_usage: vec![],
+            whitelist: None,
+            barcode_consistency: None,
+            guides: None,
+            spatial: None,
+            demux: None,
+            seed: 0,
+        }
+    }
+
+    /// The number of fragments that were successfully parsed and
+    /// transformed.
+    pub fn transformed_fragments(&self) -> u64 {
+        self.total_fragments - self.failed_parsing
+    }
+
+    /// The fraction of fragments that were succesfully parsed
+    /// and transformed (0 if no fragments were observed).
+    pub fn pass_rate(&self) -> f64 {
+        if self.total_fragments > 0 {
+            self.transformed_fragments() as f64 / self.total_fragments as f64
+        } else {
+            0_f64
+        }
+    }
+
+    /// The fraction of fragments that failed to parse (0 if no fragments
+    /// were observed).  Unless no fragments were observed, this is
+    /// `1 - pass_rate()`.
+    pub fn fail_rate(&self) -> f64 {
+        if self.total_fragments > 0 {
+            self.failed_parsing as f64 / self.total_fragments as f64
+        } else {
+            0_f64
+        }
+    }
+
+    /// The same as [`XformStats::pass_rate`].
+    pub fn match_rate(&self) -> f64 {
+        self.pass_rate()
+    }
+
+    /// The fraction of the transformed fragments that were duplicates of an
+    /// earlier fragment, if duplicates were detected.
+    pub fn duplicate_rate(&self) -> Option<f64> {
+        let transformed = self.total_fragments - self.failed_parsing;
+        self.duplicate_fragments.map(|d| {
+            if transformed > 0 {
+                d as f64 / transformed as f64
+            } else {
+                0_f64
+            }
+        })
+    }
+
+    /// The fraction of the transformed fragments whose UMI was corrected,
+    /// if UMIs were corrected.
+    pub fn umi_correction_rate(&self) -> Option<f64> {
+        let transformed = self.total_fragments - self.failed_parsing;
+        self.corrected_umis.map(|c| {
+            if transformed > 0 {
+                c as f64 / transformed as f64
+            } else {
+                0_f64
+            }
+        })
+    }
+
+    /// The header line of the TSV written by [`XformStats::write_tsv`].
+    pub const TSV_HEADER: &'static str =
+        "geometry\tpadding\ttotal_fragments\tfailed_parsing\tmatch_rate\truntime_secs\tfragments_per_sec\tpass_rate\tfail_rate";
+
+    /// Writes the key statistics of a run that transformed fragments with the
+    /// geometry `geometry` in `runtime` as a single-row TSV to `w`, preceded
+    /// by the header line [`XformStats::TSV_HEADER`].  Because every report
+    /// has the same columns, the rows of many runs can be concatenated into
+    /// a single table.
+    pub fn write_tsv<W: io::Write>(
+        &self,
+        w: &mut W,
+        geometry: &str,
+        runtime: Duration,
+    ) -> io::Result<()> {
+        let secs = runtime.as_secs_f64();
+        let throughput = if secs > 0_f64 {
+            self.total_fragments as f64 / secs
+        } else {
+            0_f64
+        };
+        writeln!(w, "{}", Self::TSV_HEADER)?;
+        writeln!(
+            w,
+            "{}\t{}\t{}\t{}\t{:.6}\t{:.3}\t{:.1}\t{:.6}\t{:.6}",
+            geometry,
+            self.padding,
+            self.total_fragments,
+            self.failed_parsing,
+            self.match_rate(),
+            secs,
+            throughput,
+            self.pass_rate(),
+            self.fail_rate()
+        )
+    }
+
+    /// Writes the statistics of a run that transformed fragments with the
+    /// geometry `geometry` in `runtime` as a JSON object to `w`.  The counts
+    /// that were not collected are `null`, and the failures, if they were
+    /// diagnosed, are listed by read, piece (1-based, `null` if the failure
+    /// is not due to a specific piece) and cause.
+    pub fn write_json<W: io::Write>(
+        &self,
+        w: &mut W,
+        geometry: &str,
+        runtime: Duration,
+    ) -> io::Result<()> {
+        let opt = |c: Option<u64>| c.map_or_else(|| "null".to_string(), |c| c.to_string());
+        let mut out = String::from("{\n  \"geometry\": ");
+        failure::push_json_string(&mut out, geometry);
+        out += &format!(
+            ",\n  \"padding\": \"{}\",\n  \"seed\": {},\n  \"total_fragments\": {},\n  \"failed_parsing\": {},\n  \"match_rate\": {:.6},\n  \"pass_rate\": {:.6},\n  \"fail_rate\": {:.6},\n  \"runtime_secs\": {:.3}",
+            self.padding,
+            self.seed,
+            self.total_fragments,
+            self.failed_parsing,
+            self.match_rate(),
+            self.pass_rate(),
+            self.fail_rate(),
+            runtime.as_secs_f64()
+        );
+        for (name, count) in [
+            ("duplicate_fragments", self.duplicate_fragments),
+            ("corrected_umis", self.corrected_umis),
+            ("rescued_fragments", self.rescued_fragments),
+            ("malformed_fragments", self.malformed_fragments),
+            ("io_retries", self.io_retries),
+        ] {
+            out += &format!(",\n  \"{}\": {}", name, opt(count));
+        }
+        out += ",\n  \"whitelist\": ";
+        match &self.whitelist {
+            Some(wc) => {
+                out += &format!(
+                    "{{\"exact\": {}, \"corrected\": {}, \"failed\": {}}}",
+                    wc.exact, wc.corrected, wc.failed
+                )
+            }
+            None => out += "null",
+        }
+        out += ",\n  \"control_counts\": ";
+        match &self.control_counts {
+            Some(cc) => {
+                out.push('{');
+                for (i, (name, count)) in cc.names.iter().zip(&cc.counts).enumerate() {
+                    if i > 0 {
+                        out += ", ";
+                    }
+                    failure::push_json_string(&mut out, name);
+                    out += &format!(": {}", count);
+                }
+                out.push('}');
+            }
+            None => out += "null",
+        }
+        out += ",\n  \"barcode_consistency\": ";
+        match &self.barcode_consistency {
+            Some(cc) => {
+                out += &format!(
+                    "{{\"consistent\": {}, \"inconsistent\": {}}}",
+                    cc.consistent, cc.inconsistent
+                )
+            }
+            None => out += "null",
+        }
+        out += ",\n  \"guides\": ";
+        match &self.guides {
+            Some(gc) => {
+                out += &format!(
+                    "{{\"forward\": {}, \"reverse\": {}, \"not_found\": {}}}",
+                    gc.forward, gc.reverse, gc.not_found
+                )
+            }
+            None => out += "null",
+        }
+        out += ",\n  \"probes\": ";
+        match &self.probes {
+            Some(pc) => {
+                out += &format!(
+                    "{{\"exact\": {}, \"corrected\": {}, \"unmatched\": {}}}",
+                    pc.exact, pc.corrected, pc.unmatched
+                )
+            }
+            None => out += "null",
         }
+        out += ",\n  \"spatial\": ";
+        match &self.spatial {
+            Some(sc) => {
+                out += &format!(
+                    "{{\"placed\": {}, \"unplaced\": {}}}",
+                    sc.placed, sc.unplaced
+                )
+            }
+            None => out += "null",
+        }
+        out += ",\n  \"demux\": ";
+        match &self.demux {
+            Some(dc) => {
+                out += "{\"samples\": {";
+                for (i, name) in dc.names.iter().enumerate() {
+                    if i > 0 {
+                        out += ", ";
+                    }
+                    failure::push_json_string(&mut out, name);
+                    out += &format!(
+                        ": {{\"count\": {}, \"corrected\": {}}}",
+                        dc.counts[i], dc.corrected[i]
+                    );
+                }
+                out += &format!("}}, \"undetermined\": {}}}", dc.undetermined);
+            }
+            None => out += "null",
+        }
+        out += &format!(
+            ",\n  \"pairing\": {{\"mismatched_names\": {}, \"unpaired_r1\": {}, \"unpaired_r2\": {}}}",
+            self.pairing.mismatched_names, self.pairing.unpaired[0], self.pairing.unpaired[1]
+        );
+        out += ",\n  \"input_pairs\": [";
+        for (i, ps) in self.input_pairs.iter().enumerate() {
+            out += if i > 0 {
+                ",\n    {\"r1\": "
+            } else {
+                "\n    {\"r1\": "
+            };
+            failure::push_json_string(&mut out, &ps.r1.to_string_lossy());
+            out += ", \"r2\": ";
+            failure::push_json_string(&mut out, &ps.r2.to_string_lossy());
+            out += &format!(
+                ", \"geometry\": {}, \"total_fragments\": {}, \"failed_parsing\": {}, \"fail_rate\": {:.6}, \"pairing\": {{\"mismatched_names\": {}, \"unpaired_r1\": {}, \"unpaired_r2\": {}}}}}",
+                ps.geometry,
+                ps.total_fragments,
+                ps.failed_parsing,
+                ps.fail_rate(),
+                ps.pairing.mismatched_names,
+                ps.pairing.unpaired[0],
+                ps.pairing.unpaired[1]
+            );
+        }
+        out += if self.input_pairs.is_empty() {
+            "]"
+        } else {
+            "\n  ]"
+        };
+        out += ",\n  \"padding_usage\": [";
+        for (i, pl) in self.padding_usage.iter().enumerate() {
+            out += &format!(
+                "{}\n    {{\"read\": {}, \"piece\": {}, \"declared\": [{}, {}], \"lengths\": {{",
+                if i > 0 { "," } else { "" },
+                pl.read + 1,
+                pl.piece + 1,
+                pl.declared.0,
+                pl.declared.1
+            );
+            for (j, (len, c)) in pl.lengths.iter().enumerate() {
+                out += &format!("{}\"{}\": {}", if j > 0 { ", " } else { "" }, len, c);
+            }
+            out += "}}"; // closes the lengths and the piece
+        }
+        out += if self.padding_usage.is_empty() {
+            "]"
+        } else {
+            "\n  ]"
+        };
+        out += ",\n  \"failures\": ";
+        match &self.failures {
+            Some(fc) => {
+                out.push('[');
+                for (i, (f, c)) in fc.iter().enumerate() {
+                    out += &format!(
+                        "{}\n    {{\"read\": {}, \"piece\": {}, \"cause\": \"{}\", \"count\": {}}}",
+                        if i > 0 { "," } else { "" },
+                        f.read,
+                        opt(f.cause.piece().map(|p| p as u64 + 1)),
+                        f.cause.name(),
+                        c
+                    );
+                }
+                out += if fc.total() > 0 { "\n  ]" } else { "]" };
+            }
+            None => out += "null",
+        }
+        out += "\n}\n";
+        w.write_all(out.as_bytes())
     }
 }
 
@@ -417,80 +2090,1184 @@ impl Default for XformStats {
     }
 }
 
+/// Formats `count` along with its percentage of `total` (e.g. `1,250
+/// (12.50%)`), 0% if `total` is 0.
+fn with_percentage(count: u64, total: u64) -> String {
+    let pct = if total > 0 {
+        count as f64 / total as f64 * 100_f64
+    } else {
+        0_f64
+    };
+    format!("{} ({:.2}%)", count.separate_with_commas(), pct)
+}
+
 impl fmt::Display for XformStats {
-    /// Formats and returns the canonical string representation of each type of
-    /// `GeomPiece`.
+    /// Formats the statistics as one line per count that was collected, the
+    /// fractions of the fragments being computed as by
+    /// [`XformStats::pass_rate`] and [`XformStats::fail_rate`].
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            r#"XformStats {{ 
-    total fragments: {},
-    fragments failing parsing: {},
-    percentage successfully transformed fragments: {:.2},
-}}"#,
-            self.total_fragments.separate_with_commas(),
-            self.failed_parsing.separate_with_commas(),
-            if self.total_fragments > 0 {
-                1_f64 - ((self.failed_parsing as f64) / (self.total_fragments as f64))
-            } else {
-                1_f64
-            } * 100_f64
-        )
+        let total = self.total_fragments;
+        let transformed = self.transformed_fragments();
+        let mut lines = vec![
+            format!("total fragments: {}", total.separate_with_commas()),
+            format!(
+                "fragments failing parsing: {}",
+                with_percentage(self.failed_parsing, total)
+            ),
+            format!(
+                "fragments transformed: {}",
+                with_percentage(transformed, total)
+            ),
+        ];
+        // the failures of each read, by cause and piece
+        if let Some(fc) = &self.failures {
+            let mut read = None;
+            for (pf, c) in fc.iter() {
+                if read != Some(pf.read) {
+                    read = Some(pf.read);
+                    let failed = fc.iter().filter(|(p, _)| p.read == pf.read).map(|(_, c)| c);
+                    lines.push(format!(
+                        "fragments failing parsing on read {}: {}:",
+                        pf.read,
+                        with_percentage(failed.sum(), total)
+                    ));
+                }
+                let line = lines.last_mut().unwrap();
+                if !line.ends_with(':') {
+                    line.push(',');
+                }
+                line.push_str(&format!(" {} {}", pf.cause, c.separate_with_commas()));
+            }
+        }
+        if !self.pairing.is_synchronized() {
+            lines.push(format!(
+                "desynchronized input: fragments with mismatched names {}, unpaired read 1 records {}, unpaired read 2 records {}",
+                self.pairing.mismatched_names.separate_with_commas(),
+                self.pairing.unpaired[0].separate_with_commas(),
+                self.pairing.unpaired[1].separate_with_commas()
+            ));
+        }
+        if let Some(fc) = &self.feature_counts {
+            lines.push(format!(
+                "fragments matching a feature: {}",
+                with_percentage(fc.matched(), transformed)
+            ));
+        }
+        if let Some(pc) = &self.probes {
+            lines.push(format!(
+                "fragments by probe: exact {}, corrected {}, unmatched {}",
+                with_percentage(pc.exact, transformed),
+                with_percentage(pc.corrected, transformed),
+                with_percentage(pc.unmatched, transformed)
+            ));
+        }
+        if let Some(sc) = &self.spatial {
+            lines.push(format!(
+                "fragments by spatial barcode: placed {}, unplaced {}",
+                with_percentage(sc.placed, transformed),
+                with_percentage(sc.unplaced, transformed)
+            ));
+        }
+        if let Some(dc) = &self.demux {
+            lines.push(format!(
+                "fragments by sample: assigned {} to {} samples, undetermined {}",
+                with_percentage(dc.assigned(), transformed),
+                dc.counts.iter().filter(|c| **c > 0).count(),
+                with_percentage(dc.undetermined, transformed)
+            ));
+        }
+        if let Some(cc) = &self.control_counts {
+            lines.push(format!(
+                "fragments matching a control sequence: {}",
+                with_percentage(cc.matched(), transformed)
+            ));
+        }
+        if let Some(d) = self.duplicate_fragments {
+            lines.push(format!(
+                "duplicate fragments: {}",
+                with_percentage(d, transformed)
+            ));
+        }
+        if let Some(c) = self.corrected_umis {
+            lines.push(format!(
+                "fragments with a corrected UMI: {}",
+                with_percentage(c, transformed)
+            ));
+        }
+        if let Some(bc) = &self.barcode_counts {
+            lines.push(format!(
+                "distinct barcodes: {}",
+                bc.len().separate_with_commas()
+            ));
+        }
+        if let Some(wc) = &self.whitelist {
+            lines.push(format!(
+                "fragments by whitelist barcode: exact {}, corrected {}, uncorrectable {}",
+                with_percentage(wc.exact, total),
+                with_percentage(wc.corrected, total),
+                with_percentage(wc.failed, total)
+            ));
+        }
+        if let Some(cc) = &self.barcode_consistency {
+            lines.push(format!(
+                "fragments by read 1 and read 2 barcodes: consistent {}, inconsistent {}",
+                with_percentage(cc.consistent, total),
+                with_percentage(cc.inconsistent, total)
+            ));
+        }
+        if let Some(gc) = &self.guides {
+            lines.push(format!(
+                "fragments by guide strand: forward {}, reverse {}, not found {}",
+                with_percentage(gc.forward, total),
+                with_percentage(gc.reverse, total),
+                with_percentage(gc.not_found, total)
+            ));
+        }
+        if let Some(o) = &self.orientations {
+            lines.push(format!(
+                "fragments with a reverse complemented read: {} (read 1: {}, read 2: {}, both: {})",
+                with_percentage(o.reversed(), transformed),
+                o.read1_reversed.separate_with_commas(),
+                o.read2_reversed.separate_with_commas(),
+                o.both_reversed.separate_with_commas()
+            ));
+        }
+        if let Some(r) = self.rescued_fragments {
+            lines.push(format!(
+                "fragments rescued by allowing anchor mismatches: {}",
+                with_percentage(r, transformed)
+            ));
+        }
+        if let Some(m) = self.malformed_fragments {
+            lines.push(format!(
+                "fragments dropped for a malformed record: {}",
+                m.separate_with_commas()
+            ));
+        }
+        // the captured lengths of each variable length piece
+        for pl in &self.padding_usage {
+            let observed = pl.observed();
+            let mut line = format!(
+                "captured lengths of piece {} of read {} ({}-{}):",
+                pl.piece + 1,
+                pl.read + 1,
+                pl.declared.0,
+                pl.declared.1
+            );
+            for (len, c) in &pl.lengths {
+                line.push_str(&format!(" {}: {}", len, with_percentage(*c, observed)));
+            }
+            lines.push(line);
+        }
+        if let Some(r) = self.io_retries {
+            lines.push(format!(
+                "retried I/O operations: {}",
+                r.separate_with_commas()
+            ));
+        }
+        writeln!(f, "XformStats {{ ")?;
+        for l in &lines {
+            writeln!(f, "    {},", l)?;
+        }
+        write!(f, "}}")
     }
 }
 
+/// Options controlling how the transformed records are written.
+#[derive(Debug, Clone, Default)]
+pub struct XformOptions {
+    /// If set, the output is rotated into numbered parts according
+    /// to this policy, and a manifest listing the parts is written.
+    pub split_output: Option<SplitPolicy>,
+    /// Configuration of the read 1 output.
+    pub out1: SinkConfig,
+    /// Configuration of the read 2 output.
+    pub out2: SinkConfig,
+    /// If set, the transformed fragments are matched against these
+    /// feature barcodes (see the [`feature`] module).
+    pub features: Option<FeatureOptions>,
+    /// If set, the read sequence of the transformed fragments is matched
+    /// against this panel of probes (see the [`probe`] module), and the
+    /// fragments are tagged with the id of the matching probe.
+    pub probes: Option<ProbeOptions>,
+    /// If set, the transformed fragments are matched against these control
+    /// sequences (see the [`control`] module), and tagged or written to
+    /// separate outputs.
+    pub controls: Option<ControlOptions>,
+    /// If set, the barcodes of the transformed fragments are translated into
+    /// the coordinates of the spots of a spatial array (see the [`spatial`]
+    /// module).
+    pub spatial: Option<SpatialOptions>,
+    /// If set, the transformed fragments are written to the outputs of the
+    /// sample their barcode identifies (see the [`demux`] module) rather than
+    /// to the main outputs, which receive the fragments of no sample.
+    pub demux: Option<DemuxOptions>,
+    /// If set, duplicate fragments are detected (see the [`dedup`] module)
+    /// and flagged or dropped.
+    pub dedup: Option<DedupOptions>,
+    /// If set, the UMIs of the transformed fragments are error-corrected
+    /// (see the [`umi`] module) before they are written.
+    pub umi_correction: Option<UmiOptions>,
+    /// If true, the barcodes of the written fragments are counted (see the
+    /// [`barcode`] module).
+    pub count_barcodes: bool,
+    /// If set, reads from the input files and writes to the output files
+    /// that fail with a transient error (see [`retry::is_transient`]) are
+    /// retried with backoff rather than failing the transformation.
+    pub retry: Option<RetryOptions>,
+    /// The format of the read 1 input files.
+    pub input_format: InputFormat,
+    /// The decoder run on the ORA compressed input files (see the
+    /// [`decode`] module).
+    pub ora_decoder: ExternalDecoder,
+    /// If set, the live statistics of the transformation are published to
+    /// this handle as the fragments are transformed (see the [`progress`]
+    /// module).
+    pub progress: Option<XformStatsHandle>,
+    /// The framing of the records written to both outputs.
+    pub format: OutputFormat,
+    /// If set, the output records are named after this template rather than
+    /// after the headers of the input records (see the [`naming`] module).
+    pub id_format: Option<IdFormat>,
+    /// The number of threads matching the fragments against the geometry
+    /// (see the [`parallel`] module); 0 is the same as 1.  The output is
+    /// the same, and in the same order, whatever the number of threads.
+    pub num_threads: usize,
+    /// The seed from which any random choice of the run is derived, which
+    /// is recorded in [`XformStats::seed`].  The transformation itself makes
+    /// no random choice: the outputs are bit-identical for the same inputs
+    /// and options, whatever the number of threads.  The simulation of reads
+    /// (see [`sim::seeded_rng`]) is seeded in the same way.
+    pub seed: u64,
+    /// If set, the read 1 records of the fragments that fail to parse are
+    /// written, as they were read, to this file (compressed as implied by
+    /// its extension) rather than being dropped.
+    pub unmatched1: Option<PathBuf>,
+    /// Like [`XformOptions::unmatched1`], for the read 2 records.
+    pub unmatched2: Option<PathBuf>,
+    /// If true, the fragments failing to parse are examined to find which
+    /// read and which piece of the geometry caused the failure (see the
+    /// [`failure`] module), which is reported in [`XformStats::failures`].
+    pub diagnose_failures: bool,
+    /// The input files and outputs of the index reads (see the [`index`]
+    /// module), which must be given if the geometry has index reads.
+    pub index_reads: Option<IndexReadOptions>,
+    /// If set, the barcodes of the transformed fragments are corrected
+    /// against this whitelist, and the fragments whose barcode cannot be
+    /// corrected fail to parse (see the [`whitelist`] module).
+    pub whitelist: Option<Whitelist>,
+    /// If true, the barcodes are compared against the whitelist by their
+    /// captured (unpadded) sequence rather than as padded in the output, so
+    /// that the whitelist may hold barcodes of several lengths (see the
+    /// [`whitelist`] module).
+    pub length_aware_whitelist: bool,
+    /// If set, the barcodes are corrected against the whitelist by this
+    /// strategy rather than by [`correction::OneMismatchCorrector`] (see the
+    /// [`correction`] module).
+    pub barcode_corrector: Option<BarcodeCorrectorHandle>,
+    /// If set, the barcodes captured from read 1 are cross-checked against
+    /// those captured from read 2 (see the [`consistency`] module), and the
+    /// fragments whose barcodes differ are flagged or fail to parse.
+    pub barcode_consistency: Option<ConsistencyOptions>,
+    /// If set, the read sequence of the transformed fragments is replaced by
+    /// the guide it holds (see the [`guide`] module), and the fragments in
+    /// which it cannot be found fail to parse.
+    pub guides: Option<GuideOptions>,
+    /// If set, this hook is called with each transformed fragment just
+    /// before it is written to the main outputs, and may modify it (see the
+    /// [`hook`] module).
+    pub record_hook: Option<RecordHookHandle>,
+    /// If set, the transformed fragments are written as unaligned BAM to
+    /// this file rather than to the read 1 and read 2 outputs (see the
+    /// [`bam`] module).
+    pub bam: Option<BamOptions>,
+    /// If set, the events logged by the transformation (on the calling
+    /// thread and on the threads it spawns) are sent to this subscriber
+    /// rather than to the default one, so that an embedding tool controls
+    /// what is logged without installing a global subscriber.
+    pub dispatch: Option<tracing::Dispatch>,
+    /// If set, the transformed fragments are written sorted by barcode
+    /// (see the [`sort`] module) rather than in the order of the input.
+    pub sort_by_barcode: Option<SortOptions>,
+    /// If set, an index of the offsets of the records in the outputs is
+    /// written next to them (see the [`offsets`] module).
+    pub offset_index: Option<OffsetIndexOptions>,
+    /// If true, the read 1 and read 2 records of each fragment are written
+    /// one after the other into the read 1 output (configured by `out1`),
+    /// and the read 2 output must not be given.
+    pub interleaved: bool,
+    /// If true, the transformation fails if the read 1 and read 2 records of
+    /// a fragment have different names, or if one of the inputs holds more
+    /// records than the other; otherwise, these are reported as warnings
+    /// (see the [`pairing`] module).
+    pub strict_pairing: bool,
+    /// If set, only this many fragments are read (over all of the inputs)
+    /// and transformed, e.g. to preview the transformation of a geometry
+    /// before a long run (see the [`preview`] module).
+    pub max_records: Option<u64>,
+    /// What to do with the malformed records of the inputs: fail (by
+    /// default), or drop the fragments holding them (see the [`malformed`]
+    /// module).
+    pub on_error: OnError,
+}
+
 /// Given input file paths (possibly multiple sets of files) in `r1` and `r2`,
 /// read sequence records from these files and transform them in accordance with
-/// the `FragmentRegexDesc` provided as `geo_re`.  The transformed records are then
+/// the `FragmentRegexDesc` provided as `geo_re`.  One of the inputs may be `-`
+/// (see [`STDIN_PATH`]), to read it from the standard input.  The transformed records are then
 /// written out to `r1_ofile` and `r2_ofile`. Currently all output is written in `FASTA`
 /// format, so any quality lines or comment lines (if the input is `FASTQ`) will be
 /// dropped.
 pub fn xform_read_pairs_to_file(
-    mut geo_re: FragmentRegexDesc,
+    geo_re: FragmentRegexDesc,
     r1: &[PathBuf],
     r2: &[PathBuf],
     r1_ofile: PathBuf,
     r2_ofile: PathBuf,
 ) -> Result<XformStats> {
-    let f1 = File::create(r1_ofile).expect("Unable to open read 1 file");
-    let f2 = File::create(r2_ofile).expect("Unable to open read 2 file");
+    xform_read_pairs_to_file_with_options(
+        geo_re,
+        r1,
+        r2,
+        Some(r1_ofile),
+        Some(r2_ofile),
+        &XformOptions::default(),
+    )
+}
+
+/// Writes a `FASTA` record with the header `id` (followed by `comment`, which
+/// is either empty or starts with a space) and the sequence `seq` to `w`.
+/// The (raw) bytes of `id` are written as is, so there is no need for them to
+/// be valid UTF-8.
+#[inline]
+pub(crate) fn write_fasta_record<W: io::Write + ?Sized>(
+    w: &mut W,
+    id: &[u8],
+    comment: &[u8],
+    seq: &[u8],
+) -> io::Result<()> {
+    w.write_all(b">")?;
+    w.write_all(id)?;
+    w.write_all(comment)?;
+    w.write_all(b"\n")?;
+    w.write_all(seq)?;
+    w.write_all(b"\n")
+}
+
+/// Like [`xform_read_pairs_to_file`], but the way the output is written is
+/// controlled by `opts`.  If `opts.split_output` is set, then `r1_ofile` and
+/// `r2_ofile` are used as the templates for the names of the numbered output
+/// parts (see [`output::part_path`]) rather than being written directly.
+///
+/// If the geometry discards all of one of the reads (e.g. `2{x:}`), then the
+/// corresponding output may be `None`, in which case it is not written at all
+/// rather than being filled with empty records.  The records are
+/// written in the format `opts.format` (`FASTA` by default).
+pub fn xform_read_pairs_to_file_with_options(
+    geo_re: FragmentRegexDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    r1_ofile: Option<PathBuf>,
+    r2_ofile: Option<PathBuf>,
+    opts: &XformOptions,
+) -> Result<XformStats> {
+    xform_read_pairs_to_file_with_geometries(vec![geo_re], r1, r2, r1_ofile, r2_ofile, opts)
+}
+
+/// Checks that `geo_res` holds either a single geometry or one geometry for
+/// each of the `num_pairs` pairs of input files, and that all of them have
+/// the same simplified geometry and padding scheme.
+pub(crate) fn check_geometries(geo_res: &[FragmentRegexDesc], num_pairs: usize) -> Result<()> {
+    let Some(geo_re) = geo_res.first() else {
+        bail!("At least one geometry must be given");
+    };
+    if geo_res.len() != 1 && geo_res.len() != num_pairs {
+        bail!(
+            "Either one geometry or one geometry per pair of input files ({}) must be given, but got {}",
+            num_pairs,
+            geo_res.len()
+        );
+    }
+    let simplified = geo_re.get_simplified_description_string();
+    for (i, g) in geo_res.iter().enumerate().skip(1) {
+        let s = g.get_simplified_description_string();
+        if s != simplified {
+            bail!(
+                "All geometries must have the same simplified geometry, but that of geometry {} ({}) differs from that of geometry 1 ({})",
+                i + 1,
+                s,
+                simplified
+            );
+        }
+        if g.padding() != geo_re.padding() {
+            bail!(
+                "All geometries must have the same padding scheme, but that of geometry {} ({}) differs from that of geometry 1 ({})",
+                i + 1,
+                g.padding(),
+                geo_re.padding()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Like [`xform_read_pairs_to_file_with_options`], but each pair of input
+/// files may have its own geometry: the `i`-th pair (`r1[i]`, `r2[i]`) is
+/// parsed with `geo_res[i]`.  If a single geometry is given, it is used for
+/// all pairs.  This allows, e.g., transforming lanes sequenced with different
+/// kit versions in one invocation, so long as all of the geometries have the
+/// same simplified geometry (which is what is written).  The statistics of
+/// each pair are reported in [`XformStats::input_pairs`].
+pub fn xform_read_pairs_to_file_with_geometries(
+    geo_res: Vec<FragmentRegexDesc>,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    r1_ofile: Option<PathBuf>,
+    r2_ofile: Option<PathBuf>,
+    opts: &XformOptions,
+) -> Result<XformStats> {
+    let res = with_dispatch(opts, || {
+        transform_pairs_to_file(geo_res, r1, r2, r1_ofile, r2_ofile, opts)
+    });
+    if let Some(h) = &opts.progress {
+        h.finish();
+    }
+    res
+}
 
-    let mut stream1 = BufWriter::new(f1);
-    let mut stream2 = BufWriter::new(f2);
+/// Runs `f` with the events it logs sent to `opts.dispatch`, if set (see
+/// [`XformOptions::dispatch`]).
+fn with_dispatch<T>(opts: &XformOptions, f: impl FnOnce() -> T) -> T {
+    match &opts.dispatch {
+        Some(d) => tracing::dispatcher::with_default(d, f),
+        None => f(),
+    }
+}
+
+/// Returns the live statistics of a transformation that has so far
+/// transformed the pairs of input files in `stats`, and the fragments of the
+/// pair `current` (if any) being read, having read `input` so far.
+fn progress_snapshot(
+    stats: &XformStats,
+    current: Option<&InputPairStats>,
+    retry: Option<&RetryPolicy>,
+    input: &InputProgress,
+) -> StatsSnapshot {
+    let (total, failed) = current.map_or((0, 0), |p| (p.total_fragments, p.failed_parsing));
+    let mut s = StatsSnapshot {
+        total_fragments: stats.total_fragments + total,
+        failed_parsing: stats.failed_parsing + failed,
+        duplicate_fragments: stats.duplicate_fragments,
+        corrected_umis: stats.corrected_umis,
+        io_retries: retry.map(|r| r.retries()),
+        completed_pairs: stats.input_pairs.len(),
+        ..StatsSnapshot::default()
+    };
+    input.fill(&mut s);
+    s
+}
 
+fn transform_pairs_to_file(
+    geo_res: Vec<FragmentRegexDesc>,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    r1_ofile: Option<PathBuf>,
+    r2_ofile: Option<PathBuf>,
+    opts: &XformOptions,
+) -> Result<XformStats> {
+    let groups = input_groups(r1, r2, opts.input_format)?;
+    // the bytes read from the read 1 inputs, to estimate the time remaining
+    let input_progress = InputProgress::new(r1);
+    check_geometries(&geo_res, groups.len())?;
+    // as all geometries have the same simplified geometry, the pieces (e.g.
+    // the barcodes) are at the same locations in all of the transformed
+    // reads, so the outputs and the stages are set up from the first one
+    let geo_re = &geo_res[0];
+    if opts.interleaved && (r1_ofile.is_none() || r2_ofile.is_some()) {
+        bail!("Interleaved records are written to the read 1 output, and only to it");
+    }
+    if r1_ofile.is_none() && opts.bam.is_none() && !geo_re.discards_read1() {
+        bail!("No read 1 output was given, but the geometry does not discard all of read 1");
+    }
+    if r2_ofile.is_none() && opts.bam.is_none() && !geo_re.discards_read2() && !opts.interleaved {
+        bail!("No read 2 output was given, but the geometry does not discard all of read 2");
+    }
+    let retry = opts.retry.clone().map(RetryPolicy::new);
+    let (mut out1, mut out2) = (opts.out1.clone(), opts.out2.clone());
+    if retry.is_some() {
+        out1.retry = retry.clone();
+        out2.retry = retry.clone();
+    }
+    let index_path = r1_ofile
+        .as_deref()
+        .or(r2_ofile.as_deref())
+        .map(offset_index_path);
+    if [&r1_ofile, &r2_ofile]
+        .into_iter()
+        .flatten()
+        .any(|p| handle::is_handle(p))
+        && (opts.offset_index.is_some() || opts.demux.is_some())
+    {
+        bail!("The offset index and the outputs of the samples cannot be named after an output handle");
+    }
+    // the outputs of the samples are named after the main outputs
+    let demux_bases = [r1_ofile.clone(), r2_ofile.clone()];
+    let mut writer = match (&opts.bam, opts.interleaved, r1_ofile) {
+        (Some(bo), false, None) if r2_ofile.is_none() && opts.split_output.is_none() => {
+            if opts.offset_index.is_some() {
+                bail!("An offset index cannot be written for unaligned BAM output");
+            }
+            FragmentWriter::Bam(BamWriter::new(geo_re, bo)?)
+        }
+        (Some(_), ..) => bail!(
+            "The transformed fragments are written as unaligned BAM, so no read 1, read 2 or \
+             interleaved output may be given, and the output cannot be split"
+        ),
+        (None, true, Some(o)) => {
+            FragmentWriter::Fastx(PairedWriter::new_interleaved(o, &out1, opts.split_output)?)
+        }
+        (None, _, r1_ofile) => FragmentWriter::Fastx(PairedWriter::new(
+            r1_ofile,
+            r2_ofile,
+            &out1,
+            &out2,
+            opts.split_output,
+        )?),
+    };
+    if let (Some(oi), Some(p), FragmentWriter::Fastx(w)) =
+        (&opts.offset_index, index_path, &mut writer)
+    {
+        // the records sorted by barcode are indexed by barcode
+        let by_barcode = opts.sort_by_barcode.is_some();
+        w.set_offset_index(OffsetIndex::new(&p, oi, geo_re, by_barcode)?)?;
+    }
     let mut xform_stats = XformStats::new();
+    if geo_res.iter().any(|g| g.try_revcomp()) {
+        xform_stats.orientations = Some(OrientationCounts::default());
+    }
+    if geo_res.iter().any(|g| g.tolerates_anchor_mismatches()) {
+        xform_stats.rescued_fragments = Some(0);
+    }
+    if opts.on_error != OnError::Abort {
+        xform_stats.malformed_fragments = Some(0);
+    }
+    xform_stats.padding = geo_re.padding();
+    xform_stats.padding_usage = ranged_pieces(geo_re);
+    xform_stats.seed = opts.seed;
+    if opts.diagnose_failures {
+        xform_stats.failures = Some(FailureCounts::new());
+    }
+    let mut stages = FragmentStages::new(
+        geo_re,
+        opts,
+        r1.len(),
+        [&out1, &out2],
+        [demux_bases[0].as_deref(), demux_bases[1].as_deref()],
+        retry.as_ref(),
+        &mut xform_stats,
+    )?;
+    let pairing = PairingChecker {
+        strict: opts.strict_pairing,
+    };
+    let num_groups = groups.len();
+    let limit = opts.max_records.unwrap_or(u64::MAX);
+    for (i, (files1, files2)) in groups.into_iter().enumerate() {
+        if xform_stats.total_fragments >= limit {
+            break;
+        }
+        let gi = if geo_res.len() == 1 { 0 } else { i };
+        let mut parser = BatchParser::new(&geo_res[gi], opts.num_threads, opts.diagnose_failures);
+        let mut pair_stats = InputPairStats {
+            r1: files1.first().cloned().unwrap_or_default(),
+            r2: files2.first().cloned().unwrap_or_default(),
+            geometry: gi,
+            total_fragments: 0,
+            failed_parsing: 0,
+            pairing: PairingCounts::default(),
+        };
+
+        // the records are decoded on a dedicated thread, while the
+        // previously decoded batch is being transformed
+        let mut batches = read_input_group(
+            files1,
+            files2,
+            retry.as_ref(),
+            opts.progress.as_ref().map(|_| input_progress.counter()),
+            opts.on_error,
+            &opts.ora_decoder,
+        )?;
+        if opts.strict_pairing {
+            batches = batches.with_equal_counts();
+        }
+        if let (Some(io), true) = (&opts.index_reads, stages.transforms_index_reads()) {
+            batches = read_index_group(
+                batches,
+                io,
+                i,
+                num_groups,
+                retry.as_ref(),
+                opts.on_error,
+                &opts.ora_decoder,
+            )?;
+        }
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for (frag, parsed) in batch.iter().zip(parser.parse(&batch)) {
+                if xform_stats.total_fragments + pair_stats.total_fragments >= limit {
+                    break;
+                }
+                pairing.check_names(
+                    pair_stats.total_fragments,
+                    frag.ids[0],
+                    frag.ids[1],
+                    &mut pair_stats.pairing,
+                )?;
+                pair_stats.total_fragments += 1;
+                let index = xform_stats.total_fragments + pair_stats.total_fragments;
+                if !stages.transform(&frag, parsed, index, &mut writer, &mut xform_stats)? {
+                    pair_stats.failed_parsing += 1;
+                }
+            }
+            batches.recycle(batch);
+            if let Some(h) = &opts.progress {
+                h.publish(progress_snapshot(
+                    &xform_stats,
+                    Some(&pair_stats),
+                    retry.as_ref(),
+                    &input_progress,
+                ));
+            }
+            if xform_stats.total_fragments + pair_stats.total_fragments >= limit {
+                break;
+            }
+        }
+        pair_stats.pairing.unpaired = batches.unpaired();
+        if let Some(m) = xform_stats.malformed_fragments.as_mut() {
+            *m += batches.malformed();
+        }
+        pairing.report(&pair_stats.r1, &pair_stats.r2, &pair_stats.pairing)?;
+        xform_stats.total_fragments += pair_stats.total_fragments;
+        xform_stats.failed_parsing += pair_stats.failed_parsing;
+        xform_stats.pairing.add(&pair_stats.pairing);
+        xform_stats.input_pairs.push(pair_stats);
+    }
+    stages.finish(writer, &mut xform_stats)?;
+    if let Some(h) = &opts.progress {
+        h.publish(progress_snapshot(
+            &xform_stats,
+            None,
+            retry.as_ref(),
+            &input_progress,
+        ));
+    }
+    xform_stats.io_retries = retry.map(|r| r.retries());
+    Ok(xform_stats)
+}
+
+/// Appends the tag recording the captured lengths `lens` of the variable
+/// length pieces of a read (e.g. ` lens=9,10`) to `comment`, unless the read
+/// has no such pieces.
+pub(crate) fn push_lens_tag(comment: &mut Vec<u8>, lens: &[u32]) {
+    for (i, l) in lens.iter().enumerate() {
+        comment.extend_from_slice(if i == 0 { b" lens=" } else { b"," });
+        comment.extend_from_slice(l.to_string().as_bytes());
+    }
+}
+
+/// The input path read from the standard input rather than from a file.
+pub const STDIN_PATH: &str = "-";
+
+/// Returns true if the input path `p` designates the standard input (see
+/// [`STDIN_PATH`]).
+pub fn is_stdin(p: &Path) -> bool {
+    p.as_os_str() == STDIN_PATH
+}
+
+/// The format of the read 1 input files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Unaligned BAM (or CRAM) holding both reads of each fragment (see the
+    /// [`bam`] module) if all of the files have the `.bam`, `.ubam` or
+    /// `.cram` extension (see [`bam::is_bam`]), and `FASTA` or `FASTQ`
+    /// otherwise.
+    #[default]
+    FromExtension,
+    /// Unaligned BAM (or CRAM, if their extension is `.cram`), whatever the
+    /// name of the files (e.g. when reading BAM from the standard input).
+    Bam,
+}
+
+/// Returns true if the read 1 input files `r1` are unaligned BAM (or CRAM)
+/// files holding both reads, as told by `format`, checking that read 2 input
+/// files `r2` are given if and only if they are not.
+pub(crate) fn reads_bam(r1: &[PathBuf], r2: &[PathBuf], format: InputFormat) -> Result<bool> {
+    let bam = match format {
+        InputFormat::FromExtension => r1.iter().all(|p| bam::is_bam(p)),
+        InputFormat::Bam => true,
+    };
+    if bam && !r2.is_empty() {
+        bail!(
+            "the unaligned BAM (or CRAM) read 1 inputs hold both reads, so no read 2 input \
+             can be given"
+        );
+    }
+    if !bam && r2.is_empty() {
+        bail!(
+            "no read 2 input was given, but the read 1 inputs are not unaligned BAM (or CRAM) \
+             files (with the .bam, .ubam or .cram extension, or read as BAM explicitly)"
+        );
+    }
+    Ok(bam)
+}
+
+/// Groups the input files `r1` and `r2`, the former in the format `format`,
+/// into the pairs of inputs whose records are read together: each pair of
+/// files (`r1[i]`, `r2[i]`) if there are as many read 1 as read 2 files, and
+/// otherwise all of the read 1 files, read one after the other, along with
+/// all of the read 2 files.  The latter allows the reads of a sample to be
+/// delivered in different numbers of read 1 and read 2 chunks, so long as
+/// they hold the same number of records.  Unaligned BAM (or CRAM) read 1
+/// files hold both reads (see [`reads_bam`]), so that there are no read 2
+/// files, and each of them is a group of its own.  As the standard input can
+/// only be read once, at most one of the inputs may be read from it.
+pub(crate) fn input_groups<'a>(
+    r1: &'a [PathBuf],
+    r2: &'a [PathBuf],
+    format: InputFormat,
+) -> Result<Vec<(&'a [PathBuf], &'a [PathBuf])>> {
+    if r1.is_empty() {
+        bail!("at least one read 1 input must be given");
+    }
+    reads_bam(r1, r2, format)?;
+    if r1.iter().chain(r2).filter(|p| is_stdin(p)).count() > 1 {
+        bail!(
+            "at most one of the inputs can be read from the standard input ({})",
+            STDIN_PATH
+        );
+    }
+    Ok(if r2.is_empty() {
+        r1.chunks(1).map(|f| (f, r2)).collect()
+    } else if r1.len() == r2.len() {
+        r1.chunks(1).zip(r2.chunks(1)).collect()
+    } else {
+        vec![(r1, r2)]
+    })
+}
+
+/// Opens the input files `files1` and `files2` of a group returned by
+/// [`input_groups`], and starts reading their fragments in batches.  If the
+/// files of either read are concatenated, reading fails unless both reads
+/// hold the same number of records.  The groups of unaligned BAM files have
+/// no read 2 files, and are read as such (see [`open_bam`]).  If `bytes_read` is
+/// given, the bytes read from the read 1 files are added to it.  Unless
+/// `on_error` is [`OnError::Abort`], the fragments holding a malformed record
+/// are dropped (see the [`malformed`] module).
+pub(crate) fn read_input_group(
+    files1: &[PathBuf],
+    files2: &[PathBuf],
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+    on_error: OnError,
+    ora_decoder: &ExternalDecoder,
+) -> Result<BatchReader> {
+    read_input_group_in_batches(
+        files1,
+        files2,
+        batch::DEFAULT_CAPACITY,
+        retry,
+        bytes_read,
+        on_error,
+        ora_decoder,
+    )
+}
+
+/// Like [`read_input_group`], but in batches of `capacity` fragments.
+pub(crate) fn read_input_group_in_batches(
+    files1: &[PathBuf],
+    files2: &[PathBuf],
+    capacity: usize,
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+    on_error: OnError,
+    ora_decoder: &ExternalDecoder,
+) -> Result<BatchReader> {
+    if files2.is_empty() {
+        let readers = files1
+            .iter()
+            .map(|f| {
+                open_bam(f, retry, bytes_read)
+                    .with_context(|| format!("could not open {}", f.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        return Ok(BatchReader::spawn_pairs(readers, capacity));
+    }
+    let (mut readers1, mut readers2) = (
+        open_all(files1, retry, bytes_read, on_error, ora_decoder)?,
+        open_all(files2, retry, None, on_error, ora_decoder)?,
+    );
+    let batches = if readers1.len() == 1 && readers2.len() == 1 {
+        BatchReader::spawn(readers1.remove(0), readers2.remove(0), capacity)
+    } else {
+        BatchReader::spawn_concatenated(readers1, readers2, capacity)
+    };
+    Ok(if on_error == OnError::Abort {
+        batches
+    } else {
+        batches.dropping_malformed()
+    })
+}
+
+/// Opens the index read input files of the `group`-th of the `num_groups`
+/// groups returned by [`input_groups`], and starts reading their records
+/// along with those of `batches`.  When the read 1 and read 2 files are read
+/// in pairs, the index read files are read in the same pairs, and otherwise
+/// all of them are read one after the other.
+pub(crate) fn read_index_group(
+    batches: BatchReader,
+    index_reads: &IndexReadOptions,
+    group: usize,
+    num_groups: usize,
+    retry: Option<&RetryPolicy>,
+    on_error: OnError,
+    ora_decoder: &ExternalDecoder,
+) -> Result<BatchReader> {
+    let [index1, index2] = &index_reads.inputs;
+    let open_group = |files: &[PathBuf]| -> Result<Option<Vec<Box<dyn FastxReader>>>> {
+        if files.is_empty() {
+            return Ok(None);
+        }
+        if files.iter().any(|f| is_stdin(f)) {
+            bail!(
+                "the index reads cannot be read from the standard input ({})",
+                STDIN_PATH
+            );
+        }
+        let files = if files.len() == num_groups {
+            &files[group..group + 1]
+        } else {
+            files
+        };
+        open_all(files, retry, None, on_error, ora_decoder).map(Some)
+    };
+    Ok(batches.with_index_reads(open_group(index1)?, open_group(index2)?))
+}
+
+/// Opens each of the `FASTA` or `FASTQ` files `files` (see [`open_fastx`]).
+fn open_all(
+    files: &[PathBuf],
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+    on_error: OnError,
+    ora_decoder: &ExternalDecoder,
+) -> Result<Vec<Box<dyn FastxReader>>> {
+    files
+        .iter()
+        .map(|f| {
+            open_fastx_recovering(f, retry, bytes_read, on_error, ora_decoder)
+                .with_context(|| format!("could not open {}", f.display()))
+        })
+        .collect()
+}
+
+/// Opens the `FASTA` or `FASTQ` file `p` (which may be compressed).  If
+/// `retry` is given, reads from the file are retried in accordance with it.
+/// If `bytes_read` is given, the bytes read from a (regular) file are added
+/// to it.  An ORA compressed file is instead decoded by running `ora_decoder`
+/// on it, and [`STDIN_PATH`] designates the standard input, whose
+/// compression is detected in the same way as that of a file, as does the
+/// path of an input [`handle`].
+pub(crate) fn open_fastx(
+    p: &Path,
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+    ora_decoder: &ExternalDecoder,
+) -> Result<Box<dyn FastxReader>> {
+    if let Some(r) = handle::open_read(p) {
+        return Ok(parse_fastx_reader(wrap_input(r?, retry, bytes_read))?);
+    }
+    if is_stdin(p) {
+        return Ok(match retry {
+            Some(r) => parse_fastx_reader(r.wrap(io::stdin()))?,
+            None => parse_fastx_reader(io::stdin())?,
+        });
+    }
+    if decode::is_ora(p) {
+        return Ok(parse_fastx_reader(ora_decoder.open(p)?)?);
+    }
+    if retry.is_none() && bytes_read.is_none() {
+        return Ok(parse_fastx_file(p)?);
+    }
+    Ok(parse_fastx_reader(open_file(p, retry, bytes_read)?)?)
+}
+
+/// Opens the `FASTA` or `FASTQ` file `p` as [`open_fastx`] does, but unless
+/// `on_error` is [`OnError::Abort`], the malformed records of a `FASTQ` file
+/// are replaced by placeholders (see the [`malformed`] module).
+fn open_fastx_recovering(
+    p: &Path,
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+    on_error: OnError,
+    ora_decoder: &ExternalDecoder,
+) -> Result<Box<dyn FastxReader>> {
+    if on_error == OnError::Abort {
+        return open_fastx(p, retry, bytes_read, ora_decoder);
+    }
+    let r: Box<dyn io::Read + Send> = if let Some(r) = handle::open_read(p) {
+        wrap_input(r?, retry, bytes_read)
+    } else if is_stdin(p) {
+        match retry {
+            Some(r) => Box::new(r.wrap(io::stdin())),
+            None => Box::new(io::stdin()),
+        }
+    } else if decode::is_ora(p) {
+        Box::new(ora_decoder.open(p)?)
+    } else {
+        open_file(p, retry, bytes_read)?
+    };
+    Ok(parse_fastx_reader(malformed::recovering(r, p, on_error)?)?)
+}
+
+/// Opens the file `p`, whose reads are retried in accordance with `retry`
+/// and counted in `bytes_read`, if given.
+fn open_file(
+    p: &Path,
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+) -> Result<Box<dyn io::Read + Send>> {
+    Ok(wrap_input(Box::new(File::open(p)?), retry, bytes_read))
+}
+
+/// Retries the reads from the input `f` in accordance with `retry`, and
+/// counts them in `bytes_read`, if given.
+fn wrap_input(
+    f: Box<dyn io::Read + Send>,
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+) -> Box<dyn io::Read + Send> {
+    let f: Box<dyn io::Read + Send> = match bytes_read {
+        Some(c) => Box::new(CountingReader::new(f, c.clone())),
+        None => f,
+    };
+    match retry {
+        Some(r) => Box::new(r.wrap(f)),
+        None => f,
+    }
+}
+
+/// Opens the unaligned BAM file `p`, holding both reads of each fragment
+/// (see the [`bam`] module).  A CRAM file is instead decoded by running
+/// [`bam::cram_decoder`] on it, and [`STDIN_PATH`] designates the standard
+/// input.  The path of an input [`handle`] is read from the handle.
+pub(crate) fn open_bam(
+    p: &Path,
+    retry: Option<&RetryPolicy>,
+    bytes_read: Option<&Arc<AtomicU64>>,
+) -> Result<BamReader> {
+    let r: Box<dyn io::Read + Send> = if let Some(r) = handle::open_read(p) {
+        wrap_input(r?, retry, bytes_read)
+    } else if is_stdin(p) {
+        match retry {
+            Some(r) => Box::new(r.wrap(io::stdin())),
+            None => Box::new(io::stdin()),
+        }
+    } else if bam::is_cram(p) {
+        Box::new(bam::cram_decoder().open(p)?)
+    } else {
+        open_file(p, retry, bytes_read)?
+    };
+    BamReader::new(r)
+}
+
+/// Writes a transformed fragment, given the headers (ids and comments) of its
+/// read 1 and read 2 records and the ranges of their padding, to `writer` in
+/// the format `format`.
+pub(crate) fn write_fragment(
+    writer: &mut PairedWriter,
+    format: OutputFormat,
+    headers: [(&[u8], &[u8]); 2],
+    frag: &SeqPair,
+    pads: [&[Range<u32>]; 2],
+) -> Result<()> {
+    writer.index_record(frag)?;
+    let interleaved = writer.is_interleaved();
+    let (stream1, stream2) = writer.streams();
+    let [(id1, comment1), (id2, comment2)] = headers;
+    format
+        .write_record(stream1, id1, comment1, frag.s1.as_slice(), pads[0])
+        .context("couldn't write output to file 1")?;
+    let stream2 = if interleaved { stream1 } else { stream2 };
+    format
+        .write_record(stream2, id2, comment2, frag.s2.as_slice(), pads[1])
+        .context("couldn't write output to file 2")?;
+    writer.summarize_record(1, format, frag.s1.len(), pads[0]);
+    let read2 = if interleaved { 1 } else { 2 };
+    writer.summarize_record(read2, format, frag.s2.len(), pads[1]);
+    writer.end_record()
+}
+
+/// The main output of a transformation.
+// there is a single writer per transformation, so its size does not matter
+#[allow(clippy::large_enum_variant)]
+enum FragmentWriter {
+    /// The read 1 and read 2 outputs, in one of the [`OutputFormat`]s.
+    Fastx(PairedWriter),
+    /// An unaligned BAM (see the [`bam`] module).
+    Bam(BamWriter),
+}
+
+impl FragmentWriter {
+    /// Writes a transformed fragment, given the headers (ids and comments) of
+    /// its read 1 and read 2 records and the ranges of their padding (see
+    /// [`write_fragment`]).
+    fn write(
+        &mut self,
+        format: OutputFormat,
+        headers: [(&[u8], &[u8]); 2],
+        frag: &SeqPair,
+        pads: [&[Range<u32>]; 2],
+    ) -> Result<()> {
+        match self {
+            FragmentWriter::Fastx(w) => write_fragment(w, format, headers, frag, pads),
+            FragmentWriter::Bam(w) => w.write(headers, frag),
+        }
+    }
+
+    /// Flushes the output, completing it.
+    fn finish(self) -> Result<()> {
+        match self {
+            FragmentWriter::Fastx(w) => w.finish().map(|_| ()),
+            FragmentWriter::Bam(w) => w.finish(),
+        }
+    }
+}
+
+/// A window of transformed fragments whose UMIs are corrected together
+/// before they are written (see the [`umi`] module).
+struct UmiWindow {
+    corrector: UmiCorrector,
+    size: usize,
+    /// The ids and comments of the read 1 and read 2 records.
+    headers: Vec<[(Vec<u8>, Vec<u8>); 2]>,
+    frags: Vec<SeqPair>,
+    /// The ranges of the padding of the read 1 and read 2 records.
+    pads: Vec<[Vec<Range<u32>>; 2]>,
+}
+
+impl UmiWindow {
+    fn push(&mut self, headers: [(&[u8], &[u8]); 2], frag: &SeqPair, pads: [&[Range<u32>]; 2]) {
+        self.headers
+            .push(headers.map(|(id, comment)| (id.to_vec(), comment.to_vec())));
+        self.pads.push(pads.map(|p| p.to_vec()));
+        self.frags.push(SeqPair {
+            s1: frag.s1.clone(),
+            s2: frag.s2.clone(),
+        });
+    }
+
+    fn is_full(&self) -> bool {
+        self.frags.len() >= self.size
+    }
+
+    /// Corrects the UMIs of the fragments in the window and writes them to
+    /// `writer` in the format `format`, returning the number of fragments
+    /// whose UMI was corrected.
+    fn flush(&mut self, writer: &mut FragmentWriter, format: OutputFormat) -> Result<u64> {
+        let corrected = self.corrector.correct(&mut self.frags);
+        for (([(id1, c1), (id2, c2)], frag), [p1, p2]) in
+            self.headers.iter().zip(&self.frags).zip(&self.pads)
+        {
+            writer.write(format, [(id1, c1), (id2, c2)], frag, [p1, p2])?;
+        }
+        self.headers.clear();
+        self.frags.clear();
+        self.pads.clear();
+        Ok(corrected)
+    }
+}
+
+/// Given input file paths (possibly multiple sets of files) in `r1` and `r2`,
+/// read sequence records from these files and attempt to parse them in accordance
+/// with the `FragmentRegexDesc` provided as `geo_re`, without writing any output.
+/// If `max_fragments` is provided, at most this many fragments are examined. This
+/// is useful for quickly assessing how well a geometry describes some reads.
+pub fn compute_xform_stats(
+    geo_re: &mut FragmentRegexDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    max_fragments: Option<u64>,
+) -> Result<XformStats> {
+    compute_xform_stats_with_decoder(
+        geo_re,
+        r1,
+        r2,
+        max_fragments,
+        InputFormat::default(),
+        &ExternalDecoder::default(),
+    )
+}
+
+/// Like [`compute_xform_stats`], but the read 1 inputs are in the format
+/// `input_format`, and the ORA compressed input files are decoded by running
+/// `ora_decoder` on them.
+pub fn compute_xform_stats_with_decoder(
+    geo_re: &mut FragmentRegexDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    max_fragments: Option<u64>,
+    input_format: InputFormat,
+    ora_decoder: &ExternalDecoder,
+) -> Result<XformStats> {
+    let mut xform_stats = XformStats::new();
+    if geo_re.try_revcomp() {
+        xform_stats.orientations = Some(OrientationCounts::default());
+    }
+    if geo_re.tolerates_anchor_mismatches() {
+        xform_stats.rescued_fragments = Some(0);
+    }
+    xform_stats.padding = geo_re.padding();
+    xform_stats.padding_usage = ranged_pieces(geo_re);
+    // the failures are always diagnosed, as computing the statistics is
+    // meant to evaluate how well the geometry fits the reads
+    let mut failures = FailureCounts::new();
     let mut parsed_records = SeqPair::new();
-    for (filename1, filename2) in r1.iter().zip(r2.iter()) {
-        let mut reader = parse_fastx_file(filename1).expect("valid path/file");
-        let mut reader2 = parse_fastx_file(filename2).expect("valid path/file");
-
-        while let (Some(record), Some(record2)) = (reader.next(), reader2.next()) {
-            xform_stats.total_fragments += 1;
-            let seqrec = record.expect("invalid record");
-            let seqrec2 = record2.expect("invalid record");
-
-            if geo_re.parse_into(seqrec.sequence(), seqrec2.sequence(), &mut parsed_records) {
-                unsafe {
-                    std::write!(
-                        &mut stream1,
-                        ">{}\n{}\n",
-                        std::str::from_utf8_unchecked(seqrec.id()),
-                        parsed_records.s1
-                    )
-                    .expect("couldn't write output to file 1");
-                    std::write!(
-                        &mut stream2,
-                        ">{}\n{}\n",
-                        std::str::from_utf8_unchecked(seqrec2.id()),
-                        parsed_records.s2
-                    )
-                    .expect("couldn't write output to file 2");
+    let limit = max_fragments.unwrap_or(u64::MAX);
+    'files: for (files1, files2) in input_groups(r1, r2, input_format)? {
+        let mut batches =
+            read_input_group(files1, files2, None, None, OnError::Abort, ora_decoder)?;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                if xform_stats.total_fragments >= limit {
+                    break 'files;
+                }
+                xform_stats.total_fragments += 1;
+                let pair_matched =
+                    geo_re.parse_into(frag.seqs[0], frag.seqs[1], &mut parsed_records);
+                if pair_matched && geo_re.parse_name_into(frag.ids[0], &mut parsed_records) {
+                    if let Some(o) = xform_stats.orientations.as_mut() {
+                        o.record(geo_re.last_reversed());
+                    }
+                    if geo_re.last_rescued() {
+                        *xform_stats.rescued_fragments.as_mut().unwrap() += 1;
+                    }
+                    record_lens(&mut xform_stats.padding_usage, geo_re.last_lens());
+                } else {
+                    xform_stats.failed_parsing += 1;
+                    failures.record(if pair_matched {
+                        ParseFailure {
+                            read: 1,
+                            cause: FailureCause::NameNotMatched,
+                        }
+                    } else {
+                        geo_re.diagnose_failure(frag.seqs[0], frag.seqs[1])
+                    });
                 }
-            } else {
-                xform_stats.failed_parsing += 1;
             }
+            batches.recycle(batch);
         }
     }
+    xform_stats.failures = Some(failures);
     Ok(xform_stats)
 }
 
@@ -504,57 +3281,193 @@ pub fn xform_read_pairs_to_file(
 /// Currently, all output is written in `FASTA` format, so any quality lines or comment lines
 /// (if the input is `FASTQ`) will be dropped.  If an error occurs up to the creation of the
 /// spawned thread, then this function returns an `Err(anyhow::Error)`.  The spawned thread
-/// itself returns a `Result<()>`.
+/// itself returns a `Result<XformStats>`; if it panics, the panic is caught and returned as
+/// a [`WorkerPanic`] error, and the fifos are removed as they would otherwise be.
 pub fn xform_read_pairs_to_fifo(
     geo_re: FragmentRegexDesc,
     r1: Vec<PathBuf>,
     r2: Vec<PathBuf>,
 ) -> Result<FifoXFormData> {
-    if r1.len() != r2.len() {
+    xform_read_pairs_to_fifo_with_options(geo_re, r1, r2, XformOptions::default())
+}
+
+/// Like [`xform_read_pairs_to_fifo`], but the bytes written into the fifos are
+/// gzip compressed with the compression level `level` (0-9).  This trades CPU
+/// time for pipe bandwidth, for consumers reading gzipped streams when the
+/// pipes are the bottleneck.
+pub fn xform_read_pairs_to_gzipped_fifo(
+    geo_re: FragmentRegexDesc,
+    r1: Vec<PathBuf>,
+    r2: Vec<PathBuf>,
+    level: u32,
+) -> Result<FifoXFormData> {
+    if level > 9 {
         bail!(
-            "The number of R1 files ({}) must match the number of R2 files ({})",
-            r1.len(),
-            r2.len()
+            "gzip compression level must be in [0, 9], but got {}",
+            level
         );
     }
+    let sink = SinkConfig {
+        compression: Compression::Gzip(level),
+        ..SinkConfig::default()
+    };
+    let opts = XformOptions {
+        out1: sink.clone(),
+        out2: sink,
+        ..XformOptions::default()
+    };
+    xform_read_pairs_to_fifo_with_options(geo_re, r1, r2, opts)
+}
 
-    let tmp_dir = tempdir()?;
-    let r1_fifo = tmp_dir.path().join("r1.pipe");
-    let r2_fifo = tmp_dir.path().join("r2.pipe");
+/// Like [`xform_read_pairs_to_fifo`], but the way the records are written into
+/// the fifos is controlled by `opts` (e.g. the compression of each stream, as
+/// with [`xform_read_pairs_to_gzipped_fifo`]).
+/// Splitting the output into parts is not supported when writing to fifos.
+/// If `opts.progress` is not set, a new handle is created for the returned
+/// [`FifoXFormData::stats`].  With [`SinkConfig::framed`], the records are
+/// written as chunks checked by a CRC32, to be read through a
+/// [`frame::FrameReader`], which fails rather than returning a partial
+/// record if the transformation stops before the end of the stream.
+pub fn xform_read_pairs_to_fifo_with_options(
+    geo_re: FragmentRegexDesc,
+    r1: Vec<PathBuf>,
+    r2: Vec<PathBuf>,
+    opts: XformOptions,
+) -> Result<FifoXFormData> {
+    if opts.interleaved {
+        bail!("Interleaved records are written into a single fifo by xform_read_pairs_to_interleaved_fifo");
+    }
+    let compression = [opts.out1.compression, opts.out2.compression];
+    let names = [
+        fifo_name("r1", compression[0]),
+        fifo_name("r2", compression[1]),
+    ];
+    let (mut fifos, join_handle, stats) = spawn_fifo_xform(geo_re, r1, r2, opts, &names)?;
+    let r2_fifo = fifos.pop().unwrap();
+    let r1_fifo = fifos.pop().unwrap();
+    Ok(FifoXFormData {
+        r1_fifo,
+        r2_fifo,
+        compression,
+        join_handle,
+        stats,
+    })
+}
 
-    // create new fifo and give read, write and execute rights to the owner
-    match unistd::mkfifo(&r1_fifo, stat::Mode::S_IRWXU) {
-        Ok(_) => {
-            info!("created {:?}", r1_fifo);
-            assert!(std::path::Path::new(&r1_fifo).exists());
-        }
-        Err(err) => bail!("Error creating read 1 fifo: {}", err),
+/// Like [`xform_read_pairs_to_fifo_with_options`], but the read 1 and read 2
+/// records of each fragment are written one after the other into a single
+/// fifo (see [`XformOptions::interleaved`]), for consumers reading
+/// interleaved pairs.  The records are written as configured by `opts.out1`.
+pub fn xform_read_pairs_to_interleaved_fifo(
+    geo_re: FragmentRegexDesc,
+    r1: Vec<PathBuf>,
+    r2: Vec<PathBuf>,
+    mut opts: XformOptions,
+) -> Result<InterleavedFifoXFormData> {
+    opts.interleaved = true;
+    let compression = opts.out1.compression;
+    let names = [fifo_name("interleaved", compression)];
+    let (mut fifos, join_handle, stats) = spawn_fifo_xform(geo_re, r1, r2, opts, &names)?;
+    Ok(InterleavedFifoXFormData {
+        fifo: fifos.pop().unwrap(),
+        compression,
+        join_handle,
+        stats,
+    })
+}
+
+/// The name of the fifo into which the records of `read` are written with the
+/// compression `c`.
+fn fifo_name(read: &str, c: Compression) -> String {
+    // consumers often recognize compressed input by its extension
+    match c.extension() {
+        Some(ext) => format!("{}.pipe.{}", read, ext),
+        None => format!("{}.pipe", read),
     }
-    // create new fifo and give read, write and execute rights to the owner
-    match unistd::mkfifo(&r2_fifo, stat::Mode::S_IRWXU) {
-        Ok(_) => {
-            info!("created {:?}", r2_fifo);
-            assert!(std::path::Path::new(&r2_fifo).exists());
-        }
-        Err(err) => bail!("Error creating read 2 fifo: {}", err),
+}
+
+/// Creates the fifos `names` (the read 1 fifo, followed by the read 2 fifo
+/// unless the records are interleaved) in a new temporary directory, and
+/// spawns the thread transforming the reads into them.  Returns the paths of
+/// the fifos, the handle of the thread and the live statistics.
+fn spawn_fifo_xform(
+    geo_re: FragmentRegexDesc,
+    r1: Vec<PathBuf>,
+    r2: Vec<PathBuf>,
+    mut opts: XformOptions,
+    names: &[String],
+) -> Result<(
+    Vec<PathBuf>,
+    thread::JoinHandle<Result<XformStats>>,
+    XformStatsHandle,
+)> {
+    if opts.split_output.is_some() {
+        bail!("Splitting the output into parts is not supported when writing to fifos");
+    }
+    let tmp_dir = tempdir()?;
+    let fifos: Vec<PathBuf> = names
+        .iter()
+        .map(|n| pipe::pipe_path(tmp_dir.path(), n))
+        .collect();
+
+    // the transformation, on its own thread, logs to the subscriber of the
+    // caller unless another one is given
+    let dispatch = opts
+        .dispatch
+        .get_or_insert_with(|| tracing::dispatcher::get_default(|d| d.clone()))
+        .clone();
+    let _dispatch_guard = tracing::dispatcher::set_default(&dispatch);
+
+    for (i, fifo) in fifos.iter().enumerate() {
+        pipe::create(fifo).with_context(|| format!("could not create fifo {}", names[i]))?;
+        info!("created {:?}", fifo);
     }
 
     // we clone this here because we want to move these into
     // the thread that will do the transformation but we need
-    // to retain a copy to pass to the FifoXFormData that we
-    // will return.
-    let r1_fifo_clone = r1_fifo.clone();
-    let r2_fifo_clone = r2_fifo.clone();
+    // to retain a copy to return.
+    let fifos_clone = fifos.clone();
+    let stats = opts
+        .progress
+        .get_or_insert_with(XformStatsHandle::new)
+        .clone();
 
     let join_handle: thread::JoinHandle<Result<XformStats>> = thread::spawn(move || {
-        let xform_stats = xform_read_pairs_to_file(geo_re, &r1, &r2, r1_fifo_clone, r2_fifo_clone)?;
+        // a panic (e.g. on a malformed record) is turned into an error, so
+        // that the fifos are still released and the temporary directory
+        // holding them is still removed
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            xform_read_pairs_to_file_with_options(
+                geo_re,
+                &r1,
+                &r2,
+                fifos_clone.first().cloned(),
+                fifos_clone.get(1).cloned(),
+                &opts,
+            )
+        }))
+        .unwrap_or_else(|payload| Err(WorkerPanic::from_payload(payload).into()));
+        if let Some(h) = &opts.progress {
+            // the transformation may have panicked before marking itself
+            // as completed
+            h.finish();
+        }
+        if res.is_err() {
+            // a consumer may still be waiting to open a fifo that was never
+            // opened for writing; make sure it sees an end of file instead
+            for fifo in &fifos_clone {
+                pipe::release(fifo);
+            }
+        }
         // Explicitly check for and propagate any errors encountered in the
         // closing and deleting of the temporary directory.  The directory
         // will be deleted when the handle goes out of scope, but without
         // calling this method, any encountered errors will be silently
         // ignored.
         // see: https://docs.rs/tempfile/latest/tempfile/struct.TempDir.html#method.close
-        match tmp_dir.close() {
+        let closed = tmp_dir.close();
+        let xform_stats = res?;
+        match closed {
             Ok(_) => Ok(xform_stats),
             Err(e) => {
                 bail!("When closing (deleting) the temp directory, the following error was encountered {:?}", e);
@@ -562,16 +3475,671 @@ pub fn xform_read_pairs_to_fifo(
         }
     });
 
-    Ok(FifoXFormData {
-        r1_fifo,
-        r2_fifo,
-        join_handle,
-    })
+    Ok((fifos, join_handle, stats))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::xform_in_dir;
+
+    #[test]
+    fn fifo_worker_panics_are_contained() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&r1, ">a\nACGTAC\n").unwrap();
+        std::fs::write(&r2, ">a\nTTTTTT\n").unwrap();
+        let opts = XformOptions {
+            record_hook: Some(RecordHookHandle::new(
+                |_: &mut hook::OutputRecord<'_>| -> Result<()> { panic!("malformed record") },
+            )),
+            ..XformOptions::default()
+        };
+        let data = xform_read_pairs_to_fifo_with_options(geo_re, vec![r1], vec![r2], opts).unwrap();
+        // open the fifos as a consumer would; the worker opens them before
+        // it panics on the first record
+        let mut f1 = std::fs::File::open(&data.r1_fifo).unwrap();
+        let mut f2 = std::fs::File::open(&data.r2_fifo).unwrap();
+        let mut out = vec![];
+        io::Read::read_to_end(&mut f1, &mut out).unwrap();
+        io::Read::read_to_end(&mut f2, &mut out).unwrap();
+        assert!(out.is_empty());
+
+        let err = data.join_handle.join().unwrap().unwrap_err();
+        assert!(err.downcast_ref::<WorkerPanic>().is_some());
+        assert!(data.stats.snapshot().finished);
+        assert!(!data.r1_fifo.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn gzipped_fifos() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&r1, ">a\nACGTAC\n>b\nTTTTTT\n").unwrap();
+        std::fs::write(&r2, ">a\nGG\n>b\nCC\n").unwrap();
+        let data = xform_read_pairs_to_gzipped_fifo(geo_re, vec![r1], vec![r2], 1).unwrap();
+        assert_eq!(data.compression, [Compression::Gzip(1); 2]);
+        assert!(data.r1_fifo.to_string_lossy().ends_with("r1.pipe.gz"));
+
+        let mut out = [String::new(), String::new()];
+        let f1 = std::fs::File::open(&data.r1_fifo).unwrap();
+        let f2 = std::fs::File::open(&data.r2_fifo).unwrap();
+        for (f, o) in [f1, f2].into_iter().zip(out.iter_mut()) {
+            io::Read::read_to_string(&mut flate2::read::MultiGzDecoder::new(f), o).unwrap();
+        }
+        assert_eq!(out, [">a\nACGT\n>b\nTTTT\n", ">a\nGG\n>b\nCC\n"]);
+        assert_eq!(data.join_handle.join().unwrap().unwrap().total_fragments, 2);
+    }
+
+    #[test]
+    fn interleaved_fifo() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&r1, ">a\nACGTAC\n>b\nTTTTTT\n").unwrap();
+        std::fs::write(&r2, ">a\nGG\n>b\nCC\n").unwrap();
+        let data = xform_read_pairs_to_interleaved_fifo(
+            geo_re,
+            vec![r1],
+            vec![r2],
+            XformOptions::default(),
+        )
+        .unwrap();
+        assert!(data.fifo.to_string_lossy().ends_with("interleaved.pipe"));
+        let mut out = String::new();
+        io::Read::read_to_string(&mut std::fs::File::open(&data.fifo).unwrap(), &mut out).unwrap();
+        assert_eq!(out, ">a\nACGT\n>a\nGG\n>b\nTTTT\n>b\nCC\n");
+        assert_eq!(data.join_handle.join().unwrap().unwrap().total_fragments, 2);
+    }
+
+    #[test]
+    fn unanchored_search() {
+        let gd = FragmentGeomDesc::try_from("1{f[CTACACGA]b[4]u[4]}2{r:}").unwrap();
+        let mut geo_re = gd.as_unanchored_regex().unwrap();
+        let mut anchored = gd.as_regex().unwrap();
+        let mut sp = SeqPair::new();
+        let construct = b"TTTTTTTTCTACACGAAAAACCCCGGGGGGGGGGGG";
+        assert!(!anchored.parse_into(construct, b"ACGT", &mut sp));
+        assert!(geo_re.parse_into(construct, b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"AAAACCCC");
+
+        // the construct is on the reverse strand
+        let mut rc = vec![];
+        reverse_complement_into(construct, &mut rc);
+        assert!(geo_re.parse_into(&rc, b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"AAAACCCC");
+        assert_eq!(sp.s2, b"ACGT");
+        assert!(!geo_re.parse_into(b"TTTTCTACACAAAACCCCGG", b"ACGT", &mut sp));
+    }
+
+    #[test]
+    fn reverse_complement_fallback() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{f[CTACACGA]b[4]u[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut sp = SeqPair::new();
+        let mut rc = vec![];
+        reverse_complement_into(b"CTACACGAAAAACCCCGG", &mut rc);
+        assert!(!geo_re.parse_into(&rc, b"ACGT", &mut sp));
+        geo_re.set_try_revcomp(true);
+        assert!(geo_re.parse_into(&rc, b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"AAAACCCC");
+        assert_eq!(geo_re.last_reversed(), [true, false]);
+        assert!(geo_re.parse_into(b"CTACACGAAAAACCCCGG", b"ACGT", &mut sp));
+        assert_eq!(geo_re.last_reversed(), [false, false]);
+
+        let mut counts = OrientationCounts::default();
+        counts.record([true, false]);
+        counts.record([false, false]);
+        counts.record([true, true]);
+        assert_eq!(counts.forward, 1);
+        assert_eq!(counts.reversed(), 2);
+    }
+
+    #[test]
+    fn anchor_mismatches() {
+        let gd = FragmentGeomDesc::try_from("1{b[3-4]f[CAGAGC]u[4]}2{r:}").unwrap();
+        let mut geo_re = gd.as_regex_with_anchor_mismatches(1).unwrap();
+        assert!(geo_re.tolerates_anchor_mismatches());
+        let mut sp = SeqPair::new();
+        assert!(geo_re.parse_into(b"AAAACAGAGCTTTT", b"ACGT", &mut sp));
+        assert!(!geo_re.last_rescued());
+        assert!(geo_re.parse_into(b"AAAACAGTGCTTTT", b"ACGT", &mut sp));
+        assert!(geo_re.last_rescued());
+        assert_eq!(sp.s1, b"AAAAATTTT");
+        assert!(!geo_re.parse_into(b"AAAACTGTGCTTTT", b"ACGT", &mut sp));
+
+        let mut geo_re = gd.as_regex_with_anchor_mismatches(2).unwrap();
+        assert!(geo_re.parse_into(b"AAACTGTGCTTTT", b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"AAAACTTTT");
+        assert_eq!(
+            fuzzy_anchor_regex_string("ACG", 2, "[ACGTN]", false).unwrap(),
+            "(?:[ACGTN][ACGTN]G|[ACGTN]C[ACGTN]|A[ACGTN][ACGTN])"
+        );
+        assert!(gd.as_regex_with_anchor_mismatches(6).is_err());
+        assert!(!FragmentGeomDesc::try_from("1{b[4]}2{r:}")
+            .unwrap()
+            .as_regex_with_anchor_mismatches(1)
+            .unwrap()
+            .tolerates_anchor_mismatches());
+    }
+
+    #[test]
+    fn extended_alphabet() {
+        let gd = FragmentGeomDesc::try_from("1{b[4]f[AC]u[2]}2{r:}").unwrap();
+        let mut sp = SeqPair::new();
+        assert!(!gd
+            .as_regex()
+            .unwrap()
+            .parse_into(b"ACGUACTT", b"ACGU", &mut sp));
+        let mut opts = RegexOptions::default();
+        opts.extend_alphabet("UN");
+        assert_eq!(opts.alphabet, "ACGTNU");
+        let mut geo_re = gd.as_regex_with_options(&opts).unwrap();
+        assert!(geo_re.parse_into(b"ACGUACTT", b"ACGU", &mut sp));
+        assert_eq!(sp.s1, b"ACGUTT");
+        assert_eq!(sp.s2, b"ACGU");
+        // the mismatched positions of the anchors also match the alphabet
+        opts.anchor_mismatches = 1;
+        let mut geo_re = gd.as_regex_with_options(&opts).unwrap();
+        assert!(geo_re.parse_into(b"ACGUAUTT", b"ACGU", &mut sp));
+        assert!(geo_re.last_rescued());
+
+        opts.alphabet = String::from("AC[GT");
+        assert!(gd.as_regex_with_options(&opts).is_err());
+        assert!(gd
+            .as_regex_with_options(&RegexOptions {
+                unanchored: true,
+                anchor_mismatches: 1,
+                ..RegexOptions::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn ambiguous_and_n_tolerant_anchors() {
+        let geo = FragmentGeomDesc {
+            read1_desc: vec![
+                GeomPiece::Barcode(GeomLen::FixedLen(4)),
+                GeomPiece::Fixed(NucStr::Seq(String::from("CAGRGC"))),
+                GeomPiece::Umi(GeomLen::FixedLen(2)),
+            ],
+            read2_desc: vec![GeomPiece::ReadSeq(GeomLen::Unbounded)],
+        };
+        let mut sp = SeqPair::new();
+        let mut geo_re = geo.as_regex().unwrap();
+        assert!(geo_re.parse_into(b"ACGTCAGAGCTT", b"AC", &mut sp));
+        assert_eq!(sp.s1, b"ACGTTT");
+        assert!(geo_re.parse_into(b"ACGTCAGGGCTT", b"AC", &mut sp));
+        assert!(!geo_re.parse_into(b"ACGTCAGCGCTT", b"AC", &mut sp));
+        assert!(!geo_re.parse_into(b"ACGTCNGAGCTT", b"AC", &mut sp));
+
+        let mut geo_re = geo
+            .as_regex_with_options(&RegexOptions {
+                n_tolerant_anchors: true,
+                ..RegexOptions::default()
+            })
+            .unwrap();
+        assert!(geo_re.parse_into(b"ACGTCNGAGCTT", b"AC", &mut sp));
+        assert!(geo_re.parse_into(b"ACGTCAGNGCTT", b"AC", &mut sp));
+        assert!(!geo_re.parse_into(b"ACGTCAGCGCTT", b"AC", &mut sp));
+
+        let mut geo_re = geo.as_regex_with_anchor_mismatches(1).unwrap();
+        assert!(geo_re.parse_into(b"ACGTCTGAGCTT", b"AC", &mut sp));
+        assert!(!geo_re.parse_into(b"ACGTCTGCGCTT", b"AC", &mut sp));
+    }
+
+    #[test]
+    fn max_len_padding() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[9-10]f[CAGAGC]u[4]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[11]u[4]}2{r:}"
+        );
+        assert_eq!(geo_re.output_read_lens(), [(1, Some(15)), (2, None)]);
+        geo_re.set_padding(PaddingScheme::MaxLen);
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[10]u[4]}2{r:}"
+        );
+        assert_eq!(geo_re.output_read_lens()[0], (1, Some(14)));
+        assert_eq!(
+            geo_re
+                .piece_spans(|gp| !matches!(gp, GeomPiece::ReadSeq(_)))
+                .unwrap(),
+            vec![(0, 0, 10), (0, 10, 4)]
+        );
+
+        let mut sp = SeqPair::new();
+        assert!(geo_re.parse_into(b"ACGTACGTACAGAGCTTTT", b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"ACGTACGTAATTTT");
+        assert_eq!(geo_re.last_lens(), [&[9_u32][..], &[][..]]);
+        assert!(geo_re.parse_into(b"ACGTACGTACCAGAGCTTTT", b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"ACGTACGTACTTTT");
+        assert_eq!(geo_re.last_lens()[0], [10_u32]);
+
+        let mut tag = b" feature=CD3".to_vec();
+        push_lens_tag(&mut tag, &[9, 10]);
+        assert_eq!(tag, b" feature=CD3 lens=9,10");
+        assert_eq!("h".parse::<PaddingScheme>().unwrap(), PaddingScheme::MaxLen);
+        assert_eq!(PaddingScheme::Distinct.to_string(), "h+1");
+    }
+
+    #[test]
+    fn padding_has_the_lowest_quality() {
+        let geo = FragmentGeomDesc::try_from("1{b[3-4]f[ACG]u[2-3]}2{r:}").unwrap();
+        let mut geo_re = geo.as_regex().unwrap();
+        let mut sp = SeqPair::new();
+        assert!(geo_re.parse_into(b"TTTACGCC", b"GG", &mut sp));
+        assert_eq!(sp.s1, b"TTTACCCAC");
+        assert_eq!(geo_re.last_padding(), [&[3_u32..5, 7..9][..], &[][..]]);
+
+        // the padding is kept through the sort by barcode, even once spilled
+        // to disk
+        let dir = tempdir().unwrap();
+        let spilled = SortOptions {
+            chunk_size: 1,
+            tmp_dir: None,
+        };
+        for sort_by_barcode in [None, Some(spilled)] {
+            let out = xform_in_dir(
+                dir.path(),
+                geo.as_regex().unwrap(),
+                ">a\nTTTACGCC\n>b\nTTTTACGCCC\n",
+                ">a\nGG\n>b\nGGG\n",
+                &XformOptions {
+                    format: OutputFormat::Fastq,
+                    sort_by_barcode,
+                    ..XformOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(
+                out.out1,
+                "@a\nTTTACCCAC\n+\nIII##II##\n@b\nTTTTACCCA\n+\nIIII#III#\n"
+            );
+            assert_eq!(out.out2, "@a\nGG\n+\nII\n@b\nGGG\n+\nIII\n");
+        }
+    }
+
+    #[test]
+    fn wide_length_ranges() {
+        // 8 distinct lengths take a suffix of 2 bases
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[2-9]f[ACG]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[11]u[2]}2{r:}"
+        );
+        let mut sp = SeqPair::new();
+        assert!(geo_re.parse_into(b"TTACGGG", b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"TTAAAAAAACTGG");
+        assert!(geo_re.parse_into(b"TTTTTTACGGG", b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"TTTTTTAAAATGG");
+        assert!(geo_re.parse_into(b"TTTTTTTTTACGGG", b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"TTTTTTTTTAAGG");
+        assert_eq!((len_code_width(1, 4), len_code_width(1, 5)), (1, 2));
+        assert_eq!(len_code_width(0, 16), 3);
+    }
+
+    #[test]
+    fn batches_are_transformed_in_memory() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[2-3]f[ACG]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let pairs: [(&[u8], &[u8]); 3] = [
+            (b"AACACGTT", b"GGCC"),
+            (b"AAAAAAAA", b"GGCC"),
+            (b"CCACGGA", b"TTA"),
+        ];
+        let mut out: Vec<Option<SeqPair>> = (0..5).map(|_| None).collect();
+        out[1] = Some(SeqPair::new());
+        geo_re.transform_batch(&pairs, &mut out);
+        let seqs = out
+            .iter()
+            .map(|sp| sp.as_ref().map(|sp| (&sp.s1[..], &sp.s2[..])))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            seqs,
+            [
+                Some((&b"AACATT"[..], &b"GGCC"[..])),
+                None,
+                Some((&b"CCACGA"[..], &b"TTA"[..]))
+            ]
+        );
+        geo_re.transform_batch(&pairs[1..2], &mut out);
+        assert!(matches!(out[..], [None]));
+    }
+
+    #[test]
+    fn single_reads_are_parsed() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[2-3]f[ACG]u[2]}2{x[2]r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut s = b"stale".to_vec();
+        assert!(geo_re.parse_read_into(1, b"CCACGGA", &mut s));
+        assert_eq!(s, b"CCACGA");
+        assert_eq!(geo_re.last_lens()[0], [2]);
+        assert!(geo_re.parse_read_into(2, b"TTACGT", &mut s));
+        assert_eq!(s, b"ACGT");
+        assert!(!geo_re.parse_read_into(1, b"AAAAAAAA", &mut s));
+        assert!(!geo_re.parse_read_into(3, b"CCACGGA", &mut s));
+    }
+
+    #[test]
+    fn reads_are_transformed_as_bytes() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[2]u[2]r:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut sp = SeqPair::new();
+        assert!(geo_re.parse_into(b"ACGTTTN", b"NT", &mut sp));
+        assert_eq!((&sp.s1[..], &sp.s2[..]), (&b"ACGTTTN"[..], &b"NT"[..]));
+        // bytes outside of the alphabet (which may not be UTF-8) do not match
+        assert!(!geo_re.parse_into(b"ACGT\xffT", b"NT", &mut sp));
+        assert!(!geo_re.parse_into(b"ACGTacg", b"NT", &mut sp));
+
+        // nor are the headers required to be UTF-8
+        let dir = tempdir().unwrap();
+        let (o1, o2) = (dir.path().join("o1.fq"), dir.path().join("o2.fq"));
+        let cfg = SinkConfig::default();
+        let mut w =
+            PairedWriter::new(Some(o1.clone()), Some(o2.clone()), &cfg, &cfg, None).unwrap();
+        let headers = [(&b"r\xe9"[..], &b" x=\xff"[..]), (&b"r\xe9"[..], &b""[..])];
+        assert!(geo_re.parse_into(b"ACGTTTN", b"NT", &mut sp));
+        write_fragment(
+            &mut w,
+            OutputFormat::Fastq,
+            headers,
+            &sp,
+            geo_re.last_padding(),
+        )
+        .unwrap();
+        w.finish().unwrap();
+        assert_eq!(
+            std::fs::read(o1).unwrap(),
+            b"@r\xe9 x=\xff\nACGTTTN\n+\nIIIIIII\n"
+        );
+        assert_eq!(std::fs::read(o2).unwrap(), b"@r\xe9\nNT\n+\nII\n");
+    }
+
+    #[test]
+    fn capture_groups_match_pieces() {
+        let barcode = [GeomPiece::Barcode(GeomLen::FixedLen(4))];
+        assert!(check_capture_groups(1, &Regex::new("^([ACGTN]{4})").unwrap(), &barcode).is_ok());
+        let err = check_capture_groups(2, &Regex::new("^([ACGTN]{4})(A)").unwrap(), &barcode)
+            .unwrap_err();
+        let mismatch = err.downcast_ref::<CaptureGroupMismatch>().unwrap();
+        assert_eq!(
+            (
+                mismatch.read,
+                mismatch.capture_groups,
+                mismatch.captured_pieces
+            ),
+            (2, 2, 1)
+        );
+    }
+
+    #[test]
+    fn unmatched_fragments_are_kept() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]f[ACG]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fq"), dir.path().join("r2.fq"));
+        std::fs::write(
+            &r1,
+            "@a x\nTTTTACGA\n+\nIIIIIIII\n@b y\nTTTTTTTT\n+\nABCDEFGH\n",
+        )
+        .unwrap();
+        std::fs::write(&r2, "@a x\nGGG\n+\nIII\n@b y\nCCC\n+\n#II\n").unwrap();
+        let (u1, u2) = (dir.path().join("u1.fq"), dir.path().join("u2.fq.gz"));
+        let opts = XformOptions {
+            unmatched1: Some(u1.clone()),
+            unmatched2: Some(u2.clone()),
+            ..XformOptions::default()
+        };
+        let stats = xform_read_pairs_to_file_with_options(
+            geo_re,
+            &[r1],
+            &[r2],
+            Some(dir.path().join("o1.fa")),
+            Some(dir.path().join("o2.fa")),
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(stats.failed_parsing, 1);
+        assert_eq!(
+            std::fs::read_to_string(u1).unwrap(),
+            "@b y\nTTTTTTTT\n+\nABCDEFGH\n"
+        );
+        let mut u2_text = String::new();
+        io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(File::open(u2).unwrap()),
+            &mut u2_text,
+        )
+        .unwrap();
+        assert_eq!(u2_text, "@b y\nCCC\n+\n#II\n");
+    }
+
+    #[test]
+    fn zstd_inputs_are_read() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fq.zst"), dir.path().join("r2.fq.zst"));
+        let zstd = |text: &str| zstd::encode_all(text.as_bytes(), 3).unwrap();
+        std::fs::write(&r1, zstd("@a\nACGTTT\n+\nIIIIII\n")).unwrap();
+        std::fs::write(&r2, zstd("@a\nGGCC\n+\nIIII\n")).unwrap();
+        // the outputs mirror the compression of the inputs by default
+        assert_eq!(
+            output::detect_compression(&r1).unwrap(),
+            Compression::Zstd(Compression::DEFAULT_ZSTD_LEVEL)
+        );
+        let (o1, o2) = (dir.path().join("o1.fa"), dir.path().join("o2.fa"));
+        let stats = xform_read_pairs_to_file_with_options(
+            geo_re,
+            &[r1],
+            &[r2],
+            Some(o1.clone()),
+            Some(o2.clone()),
+            &XformOptions::default(),
+        )
+        .unwrap();
+        assert_eq!((stats.total_fragments, stats.failed_parsing), (1, 0));
+        assert_eq!(std::fs::read_to_string(o1).unwrap(), ">a\nACGT\n");
+        assert_eq!(std::fs::read_to_string(o2).unwrap(), ">a\nGGCC\n");
+    }
+
+    #[test]
+    fn per_pair_geometries() {
+        let compile = |gd: &str| FragmentGeomDesc::try_from(gd).unwrap().as_regex().unwrap();
+        let dir = tempdir().unwrap();
+        let (o1, o2) = (dir.path().join("o1.fa"), dir.path().join("o2.fa"));
+        let inputs = [dir.path().join("a.fa"), dir.path().join("b.fa")];
+        for (p, seq) in inputs.iter().zip(["ACGTACGTCAGAGC", "TTACGTACGTCAGAGC"]) {
+            std::fs::write(p, format!(">r\n{}\n", seq)).unwrap();
+        }
+
+        let stats = xform_read_pairs_to_file_with_geometries(
+            vec![compile("1{b[8]x:}2{x:}"), compile("1{x[2]b[8]x:}2{x:}")],
+            &inputs,
+            &inputs,
+            Some(o1.clone()),
+            None,
+            &XformOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.failed_parsing, 0);
+        assert_eq!(stats.input_pairs.len(), 2);
+        assert_eq!(stats.input_pairs[1].geometry, 1);
+        assert_eq!(
+            std::fs::read_to_string(&o1).unwrap(),
+            ">r\nACGTACGT\n>r\nACGTACGT\n"
+        );
+
+        // the simplified geometries must agree
+        assert!(xform_read_pairs_to_file_with_geometries(
+            vec![compile("1{b[8]x:}2{r:}"), compile("1{b[9]x:}2{r:}")],
+            &inputs,
+            &inputs,
+            Some(o1),
+            Some(o2),
+            &XformOptions::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn len_range_limits() {
+        for (gd, ok) in [
+            ("1{b[7-10]}2{r:}", true),
+            ("1{b[6-10]}2{r:}", true),
+            ("1{x[6-10]b[16]}2{r:}", true),
+        ] {
+            let geo = FragmentGeomDesc::try_from(gd).unwrap();
+            assert_eq!(geo.as_regex().is_ok(), ok, "{}", gd);
+        }
+
+        // every permitted length receives a distinct padding
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[7-10]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut sp = SeqPair::new();
+        for len in 7..=10 {
+            let r1 = "ACGTACGTAC".get(..len).unwrap();
+            assert!(geo_re.parse_into(r1.as_bytes(), b"ACGT", &mut sp));
+            assert_eq!(sp.s1.len(), 11);
+        }
+    }
+
+    #[test]
+    fn stats_tsv_row() {
+        let stats = XformStats {
+            total_fragments: 200,
+            failed_parsing: 50,
+            ..XformStats::default()
+        };
+        let mut out = Vec::new();
+        stats
+            .write_tsv(&mut out, "1{b[16]u[12]}2{r:}", Duration::from_secs(2))
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], XformStats::TSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "1{b[16]u[12]}2{r:}\th+1\t200\t50\t0.750000\t2.000\t100.0\t0.750000\t0.250000"
+        );
+    }
+
+    #[test]
+    fn stats_json() {
+        let mut failures = FailureCounts::new();
+        for _ in 0..3 {
+            failures.record(ParseFailure {
+                read: 1,
+                cause: FailureCause::AnchorNotFound { piece: 1 },
+            });
+        }
+        failures.record(ParseFailure {
+            read: 2,
+            cause: FailureCause::TooShort,
+        });
+        let stats = XformStats {
+            total_fragments: 200,
+            failed_parsing: 4,
+            failures: Some(failures),
+            ..XformStats::default()
+        };
+        let mut out = Vec::new();
+        stats
+            .write_json(&mut out, "1{b[4]f[ACG]}2{r:}", Duration::from_secs(2))
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(
+            "{\n  \"geometry\": \"1{b[4]f[ACG]}2{r:}\",\n  \"padding\": \"h+1\",\n  \"seed\": 0"
+        ));
+        assert!(out.contains("\"match_rate\": 0.980000"));
+        assert!(out.contains("\"pass_rate\": 0.980000,\n  \"fail_rate\": 0.020000"));
+        assert!(out.contains("\"duplicate_fragments\": null"));
+        assert!(out.contains(r#"{"read": 1, "piece": 2, "cause": "anchor_not_found", "count": 3}"#));
+        assert!(out.contains(r#"{"read": 2, "piece": null, "cause": "too_short", "count": 1}"#));
+        assert!(out.ends_with("  ]\n}\n"));
+
+        // the statistics of each pair of input files
+        let stats = XformStats {
+            input_pairs: vec![InputPairStats {
+                r1: PathBuf::from("lane1_R1.fq"),
+                r2: PathBuf::from("lane1_R2.fq"),
+                geometry: 0,
+                total_fragments: 8,
+                failed_parsing: 2,
+                pairing: PairingCounts::default(),
+            }],
+            ..XformStats::default()
+        };
+        let mut out = Vec::new();
+        stats.write_json(&mut out, "", Duration::ZERO).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            r#"{"r1": "lane1_R1.fq", "r2": "lane1_R2.fq", "geometry": 0, "total_fragments": 8, "failed_parsing": 2, "fail_rate": 0.250000, "pairing": {"mismatched_names": 0, "unpaired_r1": 0, "unpaired_r2": 0}}"#
+        ));
+    }
+
+    #[test]
+    fn stats_display() {
+        let mut failures = FailureCounts::new();
+        for (read, cause) in [
+            (1, FailureCause::AnchorNotFound { piece: 1 }),
+            (1, FailureCause::AnchorNotFound { piece: 1 }),
+            (1, FailureCause::TooShort),
+            (2, FailureCause::TooShort),
+        ] {
+            failures.record(ParseFailure { read, cause });
+        }
+        let stats = XformStats {
+            total_fragments: 2000,
+            failed_parsing: 4,
+            failures: Some(failures),
+            duplicate_fragments: Some(499),
+            ..XformStats::default()
+        };
+        assert_eq!(
+            stats.to_string(),
+            "XformStats { \n    total fragments: 2,000,\n    fragments failing parsing: 4 (0.20%),\n    \
+             fragments transformed: 1,996 (99.80%),\n    \
+             fragments failing parsing on read 1: 3 (0.15%): too_short 1, anchor_not_found (piece 2) 2,\n    \
+             fragments failing parsing on read 2: 1 (0.05%): too_short 1,\n    \
+             duplicate fragments: 499 (25.00%),\n}"
+        );
+        assert!((stats.pass_rate() + stats.fail_rate() - 1_f64).abs() < 1e-12);
+        assert_eq!(XformStats::default().pass_rate(), 0_f64);
+    }
 
     /// This test checks that technical reads from
     /// sciseq v3 can be properly parsed.  This is a set
@@ -627,10 +4195,10 @@ mod tests {
                         dbg!("tr = {}, sp = {:?}", &tr, &sp);
                         match pref_len {
                             9 => {
-                                assert_eq!(&sp.s1[9..11], VAR_LEN_BC_PADDING[1]);
+                                assert_eq!(&sp.s1[9..11], b"AC");
                             }
                             10 => {
-                                assert_eq!(&sp.s1[10..11], VAR_LEN_BC_PADDING[0]);
+                                assert_eq!(&sp.s1[10..11], b"A");
                             }
                             _ => {
                                 panic!("shouldn't happen");
@@ -644,4 +4212,30 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn at_most_one_input_from_stdin() {
+        let stdin = PathBuf::from(STDIN_PATH);
+        let file = PathBuf::from("r2.fq.gz");
+        assert!(is_stdin(&stdin) && !is_stdin(&file));
+        assert_eq!(
+            input_groups(
+                std::slice::from_ref(&stdin),
+                std::slice::from_ref(&file),
+                InputFormat::FromExtension
+            )
+            .unwrap(),
+            [(std::slice::from_ref(&stdin), std::slice::from_ref(&file))]
+        );
+        assert!(input_groups(
+            std::slice::from_ref(&stdin),
+            std::slice::from_ref(&stdin),
+            InputFormat::FromExtension
+        )
+        .is_err());
+        assert_eq!(
+            output::detect_compression(&stdin).unwrap(),
+            Compression::None
+        );
+    }
 }
@@ -0,0 +1,318 @@
+//! Recovery from malformed input records.
+//!
+//! A single corrupt record (e.g. a truncated record, or a quality line
+//! shorter than its sequence) makes the parser of the input fail, and by
+//! default fails the whole run ([`OnError::Abort`]).  With
+//! [`OnError::Skip`] or [`OnError::Count`], the `FASTQ` inputs are instead
+//! checked record by record before they are parsed: each run of lines that
+//! do not form a valid record (a header starting with `@`, a sequence, a
+//! separator starting with `+`, and qualities as long as the sequence) is
+//! taken to be a single malformed record, up to the next valid record.  It
+//! is replaced by a placeholder, so that the records of the other input
+//! files of the fragment stay in step, and the fragments holding a
+//! placeholder are dropped and counted in
+//! [`crate::XformStats::malformed_fragments`] rather than transformed.
+//!
+//! If the lines of a malformed record span several records, the records of
+//! the pair of input files no longer match, which the pairing checks report
+//! (see the [`crate::pairing`] module).  Checking the records costs a copy
+//! of the input, and only gzip and zstd compressed (or uncompressed) inputs
+//! can be checked.  `FASTA` inputs and unaligned BAM files are parsed as
+//! they are.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+/// The id of the placeholders of the malformed records.
+pub(crate) const MALFORMED_ID: &[u8] = b"\x01malformed";
+
+/// What to do with the malformed records of the inputs (see the module
+/// documentation).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Fail at the first malformed record.
+    #[default]
+    Abort,
+    /// Drop the fragments holding a malformed record, logging a warning for
+    /// each of them, and count them.
+    Skip,
+    /// Drop the fragments holding a malformed record, only counting them.
+    Count,
+}
+
+impl std::str::FromStr for OnError {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(OnError::Abort),
+            "skip" => Ok(OnError::Skip),
+            "count" => Ok(OnError::Count),
+            _ => bail!("unknown error policy {}; expected abort, skip or count", s),
+        }
+    }
+}
+
+impl fmt::Display for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OnError::Abort => "abort",
+            OnError::Skip => "skip",
+            OnError::Count => "count",
+        })
+    }
+}
+
+/// Returns the input `r` of the file `p`, decompressed and, if it holds
+/// `FASTQ` records, with its malformed records replaced by placeholders (see
+/// the module documentation).  A warning is logged for each of them if
+/// `on_error` is [`OnError::Skip`].
+pub(crate) fn recovering(
+    r: Box<dyn Read + Send>,
+    p: &Path,
+    on_error: OnError,
+) -> Result<Box<dyn Read + Send>> {
+    let mut r = BufReader::new(r);
+    let head = r.fill_buf()?;
+    let r: Box<dyn Read + Send> = if head.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::MultiGzDecoder::new(r))
+    } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::stream::read::Decoder::with_buffer(r)?)
+    } else if head.starts_with(b"BZh") || head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z']) {
+        bail!(
+            "the malformed records of {} cannot be skipped, as only gzip and zstd compressed inputs are checked",
+            p.display()
+        );
+    } else {
+        Box::new(r)
+    };
+    let mut r = BufReader::new(r);
+    if r.fill_buf()?.first() != Some(&b'@') {
+        return Ok(Box::new(r));
+    }
+    Ok(Box::new(FastqRepair {
+        inner: r,
+        path: p.to_path_buf(),
+        warn: on_error == OnError::Skip,
+        lines: VecDeque::new(),
+        line: 1,
+        eof: false,
+        out: vec![],
+        pos: 0,
+    }))
+}
+
+/// A `FASTQ` input whose malformed records are replaced by placeholders.
+struct FastqRepair<R> {
+    inner: R,
+    path: PathBuf,
+    /// Whether to log a warning for each malformed record.
+    warn: bool,
+    /// The lines read ahead, without their line ending.
+    lines: VecDeque<Vec<u8>>,
+    /// The number of the first line of `lines` in the input.
+    line: u64,
+    eof: bool,
+    /// The records to be read, from `pos` on.
+    out: Vec<u8>,
+    pos: usize,
+}
+
+/// Returns `l` without its trailing carriage return.
+fn trim_cr(l: &[u8]) -> &[u8] {
+    l.strip_suffix(b"\r").unwrap_or(l)
+}
+
+impl<R: BufRead> FastqRepair<R> {
+    /// Reads ahead until `n` lines are available, or the input ends.
+    fn fill(&mut self, n: usize) -> io::Result<()> {
+        while self.lines.len() < n && !self.eof {
+            let mut l = vec![];
+            if self.inner.read_until(b'\n', &mut l)? == 0 {
+                self.eof = true;
+            } else {
+                if l.last() == Some(&b'\n') {
+                    l.pop();
+                }
+                self.lines.push_back(l);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the first 4 lines read ahead form a valid record.
+    fn check(&self) -> std::result::Result<(), &'static str> {
+        if self.lines.len() < 4 {
+            return Err("truncated record");
+        }
+        let l = |i: usize| trim_cr(&self.lines[i]);
+        if l(0).first() != Some(&b'@') {
+            Err("header not starting with @")
+        } else if l(2).first() != Some(&b'+') {
+            Err("separator not starting with +")
+        } else if l(1).len() != l(3).len() {
+            Err("sequence and qualities of different lengths")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn pop_line(&mut self) {
+        self.lines.pop_front();
+        self.line += 1;
+    }
+
+    /// Adds the next record, or the placeholder of the next malformed
+    /// record, to `out`.  Returns false at the end of the input.
+    fn next_record(&mut self) -> io::Result<bool> {
+        self.fill(4)?;
+        // blank lines between records (e.g. at the end of the input)
+        while self.lines.front().is_some_and(|l| trim_cr(l).is_empty()) {
+            self.pop_line();
+            self.fill(4)?;
+        }
+        if self.lines.is_empty() {
+            return Ok(false);
+        }
+        let Err(why) = self.check() else {
+            for _ in 0..4 {
+                self.out.extend_from_slice(&self.lines[0]);
+                self.out.push(b'\n');
+                self.pop_line();
+            }
+            return Ok(true);
+        };
+        if self.warn {
+            warn!(
+                "skipping the malformed record at line {} of {} ({})",
+                self.line,
+                self.path.display(),
+                why
+            );
+        }
+        // the malformed record extends up to the next valid record
+        self.pop_line();
+        loop {
+            self.fill(4)?;
+            if self.lines.is_empty() || self.check().is_ok() {
+                break;
+            }
+            self.pop_line();
+        }
+        self.out.extend_from_slice(b"@");
+        self.out.extend_from_slice(MALFORMED_ID);
+        self.out.extend_from_slice(b"\nN\n+\n!\n");
+        Ok(true)
+    }
+}
+
+impl<R: BufRead> Read for FastqRepair<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            if !self.next_record()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use std::io::Write;
+
+    fn repaired(input: Vec<u8>) -> Result<String> {
+        let mut out = String::new();
+        recovering(
+            Box::new(io::Cursor::new(input)),
+            Path::new("r1.fq"),
+            OnError::Count,
+        )?
+        .read_to_string(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn malformed_records_are_replaced() {
+        let placeholder = "@\x01malformed\nN\n+\n!\n";
+        // CRLF line endings are kept, and blank lines dropped
+        assert_eq!(
+            repaired(b"@a\r\nAC\r\n+\r\nII\r\n\n\n@b\nGG\n+\nII".to_vec()).unwrap(),
+            "@a\r\nAC\r\n+\r\nII\r\n@b\nGG\n+\nII\n"
+        );
+        // a malformed record extends up to the next valid one
+        assert_eq!(
+            repaired(b"@a\nAC\n+\nI\nx\n@b\nGG\n+\nII\n".to_vec()).unwrap(),
+            format!("{}@b\nGG\n+\nII\n", placeholder)
+        );
+        assert_eq!(
+            repaired(b"@a\nAC\n-\nII\n@b\nGG\n+\nII\n@c\nTT\n".to_vec()).unwrap(),
+            format!("{}@b\nGG\n+\nII\n{}", placeholder, placeholder)
+        );
+        // FASTA inputs are not checked
+        let fasta = ">a\nAC\n>b\n";
+        assert_eq!(repaired(fasta.as_bytes().to_vec()).unwrap(), fasta);
+        assert_eq!(repaired(vec![]).unwrap(), "");
+
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        gz.write_all(b"@a\nAC\n+\nI\n").unwrap();
+        assert_eq!(repaired(gz.finish().unwrap()).unwrap(), placeholder);
+        let zst = zstd::encode_all(&b"@a\nAC\n+\nII\n"[..], 1).unwrap();
+        assert_eq!(repaired(zst).unwrap(), "@a\nAC\n+\nII\n");
+        let err = repaired(b"BZh91AY&SY".to_vec()).unwrap_err();
+        assert!(err.to_string().contains("cannot be skipped"));
+
+        for policy in [OnError::Abort, OnError::Skip, OnError::Count] {
+            assert_eq!(policy.to_string().parse::<OnError>().unwrap(), policy);
+        }
+        assert!("ignore".parse::<OnError>().is_err());
+    }
+
+    #[test]
+    fn malformed_records_are_skipped() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let run = |on_error| {
+            // the qualities of b are truncated, and d is missing its separator
+            xform_in_dir(
+                dir.path(),
+                geo_re.clone(),
+                "@a\nACGTTTC\n+\nIIIIIII\n@b\nACGTAA\n+\nII\n@c\nGGGGAA\n+\nIIIIII\n\
+                 @d\nTTTTCC\nIIIIII\n@e\nCCCCTT\n+\nIIIIII\n",
+                "@a\nCCCC\n+\nIIII\n@b\nGG\n+\nII\n@c\nAA\n+\nII\n@d\nTT\n+\nII\n@e\nGG\n+\nII\n",
+                &XformOptions {
+                    on_error,
+                    ..XformOptions::default()
+                },
+            )
+        };
+        assert!(run(OnError::Abort).is_err());
+        for on_error in [OnError::Skip, OnError::Count] {
+            let out = run(on_error).unwrap();
+            assert_eq!(out.stats.malformed_fragments, Some(2));
+            assert_eq!(
+                (out.stats.total_fragments, out.stats.failed_parsing),
+                (3, 0)
+            );
+            assert_eq!(out.out1, ">a\nACGTTT\n>c\nGGGGAA\n>e\nCCCCTT\n");
+            assert_eq!(out.out2, ">a\nCCCC\n>c\nAA\n>e\nGG\n");
+        }
+    }
+}
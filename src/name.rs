@@ -0,0 +1,262 @@
+//! Pieces captured from the read names.
+//!
+//! Some of the information identifying a fragment is only found in the name
+//! of its reads: the sample index of Illumina headers (e.g.
+//! `@M00:1:FC:1:1101:1000:2000 1:N:0:ACGTACGT`), or the lane and the tile,
+//! which can be made part of a synthetic barcode.  A [`NameCapture`] matches a
+//! regex against the name of read 1 of each fragment, and turns each of its
+//! capture groups into a fixed-length piece appended to the pieces captured
+//! from one of the reads (see
+//! [`crate::FragmentRegexDesc::set_name_capture`]), so that the captured
+//! pieces are part of the transformed read, and of its simplified geometry,
+//! like the pieces captured from its sequence.  A capture made of
+//! nucleotides is copied as it is, and a capture made of digits (e.g. a lane
+//! or a tile number) is written in base 4, with the digits `ACGT`, padded
+//! with `A`s to the length of its piece.  A fragment whose read name does not
+//! match the regex, or whose captures do not fit their pieces, fails to
+//! parse.
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::bytes::Regex;
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece};
+
+/// The regex matched against the read names, and the pieces made of its
+/// capture groups.
+#[derive(Debug, Clone)]
+pub struct NameCapture {
+    regex: Regex,
+    /// The piece of each capture group, in order.
+    pieces: Vec<GeomPiece>,
+    /// The read (`1` or `2`) to whose captured pieces the pieces are
+    /// appended.
+    read: usize,
+}
+
+impl NameCapture {
+    /// Create a new `NameCapture` making a piece `pieces[i]` of the capture
+    /// group `i + 1` of `regex`, appended to the pieces of read `read`.  The
+    /// pieces must be fixed-length barcode (`b`), UMI (`u`) or read (`r`)
+    /// pieces, one for each capture group of the regex.
+    pub fn new(regex: &str, pieces: Vec<GeomPiece>, read: usize) -> Result<Self> {
+        let regex = Regex::new(regex)
+            .with_context(|| format!("invalid regex {} over the read names", regex))?;
+        if !(1..=2).contains(&read) {
+            bail!("the pieces captured from the read names can only be added to read 1 or 2");
+        }
+        let groups = regex.captures_len() - 1;
+        if groups == 0 || groups != pieces.len() {
+            bail!(
+                "the regex {} over the read names has {} capture groups, but {} pieces are captured from them",
+                regex.as_str(),
+                groups,
+                pieces.len()
+            );
+        }
+        for gp in &pieces {
+            if !matches!(
+                gp,
+                GeomPiece::Barcode(GeomLen::FixedLen(_))
+                    | GeomPiece::Umi(GeomLen::FixedLen(_))
+                    | GeomPiece::ReadSeq(GeomLen::FixedLen(_))
+            ) {
+                bail!(
+                    "the pieces captured from the read names must be fixed-length barcode, UMI \
+                     or read pieces, but found {:?}",
+                    gp
+                );
+            }
+        }
+        Ok(Self {
+            regex,
+            pieces,
+            read,
+        })
+    }
+
+    /// Like [`NameCapture::new`], with the pieces given in the syntax of a
+    /// geometry (e.g. `b[1]b[6]`).
+    pub fn from_description(regex: &str, pieces: &str, read: usize) -> Result<Self> {
+        let desc = format!("1{{{}}}2{{r:}}", pieces);
+        let geo = FragmentGeomDesc::try_from(desc.as_str()).map_err(|e| {
+            anyhow!(
+                "could not parse the pieces {} captured from the read names: {:?}",
+                pieces,
+                e
+            )
+        })?;
+        Self::new(regex, geo.read1_desc, read)
+    }
+
+    /// The read (`1` or `2`) to whose pieces the captured pieces are
+    /// appended.
+    pub fn read(&self) -> usize {
+        self.read
+    }
+
+    /// The pieces made of the capture groups, in order.
+    pub fn pieces(&self) -> &[GeomPiece] {
+        &self.pieces
+    }
+
+    /// Appends the pieces captured from the read name `name` to `out`.
+    /// Returns false if the name does not match the regex, or if one of the
+    /// captures does not fit its piece.
+    pub(crate) fn capture_into(&self, name: &[u8], out: &mut Vec<u8>) -> bool {
+        let Some(caps) = self.regex.captures(name) else {
+            return false;
+        };
+        for (i, gp) in self.pieces.iter().enumerate() {
+            let (GeomPiece::Barcode(GeomLen::FixedLen(len))
+            | GeomPiece::Umi(GeomLen::FixedLen(len))
+            | GeomPiece::ReadSeq(GeomLen::FixedLen(len))) = gp
+            else {
+                return false;
+            };
+            let Some(c) = caps.get(i + 1).map(|m| m.as_bytes()) else {
+                return false;
+            };
+            if !push_capture(c, *len as usize, out) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Appends the capture `c` as a piece of length `len` to `out`: as it is if
+/// it is made of nucleotides, or, if it is a number, in base 4.  Returns
+/// false if it is neither, or does not fit.
+fn push_capture(c: &[u8], len: usize, out: &mut Vec<u8>) -> bool {
+    if c.len() == len && c.iter().all(|b| b"ACGTNacgtn".contains(b)) {
+        out.extend(c.iter().map(u8::to_ascii_uppercase));
+        return true;
+    }
+    if c.is_empty() || !c.iter().all(u8::is_ascii_digit) {
+        return false;
+    }
+    // the number is at most 4^len - 1
+    let Some(n) = std::str::from_utf8(c)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .filter(|&n| len >= 32 || n < 1 << (2 * len))
+    else {
+        return false;
+    };
+    for d in (0..len).rev() {
+        let digit = if d >= 32 { 0 } else { (n >> (2 * d)) & 3 };
+        out.push(b"ACGT"[digit as usize]);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use tempfile::tempdir;
+
+    #[test]
+    fn captures_are_written_as_pieces() {
+        let push = |c: &[u8], len| {
+            let mut out = b"TT".to_vec();
+            push_capture(c, len, &mut out).then(|| String::from_utf8(out[2..].to_vec()).unwrap())
+        };
+        assert_eq!(push(b"acgN", 4).as_deref(), Some("ACGN"));
+        // numbers are written in base 4, padded with As
+        assert_eq!(push(b"6", 2).as_deref(), Some("CG"));
+        assert_eq!(push(b"15", 2).as_deref(), Some("TT"));
+        assert_eq!(push(b"0", 3).as_deref(), Some("AAA"));
+        assert_eq!(push(b"0017", 4).as_deref(), Some("ACAC"));
+        assert_eq!(push(b"3", 33).unwrap(), format!("{}T", "A".repeat(32)));
+        assert_eq!(push(b"16", 2), None);
+        assert_eq!(push(b"ACG", 2), None);
+        assert_eq!(push(b"1a", 2), None);
+        assert_eq!(push(b"", 2), None);
+        assert_eq!(push(b"99999999999999999999", 40), None);
+    }
+
+    #[test]
+    fn pieces_are_captured_from_the_read_names() {
+        // the lane and the sample index of Illumina headers
+        let nc =
+            NameCapture::from_description(r"^\S+:(\d+):\d+:\d+:\d+ \S+:([ACGTN]+)$", "b[2]b[4]", 1)
+                .unwrap();
+        let mut out = Vec::new();
+        assert!(nc.capture_into(b"M0:1:FC:3:1101:10:20 1:N:0:ACGT", &mut out));
+        assert_eq!(out, b"ATACGT");
+        // lane 17 does not fit in 2 bases
+        assert!(!nc.capture_into(b"M0:1:FC:17:1101:10:20 1:N:0:ACGT", &mut out));
+        assert!(!nc.capture_into(b"M0:1:FC:3:1101:10:20 1:N:0:ACG", &mut out));
+        assert!(!nc.capture_into(b"read1", &mut out));
+        // an optional group that did not take part in the match
+        let nc = NameCapture::from_description(r"^r(\d)?", "u[1]", 2).unwrap();
+        assert_eq!((nc.read(), nc.pieces().len()), (2, 1));
+        assert!(nc.capture_into(b"r2", &mut out));
+        assert!(!nc.capture_into(b"r", &mut out));
+
+        assert!(NameCapture::from_description(r"(\d+)", "b[2]b[4]", 1).is_err());
+        assert!(NameCapture::from_description(r"\d+", "b[2]", 1).is_err());
+        assert!(NameCapture::from_description(r"(\d+)", "b[2-3]", 1).is_err());
+        assert!(NameCapture::from_description(r"(\d+)", "f[ACG]", 1).is_err());
+        assert!(NameCapture::from_description(r"(\d+)", "b[2]", 3).is_err());
+        assert!(NameCapture::from_description(r"(\d+", "b[2]", 1).is_err());
+        assert!(NameCapture::from_description(r"(\d+)", "b[", 1).is_err());
+    }
+
+    #[test]
+    fn captured_pieces_are_added_to_the_geometry() {
+        let nc = || NameCapture::from_description(r"^(\d+)", "b[2]", 2).unwrap();
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        // a piece following an unbounded one could not be located
+        assert!(geo_re.set_name_capture(nc()).is_err());
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r[4]x:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        geo_re.set_name_capture(nc()).unwrap();
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[4]u[2]}2{r[4]b[2]}"
+        );
+        assert!(geo_re.set_name_capture(nc()).is_err());
+    }
+
+    #[test]
+    fn fragments_are_captured_from_their_names() {
+        let nc =
+            NameCapture::from_description(r"^\S+:(\d+):\d+:\d+:\d+ \S+:([ACGTN]+)$", "b[2]b[4]", 1)
+                .unwrap();
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        geo_re.set_name_capture(nc).unwrap();
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[4]u[2]b[2]b[4]}2{r:}"
+        );
+
+        let dir = tempdir().unwrap();
+        let out = xform_in_dir(
+            dir.path(),
+            geo_re,
+            ">M0:1:FC:2:1101:10:20 1:N:0:GGCC\nAAAATTCC\n>M0:1:FC:2:1101:10:21 1:N:0:NNNNN\nAAAATTCC\n",
+            ">a\nACGT\n>b\nACGT\n",
+            &XformOptions {
+                diagnose_failures: true,
+                ..XformOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(out.stats.failed_parsing, 1);
+        assert_eq!(
+            out.stats.failures.unwrap().iter().next().unwrap().0.cause,
+            crate::failure::FailureCause::NameNotMatched
+        );
+        assert_eq!(out.out1, ">M0:1:FC:2:1101:10:20 1:N:0:GGCC\nAAAATTAGGGCC\n");
+    }
+}
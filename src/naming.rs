@@ -0,0 +1,313 @@
+//! Rewriting of the names of the output records.
+//!
+//! By default, each output record is named after the header of its input
+//! record, comments included.  An [`IdFormat`] (given through
+//! [`crate::XformOptions::id_format`]) instead builds the name of each record
+//! from a template, e.g. `{name}_{bc}_{umi}` to strip the comments and append
+//! the barcode and the UMI as UMI-tools expects.  The fields of a template
+//! are:
+//!
+//! - `{name}`: the name of the input record, up to the first whitespace;
+//! - `{comment}`: the comment of the input record, after the first whitespace;
+//! - `{header}`: the whole header of the input record;
+//! - `{index}`: the (1-based) position of the fragment among the fragments
+//!   read, over all of the inputs;
+//! - `{read}`: the read (`1` or `2`) of the record;
+//! - `{bc}`: the barcode (`b`) pieces of the transformed fragment,
+//!   concatenated, as written (i.e. corrected and padded);
+//! - `{umi}`: the UMI (`u`) pieces of the transformed fragment, concatenated.
+//!
+//! Anything else is copied as it is.  A few common templates are available by
+//! name (see [`IdFormat::PRESETS`]).  The tags annotating the records (e.g.
+//! `feature=CD3`) still follow the rewritten names.
+
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use seq_geom_parser::GeomPiece;
+
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// A field of an [`IdFormat`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Literal(String),
+    Name,
+    Comment,
+    Header,
+    Index,
+    Read,
+    Barcode,
+    Umi,
+}
+
+/// The template from which the names of the output records are built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdFormat {
+    fields: Vec<Field>,
+}
+
+impl IdFormat {
+    /// The templates available by name: the name without its comment, the
+    /// name followed by the barcode and the UMI (as expected by UMI-tools),
+    /// and the UMI appended to the name with the barcode in the comment, as
+    /// bcl2fastq writes the UMI and the sample index.
+    pub const PRESETS: &'static [(&'static str, &'static str)] = &[
+        ("name", "{name}"),
+        ("umi-tools", "{name}_{bc}_{umi}"),
+        ("bcl2fastq", "{name}:{umi} {read}:N:0:{bc}"),
+    ];
+
+    /// Parses the template `template` (see the module documentation).
+    pub fn new(template: &str) -> Result<Self> {
+        let mut fields = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                bail!("unmatched }} in the read name template {}", template);
+            }
+            if start > 0 {
+                fields.push(Field::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unmatched {{ in the read name template {}", template))?;
+            fields.push(match &rest[start + 1..start + end] {
+                "name" => Field::Name,
+                "comment" => Field::Comment,
+                "header" => Field::Header,
+                "index" => Field::Index,
+                "read" => Field::Read,
+                "bc" => Field::Barcode,
+                "umi" => Field::Umi,
+                f => bail!(
+                    "unknown field {{{}}} in the read name template {}; expected one of {{name}}, \
+                     {{comment}}, {{header}}, {{index}}, {{read}}, {{bc}} or {{umi}}",
+                    f,
+                    template
+                ),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            fields.push(Field::Literal(rest.to_string()));
+        }
+        if fields.is_empty() {
+            bail!("the read name template is empty");
+        }
+        Ok(Self { fields })
+    }
+
+    fn uses(&self, field: &Field) -> bool {
+        self.fields.contains(field)
+    }
+}
+
+impl FromStr for IdFormat {
+    type Err = anyhow::Error;
+
+    /// Parses the name of one of the [`IdFormat::PRESETS`], or a template.
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::PRESETS.iter().find(|(name, _)| *name == s) {
+            Some((_, template)) => Self::new(template),
+            None => Self::new(s),
+        }
+    }
+}
+
+impl fmt::Display for IdFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for field in &self.fields {
+            match field {
+                Field::Literal(s) => write!(f, "{}", s)?,
+                Field::Name => write!(f, "{{name}}")?,
+                Field::Comment => write!(f, "{{comment}}")?,
+                Field::Header => write!(f, "{{header}}")?,
+                Field::Index => write!(f, "{{index}}")?,
+                Field::Read => write!(f, "{{read}}")?,
+                Field::Barcode => write!(f, "{{bc}}")?,
+                Field::Umi => write!(f, "{{umi}}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds the names of the records of the transformed fragments.
+#[derive(Debug)]
+pub(crate) struct IdWriter {
+    format: IdFormat,
+    /// The locations of the barcode and UMI pieces in the transformed reads.
+    spans: [Vec<PieceSpan>; 2],
+    /// The barcode and the UMI of the current fragment.
+    pieces: [Vec<u8>; 2],
+    /// The names of the read 1 and read 2 records of the current fragment.
+    ids: [Vec<u8>; 2],
+}
+
+impl IdWriter {
+    pub(crate) fn new(format: &IdFormat, geo_re: &FragmentRegexDesc) -> Result<Self> {
+        let mut spans = [vec![], vec![]];
+        for (i, (field, what)) in [(Field::Barcode, "barcode"), (Field::Umi, "UMI")]
+            .into_iter()
+            .enumerate()
+        {
+            if !format.uses(&field) {
+                continue;
+            }
+            let select = |gp: &GeomPiece| match field {
+                Field::Barcode => matches!(gp, GeomPiece::Barcode(_)),
+                _ => matches!(gp, GeomPiece::Umi(_)),
+            };
+            spans[i] = geo_re.piece_spans(select).map_err(|gp| {
+                anyhow!(
+                    "naming the records after their {} requires the {} pieces of the geometry \
+                     to be of bounded length and preceded only by bounded pieces, but found {:?}",
+                    what,
+                    what,
+                    gp
+                )
+            })?;
+            if spans[i].is_empty() {
+                bail!(
+                    "the read name template {} holds the {} of the fragments, but the geometry has no {} piece",
+                    format,
+                    what,
+                    what
+                );
+            }
+        }
+        Ok(Self {
+            format: format.clone(),
+            spans,
+            pieces: [vec![], vec![]],
+            ids: [vec![], vec![]],
+        })
+    }
+
+    /// Builds the names of the read 1 and read 2 records of the transformed
+    /// fragment `frag`, the `index`-th fragment read, whose input records
+    /// have the headers `headers`.
+    pub(crate) fn write(&mut self, headers: [&[u8]; 2], index: u64, frag: &SeqPair) -> [&[u8]; 2] {
+        for (spans, piece) in self.spans.iter().zip(&mut self.pieces) {
+            if !spans.is_empty() {
+                extract_spans(frag, spans, piece);
+            }
+        }
+        for (read, (id, header)) in self.ids.iter_mut().zip(headers).enumerate() {
+            let (name, comment) = match header.iter().position(u8::is_ascii_whitespace) {
+                Some(i) => (&header[..i], &header[i + 1..]),
+                None => (header, &b""[..]),
+            };
+            id.clear();
+            for field in &self.format.fields {
+                match field {
+                    Field::Literal(s) => id.extend_from_slice(s.as_bytes()),
+                    Field::Name => id.extend_from_slice(name),
+                    Field::Comment => id.extend_from_slice(comment),
+                    Field::Header => id.extend_from_slice(header),
+                    // writing into a `Vec` cannot fail
+                    Field::Index => write!(id, "{}", index).unwrap(),
+                    Field::Read => id.push(b'1' + read as u8),
+                    Field::Barcode => id.extend_from_slice(&self.pieces[0]),
+                    Field::Umi => id.extend_from_slice(&self.pieces[1]),
+                }
+            }
+        }
+        let [id1, id2] = &self.ids;
+        [id1, id2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+
+    fn geometry(geom: &str) -> FragmentRegexDesc {
+        FragmentGeomDesc::try_from(geom)
+            .unwrap()
+            .as_regex()
+            .unwrap()
+    }
+
+    #[test]
+    fn templates_are_parsed() {
+        for (name, template) in IdFormat::PRESETS {
+            assert_eq!(name.parse::<IdFormat>().unwrap().to_string(), *template);
+        }
+        let all = "{name} {comment}|{header}|{index}/{read}:{bc}+{umi}";
+        assert_eq!(IdFormat::new(all).unwrap().to_string(), all);
+        assert_eq!(
+            IdFormat::new("r{index}/{read}").unwrap().to_string(),
+            "r{index}/{read}"
+        );
+        assert_eq!(
+            IdFormat::new("fixed").unwrap().fields,
+            [Field::Literal(String::from("fixed"))]
+        );
+        assert!(IdFormat::new("{name").is_err());
+        assert!(IdFormat::new("name}").is_err());
+        assert!(IdFormat::new("{}").is_err());
+        let err = IdFormat::new("{name}_{barcode}").unwrap_err();
+        assert!(err.to_string().contains("unknown field {barcode}"));
+        assert!(IdFormat::new("").is_err());
+    }
+
+    #[test]
+    fn records_are_renamed() {
+        let format = "bcl2fastq".parse::<IdFormat>().unwrap();
+        let mut w = IdWriter::new(&format, &geometry("1{b[4]u[2]x:}2{r:}")).unwrap();
+        let mut frag = SeqPair::new();
+        frag.s1.extend_from_slice(b"ACGTTT");
+        frag.s2.extend_from_slice(b"GGGG");
+        assert_eq!(
+            w.write([b"M0:1:FC:1 1:N:0:AA", b"M0:1:FC:1 2:N:0:AA"], 7, &frag),
+            [
+                &b"M0:1:FC:1:TT 1:N:0:ACGT"[..],
+                &b"M0:1:FC:1:TT 2:N:0:ACGT"[..]
+            ]
+        );
+
+        // the barcode pieces of both reads are concatenated, and a header
+        // without a comment is all name
+        let format = IdFormat::new("{name}|{comment}|{header}_{bc}").unwrap();
+        let mut w = IdWriter::new(&format, &geometry("1{b[2]r:}2{b[2]r:}")).unwrap();
+        frag.s1 = b"AACCC".to_vec();
+        frag.s2 = b"GTTTT".to_vec();
+        assert_eq!(
+            w.write([b"a\tx=1 y=2", b"a"], 1, &frag),
+            [&b"a|x=1 y=2|a\tx=1 y=2_AAGT"[..], &b"a||a_AAGT"[..]]
+        );
+
+        let format = "umi-tools".parse::<IdFormat>().unwrap();
+        assert!(IdWriter::new(&format, &geometry("1{r:}2{r:}")).is_err());
+        assert!(IdWriter::new(&format, &geometry("1{b[2]r:}2{r:}")).is_err());
+        assert!(IdWriter::new(&format, &geometry("1{b:}2{u[2]r:}")).is_err());
+        // the pieces not in the template are not required
+        let format = IdFormat::new("{name}_{umi}").unwrap();
+        assert!(IdWriter::new(&format, &geometry("1{u[2]b:}2{r:}")).is_ok());
+    }
+
+    #[test]
+    fn transformed_records_are_renamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = xform_in_dir(
+            dir.path(),
+            geometry("1{b[4]u[2]x:}2{r:}"),
+            ">a x=1\nACGTTTC\n>b\nNN\n>c\nGGGGAAC\n",
+            ">a x=1\nCCCC\n>b\nTTTT\n>c\nTTTT\n",
+            &XformOptions {
+                id_format: Some(IdFormat::new("{name}_{bc}_{umi}#{index}").unwrap()),
+                ..XformOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(out.out1, ">a_ACGT_TT#1\nACGTTT\n>c_GGGG_AA#3\nGGGGAA\n");
+        assert_eq!(out.out2, ">a_ACGT_TT#1\nCCCC\n>c_GGGG_AA#3\nTTTT\n");
+    }
+}
@@ -0,0 +1,341 @@
+//! A companion index of the offsets of the transformed records in the
+//! outputs.
+//!
+//! When [`crate::XformOptions::offset_index`] is set, a small tab-separated
+//! index is written next to the outputs (see [`offset_index_path`]), so that
+//! downstream tools can seek directly to a region of the transformed files
+//! rather than reading them from the start.  The index holds the offsets of
+//! every [`OffsetIndexOptions::every`]-th record, or, when the output is
+//! sorted by barcode (see the [`crate::sort`] module), the offsets of the
+//! first record of each barcode.
+//!
+//! The offsets of an uncompressed output are byte offsets into the file.
+//! Those of a BGZF compressed output are virtual offsets as used by htslib:
+//! the offset of the block holding the record in the file, shifted left by
+//! 16 bits, plus the offset of the record in the uncompressed data of that
+//! block.  Outputs compressed with gzip or zstd cannot be seeked into, so
+//! they cannot be indexed, and neither can outputs split into parts.  The
+//! offsets of an omitted output are written as `-`.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use seq_geom_parser::GeomPiece;
+use tracing::info;
+
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// Options controlling the index of the offsets of the records.
+#[derive(Debug, Clone)]
+pub struct OffsetIndexOptions {
+    /// The offsets of every `every`-th record (starting with the first) are
+    /// written to the index.  This is ignored when the output is sorted by
+    /// barcode.
+    pub every: u64,
+}
+
+impl OffsetIndexOptions {
+    /// The default number of records between the entries of the index.
+    pub const DEFAULT_EVERY: u64 = 10_000;
+}
+
+impl Default for OffsetIndexOptions {
+    fn default() -> Self {
+        Self {
+            every: Self::DEFAULT_EVERY,
+        }
+    }
+}
+
+/// Returns the path of the offset index of the outputs whose read 1 output
+/// (or read 2 output, if read 1 is omitted) was requested at `base`.
+pub fn offset_index_path(base: &Path) -> PathBuf {
+    let mut fname = base
+        .file_name()
+        .map(|f| f.to_os_string())
+        .unwrap_or_default();
+    fname.push(".idx.tsv");
+    base.with_file_name(fname)
+}
+
+/// Writes the entries of the offset index as the records are written.
+#[derive(Debug)]
+pub(crate) struct OffsetIndex {
+    path: PathBuf,
+    w: BufWriter<File>,
+    every: u64,
+    /// The spans of the barcode pieces, if the records are indexed by
+    /// barcode.
+    barcode_spans: Option<Vec<PieceSpan>>,
+    barcode: Vec<u8>,
+    /// The barcode of the previous record, if the records are indexed by
+    /// barcode.
+    last_barcode: Option<Vec<u8>>,
+    entries: u64,
+}
+
+impl OffsetIndex {
+    /// Create the index `path` of the fragments transformed by `geo_re`.  If
+    /// `by_barcode` is true, the records are indexed by barcode, and the
+    /// barcode pieces of `geo_re` must lie at fixed offsets in the
+    /// transformed fragments.
+    pub(crate) fn new(
+        path: &Path,
+        opts: &OffsetIndexOptions,
+        geo_re: &FragmentRegexDesc,
+        by_barcode: bool,
+    ) -> Result<Self> {
+        if opts.every == 0 {
+            bail!("The offset index must hold the offsets of every n-th record, with n > 0");
+        }
+        let barcode_spans = if by_barcode {
+            let spans = geo_re
+                .piece_spans(|gp| matches!(gp, GeomPiece::Barcode(_)))
+                .map_err(|gp| {
+                    anyhow!(
+                        "indexing by barcode requires the barcode pieces of the geometry to be \
+                         of bounded length and preceded only by bounded pieces, but found {:?}",
+                        gp
+                    )
+                })?;
+            if spans.is_empty() {
+                bail!("indexing by barcode requires the geometry to have a barcode (b) piece");
+            }
+            Some(spans)
+        } else {
+            None
+        };
+        let mut w = BufWriter::new(
+            File::create(path)
+                .with_context(|| format!("Unable to create offset index {:?}", path))?,
+        );
+        if barcode_spans.is_some() {
+            writeln!(w, "barcode\trecord\tr1_offset\tr2_offset")?;
+        } else {
+            writeln!(w, "record\tr1_offset\tr2_offset")?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            w,
+            every: opts.every,
+            barcode_spans,
+            barcode: Vec::new(),
+            last_barcode: None,
+            entries: 0,
+        })
+    }
+
+    /// Records that the fragment `frag`, the `record`-th (0-based) written,
+    /// starts at `offsets` in the read 1 and read 2 outputs (`None` for an
+    /// omitted output).
+    pub(crate) fn record(
+        &mut self,
+        record: u64,
+        offsets: [Option<u64>; 2],
+        frag: &SeqPair,
+    ) -> Result<()> {
+        if let Some(spans) = &self.barcode_spans {
+            extract_spans(frag, spans, &mut self.barcode);
+            if self.last_barcode.as_ref() == Some(&self.barcode) {
+                return Ok(());
+            }
+            self.w.write_all(&self.barcode)?;
+            self.w.write_all(b"\t")?;
+            self.last_barcode = Some(self.barcode.clone());
+        } else if !record.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        let offset = |o: Option<u64>| o.map_or_else(|| String::from("-"), |o| o.to_string());
+        writeln!(
+            self.w,
+            "{}\t{}\t{}",
+            record,
+            offset(offsets[0]),
+            offset(offsets[1])
+        )?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Flushes the index.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.w.flush()?;
+        info!(
+            "wrote {} entries of the offset index to {:?}",
+            self.entries, self.path
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{Compression, SinkConfig};
+    use crate::sort::SortOptions;
+    use crate::test_utils::xform_in_dir;
+    use crate::{xform_read_pairs_to_file_with_options, FragmentGeomDescExt, XformOptions};
+    use flate2::read::MultiGzDecoder;
+    use seq_geom_parser::FragmentGeomDesc;
+    use std::io::{Read, Seek, SeekFrom};
+    use tempfile::tempdir;
+
+    /// The entries of the index `p`, without its header.
+    fn entries(p: &Path) -> Vec<Vec<String>> {
+        std::fs::read_to_string(p)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|l| l.split('\t').map(String::from).collect())
+            .collect()
+    }
+
+    /// The fragment whose read 1 is `s1`.
+    fn frag(s1: &[u8]) -> SeqPair {
+        SeqPair {
+            s1: s1.to_vec(),
+            s2: b"GG".to_vec(),
+        }
+    }
+
+    #[test]
+    fn every_nth_record_is_indexed() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[2]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let path = offset_index_path(&dir.path().join("o1.fa"));
+        assert_eq!(path, dir.path().join("o1.fa.idx.tsv"));
+        assert!(OffsetIndex::new(&path, &OffsetIndexOptions { every: 0 }, &geo_re, false).is_err());
+
+        let mut index =
+            OffsetIndex::new(&path, &OffsetIndexOptions { every: 2 }, &geo_re, false).unwrap();
+        for (i, offsets) in [[Some(0), None], [Some(8), None], [Some(16), None]]
+            .into_iter()
+            .enumerate()
+        {
+            index.record(i as u64, offsets, &frag(b"AACC")).unwrap();
+        }
+        index.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "record\tr1_offset\tr2_offset\n0\t0\t-\n2\t16\t-\n"
+        );
+    }
+
+    #[test]
+    fn the_first_record_of_each_barcode_is_indexed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("o1.fa.idx.tsv");
+        let index = |geom: &str| {
+            let geo_re = FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap();
+            OffsetIndex::new(&path, &OffsetIndexOptions { every: 100 }, &geo_re, true)
+        };
+        assert!(index("1{u[2]x:}2{r:}").is_err());
+        assert!(index("1{b:}2{r:}").is_err());
+
+        let mut index = index("1{u[1]b[2]x:}2{r:}").unwrap();
+        for (i, s1) in [b"TAAC", b"GAAG", b"TCCC", b"GAAC"].into_iter().enumerate() {
+            let offset = 6 * i as u64;
+            index
+                .record(i as u64, [Some(offset), Some(offset)], &frag(s1))
+                .unwrap();
+        }
+        index.finish().unwrap();
+        // a barcode seen again after another one is indexed again, as the
+        // records are only expected to be sorted by barcode
+        assert_eq!(
+            entries(&path),
+            [
+                ["AA", "0", "0", "0"],
+                ["CC", "2", "12", "12"],
+                ["AA", "3", "18", "18"]
+            ]
+        );
+    }
+
+    #[test]
+    fn offsets_point_to_the_records() {
+        let geo = FragmentGeomDesc::try_from("1{b[2]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        let (mut s1, mut s2) = (String::new(), String::new());
+        for i in 0..10 {
+            let bc = ["TT", "AC", "GA"][i % 3];
+            s1 += &format!(">r{}\n{}CC\n", i, bc);
+            s2 += &format!(">r{}\nGG\n", i);
+        }
+
+        // every 4th record of uncompressed outputs
+        let opts = XformOptions {
+            offset_index: Some(OffsetIndexOptions { every: 4 }),
+            ..XformOptions::default()
+        };
+        let out = xform_in_dir(dir.path(), geo.as_regex().unwrap(), &s1, &s2, &opts).unwrap();
+        let idx = entries(&offset_index_path(&p("o1.fa")));
+        assert_eq!(idx.len(), 3);
+        for e in &idx {
+            let off: usize = e[2].parse().unwrap();
+            assert!(out.out2[off..].starts_with(&format!(">r{}\n", e[0])));
+        }
+
+        // the first record of each barcode of sorted BGZF outputs
+        let bgzf = SinkConfig {
+            compression: Compression::Bgzf(Compression::DEFAULT_GZIP_LEVEL),
+            ..SinkConfig::default()
+        };
+        let opts = XformOptions {
+            offset_index: Some(OffsetIndexOptions::default()),
+            sort_by_barcode: Some(SortOptions::default()),
+            out1: bgzf.clone(),
+            out2: bgzf,
+            ..XformOptions::default()
+        };
+        xform_read_pairs_to_file_with_options(
+            geo.as_regex().unwrap(),
+            &[p("r1.fa")],
+            &[p("r2.fa")],
+            Some(p("o1.fa.gz")),
+            Some(p("o2.fa.gz")),
+            &opts,
+        )
+        .unwrap();
+        let idx = entries(&offset_index_path(&p("o1.fa.gz")));
+        let keys: Vec<(&str, &str)> = idx.iter().map(|e| (&e[0][..], &e[1][..])).collect();
+        assert_eq!(keys, [("AC", "0"), ("GA", "3"), ("TT", "6")]);
+        for e in &idx {
+            let voff: u64 = e[2].parse().unwrap();
+            let mut f = File::open(p("o1.fa.gz")).unwrap();
+            f.seek(SeekFrom::Start(voff >> 16)).unwrap();
+            let mut data = String::new();
+            MultiGzDecoder::new(f).read_to_string(&mut data).unwrap();
+            let rec = &data[(voff & 0xffff) as usize..];
+            assert!(rec.lines().nth(1).unwrap().starts_with(&e[0]));
+        }
+
+        // gzip outputs cannot be seeked into
+        let opts = XformOptions {
+            offset_index: Some(OffsetIndexOptions::default()),
+            out1: SinkConfig {
+                compression: Compression::Gzip(Compression::DEFAULT_GZIP_LEVEL),
+                ..SinkConfig::default()
+            },
+            ..XformOptions::default()
+        };
+        assert!(xform_read_pairs_to_file_with_options(
+            geo.as_regex().unwrap(),
+            &[p("r1.fa")],
+            &[p("r2.fa")],
+            Some(p("o3.fa.gz")),
+            Some(p("o3.fa")),
+            &opts,
+        )
+        .is_err());
+    }
+}
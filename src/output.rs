@@ -0,0 +1,1166 @@
+//! Output sinks for transformed read pairs.
+//!
+//! The [`PairedWriter`] owns the two output streams (one for each read of the
+//! fragment) and takes care of rotating them into numbered parts when a
+//! [`SplitPolicy`] is in effect.  Rotation always happens on *fragment*
+//! boundaries, and both outputs are always rotated together, so that part `i`
+//! of the read 1 output is paired with part `i` of the read 2 output.
+//!
+//! Either of the outputs may be omitted (e.g. if the geometry discards all
+//! of the corresponding read), in which case nothing is opened or written
+//! for it.
+//!
+//! Each of the two outputs is configured independently through a
+//! [`SinkConfig`], so that, e.g., the highly-redundant barcode/UMI read can
+//! be heavily compressed while the biological read is written uncompressed
+//! into a FIFO.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use tracing::info;
+
+use crate::bgzf::BgzfWriter;
+use crate::frame::FrameWriter;
+use crate::offsets::OffsetIndex;
+use crate::retry::RetryPolicy;
+
+/// The compression applied to an output stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Write the output uncompressed.
+    #[default]
+    None,
+    /// Gzip compress the output with the given level (0-9).
+    Gzip(u32),
+    /// Compress the output into BGZF blocks (as `bgzip` does) with the given
+    /// level (0-9).  The output can be read by any gzip decoder.
+    Bgzf(u32),
+    /// Zstd compress the output with the given level (1-22).
+    Zstd(i32),
+}
+
+impl Compression {
+    /// The default gzip compression level.
+    pub const DEFAULT_GZIP_LEVEL: u32 = 6;
+    /// The default zstd compression level.
+    pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+    /// The file name extension conventionally given to a stream with this
+    /// compression (`gz` for both gzip and BGZF), if it is compressed.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip(_) | Compression::Bgzf(_) => Some("gz"),
+            Compression::Zstd(_) => Some("zst"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    /// Parses a compression specification of the form `codec[:level]`,
+    /// where `codec` is one of `none`, `gzip` (or `gz`), `bgzf` (or `bgz`)
+    /// and `zstd` (or `zst`).  If the level is omitted, the codec's default is used.
+    fn from_str(s: &str) -> Result<Self> {
+        let (codec, level) = match s.split_once(':') {
+            Some((c, l)) => (c, Some(l)),
+            None => (s, None),
+        };
+        match (codec.to_ascii_lowercase().as_str(), level) {
+            ("none", None) => Ok(Compression::None),
+            ("none", Some(_)) => bail!("compression level given for codec \"none\""),
+            ("gzip" | "gz", l) => {
+                let level = match l {
+                    Some(l) => l
+                        .parse()
+                        .with_context(|| format!("invalid gzip level {}", l))?,
+                    None => Self::DEFAULT_GZIP_LEVEL,
+                };
+                if level > 9 {
+                    bail!(
+                        "gzip compression level must be in [0, 9], but got {}",
+                        level
+                    );
+                }
+                Ok(Compression::Gzip(level))
+            }
+            ("bgzf" | "bgz", l) => {
+                let level = match l {
+                    Some(l) => l
+                        .parse()
+                        .with_context(|| format!("invalid bgzf level {}", l))?,
+                    None => Self::DEFAULT_GZIP_LEVEL,
+                };
+                if level > 9 {
+                    bail!(
+                        "bgzf compression level must be in [0, 9], but got {}",
+                        level
+                    );
+                }
+                Ok(Compression::Bgzf(level))
+            }
+            ("zstd" | "zst", l) => {
+                let level = match l {
+                    Some(l) => l
+                        .parse()
+                        .with_context(|| format!("invalid zstd level {}", l))?,
+                    None => Self::DEFAULT_ZSTD_LEVEL,
+                };
+                if !(1..=22).contains(&level) {
+                    bail!(
+                        "zstd compression level must be in [1, 22], but got {}",
+                        level
+                    );
+                }
+                Ok(Compression::Zstd(level))
+            }
+            (c, _) => bail!("unknown compression codec {}", c),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip(l) => write!(f, "gzip:{}", l),
+            Compression::Bgzf(l) => write!(f, "bgzf:{}", l),
+            Compression::Zstd(l) => write!(f, "zstd:{}", l),
+        }
+    }
+}
+
+/// The framing of the records written to an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// `FASTA` records, whose header holds the id followed by the tags
+    /// (e.g. `feature=CD3`), separated by spaces.
+    #[default]
+    Fasta,
+    /// `FASTQ` records, with the same headers as `FASTA` records.  As the
+    /// transformed sequences carry no qualities, every base is given the
+    /// quality `I`, except for the padding of the variable length pieces
+    /// (see [`crate::PaddingScheme`]), which is given the quality `#` (the
+    /// lowest quality, 2), so that aligners and UMI tools do not take the
+    /// padding for sequenced bases.
+    Fastq,
+    /// Two lines per record: the id followed by the tags, separated by tabs,
+    /// and the sequence.  This is the cheapest framing to write and to parse,
+    /// for custom downstream consumers.
+    Raw,
+}
+
+impl OutputFormat {
+    /// The quality given to the bases of the `FASTQ` records.
+    pub const FASTQ_QUALITY: u8 = b'I';
+    /// The quality given to the padding bases of the `FASTQ` records.
+    pub const FASTQ_PAD_QUALITY: u8 = b'#';
+
+    /// The file name extension conventionally given to an (uncompressed)
+    /// output with this framing.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Fasta => "fa",
+            OutputFormat::Fastq => "fq",
+            OutputFormat::Raw => "txt",
+        }
+    }
+
+    /// Writes a record with the id `id`, the tags `comment` (which is either
+    /// empty or holds tags, each preceded by a space) and the sequence `seq`,
+    /// whose padding bases are in the ranges `pads`, to `w`.
+    pub(crate) fn write_record<W: Write + ?Sized>(
+        &self,
+        w: &mut W,
+        id: &[u8],
+        comment: &[u8],
+        seq: &[u8],
+        pads: &[Range<u32>],
+    ) -> io::Result<()> {
+        match self {
+            OutputFormat::Fasta => crate::write_fasta_record(w, id, comment, seq),
+            OutputFormat::Fastq => {
+                w.write_all(b"@")?;
+                w.write_all(id)?;
+                w.write_all(comment)?;
+                w.write_all(b"\n")?;
+                w.write_all(seq)?;
+                w.write_all(b"\n+\n")?;
+                let mut pos = 0;
+                for pad in pads {
+                    // the ranges are sorted, and lie within the sequence
+                    // unless it was rewritten after being parsed
+                    let start = (pad.start as usize).clamp(pos, seq.len());
+                    let end = (pad.end as usize).clamp(start, seq.len());
+                    write_qualities(w, OutputFormat::FASTQ_QUALITY, start - pos)?;
+                    write_qualities(w, OutputFormat::FASTQ_PAD_QUALITY, end - start)?;
+                    pos = end;
+                }
+                write_qualities(w, OutputFormat::FASTQ_QUALITY, seq.len() - pos)?;
+                w.write_all(b"\n")
+            }
+            OutputFormat::Raw => {
+                w.write_all(id)?;
+                for tag in comment.split(|&c| c == b' ').filter(|t| !t.is_empty()) {
+                    w.write_all(b"\t")?;
+                    w.write_all(tag)?;
+                }
+                w.write_all(b"\n")?;
+                w.write_all(seq)?;
+                w.write_all(b"\n")
+            }
+        }
+    }
+
+    /// The sum of the Phred scores of the qualities written for a sequence
+    /// of `len` bases whose padding bases are in the ranges `pads` (as by
+    /// [`OutputFormat::write_record`]), or `None` if the records of this
+    /// format carry no qualities.
+    pub(crate) fn quality_sum(&self, len: usize, pads: &[Range<u32>]) -> Option<u64> {
+        if *self != OutputFormat::Fastq {
+            return None;
+        }
+        let (mut pos, mut padded) = (0, 0);
+        for pad in pads {
+            let start = (pad.start as usize).clamp(pos, len);
+            let end = (pad.end as usize).clamp(start, len);
+            padded += end - start;
+            pos = end;
+        }
+        let phred = |q: u8| u64::from(q - 33);
+        Some(
+            phred(OutputFormat::FASTQ_QUALITY) * (len - padded) as u64
+                + phred(OutputFormat::FASTQ_PAD_QUALITY) * padded as u64,
+        )
+    }
+}
+
+/// Writes the quality `q` `n` times to `w`.
+fn write_qualities<W: Write + ?Sized>(w: &mut W, q: u8, mut n: usize) -> io::Result<()> {
+    let quals = [q; 256];
+    while n > 0 {
+        let k = n.min(quals.len());
+        w.write_all(&quals[..k])?;
+        n -= k;
+    }
+    Ok(())
+}
+
+/// Writes an input record, with the header `id`, the sequence `seq` and the
+/// qualities `qual`, to `w` as it was read: as a `FASTQ` record if it has
+/// qualities, and as a `FASTA` record otherwise.
+pub(crate) fn write_input_record<W: Write + ?Sized>(
+    w: &mut W,
+    id: &[u8],
+    seq: &[u8],
+    qual: Option<&[u8]>,
+) -> io::Result<()> {
+    match qual {
+        Some(q) => {
+            w.write_all(b"@")?;
+            w.write_all(id)?;
+            w.write_all(b"\n")?;
+            w.write_all(seq)?;
+            w.write_all(b"\n+\n")?;
+            w.write_all(q)?;
+            w.write_all(b"\n")
+        }
+        None => crate::write_fasta_record(w, id, b"", seq),
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fasta" | "fa" => Ok(OutputFormat::Fasta),
+            "fastq" | "fq" => Ok(OutputFormat::Fastq),
+            "raw" => Ok(OutputFormat::Raw),
+            _ => bail!("unknown output format {} (expected fasta, fastq or raw)", s),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Fasta => write!(f, "fasta"),
+            OutputFormat::Fastq => write!(f, "fastq"),
+            OutputFormat::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+/// Returns the compression implied by the extension of the file name of `p`
+/// (`.gz`, `.bgz`, `.bgzf`, `.zst` or `.zstd`), at the codec's default level,
+/// or `None` if it has no such extension.
+pub fn compression_from_extension(p: &Path) -> Option<Compression> {
+    let ext = p.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "gz" => Some(Compression::Gzip(Compression::DEFAULT_GZIP_LEVEL)),
+        "bgz" | "bgzf" => Some(Compression::Bgzf(Compression::DEFAULT_GZIP_LEVEL)),
+        "zst" | "zstd" => Some(Compression::Zstd(Compression::DEFAULT_ZSTD_LEVEL)),
+        _ => None,
+    }
+}
+
+/// Detects the compression of the file at `p` from its magic bytes.  Only
+/// regular files are inspected, so that no data is consumed from e.g. fifos,
+/// process substitutions or the standard input (see [`crate::STDIN_PATH`]);
+/// for these, and for files in any format other than gzip or zstd,
+/// `Compression::None` is returned.  The level of a detected codec is its
+/// default level.
+pub fn detect_compression(p: &Path) -> Result<Compression> {
+    if crate::is_stdin(p) {
+        return Ok(Compression::None);
+    }
+    if !std::fs::metadata(p)
+        .with_context(|| format!("could not access {}", p.display()))?
+        .is_file()
+    {
+        return Ok(Compression::None);
+    }
+    // enough for the gzip header up to the subfield identifier of BGZF
+    let mut magic = [0u8; 14];
+    let mut f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let mut n = 0;
+    while n < magic.len() {
+        match f.read(&mut magic[n..])? {
+            0 => break,
+            m => n += m,
+        }
+    }
+    Ok(match &magic[..n] {
+        // gzip with an extra field holding the BC subfield
+        [0x1f, 0x8b, 0x08, flg, _, _, _, _, _, _, _, _, b'B', b'C'] if flg & 0x04 != 0 => {
+            Compression::Bgzf(Compression::DEFAULT_GZIP_LEVEL)
+        }
+        [0x1f, 0x8b, ..] => Compression::Gzip(Compression::DEFAULT_GZIP_LEVEL),
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => Compression::Zstd(Compression::DEFAULT_ZSTD_LEVEL),
+        _ => Compression::None,
+    })
+}
+
+/// Configuration of a single output stream.
+#[derive(Debug, Clone, Default)]
+pub struct SinkConfig {
+    /// The compression applied to this output.
+    pub compression: Compression,
+    /// If set, writes to this output that fail with a transient error are
+    /// retried (this is set from [`crate::XformOptions::retry`] by the
+    /// functions transforming reads into files).
+    pub retry: Option<RetryPolicy>,
+    /// If true, the records are written as chunks checked by a CRC32, so
+    /// that the consumer of a fifo can detect a truncated or corrupted
+    /// stream (see the [`crate::frame`] module).  A framed output must be
+    /// uncompressed.
+    pub framed: bool,
+}
+
+/// Describes when the output should be rotated into a new numbered part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitPolicy {
+    /// Start a new part after this many fragments have been written to
+    /// the current part.
+    Records(u64),
+    /// Start a new part once either output of the current part has
+    /// reached this many bytes.
+    Bytes(u64),
+}
+
+/// Parses a human readable size like `50G`, `512M`, `10k` or `4096` into
+/// a number of bytes.  Suffixes are interpreted as binary units (i.e. `1K`
+/// is 1024 bytes), are case-insensitive, and may optionally be followed by
+/// `B` or `iB`.
+pub fn parse_byte_size(s: &str) -> Result<u64> {
+    let t = s.trim();
+    let t = t
+        .strip_suffix("iB")
+        .or_else(|| t.strip_suffix("ib"))
+        .or_else(|| t.strip_suffix(['B', 'b']))
+        .unwrap_or(t);
+    let (num, mult) = match t.chars().last() {
+        Some('k' | 'K') => (&t[..t.len() - 1], 1u64 << 10),
+        Some('m' | 'M') => (&t[..t.len() - 1], 1u64 << 20),
+        Some('g' | 'G') => (&t[..t.len() - 1], 1u64 << 30),
+        Some('t' | 'T') => (&t[..t.len() - 1], 1u64 << 40),
+        _ => (t, 1u64),
+    };
+    let n: u64 = num
+        .trim()
+        .parse()
+        .with_context(|| format!("could not parse {} as a size", s))?;
+    if n == 0 {
+        bail!("size must be greater than 0, but got {}", s);
+    }
+    n.checked_mul(mult)
+        .with_context(|| format!("size {} is too large", s))
+}
+
+/// Returns the path of part `idx` (1-based) of the output `base`.  The
+/// part number is inserted before the first extension of the file name,
+/// so that `out/r1.fastq.gz` becomes `out/r1.part0001.fastq.gz`.
+pub fn part_path(base: &Path, idx: usize) -> PathBuf {
+    let fname = base
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // a leading '.' (hidden file) is not the start of an extension
+    let split_at = fname
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| *c == '.')
+        .map(|(i, _)| i)
+        .unwrap_or(fname.len());
+    let (stem, ext) = fname.split_at(split_at);
+    base.with_file_name(format!("{}.part{:04}{}", stem, idx, ext))
+}
+
+/// Returns the path of the manifest describing the parts of a split output
+/// whose read 1 output was requested at `base1`.
+pub fn manifest_path(base1: &Path) -> PathBuf {
+    let mut fname = base1
+        .file_name()
+        .map(|f| f.to_os_string())
+        .unwrap_or_default();
+    fname.push(".manifest.tsv");
+    base1.with_file_name(fname)
+}
+
+/// A writer that keeps track of how many bytes have been
+/// written through it.
+#[derive(Debug)]
+pub(crate) struct CountingWriter<W: Write> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, bytes: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Information about a single (paired) part of the output.
+#[derive(Debug, Clone)]
+pub struct OutputPart {
+    /// The read 1 output of this part, or `None` if it was omitted.
+    pub r1_path: Option<PathBuf>,
+    /// The read 2 output of this part, or `None` if it was omitted.
+    pub r2_path: Option<PathBuf>,
+    pub records: u64,
+    pub r1_bytes: u64,
+    pub r2_bytes: u64,
+    /// The records written to the read 1 output of this part (both reads of
+    /// each fragment if the output is interleaved).
+    pub r1_summary: RecordSummary,
+    /// The records written to the read 2 output of this part.
+    pub r2_summary: RecordSummary,
+}
+
+impl OutputPart {
+    fn new(r1_path: Option<PathBuf>, r2_path: Option<PathBuf>) -> Self {
+        Self {
+            r1_path,
+            r2_path,
+            records: 0,
+            r1_bytes: 0,
+            r2_bytes: 0,
+            r1_summary: RecordSummary::default(),
+            r2_summary: RecordSummary::default(),
+        }
+    }
+}
+
+/// Aggregate metrics of the records written to an output file, so that the
+/// outputs can be checked without reading them again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordSummary {
+    /// The number of records.
+    pub records: u64,
+    /// The length of the shortest record (0 if there is none).
+    pub min_len: u64,
+    /// The length of the longest record.
+    pub max_len: u64,
+    /// The total length of the records.
+    pub total_len: u64,
+    /// The sum of the Phred scores of the bases of the records, or `None`
+    /// if the records carry no qualities (i.e. are not `FASTQ` records).
+    pub total_quality: Option<u64>,
+}
+
+impl RecordSummary {
+    /// Adds a record of `len` bases, the Phred scores of which sum to
+    /// `quality` (if it has qualities).
+    pub fn record(&mut self, len: usize, quality: Option<u64>) {
+        let len = len as u64;
+        self.min_len = if self.records == 0 {
+            len
+        } else {
+            self.min_len.min(len)
+        };
+        self.max_len = self.max_len.max(len);
+        self.total_len += len;
+        self.total_quality = match (self.records, quality) {
+            (0, q) => q,
+            (_, q) => self.total_quality.zip(q).map(|(t, q)| t + q),
+        };
+        self.records += 1;
+    }
+
+    /// The mean length of the records (0 if there is none).
+    pub fn mean_len(&self) -> f64 {
+        self.total_len as f64 / self.records.max(1) as f64
+    }
+
+    /// The mean quality (Phred score) of the bases of the records, if they
+    /// carry qualities.
+    pub fn mean_quality(&self) -> Option<f64> {
+        self.total_quality
+            .filter(|_| self.total_len > 0)
+            .map(|q| q as f64 / self.total_len as f64)
+    }
+}
+
+type CountedFile = CountingWriter<BufWriter<Box<dyn Write + Send>>>;
+
+/// An open output stream, along with any encoder wrapping it.  Bytes are
+/// counted *after* compression, so that rotation by size applies to the
+/// size of the files on disk.  An `Omitted` sink has no underlying file and
+/// drops anything written to it.
+enum Sink {
+    Omitted,
+    Plain(CountedFile),
+    Gzip(GzEncoder<CountedFile>),
+    Bgzf(BgzfWriter<CountedFile>),
+    Zstd(zstd::Encoder<'static, CountedFile>),
+    Framed(FrameWriter<CountedFile>),
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            Sink::Omitted => "Omitted",
+            Sink::Plain(_) => "Plain",
+            Sink::Gzip(_) => "Gzip",
+            Sink::Bgzf(_) => "Bgzf",
+            Sink::Zstd(_) => "Zstd",
+            Sink::Framed(_) => "Framed",
+        };
+        write!(f, "Sink::{} {{ bytes: {} }}", kind, self.bytes())
+    }
+}
+
+impl Sink {
+    fn open(p: Option<&Path>, cfg: &SinkConfig) -> Result<Self> {
+        let Some(p) = p else {
+            return Ok(Sink::Omitted);
+        };
+        // the fifos of `xform_read_pairs_to_fifo` are opened as pipes
+        let f: Box<dyn Write + Send> = match crate::handle::open_write(p) {
+            Some(w) => w,
+            None => crate::pipe::open_write(p).map(|f| Box::new(f) as Box<dyn Write + Send>),
+        }
+        .with_context(|| format!("Unable to open output file {:?}", p))?;
+        let f: Box<dyn Write + Send> = match &cfg.retry {
+            Some(r) => Box::new(r.wrap(f)),
+            None => Box::new(f),
+        };
+        let w = CountingWriter::new(BufWriter::new(f));
+        Ok(match cfg.compression {
+            Compression::None if cfg.framed => Sink::Framed(FrameWriter::new(w)),
+            _ if cfg.framed => bail!(
+                "The output {:?} cannot be both framed and compressed ({})",
+                p,
+                cfg.compression
+            ),
+            Compression::None => Sink::Plain(w),
+            Compression::Gzip(l) => Sink::Gzip(GzEncoder::new(w, flate2::Compression::new(l))),
+            Compression::Bgzf(l) => Sink::Bgzf(BgzfWriter::new(w, flate2::Compression::new(l))),
+            Compression::Zstd(l) => Sink::Zstd(zstd::Encoder::new(w, l)?),
+        })
+    }
+
+    fn bytes(&self) -> u64 {
+        match self {
+            Sink::Omitted => 0,
+            Sink::Plain(w) => w.bytes,
+            Sink::Gzip(w) => w.get_ref().bytes,
+            Sink::Bgzf(w) => w.get_ref().bytes,
+            Sink::Zstd(w) => w.get_ref().bytes,
+            Sink::Framed(w) => w.get_ref().bytes,
+        }
+    }
+
+    /// The offset at which the next byte written will be found in the
+    /// output (see the [`crate::offsets`] module), or `None` if the output
+    /// is omitted or cannot be seeked into.
+    fn offset(&self) -> Option<u64> {
+        match self {
+            Sink::Plain(w) => Some(w.bytes),
+            Sink::Bgzf(w) => Some((w.get_ref().bytes << 16) | w.buffered() as u64),
+            Sink::Omitted | Sink::Gzip(_) | Sink::Zstd(_) | Sink::Framed(_) => None,
+        }
+    }
+
+    /// Marks the end of a record, which is where a framed stream may be cut
+    /// into a new chunk.
+    fn end_record(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Framed(w) => w.end_record(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finish any encoding, flush the stream and return the total number
+    /// of bytes written.
+    fn finish(self) -> Result<u64> {
+        let mut w = match self {
+            Sink::Omitted => return Ok(0),
+            Sink::Plain(w) => w,
+            Sink::Gzip(w) => w.finish()?,
+            Sink::Bgzf(w) => w.finish()?,
+            Sink::Zstd(w) => w.finish()?,
+            Sink::Framed(w) => w.finish()?,
+        };
+        w.flush()?;
+        Ok(w.bytes)
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Omitted => Ok(buf.len()),
+            Sink::Plain(w) => w.write(buf),
+            Sink::Gzip(w) => w.write(buf),
+            Sink::Bgzf(w) => w.write(buf),
+            Sink::Zstd(w) => w.write(buf),
+            Sink::Framed(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Omitted => Ok(()),
+            Sink::Plain(w) => w.flush(),
+            Sink::Gzip(w) => w.flush(),
+            Sink::Bgzf(w) => w.flush(),
+            Sink::Zstd(w) => w.flush(),
+            Sink::Framed(w) => w.flush(),
+        }
+    }
+}
+
+/// Writes transformed fragments to a pair of outputs, optionally
+/// rotating them into numbered parts.  Callers write a fragment into
+/// [`PairedWriter::streams`] and then call [`PairedWriter::end_record`],
+/// which is where any rotation takes place.
+#[derive(Debug)]
+pub struct PairedWriter {
+    base1: Option<PathBuf>,
+    base2: Option<PathBuf>,
+    split: Option<SplitPolicy>,
+    cfg1: SinkConfig,
+    cfg2: SinkConfig,
+    w1: Sink,
+    w2: Sink,
+    current: OutputPart,
+    parts: Vec<OutputPart>,
+    index: Option<OffsetIndex>,
+    interleaved: bool,
+}
+
+impl PairedWriter {
+    /// Create a new `PairedWriter` writing to `r1_ofile` and `r2_ofile`,
+    /// configured by `cfg1` and `cfg2` respectively.  If `split` is `None`,
+    /// the outputs are written to exactly these paths, otherwise numbered
+    /// parts are derived from them (see [`part_path`]).  An output that is
+    /// `None` is omitted, but at least one of the outputs must be given.
+    pub fn new(
+        r1_ofile: Option<PathBuf>,
+        r2_ofile: Option<PathBuf>,
+        cfg1: &SinkConfig,
+        cfg2: &SinkConfig,
+        split: Option<SplitPolicy>,
+    ) -> Result<Self> {
+        if r1_ofile.is_none() && r2_ofile.is_none() {
+            bail!("at least one of the read 1 and read 2 outputs must be given");
+        }
+        let is_handle = |p: &Option<PathBuf>| p.as_deref().is_some_and(crate::handle::is_handle);
+        if split.is_some() && (is_handle(&r1_ofile) || is_handle(&r2_ofile)) {
+            bail!("an output handle cannot be split into parts");
+        }
+        let (p1, p2) = match split {
+            Some(_) => (
+                r1_ofile.as_deref().map(|p| part_path(p, 1)),
+                r2_ofile.as_deref().map(|p| part_path(p, 1)),
+            ),
+            None => (r1_ofile.clone(), r2_ofile.clone()),
+        };
+        Ok(Self {
+            w1: Sink::open(p1.as_deref(), cfg1)?,
+            w2: Sink::open(p2.as_deref(), cfg2)?,
+            base1: r1_ofile,
+            base2: r2_ofile,
+            split,
+            cfg1: cfg1.clone(),
+            cfg2: cfg2.clone(),
+            current: OutputPart::new(p1, p2),
+            parts: vec![],
+            index: None,
+            interleaved: false,
+        })
+    }
+
+    /// Create a new `PairedWriter` writing the read 1 and read 2 records of
+    /// each fragment one after the other into the single output `ofile`,
+    /// configured by `cfg`.  The read 2 output of the parts of a split output
+    /// is omitted.
+    pub fn new_interleaved(
+        ofile: PathBuf,
+        cfg: &SinkConfig,
+        split: Option<SplitPolicy>,
+    ) -> Result<Self> {
+        let mut w = Self::new(Some(ofile), None, cfg, &SinkConfig::default(), split)?;
+        w.interleaved = true;
+        Ok(w)
+    }
+
+    /// Whether the read 1 and read 2 records are interleaved in the read 1
+    /// output (see [`PairedWriter::new_interleaved`]), in which case both
+    /// are written into the first of the [`PairedWriter::streams`].
+    pub fn is_interleaved(&self) -> bool {
+        self.interleaved
+    }
+
+    /// Record the offsets of the fragments written from now on in `index`.
+    /// The outputs must not be split, and must be either uncompressed or
+    /// compressed with BGZF.
+    pub(crate) fn set_offset_index(&mut self, index: OffsetIndex) -> Result<()> {
+        if self.split.is_some() {
+            bail!("The output cannot be split into parts when writing an offset index");
+        }
+        for (i, w) in [&self.w1, &self.w2].into_iter().enumerate() {
+            if let Sink::Gzip(_) | Sink::Zstd(_) | Sink::Framed(_) = w {
+                bail!(
+                    "The read {} output must be unframed, and uncompressed or compressed with bgzf, to write an offset index",
+                    i + 1
+                );
+            }
+        }
+        self.index = Some(index);
+        Ok(())
+    }
+
+    /// Records the offsets of the fragment `frag` in the offset index, if
+    /// any, before it is written into the streams.
+    pub(crate) fn index_record(&mut self, frag: &crate::SeqPair) -> Result<()> {
+        if let Some(index) = &mut self.index {
+            let offsets = [self.w1.offset(), self.w2.offset()];
+            index.record(self.current.records, offsets, frag)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a record of `len` bases, whose padding bases are in the ranges
+    /// `pads`, written in the format `format` into the read `read` (1 or 2)
+    /// output of the current part, to its [`RecordSummary`].
+    pub(crate) fn summarize_record(
+        &mut self,
+        read: usize,
+        format: OutputFormat,
+        len: usize,
+        pads: &[Range<u32>],
+    ) {
+        let summary = match read {
+            1 => &mut self.current.r1_summary,
+            _ => &mut self.current.r2_summary,
+        };
+        summary.record(len, format.quality_sum(len, pads));
+    }
+
+    /// The read 1 and read 2 output streams of the current part.
+    pub fn streams(&mut self) -> (&mut dyn Write, &mut dyn Write) {
+        (&mut self.w1, &mut self.w2)
+    }
+
+    /// Signal that a complete fragment has been written to the streams,
+    /// rotating to the next part if the split policy requires it.
+    pub fn end_record(&mut self) -> Result<()> {
+        self.w1.end_record()?;
+        self.w2.end_record()?;
+        self.current.records += 1;
+        let rotate = match self.split {
+            None => false,
+            Some(SplitPolicy::Records(n)) => self.current.records >= n,
+            Some(SplitPolicy::Bytes(n)) => self.w1.bytes() >= n || self.w2.bytes() >= n,
+        };
+        if rotate {
+            let idx = self.parts.len() + 2;
+            let p1 = self.base1.as_deref().map(|p| part_path(p, idx));
+            let p2 = self.base2.as_deref().map(|p| part_path(p, idx));
+            let w1 = std::mem::replace(&mut self.w1, Sink::open(p1.as_deref(), &self.cfg1)?);
+            let w2 = std::mem::replace(&mut self.w2, Sink::open(p2.as_deref(), &self.cfg2)?);
+            let next = OutputPart::new(p1, p2);
+            let done = std::mem::replace(&mut self.current, next);
+            close_part(&mut self.parts, done, w1, w2)?;
+        }
+        Ok(())
+    }
+
+    /// Flush and close the outputs, returning the list of parts that were
+    /// written.  If the output was split, a manifest listing the parts is
+    /// written next to the read 1 output, or next to the read 2 output if
+    /// the read 1 output was omitted (see [`manifest_path`]).  Note that
+    /// a trailing empty part is only kept if no other part was written.
+    pub fn finish(self) -> Result<Vec<OutputPart>> {
+        let PairedWriter {
+            base1,
+            base2,
+            split,
+            w1,
+            w2,
+            current,
+            mut parts,
+            index,
+            ..
+        } = self;
+        if let Some(index) = index {
+            index.finish()?;
+        }
+        if current.records == 0 && !parts.is_empty() {
+            w1.finish()?;
+            w2.finish()?;
+            for p in [&current.r1_path, &current.r2_path].into_iter().flatten() {
+                std::fs::remove_file(p)?;
+            }
+        } else {
+            close_part(&mut parts, current, w1, w2)?;
+        }
+
+        if let (Some(_), Some(base)) = (split, base1.or(base2)) {
+            let mpath = manifest_path(&base);
+            let mut m = BufWriter::new(
+                File::create(&mpath)
+                    .with_context(|| format!("Unable to create manifest {:?}", mpath))?,
+            );
+            write!(m, "part\tr1_path\tr2_path\trecords\tr1_bytes\tr2_bytes")?;
+            for r in ["r1", "r2"] {
+                for col in ["records", "min_len", "mean_len", "max_len", "mean_quality"] {
+                    write!(m, "\t{}_{}", r, col)?;
+                }
+            }
+            writeln!(m)?;
+            // omitted outputs are listed as "-"
+            let display = |p: &Option<PathBuf>| {
+                p.as_ref()
+                    .map_or_else(|| String::from("-"), |p| p.display().to_string())
+            };
+            for (i, p) in parts.iter().enumerate() {
+                write!(
+                    m,
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    i + 1,
+                    display(&p.r1_path),
+                    display(&p.r2_path),
+                    p.records,
+                    p.r1_bytes,
+                    p.r2_bytes
+                )?;
+                for s in [&p.r1_summary, &p.r2_summary] {
+                    // the qualities of records without any are listed as "-"
+                    let quality = s
+                        .mean_quality()
+                        .map_or_else(|| String::from("-"), |q| format!("{:.2}", q));
+                    write!(
+                        m,
+                        "\t{}\t{}\t{:.2}\t{}\t{}",
+                        s.records,
+                        s.min_len,
+                        s.mean_len(),
+                        s.max_len,
+                        quality
+                    )?;
+                }
+                writeln!(m)?;
+            }
+            m.flush()?;
+            info!("wrote output manifest to {:?}", mpath);
+        }
+        Ok(parts)
+    }
+}
+
+/// Flush the streams of a finished part and record it in `parts`.
+fn close_part(parts: &mut Vec<OutputPart>, mut part: OutputPart, w1: Sink, w2: Sink) -> Result<()> {
+    part.r1_bytes = w1.finish()?;
+    part.r2_bytes = w2.finish()?;
+    info!(
+        "finished output part {} ({} fragments)",
+        parts.len() + 1,
+        part.records
+    );
+    parts.push(part);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding_bases_have_the_lowest_quality() {
+        let write = |format: OutputFormat, pads: &[Range<u32>]| {
+            let mut out = vec![];
+            format
+                .write_record(&mut out, b"a", b" CB:Z:AC", b"ACGTAC", pads)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            write(OutputFormat::Fastq, &[1..2, 4..6]),
+            "@a CB:Z:AC\nACGTAC\n+\nI#II##\n"
+        );
+        // the ranges are clamped to the sequence, which may have been
+        // shortened after being parsed
+        assert_eq!(
+            write(OutputFormat::Fastq, &[0..1, 5..9, 12..14]),
+            "@a CB:Z:AC\nACGTAC\n+\n#IIII#\n"
+        );
+        assert_eq!(OutputFormat::Fastq.quality_sum(6, &[0..1, 5..9]), Some(164));
+        assert_eq!(OutputFormat::Fastq.quality_sum(6, &[]), Some(240));
+        // nor do the records without qualities carry the padding
+        assert_eq!(
+            write(OutputFormat::Fasta, &[1..2, 4..6]),
+            ">a CB:Z:AC\nACGTAC\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Raw, &[1..2, 4..6]),
+            "a\tCB:Z:AC\nACGTAC\n"
+        );
+        assert_eq!(OutputFormat::Fasta.quality_sum(6, &[1..2, 4..6]), None);
+    }
+
+    #[test]
+    fn byte_sizes_and_part_names() {
+        assert_eq!(parse_byte_size("4096").unwrap(), 4096);
+        assert_eq!(parse_byte_size("10k").unwrap(), 10 * 1024);
+        assert_eq!(parse_byte_size("50G").unwrap(), 50 * (1u64 << 30));
+        assert_eq!(parse_byte_size("2MiB").unwrap(), 2 * (1u64 << 20));
+        assert!(parse_byte_size("0").is_err());
+        assert!(parse_byte_size("lots").is_err());
+
+        assert_eq!(
+            part_path(Path::new("out/r1.fastq.gz"), 3),
+            PathBuf::from("out/r1.part0003.fastq.gz")
+        );
+        assert_eq!(part_path(Path::new("r2"), 12), PathBuf::from("r2.part0012"));
+        assert_eq!(
+            manifest_path(Path::new("out/r1.fa")),
+            PathBuf::from("out/r1.fa.manifest.tsv")
+        );
+    }
+
+    #[test]
+    fn compression_detection() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("r.fq");
+        std::fs::write(&plain, "@r\nACGT\n+\nIIII\n").unwrap();
+        assert_eq!(detect_compression(&plain).unwrap(), Compression::None);
+        let gz = dir.path().join("r.fq.gz");
+        let cfg = SinkConfig {
+            compression: Compression::Gzip(1),
+            ..SinkConfig::default()
+        };
+        Sink::open(Some(&gz), &cfg).unwrap().finish().unwrap();
+        assert_eq!(
+            detect_compression(&gz).unwrap(),
+            Compression::Gzip(Compression::DEFAULT_GZIP_LEVEL)
+        );
+        let bgz = dir.path().join("r.fq.bgz");
+        let cfg = SinkConfig {
+            compression: Compression::Bgzf(1),
+            ..SinkConfig::default()
+        };
+        let mut sink = Sink::open(Some(&bgz), &cfg).unwrap();
+        sink.write_all(b"@r\nACGT\n+\nIIII\n").unwrap();
+        sink.finish().unwrap();
+        assert_eq!(
+            detect_compression(&bgz).unwrap(),
+            Compression::Bgzf(Compression::DEFAULT_GZIP_LEVEL)
+        );
+        assert_eq!(
+            compression_from_extension(&bgz),
+            Some(Compression::Bgzf(Compression::DEFAULT_GZIP_LEVEL))
+        );
+        assert_eq!(compression_from_extension(&plain), None);
+        let zst = dir.path().join("r.fq.zst");
+        std::fs::write(
+            &zst,
+            zstd::encode_all(&b"@r\nACGT\n+\nIIII\n"[..], 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            detect_compression(&zst).unwrap(),
+            Compression::Zstd(Compression::DEFAULT_ZSTD_LEVEL)
+        );
+        let empty = dir.path().join("empty.fq");
+        std::fs::write(&empty, "").unwrap();
+        assert_eq!(detect_compression(&empty).unwrap(), Compression::None);
+    }
+
+    #[test]
+    fn compression_specs() {
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+        assert_eq!(
+            "gzip".parse::<Compression>().unwrap(),
+            Compression::Gzip(Compression::DEFAULT_GZIP_LEVEL)
+        );
+        assert_eq!("gz:9".parse::<Compression>().unwrap(), Compression::Gzip(9));
+        assert_eq!(
+            "zstd:19".parse::<Compression>().unwrap(),
+            Compression::Zstd(19)
+        );
+        assert_eq!(
+            "bgzf:4".parse::<Compression>().unwrap(),
+            Compression::Bgzf(4)
+        );
+        assert!("gzip:10".parse::<Compression>().is_err());
+        assert!("zstd:0".parse::<Compression>().is_err());
+        assert!("bz2".parse::<Compression>().is_err());
+        assert_eq!(Compression::Zstd(7).to_string(), "zstd:7");
+    }
+
+    #[test]
+    fn record_framing() {
+        let write = |format: OutputFormat| {
+            let mut out = vec![];
+            format
+                .write_record(&mut out, b"r1", b" feature=CD3 duplicate=1", b"ACGT", &[])
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            write(OutputFormat::Fasta),
+            ">r1 feature=CD3 duplicate=1\nACGT\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Fastq),
+            "@r1 feature=CD3 duplicate=1\nACGT\n+\nIIII\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Raw),
+            "r1\tfeature=CD3\tduplicate=1\nACGT\n"
+        );
+        assert_eq!("FQ".parse::<OutputFormat>().unwrap(), OutputFormat::Fastq);
+        assert!("sam".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn omitted_output_is_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let out2 = dir.path().join("r2.fa");
+        let cfg = SinkConfig::default();
+        let mut w = PairedWriter::new(
+            None,
+            Some(out2.clone()),
+            &cfg,
+            &cfg,
+            Some(SplitPolicy::Records(2)),
+        )
+        .unwrap();
+        for _ in 0..3 {
+            let (s1, s2) = w.streams();
+            s1.write_all(b">r\n\n").unwrap();
+            s2.write_all(b">r\nACGT\n").unwrap();
+            w.end_record().unwrap();
+        }
+        let parts = w.finish().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|p| p.r1_path.is_none() && p.r1_bytes == 0));
+        assert_eq!(parts[1].records, 1);
+
+        let mut written: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort();
+        assert_eq!(
+            written,
+            ["r2.fa.manifest.tsv", "r2.part0001.fa", "r2.part0002.fa"]
+        );
+        assert!(PairedWriter::new(None, None, &cfg, &cfg, None).is_err());
+    }
+
+    #[test]
+    fn manifest_summarizes_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let out1 = dir.path().join("r1.fq");
+        let cfg = SinkConfig::default();
+        let mut w = PairedWriter::new(
+            Some(out1.clone()),
+            Some(dir.path().join("r2.fq")),
+            &cfg,
+            &cfg,
+            Some(SplitPolicy::Records(2)),
+        )
+        .unwrap();
+        let headers = [(&b"r"[..], &b""[..]); 2];
+        // the sequences are padded from their `unpadded`-th base
+        for (s1, unpadded) in [("ACGTAA", 4), ("ACG", 3), ("ACGTACGT", 8)] {
+            let pads: Vec<Range<u32>> = std::iter::once(unpadded..s1.len() as u32)
+                .filter(|r| !r.is_empty())
+                .collect();
+            let frag = crate::SeqPair {
+                s1: s1.as_bytes().to_vec(),
+                s2: b"TTTT".to_vec(),
+            };
+            crate::write_fragment(&mut w, OutputFormat::Fastq, headers, &frag, [&pads, &[]])
+                .unwrap();
+        }
+        let parts = w.finish().unwrap();
+        let s = parts[0].r1_summary;
+        assert_eq!((s.records, s.min_len, s.max_len), (2, 3, 6));
+        assert_eq!(s.mean_len(), 4.5);
+        // 7 bases of quality 40 and 2 padding bases of quality 2
+        assert_eq!(s.mean_quality(), Some(284.0 / 9.0));
+        assert_eq!(parts[1].r2_summary.mean_quality(), Some(40.0));
+
+        let manifest = std::fs::read_to_string(manifest_path(&out1)).unwrap();
+        let lines: Vec<Vec<&str>> = manifest.lines().map(|l| l.split('\t').collect()).collect();
+        assert_eq!(
+            lines[0][6..11],
+            [
+                "r1_records",
+                "r1_min_len",
+                "r1_mean_len",
+                "r1_max_len",
+                "r1_mean_quality"
+            ]
+        );
+        assert_eq!(lines[1][6..11], ["2", "3", "4.50", "6", "31.56"]);
+        assert_eq!(lines[2][11..], ["1", "4", "4.00", "4", "40.00"]);
+    }
+}
@@ -0,0 +1,231 @@
+//! Validation of the pairing of the read 1 and read 2 records.
+//!
+//! The fragments are formed by reading the read 1 and read 2 inputs in step,
+//! so inputs that fell out of sync (e.g. a truncated download, or files of
+//! different runs) silently produce fragments from unrelated reads.  While
+//! transforming, the names of the read 1 and read 2 records of each fragment
+//! are compared (see [`read_name`]), and the records of the longer input left
+//! without a mate once the shorter one ends are counted.  By default, the
+//! problems found are reported as warnings, and counted in
+//! [`crate::XformStats::pairing`]; with [`crate::XformOptions::strict_pairing`]
+//! the transformation fails instead.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+/// The problems found in the pairing of the read 1 and read 2 records.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PairingCounts {
+    /// Fragments whose read 1 and read 2 records have different names.
+    pub mismatched_names: u64,
+    /// The records of read 1 and of read 2 that were not transformed, as
+    /// the other input had ended.
+    pub unpaired: [u64; 2],
+}
+
+impl PairingCounts {
+    /// Returns true if no problem was found.
+    pub fn is_synchronized(&self) -> bool {
+        self.mismatched_names == 0 && self.unpaired == [0, 0]
+    }
+
+    /// Adds the counts of `other` to these.
+    pub fn add(&mut self, other: &PairingCounts) {
+        self.mismatched_names += other.mismatched_names;
+        self.unpaired[0] += other.unpaired[0];
+        self.unpaired[1] += other.unpaired[1];
+    }
+}
+
+/// Returns the name of the read whose header (without the leading `>` or
+/// `@`) is `header`: the header up to its first whitespace, without the
+/// `/1` or `/2` suffix marking the mates of a pair.
+pub fn read_name(header: &[u8]) -> &[u8] {
+    let end = header
+        .iter()
+        .position(|c| c.is_ascii_whitespace())
+        .unwrap_or(header.len());
+    let name = &header[..end];
+    match name {
+        [rest @ .., b'/', b'1' | b'2'] => rest,
+        _ => name,
+    }
+}
+
+/// Checks the pairing of the records of a pair of inputs, counting the
+/// problems found or failing on the first one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PairingChecker {
+    pub(crate) strict: bool,
+}
+
+impl PairingChecker {
+    /// Checks that the read 1 and read 2 records of the `fragment`-th
+    /// (0-based) fragment, whose headers are `header1` and `header2`, have the
+    /// same name.
+    pub(crate) fn check_names(
+        &self,
+        fragment: u64,
+        header1: &[u8],
+        header2: &[u8],
+        counts: &mut PairingCounts,
+    ) -> Result<()> {
+        let (name1, name2) = (read_name(header1), read_name(header2));
+        if name1 == name2 {
+            return Ok(());
+        }
+        if self.strict {
+            bail!(
+                "the read 1 and read 2 records of fragment {} have different names ({} and {}); \
+                 the inputs are not paired",
+                fragment + 1,
+                String::from_utf8_lossy(name1),
+                String::from_utf8_lossy(name2)
+            );
+        }
+        counts.mismatched_names += 1;
+        Ok(())
+    }
+
+    /// Reports the problems found in the pairing of the inputs `r1` and `r2`,
+    /// once all of their records have been read.
+    pub(crate) fn report(&self, r1: &Path, r2: &Path, counts: &PairingCounts) -> Result<()> {
+        if counts.mismatched_names > 0 {
+            warn!(
+                "{} fragments of {} / {} have read 1 and read 2 records with different names",
+                counts.mismatched_names,
+                r1.display(),
+                r2.display()
+            );
+        }
+        for (read, (p, n)) in [r1, r2].iter().zip(counts.unpaired).enumerate() {
+            if n == 0 {
+                continue;
+            }
+            if self.strict {
+                bail!(
+                    "{} holds {} more records than its mate; the inputs are not paired",
+                    p.display(),
+                    n
+                );
+            }
+            warn!(
+                "the last {} read {} records of {} have no mate, and were not transformed",
+                n,
+                read + 1,
+                p.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn mates_are_named_without_their_suffix() {
+        assert_eq!(read_name(b"r1/1 BC:Z:AC"), b"r1");
+        assert_eq!(read_name(b"r1/2"), b"r1");
+        assert_eq!(read_name(b"SRR1.1 1:N:0"), b"SRR1.1");
+        assert_eq!(read_name(b"r1\tx/1"), b"r1");
+        // only the suffixes of the mates of a pair are removed
+        assert_eq!(read_name(b"r1/3"), b"r1/3");
+        assert_eq!(read_name(b"r1/1/1"), b"r1/1");
+        assert_eq!(read_name(b"/1"), b"");
+        assert_eq!(read_name(b""), b"");
+    }
+
+    #[test]
+    fn mismatched_names_are_counted_or_fail() {
+        let mut counts = PairingCounts::default();
+        let lenient = PairingChecker { strict: false };
+        lenient
+            .check_names(0, b"a/1", b"a/2 x", &mut counts)
+            .unwrap();
+        lenient.check_names(1, b"a/1", b"b/2", &mut counts).unwrap();
+        assert_eq!(counts.mismatched_names, 1);
+        assert!(!counts.is_synchronized());
+
+        let strict = PairingChecker { strict: true };
+        strict.check_names(0, b"a", b"a", &mut counts).unwrap();
+        let err = strict.check_names(1, b"a", b"b", &mut counts).unwrap_err();
+        assert!(err.to_string().contains("fragment 2"), "{}", err);
+        assert_eq!(counts.mismatched_names, 1);
+    }
+
+    #[test]
+    fn unpaired_records_are_reported() {
+        let (r1, r2) = (Path::new("r1.fa"), Path::new("r2.fa"));
+        let mut counts = PairingCounts::default();
+        assert!(counts.is_synchronized());
+        PairingChecker { strict: true }
+            .report(r1, r2, &counts)
+            .unwrap();
+
+        counts.add(&PairingCounts {
+            mismatched_names: 2,
+            unpaired: [0, 3],
+        });
+        counts.add(&PairingCounts {
+            mismatched_names: 1,
+            unpaired: [1, 0],
+        });
+        assert_eq!(
+            counts,
+            PairingCounts {
+                mismatched_names: 3,
+                unpaired: [1, 3]
+            }
+        );
+        PairingChecker { strict: false }
+            .report(r1, r2, &counts)
+            .unwrap();
+        let err = PairingChecker { strict: true }
+            .report(r1, r2, &counts)
+            .unwrap_err();
+        assert!(err.to_string().contains("r1.fa holds 1 more"), "{}", err);
+    }
+
+    #[test]
+    fn desynchronized_inputs_are_detected() {
+        let geo = FragmentGeomDesc::try_from("1{b[2]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let xform = |r2: &str, strict_pairing: bool| {
+            let opts = XformOptions {
+                strict_pairing,
+                ..XformOptions::default()
+            };
+            xform_in_dir(
+                dir.path(),
+                geo.as_regex().unwrap(),
+                ">a/1\nAACC\n>b/1\nGGTT\n>c/1\nACGT\n",
+                r2,
+                &opts,
+            )
+        };
+        let desynchronized = ">a/2\nTTTT\n>x/2\nGGGG\n";
+        let stats = xform(desynchronized, false).unwrap().stats;
+        let expected = PairingCounts {
+            mismatched_names: 1,
+            unpaired: [1, 0],
+        };
+        assert_eq!(stats.pairing, expected);
+        assert_eq!(stats.input_pairs[0].pairing, expected);
+        assert_eq!(stats.total_fragments, 2);
+        assert!(xform(desynchronized, true).is_err());
+
+        // the unpaired records alone fail a strict transformation
+        let truncated = ">a/2\nTTTT\n>b/2\nGGGG\n";
+        let stats = xform(truncated, false).unwrap().stats;
+        assert_eq!(stats.pairing.unpaired, [1, 0]);
+        assert!(xform(truncated, true).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Parsing the fragments of a batch on several threads.
+//!
+//! Matching the reads against the regexes of a geometry is the bulk of the
+//! work of a transformation.  A [`BatchParser`] splits each batch of
+//! fragments (see the `batch` module) into contiguous chunks, which are
+//! parsed by as many threads, each with its own copy of the
+//! `FragmentRegexDesc`.  The parsed fragments are kept in the order of the
+//! batch, so that the rest of the transformation (duplicate detection, UMI
+//! correction, writing, ...), which is done on the calling thread, sees the
+//! fragments in the order of the input whatever the number of threads.
+
+use std::ops::Range;
+use std::thread;
+
+use crate::batch::{BatchFragment, FragmentBatch};
+use crate::failure::{FailureCause, ParseFailure};
+use crate::{FragmentRegexDesc, SeqPair};
+
+/// The outcome of parsing one fragment of a batch.
+#[derive(Debug, Default)]
+pub(crate) struct ParsedFragment {
+    /// Whether the fragment matched the geometry.
+    pub(crate) matched: bool,
+    /// Whether read 1 and read 2 were matched as their reverse complement.
+    pub(crate) reversed: [bool; 2],
+    /// Whether the fragment only matched with mismatches in its anchors.
+    pub(crate) rescued: bool,
+    /// The captured lengths of the variable length pieces of each read.
+    pub(crate) lens: [Vec<u32>; 2],
+    /// The ranges of the padding in each transformed read (see
+    /// [`FragmentRegexDesc::last_padding`]).
+    pub(crate) pads: [Vec<Range<u32>>; 2],
+    /// Why the fragment did not match, if it did not and failures are
+    /// diagnosed.
+    pub(crate) failure: Option<ParseFailure>,
+    /// The transformed fragment, if it matched.
+    pub(crate) seqs: SeqPair,
+    /// The transformed index reads (read 3 and read 4) of the fragment, if
+    /// it matched.
+    pub(crate) index: SeqPair,
+    /// The ranges of the padding in each transformed index read.
+    pub(crate) index_pads: [Vec<Range<u32>>; 2],
+}
+
+/// Parses the fragments of batches with a geometry, on `num_threads` threads.
+pub(crate) struct BatchParser {
+    /// One copy of the geometry per thread.
+    workers: Vec<FragmentRegexDesc>,
+    /// The parsed fragments of the last batch; their buffers are reused.
+    parsed: Vec<ParsedFragment>,
+    /// Whether to diagnose the fragments that fail to parse.
+    diagnose: bool,
+}
+
+impl BatchParser {
+    /// Creates a `BatchParser` parsing fragments with `geo_re` on
+    /// `num_threads` threads.  With 0 or 1 threads, the fragments are
+    /// parsed on the calling thread.  If `diagnose` is true, the fragments
+    /// that fail to parse are diagnosed (see
+    /// [`FragmentRegexDesc::diagnose_failure`]).
+    pub(crate) fn new(geo_re: &FragmentRegexDesc, num_threads: usize, diagnose: bool) -> Self {
+        Self {
+            workers: vec![geo_re.clone(); num_threads.max(1)],
+            parsed: Vec::new(),
+            diagnose,
+        }
+    }
+
+    /// Parses the fragments of `batch`, returning the outcome for each of
+    /// them in the order of the batch.
+    pub(crate) fn parse(&mut self, batch: &FragmentBatch) -> &[ParsedFragment] {
+        let frags: Vec<BatchFragment> = batch.iter().collect();
+        if self.parsed.len() < frags.len() {
+            self.parsed
+                .resize_with(frags.len(), ParsedFragment::default);
+        }
+        let parsed = &mut self.parsed[..frags.len()];
+        if self.workers.len() == 1 || frags.len() < self.workers.len() {
+            parse_chunk(&mut self.workers[0], &frags, parsed, self.diagnose);
+        } else {
+            let chunk_len = frags.len().div_ceil(self.workers.len());
+            let diagnose = self.diagnose;
+            thread::scope(|s| {
+                for ((geo_re, frags), parsed) in self
+                    .workers
+                    .iter_mut()
+                    .zip(frags.chunks(chunk_len))
+                    .zip(parsed.chunks_mut(chunk_len))
+                {
+                    s.spawn(move || parse_chunk(geo_re, frags, parsed, diagnose));
+                }
+            });
+        }
+        &self.parsed[..frags.len()]
+    }
+}
+
+fn parse_chunk(
+    geo_re: &mut FragmentRegexDesc,
+    frags: &[BatchFragment],
+    out: &mut [ParsedFragment],
+    diagnose: bool,
+) {
+    for (frag, p) in frags.iter().zip(out.iter_mut()) {
+        let pair_matched = geo_re.parse_into(frag.seqs[0], frag.seqs[1], &mut p.seqs);
+        let index_matched = pair_matched && geo_re.parse_index_into(frag.index, &mut p.index);
+        p.matched = index_matched && geo_re.parse_name_into(frag.ids[0], &mut p.seqs);
+        p.reversed = geo_re.last_reversed();
+        p.rescued = geo_re.last_rescued();
+        for (lens, last) in p.lens.iter_mut().zip(geo_re.last_lens()) {
+            lens.clear();
+            lens.extend_from_slice(last);
+        }
+        for (pads, last) in p.pads.iter_mut().zip(geo_re.last_padding()) {
+            pads.clear();
+            pads.extend_from_slice(last);
+        }
+        for (pads, last) in p.index_pads.iter_mut().zip(geo_re.last_index_padding()) {
+            pads.clear();
+            pads.extend_from_slice(last);
+        }
+        p.failure = match (diagnose && !p.matched, pair_matched, index_matched) {
+            (false, _, _) => None,
+            (true, false, _) => Some(geo_re.diagnose_failure(frag.seqs[0], frag.seqs[1])),
+            (true, true, false) => geo_re.diagnose_index_failure(frag.index),
+            (true, true, true) => Some(ParseFailure {
+                read: 1,
+                cause: FailureCause::NameNotMatched,
+            }),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{xform_read_pairs_to_file_with_options, FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn output_does_not_depend_on_the_number_of_threads() {
+        let geo = FragmentGeomDesc::try_from("1{b[3-4]f[ACG]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        let (mut s1, mut s2) = (String::new(), String::new());
+        for i in 0..10_000 {
+            let bc = ["TTT", "TTTT", "TTTTT"][i % 3];
+            s1 += &format!(">r{}\n{}ACGCC\n", i, bc);
+            s2 += &format!(">r{}\nGG{}\n", i, "A".repeat(i % 11))
+        }
+        std::fs::write(&r1, s1).unwrap();
+        std::fs::write(&r2, s2).unwrap();
+
+        let mut outputs = vec![];
+        for num_threads in [1, 3, 8] {
+            let (o1, o2) = (
+                dir.path().join(format!("o1_{}.fa", num_threads)),
+                dir.path().join(format!("o2_{}.fa", num_threads)),
+            );
+            let opts = XformOptions {
+                num_threads,
+                ..XformOptions::default()
+            };
+            let stats = xform_read_pairs_to_file_with_options(
+                geo.as_regex().unwrap(),
+                std::slice::from_ref(&r1),
+                std::slice::from_ref(&r2),
+                Some(o1.clone()),
+                Some(o2.clone()),
+                &opts,
+            )
+            .unwrap();
+            assert_eq!(stats.failed_parsing, 3_333);
+            outputs.push((std::fs::read(o1).unwrap(), std::fs::read(o2).unwrap()));
+        }
+        assert!(outputs.windows(2).all(|w| w[0] == w[1]));
+    }
+}
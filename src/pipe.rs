@@ -0,0 +1,159 @@
+//! The named pipes into which the transformed reads are streamed by
+//! [`crate::xform_read_pairs_to_fifo`].
+//!
+//! On Unix, the pipes are fifos created (with `mkfifo`) in a temporary
+//! directory.  On Windows, they are named pipes in the `\\.\pipe\` namespace,
+//! created by this crate as their server end; a consumer opens them by path as
+//! it would open a file, and the transformation starts writing into a pipe
+//! once its consumer has connected, just as it starts writing into a fifo once
+//! its consumer has opened it.  In both cases, opening a pipe for writing (see
+//! [`open_write`]) blocks until it has a reader.
+
+use std::path::{Path, PathBuf};
+
+/// The path of the pipe named `name` (e.g. `r1.pipe`), for a transformation
+/// whose temporary files are held in `tmp_dir`.
+pub(crate) fn pipe_path(tmp_dir: &Path, name: &str) -> PathBuf {
+    if cfg!(windows) {
+        // named pipes live in their own namespace, where the (unique) name
+        // of the temporary directory keeps concurrent transformations apart
+        let unique = tmp_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().trim_start_matches('.').to_owned())
+            .unwrap_or_default();
+        PathBuf::from(format!(r"\\.\pipe\seq_geom_xform-{}-{}", unique, name))
+    } else {
+        tmp_dir.join(name)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+
+    use anyhow::{bail, Result};
+    use nix::fcntl::{self, OFlag};
+    use nix::sys::stat;
+    use nix::unistd;
+
+    /// Creates the pipe `p`.
+    pub(crate) fn create(p: &Path) -> Result<()> {
+        // give read, write and execute rights to the owner
+        if let Err(err) = unistd::mkfifo(p, stat::Mode::S_IRWXU) {
+            bail!("Error creating fifo {}: {}", p.display(), err);
+        }
+        Ok(())
+    }
+
+    /// Opens the pipe `p` for writing, blocking until it has a reader.
+    pub(crate) fn open_write(p: &Path) -> io::Result<File> {
+        File::create(p)
+    }
+
+    /// Opens and immediately closes the write end of the fifo `p`, without
+    /// blocking, so that a reader waiting to open it (or reading from it)
+    /// gets an end of file.  This does nothing if the fifo has no reader.
+    pub(crate) fn release(p: &Path) {
+        if let Ok(fd) = fcntl::open(p, OFlag::O_WRONLY | OFlag::O_NONBLOCK, stat::Mode::empty()) {
+            let _ = unistd::close(fd);
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) use unix::{create, open_write, release};
+
+#[cfg(windows)]
+mod windows {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{FromRawHandle, RawHandle};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    use anyhow::{bail, Result};
+    use windows_sys::Win32::Foundation::{
+        CloseHandle, ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE,
+    };
+    use windows_sys::Win32::Storage::FileSystem::PIPE_ACCESS_OUTBOUND;
+    use windows_sys::Win32::System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeW, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_WAIT,
+    };
+
+    /// The server ends of the pipes that were created but not opened yet.
+    static PENDING: Mutex<Vec<(PathBuf, Pending)>> = Mutex::new(Vec::new());
+
+    struct Pending(HANDLE);
+
+    // the handle is only ever used by the thread that takes it out of PENDING
+    unsafe impl Send for Pending {}
+
+    impl Drop for Pending {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    fn take(p: &Path) -> Option<Pending> {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        let i = pending.iter().position(|(q, _)| q == p)?;
+        Some(pending.swap_remove(i).1)
+    }
+
+    pub(crate) fn create(p: &Path) -> Result<()> {
+        let name: Vec<u16> = p.as_os_str().encode_wide().chain([0]).collect();
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_OUTBOUND,
+                PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                1 << 16,
+                0,
+                0,
+                std::ptr::null(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            bail!(
+                "Error creating named pipe {}: {}",
+                p.display(),
+                io::Error::last_os_error()
+            );
+        }
+        PENDING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((p.to_path_buf(), Pending(handle)));
+        Ok(())
+    }
+
+    pub(crate) fn open_write(p: &Path) -> io::Result<File> {
+        // any other path is opened as a file
+        let Some(pipe) = take(p) else {
+            return File::create(p);
+        };
+        if unsafe { ConnectNamedPipe(pipe.0, std::ptr::null_mut()) } == 0 {
+            let err = io::Error::last_os_error();
+            // the reader may have connected before we started waiting
+            if err.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                return Err(err);
+            }
+        }
+        let handle = pipe.0;
+        std::mem::forget(pipe);
+        Ok(unsafe { File::from_raw_handle(handle as RawHandle) })
+    }
+
+    pub(crate) fn release(p: &Path) {
+        // closing the server end makes a waiting reader fail to connect, and
+        // a connected reader see the end of the pipe
+        drop(take(p));
+    }
+}
+
+#[cfg(windows)]
+pub(crate) use windows::{create, open_write, release};
@@ -0,0 +1,134 @@
+//! Named geometries of common single-cell chemistries.
+//!
+//! Rather than writing out the geometry of a widely used chemistry, it can be
+//! looked up by name (e.g. `sciseq3` or `10xv3`) with [`geometry`], or with
+//! the `--chemistry` option of `seq_xformer`.  Names are matched ignoring
+//! case, `-` and `_`, so `sci-RNA-seq3` and `sciseq3` are the same preset.
+
+use anyhow::{anyhow, Result};
+use seq_geom_parser::FragmentGeomDesc;
+
+/// A named geometry of a chemistry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chemistry {
+    /// The name under which the preset is listed.
+    pub name: &'static str,
+    /// Other names under which the preset can be looked up.
+    pub aliases: &'static [&'static str],
+    /// A short description of the chemistry.
+    pub description: &'static str,
+    /// The geometry specification of the chemistry.
+    pub geometry: &'static str,
+}
+
+impl Chemistry {
+    /// Parses the geometry of the chemistry.
+    pub fn geom_desc(&self) -> Result<FragmentGeomDesc> {
+        FragmentGeomDesc::try_from(self.geometry)
+            .map_err(|e| anyhow!("invalid geometry for chemistry {}: {:?}", self.name, e))
+    }
+}
+
+/// The known chemistries.
+pub const CHEMISTRIES: &[Chemistry] = &[
+    Chemistry {
+        name: "sciseq3",
+        aliases: &["sci-rna-seq3"],
+        description: "sci-RNA-seq3: a 9-10 base RT barcode, the CAGAGC linker, an 8 base \
+                      UMI and a 10 base ligation barcode on read 1",
+        geometry: "1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}",
+    },
+    Chemistry {
+        name: "10xv2",
+        aliases: &["chromium-v2"],
+        description: "10x Genomics Chromium 3' v2: a 16 base barcode and a 10 base UMI on \
+                      read 1",
+        geometry: "1{b[16]u[10]x:}2{r:}",
+    },
+    Chemistry {
+        name: "10xv3",
+        aliases: &["chromium-v3"],
+        description: "10x Genomics Chromium 3' v3: a 16 base barcode and a 12 base UMI on \
+                      read 1",
+        geometry: "1{b[16]u[12]x:}2{r:}",
+    },
+    Chemistry {
+        name: "splitseq",
+        aliases: &["split-seq"],
+        description: "SPLiT-seq: a 10 base UMI and three 8 base barcodes separated by 30 \
+                      base linkers on read 2, the cDNA on read 1",
+        geometry: "1{r:}2{u[10]b[8]x[30]b[8]x[30]b[8]x:}",
+    },
+    Chemistry {
+        name: "indrop",
+        aliases: &["indrops"],
+        description: "inDrop (v2): an 8-11 base barcode, the W1 adapter, an 8 base barcode \
+                      and a 6 base UMI on read 1",
+        geometry: "1{b[8-11]f[GAGTGATTGCTTGTGACGCCTT]b[8]u[6]x:}2{r:}",
+    },
+    Chemistry {
+        name: "dropseq",
+        aliases: &["drop-seq"],
+        description: "Drop-seq: a 12 base barcode and an 8 base UMI on read 1",
+        geometry: "1{b[12]u[8]x:}2{r:}",
+    },
+];
+
+/// Normalizes a chemistry name for matching, ignoring case, `-` and `_`.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '-' | '_'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Returns the chemistry named (or aliased) `name`, if any.
+pub fn lookup(name: &str) -> Option<&'static Chemistry> {
+    let name = normalize(name);
+    CHEMISTRIES.iter().find(|c| {
+        std::iter::once(&c.name)
+            .chain(c.aliases)
+            .any(|n| normalize(n) == name)
+    })
+}
+
+/// Returns the chemistry named (or aliased) `name`, failing with the list of
+/// known chemistries if there is none.
+pub fn find(name: &str) -> Result<&'static Chemistry> {
+    lookup(name).ok_or_else(|| {
+        anyhow!(
+            "unknown chemistry {} (expected one of {})",
+            name,
+            CHEMISTRIES
+                .iter()
+                .map(|c| c.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })
+}
+
+/// Returns the geometry of the chemistry named (or aliased) `name`, failing
+/// with the list of known chemistries if there is none.
+pub fn geometry(name: &str) -> Result<FragmentGeomDesc> {
+    find(name)?.geom_desc()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_found_by_name_and_alias() {
+        for c in CHEMISTRIES {
+            assert!(c.geom_desc().is_ok(), "{}", c.name);
+            assert_eq!(lookup(c.name), Some(c));
+        }
+        assert_eq!(lookup("sci-RNA-seq3").unwrap().name, "sciseq3");
+        assert_eq!(lookup("10X_V3").unwrap().name, "10xv3");
+        assert_eq!(lookup("Drop-seq").unwrap().name, "dropseq");
+        assert!(lookup("10xv4").is_none());
+        let err = geometry("10xv4").unwrap_err().to_string();
+        assert!(err.contains("sciseq3, 10xv2"), "{}", err);
+    }
+}
@@ -0,0 +1,156 @@
+//! A preview of the transformation of the first fragments.
+//!
+//! A geometry that does not describe the reads is best noticed before a
+//! multi-hour run.  [`preview_fragments`] transforms the first few fragments
+//! of the inputs, and returns each of them along with its input reads, so
+//! that the two can be compared by eye (as `seq_xformer preview` does).  To
+//! transform only the first fragments into the outputs, see
+//! [`crate::XformOptions::max_records`].
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::decode::ExternalDecoder;
+use crate::malformed::OnError;
+use crate::{input_groups, read_input_group, FragmentRegexDesc, InputFormat, SeqPair};
+
+/// A fragment, as read and as transformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreviewedFragment {
+    /// The headers of the read 1 and read 2 records.
+    pub ids: [Vec<u8>; 2],
+    /// The input read 1 and read 2.
+    pub reads: [Vec<u8>; 2],
+    /// The transformed fragment, or `None` if the fragment does not match
+    /// the geometry.
+    pub transformed: Option<SeqPair>,
+}
+
+/// Transforms the first `num_fragments` fragments of the input files `r1` and
+/// `r2` with `geo_re`.  The read 1 inputs are in the format `input_format`,
+/// and the ORA compressed input files are decoded by running `ora_decoder` on
+/// them.
+pub fn preview_fragments(
+    geo_re: &mut FragmentRegexDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    num_fragments: usize,
+    input_format: InputFormat,
+    ora_decoder: &ExternalDecoder,
+) -> Result<Vec<PreviewedFragment>> {
+    let mut previewed = vec![];
+    'files: for (files1, files2) in input_groups(r1, r2, input_format)? {
+        let mut batches =
+            read_input_group(files1, files2, None, None, OnError::Abort, ora_decoder)?;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                if previewed.len() >= num_fragments {
+                    break 'files;
+                }
+                let mut sp = SeqPair::new();
+                let matched = geo_re.parse_into(frag.seqs[0], frag.seqs[1], &mut sp);
+                previewed.push(PreviewedFragment {
+                    ids: [frag.ids[0].to_vec(), frag.ids[1].to_vec()],
+                    reads: [frag.seqs[0].to_vec(), frag.seqs[1].to_vec()],
+                    transformed: matched.then_some(sp),
+                });
+            }
+            batches.recycle(batch);
+        }
+    }
+    Ok(previewed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn first_fragments_are_previewed() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        std::fs::write(p("r1.fa"), ">a x\nACGTTTC\n>b\nNN\n>c\nGGGGAAC\n").unwrap();
+        std::fs::write(p("r2.fa"), ">a y\nCCCC\n>b\nTTTT\n>c\nTTTT\n").unwrap();
+        let decoder = ExternalDecoder::default();
+
+        let previewed = preview_fragments(
+            &mut geo_re.clone(),
+            &[p("r1.fa")],
+            &[p("r2.fa")],
+            2,
+            InputFormat::default(),
+            &decoder,
+        )
+        .unwrap();
+        assert_eq!(previewed.len(), 2);
+        assert_eq!(previewed[0].ids, [b"a x".to_vec(), b"a y".to_vec()]);
+        assert_eq!(previewed[0].reads[0], b"ACGTTTC");
+        let transformed = previewed[0].transformed.as_ref().unwrap();
+        assert_eq!(
+            (&transformed.s1[..], &transformed.s2[..]),
+            (&b"ACGTTT"[..], &b"CCCC"[..])
+        );
+        assert_eq!(previewed[1].transformed, None);
+
+        // the fragments are taken from one input file after the other
+        std::fs::write(p("r1b.fa"), ">d\nTTTTGGA\n").unwrap();
+        std::fs::write(p("r2b.fa"), ">d\nAAAA\n").unwrap();
+        let preview = |n| {
+            preview_fragments(
+                &mut geo_re.clone(),
+                &[p("r1.fa"), p("r1b.fa")],
+                &[p("r2.fa"), p("r2b.fa")],
+                n,
+                InputFormat::default(),
+                &decoder,
+            )
+            .unwrap()
+        };
+        assert!(preview(0).is_empty());
+        let previewed = preview(10);
+        assert_eq!(previewed.len(), 4);
+        assert_eq!(previewed[3].reads, [b"TTTTGGA".to_vec(), b"AAAA".to_vec()]);
+        assert_eq!(previewed[3].transformed.as_ref().unwrap().s1, b"TTTTGG");
+    }
+
+    #[test]
+    fn first_fragments_are_transformed() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let run = |max_records| {
+            xform_in_dir(
+                dir.path(),
+                geo_re.clone(),
+                ">a x\nACGTTTC\n>b\nNN\n>c\nGGGGAAC\n",
+                ">a y\nCCCC\n>b\nTTTT\n>c\nTTTT\n",
+                &XformOptions {
+                    max_records,
+                    ..XformOptions::default()
+                },
+            )
+            .unwrap()
+        };
+        // only the first fragments are transformed into the outputs
+        let out = run(Some(2));
+        assert_eq!(
+            (out.stats.total_fragments, out.stats.failed_parsing),
+            (2, 1)
+        );
+        assert_eq!(out.out1, ">a x\nACGTTT\n");
+        let out = run(Some(10));
+        assert_eq!(out.stats.total_fragments, 3);
+        assert_eq!(out.out2, ">a y\nCCCC\n>c\nTTTT\n");
+    }
+}
@@ -0,0 +1,341 @@
+//! Matching of the read sequence against the probes of targeted panels.
+//!
+//! In probe-based assays (e.g. the fixed RNA profiling of 10x Genomics), the
+//! "biological" read (the `r` piece of the geometry) starts with (or holds,
+//! at a known offset) the sequence of the probe that hybridized to the
+//! transcript.  When a [`ProbePanel`] is given (through
+//! [`crate::XformOptions::probes`]), this region of each transformed fragment
+//! is looked up in the panel, exactly or with a single mismatch, and the id
+//! of the matching probe is appended to the headers of the records of the
+//! fragment (as `probe=<id>`), so that targeted panels can be quantified
+//! directly from the transformed reads.  The outcomes are counted in
+//! [`crate::XformStats::probes`].
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+/// A panel of probes of the same length, indexed for exact and single
+/// mismatch lookups.
+#[derive(Debug, Clone)]
+pub struct ProbePanel {
+    ids: Vec<String>,
+    seqs: Vec<Vec<u8>>,
+    len: usize,
+    exact: HashMap<Vec<u8>, usize>,
+    /// The probes by the sequence of their first and of their second half;
+    /// a sequence within one mismatch of a probe matches one of its halves
+    /// exactly.
+    halves: [HashMap<Vec<u8>, Vec<usize>>; 2],
+}
+
+impl ProbePanel {
+    /// Create a new `ProbePanel` from pairs of probe ids and sequences.  The
+    /// sequences must be distinct, of the same (non-zero) length, and
+    /// consist only of `ACGT` (case-insensitive).
+    pub fn new(probes: Vec<(String, Vec<u8>)>) -> Result<Self> {
+        let Some(len) = probes.first().map(|(_, s)| s.len()) else {
+            bail!("the probe panel contains no probes");
+        };
+        let mut panel = Self {
+            ids: Vec::with_capacity(probes.len()),
+            seqs: Vec::with_capacity(probes.len()),
+            len,
+            exact: HashMap::with_capacity(probes.len()),
+            halves: [HashMap::new(), HashMap::new()],
+        };
+        for (id, mut seq) in probes {
+            seq.make_ascii_uppercase();
+            if seq.is_empty() || !seq.iter().all(|c| b"ACGT".contains(c)) {
+                bail!(
+                    "invalid sequence {:?} for probe {}",
+                    String::from_utf8_lossy(&seq),
+                    id
+                );
+            }
+            if seq.len() != len {
+                bail!(
+                    "probe {} has {} bases, but the panel has probes of {} bases",
+                    id,
+                    seq.len(),
+                    len
+                );
+            }
+            let i = panel.ids.len();
+            if let Some(&j) = panel.exact.get(&seq) {
+                bail!("probes {} and {} have the same sequence", panel.ids[j], id);
+            }
+            panel.exact.insert(seq.clone(), i);
+            let (h1, h2) = seq.split_at(len / 2);
+            for (h, half) in panel.halves.iter_mut().zip([h1, h2]) {
+                h.entry(half.to_vec()).or_default().push(i);
+            }
+            panel.ids.push(id);
+            panel.seqs.push(seq);
+        }
+        Ok(panel)
+    }
+
+    /// Reads a probe panel from the CSV or TSV file `p`.  See
+    /// [`ProbePanel::from_reader`] for the accepted formats.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let f =
+            File::open(p).with_context(|| format!("could not open probe panel {}", p.display()))?;
+        Self::from_reader(BufReader::new(f))
+            .with_context(|| format!("could not parse probe panel {}", p.display()))
+    }
+
+    /// Reads a probe panel with one probe per line, with comma or tab
+    /// separated fields.  If the first line is a header containing a
+    /// `probe_seq` (or `sequence`) column, as in the probe sets of 10x
+    /// Genomics, the sequences are read from that column and the ids from
+    /// the `probe_id` (or else the `id`) column, and the probes with
+    /// `FALSE` in an `included` column are skipped.  Otherwise, each line
+    /// must consist of the id of a probe followed by its sequence.  Empty
+    /// lines and lines starting with `#` are ignored.
+    pub fn from_reader<R: BufRead>(r: R) -> Result<Self> {
+        let mut lines = r.lines().filter(|l| {
+            l.as_ref()
+                .map_or(true, |l| !l.trim().is_empty() && !l.starts_with('#'))
+        });
+        let Some(first) = lines.next().transpose()? else {
+            bail!("the probe panel contains no probes");
+        };
+        let sep = if first.contains('\t') { '\t' } else { ',' };
+        let split = |l: &str| {
+            l.split(sep)
+                .map(|f| f.trim().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let header = split(&first);
+        let col = |c: &str| header.iter().position(|h| h.eq_ignore_ascii_case(c));
+        let seq_col = col("probe_seq").or(col("sequence"));
+        let (id_col, seq_col, included_col, mut probes) = match seq_col {
+            Some(seq_col) => (
+                col("probe_id").or(col("id")).unwrap_or(0),
+                seq_col,
+                col("included"),
+                vec![],
+            ),
+            None => (0, 1, None, vec![header]),
+        };
+        for l in lines {
+            probes.push(split(&l?));
+        }
+        let mut panel = Vec::with_capacity(probes.len());
+        for p in probes {
+            if let Some(inc) = included_col.and_then(|c| p.get(c)) {
+                if inc.eq_ignore_ascii_case("false") {
+                    continue;
+                }
+            }
+            match (p.get(id_col), p.get(seq_col)) {
+                (Some(id), Some(s)) => panel.push((id.clone(), s.clone().into_bytes())),
+                _ => bail!(
+                    "expected a probe id and sequence, but got {:?}",
+                    p.join(",")
+                ),
+            }
+        }
+        Self::new(panel)
+    }
+
+    /// The number of probes in the panel.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Returns true if the panel contains no probes.
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The length of the probes.
+    pub fn probe_len(&self) -> usize {
+        self.len
+    }
+
+    /// The id of the `i`-th probe.
+    pub fn id(&self, i: usize) -> &str {
+        &self.ids[i]
+    }
+
+    /// Returns the index of the probe matching the start of `s` exactly or,
+    /// if `max_mismatches` is 1, of the single probe within one mismatch of
+    /// it, along with the number of mismatches.
+    pub fn find(&self, s: &[u8], max_mismatches: u32) -> Option<(usize, u32)> {
+        let s = s.get(..self.len)?;
+        if let Some(&i) = self.exact.get(s) {
+            return Some((i, 0));
+        }
+        if max_mismatches == 0 {
+            return None;
+        }
+        let (h1, h2) = s.split_at(self.len / 2);
+        let mut found = None;
+        for (h, half) in self.halves.iter().zip([h1, h2]) {
+            for &i in h.get(half).into_iter().flatten() {
+                let d = self.seqs[i].iter().zip(s).filter(|(a, b)| a != b).count();
+                if d == 1 {
+                    match found {
+                        Some(j) if j != i => return None,
+                        _ => found = Some(i),
+                    }
+                }
+            }
+        }
+        found.map(|i| (i, 1))
+    }
+}
+
+/// Options for matching the transformed fragments against a [`ProbePanel`].
+#[derive(Debug, Clone)]
+pub struct ProbeOptions {
+    /// The panel of probes.
+    pub panel: ProbePanel,
+    /// The offset of the probe in the read sequence (`r`) piece.
+    pub offset: usize,
+    /// The maximum number of mismatches (0 or 1) allowed between a probe and
+    /// the read.
+    pub max_mismatches: u32,
+}
+
+impl ProbeOptions {
+    /// Checks that at most one mismatch is allowed.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_mismatches > 1 {
+            bail!(
+                "at most 1 mismatch can be allowed between a probe and the read, not {}",
+                self.max_mismatches
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The number of transformed fragments matching a probe exactly, with a
+/// mismatch, or matching none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProbeCounts {
+    pub exact: u64,
+    pub corrected: u64,
+    pub unmatched: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    /// The probe set of 10x Genomics of the tests.
+    const PROBE_SET: &str = "#panel_name=test\n\
+                             gene_id,probe_seq,probe_id,included\n\
+                             G1,AACCGGTT,G1|p1,TRUE\n\
+                             G2,ACGTACGT,G2|p1,TRUE\n\
+                             G3,ACGTACGA,G3|p1,FALSE\n";
+
+    #[test]
+    fn probe_panels_are_parsed() {
+        let panel = ProbePanel::from_reader(PROBE_SET.as_bytes()).unwrap();
+        assert_eq!((panel.len(), panel.probe_len()), (2, 8));
+        assert_eq!((panel.id(0), panel.id(1)), ("G1|p1", "G2|p1"));
+        let panel = ProbePanel::from_reader("id\tsequence\np1\tacgt\n".as_bytes()).unwrap();
+        assert_eq!((panel.id(0), panel.find(b"ACGT", 0)), ("p1", Some((0, 0))));
+        let panel =
+            ProbePanel::from_reader("\n# no header\np1,ACGT\np2,TTTT\n".as_bytes()).unwrap();
+        assert_eq!(panel.id(1), "p2");
+
+        let dir = tempdir().unwrap();
+        let p = dir.path().join("probes.csv");
+        std::fs::write(&p, PROBE_SET).unwrap();
+        assert_eq!(ProbePanel::from_path(&p).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_probe_panels_are_rejected() {
+        for panel in [
+            "a,ACGT\nb,ACG\n",
+            "a,ACGT\nb,acgt\n",
+            "a,ACNT\n",
+            "a\n",
+            "# no probes\n",
+            "id,probe_seq,included\na,ACGT,FALSE\n",
+        ] {
+            assert!(
+                ProbePanel::from_reader(panel.as_bytes()).is_err(),
+                "{}",
+                panel
+            );
+        }
+        let opts = |max_mismatches| ProbeOptions {
+            panel: ProbePanel::from_reader(PROBE_SET.as_bytes()).unwrap(),
+            offset: 0,
+            max_mismatches,
+        };
+        opts(1).validate().unwrap();
+        assert!(opts(2).validate().is_err());
+    }
+
+    #[test]
+    fn probes_are_found_within_one_mismatch() {
+        let panel = ProbePanel::from_reader(PROBE_SET.as_bytes()).unwrap();
+        assert_eq!(panel.find(b"AACCGGTTTT", 0), Some((0, 0)));
+        assert_eq!(panel.find(b"AACCGCTT", 0), None);
+        // a mismatch in either half of the probe
+        assert_eq!(panel.find(b"AACCGCTT", 1), Some((0, 1)));
+        assert_eq!(panel.find(b"TCGTACGT", 1), Some((1, 1)));
+        assert_eq!(panel.find(b"TCGTACGC", 1), None);
+        assert_eq!(panel.find(b"ACGTAC", 1), None);
+        // ACGTACGT and ACGTACGA are both one mismatch away from ACGTACGC
+        let ambiguous = ProbePanel::from_reader("a\tACGTACGT\nb\tACGTACGA\n".as_bytes()).unwrap();
+        assert_eq!(ambiguous.find(b"ACGTACGC", 1), None);
+        assert_eq!(ambiguous.find(b"ACGTACGA", 1), Some((1, 0)));
+        // the halves of probes of odd length
+        let odd = ProbePanel::from_reader("a,AACCG\n".as_bytes()).unwrap();
+        assert_eq!(odd.find(b"TACCG", 1), Some((0, 1)));
+        assert_eq!(odd.find(b"AACCT", 1), Some((0, 1)));
+    }
+
+    #[test]
+    fn probes_are_matched_and_tagged() {
+        let panel = ProbePanel::from_reader(PROBE_SET.as_bytes()).unwrap();
+        let geo = FragmentGeomDesc::try_from("1{b[2]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let opts = XformOptions {
+            probes: Some(ProbeOptions {
+                panel,
+                offset: 2,
+                max_mismatches: 1,
+            }),
+            ..XformOptions::default()
+        };
+        let out = xform_in_dir(
+            dir.path(),
+            geo.as_regex().unwrap(),
+            ">a\nAACC\n>b\nGGTT\n>c\nACGT\n",
+            ">a\nTTAACCGGTTTT\n>b\nTTACGTACCTGG\n>c\nTTTTTTTTTTTT\n",
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(
+            out.stats.probes,
+            Some(ProbeCounts {
+                exact: 1,
+                corrected: 1,
+                unmatched: 1
+            })
+        );
+        assert_eq!(
+            out.out1,
+            ">a probe=G1|p1\nAACC\n>b probe=G2|p1\nGGTT\n>c\nACGT\n"
+        );
+    }
+}
@@ -0,0 +1,389 @@
+//! Live statistics of a running transformation.
+//!
+//! The statistics of a transformation (see [`crate::XformStats`]) are only
+//! returned once all of the input has been read.  An [`XformStatsHandle`],
+//! passed in [`crate::XformOptions::progress`] (or returned in
+//! [`crate::FifoXFormData::stats`]), is instead updated as the fragments are
+//! transformed, so that e.g. a GUI or a service embedding the transformation
+//! can poll its progress with [`XformStatsHandle::snapshot`], or be called
+//! back on every update (see [`XformStatsHandle::with_callback`]).
+//!
+//! For long runs on the command line, [`XformStatsHandle::with_logging`]
+//! instead logs the number of fragments transformed so far, the throughput
+//! and, when the size of the read 1 inputs is known, the estimated time
+//! remaining (see [`ProgressLogger`]).
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use thousands::Separable;
+use tracing::info;
+
+use crate::{decode, is_stdin};
+
+/// A point-in-time copy of the counters of a running transformation.  The
+/// counters of successive snapshots of the same transformation never
+/// decrease.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub total_fragments: u64,
+    pub failed_parsing: u64,
+    /// The number of duplicate fragments, if duplicates are detected.
+    pub duplicate_fragments: Option<u64>,
+    /// The number of fragments whose UMI was corrected, if UMIs are
+    /// corrected.
+    pub corrected_umis: Option<u64>,
+    /// The number of retried I/O operations, if I/O operations are retried.
+    pub io_retries: Option<u64>,
+    /// The number of pairs of input files that have been read entirely.
+    pub completed_pairs: usize,
+    /// The number of bytes read so far from the read 1 input files (as
+    /// stored, i.e. compressed if they are).
+    pub input_bytes_read: u64,
+    /// The total size of the read 1 input files, if known (it is not when
+    /// reading from the standard input or from ORA compressed files).
+    pub input_bytes_total: Option<u64>,
+    /// True once the transformation has completed (successfully or not).
+    pub finished: bool,
+}
+
+impl StatsSnapshot {
+    /// The fraction of the fragments observed so far that were succesfully
+    /// parsed and transformed (0 if no fragments were observed).
+    pub fn match_rate(&self) -> f64 {
+        if self.total_fragments > 0 {
+            1_f64 - ((self.failed_parsing as f64) / (self.total_fragments as f64))
+        } else {
+            0_f64
+        }
+    }
+
+    /// The fraction of the read 1 inputs read so far, if their size is
+    /// known.
+    pub fn fraction_read(&self) -> Option<f64> {
+        match self.input_bytes_total {
+            Some(0) => Some(1_f64),
+            Some(t) => Some((self.input_bytes_read as f64 / t as f64).min(1_f64)),
+            None => None,
+        }
+    }
+}
+
+/// A function called with the counters of a transformation on every update.
+pub type ProgressCallback = Arc<dyn Fn(&StatsSnapshot) + Send + Sync>;
+
+/// A handle on the live statistics of a transformation, which may be cloned
+/// and polled from other threads while the transformation runs.
+#[derive(Clone, Default)]
+pub struct XformStatsHandle {
+    current: Arc<Mutex<StatsSnapshot>>,
+    callback: Option<ProgressCallback>,
+}
+
+impl fmt::Debug for XformStatsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("XformStatsHandle")
+            .field("current", &self.current)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
+
+impl XformStatsHandle {
+    /// Create a new `XformStatsHandle`, whose counters are all 0 until the
+    /// transformation it is passed to starts updating them.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new `XformStatsHandle` that, in addition to recording the
+    /// counters, calls `callback` with them on every update (including the
+    /// last one, marking the transformation as finished).  The callback is
+    /// run on the thread of the transformation, so it should return quickly.
+    pub fn with_callback<F: Fn(&StatsSnapshot) + Send + Sync + 'static>(callback: F) -> Self {
+        Self {
+            callback: Some(Arc::new(callback)),
+            ..Self::default()
+        }
+    }
+
+    /// Create a new `XformStatsHandle` logging the progress of the
+    /// transformation every `every` fragments (see [`ProgressLogger`]).
+    pub fn with_logging(every: u64) -> Self {
+        let logger = Mutex::new(ProgressLogger::new(every));
+        Self::with_callback(move |s| match logger.lock() {
+            Ok(mut l) => l.log(s),
+            Err(poisoned) => poisoned.into_inner().log(s),
+        })
+    }
+
+    /// Returns a consistent copy of the counters as of the last update.  The
+    /// counters are updated after each batch of fragments, rather than after
+    /// every fragment.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        match self.current.lock() {
+            Ok(s) => s.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Replaces the counters with `s`.
+    pub(crate) fn publish(&self, s: StatsSnapshot) {
+        if let Some(cb) = &self.callback {
+            cb(&s);
+        }
+        match self.current.lock() {
+            Ok(mut cur) => *cur = s,
+            Err(poisoned) => *poisoned.into_inner() = s,
+        }
+    }
+
+    /// Marks the transformation as completed, leaving the counters as of
+    /// the last update.
+    pub(crate) fn finish(&self) {
+        let snap = match self.current.lock() {
+            Ok(mut cur) => {
+                cur.finished = true;
+                cur.clone()
+            }
+            Err(poisoned) => {
+                let mut cur = poisoned.into_inner();
+                cur.finished = true;
+                cur.clone()
+            }
+        };
+        if let Some(cb) = &self.callback {
+            cb(&snap);
+        }
+    }
+}
+
+/// Logs the progress of a transformation, at the info level, each time
+/// another `every` fragments have been transformed: the number of fragments
+/// transformed so far and the fraction that matched the geometry, the
+/// throughput and, if the size of the read 1 inputs is known, the fraction
+/// read and the estimated time remaining.  As the counters are updated after
+/// each batch of fragments, the messages are logged at the end of the batch
+/// crossing each multiple of `every`.
+#[derive(Debug)]
+pub struct ProgressLogger {
+    every: u64,
+    next: u64,
+    start: Instant,
+}
+
+impl ProgressLogger {
+    /// Create a new `ProgressLogger` logging every `every` fragments, and
+    /// measuring the throughput from now on.
+    pub fn new(every: u64) -> Self {
+        let every = every.max(1);
+        Self {
+            every,
+            next: every,
+            start: Instant::now(),
+        }
+    }
+
+    /// Logs the progress of the transformation if another `every` fragments
+    /// were transformed since the last message.
+    pub fn log(&mut self, s: &StatsSnapshot) {
+        if s.finished || s.total_fragments < self.next {
+            return;
+        }
+        self.next = (s.total_fragments / self.every + 1) * self.every;
+        info!("{}", Self::message(s, self.start.elapsed()));
+    }
+
+    /// The progress message for the counters `s`, `elapsed` after the start
+    /// of the transformation.
+    fn message(s: &StatsSnapshot, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64();
+        let rate = if secs > 0_f64 {
+            (s.total_fragments as f64 / secs) as u64
+        } else {
+            0
+        };
+        let mut msg = format!(
+            "processed {} fragments ({:.2}% matched) in {}, {} fragments/s",
+            s.total_fragments.separate_with_commas(),
+            s.match_rate() * 100_f64,
+            format_duration(secs),
+            rate.separate_with_commas()
+        );
+        if let Some(f) = s.fraction_read() {
+            msg.push_str(&format!("; {:.1}% of the input read", f * 100_f64));
+            if f > 0_f64 {
+                let remaining = secs * (1_f64 - f) / f;
+                msg.push_str(&format!(", about {} remaining", format_duration(remaining)));
+            }
+        }
+        msg
+    }
+}
+
+/// Formats a duration of `secs` seconds as e.g. `1h 02m 03s`, `2m 03s` or
+/// `3s`.
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {:02}m {:02}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// The number of bytes read from the read 1 input files of a
+/// transformation, and their total size.
+#[derive(Debug, Clone)]
+pub(crate) struct InputProgress {
+    read: Arc<AtomicU64>,
+    total: Option<u64>,
+}
+
+impl InputProgress {
+    /// Create a new `InputProgress` for the read 1 input files `files`.
+    pub(crate) fn new(files: &[PathBuf]) -> Self {
+        let total = files
+            .iter()
+            .map(|f| {
+                if is_stdin(f) || decode::is_ora(f) {
+                    None
+                } else {
+                    fs::metadata(f).ok().map(|m| m.len())
+                }
+            })
+            .sum::<Option<u64>>();
+        Self {
+            read: Arc::new(AtomicU64::new(0)),
+            total,
+        }
+    }
+
+    /// The counter to which the bytes read from the files are added.
+    pub(crate) fn counter(&self) -> &Arc<AtomicU64> {
+        &self.read
+    }
+
+    /// Records the bytes read so far, and the total size of the files, in
+    /// `s`.
+    pub(crate) fn fill(&self, s: &mut StatsSnapshot) {
+        s.input_bytes_read = self.read.load(Ordering::Relaxed);
+        s.input_bytes_total = self.total;
+    }
+}
+
+/// A reader adding the number of bytes read from `inner` to a counter.
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    /// Create a new `CountingReader` adding the bytes read from `inner` to
+    /// `count`.
+    pub(crate) fn new(inner: R, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{xform_read_pairs_to_file_with_options, FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn snapshots_follow_the_transformation() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&r1, ">a\nACGTAC\n>b\nAC\n>c\nTTTTTT\n").unwrap();
+        std::fs::write(&r2, ">a\nGG\n>b\nGG\n>c\nGG\n").unwrap();
+
+        let handle = XformStatsHandle::new();
+        assert_eq!(handle.snapshot(), StatsSnapshot::default());
+        let opts = XformOptions {
+            progress: Some(handle.clone()),
+            ..XformOptions::default()
+        };
+        let stats = xform_read_pairs_to_file_with_options(
+            geo_re,
+            std::slice::from_ref(&r1),
+            std::slice::from_ref(&r2),
+            Some(dir.path().join("o1.fa")),
+            Some(dir.path().join("o2.fa")),
+            &opts,
+        )
+        .unwrap();
+        let snap = handle.snapshot();
+        assert!(snap.finished);
+        assert_eq!(snap.total_fragments, stats.total_fragments);
+        assert_eq!(snap.failed_parsing, 1);
+        assert_eq!(snap.completed_pairs, 1);
+        assert!((snap.match_rate() - stats.match_rate()).abs() < 1e-12);
+        let size = std::fs::metadata(&r1).unwrap().len();
+        assert_eq!(snap.input_bytes_read, size);
+        assert_eq!(snap.input_bytes_total, Some(size));
+        assert_eq!(snap.fraction_read(), Some(1_f64));
+
+        // the callback sees every update, up to the finished one
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_cb = seen.clone();
+        let opts = XformOptions {
+            progress: Some(XformStatsHandle::with_callback(move |s| {
+                seen_cb.lock().unwrap().push(s.clone())
+            })),
+            ..XformOptions::default()
+        };
+        xform_read_pairs_to_file_with_options(
+            FragmentGeomDesc::try_from("1{b[4]x:}2{r:}")
+                .unwrap()
+                .as_regex()
+                .unwrap(),
+            &[r1],
+            &[r2],
+            Some(dir.path().join("o1.fa")),
+            Some(dir.path().join("o2.fa")),
+            &opts,
+        )
+        .unwrap();
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() >= 2);
+        assert!(seen.last().unwrap().finished);
+        assert_eq!(seen.last().unwrap().total_fragments, 3);
+
+        let halfway = StatsSnapshot {
+            total_fragments: 1_500_000,
+            failed_parsing: 150_000,
+            input_bytes_read: 250,
+            input_bytes_total: Some(1000),
+            ..StatsSnapshot::default()
+        };
+        assert_eq!(
+            ProgressLogger::message(&halfway, Duration::from_secs(100)),
+            "processed 1,500,000 fragments (90.00% matched) in 1m 40s, 15,000 fragments/s; \
+             25.0% of the input read, about 5m 00s remaining"
+        );
+        assert_eq!(format_duration(3723.4), "1h 02m 03s");
+    }
+}
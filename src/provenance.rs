@@ -0,0 +1,213 @@
+//! Recognizing runs that were already completed.
+//!
+//! Workflow engines retry the steps that they believe failed, and may rerun
+//! a step whose outputs are all there (e.g. after their own state was lost).
+//! The description of a run written to `run_info.json` by `--outdir` records
+//! a [`fingerprint`] of the run: a hash of the version, of the options and
+//! geometry, and of the path, size and modification time of each input
+//! file.  Before a run starts, [`RecordedRun::is_complete`] tells whether
+//! the recorded run had the same fingerprint, succeeded, and left all its
+//! outputs in place, in which case the run can be skipped (or refused, see
+//! [`IfDone`]) rather than recomputed.  Runs reading the standard input (or
+//! any other input that is not a regular file) have no fingerprint, and so
+//! are never taken to be complete.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+
+/// What to do with a run that was already completed (see the module
+/// documentation).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IfDone {
+    /// Run again, overwriting the outputs.
+    #[default]
+    Rerun,
+    /// Leave the outputs as they are, and succeed.
+    Skip,
+    /// Leave the outputs as they are, and fail.
+    Fail,
+}
+
+impl std::str::FromStr for IfDone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rerun" => Ok(IfDone::Rerun),
+            "skip" => Ok(IfDone::Skip),
+            "fail" => Ok(IfDone::Fail),
+            _ => bail!("unknown policy {}; expected rerun, skip or fail", s),
+        }
+    }
+}
+
+impl fmt::Display for IfDone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IfDone::Rerun => "rerun",
+            IfDone::Skip => "skip",
+            IfDone::Fail => "fail",
+        })
+    }
+}
+
+/// Returns the fingerprint of a run described by `options` (which should
+/// hold the version, the options and the geometry of the run) reading the
+/// files `inputs`, as 16 hexadecimal digits, or `None` if one of the inputs
+/// is not a regular file.  The fingerprint changes whenever one of the
+/// inputs is modified, moved, or replaced by another file.
+pub fn fingerprint(options: &str, inputs: &[PathBuf]) -> Option<String> {
+    // 64-bit FNV-1a, which (unlike the hasher of the standard library) is
+    // the same across Rust versions and platforms
+    let mut h = 0xcbf2_9ce4_8422_2325_u64;
+    let mut hash = |bytes: &[u8]| {
+        for &b in bytes {
+            h = (h ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    hash(options.as_bytes());
+    for p in inputs {
+        let meta = std::fs::metadata(p).ok().filter(|m| m.is_file())?;
+        let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        hash(b"\0");
+        hash(p.display().to_string().as_bytes());
+        hash(&meta.len().to_le_bytes());
+        hash(&modified.as_nanos().to_le_bytes());
+    }
+    Some(format!("{:016x}", h))
+}
+
+/// The parts of a `run_info.json` telling whether the run it describes can
+/// stand for a new run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordedRun {
+    /// The fingerprint of the run, if it had one.
+    pub fingerprint: Option<String>,
+    /// Whether the run succeeded.
+    pub succeeded: bool,
+    /// The outputs written by the run.
+    pub outputs: Vec<PathBuf>,
+}
+
+impl RecordedRun {
+    /// Reads the description of a run from the `run_info.json` file `p`, or
+    /// returns `None` if there is no such file.
+    pub fn read(p: &Path) -> Result<Option<Self>> {
+        match std::fs::read_to_string(p) {
+            Ok(json) => Ok(Some(Self::parse(&json))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("couldn't read {}", p.display())),
+        }
+    }
+
+    /// Parses the description of a run from the contents `json` of a
+    /// `run_info.json` file, as laid out by `seq_xformer` (one top-level
+    /// field, and one output, per line).
+    pub fn parse(json: &str) -> Self {
+        let mut run = RecordedRun::default();
+        let mut in_outputs = false;
+        for line in json.lines().map(str::trim) {
+            if in_outputs {
+                if line.starts_with('}') {
+                    in_outputs = false;
+                } else if let Some((_, Some(p))) = json_entry(line) {
+                    run.outputs.push(PathBuf::from(p));
+                }
+                continue;
+            }
+            match json_entry(line) {
+                Some((key, value)) if key == "fingerprint" => run.fingerprint = value,
+                Some((key, value)) if key == "status" => {
+                    run.succeeded = value.as_deref() == Some("succeeded")
+                }
+                _ => in_outputs = line == "\"outputs\": {",
+            }
+        }
+        run
+    }
+
+    /// Whether the recorded run has the fingerprint `fingerprint`,
+    /// succeeded, and all its outputs are still there.
+    pub fn is_complete(&self, fingerprint: &str) -> bool {
+        self.succeeded
+            && self.fingerprint.as_deref() == Some(fingerprint)
+            && self.outputs.iter().all(|p| p.exists())
+    }
+}
+
+/// Parses the line `line` holding a field of a JSON object, returning its
+/// key and its value if it is a string.
+fn json_entry(line: &str) -> Option<(String, Option<String>)> {
+    let (key, rest) = json_string(line)?;
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    Some((key, json_string(rest).map(|(value, _)| value)))
+}
+
+/// Parses the JSON string starting `s`, returning it and what follows it.
+fn json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut out = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 2..])),
+            '\\' => out.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                c => c,
+            }),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failure::push_json_string;
+
+    #[test]
+    fn completed_runs_are_recognized() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("r1.fq"), dir.path().join("out \"1\".fa"));
+        std::fs::write(&input, "@a\nACGT\n+\nIIII\n").unwrap();
+        let f = fingerprint("0.4.0 -g 1{b[4]}", std::slice::from_ref(&input)).unwrap();
+        assert_eq!(
+            fingerprint("0.4.0 -g 1{b[4]}", std::slice::from_ref(&input)),
+            Some(f.clone())
+        );
+        assert_ne!(
+            fingerprint("0.4.0 -g 1{b[5]}", std::slice::from_ref(&input)),
+            Some(f.clone())
+        );
+        assert_eq!(fingerprint("0.4.0", &[PathBuf::from("-")]), None);
+
+        let mut json = String::from("{\n  \"version\": \"0.4.0\",\n  \"fingerprint\": ");
+        push_json_string(&mut json, &f);
+        json += ",\n  \"inputs\": {\"read1\": [\"r1.fq\"]},\n  \"outputs\": {\n    \"out1\": ";
+        push_json_string(&mut json, &output.display().to_string());
+        json += "\n  },\n  \"status\": \"succeeded\",\n  \"exit_code\": 0\n}\n";
+        let run = RecordedRun::parse(&json);
+        assert_eq!(run.outputs, std::slice::from_ref(&output));
+        assert!(!run.is_complete(&f));
+        std::fs::write(&output, ">a\nACGT\n").unwrap();
+        assert!(run.is_complete(&f));
+
+        std::fs::write(&input, "@a\nACGTA\n+\nIIIII\n").unwrap();
+        let changed = fingerprint("0.4.0 -g 1{b[4]}", std::slice::from_ref(&input)).unwrap();
+        assert!(!run.is_complete(&changed));
+        let failed = RecordedRun::parse(&json.replace("\"succeeded\"", "\"failed\""));
+        assert!(!failed.is_complete(&f));
+        assert_eq!(
+            RecordedRun::read(&dir.path().join("none.json")).unwrap(),
+            None
+        );
+    }
+}
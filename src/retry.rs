@@ -0,0 +1,207 @@
+//! Retrying of I/O operations that fail with transient errors.
+//!
+//! On network file systems (e.g. NFS), long runs occasionally see reads or
+//! writes fail with errors such as `EIO` or `ESTALE` that succeed when simply
+//! tried again.  Wrapping the input and output files in a [`Retrying`] reader
+//! or writer makes such operations be retried a bounded number of times, with
+//! an exponentially growing delay between attempts, rather than failing the
+//! whole run.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use nix::errno::Errno;
+use tracing::warn;
+
+/// Options controlling the retrying of I/O operations.
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+    /// The maximum number of times a failing operation is retried.
+    pub max_retries: u32,
+    /// The delay before the first retry of an operation; the delay doubles
+    /// with every further retry (up to [`RetryOptions::MAX_BACKOFF`]).
+    pub backoff: Duration,
+}
+
+impl RetryOptions {
+    /// The default maximum number of times a failing operation is retried.
+    pub const DEFAULT_MAX_RETRIES: u32 = 5;
+    /// The default delay before the first retry of an operation.
+    pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+    /// The longest delay between two attempts of an operation.
+    pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: Self::DEFAULT_MAX_RETRIES,
+            backoff: Self::DEFAULT_BACKOFF,
+        }
+    }
+}
+
+/// Returns true if `e` is an error that may not occur again if the operation
+/// is retried (`EIO`, `ESTALE` or `ETIMEDOUT`).
+#[cfg(unix)]
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error().map(Errno::from_i32),
+        Some(Errno::EIO | Errno::ESTALE | Errno::ETIMEDOUT)
+    )
+}
+
+/// Returns true if `e` is a transient error, after which the operation
+/// is retried (on platforms other than Unix, only timeouts are).
+#[cfg(not(unix))]
+pub fn is_transient(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Retries the operations wrapped with it in accordance with some
+/// [`RetryOptions`], counting the retries.  Clones of a `RetryPolicy` share
+/// the same count, so that a single count covers all of the files of a run.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    opts: RetryOptions,
+    retries: Arc<AtomicU64>,
+}
+
+impl RetryPolicy {
+    /// Create a new `RetryPolicy`, with no retries counted yet.
+    pub fn new(opts: RetryOptions) -> Self {
+        Self {
+            opts,
+            retries: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The number of retries made so far (by this policy and its clones).
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Wraps the reader or writer `inner` so that its operations are
+    /// retried in accordance with this policy.
+    pub fn wrap<T>(&self, inner: T) -> Retrying<T> {
+        Retrying {
+            inner,
+            policy: self.clone(),
+        }
+    }
+
+    /// Runs `op` until it succeeds, fails with an error that is not
+    /// transient, or has been retried `max_retries` times.
+    fn run<R>(&self, mut op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+        let mut backoff = self.opts.backoff;
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if attempt < self.opts.max_retries && is_transient(&e) => {
+                    attempt += 1;
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "I/O error ({}); retrying (attempt {} of {}) in {:?}",
+                        e, attempt, self.opts.max_retries, backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(RetryOptions::MAX_BACKOFF);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// A reader or writer whose operations are retried in accordance with a
+/// [`RetryPolicy`].
+#[derive(Debug)]
+pub struct Retrying<T> {
+    inner: T,
+    policy: RetryPolicy,
+}
+
+impl<T> Retrying<T> {
+    /// The wrapped reader or writer.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for Retrying<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.read(buf))
+    }
+}
+
+impl<W: Write> Write for Retrying<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.flush())
+    }
+}
+
+// the tests simulate Unix I/O errors
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A reader failing with `EIO` a given number of times before each
+    /// successful read.
+    struct Flaky {
+        failures: u32,
+        left: u32,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.left > 0 {
+                self.left -= 1;
+                return Err(io::Error::from_raw_os_error(Errno::EIO as i32));
+            }
+            self.left = self.failures;
+            buf[0] = b'A';
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let policy = RetryPolicy::new(RetryOptions {
+            max_retries: 2,
+            backoff: Duration::ZERO,
+        });
+        let mut buf = [0; 4];
+        let mut r = policy.wrap(Flaky {
+            failures: 2,
+            left: 2,
+        });
+        assert_eq!(r.read(&mut buf).unwrap(), 1);
+        assert_eq!(r.read(&mut buf).unwrap(), 1);
+        assert_eq!(policy.retries(), 4);
+
+        let mut r = policy.wrap(Flaky {
+            failures: 3,
+            left: 3,
+        });
+        assert_eq!(
+            r.read(&mut buf).unwrap_err().raw_os_error(),
+            Some(Errno::EIO as i32)
+        );
+        assert_eq!(policy.retries(), 6);
+
+        // other errors are not retried
+        let not_found = io::Error::from(io::ErrorKind::NotFound);
+        assert!(!is_transient(&not_found));
+    }
+}
@@ -0,0 +1,464 @@
+//! Routing the fragments of a multi-modal library by their structure.
+//!
+//! In multiplexed multi-modal runs, the fragments of several libraries (e.g.
+//! the gene expression and the antibody-derived tag libraries of CITE-seq)
+//! are sequenced in a single pool, and the library of each fragment can only
+//! be told by its structure.  Rather than transforming the whole pool once
+//! per library, [`route_read_pairs_to_files`] reads it once, and matches each
+//! fragment against the geometries of the [`Modality`]s in the order given:
+//! the fragment is transformed by the first geometry it matches, and written
+//! to the outputs of that modality.  The geometries should thus be given from
+//! the most to the least specific: e.g. a geometry with an anchor before a
+//! geometry whose read 2 is a single `r:` piece, which would match the
+//! fragments of both.  The fragments matching no geometry are only counted,
+//! in [`RoutingStats::unrouted`].
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use thousands::Separable;
+
+use crate::decode::ExternalDecoder;
+use crate::lengths::{ranged_pieces, record_lens, PieceLengths};
+use crate::malformed::OnError;
+use crate::output::{OutputFormat, PairedWriter, SinkConfig};
+use crate::pairing::{PairingChecker, PairingCounts};
+use crate::{
+    input_groups, push_lens_tag, read_input_group, write_fragment, FragmentRegexDesc, InputFormat,
+    PaddingScheme, SeqPair,
+};
+
+/// The name under which the fragments matching no modality are reported.
+pub const UNROUTED: &str = "unrouted";
+
+/// A library of a multi-modal pool: the geometry of its fragments, and the
+/// outputs they are written to.
+#[derive(Debug, Clone)]
+pub struct Modality {
+    /// The name of the modality (e.g. `GEX` or `ADT`), which may only contain
+    /// ASCII letters, digits, `_`, `-` and `.` (but not start with `.`), so
+    /// that it can name output files.
+    pub name: String,
+    /// The geometry of the fragments of the modality.
+    pub geo_re: FragmentRegexDesc,
+    /// Where the read 1 records of the modality are written; may be `None`
+    /// if the geometry discards all of read 1.
+    pub r1_ofile: Option<PathBuf>,
+    /// Where the read 2 records of the modality are written; may be `None`
+    /// if the geometry discards all of read 2.
+    pub r2_ofile: Option<PathBuf>,
+}
+
+/// Options controlling how the fragments are read and routed.
+#[derive(Debug, Clone, Default)]
+pub struct RoutingOptions {
+    /// Configuration of the read 1 outputs of the modalities.
+    pub out1: SinkConfig,
+    /// Configuration of the read 2 outputs of the modalities.
+    pub out2: SinkConfig,
+    /// The framing of the records written to the outputs.
+    pub format: OutputFormat,
+    /// As [`crate::XformOptions::strict_pairing`].
+    pub strict_pairing: bool,
+    /// As [`crate::XformOptions::on_error`].
+    pub on_error: OnError,
+    /// As [`crate::XformOptions::input_format`].
+    pub input_format: InputFormat,
+    /// The decoder run on the ORA compressed input files (see the
+    /// [`crate::decode`] module).
+    pub ora_decoder: ExternalDecoder,
+}
+
+/// The fragments routed to a modality.
+#[derive(Debug, Clone)]
+pub struct ModalityStats {
+    /// The name of the modality.
+    pub name: String,
+    /// The number of fragments routed to (and transformed by) the modality.
+    pub fragments: u64,
+    /// The captured lengths of the variable length pieces of the geometry
+    /// of the modality (as in [`crate::XformStats::padding_usage`]).
+    pub padding_usage: Vec<PieceLengths>,
+}
+
+/// The statistics of a routing of the fragments by modality.
+#[derive(Debug, Clone)]
+pub struct RoutingStats {
+    /// The number of input fragments.
+    pub total_fragments: u64,
+    /// The fragments routed to each modality, in the order of the
+    /// modalities.
+    pub modalities: Vec<ModalityStats>,
+    /// The number of fragments matching the geometry of no modality.
+    pub unrouted: u64,
+    /// The problems found in the pairing of the read 1 and read 2 records,
+    /// over all of the inputs.
+    pub pairing: PairingCounts,
+    /// The number of fragments dropped for holding a malformed record, or
+    /// `None` if the malformed records fail the routing (see
+    /// [`RoutingOptions::on_error`]).
+    pub malformed_fragments: Option<u64>,
+}
+
+impl RoutingStats {
+    /// Writes the number of fragments routed to each modality as a TSV with
+    /// a `modality`, a `fragments` and a `percent` column, with one row per
+    /// modality, followed by a row for the [`UNROUTED`] fragments.
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let percent = |n: u64| n as f64 * 100_f64 / self.total_fragments.max(1) as f64;
+        writeln!(w, "modality\tfragments\tpercent")?;
+        for m in &self.modalities {
+            writeln!(
+                w,
+                "{}\t{}\t{:.2}",
+                m.name,
+                m.fragments,
+                percent(m.fragments)
+            )?;
+        }
+        writeln!(
+            w,
+            "{}\t{}\t{:.2}",
+            UNROUTED,
+            self.unrouted,
+            percent(self.unrouted)
+        )
+    }
+}
+
+impl fmt::Display for RoutingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |n: u64| n as f64 * 100_f64 / self.total_fragments.max(1) as f64;
+        writeln!(f, "RoutingStats {{ ")?;
+        writeln!(
+            f,
+            "    total fragments: {},",
+            self.total_fragments.separate_with_commas()
+        )?;
+        for m in &self.modalities {
+            writeln!(
+                f,
+                "    routed to {}: {} ({:.2}%),",
+                m.name,
+                m.fragments.separate_with_commas(),
+                percent(m.fragments)
+            )?;
+        }
+        writeln!(
+            f,
+            "    {}: {} ({:.2}%),",
+            UNROUTED,
+            self.unrouted.separate_with_commas(),
+            percent(self.unrouted)
+        )?;
+        if let Some(m) = self.malformed_fragments {
+            writeln!(
+                f,
+                "    fragments dropped for a malformed record: {},",
+                m.separate_with_commas()
+            )?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// Reads the fragments of the input files `r1` and `r2` once, and writes
+/// each of them, transformed, to the outputs of the first of the
+/// `modalities` whose geometry it matches (see the module documentation).
+/// The geometries must not have index reads.
+pub fn route_read_pairs_to_files(
+    modalities: Vec<Modality>,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    opts: &RoutingOptions,
+) -> Result<RoutingStats> {
+    if modalities.is_empty() {
+        bail!("at least one modality must be given");
+    }
+    for (i, m) in modalities.iter().enumerate() {
+        if m.name.is_empty()
+            || m.name.starts_with('.')
+            || m.name == UNROUTED
+            || !m
+                .name
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"_-.".contains(&c))
+        {
+            bail!("invalid modality name {:?}", m.name);
+        }
+        if modalities[..i].iter().any(|o| o.name == m.name) {
+            bail!("modality {} is given more than once", m.name);
+        }
+        if !m.geo_re.index_reads().is_empty() {
+            bail!(
+                "the geometry of modality {} has index reads, which cannot be routed",
+                m.name
+            );
+        }
+        if m.r1_ofile.is_none() && !m.geo_re.discards_read1() {
+            bail!(
+                "No read 1 output was given for modality {}, but its geometry does not discard all of read 1",
+                m.name
+            );
+        }
+        if m.r2_ofile.is_none() && !m.geo_re.discards_read2() {
+            bail!(
+                "No read 2 output was given for modality {}, but its geometry does not discard all of read 2",
+                m.name
+            );
+        }
+    }
+
+    let mut stats = RoutingStats {
+        total_fragments: 0,
+        modalities: modalities
+            .iter()
+            .map(|m| ModalityStats {
+                name: m.name.clone(),
+                fragments: 0,
+                padding_usage: ranged_pieces(&m.geo_re),
+            })
+            .collect(),
+        unrouted: 0,
+        pairing: PairingCounts::default(),
+        malformed_fragments: (opts.on_error != OnError::Abort).then_some(0),
+    };
+    let mut routes = Vec::with_capacity(modalities.len());
+    for m in modalities {
+        let writer = PairedWriter::new(
+            m.r1_ofile.clone(),
+            m.r2_ofile.clone(),
+            &opts.out1,
+            &opts.out2,
+            None,
+        )
+        .with_context(|| format!("could not open the outputs of modality {}", m.name))?;
+        routes.push((m.geo_re, writer));
+    }
+
+    let pairing = PairingChecker {
+        strict: opts.strict_pairing,
+    };
+    let mut sp = SeqPair::new();
+    let (mut comment1, mut comment2) = (vec![], vec![]);
+    for (files1, files2) in input_groups(r1, r2, opts.input_format)? {
+        let mut batches =
+            read_input_group(files1, files2, None, None, opts.on_error, &opts.ora_decoder)?;
+        if opts.strict_pairing {
+            batches = batches.with_equal_counts();
+        }
+        let mut counts = PairingCounts::default();
+        let mut fragment = 0;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                pairing.check_names(fragment, frag.ids[0], frag.ids[1], &mut counts)?;
+                fragment += 1;
+                // the first modality whose geometry matches the fragment
+                let routed = routes.iter_mut().position(|(geo_re, _)| {
+                    geo_re.parse_into(frag.seqs[0], frag.seqs[1], &mut sp)
+                        && geo_re.parse_name_into(frag.ids[0], &mut sp)
+                });
+                let Some(i) = routed else {
+                    stats.unrouted += 1;
+                    continue;
+                };
+                let ((geo_re, writer), ms) = (&mut routes[i], &mut stats.modalities[i]);
+                ms.fragments += 1;
+                record_lens(&mut ms.padding_usage, geo_re.last_lens());
+                comment1.clear();
+                comment2.clear();
+                if geo_re.padding() == PaddingScheme::MaxLen {
+                    let [lens1, lens2] = geo_re.last_lens();
+                    push_lens_tag(&mut comment1, lens1);
+                    push_lens_tag(&mut comment2, lens2);
+                }
+                let headers = [(frag.ids[0], &comment1[..]), (frag.ids[1], &comment2[..])];
+                write_fragment(writer, opts.format, headers, &sp, geo_re.last_padding())?;
+            }
+            batches.recycle(batch);
+        }
+        counts.unpaired = batches.unpaired();
+        if let Some(m) = stats.malformed_fragments.as_mut() {
+            *m += batches.malformed();
+        }
+        if let (Some(p1), Some(p2)) = (files1.first(), files2.first()) {
+            pairing.report(p1, p2, &counts)?;
+        }
+        stats.total_fragments += fragment;
+        stats.pairing.add(&counts);
+    }
+    for (_, writer) in routes {
+        writer.finish()?;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::split_geometry;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    fn geometry(geom: &str) -> FragmentRegexDesc {
+        let (main, index) = split_geometry(geom).unwrap();
+        let mut geo_re = FragmentGeomDesc::try_from(main.as_str())
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        for (read, pieces) in &index {
+            geo_re.add_index_read(*read, pieces).unwrap();
+        }
+        geo_re
+    }
+
+    fn modality(name: &str, geom: &str, outputs: [Option<PathBuf>; 2]) -> Modality {
+        let [r1_ofile, r2_ofile] = outputs;
+        Modality {
+            name: String::from(name),
+            geo_re: geometry(geom),
+            r1_ofile,
+            r2_ofile,
+        }
+    }
+
+    #[test]
+    fn modalities_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = |name: &str| Some(dir.path().join(name));
+        std::fs::write(dir.path().join("r1.fa"), ">a\nACGTAC\n").unwrap();
+        std::fs::write(dir.path().join("r2.fa"), ">a\nAAGG\n").unwrap();
+        let route = |modalities| {
+            let inputs = [p("r1.fa").unwrap(), p("r2.fa").unwrap()];
+            route_read_pairs_to_files(
+                modalities,
+                &inputs[..1],
+                &inputs[1..],
+                &RoutingOptions::default(),
+            )
+            .map_err(|e| e.to_string())
+        };
+        let gex = |name: &str| modality(name, "1{b[4]u[2]}2{r:}", [p("o1.fa"), p("o2.fa")]);
+
+        assert!(route(vec![]).unwrap_err().contains("at least one"));
+        for name in ["", ".adt", "unrouted", "a/b"] {
+            assert!(route(vec![gex(name)]).unwrap_err().contains("invalid"));
+        }
+        assert!(route(vec![gex("GEX"), gex("GEX")])
+            .unwrap_err()
+            .contains("more than once"));
+        let indexed = modality("GEX", "1{b[4]u[2]}2{r:}3{b[8]}", [p("o1.fa"), p("o2.fa")]);
+        assert!(route(vec![indexed]).unwrap_err().contains("index reads"));
+        let no_r2 = modality("GEX", "1{b[4]u[2]}2{r:}", [p("o1.fa"), None]);
+        assert!(route(vec![no_r2]).unwrap_err().contains("No read 2 output"));
+        // nor is an output needed for a read that is discarded
+        let no_r1 = modality("GEX", "1{x:}2{r:}", [None, p("o2.fa")]);
+        let stats = route(vec![no_r1]).unwrap();
+        assert_eq!((stats.modalities[0].fragments, stats.unrouted), (1, 0));
+        assert_eq!(
+            std::fs::read_to_string(p("o2.fa").unwrap()).unwrap(),
+            ">a\nAAGG\n"
+        );
+    }
+
+    #[test]
+    fn fragments_are_routed_by_structure() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        // ADT fragments hold the anchor GGGG on read 2, GEX fragments do not
+        std::fs::write(p("r1.fa"), ">a\nACGTAC\n>b\nTTTTCC\n>c\nCCCCAA\n>d\nAC\n").unwrap();
+        std::fs::write(
+            p("r2.fa"),
+            ">a\nAAGGGGTT\n>b\nCATCATCAT\n>c\nCCGGGGAA\n>d\nTT\n",
+        )
+        .unwrap();
+        let modalities = vec![
+            Modality {
+                name: String::from("ADT"),
+                geo_re: geometry("1{b[4]u[2]}2{b[2]f[GGGG]x:}"),
+                r1_ofile: Some(p("adt_R1.fa")),
+                r2_ofile: Some(p("adt_R2.fa")),
+            },
+            Modality {
+                name: String::from("GEX"),
+                geo_re: geometry("1{b[4]u[2]x:}2{r[5-10]}"),
+                r1_ofile: Some(p("gex_R1.fa")),
+                r2_ofile: Some(p("gex_R2.fa")),
+            },
+        ];
+        let stats = route_read_pairs_to_files(
+            modalities.clone(),
+            &[p("r1.fa")],
+            &[p("r2.fa")],
+            &RoutingOptions::default(),
+        )
+        .unwrap();
+        let routed: Vec<u64> = stats.modalities.iter().map(|m| m.fragments).collect();
+        assert_eq!(routed, [2, 1]);
+        assert_eq!((stats.total_fragments, stats.unrouted), (4, 1));
+        assert_eq!(
+            std::fs::read_to_string(p("adt_R2.fa")).unwrap(),
+            ">a\nAA\n>c\nCC\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(p("gex_R1.fa")).unwrap(),
+            ">b\nTTTTCC\n"
+        );
+        assert_eq!(stats.modalities[1].padding_usage[0].lengths[&9], 1);
+        let mut tsv = vec![];
+        stats.write_tsv(&mut tsv).unwrap();
+        assert!(String::from_utf8(tsv)
+            .unwrap()
+            .ends_with("unrouted\t1\t25.00\n"));
+    }
+
+    #[test]
+    fn fragments_go_to_the_first_matching_modality() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = |name: &str| Some(dir.path().join(name));
+        std::fs::write(dir.path().join("r1.fa"), ">a\nACGTAC\n>b\nACG\n").unwrap();
+        std::fs::write(dir.path().join("r2.fa"), ">a\nAAGGGGTT\n>b\nCC\n").unwrap();
+        let adt = modality(
+            "ADT",
+            "1{b[4]u[2]}2{b[2]f[GGGG]x:}",
+            [p("adt1.fa"), p("adt2.fa")],
+        );
+        let mut gex = modality("GEX", "1{b[3-4]u[0-2]}2{r:}", [p("gex1.fa"), p("gex2.fa")]);
+        gex.geo_re.set_padding(PaddingScheme::MaxLen);
+        let route = |modalities| {
+            route_read_pairs_to_files(
+                modalities,
+                &[p("r1.fa").unwrap()],
+                &[p("r2.fa").unwrap()],
+                &RoutingOptions::default(),
+            )
+            .unwrap()
+        };
+        let read = |name: &str| std::fs::read_to_string(p(name).unwrap()).unwrap();
+
+        // a matches both geometries, and goes to the first one given
+        let stats = route(vec![adt.clone(), gex.clone()]);
+        assert_eq!(stats.modalities[0].fragments, 1);
+        assert_eq!(read("adt1.fa"), ">a\nACGTAC\n");
+        // the lengths captured with the maximum length padding are tagged
+        assert_eq!(read("gex1.fa"), ">b lens=3,0\nACGAAA\n");
+        let stats = route(vec![gex, adt]);
+        assert_eq!(
+            stats
+                .modalities
+                .iter()
+                .map(|m| m.fragments)
+                .collect::<Vec<_>>(),
+            [2, 0]
+        );
+        assert_eq!(read("adt1.fa"), "");
+        assert_eq!(
+            stats.to_string(),
+            "RoutingStats { \n    total fragments: 2,\n    routed to GEX: 2 (100.00%),\n    \
+             routed to ADT: 0 (0.00%),\n    unrouted: 0 (0.00%),\n}"
+        );
+    }
+}
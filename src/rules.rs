@@ -0,0 +1,321 @@
+//! Transformations of single captured pieces.
+//!
+//! Some chemistries need a captured piece to be rewritten before it is
+//! concatenated with the others, e.g. a barcode sequenced from the reverse
+//! strand, which must be reverse complemented to match its whitelist.  A
+//! [`PieceRule`] (given through [`crate::RegexOptions::piece_rules`])
+//! applies a sequence of [`PieceOp`]s to one of the captured (`b`, `u` or
+//! `r`) pieces of the geometry of read 1 or read 2.  Trimming a piece is
+//! the same as discarding its first or last bases, so the geometry is
+//! rewritten accordingly (`b[16]` trimmed by 2 on the left becomes
+//! `x[2]b[14]`), which the simplified geometry reflects.  The other
+//! operations keep the length of the piece, and are applied to its captured
+//! bases as it is parsed, before any padding is added.
+//!
+//! On the command line, a rule is written as `<read>.<piece>:<op>[,<op>...]`,
+//! where `<piece>` is the (1-based) position of the piece among all of the
+//! pieces of the read's geometry, and each `<op>` is one of `revcomp`,
+//! `reverse`, `complement`, `mask`, `trim-left=<n>` or `trim-right=<n>`
+//! (e.g. `1.3:trim-left=1,revcomp`).
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece};
+
+/// An operation applied to a captured piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceOp {
+    /// Reverse complement the piece.
+    RevComp,
+    /// Reverse the piece.
+    Reverse,
+    /// Complement the piece.
+    Complement,
+    /// Discard this many bases from the start of the piece.
+    TrimLeft(u32),
+    /// Discard this many bases from the end of the piece.
+    TrimRight(u32),
+    /// Replace every base of the piece with `N`.
+    Mask,
+}
+
+impl FromStr for PieceOp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let trim = |n: &str| {
+            n.parse::<u32>()
+                .with_context(|| format!("invalid number of bases to trim {:?}", n))
+        };
+        match s.split_once('=') {
+            Some(("trim-left", n)) => Ok(PieceOp::TrimLeft(trim(n)?)),
+            Some(("trim-right", n)) => Ok(PieceOp::TrimRight(trim(n)?)),
+            Some(_) => bail!("unknown piece operation {}", s),
+            None => match s {
+                "revcomp" => Ok(PieceOp::RevComp),
+                "reverse" => Ok(PieceOp::Reverse),
+                "complement" => Ok(PieceOp::Complement),
+                "mask" => Ok(PieceOp::Mask),
+                _ => bail!(
+                    "unknown piece operation {}; expected revcomp, reverse, complement, mask, \
+                     trim-left=<n> or trim-right=<n>",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// The operations applied, in order, to a captured piece of the geometry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceRule {
+    /// The read (`1` or `2`) of the piece.
+    pub read: usize,
+    /// The (1-based) position of the piece among the pieces of the geometry
+    /// of its read.
+    pub piece: usize,
+    pub ops: Vec<PieceOp>,
+}
+
+impl FromStr for PieceRule {
+    type Err = anyhow::Error;
+
+    /// Parses a rule written as `<read>.<piece>:<op>[,<op>...]`.
+    fn from_str(s: &str) -> Result<Self> {
+        let syntax = || {
+            anyhow!(
+                "invalid piece rule {:?}; expected <read>.<piece>:<op>[,<op>...]",
+                s
+            )
+        };
+        let (loc, ops) = s.split_once(':').ok_or_else(syntax)?;
+        let (read, piece) = loc.split_once('.').ok_or_else(syntax)?;
+        Ok(Self {
+            read: read.parse().map_err(|_| syntax())?,
+            piece: piece.parse().map_err(|_| syntax())?,
+            ops: ops
+                .split(',')
+                .map(PieceOp::from_str)
+                .collect::<Result<_>>()
+                .with_context(|| format!("invalid piece rule {:?}", s))?,
+        })
+    }
+}
+
+/// The operations of a [`PieceRule`] that keep the length of a piece,
+/// combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct PieceXform {
+    reverse: bool,
+    complement: bool,
+    mask: bool,
+}
+
+impl PieceXform {
+    /// Returns true if the piece is left as it is.
+    pub(crate) fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Appends the piece `p`, transformed, to `out`.
+    #[inline]
+    pub(crate) fn push(&self, p: &[u8], out: &mut Vec<u8>) {
+        let map = |c: &u8| {
+            if self.mask {
+                b'N'
+            } else if self.complement {
+                complement(*c)
+            } else {
+                *c
+            }
+        };
+        if self.reverse {
+            out.extend(p.iter().rev().map(map));
+        } else {
+            out.extend(p.iter().map(map));
+        }
+    }
+}
+
+/// The complement of the nucleotide `c`; other characters are left as they
+/// are.
+fn complement(c: u8) -> u8 {
+    match c {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        _ => c,
+    }
+}
+
+/// Applies the rules `rules` to the geometry `geo`.  Returns the geometry
+/// with the trimmed bases of the pieces discarded, along with the
+/// transformation of each captured piece of read 1 and read 2, in order
+/// (both empty if no piece is transformed).
+pub(crate) fn apply_rules(
+    geo: &FragmentGeomDesc,
+    rules: &[PieceRule],
+) -> Result<(FragmentGeomDesc, [Vec<PieceXform>; 2])> {
+    let mut xforms = [vec![], vec![]];
+    let mut reads = [vec![], vec![]];
+    for (i, pieces) in [&geo.read1_desc, &geo.read2_desc].into_iter().enumerate() {
+        for r in rules.iter().filter(|r| r.read == i + 1) {
+            match pieces.get(r.piece.wrapping_sub(1)) {
+                Some(GeomPiece::Barcode(_) | GeomPiece::Umi(_) | GeomPiece::ReadSeq(_)) => {}
+                Some(gp) => bail!(
+                    "piece {} of read {} ({:?}) is not captured, so no rule applies to it",
+                    r.piece,
+                    r.read,
+                    gp
+                ),
+                None => bail!(
+                    "read {} has {} pieces, so there is no piece {} to apply a rule to",
+                    r.read,
+                    pieces.len(),
+                    r.piece
+                ),
+            }
+        }
+        let has_xform = rules.iter().any(|r| {
+            r.read == i + 1
+                && r.ops
+                    .iter()
+                    .any(|op| !matches!(op, PieceOp::TrimLeft(_) | PieceOp::TrimRight(_)))
+        });
+        for (j, gp) in pieces.iter().enumerate() {
+            let ops = rules
+                .iter()
+                .filter(|r| r.read == i + 1 && r.piece == j + 1)
+                .flat_map(|r| &r.ops);
+            let mut xf = PieceXform::default();
+            let (mut left, mut right) = (0, 0);
+            for op in ops {
+                match op {
+                    PieceOp::RevComp => {
+                        xf.reverse = !xf.reverse;
+                        xf.complement = !xf.complement;
+                    }
+                    PieceOp::Reverse => xf.reverse = !xf.reverse,
+                    PieceOp::Complement => xf.complement = !xf.complement,
+                    PieceOp::Mask => xf.mask = true,
+                    PieceOp::TrimLeft(n) => left += n,
+                    PieceOp::TrimRight(n) => right += n,
+                }
+            }
+            let trimmed = trim(gp, left, right).with_context(|| {
+                format!("cannot trim piece {} of read {} ({:?})", j + 1, i + 1, gp)
+            })?;
+            reads[i].extend(trimmed);
+            if has_xform
+                && matches!(
+                    gp,
+                    GeomPiece::Barcode(_) | GeomPiece::Umi(_) | GeomPiece::ReadSeq(_)
+                )
+            {
+                xforms[i].push(xf);
+            }
+        }
+    }
+    let [read1_desc, read2_desc] = reads;
+    Ok((
+        FragmentGeomDesc {
+            read1_desc,
+            read2_desc,
+        },
+        xforms,
+    ))
+}
+
+/// Returns the pieces replacing the captured piece `gp` trimmed of `left`
+/// bases on the left and of `right` bases on the right: the piece itself,
+/// shortened, surrounded by the discarded bases.  At least one base of the
+/// piece must be left.
+fn trim(gp: &GeomPiece, left: u32, right: u32) -> Result<Vec<GeomPiece>> {
+    if left == 0 && right == 0 {
+        return Ok(vec![gp.clone()]);
+    }
+    let trimmed = |gl: &GeomLen| -> Result<GeomLen> {
+        let cut = left + right;
+        match gl {
+            GeomLen::FixedLen(x) if *x > cut => Ok(GeomLen::FixedLen(x - cut)),
+            GeomLen::LenRange(l, h) if *l > cut => Ok(GeomLen::LenRange(l - cut, h - cut)),
+            GeomLen::Unbounded if right == 0 => Ok(GeomLen::Unbounded),
+            GeomLen::Unbounded => bail!("the end of an unbounded piece cannot be trimmed"),
+            _ => bail!("trimming {} bases would leave no base of the piece", cut),
+        }
+    };
+    let piece = match gp {
+        GeomPiece::Barcode(gl) => GeomPiece::Barcode(trimmed(gl)?),
+        GeomPiece::Umi(gl) => GeomPiece::Umi(trimmed(gl)?),
+        GeomPiece::ReadSeq(gl) => GeomPiece::ReadSeq(trimmed(gl)?),
+        _ => bail!("only captured pieces can be trimmed"),
+    };
+    let mut pieces = vec![];
+    if left > 0 {
+        pieces.push(GeomPiece::Discard(GeomLen::FixedLen(left)));
+    }
+    pieces.push(piece);
+    if right > 0 {
+        pieces.push(GeomPiece::Discard(GeomLen::FixedLen(right)));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FragmentGeomDescExt, RegexOptions, SeqPair};
+
+    #[test]
+    fn rules_transform_the_captured_pieces() {
+        let rule = |s: &str| s.parse::<PieceRule>().unwrap();
+        assert_eq!(
+            rule("1.3:trim-left=1,revcomp"),
+            PieceRule {
+                read: 1,
+                piece: 3,
+                ops: vec![PieceOp::TrimLeft(1), PieceOp::RevComp]
+            }
+        );
+        assert!("1:revcomp".parse::<PieceRule>().is_err());
+        assert!("1.1:rc".parse::<PieceRule>().is_err());
+
+        let geo = FragmentGeomDesc::try_from("1{b[4]f[GG]u[2-3]}2{r:}").unwrap();
+        let with_rules = |rules: &[&str]| {
+            geo.as_regex_with_options(&RegexOptions {
+                piece_rules: rules.iter().map(|r| rule(r)).collect(),
+                ..RegexOptions::default()
+            })
+        };
+        let mut geo_re = with_rules(&["1.1:revcomp", "1.3:trim-right=1", "2.1:mask"]).unwrap();
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[4]u[3]}2{r:}"
+        );
+        let mut sp = SeqPair::new();
+        assert!(geo_re.parse_into(b"AACGGGTTA", b"ACGT", &mut sp));
+        // the UMI TTA is captured as TT, and padded
+        assert_eq!(sp.s1, b"CGTTTTA");
+        assert_eq!(sp.s2, b"NNNN");
+
+        let mut geo_re = with_rules(&["1.1:reverse,trim-left=2", "1.1:complement"]).unwrap();
+        assert_eq!(
+            geo_re.get_simplified_description_string(),
+            "1{b[2]u[4]}2{r:}"
+        );
+        assert!(geo_re.parse_into(b"AACGGGTT", b"ACGT", &mut sp));
+        assert_eq!(sp.s1, b"CGTTAC");
+
+        // the anchor is not captured
+        assert!(with_rules(&["1.2:revcomp"]).is_err());
+        assert!(with_rules(&["1.3:trim-left=2"]).is_err());
+        assert!(with_rules(&["2.1:trim-right=2"]).is_err());
+        assert!(with_rules(&["2.2:mask"]).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+//! Vectorized scanning of read bytes.
+//!
+//! These routines are used to cheaply reject reads that cannot possibly
+//! match a geometry (because they contain characters outside of `ACGTN`,
+//! or lack one of its fixed anchor sequences) before the more expensive
+//! regex capture extraction is attempted.  The widest instruction set
+//! available at runtime is used (AVX2 or SSE2 on `x86_64`, NEON on
+//! `aarch64`), with a scalar fallback on other platforms.
+
+use seq_geom_parser::{GeomLen, GeomPiece, NucStr};
+
+use crate::failure::FailureCause;
+use crate::iupac;
+
+/// The characters that may appear in a read matching a geometry.
+const ALPHABET: &[u8] = b"ACGTN";
+
+/// Returns true if every byte of `seq` is one of `A`, `C`, `G`, `T` or `N`.
+#[inline]
+pub fn is_acgtn(seq: &[u8]) -> bool {
+    imp::is_acgtn(seq)
+}
+
+/// Returns the offset of the first occurrence of `needle` in `hay`, or
+/// `None` if it does not occur.
+#[inline]
+pub fn find_anchor(hay: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    if needle.len() > hay.len() {
+        return None;
+    }
+    imp::find_anchor(hay, needle)
+}
+
+fn is_acgtn_scalar(seq: &[u8]) -> bool {
+    seq.iter().all(|c| ALPHABET.contains(c))
+}
+
+/// Finds `needle` in `hay` considering only the candidate positions in
+/// `start..`; expects `needle` to be non-empty.
+fn find_anchor_scalar(hay: &[u8], needle: &[u8], start: usize) -> Option<usize> {
+    hay.get(start..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|p| p + start)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod imp {
+    use super::{find_anchor_scalar, is_acgtn_scalar};
+    use std::arch::x86_64::*;
+
+    pub(super) fn is_acgtn(seq: &[u8]) -> bool {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: we just checked that AVX2 is available
+            unsafe { is_acgtn_avx2(seq) }
+        } else {
+            // SAFETY: SSE2 is part of the x86_64 baseline
+            unsafe { is_acgtn_sse2(seq) }
+        }
+    }
+
+    pub(super) fn find_anchor(hay: &[u8], needle: &[u8]) -> Option<usize> {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: we just checked that AVX2 is available
+            unsafe { find_anchor_avx2(hay, needle) }
+        } else {
+            // SAFETY: SSE2 is part of the x86_64 baseline
+            unsafe { find_anchor_sse2(hay, needle) }
+        }
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn is_acgtn_sse2(seq: &[u8]) -> bool {
+        const W: usize = 16;
+        let [a, c, g, t, n] = [b'A', b'C', b'G', b'T', b'N'].map(|x| _mm_set1_epi8(x as i8));
+        let mut chunks = seq.chunks_exact(W);
+        for chunk in &mut chunks {
+            let v = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            let ok = _mm_or_si128(
+                _mm_or_si128(_mm_cmpeq_epi8(v, a), _mm_cmpeq_epi8(v, c)),
+                _mm_or_si128(
+                    _mm_or_si128(_mm_cmpeq_epi8(v, g), _mm_cmpeq_epi8(v, t)),
+                    _mm_cmpeq_epi8(v, n),
+                ),
+            );
+            if _mm_movemask_epi8(ok) != 0xFFFF {
+                return false;
+            }
+        }
+        is_acgtn_scalar(chunks.remainder())
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn is_acgtn_avx2(seq: &[u8]) -> bool {
+        const W: usize = 32;
+        let [a, c, g, t, n] = [b'A', b'C', b'G', b'T', b'N'].map(|x| _mm256_set1_epi8(x as i8));
+        let mut chunks = seq.chunks_exact(W);
+        for chunk in &mut chunks {
+            let v = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+            let ok = _mm256_or_si256(
+                _mm256_or_si256(_mm256_cmpeq_epi8(v, a), _mm256_cmpeq_epi8(v, c)),
+                _mm256_or_si256(
+                    _mm256_or_si256(_mm256_cmpeq_epi8(v, g), _mm256_cmpeq_epi8(v, t)),
+                    _mm256_cmpeq_epi8(v, n),
+                ),
+            );
+            if _mm256_movemask_epi8(ok) != -1 {
+                return false;
+            }
+        }
+        is_acgtn_sse2(chunks.remainder())
+    }
+
+    // The anchor search compares the first and the last byte of the needle
+    // against a block of candidate positions at once, and only verifies the
+    // full needle at the candidates where both of these bytes agree.
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn find_anchor_sse2(hay: &[u8], needle: &[u8]) -> Option<usize> {
+        const W: usize = 16;
+        let last = needle.len() - 1;
+        let first_b = _mm_set1_epi8(needle[0] as i8);
+        let last_b = _mm_set1_epi8(needle[last] as i8);
+        let mut i = 0;
+        while i + last + W <= hay.len() {
+            let f = _mm_loadu_si128(hay.as_ptr().add(i) as *const __m128i);
+            let l = _mm_loadu_si128(hay.as_ptr().add(i + last) as *const __m128i);
+            let eq = _mm_and_si128(_mm_cmpeq_epi8(f, first_b), _mm_cmpeq_epi8(l, last_b));
+            let mut mask = _mm_movemask_epi8(eq) as u32;
+            while mask != 0 {
+                let p = i + mask.trailing_zeros() as usize;
+                if &hay[p..p + needle.len()] == needle {
+                    return Some(p);
+                }
+                mask &= mask - 1;
+            }
+            i += W;
+        }
+        find_anchor_scalar(hay, needle, i)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn find_anchor_avx2(hay: &[u8], needle: &[u8]) -> Option<usize> {
+        const W: usize = 32;
+        let last = needle.len() - 1;
+        let first_b = _mm256_set1_epi8(needle[0] as i8);
+        let last_b = _mm256_set1_epi8(needle[last] as i8);
+        let mut i = 0;
+        while i + last + W <= hay.len() {
+            let f = _mm256_loadu_si256(hay.as_ptr().add(i) as *const __m256i);
+            let l = _mm256_loadu_si256(hay.as_ptr().add(i + last) as *const __m256i);
+            let eq = _mm256_and_si256(_mm256_cmpeq_epi8(f, first_b), _mm256_cmpeq_epi8(l, last_b));
+            let mut mask = _mm256_movemask_epi8(eq) as u32;
+            while mask != 0 {
+                let p = i + mask.trailing_zeros() as usize;
+                if &hay[p..p + needle.len()] == needle {
+                    return Some(p);
+                }
+                mask &= mask - 1;
+            }
+            i += W;
+        }
+        find_anchor_scalar(hay, needle, i)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod imp {
+    use super::{find_anchor_scalar, is_acgtn_scalar};
+    use std::arch::aarch64::*;
+
+    pub(super) fn is_acgtn(seq: &[u8]) -> bool {
+        // SAFETY: NEON is part of the aarch64 baseline
+        unsafe { is_acgtn_neon(seq) }
+    }
+
+    pub(super) fn find_anchor(hay: &[u8], needle: &[u8]) -> Option<usize> {
+        // SAFETY: NEON is part of the aarch64 baseline
+        unsafe { find_anchor_neon(hay, needle) }
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn is_acgtn_neon(seq: &[u8]) -> bool {
+        const W: usize = 16;
+        let [a, c, g, t, n] = [b'A', b'C', b'G', b'T', b'N'].map(|x| vdupq_n_u8(x));
+        let mut chunks = seq.chunks_exact(W);
+        for chunk in &mut chunks {
+            let v = vld1q_u8(chunk.as_ptr());
+            let ok = vorrq_u8(
+                vorrq_u8(vceqq_u8(v, a), vceqq_u8(v, c)),
+                vorrq_u8(vorrq_u8(vceqq_u8(v, g), vceqq_u8(v, t)), vceqq_u8(v, n)),
+            );
+            if vminvq_u8(ok) != 0xFF {
+                return false;
+            }
+        }
+        is_acgtn_scalar(chunks.remainder())
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn find_anchor_neon(hay: &[u8], needle: &[u8]) -> Option<usize> {
+        const W: usize = 16;
+        let last = needle.len() - 1;
+        let first_b = vdupq_n_u8(needle[0]);
+        let last_b = vdupq_n_u8(needle[last]);
+        let mut i = 0;
+        while i + last + W <= hay.len() {
+            let f = vld1q_u8(hay.as_ptr().add(i));
+            let l = vld1q_u8(hay.as_ptr().add(i + last));
+            let eq = vandq_u8(vceqq_u8(f, first_b), vceqq_u8(l, last_b));
+            if vmaxvq_u8(eq) != 0 {
+                let mut lanes = [0u8; W];
+                vst1q_u8(lanes.as_mut_ptr(), eq);
+                for (j, _) in lanes.iter().enumerate().filter(|(_, &x)| x != 0) {
+                    let p = i + j;
+                    if &hay[p..p + needle.len()] == needle {
+                        return Some(p);
+                    }
+                }
+            }
+            i += W;
+        }
+        find_anchor_scalar(hay, needle, i)
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod imp {
+    pub(super) fn is_acgtn(seq: &[u8]) -> bool {
+        super::is_acgtn_scalar(seq)
+    }
+
+    pub(super) fn find_anchor(hay: &[u8], needle: &[u8]) -> Option<usize> {
+        super::find_anchor_scalar(hay, needle, 0)
+    }
+}
+
+/// A fixed sequence of a geometry, along with the range of offsets
+/// at which it may occur in a read.
+#[derive(Debug, Clone)]
+struct Anchor {
+    /// The index of the anchor among the pieces of the read.
+    piece: usize,
+    seq: Vec<u8>,
+    min_offset: usize,
+    /// `None` if the anchor is preceded by an unbounded piece.
+    max_offset: Option<usize>,
+}
+
+impl Anchor {
+    /// Whether the anchor is only preceded by fixed length pieces, and so
+    /// can only occur at `min_offset`.
+    fn at_known_offset(&self) -> bool {
+        self.max_offset == Some(self.min_offset)
+    }
+
+    /// Whether the anchor occurs within its window in the read `r`, which
+    /// must be at least as long as the minimum length of the geometry.
+    #[inline]
+    fn found_in(&self, r: &[u8]) -> bool {
+        if self.at_known_offset() {
+            r[self.min_offset..self.min_offset + self.seq.len()] == self.seq[..]
+        } else {
+            let end = self
+                .max_offset
+                .map_or(r.len(), |m| r.len().min(m + self.seq.len()));
+            find_anchor(&r[self.min_offset..end], &self.seq).is_some()
+        }
+    }
+}
+
+/// A cheap, conservative test of whether a read may match the geometry of
+/// one read.  If [`ReadPrefilter::may_match`] returns false, the read is
+/// guaranteed not to match the regex generated for the same geometry.
+#[derive(Debug, Clone)]
+pub(crate) struct ReadPrefilter {
+    /// The minimum length of a matching read.
+    min_len: usize,
+    /// The maximum length of a matching read, if the regex is anchored at the
+    /// end of a bounded piece.
+    max_len: Option<usize>,
+    /// Whether a matching read must consist only of `ACGTN`.
+    check_alphabet: bool,
+    /// The anchors at a known offset come first, as they are checked with a
+    /// single comparison rather than a search.
+    anchors: Vec<Anchor>,
+}
+
+impl ReadPrefilter {
+    pub(crate) fn new(pieces: &[GeomPiece]) -> Self {
+        let mut min_offset = 0_usize;
+        let mut max_offset = Some(0_usize);
+        let mut check_alphabet = true;
+        let mut anchors = Vec::new();
+        for (piece, gp) in pieces.iter().enumerate() {
+            let (lo, hi) = match gp {
+                GeomPiece::Discard(gl)
+                | GeomPiece::Barcode(gl)
+                | GeomPiece::Umi(gl)
+                | GeomPiece::ReadSeq(gl) => match gl {
+                    GeomLen::FixedLen(x) => (*x as usize, Some(*x as usize)),
+                    GeomLen::LenRange(l, h) => (*l as usize, Some(*h as usize)),
+                    GeomLen::Unbounded => (0, None),
+                },
+                GeomPiece::Fixed(NucStr::Seq(s)) => {
+                    let seq = s.as_bytes().to_vec();
+                    // every other piece only matches `ACGTN`, and so do the
+                    // ambiguity codes, so unless an anchor contains some other
+                    // character, so must the whole read.
+                    check_alphabet &= seq
+                        .iter()
+                        .all(|&c| ALPHABET.contains(&c) || iupac::ambiguous_bases(c).is_some());
+                    let len = seq.len();
+                    // the anchors with ambiguity codes are left to the regex
+                    if iupac::is_literal(&seq) {
+                        anchors.push(Anchor {
+                            piece,
+                            seq,
+                            min_offset,
+                            max_offset,
+                        });
+                    }
+                    (len, Some(len))
+                }
+            };
+            min_offset += lo;
+            max_offset = max_offset.zip(hi).map(|(m, h)| m + h);
+        }
+        // the regex only ends right after the last piece if that piece is of
+        // variable length, and otherwise ends with an unbounded discard.
+        let max_len = match pieces.last() {
+            Some(gp) if !gp.is_fixed_len() => max_offset,
+            _ => None,
+        };
+        anchors.sort_by_key(|a| !a.at_known_offset());
+        Self {
+            min_len: min_offset,
+            max_len,
+            check_alphabet,
+            anchors,
+        }
+    }
+
+    /// Builds a prefilter for the pieces `pieces` searched for anywhere
+    /// within a read (see [`crate::FragmentGeomDescExt::as_unanchored_regex`]),
+    /// which only checks that the read is long enough to contain them.
+    pub(crate) fn unanchored(pieces: &[GeomPiece]) -> Self {
+        Self {
+            max_len: None,
+            check_alphabet: false,
+            anchors: vec![],
+            ..Self::new(pieces)
+        }
+    }
+
+    /// Builds a prefilter for the pieces `pieces`, whose fixed pieces may
+    /// match with mismatches, which only checks the length and the alphabet
+    /// of the read.
+    pub(crate) fn without_anchors(pieces: &[GeomPiece]) -> Self {
+        Self {
+            anchors: vec![],
+            ..Self::new(pieces)
+        }
+    }
+
+    /// Returns this prefilter without its alphabet check, for regexes whose
+    /// pieces match characters other than `ACGTN`.
+    pub(crate) fn without_alphabet_check(self) -> Self {
+        Self {
+            check_alphabet: false,
+            ..self
+        }
+    }
+
+    /// Returns false if the read `r` certainly does not match the geometry
+    /// from which this prefilter was built.
+    #[inline]
+    pub(crate) fn may_match(&self, r: &[u8]) -> bool {
+        if r.len() < self.min_len || self.max_len.is_some_and(|m| r.len() > m) {
+            return false;
+        }
+        // as the read is at least `min_len` long, the window always has room
+        // for the anchor at its minimum offset.
+        let anchors_found = self.anchors.iter().all(|a| a.found_in(r));
+        // the alphabet check scans the whole read, so it comes last
+        anchors_found && (!self.check_alphabet || is_acgtn(r))
+    }
+
+    /// Returns the reason why the read `r` certainly does not match the
+    /// geometry from which this prefilter was built (checked in the same
+    /// order as [`ReadPrefilter::may_match`], and reporting the first
+    /// missing anchor of the read), or `None` if it may match.
+    pub(crate) fn diagnose(&self, r: &[u8]) -> Option<FailureCause> {
+        if r.len() < self.min_len {
+            return Some(FailureCause::TooShort);
+        }
+        if self.max_len.is_some_and(|m| r.len() > m) {
+            return Some(FailureCause::TooLong);
+        }
+        let missing = self
+            .anchors
+            .iter()
+            .filter(|a| !a.found_in(r))
+            .map(|a| a.piece)
+            .min();
+        if let Some(piece) = missing {
+            return Some(FailureCause::AnchorNotFound { piece });
+        }
+        if self.check_alphabet && !is_acgtn(r) {
+            return Some(FailureCause::InvalidBase);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn vectorized_scans_match_scalar() {
+        let mut rng = StdRng::seed_from_u64(11);
+        for len in 0..200 {
+            let mut s: Vec<u8> = (0..len).map(|_| b"ACGTN"[rng.gen_range(0..5)]).collect();
+            assert!(is_acgtn(&s));
+            if len > 0 {
+                let i = rng.gen_range(0..len);
+                s[i] = b"acgtRY.\n"[rng.gen_range(0..8)];
+                assert!(!is_acgtn(&s));
+            }
+
+            let hay: Vec<u8> = (0..len).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect();
+            for nlen in 1..=6 {
+                let needle: Vec<u8> = (0..nlen).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect();
+                let expected = if nlen > len {
+                    None
+                } else {
+                    find_anchor_scalar(&hay, &needle, 0)
+                };
+                assert_eq!(find_anchor(&hay, &needle), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn prefilter_checks_length_and_anchor_windows() {
+        let geo = seq_geom_parser::FragmentGeomDesc::try_from("1{b[4]f[ACG]u[2-3]}2{r:}").unwrap();
+        let pf = ReadPrefilter::new(&geo.read1_desc);
+        assert_eq!(pf.max_len, Some(10));
+        assert!(pf.may_match(b"TTTTACGCC"));
+        assert!(pf.may_match(b"TTTTACGCCC"));
+        // too short, too long, anchor out of place, and not `ACGTN`
+        assert!(!pf.may_match(b"TTTTACGC"));
+        assert!(!pf.may_match(b"TTTTACGCCCC"));
+        assert!(!pf.may_match(b"TTTTTACGCC"));
+        assert!(!pf.may_match(b"TTTTACGCCX"));
+
+        // the anchor may follow the variable length barcode at either offset
+        let geo = seq_geom_parser::FragmentGeomDesc::try_from("1{b[3-4]f[ACG]u[2]}2{r:}").unwrap();
+        let pf = ReadPrefilter::new(&geo.read1_desc);
+        assert_eq!(pf.max_len, None);
+        assert!(pf.may_match(b"TTTACGCCGGGG"));
+        assert!(pf.may_match(b"TTTTACGCC"));
+        assert!(!pf.may_match(b"TTTTTACGCC"));
+    }
+}
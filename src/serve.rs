@@ -0,0 +1,549 @@
+//! A streaming service transforming reads sent over HTTP.
+//!
+//! With the `serve` feature, an [`XformServer`] exposes the transformation
+//! of a geometry over HTTP/1.1, so that a central service can normalize the
+//! reads of many clients that need nothing more than an HTTP client (e.g.
+//! `curl`).  The body of a `POST /xform` request holds interleaved `FASTA`
+//! or `FASTQ` records (the read 1 record of each fragment followed by its
+//! read 2 record), sent with a `Content-Length` or in chunks, and the
+//! response streams back the transformed fragments as interleaved `FASTA`
+//! records (as written by [`crate::stream::XformReader`]) while the body is
+//! being read, so that clients should read the response as they send their
+//! reads.  The number of fragments of the request, and of those that failed
+//! to parse, are sent in the `X-Total-Fragments` and `X-Failed-Parsing`
+//! trailers of the response (along with `X-Error` if the body could not be
+//! read), and `GET /stats` returns the statistics of all of the requests
+//! served so far as JSON (see [`crate::XformStats::write_json`]).  Each
+//! connection serves a single request.  The connections are served by a
+//! fixed pool of threads (see [`XformServer::with_max_connections`]), those
+//! accepted while all of them are busy waiting in the backlog of the
+//! listener, and a connection on which nothing can be read or written for
+//! the timeout of the server (see [`XformServer::with_timeout`]) is dropped,
+//! so that idle or stalled clients cannot hold on to the threads.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use needletail::{parse_fastx_reader, Sequence};
+use tracing::warn;
+
+use crate::lengths::{ranged_pieces, record_lens, PieceLengths};
+use crate::{
+    push_lens_tag, write_fasta_record, FragmentRegexDesc, PaddingScheme, SeqPair, XformStats,
+};
+
+/// The maximum length of the request line, of the headers of a request and
+/// of the size lines of a chunked body.
+const MAX_LINE_LEN: u64 = 8192;
+
+/// Serves the transformation of a geometry over HTTP (see the [`serve`](self)
+/// module).
+pub struct XformServer {
+    listener: TcpListener,
+    geo_re: FragmentRegexDesc,
+    geometry: String,
+    stats: Arc<Mutex<XformStats>>,
+    max_connections: usize,
+    timeout: Option<Duration>,
+}
+
+impl XformServer {
+    /// The default number of connections served at once.
+    pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+    /// The default time after which an idle connection is dropped.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Create a new `XformServer` transforming the fragments with `geo_re`,
+    /// listening on `addr` (e.g. `127.0.0.1:8080`, or port 0 to let the
+    /// system choose a free port).
+    pub fn bind<A: ToSocketAddrs>(geo_re: FragmentRegexDesc, addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("could not bind the server address")?;
+        let mut stats = XformStats::new();
+        stats.padding = geo_re.padding();
+        stats.padding_usage = ranged_pieces(&geo_re);
+        Ok(Self {
+            geometry: geo_re.get_simplified_description_string(),
+            listener,
+            geo_re,
+            stats: Arc::new(Mutex::new(stats)),
+            max_connections: Self::DEFAULT_MAX_CONNECTIONS,
+            timeout: Some(Self::DEFAULT_TIMEOUT),
+        })
+    }
+
+    /// Sets the number of connections served at once (at least 1), each by
+    /// its own thread.
+    pub fn with_max_connections(mut self, n: usize) -> Self {
+        self.max_connections = n.max(1);
+        self
+    }
+
+    /// Sets the time for which a read from, or a write to, a connection may
+    /// block before the connection is dropped, or `None` to wait forever.
+    /// As the transformed fragments are streamed back while the request is
+    /// being read, this bounds the time for which a client may stall, not
+    /// the time taken by a request.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout.filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Sets the description of the geometry reported by `GET /stats` (by
+    /// default, the simplified geometry).
+    pub fn with_description(mut self, geometry: &str) -> Self {
+        self.geometry = geometry.to_owned();
+        self
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves the incoming connections until accepting a connection fails.
+    pub fn run(self) -> Result<()> {
+        let started = Instant::now();
+        // a connection is only accepted once a worker is free to take it
+        let (tx, rx) = mpsc::sync_channel::<TcpStream>(0);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..self.max_connections {
+            let rx = Arc::clone(&rx);
+            let mut geo_re = self.geo_re.clone();
+            let stats = Arc::clone(&self.stats);
+            let geometry = self.geometry.clone();
+            thread::spawn(move || loop {
+                let next = rx.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                let Ok(stream) = next else {
+                    return;
+                };
+                let peer = stream.peer_addr().ok();
+                // a panic only fails its own request, and the worker goes on
+                let served = panic::catch_unwind(AssertUnwindSafe(|| {
+                    serve_connection(stream, &mut geo_re, &stats, &geometry, started)
+                }));
+                match served {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("could not serve the request of {:?}: {:#}", peer, e),
+                    Err(_) => warn!("serving the request of {:?} panicked", peer),
+                }
+            });
+        }
+        for stream in self.listener.incoming() {
+            let stream = stream.context("could not accept a connection")?;
+            stream.set_read_timeout(self.timeout)?;
+            stream.set_write_timeout(self.timeout)?;
+            if tx.send(stream).is_err() {
+                bail!("the workers serving the connections have stopped");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parts of a request that the server uses.
+struct Request {
+    method: String,
+    path: String,
+    content_length: Option<u64>,
+    chunked: bool,
+    expect_continue: bool,
+}
+
+/// Reads a line (of at most [`MAX_LINE_LEN`] bytes) from `r`, without its
+/// line ending.
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    r.take(MAX_LINE_LEN).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated or overlong line",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Reads the request line and the headers of a request.
+fn read_request<R: BufRead>(r: &mut R) -> Result<Request> {
+    let line = read_line(r).context("could not read the request line")?;
+    let mut parts = line.split_ascii_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("malformed request line {:?}", line);
+    };
+    let mut req = Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        content_length: None,
+        chunked: false,
+        expect_continue: false,
+    };
+    loop {
+        let header = read_line(r).context("could not read the request headers")?;
+        if header.is_empty() {
+            return Ok(req);
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            bail!("malformed header {:?}", header);
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            req.content_length = Some(
+                value
+                    .parse()
+                    .with_context(|| format!("invalid Content-Length {:?}", value))?,
+            );
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            req.chunked = value.to_ascii_lowercase().ends_with("chunked");
+        } else if name.eq_ignore_ascii_case("expect") {
+            req.expect_continue = value.eq_ignore_ascii_case("100-continue");
+        }
+    }
+}
+
+/// Writes a complete response with the status `status` and the body `body`.
+fn respond<W: Write>(w: &mut W, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    w.write_all(body)?;
+    w.flush()
+}
+
+/// Serves the request of the connection `stream`, transforming fragments
+/// with `geo_re` and adding their counts to `stats`, which are reported
+/// for the geometry `geometry` since `started`.
+fn serve_connection(
+    stream: TcpStream,
+    geo_re: &mut FragmentRegexDesc,
+    stats: &Mutex<XformStats>,
+    geometry: &str,
+    started: Instant,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut out = stream;
+    let req = match read_request(&mut reader) {
+        Ok(req) => req,
+        Err(e) => {
+            respond(
+                &mut out,
+                "400 Bad Request",
+                "text/plain",
+                format!("{:#}\n", e).as_bytes(),
+            )?;
+            return Err(e);
+        }
+    };
+    match (req.method.as_str(), req.path.as_str()) {
+        ("POST", "/xform") => {
+            let body: Box<dyn Read + Send + '_> = match (req.chunked, req.content_length) {
+                (true, _) => Box::new(ChunkedReader::new(&mut reader)),
+                (false, Some(n)) => Box::new((&mut reader).take(n)),
+                (false, None) => {
+                    let msg = b"the reads must be sent with a Content-Length or in chunks\n";
+                    respond(&mut out, "411 Length Required", "text/plain", msg)?;
+                    return Ok(());
+                }
+            };
+            if req.expect_continue {
+                out.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            }
+            xform(body, out, geo_re, stats)
+        }
+        ("GET", "/stats") => {
+            let mut body = vec![];
+            stats
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write_json(&mut body, geometry, started.elapsed())?;
+            Ok(respond(&mut out, "200 OK", "application/json", &body)?)
+        }
+        (_, "/xform" | "/stats") => Ok(respond(
+            &mut out,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"use POST /xform or GET /stats\n",
+        )?),
+        _ => Ok(respond(
+            &mut out,
+            "404 Not Found",
+            "text/plain",
+            b"not found\n",
+        )?),
+    }
+}
+
+/// Transforms the interleaved fragments of `body`, streaming them back as a
+/// chunked response to `out`, with the counts of the request as trailers.
+fn xform<R: Read + Send>(
+    body: R,
+    out: TcpStream,
+    geo_re: &mut FragmentRegexDesc,
+    stats: &Mutex<XformStats>,
+) -> Result<()> {
+    let mut out = out;
+    out.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/x-fasta\r\n\
+          Transfer-Encoding: chunked\r\n\
+          Trailer: X-Total-Fragments, X-Failed-Parsing, X-Error\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    let mut w = BufWriter::new(ChunkedWriter { inner: out });
+    let (mut total, mut failed) = (0, 0);
+    let mut usage = ranged_pieces(geo_re);
+    let res = xform_interleaved(body, geo_re, &mut w, &mut total, &mut failed, &mut usage);
+    let chunked = w.into_inner().map_err(|e| e.into_error())?;
+    {
+        let mut stats = stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.total_fragments += total;
+        stats.failed_parsing += failed;
+        for (pl, req) in stats.padding_usage.iter_mut().zip(&usage) {
+            pl.add(req);
+        }
+    }
+    let mut trailers = vec![
+        ("X-Total-Fragments", total.to_string()),
+        ("X-Failed-Parsing", failed.to_string()),
+    ];
+    if let Err(e) = &res {
+        trailers.push(("X-Error", format!("{:#}", e).replace(['\r', '\n'], " ")));
+    }
+    chunked.finish(&trailers)?;
+    res
+}
+
+/// Transforms the interleaved fragments of `body` with `geo_re`, writing
+/// them to `w` as interleaved `FASTA` records, counting them in `total`
+/// and `failed`, and tallying the captured lengths of their variable length
+/// pieces in `usage`.
+fn xform_interleaved<R: Read + Send, W: Write>(
+    body: R,
+    geo_re: &mut FragmentRegexDesc,
+    w: &mut W,
+    total: &mut u64,
+    failed: &mut u64,
+    usage: &mut [PieceLengths],
+) -> Result<()> {
+    let mut body = BufReader::new(body);
+    // an empty body holds no fragment (rather than being a malformed file)
+    if body.fill_buf()?.is_empty() {
+        return Ok(());
+    }
+    let mut reader = parse_fastx_reader(body).context("could not parse the reads")?;
+    let (write1, write2) = (!geo_re.discards_read1(), !geo_re.discards_read2());
+    let mut sp = SeqPair::new();
+    let (mut id1, mut seq1) = (Vec::new(), Vec::new());
+    let (mut comment1, mut comment2) = (Vec::new(), Vec::new());
+    while let Some(rec1) = reader.next() {
+        let rec1 = rec1?;
+        // the record borrows the reader, which reads the read 2 record next
+        id1.clear();
+        id1.extend_from_slice(rec1.id());
+        seq1.clear();
+        seq1.extend_from_slice(rec1.sequence());
+        let Some(rec2) = reader.next() else {
+            bail!(
+                "fragment {} has no read 2 record",
+                String::from_utf8_lossy(&id1)
+            );
+        };
+        let rec2 = rec2?;
+        *total += 1;
+        if !geo_re.parse_into(&seq1, rec2.sequence(), &mut sp)
+            || !geo_re.parse_name_into(&id1, &mut sp)
+        {
+            *failed += 1;
+            continue;
+        }
+        record_lens(usage, geo_re.last_lens());
+        comment1.clear();
+        comment2.clear();
+        if geo_re.padding() == PaddingScheme::MaxLen {
+            let [lens1, lens2] = geo_re.last_lens();
+            push_lens_tag(&mut comment1, lens1);
+            push_lens_tag(&mut comment2, lens2);
+        }
+        if write1 {
+            write_fasta_record(w, &id1, &comment1, sp.s1.as_slice())?;
+        }
+        if write2 {
+            write_fasta_record(w, rec2.id(), &comment2, sp.s2.as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a request body sent with the chunked transfer encoding.
+struct ChunkedReader<R> {
+    inner: R,
+    /// The number of bytes left to read in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = read_line(&mut self.inner)?;
+            // the size may be followed by chunk extensions
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid chunk size {:?}", size),
+                )
+            })?;
+            if self.remaining == 0 {
+                // the last chunk may be followed by trailers
+                while !read_line(&mut self.inner)?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 && !read_line(&mut self.inner)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk longer than its size",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Writes a response body with the chunked transfer encoding, each write
+/// becoming a chunk.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Writes the last chunk, followed by the trailers `trailers`.
+    fn finish(mut self, trailers: &[(&str, String)]) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n")?;
+        for (name, value) in trailers {
+            write!(self.inner, "{}: {}\r\n", name, value)?;
+        }
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    /// Sends the request `req` to `addr`, returning the whole response.
+    fn request(addr: SocketAddr, req: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(req).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    #[test]
+    fn reads_are_transformed_over_http() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let server = XformServer::bind(geo_re, "127.0.0.1:0")
+            .unwrap()
+            .with_description("1{b[4]u[2]x:}2{r:}");
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // the fragments are sent in two chunks, splitting fragment b
+        let part1 = ">a\nAACCTTG\n>a\nACGT\n>b\nAC\n";
+        let part2 = ">b\nGGGG\n>c\nGGTTCAA\n>c\nCCAC\n";
+        let req = format!(
+            "POST /xform HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+             {:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+            part1.len(),
+            part1,
+            part2.len(),
+            part2
+        );
+        let resp = request(addr, req.as_bytes());
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.contains(">a\nAACCTT\n>a\nACGT\n>c\nGGTTCA\n>c\nCCAC\n"));
+        assert!(resp.ends_with("0\r\nX-Total-Fragments: 3\r\nX-Failed-Parsing: 1\r\n\r\n"));
+
+        let resp = request(addr, b"GET /stats HTTP/1.1\r\n\r\n");
+        assert!(resp.contains("\"geometry\": \"1{b[4]u[2]x:}2{r:}\""));
+        assert!(resp.contains("\"total_fragments\": 3,\n  \"failed_parsing\": 1"));
+        let resp = request(addr, b"GET /xform HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn idle_connections_are_dropped() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let server = XformServer::bind(geo_re, "127.0.0.1:0")
+            .unwrap()
+            .with_max_connections(1)
+            .with_timeout(Some(Duration::from_millis(200)));
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // the only worker is held by a client sending nothing, until it
+        // times out and the next connection is served
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let resp = request(addr, b"GET /stats HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        let mut resp = String::new();
+        idle.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 400"));
+    }
+}
@@ -0,0 +1,253 @@
+//! Simulation of read pairs that conform to a fragment geometry.
+//!
+//! This is the machinery behind the `seq_xformer simulate` subcommand,
+//! exposed so that integrators can generate data for their own end-to-end
+//! tests against arbitrary geometries.  The random number generator is
+//! derived from a seed with [`seeded_rng`], so that the same seed always
+//! yields the same reads.  For example:
+//!
+//! ```
+//! use seq_geom_parser::FragmentGeomDesc;
+//!
+//! let geo = FragmentGeomDesc::try_from("1{b[16]u[12]}2{r[50]}").unwrap();
+//! let mut rng = seq_geom_xform::sim::seeded_rng(42);
+//! let pairs = seq_geom_xform::sim::generate_pairs(&geo, 10, &mut rng);
+//! assert_eq!(pairs.len(), 10);
+//! assert_eq!(pairs[0].0.seq.len(), 28);
+//! ```
+
+use std::io::{self, Write};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece, NucStr};
+
+use crate::iupac;
+
+/// Configuration of the simulation.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// The length of the sequence generated for unbounded pieces.
+    pub unbounded_len: u32,
+    /// The prefix of the simulated read names; the `i`-th pair of
+    /// reads is named `{name_prefix}.{i}`.
+    pub name_prefix: String,
+    /// The quality character assigned to every simulated base.
+    pub qual: u8,
+}
+
+impl SimConfig {
+    /// Create a new `SimConfig` with the default settings.
+    pub fn new() -> Self {
+        Self {
+            unbounded_len: 50,
+            name_prefix: String::from("sim"),
+            qual: b'I',
+        }
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A single simulated sequencing record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimRecord {
+    pub name: String,
+    pub seq: Vec<u8>,
+    pub qual: Vec<u8>,
+}
+
+impl SimRecord {
+    /// Writes this record to `w` in `FASTQ` format.
+    pub fn write_fastq<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"@")?;
+        w.write_all(self.name.as_bytes())?;
+        w.write_all(b"\n")?;
+        w.write_all(&self.seq)?;
+        w.write_all(b"\n+\n")?;
+        w.write_all(&self.qual)?;
+        w.write_all(b"\n")
+    }
+}
+
+/// Returns the random number generator seeded with `seed`, from which the
+/// random choices of a run (e.g. of `seq_xformer simulate --seed`) are
+/// made.  The sequence it generates only depends on `seed` (for a given
+/// version of `rand`), so that runs with the same seed are reproducible.
+pub fn seeded_rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+/// Appends `n` uniformly random nucleotides to `s`.
+fn push_random_bases<R: Rng + ?Sized>(rng: &mut R, n: u32, s: &mut Vec<u8>) {
+    const NUCS: &[u8] = b"ACGT";
+    for _ in 0..n {
+        s.push(NUCS[rng.gen_range(0..NUCS.len())]);
+    }
+}
+
+/// Generates a random read conforming to the geometry pieces `desc`.  The
+/// length of each variable-length piece is chosen uniformly from its range.
+pub fn simulate_read<R: Rng + ?Sized>(desc: &[GeomPiece], cfg: &SimConfig, rng: &mut R) -> Vec<u8> {
+    let mut s = Vec::new();
+    for gp in desc {
+        match gp {
+            GeomPiece::Discard(gl)
+            | GeomPiece::Barcode(gl)
+            | GeomPiece::Umi(gl)
+            | GeomPiece::ReadSeq(gl) => {
+                let n = match gl {
+                    GeomLen::FixedLen(x) => *x,
+                    GeomLen::LenRange(l, h) => rng.gen_range(*l..=*h),
+                    GeomLen::Unbounded => cfg.unbounded_len,
+                };
+                push_random_bases(rng, n, &mut s);
+            }
+            GeomPiece::Fixed(NucStr::Seq(f)) => {
+                // each ambiguity code is replaced by one of its bases
+                for &c in f.as_bytes() {
+                    match iupac::ambiguous_bases(c) {
+                        Some(bases) => {
+                            let bases = bases.strip_suffix(b"N").unwrap_or(bases);
+                            s.push(bases[rng.gen_range(0..bases.len())]);
+                        }
+                        None => s.push(c),
+                    }
+                }
+            }
+        }
+    }
+    s
+}
+
+/// Generates `n` read pairs conforming to the geometry `geo`, using
+/// the default [`SimConfig`].
+pub fn generate_pairs<R: Rng + ?Sized>(
+    geo: &FragmentGeomDesc,
+    n: usize,
+    rng: &mut R,
+) -> Vec<(SimRecord, SimRecord)> {
+    generate_pairs_with_config(geo, n, rng, &SimConfig::default())
+}
+
+/// Generates `n` read pairs conforming to the geometry `geo`, in
+/// accordance with the simulation configuration `cfg`.
+pub fn generate_pairs_with_config<R: Rng + ?Sized>(
+    geo: &FragmentGeomDesc,
+    n: usize,
+    rng: &mut R,
+    cfg: &SimConfig,
+) -> Vec<(SimRecord, SimRecord)> {
+    pairs_iter(geo, rng, cfg).take(n).collect()
+}
+
+/// Returns an (endless) iterator of read pairs conforming to the geometry
+/// `geo`, generated in accordance with `cfg`.  Unlike [`generate_pairs`],
+/// this does not hold all of the simulated pairs in memory at once.
+pub fn pairs_iter<'a, R: Rng + ?Sized>(
+    geo: &'a FragmentGeomDesc,
+    rng: &'a mut R,
+    cfg: &'a SimConfig,
+) -> impl Iterator<Item = (SimRecord, SimRecord)> + 'a {
+    (0..).map(move |i: u64| {
+        let name = format!("{}.{}", cfg.name_prefix, i);
+        let s1 = simulate_read(&geo.read1_desc, cfg, rng);
+        let s2 = simulate_read(&geo.read2_desc, cfg, rng);
+        (
+            SimRecord {
+                name: name.clone(),
+                qual: vec![cfg.qual; s1.len()],
+                seq: s1,
+            },
+            SimRecord {
+                name,
+                qual: vec![cfg.qual; s2.len()],
+                seq: s2,
+            },
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, SeqPair, XformOptions};
+    use tempfile::tempdir;
+
+    #[test]
+    fn simulated_pairs_parse() {
+        let geo = FragmentGeomDesc::try_from("1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}").unwrap();
+        let mut geo_re = geo.as_regex().unwrap();
+        let mut rng = seeded_rng(7);
+        let cfg = SimConfig {
+            unbounded_len: 20,
+            ..SimConfig::default()
+        };
+        let mut sp = SeqPair::new();
+        for (r1, r2) in generate_pairs_with_config(&geo, 100, &mut rng, &cfg) {
+            assert_eq!(r1.name, r2.name);
+            assert_eq!(r2.seq.len(), 20);
+            assert_eq!(r1.seq.len(), r1.qual.len());
+            assert!(geo_re.parse_into(&r1.seq, &r2.seq, &mut sp));
+            assert_eq!(sp.s1.len(), 29);
+        }
+    }
+
+    #[test]
+    fn runs_with_the_same_seed_are_identical() {
+        let draws = |seed| {
+            let mut rng = seeded_rng(seed);
+            (0..8).map(|_| rng.gen::<u64>()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(3), draws(3));
+        assert_ne!(draws(3), draws(4));
+
+        let geo = FragmentGeomDesc::try_from("1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}").unwrap();
+        let pairs = generate_pairs(&geo, 500, &mut seeded_rng(3));
+        assert_eq!(pairs, generate_pairs(&geo, 500, &mut seeded_rng(3)));
+        assert_ne!(pairs, generate_pairs(&geo, 500, &mut seeded_rng(4)));
+        // the reads only depend on the seed, not on how many are drawn at
+        // once (but the names restart with each draw)
+        let mut rng = seeded_rng(3);
+        let mut split = generate_pairs(&geo, 200, &mut rng);
+        split.extend(generate_pairs(&geo, 300, &mut rng));
+        let seqs = |pairs: &[(SimRecord, SimRecord)]| {
+            pairs
+                .iter()
+                .map(|(r1, r2)| (r1.seq.clone(), r2.seq.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(seqs(&split), seqs(&pairs));
+    }
+
+    #[test]
+    fn transformations_record_their_seed() {
+        let geo = FragmentGeomDesc::try_from("1{b[9-10]f[CAGAGC]u[8]b[10]}2{r:}").unwrap();
+        let (mut r1, mut r2) = (vec![], vec![]);
+        for (a, b) in generate_pairs(&geo, 500, &mut seeded_rng(3)) {
+            a.write_fastq(&mut r1).unwrap();
+            b.write_fastq(&mut r2).unwrap();
+        }
+        let (r1, r2) = (
+            String::from_utf8(r1).unwrap(),
+            String::from_utf8(r2).unwrap(),
+        );
+        let dir = tempdir().unwrap();
+        let run = |num_threads| {
+            let opts = XformOptions {
+                num_threads,
+                seed: 3,
+                ..XformOptions::default()
+            };
+            xform_in_dir(dir.path(), geo.as_regex().unwrap(), &r1, &r2, &opts).unwrap()
+        };
+        let (one, four) = (run(1), run(4));
+        assert_eq!((one.stats.seed, one.stats.total_fragments), (3, 500));
+        assert_eq!((one.out1, one.out2), (four.out1, four.out2));
+    }
+}
@@ -0,0 +1,328 @@
+//! Sorting the transformed fragments by barcode.
+//!
+//! When [`crate::XformOptions::sort_by_barcode`] is set, the transformed
+//! fragments are buffered rather than written as they are transformed.  Each
+//! chunk of [`SortOptions::chunk_size`] fragments is sorted by barcode (the
+//! barcodes are concatenated, and taken as they appear in the output, i.e.
+//! after any correction against a whitelist) and spilled to a temporary file.
+//! Once all of the input has been read, the chunks are merged into the
+//! outputs, so that the fragments of each barcode are written next to each
+//! other, in the order of the barcodes.  The fragments with the same barcode
+//! are written in the order of the input, so that the output does not depend
+//! on the chunk size, which only bounds the memory used.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use seq_geom_parser::GeomPiece;
+use tempfile::TempDir;
+
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// Options controlling the sorting of the transformed fragments by barcode.
+#[derive(Debug, Clone)]
+pub struct SortOptions {
+    /// The number of fragments sorted in memory before being spilled to a
+    /// temporary file.
+    pub chunk_size: usize,
+    /// The directory in which the temporary files are created, rather than
+    /// the default temporary directory.
+    pub tmp_dir: Option<PathBuf>,
+}
+
+impl SortOptions {
+    /// The default number of fragments sorted in memory.
+    pub const DEFAULT_CHUNK_SIZE: usize = 1_000_000;
+}
+
+impl Default for SortOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: Self::DEFAULT_CHUNK_SIZE,
+            tmp_dir: None,
+        }
+    }
+}
+
+/// A transformed fragment waiting to be written.
+struct SortRecord {
+    barcode: Vec<u8>,
+    /// The ids and comments of the read 1 and read 2 records.
+    headers: [(Vec<u8>, Vec<u8>); 2],
+    frag: SeqPair,
+    /// The ranges of the padding of the read 1 and read 2 records.
+    pads: [Vec<Range<u32>>; 2],
+}
+
+/// Encodes the ranges `pads` as the little-endian bounds of each range.
+fn encode_pads(pads: &[Range<u32>]) -> Vec<u8> {
+    pads.iter()
+        .flat_map(|r| [r.start, r.end])
+        .flat_map(u32::to_le_bytes)
+        .collect()
+}
+
+/// Decodes the ranges encoded by [`encode_pads`].
+fn decode_pads(b: &[u8]) -> Vec<Range<u32>> {
+    let bound = |c: &[u8]| u32::from_le_bytes(c.try_into().unwrap());
+    b.chunks_exact(8)
+        .map(|c| bound(&c[..4])..bound(&c[4..]))
+        .collect()
+}
+
+impl SortRecord {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let [(id1, c1), (id2, c2)] = &self.headers;
+        let [p1, p2] = self.pads.each_ref().map(|p| encode_pads(p));
+        for field in [
+            &self.barcode[..],
+            id1,
+            c1,
+            id2,
+            c2,
+            self.frag.s1.as_slice(),
+            self.frag.s2.as_slice(),
+            &p1,
+            &p2,
+        ] {
+            w.write_all(&(field.len() as u32).to_le_bytes())?;
+            w.write_all(field)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next record written by [`SortRecord::write_to`] from `r`,
+    /// or returns `None` at the end of `r`.
+    fn read_from<R: Read>(r: &mut R) -> Result<Option<Self>> {
+        let mut fields: [Vec<u8>; 9] = Default::default();
+        for (i, field) in fields.iter_mut().enumerate() {
+            let mut len = [0_u8; 4];
+            match r.read_exact(&mut len) {
+                Err(e) if i == 0 && e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                res => res?,
+            }
+            field.resize(u32::from_le_bytes(len) as usize, 0);
+            r.read_exact(field)?;
+        }
+        let [barcode, id1, c1, id2, c2, s1, s2, p1, p2] = fields;
+        Ok(Some(Self {
+            barcode,
+            headers: [(id1, c1), (id2, c2)],
+            frag: SeqPair { s1, s2 },
+            pads: [decode_pads(&p1), decode_pads(&p2)],
+        }))
+    }
+}
+
+/// Buffers the transformed fragments, and writes them sorted by barcode once
+/// all of them have been transformed.
+pub(crate) struct BarcodeSorter {
+    spans: Vec<PieceSpan>,
+    chunk_size: usize,
+    tmp_dir: TempDir,
+    records: Vec<SortRecord>,
+    /// The sorted chunks spilled so far, in the order of the input.
+    chunks: Vec<PathBuf>,
+}
+
+impl BarcodeSorter {
+    /// Create a new `BarcodeSorter` for the fragments transformed by `geo_re`,
+    /// whose barcode pieces must lie at fixed offsets in the transformed
+    /// fragments.
+    pub(crate) fn new(geo_re: &FragmentRegexDesc, opts: &SortOptions) -> Result<Self> {
+        let spans = geo_re
+            .piece_spans(|gp| matches!(gp, GeomPiece::Barcode(_)))
+            .map_err(|gp| {
+                anyhow!(
+                    "sorting by barcode requires the barcode pieces of the geometry to be \
+                     of bounded length and preceded only by bounded pieces, but found {:?}",
+                    gp
+                )
+            })?;
+        if spans.is_empty() {
+            bail!("sorting by barcode requires the geometry to have a barcode (b) piece");
+        }
+        if opts.chunk_size == 0 {
+            bail!("The chunks sorted by barcode must hold at least one fragment");
+        }
+        let tmp_dir = match &opts.tmp_dir {
+            Some(d) => tempfile::tempdir_in(d),
+            None => tempfile::tempdir(),
+        }
+        .context("could not create the temporary directory for sorting by barcode")?;
+        Ok(Self {
+            spans,
+            chunk_size: opts.chunk_size,
+            tmp_dir,
+            records: Vec::new(),
+            chunks: vec![],
+        })
+    }
+
+    /// Buffers the fragment `frag`, whose records have the ids and comments
+    /// `headers` and the padding `pads`, spilling the buffered fragments once
+    /// a chunk is full.
+    pub(crate) fn push(
+        &mut self,
+        headers: [(&[u8], &[u8]); 2],
+        frag: &SeqPair,
+        pads: [&[Range<u32>]; 2],
+    ) -> Result<()> {
+        let mut barcode = Vec::new();
+        extract_spans(frag, &self.spans, &mut barcode);
+        self.records.push(SortRecord {
+            barcode,
+            headers: headers.map(|(id, comment)| (id.to_vec(), comment.to_vec())),
+            frag: SeqPair {
+                s1: frag.s1.clone(),
+                s2: frag.s2.clone(),
+            },
+            pads: pads.map(|p| p.to_vec()),
+        });
+        if self.records.len() >= self.chunk_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Sorts the buffered fragments and writes them to a new chunk.
+    fn spill(&mut self) -> Result<()> {
+        // the sort is stable, so that the fragments with the same barcode
+        // remain in the order of the input
+        self.records.sort_by(|a, b| a.barcode.cmp(&b.barcode));
+        let path = self
+            .tmp_dir
+            .path()
+            .join(format!("chunk_{}.bin", self.chunks.len()));
+        let mut w = BufWriter::new(
+            File::create(&path).with_context(|| format!("could not create {}", path.display()))?,
+        );
+        for r in self.records.drain(..) {
+            r.write_to(&mut w)?;
+        }
+        w.flush()?;
+        self.chunks.push(path);
+        Ok(())
+    }
+
+    /// Writes all of the fragments, sorted by barcode, with `write`, and
+    /// removes the temporary files.  Returns the number of chunks that were
+    /// spilled to temporary files.
+    pub(crate) fn finish<F>(mut self, mut write: F) -> Result<usize>
+    where
+        F: FnMut([(&[u8], &[u8]); 2], &SeqPair, [&[Range<u32>]; 2]) -> Result<()>,
+    {
+        let mut write_record = |r: &SortRecord| {
+            let [(id1, c1), (id2, c2)] = &r.headers;
+            write([(id1, c1), (id2, c2)], &r.frag, [&r.pads[0], &r.pads[1]])
+        };
+        if self.chunks.is_empty() {
+            // everything fits in memory
+            self.records.sort_by(|a, b| a.barcode.cmp(&b.barcode));
+            for r in &self.records {
+                write_record(r)?;
+            }
+            return Ok(0);
+        }
+        if !self.records.is_empty() {
+            self.spill()?;
+        }
+        let mut readers = vec![];
+        for p in &self.chunks {
+            let f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+            readers.push(BufReader::new(f));
+        }
+        let mut heads: Vec<Option<SortRecord>> = Vec::with_capacity(readers.len());
+        // the next record of each chunk, by barcode and then by chunk (and so
+        // by input order)
+        let mut heap = BinaryHeap::new();
+        for (i, r) in readers.iter_mut().enumerate() {
+            let head = SortRecord::read_from(r)?;
+            if let Some(rec) = &head {
+                heap.push(Reverse((rec.barcode.clone(), i)));
+            }
+            heads.push(head);
+        }
+        while let Some(Reverse((_, i))) = heap.pop() {
+            let rec = heads[i].take().unwrap();
+            write_record(&rec)?;
+            heads[i] = SortRecord::read_from(&mut readers[i])?;
+            if let Some(next) = &heads[i] {
+                heap.push(Reverse((next.barcode.clone(), i)));
+            }
+        }
+        let num_chunks = self.chunks.len();
+        self.tmp_dir
+            .close()
+            .context("could not remove the temporary files of the sort by barcode")?;
+        Ok(num_chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{xform_read_pairs_to_file_with_options, FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn padding_is_kept_in_the_sort_records() {
+        let pads = [0_u32..2, 7..9];
+        assert_eq!(decode_pads(&encode_pads(&pads)), pads);
+        assert!(decode_pads(&encode_pads(&[])).is_empty());
+    }
+
+    #[test]
+    fn output_is_sorted_by_barcode() {
+        let geo = FragmentGeomDesc::try_from("1{b[2]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        let (mut s1, mut s2) = (String::new(), String::new());
+        for i in 0..20 {
+            let bc = ["TT", "AC", "GA", "AA"][i % 4];
+            s1 += &format!(">r{}\n{}CC\n", i, bc);
+            s2 += &format!(">r{}\nGG\n", i);
+        }
+        std::fs::write(&r1, s1).unwrap();
+        std::fs::write(&r2, s2).unwrap();
+
+        let mut outputs = vec![];
+        for chunk_size in [3, 1_000] {
+            let (o1, o2) = (
+                dir.path().join(format!("o1_{}.fa", chunk_size)),
+                dir.path().join(format!("o2_{}.fa", chunk_size)),
+            );
+            let opts = XformOptions {
+                sort_by_barcode: Some(SortOptions {
+                    chunk_size,
+                    tmp_dir: Some(dir.path().to_path_buf()),
+                }),
+                ..XformOptions::default()
+            };
+            let stats = xform_read_pairs_to_file_with_options(
+                geo.as_regex().unwrap(),
+                std::slice::from_ref(&r1),
+                std::slice::from_ref(&r2),
+                Some(o1.clone()),
+                Some(o2.clone()),
+                &opts,
+            )
+            .unwrap();
+            assert_eq!(stats.total_fragments, 20);
+            outputs.push(std::fs::read_to_string(o1).unwrap());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        let ids: Vec<&str> = outputs[0].lines().step_by(2).collect();
+        assert_eq!(&ids[..6], [">r3", ">r7", ">r11", ">r15", ">r19", ">r1"]);
+        let bcs: Vec<&str> = outputs[0].lines().skip(1).step_by(2).collect();
+        assert!(bcs.windows(2).all(|w| w[0][..2] <= w[1][..2]));
+        // the temporary files are removed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 6);
+    }
+}
@@ -0,0 +1,384 @@
+//! Translation of spatial barcodes into array coordinates.
+//!
+//! In spatial chemistries (e.g. Slide-seq or Visium), the barcode of a
+//! fragment identifies the spot (or bead) of the array that captured it.
+//! When a [`CoordinateTable`] is given (through
+//! [`crate::XformOptions::spatial`]), the barcode of each transformed
+//! fragment (its barcode pieces concatenated, after correction against the
+//! whitelist if any) is looked up in the table, and its coordinates are
+//! appended to the headers of the records of the fragment (as `x=<x>
+//! y=<y>`) and/or written to a sidecar TSV (see
+//! [`SpatialOptions::sidecar`]), so that spatial QC can be run right after
+//! the transformation.  The number of fragments whose barcode was placed on
+//! the array is reported in [`crate::XformStats::spatial`].
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use seq_geom_parser::GeomPiece;
+
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// The coordinates of the spots of a spatial array, by barcode.
+#[derive(Debug, Clone)]
+pub struct CoordinateTable {
+    coords: HashMap<Vec<u8>, [String; 2]>,
+}
+
+impl CoordinateTable {
+    /// Create a new `CoordinateTable` from the barcodes and `x` and `y`
+    /// coordinates of the spots.  The barcodes must be distinct, and the
+    /// coordinates must be numbers, which are written as given.
+    pub fn new(spots: Vec<(Vec<u8>, String, String)>) -> Result<Self> {
+        let mut coords = HashMap::with_capacity(spots.len());
+        for (mut bc, x, y) in spots {
+            bc.make_ascii_uppercase();
+            for c in [&x, &y] {
+                if c.parse::<f64>().is_err() {
+                    bail!(
+                        "invalid coordinate {} for barcode {}",
+                        c,
+                        String::from_utf8_lossy(&bc)
+                    );
+                }
+            }
+            if let Some(prev) = coords.insert(bc, [x, y]) {
+                bail!(
+                    "duplicate barcode in the coordinate table (at {}, {})",
+                    prev[0],
+                    prev[1]
+                );
+            }
+        }
+        if coords.is_empty() {
+            bail!("the coordinate table contains no barcodes");
+        }
+        Ok(Self { coords })
+    }
+
+    /// Reads a coordinate table from the CSV or TSV file `p`.  See
+    /// [`CoordinateTable::from_reader`] for the accepted formats.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let f = File::open(p)
+            .with_context(|| format!("could not open coordinate table {}", p.display()))?;
+        Self::from_reader(BufReader::new(f))
+            .with_context(|| format!("could not parse coordinate table {}", p.display()))
+    }
+
+    /// Reads a coordinate table with one spot per line, with comma or tab
+    /// separated fields.  If the first line is a header containing a
+    /// `barcode` column, the coordinates are read from the `x` and `y`
+    /// columns (or `xcoord` and `ycoord`, as in Slide-seq, or `array_col`
+    /// and `array_row`, as in the tissue positions of Visium).  Otherwise,
+    /// each line must consist of a barcode followed by its `x` and `y`
+    /// coordinates.  A `-1` style suffix of the barcodes (as added by Cell
+    /// Ranger) is ignored.  Empty lines and lines starting with `#` are
+    /// ignored.
+    pub fn from_reader<R: BufRead>(r: R) -> Result<Self> {
+        let mut lines = r.lines().filter(|l| {
+            l.as_ref()
+                .map_or(true, |l| !l.trim().is_empty() && !l.starts_with('#'))
+        });
+        let Some(first) = lines.next().transpose()? else {
+            bail!("the coordinate table contains no barcodes");
+        };
+        let sep = if first.contains('\t') { '\t' } else { ',' };
+        let split = |l: &str| {
+            l.split(sep)
+                .map(|f| f.trim().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let header = split(&first);
+        let col = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|n| header.iter().position(|h| h.eq_ignore_ascii_case(n)))
+        };
+        let (cols, mut spots) = match col(&["barcode"]) {
+            Some(bc_col) => {
+                let x_col = col(&["x", "xcoord", "array_col"])
+                    .ok_or_else(|| anyhow!("the header has no x coordinate column"))?;
+                let y_col = col(&["y", "ycoord", "array_row"])
+                    .ok_or_else(|| anyhow!("the header has no y coordinate column"))?;
+                ([bc_col, x_col, y_col], vec![])
+            }
+            None => ([0, 1, 2], vec![header]),
+        };
+        for l in lines {
+            spots.push(split(&l?));
+        }
+        let spots = spots
+            .into_iter()
+            .map(|s| match cols.map(|c| s.get(c)) {
+                [Some(bc), Some(x), Some(y)] => {
+                    let bc = match bc.rsplit_once('-') {
+                        Some((bc, suffix)) if suffix.bytes().all(|c| c.is_ascii_digit()) => bc,
+                        _ => bc,
+                    };
+                    Ok((bc.as_bytes().to_vec(), x.clone(), y.clone()))
+                }
+                _ => bail!("expected a barcode and its coordinates, but got {:?}", s),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(spots)
+    }
+
+    /// The number of spots in the table.
+    pub fn len(&self) -> usize {
+        self.coords.len()
+    }
+
+    /// Returns true if the table holds no spots.
+    pub fn is_empty(&self) -> bool {
+        self.coords.is_empty()
+    }
+
+    /// The `x` and `y` coordinates of the spot with barcode `bc`, if any.
+    pub fn get(&self, bc: &[u8]) -> Option<&[String; 2]> {
+        self.coords.get(bc)
+    }
+}
+
+/// Options for translating the barcodes into array coordinates.
+#[derive(Debug, Clone)]
+pub struct SpatialOptions {
+    /// The coordinates of the spots of the array.
+    pub table: CoordinateTable,
+    /// If true, the coordinates are appended to the headers of the records.
+    pub tag_headers: bool,
+    /// If set, a TSV with the name, barcode and coordinates of each
+    /// transformed fragment placed on the array is written to this file.
+    pub sidecar: Option<PathBuf>,
+}
+
+/// The number of transformed fragments whose barcode was, or was not, found
+/// in the coordinate table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpatialCounts {
+    pub placed: u64,
+    pub unplaced: u64,
+}
+
+/// Looks up the barcodes of the transformed fragments in the coordinate
+/// table.
+#[derive(Debug)]
+pub(crate) struct SpotLocator {
+    spans: Vec<PieceSpan>,
+    buf: Vec<u8>,
+    sidecar: Option<BufWriter<File>>,
+}
+
+impl SpotLocator {
+    /// Create a new `SpotLocator` for the fragments transformed by `geo_re`,
+    /// whose barcode pieces must lie at fixed offsets in the transformed
+    /// fragments, creating the sidecar TSV of `opts`, if any.
+    pub(crate) fn new(geo_re: &FragmentRegexDesc, opts: &SpatialOptions) -> Result<Self> {
+        let spans = geo_re
+            .piece_spans(|gp| matches!(gp, GeomPiece::Barcode(_)))
+            .map_err(|gp| {
+                anyhow!(
+                    "translating barcodes into coordinates requires the barcode pieces of the \
+                     geometry to be of bounded length and preceded only by bounded pieces, but \
+                     found {:?}",
+                    gp
+                )
+            })?;
+        if spans.is_empty() {
+            bail!(
+                "translating barcodes into coordinates requires the geometry to have a barcode \
+                 (b) piece"
+            );
+        }
+        let sidecar = match &opts.sidecar {
+            Some(p) => {
+                let mut w = BufWriter::new(
+                    File::create(p).with_context(|| format!("could not create {}", p.display()))?,
+                );
+                writeln!(w, "read\tbarcode\tx\ty")?;
+                Some(w)
+            }
+            None => None,
+        };
+        Ok(Self {
+            spans,
+            buf: Vec::new(),
+            sidecar,
+        })
+    }
+
+    /// Looks up the barcode of `frag`, whose read 1 record is named `id`
+    /// (up to the first whitespace), recording the outcome in `counts` and
+    /// writing the coordinates found to the sidecar TSV.  Returns the
+    /// coordinates of the spot, if found.
+    pub(crate) fn locate<'a>(
+        &mut self,
+        table: &'a CoordinateTable,
+        id: &[u8],
+        frag: &SeqPair,
+        counts: &mut SpatialCounts,
+    ) -> Result<Option<&'a [String; 2]>> {
+        extract_spans(frag, &self.spans, &mut self.buf);
+        let Some(coords) = table.get(&self.buf) else {
+            counts.unplaced += 1;
+            return Ok(None);
+        };
+        counts.placed += 1;
+        if let Some(w) = self.sidecar.as_mut() {
+            let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+            w.write_all(name)?;
+            w.write_all(b"\t")?;
+            w.write_all(&self.buf)?;
+            writeln!(w, "\t{}\t{}", coords[0], coords[1])?;
+        }
+        Ok(Some(coords))
+    }
+
+    /// Flushes the sidecar TSV, if any.
+    pub(crate) fn finish(self) -> Result<()> {
+        if let Some(mut w) = self.sidecar {
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    /// The coordinates `x` and `y`.
+    fn xy(x: &str, y: &str) -> [String; 2] {
+        [String::from(x), String::from(y)]
+    }
+
+    #[test]
+    fn coordinate_tables_are_parsed() {
+        let csv = "barcode,in_tissue,array_row,array_col\nAACC-1,1,0,2\nGGTT-1,1,5,7\n";
+        let table = CoordinateTable::from_reader(csv.as_bytes()).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(b"GGTT"), Some(&xy("7", "5")));
+        let tsv = "# Slide-seq\n\nBarcode\tXCOORD\tYCOORD\naacc\t1.5\t-2\n";
+        let table = CoordinateTable::from_reader(tsv.as_bytes()).unwrap();
+        assert_eq!(table.get(b"AACC"), Some(&xy("1.5", "-2")));
+        // without a header, and only with numeric suffixes removed
+        let table = CoordinateTable::from_reader("AACC-A,1,2\n".as_bytes()).unwrap();
+        assert_eq!(table.get(b"AACC-A"), Some(&xy("1", "2")));
+
+        let dir = tempdir().unwrap();
+        let p = dir.path().join("coords.csv");
+        std::fs::write(&p, csv).unwrap();
+        assert_eq!(CoordinateTable::from_path(&p).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_coordinate_tables_are_rejected() {
+        for table in [
+            "AACC,1,y\n",
+            "AACC,1\n",
+            "AACC,1,2\naacc,3,4\n",
+            "barcode,x\nAACC,1\n",
+            "# no spots\n\n",
+        ] {
+            assert!(
+                CoordinateTable::from_reader(table.as_bytes()).is_err(),
+                "{}",
+                table
+            );
+        }
+        assert!(CoordinateTable::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn barcodes_are_located_on_the_array() {
+        let table = CoordinateTable::new(vec![(b"AACC".to_vec(), "2".into(), "0".into())]).unwrap();
+        let dir = tempdir().unwrap();
+        let opts = SpatialOptions {
+            table: table.clone(),
+            tag_headers: false,
+            sidecar: Some(dir.path().join("coords.tsv")),
+        };
+        let locator = |geom: &str| {
+            let geo_re = FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap();
+            SpotLocator::new(&geo_re, &opts)
+        };
+        assert!(locator("1{u[4]}2{r:}").is_err());
+        assert!(locator("1{b:}2{r:}").is_err());
+
+        // the barcode is split across both reads
+        let mut locator = locator("1{b[2]u[2]}2{b[2]r:}").unwrap();
+        let mut counts = SpatialCounts::default();
+        let frag = |s1: &[u8], s2: &[u8]| SeqPair {
+            s1: s1.to_vec(),
+            s2: s2.to_vec(),
+        };
+        let found = locator
+            .locate(&table, b"a 1:N", &frag(b"AATT", b"CCGG"), &mut counts)
+            .unwrap();
+        assert_eq!(found, Some(&xy("2", "0")));
+        let found = locator
+            .locate(&table, b"b", &frag(b"CCTT", b"AAGG"), &mut counts)
+            .unwrap();
+        assert_eq!(found, None);
+        locator.finish().unwrap();
+        assert_eq!(
+            counts,
+            SpatialCounts {
+                placed: 1,
+                unplaced: 1
+            }
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("coords.tsv")).unwrap(),
+            "read\tbarcode\tx\ty\na\tAACC\t2\t0\n"
+        );
+    }
+
+    #[test]
+    fn barcodes_are_translated_into_coordinates() {
+        let csv = "barcode,in_tissue,array_row,array_col\nAACC-1,1,0,2\nGGTT-1,1,5,7\n";
+        let table = CoordinateTable::from_reader(csv.as_bytes()).unwrap();
+        let geo = FragmentGeomDesc::try_from("1{b[4]u[2]}2{r:}").unwrap();
+        let dir = tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        let opts = XformOptions {
+            spatial: Some(SpatialOptions {
+                table,
+                tag_headers: true,
+                sidecar: Some(p("coords.tsv")),
+            }),
+            ..XformOptions::default()
+        };
+        let out = xform_in_dir(
+            dir.path(),
+            geo.as_regex().unwrap(),
+            ">a 1:N\nAACCTT\n>b\nACGTAA\n>c\nGGTTCC\n",
+            ">a\nTTTT\n>b\nGGGG\n>c\nCCCC\n",
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(
+            out.stats.spatial,
+            Some(SpatialCounts {
+                placed: 2,
+                unplaced: 1
+            })
+        );
+        assert_eq!(out.out2, ">a x=2 y=0\nTTTT\n>b\nGGGG\n>c x=7 y=5\nCCCC\n");
+        assert_eq!(
+            std::fs::read_to_string(p("coords.tsv")).unwrap(),
+            "read\tbarcode\tx\ty\na\tAACC\t2\t0\nc\tGGTT\t7\t5\n"
+        );
+    }
+}
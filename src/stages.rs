@@ -0,0 +1,630 @@
+//! The stages that the transformed fragments go through on their way to the
+//! outputs.
+//!
+//! Most of the options of [`XformOptions`] enable a stage of the
+//! transformation of each fragment, run once the fragment has matched the
+//! geometry: a stage may reject the fragment (e.g.
+//! [`XformOptions::whitelist`]), rewrite it (e.g. [`XformOptions::guides`]),
+//! annotate the headers of its records (e.g. [`XformOptions::features`]), or
+//! route it away from the main outputs (e.g. [`XformOptions::demux`]).
+//! [`FragmentStages`] sets up the stages that are enabled, and runs each
+//! fragment through them in order.
+
+use std::ops::Range;
+use std::path::Path;
+use std::sync::MutexGuard;
+
+use anyhow::{bail, Context, Result};
+use tracing::info;
+
+use crate::barcode::{BarcodeCounter, BarcodeCounts};
+use crate::batch::BatchFragment;
+use crate::consistency::{BarcodeChecker, ConsistencyCounts, ConsistencyMode};
+use crate::control::{ControlCounts, ControlOptions};
+use crate::correction::{BarcodeCorrector, OneMismatchCorrector};
+use crate::dedup::{DedupMode, DuplicateFilter};
+use crate::demux::{Demultiplexer, DemuxCounts};
+use crate::failure::{FailureCause, ParseFailure};
+use crate::feature::{self, FeatureCounts, FeatureOptions};
+use crate::guide::{GuideCounts, GuideExtractor};
+use crate::hook::{RecordHook, RecordLayout};
+use crate::index;
+use crate::lengths::record_lens;
+use crate::naming::IdWriter;
+use crate::output::{self, write_input_record, PairedWriter, SinkConfig};
+use crate::parallel::ParsedFragment;
+use crate::probe::{ProbeCounts, ProbeOptions};
+use crate::retry::RetryPolicy;
+use crate::sort::BarcodeSorter;
+use crate::spatial::{SpatialCounts, SpotLocator};
+use crate::umi::UmiCorrector;
+use crate::whitelist::{BarcodeRewriter, Whitelist, WhitelistCounts};
+use crate::{
+    push_lens_tag, write_fragment, FragmentRegexDesc, FragmentWriter, PaddingScheme, SeqPair,
+    UmiWindow, XformOptions, XformStats,
+};
+
+/// The stages enabled by the options of a transformation (see the module
+/// documentation), in the order in which they are run.
+pub(crate) struct FragmentStages<'a> {
+    opts: &'a XformOptions,
+    /// The fragments failing to parse, as they were read.
+    unmatched_writer: Option<PairedWriter>,
+    barcode_checker: Option<(BarcodeChecker, ConsistencyMode)>,
+    barcode_rewriter: Option<(BarcodeRewriter, &'a Whitelist)>,
+    /// The corrector, locked for the whole transformation.
+    corrector: Option<MutexGuard<'a, dyn BarcodeCorrector + 'static>>,
+    one_mismatch: OneMismatchCorrector,
+    guide_extractor: Option<GuideExtractor>,
+    id_writer: Option<IdWriter>,
+    /// With padding to the maximum length, the captured lengths are recorded
+    /// in the headers.
+    tag_lens: bool,
+    dedup: Option<(DuplicateFilter, DedupMode)>,
+    /// The read containing the feature barcode and its offset in the
+    /// transformed read.
+    feature_loc: Option<(usize, usize)>,
+    /// The read containing the probe and its offset in the transformed read.
+    probe_loc: Option<(usize, usize)>,
+    spot_locator: Option<SpotLocator>,
+    /// The read containing the biological piece and its offset in the
+    /// transformed read.
+    control_loc: Option<(usize, usize)>,
+    /// The fragments routed away from the main outputs.
+    control_writer: Option<PairedWriter>,
+    demultiplexer: Option<Demultiplexer>,
+    /// The hook, locked for the whole transformation, and the layout of the
+    /// pieces in the transformed reads.
+    record_hook: Option<(MutexGuard<'a, dyn RecordHook + 'static>, RecordLayout)>,
+    barcode_counter: Option<BarcodeCounter>,
+    umi_window: Option<UmiWindow>,
+    sorter: Option<BarcodeSorter>,
+    /// The transformed index reads.
+    index_writer: Option<PairedWriter>,
+    /// The transformed fragment with its barcode corrected.
+    corrected: SeqPair,
+    /// The transformed fragment with its read sequence replaced by the guide.
+    guided: SeqPair,
+    /// The transformed fragment as modified by the hook.
+    hooked: SeqPair,
+    /// Annotations appended to the headers of the read 1 and read 2 records.
+    comments: [Vec<u8>; 2],
+}
+
+impl<'a> FragmentStages<'a> {
+    /// Sets up the stages enabled by `opts` for the fragments transformed
+    /// with `geo_re` (any of the geometries of the `num_pairs` input pairs),
+    /// adding their counts to `stats`.  The outputs of the samples are named
+    /// after `demux_bases` and written as `outs`, and the writes of the other
+    /// outputs are retried in accordance with `retry`.
+    pub(crate) fn new(
+        geo_re: &FragmentRegexDesc,
+        opts: &'a XformOptions,
+        num_pairs: usize,
+        outs: [&SinkConfig; 2],
+        demux_bases: [Option<&Path>; 2],
+        retry: Option<&RetryPolicy>,
+        stats: &mut XformStats,
+    ) -> Result<Self> {
+        let cfg = |p: &Path| SinkConfig {
+            compression: output::compression_from_extension(p).unwrap_or_default(),
+            retry: retry.cloned(),
+            ..SinkConfig::default()
+        };
+        let unmatched_writer = match (&opts.unmatched1, &opts.unmatched2) {
+            (None, None) => None,
+            (u1, u2) => {
+                let cfg = |p: &Option<_>| p.as_deref().map(cfg).unwrap_or_default();
+                Some(PairedWriter::new(
+                    u1.clone(),
+                    u2.clone(),
+                    &cfg(u1),
+                    &cfg(u2),
+                    None,
+                )?)
+            }
+        };
+
+        index::check_index_reads(geo_re, opts, num_pairs)?;
+        let index_writer = match &opts.index_reads {
+            Some(io) if !geo_re.index_reads().is_empty() => {
+                let mut out = io.out.clone();
+                if retry.is_some() {
+                    for o in out.iter_mut() {
+                        o.retry = retry.cloned();
+                    }
+                }
+                let [o3, o4] = io.outputs.clone();
+                Some(PairedWriter::new(o3, o4, &out[0], &out[1], None)?)
+            }
+            _ => None,
+        };
+
+        let feature_loc = match &opts.features {
+            Some(fo) => {
+                stats.feature_counts = Some(FeatureCounts::new(&fo.reference));
+                Some(feature::feature_location(geo_re)?)
+            }
+            None => None,
+        };
+        let (control_loc, control_writer) = match &opts.controls {
+            Some(co) => {
+                stats.control_counts = Some(ControlCounts::new(&co.reference));
+                let loc = feature::read_seq_location(geo_re, "matching control sequences")?;
+                let writer = match &co.outputs {
+                    Some([c1, c2]) => Some(PairedWriter::new(
+                        Some(c1.clone()),
+                        Some(c2.clone()),
+                        &cfg(c1),
+                        &cfg(c2),
+                        None,
+                    )?),
+                    None => None,
+                };
+                (Some(loc), writer)
+            }
+            None => (None, None),
+        };
+        let demultiplexer = match &opts.demux {
+            Some(d) => {
+                if opts.bam.is_some()
+                    || opts.umi_correction.is_some()
+                    || opts.sort_by_barcode.is_some()
+                {
+                    bail!(
+                        "The fragments cannot be demultiplexed when they are written as unaligned \
+                         BAM, sorted by barcode, or have their UMIs corrected"
+                    );
+                }
+                stats.demux = Some(DemuxCounts::new(&d.sheet));
+                Some(Demultiplexer::new(
+                    geo_re,
+                    d,
+                    demux_bases,
+                    outs,
+                    opts.interleaved,
+                    opts.split_output,
+                )?)
+            }
+            None => None,
+        };
+        let probe_loc = match &opts.probes {
+            Some(po) => {
+                po.validate()?;
+                stats.probes = Some(ProbeCounts::default());
+                let (read, offset) = feature::read_seq_location(geo_re, "matching probes")?;
+                Some((read, offset + po.offset))
+            }
+            None => None,
+        };
+        let spot_locator = match &opts.spatial {
+            Some(so) => {
+                stats.spatial = Some(SpatialCounts::default());
+                Some(SpotLocator::new(geo_re, so)?)
+            }
+            None => None,
+        };
+        let dedup = match &opts.dedup {
+            Some(d) => {
+                stats.duplicate_fragments = Some(0);
+                Some((DuplicateFilter::new(geo_re, d)?, d.mode))
+            }
+            None => None,
+        };
+        let umi_window = match &opts.umi_correction {
+            Some(u) => {
+                if u.window == 0 {
+                    bail!("The UMI correction window must hold at least one fragment");
+                }
+                stats.corrected_umis = Some(0);
+                Some(UmiWindow {
+                    corrector: UmiCorrector::new(geo_re)?,
+                    size: u.window,
+                    headers: Vec::with_capacity(u.window),
+                    frags: Vec::with_capacity(u.window),
+                    pads: Vec::with_capacity(u.window),
+                })
+            }
+            None => None,
+        };
+        let id_writer = opts
+            .id_format
+            .as_ref()
+            .map(|f| IdWriter::new(f, geo_re))
+            .transpose()?;
+        let barcode_counter = if opts.count_barcodes {
+            stats.barcode_counts = Some(BarcodeCounts::new());
+            Some(BarcodeCounter::new(geo_re)?)
+        } else {
+            None
+        };
+        let barcode_rewriter = match &opts.whitelist {
+            Some(wl) => {
+                stats.whitelist = Some(WhitelistCounts::default());
+                let rewriter = BarcodeRewriter::new(geo_re, wl, opts.length_aware_whitelist)?;
+                Some((rewriter, wl))
+            }
+            None => None,
+        };
+        let barcode_checker = match &opts.barcode_consistency {
+            Some(co) => {
+                stats.barcode_consistency = Some(ConsistencyCounts::default());
+                Some((BarcodeChecker::new(geo_re, co)?, co.mode))
+            }
+            None => None,
+        };
+        let guide_extractor = match &opts.guides {
+            Some(go) => {
+                stats.guides = Some(GuideCounts::default());
+                Some(GuideExtractor::new(geo_re, go)?)
+            }
+            None => None,
+        };
+        let sorter = match &opts.sort_by_barcode {
+            Some(so) => {
+                if opts.umi_correction.is_some() {
+                    bail!("UMIs cannot be corrected when sorting the output by barcode");
+                }
+                Some(BarcodeSorter::new(geo_re, so)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            opts,
+            unmatched_writer,
+            barcode_checker,
+            barcode_rewriter,
+            corrector: opts.barcode_corrector.as_ref().map(|c| c.lock()),
+            one_mismatch: OneMismatchCorrector,
+            guide_extractor,
+            id_writer,
+            tag_lens: geo_re.padding() == PaddingScheme::MaxLen,
+            dedup,
+            feature_loc,
+            probe_loc,
+            spot_locator,
+            control_loc,
+            control_writer,
+            demultiplexer,
+            record_hook: opts
+                .record_hook
+                .as_ref()
+                .map(|h| (h.lock(), RecordLayout::new(geo_re))),
+            barcode_counter,
+            umi_window,
+            sorter,
+            index_writer,
+            corrected: SeqPair::new(),
+            guided: SeqPair::new(),
+            hooked: SeqPair::new(),
+            comments: [Vec::new(), Vec::new()],
+        })
+    }
+
+    /// Whether the index reads of the fragments are transformed.
+    pub(crate) fn transforms_index_reads(&self) -> bool {
+        self.index_writer.is_some()
+    }
+
+    /// Runs the fragment `frag`, parsed into `parsed`, through the stages,
+    /// and writes it to `writer` (or to the output it is routed to) unless
+    /// it is rejected or dropped, counting it in `stats`.  `index` is the
+    /// number of the fragment (from 1) among those read.  Returns false if
+    /// the fragment failed to parse, or was rejected by one of the stages.
+    pub(crate) fn transform(
+        &mut self,
+        frag: &BatchFragment<'_>,
+        parsed: &ParsedFragment,
+        index: u64,
+        writer: &mut FragmentWriter,
+        stats: &mut XformStats,
+    ) -> Result<bool> {
+        if !parsed.matched {
+            return self.reject(frag, parsed.failure, stats);
+        }
+        let opts = self.opts;
+        let mut records = &parsed.seqs;
+        let mut pads: [&[Range<u32>]; 2] = [&parsed.pads[0], &parsed.pads[1]];
+
+        // the stages that may reject the fragment
+        // whether the read 1 and read 2 barcodes are flagged as differing
+        let mut inconsistent = false;
+        if let Some((checker, mode)) = self.barcode_checker.as_mut() {
+            if !checker.check(records, stats.barcode_consistency.as_mut().unwrap()) {
+                match mode {
+                    ConsistencyMode::Flag => inconsistent = true,
+                    ConsistencyMode::Drop => {
+                        let failure = ParseFailure {
+                            read: 2,
+                            cause: FailureCause::InconsistentBarcodes,
+                        };
+                        return self.reject(frag, Some(failure), stats);
+                    }
+                }
+            }
+        }
+        if let Some((bcc, wl)) = self.barcode_rewriter.as_mut() {
+            let corrector: &mut dyn BarcodeCorrector = match self.corrector.as_mut() {
+                Some(c) => &mut **c,
+                None => &mut self.one_mismatch,
+            };
+            let counts = stats.whitelist.as_mut().unwrap();
+            let lens = [&parsed.lens[0][..], &parsed.lens[1][..]];
+            match bcc.correct(corrector, wl, records, lens, &mut self.corrected, counts)? {
+                Some(f) => records = f,
+                None => {
+                    let failure = ParseFailure {
+                        read: bcc.read(),
+                        cause: FailureCause::NotInWhitelist,
+                    };
+                    return self.reject(frag, Some(failure), stats);
+                }
+            }
+        }
+        if let Some(ge) = self.guide_extractor.as_mut() {
+            match ge.extract(records, &mut self.guided, stats.guides.as_mut().unwrap()) {
+                Some(f) => {
+                    records = f;
+                    // the read is replaced by the guide, which is not padded
+                    pads[ge.read() - 1] = &[];
+                }
+                None => {
+                    let failure = ParseFailure {
+                        read: ge.read(),
+                        cause: FailureCause::GuideNotFound,
+                    };
+                    return self.reject(frag, Some(failure), stats);
+                }
+            }
+        }
+
+        if let Some(o) = stats.orientations.as_mut() {
+            o.record(parsed.reversed);
+        }
+        if parsed.rescued {
+            *stats.rescued_fragments.as_mut().unwrap() += 1;
+        }
+        record_lens(&mut stats.padding_usage, [&parsed.lens[0], &parsed.lens[1]]);
+
+        // the stages annotating the fragment
+        let comments = &mut self.comments;
+        comments.iter_mut().for_each(Vec::clear);
+        let ids = match self.id_writer.as_mut() {
+            Some(w) => w.write(frag.ids, index, records),
+            None => frag.ids,
+        };
+        if self.tag_lens {
+            push_lens_tag(&mut comments[0], &parsed.lens[0]);
+            push_lens_tag(&mut comments[1], &parsed.lens[1]);
+        }
+        if inconsistent {
+            annotate(comments, &[b" barcode_mismatch=1"]);
+        }
+        if let Some((filter, mode)) = self.dedup.as_mut() {
+            if filter.check_and_insert(records.s1.as_slice(), records.s2.as_slice()) {
+                *stats.duplicate_fragments.as_mut().unwrap() += 1;
+                match mode {
+                    DedupMode::Drop => return Ok(true),
+                    DedupMode::Flag => annotate(comments, &[b" duplicate=1"]),
+                }
+            }
+        }
+        if let (Some(fo), Some(loc)) = (&opts.features, self.feature_loc) {
+            let counts = stats.feature_counts.as_mut().unwrap();
+            annotate_feature(fo, loc, records, comments, counts);
+        }
+        if let (Some(po), Some(loc)) = (&opts.probes, self.probe_loc) {
+            annotate_probe(po, loc, records, comments, stats.probes.as_mut().unwrap());
+        }
+        if let (Some(so), Some(sl)) = (&opts.spatial, self.spot_locator.as_mut()) {
+            let counts = stats.spatial.as_mut().unwrap();
+            let coords = sl.locate(&so.table, frag.ids[0], records, counts)?;
+            if let (true, Some([x, y])) = (so.tag_headers, coords) {
+                annotate(comments, &[b" x=", x.as_bytes(), b" y=", y.as_bytes()]);
+            }
+        }
+
+        // the stages routing the fragment to its output
+        if let (Some(co), Some(loc)) = (&opts.controls, self.control_loc) {
+            let counts = stats.control_counts.as_mut().unwrap();
+            let control = annotate_control(co, loc, records, comments, counts);
+            if let (true, Some(cw)) = (control, self.control_writer.as_mut()) {
+                let headers = [(ids[0], &comments[0][..]), (ids[1], &comments[1][..])];
+                write_fragment(cw, opts.format, headers, records, pads)?;
+                return Ok(true);
+            }
+        }
+        let sample = match (&opts.demux, self.demultiplexer.as_mut()) {
+            (Some(d), Some(dm)) => dm.assign(d, records, stats.demux.as_mut().unwrap()),
+            _ => None,
+        };
+        if let Some((hook, layout)) = self.record_hook.as_mut() {
+            let [c1, c2] = comments;
+            layout
+                .apply(&mut **hook, ids, [c1, c2], records, &mut self.hooked)
+                .context("the record hook failed")?;
+            // the padding is only known if the reads kept their layout
+            if self.hooked.s1.len() != records.s1.len() {
+                pads[0] = &[];
+            }
+            if self.hooked.s2.len() != records.s2.len() {
+                pads[1] = &[];
+            }
+            records = &self.hooked;
+        }
+        if let Some(bc) = self.barcode_counter.as_mut() {
+            bc.count(records, stats.barcode_counts.as_mut().unwrap());
+        }
+        let headers = [(ids[0], &comments[0][..]), (ids[1], &comments[1][..])];
+        match (
+            self.umi_window.as_mut(),
+            sample.zip(self.demultiplexer.as_mut()),
+        ) {
+            (_, Some((i, dm))) => {
+                write_fragment(dm.writer(i), opts.format, headers, records, pads)?
+            }
+            (Some(w), None) => {
+                w.push(headers, records, pads);
+                if w.is_full() {
+                    *stats.corrected_umis.as_mut().unwrap() += w.flush(writer, opts.format)?;
+                }
+            }
+            (None, None) => match self.sorter.as_mut() {
+                Some(s) => s.push(headers, records, pads)?,
+                None => writer.write(opts.format, headers, records, pads)?,
+            },
+        }
+        if let Some(iw) = self.index_writer.as_mut() {
+            // the index reads are named as the main reads
+            let headers = [(ids[0], &b""[..]), (ids[1], &b""[..])];
+            let pads = [&parsed.index_pads[0][..], &parsed.index_pads[1][..]];
+            write_fragment(iw, opts.format, headers, &parsed.index, pads)?;
+        }
+        Ok(true)
+    }
+
+    /// Counts the fragment `frag` as failing to parse (because of
+    /// `failure`, if it is known), writing it to the outputs of the
+    /// fragments failing to parse.  Returns false.
+    fn reject(
+        &mut self,
+        frag: &BatchFragment<'_>,
+        failure: Option<ParseFailure>,
+        stats: &mut XformStats,
+    ) -> Result<bool> {
+        if let (Some(fc), Some(f)) = (stats.failures.as_mut(), failure) {
+            fc.record(f);
+        }
+        if let Some(uw) = self.unmatched_writer.as_mut() {
+            let (w1, w2) = uw.streams();
+            write_input_record(w1, frag.ids[0], frag.seqs[0], frag.quals[0])?;
+            write_input_record(w2, frag.ids[1], frag.seqs[1], frag.quals[1])?;
+            uw.end_record()?;
+        }
+        Ok(false)
+    }
+
+    /// Writes the fragments still held by the stages to `writer`, and
+    /// completes all of the outputs.
+    pub(crate) fn finish(
+        mut self,
+        mut writer: FragmentWriter,
+        stats: &mut XformStats,
+    ) -> Result<()> {
+        let format = self.opts.format;
+        if let Some(w) = self.umi_window.as_mut() {
+            *stats.corrected_umis.as_mut().unwrap() += w.flush(&mut writer, format)?;
+        }
+        if let Some(s) = self.sorter {
+            let num_chunks =
+                s.finish(|headers, frag, pads| writer.write(format, headers, frag, pads))?;
+            info!(
+                "sorted the output by barcode in {} chunks",
+                num_chunks.max(1)
+            );
+        }
+        writer.finish()?;
+        if let Some(uw) = self.unmatched_writer {
+            uw.finish()?;
+        }
+        if let Some(cw) = self.control_writer {
+            cw.finish()?;
+        }
+        if let Some(dm) = self.demultiplexer {
+            dm.finish()?;
+        }
+        if let Some(sl) = self.spot_locator {
+            sl.finish()?;
+        }
+        if let Some(iw) = self.index_writer {
+            iw.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// The transformed read `read` (`0` for read 1, `1` for read 2) of `records`.
+fn transformed_read(records: &SeqPair, read: usize) -> &[u8] {
+    if read == 0 {
+        &records.s1
+    } else {
+        &records.s2
+    }
+}
+
+/// Appends the concatenation of `parts` to both `comments`.
+fn annotate(comments: &mut [Vec<u8>; 2], parts: &[&[u8]]) {
+    for comment in comments {
+        for part in parts {
+            comment.extend_from_slice(part);
+        }
+    }
+}
+
+/// Annotates the record of the read holding the feature barcode, found in
+/// `records` at the location `(read, offset)`, with the name of its feature,
+/// counting it in `counts`.
+fn annotate_feature(
+    fo: &FeatureOptions,
+    (read, offset): (usize, usize),
+    records: &SeqPair,
+    comments: &mut [Vec<u8>; 2],
+    counts: &mut FeatureCounts,
+) {
+    let found = transformed_read(records, read)
+        .get(offset..)
+        .and_then(|bc| fo.reference.find(bc, fo.max_mismatches));
+    match found {
+        Some(i) => {
+            counts.counts[i] += 1;
+            comments[read].extend_from_slice(b" feature=");
+            comments[read].extend_from_slice(fo.reference.name(i).as_bytes());
+        }
+        None => counts.unmatched += 1,
+    }
+}
+
+/// Annotates both records with the id of the probe found in `records` at
+/// the location `(read, offset)`, counting it in `counts`.
+fn annotate_probe(
+    po: &ProbeOptions,
+    (read, offset): (usize, usize),
+    records: &SeqPair,
+    comments: &mut [Vec<u8>; 2],
+    counts: &mut ProbeCounts,
+) {
+    let found = transformed_read(records, read)
+        .get(offset..)
+        .and_then(|r| po.panel.find(r, po.max_mismatches));
+    match found {
+        Some((i, d)) => {
+            if d == 0 {
+                counts.exact += 1;
+            } else {
+                counts.corrected += 1;
+            }
+            annotate(comments, &[b" probe=", po.panel.id(i).as_bytes()]);
+        }
+        None => counts.unmatched += 1,
+    }
+}
+
+/// Annotates both records with the name of the control sequence found in
+/// `records` at the location `(read, offset)`, if any, counting it in
+/// `counts`.  Returns whether a control sequence was found.
+fn annotate_control(
+    co: &ControlOptions,
+    (read, offset): (usize, usize),
+    records: &SeqPair,
+    comments: &mut [Vec<u8>; 2],
+    counts: &mut ControlCounts,
+) -> bool {
+    let found = transformed_read(records, read)
+        .get(offset..)
+        .and_then(|r| co.reference.find(r));
+    if let Some(i) = found {
+        counts.counts[i] += 1;
+        annotate(comments, &[b" control=", co.reference.name(i).as_bytes()]);
+    }
+    found.is_some()
+}
@@ -0,0 +1,365 @@
+//! Pull-based access to the transformed fragments.
+//!
+//! [`XformRecordIterator`] yields each transformed fragment as a
+//! [`TransformedPair`], so that library users can consume the transformed
+//! records in-process.  [`XformReader`] implements [`std::io::Read`] (and
+//! [`std::io::BufRead`]), yielding the transformed records as interleaved
+//! `FASTA` (the read 1 record of each fragment followed by its read 2 record)
+//! as they are read.  In both cases, input is only consumed as the fragments
+//! are pulled, without fifos, threads or temporary files.
+
+use std::io::{self, BufRead, Read};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use needletail::{FastxReader, Sequence};
+
+use crate::decode::ExternalDecoder;
+use crate::lengths::{ranged_pieces, record_lens};
+use crate::pairing::{PairingChecker, PairingCounts};
+use crate::{
+    check_geometries, open_fastx, push_lens_tag, write_fasta_record, FragmentRegexDesc,
+    InputPairStats, PaddingScheme, SeqPair, XformStats,
+};
+
+/// The pair of input files currently being read.
+struct CurrentPair {
+    reader1: Box<dyn FastxReader>,
+    reader2: Box<dyn FastxReader>,
+    stats: InputPairStats,
+}
+
+/// A fragment transformed into the simplified geometry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformedPair {
+    /// The names of the read 1 and read 2 records of the fragment.
+    pub id1: Vec<u8>,
+    pub id2: Vec<u8>,
+    /// The transformed read 1 and read 2.
+    pub seqs: SeqPair,
+    /// The captured lengths of the variable length pieces of read 1 and of
+    /// read 2, in the order of the pieces.
+    pub lens: [Vec<u32>; 2],
+    /// The index of the geometry (see [`XformRecordIterator::new`]) that
+    /// transformed the fragment.
+    pub geometry: usize,
+}
+
+/// Reads the fragments of pairs of input files, and yields those that match
+/// the geometry transformed into the simplified geometry.  The fragments that
+/// do not match are skipped (and counted in [`XformRecordIterator::stats`]).
+/// Once an error is returned (e.g. the input files could not be opened or
+/// hold malformed records), the iterator should not be used any further.
+pub struct XformRecordIterator {
+    geo_res: Vec<FragmentRegexDesc>,
+    inputs: std::vec::IntoIter<(PathBuf, PathBuf)>,
+    /// The index of the next pair of input files to be opened.
+    next_pair: usize,
+    current: Option<CurrentPair>,
+    stats: XformStats,
+    pairing: PairingChecker,
+}
+
+impl XformRecordIterator {
+    /// Create a new `XformRecordIterator` over the pairs of input files
+    /// (`r1[i]`, `r2[i]`).  As in
+    /// [`crate::xform_read_pairs_to_file_with_geometries`], either a single
+    /// geometry is given in `geo_res`, or one geometry per pair of input
+    /// files.  The input files are only opened once they are reached.
+    pub fn new(geo_res: Vec<FragmentRegexDesc>, r1: &[PathBuf], r2: &[PathBuf]) -> Result<Self> {
+        if r1.len() != r2.len() {
+            bail!(
+                "The number of R1 files ({}) must match the number of R2 files ({})",
+                r1.len(),
+                r2.len()
+            );
+        }
+        check_geometries(&geo_res, r1.len())?;
+        let inputs: Vec<(PathBuf, PathBuf)> = r1.iter().cloned().zip(r2.iter().cloned()).collect();
+        let mut stats = XformStats::new();
+        stats.padding = geo_res[0].padding();
+        stats.padding_usage = ranged_pieces(&geo_res[0]);
+        Ok(Self {
+            geo_res,
+            inputs: inputs.into_iter(),
+            next_pair: 0,
+            current: None,
+            stats,
+            pairing: PairingChecker { strict: false },
+        })
+    }
+
+    /// If `strict` is true, reading fails if the read 1 and read 2 records
+    /// of a fragment have different names, or if one of the input files of a
+    /// pair holds more records than the other, rather than reporting these
+    /// as warnings (see the [`crate::pairing`] module).
+    pub fn with_strict_pairing(mut self, strict: bool) -> Self {
+        self.pairing.strict = strict;
+        self
+    }
+
+    /// The geometries the fragments are transformed with.
+    pub fn geometries(&self) -> &[FragmentRegexDesc] {
+        &self.geo_res
+    }
+
+    /// The statistics of the fragments read so far.  The statistics of a
+    /// pair of input files are only added to [`XformStats::input_pairs`]
+    /// once all of its fragments have been read.
+    pub fn stats(&self) -> &XformStats {
+        &self.stats
+    }
+
+    /// Consumes the reader, returning the statistics of the fragments read.
+    pub fn into_stats(self) -> XformStats {
+        self.stats
+    }
+
+    /// Transforms the next fragment that can be parsed, returning `None`
+    /// once all of the input has been read.
+    fn next_pair(&mut self) -> Result<Option<TransformedPair>> {
+        let mut seqs = SeqPair::new();
+        loop {
+            let cur = match self.current.as_mut() {
+                Some(cur) => cur,
+                None => {
+                    let Some((filename1, filename2)) = self.inputs.next() else {
+                        return Ok(None);
+                    };
+                    let gi = if self.geo_res.len() == 1 {
+                        0
+                    } else {
+                        self.next_pair
+                    };
+                    self.next_pair += 1;
+                    // ORA compressed inputs are decoded with the default decoder
+                    let decoder = ExternalDecoder::default();
+                    let reader1 = open_fastx(&filename1, None, None, &decoder)
+                        .with_context(|| format!("could not open {}", filename1.display()))?;
+                    let reader2 = open_fastx(&filename2, None, None, &decoder)
+                        .with_context(|| format!("could not open {}", filename2.display()))?;
+                    self.current.insert(CurrentPair {
+                        reader1,
+                        reader2,
+                        stats: InputPairStats {
+                            r1: filename1,
+                            r2: filename2,
+                            geometry: gi,
+                            total_fragments: 0,
+                            failed_parsing: 0,
+                            pairing: PairingCounts::default(),
+                        },
+                    })
+                }
+            };
+
+            let (record, record2) = (cur.reader1.next(), cur.reader2.next());
+            if record.is_none() || record2.is_none() {
+                // the records of the longer input are left without a mate
+                let mut first = [record, record2].map(|r| r.map(|r| r.map(|_| ())));
+                let mut pair = self.current.take().unwrap();
+                let readers = [&mut pair.reader1, &mut pair.reader2];
+                for (read, reader) in readers.into_iter().enumerate() {
+                    let Some(rec) = first[read].take() else {
+                        continue;
+                    };
+                    rec?;
+                    pair.stats.pairing.unpaired[read] += 1;
+                    while let Some(rec) = reader.next() {
+                        rec?;
+                        pair.stats.pairing.unpaired[read] += 1;
+                    }
+                }
+                let pair_stats = pair.stats;
+                self.pairing
+                    .report(&pair_stats.r1, &pair_stats.r2, &pair_stats.pairing)?;
+                self.stats.total_fragments += pair_stats.total_fragments;
+                self.stats.failed_parsing += pair_stats.failed_parsing;
+                self.stats.pairing.add(&pair_stats.pairing);
+                self.stats.input_pairs.push(pair_stats);
+                continue;
+            }
+            let seqrec = record.unwrap()?;
+            let seqrec2 = record2.unwrap()?;
+            self.pairing.check_names(
+                cur.stats.total_fragments,
+                seqrec.id(),
+                seqrec2.id(),
+                &mut cur.stats.pairing,
+            )?;
+            cur.stats.total_fragments += 1;
+            let geo_re = &mut self.geo_res[cur.stats.geometry];
+            if !geo_re.parse_into(seqrec.sequence(), seqrec2.sequence(), &mut seqs)
+                || !geo_re.parse_name_into(seqrec.id(), &mut seqs)
+            {
+                cur.stats.failed_parsing += 1;
+                continue;
+            }
+            record_lens(&mut self.stats.padding_usage, geo_re.last_lens());
+            let [lens1, lens2] = geo_re.last_lens();
+            return Ok(Some(TransformedPair {
+                id1: seqrec.id().to_vec(),
+                id2: seqrec2.id().to_vec(),
+                seqs,
+                lens: [lens1.to_vec(), lens2.to_vec()],
+                geometry: cur.stats.geometry,
+            }));
+        }
+    }
+}
+
+impl Iterator for XformRecordIterator {
+    type Item = Result<TransformedPair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pair().transpose()
+    }
+}
+
+/// Reads the fragments of pairs of input files, and yields them transformed
+/// into the simplified geometry, as interleaved `FASTA` records.  If the
+/// geometry discards all of one of the reads (e.g. `2{x:}`), the records of
+/// that read are left out, so that the output holds a single record per
+/// fragment.
+pub struct XformReader {
+    records: XformRecordIterator,
+    write1: bool,
+    write2: bool,
+    /// The records of the current fragment, of which `buf[pos..]` remain
+    /// to be read.
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl XformReader {
+    /// Create a new `XformReader` over the pairs of input files (`r1[i]`,
+    /// `r2[i]`), with the geometries `geo_res` (see
+    /// [`XformRecordIterator::new`]).
+    pub fn new(geo_res: Vec<FragmentRegexDesc>, r1: &[PathBuf], r2: &[PathBuf]) -> Result<Self> {
+        let records = XformRecordIterator::new(geo_res, r1, r2)?;
+        let geo_re = &records.geometries()[0];
+        Ok(Self {
+            write1: !geo_re.discards_read1(),
+            write2: !geo_re.discards_read2(),
+            records,
+            buf: vec![],
+            pos: 0,
+        })
+    }
+
+    /// If `strict` is true, reading fails if the inputs are not paired (see
+    /// [`XformRecordIterator::with_strict_pairing`]).
+    pub fn with_strict_pairing(mut self, strict: bool) -> Self {
+        self.records = self.records.with_strict_pairing(strict);
+        self
+    }
+
+    /// The statistics of the fragments read so far.  The statistics of a
+    /// pair of input files are only added to [`XformStats::input_pairs`]
+    /// once all of its fragments have been read.
+    pub fn stats(&self) -> &XformStats {
+        self.records.stats()
+    }
+
+    /// Consumes the reader, returning the statistics of the fragments read.
+    pub fn into_stats(self) -> XformStats {
+        self.records.into_stats()
+    }
+
+    /// Writes the records of the next fragment that can be parsed into
+    /// `self.buf`, returning false once all of the input has been read.
+    fn next_fragment(&mut self) -> Result<bool> {
+        let Some(pair) = self.records.next_pair()? else {
+            return Ok(false);
+        };
+        // with padding to the maximum length, the captured lengths are
+        // recorded in the headers
+        let (mut comment1, mut comment2) = (Vec::new(), Vec::new());
+        if self.records.geo_res[pair.geometry].padding() == PaddingScheme::MaxLen {
+            push_lens_tag(&mut comment1, &pair.lens[0]);
+            push_lens_tag(&mut comment2, &pair.lens[1]);
+        }
+        self.buf.clear();
+        self.pos = 0;
+        if self.write1 {
+            write_fasta_record(&mut self.buf, &pair.id1, &comment1, pair.seqs.s1.as_slice())?;
+        }
+        if self.write2 {
+            write_fasta_record(&mut self.buf, &pair.id2, &comment2, pair.seqs.s2.as_slice())?;
+        }
+        Ok(true)
+    }
+}
+
+impl BufRead for XformReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            self.next_fragment().map_err(io::Error::other)?;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
+}
+
+impl Read for XformReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let avail = self.fill_buf()?;
+        let n = avail.len().min(out.len());
+        out[..n].copy_from_slice(&avail[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn reader_yields_interleaved_records() {
+        let dir = tempdir().unwrap();
+        let (p1, p2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(&p1, ">a\nACGTACGTTT\n>b\nACG\n>c\nTTTTACGTGG\n").unwrap();
+        std::fs::write(&p2, ">a\nCCCC\n>b\nGGGG\n>c\nAAAA\n").unwrap();
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut reader = XformReader::new(
+            vec![geo_re],
+            std::slice::from_ref(&p1),
+            std::slice::from_ref(&p2),
+        )
+        .unwrap();
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, ">a\nACGTACGT\n>a\nCCCC\n>c\nTTTTACGT\n>c\nAAAA\n");
+        let stats = reader.into_stats();
+        assert_eq!(stats.total_fragments, 3);
+        assert_eq!(stats.failed_parsing, 1);
+        assert_eq!(stats.input_pairs.len(), 1);
+
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2-4]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut records = XformRecordIterator::new(vec![geo_re], &[p1], &[p2]).unwrap();
+        let pairs: Vec<TransformedPair> = records.by_ref().collect::<Result<_>>().unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].id1, b"a");
+        assert_eq!(pairs[1].seqs.s2, b"AAAA");
+        assert_eq!(pairs[1].lens, [vec![4], vec![]]);
+        // the captured lengths are tallied
+        let usage = &records.stats().padding_usage;
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].lengths.get(&4), Some(&2));
+    }
+}
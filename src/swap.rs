@@ -0,0 +1,182 @@
+//! Detection of swapped read 1 and read 2 inputs.
+//!
+//! Giving the read 2 files as read 1 (and vice versa) is the most common
+//! mistake made when transforming reads: the fragments then mostly fail to
+//! parse, or worse, parse into meaningless barcodes when the geometry has no
+//! anchor to reject them.  [`detect_swap`] matches the first fragments of the
+//! inputs against the geometry both as given and with their reads
+//! exchanged, and [`SwapReport::is_swapped`] tells whether the geometry fits
+//! the exchanged reads far better.  A geometry that matches any read (e.g.
+//! `1{b[16]u[12]x:}2{r:}`, given reads of at least 28 bases) fits both
+//! orders equally well, so that swapped inputs can only be detected through
+//! its anchors and fixed lengths.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::decode::ExternalDecoder;
+use crate::malformed::OnError;
+use crate::{input_groups, read_input_group, FragmentRegexDesc, InputFormat, SeqPair};
+
+/// How many times as many fragments must match the geometry with their
+/// reads exchanged for the inputs to look swapped.
+pub const SWAP_RATIO: u64 = 4;
+/// The smallest fraction of the fragments that must match the geometry with
+/// their reads exchanged for the inputs to look swapped.
+pub const MIN_SWAPPED_RATE: f64 = 0.25;
+
+/// The number of fragments matching the geometry with their reads as given
+/// and exchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwapReport {
+    /// The number of fragments examined.
+    pub fragments: u64,
+    /// The number of fragments matching the geometry as given.
+    pub matched: u64,
+    /// The number of fragments matching the geometry with their read 1 and
+    /// read 2 exchanged.
+    pub matched_swapped: u64,
+}
+
+impl SwapReport {
+    /// The fraction of the fragments matching the geometry as given.
+    pub fn match_rate(&self) -> f64 {
+        self.matched as f64 / self.fragments.max(1) as f64
+    }
+
+    /// The fraction of the fragments matching the geometry with their reads
+    /// exchanged.
+    pub fn swapped_match_rate(&self) -> f64 {
+        self.matched_swapped as f64 / self.fragments.max(1) as f64
+    }
+
+    /// Whether the inputs look swapped: at least [`MIN_SWAPPED_RATE`] of the
+    /// fragments match the geometry with their reads exchanged, and at least
+    /// [`SWAP_RATIO`] times as many as match it as given.
+    pub fn is_swapped(&self) -> bool {
+        self.swapped_match_rate() >= MIN_SWAPPED_RATE
+            && self.matched_swapped >= SWAP_RATIO * self.matched
+    }
+}
+
+/// Matches the first `max_fragments` fragments of the input files `r1` and
+/// `r2` against `geo_re`, with their reads as given and exchanged (see the
+/// module documentation).  The read 1 inputs are in the format
+/// `input_format`, and the ORA compressed input files are decoded by running
+/// `ora_decoder` on them.  Unless `on_error` is [`OnError::Abort`], the
+/// fragments holding a malformed record are left out.
+pub fn detect_swap(
+    geo_re: &mut FragmentRegexDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    max_fragments: u64,
+    on_error: OnError,
+    input_format: InputFormat,
+    ora_decoder: &ExternalDecoder,
+) -> Result<SwapReport> {
+    let mut report = SwapReport::default();
+    let mut parsed = SeqPair::new();
+    'files: for (files1, files2) in input_groups(r1, r2, input_format)? {
+        let mut batches = read_input_group(files1, files2, None, None, on_error, ora_decoder)?;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                if report.fragments >= max_fragments {
+                    break 'files;
+                }
+                report.fragments += 1;
+                if geo_re.parse_into(frag.seqs[0], frag.seqs[1], &mut parsed) {
+                    report.matched += 1;
+                }
+                if geo_re.parse_into(frag.seqs[1], frag.seqs[0], &mut parsed) {
+                    report.matched_swapped += 1;
+                }
+            }
+            batches.recycle(batch);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn swapped_inputs_are_detected() {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[4]f[ACGT]u[4]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (r1, r2) = (dir.path().join("r1.fa"), dir.path().join("r2.fa"));
+        std::fs::write(
+            &r1,
+            ">a\nAAAAACGTCCCC\n>b\nTTTTACGTGGGG\n>c\nTTTTTTTTTTTT\n>d\nCCCCACGTAAAA\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &r2,
+            ">a\nGGGGGGGGGGGG\n>b\nCCC\n>c\nAAAA\n>d\nGATTACAGATTA\n",
+        )
+        .unwrap();
+        let decoder = ExternalDecoder::default();
+
+        let (r1, r2) = (vec![r1], vec![r2]);
+        let report = detect_swap(
+            &mut geo_re,
+            &r1,
+            &r2,
+            10,
+            OnError::Abort,
+            InputFormat::default(),
+            &decoder,
+        )
+        .unwrap();
+        assert_eq!(
+            report,
+            SwapReport {
+                fragments: 4,
+                matched: 3,
+                matched_swapped: 0
+            }
+        );
+        assert!(!report.is_swapped());
+
+        let report = detect_swap(
+            &mut geo_re,
+            &r2,
+            &r1,
+            10,
+            OnError::Abort,
+            InputFormat::default(),
+            &decoder,
+        )
+        .unwrap();
+        assert_eq!((report.matched, report.matched_swapped), (0, 3));
+        assert!(report.is_swapped());
+        // only the first fragments are examined
+        let report = detect_swap(
+            &mut geo_re,
+            &r2,
+            &r1,
+            2,
+            OnError::Abort,
+            InputFormat::default(),
+            &decoder,
+        )
+        .unwrap();
+        assert_eq!((report.fragments, report.matched_swapped), (2, 2));
+
+        // as often swapped as not
+        let report = SwapReport {
+            fragments: 100,
+            matched: 40,
+            matched_swapped: 50,
+        };
+        assert!(!report.is_swapped());
+    }
+}
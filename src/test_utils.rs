@@ -0,0 +1,196 @@
+//! [`proptest`] strategies for generating fragment geometries and reads
+//! that conform to them, and [`xform_in_dir`], which runs a whole
+//! transformation on a few read pairs.  These are used for the tests of this
+//! crate, and are exposed (behind the `test_utils` feature) so that
+//! downstream crates can reuse them in their own tests.
+
+use std::path::Path;
+
+use anyhow::Result;
+use proptest::prelude::*;
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece, NucStr};
+
+use crate::{
+    xform_read_pairs_to_file_with_options, FragmentRegexDesc, PaddingScheme, XformOptions,
+    XformStats,
+};
+
+/// A strategy producing the textual description of a bounded
+/// (i.e. not unbounded) geometry piece, such as `b[16]`, `u[8-10]`
+/// or `f[CAGAGC]`.
+pub fn arb_bounded_piece() -> impl Strategy<Value = String> {
+    let kind = prop::sample::select(vec!['x', 'b', 'u', 'r']);
+    prop_oneof![
+        (kind.clone(), 1u32..=20).prop_map(|(k, x)| format!("{}[{}]", k, x)),
+        // ranged pieces, some of which take a padding suffix of 2 bases
+        (kind, 1u32..=12, 1u32..=7).prop_map(|(k, l, w)| format!("{}[{}-{}]", k, l, l + w)),
+        "[ACGT]{1,8}".prop_map(|s| format!("f[{}]", s)),
+    ]
+}
+
+/// A strategy producing the textual description of the pieces of a
+/// single read: one or more bounded pieces, optionally followed by an
+/// unbounded piece.
+pub fn arb_read_desc() -> impl Strategy<Value = String> {
+    (
+        prop::collection::vec(arb_bounded_piece(), 1..6),
+        prop::option::of(prop::sample::select(vec!["x:", "b:", "u:", "r:"])),
+    )
+        .prop_map(|(pieces, unbounded)| {
+            let mut s = pieces.concat();
+            if let Some(u) = unbounded {
+                s.push_str(u);
+            }
+            s
+        })
+}
+
+/// A strategy producing the textual description of a complete fragment
+/// geometry, e.g. `1{b[9-10]f[CAGAGC]u[8]}2{r:}`.
+pub fn arb_geometry() -> impl Strategy<Value = String> {
+    (arb_read_desc(), arb_read_desc()).prop_map(|(r1, r2)| format!("1{{{}}}2{{{}}}", r1, r2))
+}
+
+/// A strategy producing a uniformly random nucleotide sequence
+/// whose length lies in `len`.
+pub fn arb_nucs(len: std::ops::RangeInclusive<usize>) -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(prop::sample::select(b"ACGT".to_vec()), len)
+}
+
+/// A strategy producing reads that conform to the geometry pieces
+/// `pieces`.  Unbounded pieces are given lengths of up to
+/// `max_unbounded` bases.
+pub fn arb_conforming_read(
+    pieces: &[GeomPiece],
+    max_unbounded: usize,
+) -> impl Strategy<Value = Vec<u8>> {
+    let parts: Vec<BoxedStrategy<Vec<u8>>> = pieces
+        .iter()
+        .map(|gp| match gp {
+            GeomPiece::Discard(gl)
+            | GeomPiece::Barcode(gl)
+            | GeomPiece::Umi(gl)
+            | GeomPiece::ReadSeq(gl) => {
+                let len = match gl {
+                    GeomLen::FixedLen(x) => (*x as usize)..=(*x as usize),
+                    GeomLen::LenRange(l, h) => (*l as usize)..=(*h as usize),
+                    GeomLen::Unbounded => 0..=max_unbounded,
+                };
+                arb_nucs(len).boxed()
+            }
+            GeomPiece::Fixed(NucStr::Seq(s)) => Just(s.as_bytes().to_vec()).boxed(),
+        })
+        .collect();
+    parts.prop_map(|p| p.concat())
+}
+
+/// A strategy producing a geometry (as in [`arb_geometry`]) along with
+/// a read pair that conforms to it.
+pub fn arb_geometry_with_reads() -> impl Strategy<Value = (String, Vec<u8>, Vec<u8>)> {
+    arb_geometry().prop_flat_map(|gd| {
+        let geo = FragmentGeomDesc::try_from(gd.as_str()).expect("generated a valid geometry");
+        (
+            Just(gd),
+            arb_conforming_read(&geo.read1_desc, 100),
+            arb_conforming_read(&geo.read2_desc, 100),
+        )
+    })
+}
+
+/// The length of the read produced when transforming a read with the
+/// (simplified) geometry pieces `pieces`, or `None` if this length is
+/// not fixed (i.e. if the read contains an unbounded captured piece).
+pub fn transformed_read_len(pieces: &[GeomPiece]) -> Option<usize> {
+    let mut len = 0;
+    for gp in pieces {
+        match gp {
+            GeomPiece::Barcode(GeomLen::FixedLen(x))
+            | GeomPiece::Umi(GeomLen::FixedLen(x))
+            | GeomPiece::ReadSeq(GeomLen::FixedLen(x)) => len += *x as usize,
+            GeomPiece::Barcode(GeomLen::LenRange(l, h))
+            | GeomPiece::Umi(GeomLen::LenRange(l, h))
+            | GeomPiece::ReadSeq(GeomLen::LenRange(l, h)) => {
+                len += PaddingScheme::Distinct.padded_len(*l, *h) as usize
+            }
+            GeomPiece::Barcode(GeomLen::Unbounded)
+            | GeomPiece::Umi(GeomLen::Unbounded)
+            | GeomPiece::ReadSeq(GeomLen::Unbounded) => return None,
+            GeomPiece::Discard(_) | GeomPiece::Fixed(_) => {}
+        }
+    }
+    Some(len)
+}
+
+/// The outputs of a transformation run by [`xform_in_dir`].
+#[derive(Debug)]
+pub struct XformOutputs {
+    /// The read 1 output, or an empty string if it was not written.
+    pub out1: String,
+    /// The read 2 output, or an empty string if it was not written.
+    pub out2: String,
+    /// The statistics of the transformation.
+    pub stats: XformStats,
+}
+
+/// Writes the read 1 and read 2 records `r1` and `r2` (as `FASTA` or `FASTQ`
+/// text) to the files `r1.fa` and `r2.fa` of the directory `dir`, transforms
+/// them with the geometry `geo_re` and the options `opts` into the files
+/// `o1.fa` and `o2.fa` of `dir`, and returns both outputs along with the
+/// statistics.  The other files of the transformation (e.g. those of
+/// [`XformOptions::index_reads`]) may be given in `dir` as well.
+pub fn xform_in_dir(
+    dir: &Path,
+    geo_re: FragmentRegexDesc,
+    r1: &str,
+    r2: &str,
+    opts: &XformOptions,
+) -> Result<XformOutputs> {
+    let p = |name: &str| dir.join(name);
+    std::fs::write(p("r1.fa"), r1)?;
+    std::fs::write(p("r2.fa"), r2)?;
+    let stats = xform_read_pairs_to_file_with_options(
+        geo_re,
+        &[p("r1.fa")],
+        &[p("r2.fa")],
+        Some(p("o1.fa")),
+        Some(p("o2.fa")),
+        opts,
+    )?;
+    Ok(XformOutputs {
+        out1: std::fs::read_to_string(p("o1.fa")).unwrap_or_default(),
+        out2: std::fs::read_to_string(p("o2.fa")).unwrap_or_default(),
+        stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FragmentGeomDescExt, SeqPair};
+
+    proptest! {
+        #[test]
+        fn conforming_reads_parse((gd, r1, r2) in arb_geometry_with_reads()) {
+            let geo = FragmentGeomDesc::try_from(gd.as_str()).unwrap();
+            let mut geo_re = geo.as_regex().unwrap();
+            let mut sp = SeqPair::new();
+            prop_assert!(geo_re.parse_into(&r1, &r2, &mut sp));
+
+            let simplified = geo_re.get_simplified_geo_desc();
+            for (s, pieces) in [(&sp.s1, &simplified.read1_desc), (&sp.s2, &simplified.read2_desc)] {
+                prop_assert!(s.iter().all(|c| b"ACGTN".contains(c)));
+                if let Some(l) = transformed_read_len(pieces) {
+                    prop_assert_eq!(s.len(), l);
+                }
+            }
+        }
+
+        #[test]
+        fn arbitrary_reads_do_not_panic(gd in arb_geometry(), r1 in arb_nucs(0..=80), r2 in arb_nucs(0..=80)) {
+            let geo = FragmentGeomDesc::try_from(gd.as_str()).unwrap();
+            let mut geo_re = geo.as_regex().unwrap();
+            let mut sp = SeqPair::new();
+            geo_re.parse_into(&r1, &r2, &mut sp);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! On-the-fly correction of UMI sequencing errors.
+//!
+//! The transformed fragments are buffered in windows of a fixed number of
+//! fragments.  Within each window, the UMIs observed with each barcode are
+//! clustered with the "directional" method of UMI-tools: a UMI `a` absorbs a
+//! UMI `b` at Hamming distance 1 if `count(a) >= 2 * count(b) - 1`, and every
+//! UMI of a cluster is rewritten to the most abundant UMI of the cluster.
+//! Because the clustering is restricted to a window, errors are only
+//! corrected when the erroneous UMI is observed close (in the input) to the
+//! UMI it derives from, which is typically the case for reads that are sorted
+//! or grouped by cell, and the memory used is bounded by the window size.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use seq_geom_parser::GeomPiece;
+
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// Options controlling the correction of UMIs.
+#[derive(Debug, Clone)]
+pub struct UmiOptions {
+    /// The number of fragments within which UMIs are clustered.
+    pub window: usize,
+}
+
+impl UmiOptions {
+    /// The default number of fragments within which UMIs are clustered.
+    pub const DEFAULT_WINDOW: usize = 100_000;
+}
+
+impl Default for UmiOptions {
+    fn default() -> Self {
+        Self {
+            window: Self::DEFAULT_WINDOW,
+        }
+    }
+}
+
+/// Clusters and corrects the UMIs of windows of transformed fragments.
+#[derive(Debug, Clone)]
+pub struct UmiCorrector {
+    barcodes: Vec<PieceSpan>,
+    umis: Vec<PieceSpan>,
+}
+
+impl UmiCorrector {
+    /// Create a new `UmiCorrector` for the fragments transformed by `geo_re`.
+    /// All of the barcode and UMI pieces of the geometry must be preceded
+    /// only by bounded pieces, so that they lie at fixed offsets in the
+    /// transformed fragments.
+    pub fn new(geo_re: &FragmentRegexDesc) -> Result<Self> {
+        let spans = |select: fn(&GeomPiece) -> bool| {
+            geo_re.piece_spans(select).map_err(|gp| {
+                anyhow!(
+                    "correcting UMIs requires the barcode and UMI pieces of the geometry \
+                     to be of bounded length and preceded only by bounded pieces, but \
+                     found {:?}",
+                    gp
+                )
+            })
+        };
+        let barcodes = spans(|gp| matches!(gp, GeomPiece::Barcode(_)))?;
+        let umis = spans(|gp| matches!(gp, GeomPiece::Umi(_)))?;
+        if umis.is_empty() {
+            bail!("correcting UMIs requires the geometry to have a UMI (u) piece");
+        }
+        Ok(Self { barcodes, umis })
+    }
+
+    /// Corrects the UMIs of the window of fragments `frags` in place,
+    /// returning the number of fragments whose UMI was changed.
+    pub fn correct(&self, frags: &mut [SeqPair]) -> u64 {
+        // the number of occurrences of each UMI, by barcode
+        let mut counts: HashMap<Vec<u8>, HashMap<Vec<u8>, u64>> = HashMap::new();
+        let (mut bc, mut umi) = (Vec::new(), Vec::new());
+        for f in frags.iter() {
+            extract_spans(f, &self.barcodes, &mut bc);
+            extract_spans(f, &self.umis, &mut umi);
+            *counts
+                .entry(bc.clone())
+                .or_default()
+                .entry(umi.clone())
+                .or_insert(0) += 1;
+        }
+        let corrections: HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>> = counts
+            .into_iter()
+            .map(|(bc, umi_counts)| (bc, directional_clusters(&umi_counts)))
+            .filter(|(_, c)| !c.is_empty())
+            .collect();
+
+        let mut corrected = 0;
+        for f in frags.iter_mut() {
+            extract_spans(f, &self.barcodes, &mut bc);
+            let Some(c) = corrections.get(&bc) else {
+                continue;
+            };
+            extract_spans(f, &self.umis, &mut umi);
+            let Some(new_umi) = c.get(&umi) else {
+                continue;
+            };
+            let mut pos = 0;
+            for &(read, start, len) in &self.umis {
+                let s = if read == 0 { &mut f.s1 } else { &mut f.s2 };
+                s[start..start + len].copy_from_slice(&new_umi[pos..pos + len]);
+                pos += len;
+            }
+            corrected += 1;
+        }
+        corrected
+    }
+}
+
+/// Clusters the UMIs in `counts` with the directional method, returning the
+/// UMIs that should be rewritten, mapped to their corrected sequence.
+fn directional_clusters(counts: &HashMap<Vec<u8>, u64>) -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut corrections = HashMap::new();
+    if counts.len() < 2 {
+        return corrections;
+    }
+    // visit the UMIs from the most to the least abundant (breaking ties by
+    // sequence, so that the result does not depend on the hashing order)
+    let mut order: Vec<(&Vec<u8>, u64)> = counts.iter().map(|(u, c)| (u, *c)).collect();
+    order.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut assigned: HashSet<&[u8]> = HashSet::with_capacity(counts.len());
+    let mut stack = vec![];
+    let mut neighbor = Vec::new();
+    for (root, count) in &order {
+        if !assigned.insert(root.as_slice()) {
+            continue;
+        }
+        stack.push((root.as_slice(), *count));
+        while let Some((u, cu)) = stack.pop() {
+            // every UMI at Hamming distance 1 from u
+            for i in 0..u.len() {
+                for &b in b"ACGTN" {
+                    if b == u[i] {
+                        continue;
+                    }
+                    neighbor.clear();
+                    neighbor.extend_from_slice(u);
+                    neighbor[i] = b;
+                    let Some((v, &cv)) = counts.get_key_value(neighbor.as_slice()) else {
+                        continue;
+                    };
+                    if cu + 1 >= 2 * cv && assigned.insert(v.as_slice()) {
+                        corrections.insert(v.clone(), (*root).clone());
+                        stack.push((v.as_slice(), cv));
+                    }
+                }
+            }
+        }
+    }
+    corrections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FragmentGeomDescExt;
+    use seq_geom_parser::FragmentGeomDesc;
+
+    #[test]
+    fn directional_correction() {
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[6]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let corrector = UmiCorrector::new(&geo_re).unwrap();
+        let mut frags: Vec<SeqPair> = [
+            "AAAACCCCCC",
+            "AAAACCCCCC",
+            "AAAACCCCCC",
+            // one edit from the abundant UMI: corrected
+            "AAAACCCCCA",
+            // one edit from the abundant UMI, but with another barcode
+            "TTTTCCCCCA",
+            // one edit from the corrected UMI: corrected through it
+            "AAAACCCCAA",
+            // one edit from each other, but equally abundant
+            "AAAAGGGGGG",
+            "AAAAGGGGGG",
+            "AAAAGGGGGT",
+            "AAAAGGGGGT",
+        ]
+        .iter()
+        .map(|s1| SeqPair {
+            s1: s1.as_bytes().to_vec(),
+            s2: b"ACGT".to_vec(),
+        })
+        .collect();
+        assert_eq!(corrector.correct(&mut frags), 2);
+        assert_eq!(frags[3].s1, b"AAAACCCCCC");
+        assert_eq!(frags[4].s1, b"TTTTCCCCCA");
+        assert_eq!(frags[5].s1, b"AAAACCCCCC");
+        assert_eq!(frags[6].s1, b"AAAAGGGGGG");
+        assert_eq!(frags[8].s1, b"AAAAGGGGGT");
+    }
+}
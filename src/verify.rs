@@ -0,0 +1,577 @@
+//! Auditing a transformation from its inputs and outputs.
+//!
+//! [`verify_transformation`] checks the outputs of a transformation against
+//! its inputs, without transforming the reads again.  The output records
+//! must follow the order of the input fragments they were transformed from,
+//! and be named after them (so the outputs cannot have been sorted by
+//! barcode, nor renamed with [`crate::XformOptions::id_format`]).  Each
+//! output record is matched to the input fragment of the same name, and
+//! its read is cut into the captured pieces of the geometry, in accordance
+//! with the simplified geometry.  The transformation passes if:
+//!
+//! - the read 1 and read 2 outputs hold the same number of records, with
+//!   the same names, each of which is the name of an input fragment;
+//! - each transformed read has the length of the simplified geometry;
+//! - the padding of each variable length piece is that of its padding
+//!   scheme, for a captured length within the range of the piece (with
+//!   [`PaddingScheme::MaxLen`], the captured lengths are read from the
+//!   `lens=` tag of the records);
+//! - the captured pieces are found in the input read, in order, either as
+//!   they are or all of them reverse complemented (for the reads matched in
+//!   the reverse orientation).
+//!
+//! The pieces whose bases are changed by the transformation (e.g. corrected
+//! barcodes) are found by allowing mismatches (see
+//! [`VerifyOptions::max_mismatches`]), but masked pieces and the pieces
+//! captured from the read names are not found in the reads.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use needletail::FastxReader;
+use seq_geom_parser::{GeomLen, GeomPiece};
+
+use crate::decode::ExternalDecoder;
+use crate::malformed::OnError;
+use crate::{
+    input_groups, open_fastx, read_input_group, reverse_complement_into, FragmentRegexDesc,
+    InputFormat, PaddingScheme, LEN_CODE_DIGITS,
+};
+
+/// The number of failures described in a [`VerifyReport`]; the others are
+/// only counted.
+pub const MAX_REPORTED_FAILURES: usize = 10;
+
+/// Options of [`verify_transformation`].
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// The number of mismatches allowed between each captured piece and
+    /// the input read in which it is found.
+    pub max_mismatches: u32,
+    /// As [`crate::XformOptions::input_format`].
+    pub input_format: InputFormat,
+    /// The decoder run on the ORA compressed input files.
+    pub ora_decoder: ExternalDecoder,
+}
+
+/// An output record failing verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyFailure {
+    /// The (1-based) position of the record in the outputs.
+    pub record: u64,
+    /// The name of the record.
+    pub name: String,
+    /// Why the record fails verification.
+    pub reason: String,
+}
+
+impl fmt::Display for VerifyFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "record {} ({}): {}", self.record, self.name, self.reason)
+    }
+}
+
+/// The outcome of [`verify_transformation`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of input fragments read.
+    pub input_fragments: u64,
+    /// The number of output records (i.e. of transformed fragments).
+    pub output_records: u64,
+    /// The number of output records failing verification.
+    pub failed_records: u64,
+    /// The first [`MAX_REPORTED_FAILURES`] failures.
+    pub failures: Vec<VerifyFailure>,
+}
+
+impl VerifyReport {
+    /// Whether all of the output records pass verification.
+    pub fn passed(&self) -> bool {
+        self.failed_records == 0
+    }
+
+    fn fail(&mut self, record: u64, name: &[u8], reason: String) {
+        self.failed_records += 1;
+        if self.failures.len() < MAX_REPORTED_FAILURES {
+            self.failures.push(VerifyFailure {
+                record,
+                name: String::from_utf8_lossy(name).into_owned(),
+                reason,
+            });
+        }
+    }
+}
+
+/// A record of the read 1 and read 2 outputs, either of which may be
+/// omitted.
+struct OutputRecord {
+    headers: [Vec<u8>; 2],
+    seqs: [Option<Vec<u8>>; 2],
+}
+
+/// Returns the name of a record, i.e. its header up to the first whitespace.
+fn record_name(header: &[u8]) -> &[u8] {
+    let end = header
+        .iter()
+        .position(u8::is_ascii_whitespace)
+        .unwrap_or(header.len());
+    &header[..end]
+}
+
+/// Reads the next record of each of the `outputs`.
+fn next_output(
+    outputs: &mut [Option<Box<dyn FastxReader>>; 2],
+    record: u64,
+) -> Result<Option<OutputRecord>> {
+    let mut rec = OutputRecord {
+        headers: [vec![], vec![]],
+        seqs: [None, None],
+    };
+    let mut ended = [false; 2];
+    for (i, out) in outputs.iter_mut().enumerate() {
+        let Some(reader) = out else {
+            ended[i] = true;
+            continue;
+        };
+        match reader.next() {
+            Some(r) => {
+                let r = r?;
+                rec.headers[i] = r.id().to_vec();
+                rec.seqs[i] = Some(r.seq().into_owned());
+            }
+            None => ended[i] = true,
+        }
+    }
+    let both = outputs.iter().all(|o| o.is_some());
+    if ended == [true, true] {
+        return Ok(None);
+    }
+    if both && ended[0] != ended[1] {
+        let (short, long) = if ended[0] { (1, 2) } else { (2, 1) };
+        bail!(
+            "the read {} output ends at record {}, before the read {} output",
+            short,
+            record,
+            long
+        );
+    }
+    if both && record_name(&rec.headers[0]) != record_name(&rec.headers[1]) {
+        bail!(
+            "the read 1 and read 2 records {} are named {} and {}",
+            record,
+            String::from_utf8_lossy(&rec.headers[0]),
+            String::from_utf8_lossy(&rec.headers[1])
+        );
+    }
+    if outputs[0].is_none() {
+        rec.headers[0] = rec.headers[1].clone();
+    }
+    Ok(Some(rec))
+}
+
+/// Returns the captured lengths recorded in the `lens=` tag of `header`.
+fn lens_tag(header: &[u8]) -> Option<Vec<u32>> {
+    let header = std::str::from_utf8(header).ok()?;
+    let tag = header
+        .split_ascii_whitespace()
+        .find_map(|t| t.strip_prefix("lens="))?;
+    tag.split(',').map(|l| l.parse().ok()).collect()
+}
+
+/// Returns the piece captured with the length range `[l, h]` from the start
+/// of `out`, which is padded with `padding`, and the length of the padded
+/// piece.  `lens` holds the captured lengths of the variable length pieces
+/// remaining, with [`PaddingScheme::MaxLen`].
+fn unpad<'a>(
+    out: &'a [u8],
+    (l, h): (u32, u32),
+    padding: PaddingScheme,
+    lens: &mut dyn Iterator<Item = u32>,
+) -> std::result::Result<(&'a [u8], usize), String> {
+    let padded = padding.padded_len(l, h) as usize;
+    let Some(piece) = out.get(..padded) else {
+        return Err(String::from(
+            "the read is shorter than the simplified geometry",
+        ));
+    };
+    let len = match padding {
+        PaddingScheme::Distinct => {
+            let mut i = 0;
+            for c in &piece[h as usize..] {
+                match LEN_CODE_DIGITS.iter().position(|d| d == c) {
+                    Some(d) => i = 4 * i + d as u32,
+                    None => return Err(format!("invalid length code in padding {}", c)),
+                }
+            }
+            h.checked_sub(i)
+                .ok_or_else(|| String::from("invalid length code in padding"))?
+        }
+        PaddingScheme::MaxLen => lens.next().ok_or_else(|| {
+            String::from("the lens= tag does not list every variable length piece")
+        })?,
+    };
+    if len < l || len > h {
+        return Err(format!(
+            "captured length {} out of the range [{}-{}] of a piece",
+            len, l, h
+        ));
+    }
+    if piece[len as usize..h as usize].iter().any(|&c| c != b'A') {
+        return Err(String::from("padding of a piece that is not made of As"));
+    }
+    Ok((&piece[..len as usize], padded))
+}
+
+/// Cuts the transformed read `out` into the captured pieces `cginfo`,
+/// checking their padding, whose captured lengths are listed in `lens` with
+/// [`PaddingScheme::MaxLen`].
+fn cut_pieces<'a>(
+    out: &'a [u8],
+    cginfo: &[GeomPiece],
+    padding: PaddingScheme,
+    lens: Option<Vec<u32>>,
+) -> std::result::Result<Vec<&'a [u8]>, String> {
+    if padding == PaddingScheme::MaxLen
+        && lens.is_none()
+        && cginfo.iter().any(|gp| {
+            matches!(
+                gp,
+                GeomPiece::Barcode(GeomLen::LenRange(..))
+                    | GeomPiece::Umi(GeomLen::LenRange(..))
+                    | GeomPiece::ReadSeq(GeomLen::LenRange(..))
+            )
+        })
+    {
+        return Err(String::from("no lens= tag records the captured lengths"));
+    }
+    let mut lens = lens.unwrap_or_default().into_iter();
+    let mut pieces = vec![];
+    let mut rest = out;
+    for gp in cginfo {
+        let gl = match gp {
+            GeomPiece::Barcode(gl) | GeomPiece::Umi(gl) | GeomPiece::ReadSeq(gl) => gl,
+            _ => continue,
+        };
+        let (piece, len) = match gl {
+            GeomLen::FixedLen(x) => match rest.get(..*x as usize) {
+                Some(p) => (p, *x as usize),
+                None => {
+                    return Err(String::from(
+                        "the read is shorter than the simplified geometry",
+                    ))
+                }
+            },
+            GeomLen::LenRange(l, h) => unpad(rest, (*l, *h), padding, &mut lens)?,
+            GeomLen::Unbounded => (rest, rest.len()),
+        };
+        pieces.push(piece);
+        rest = &rest[len..];
+    }
+    if !rest.is_empty() {
+        return Err(format!(
+            "the read is {} bases longer than the simplified geometry",
+            rest.len()
+        ));
+    }
+    Ok(pieces)
+}
+
+/// Returns true if `pieces` are found, in order and without overlapping,
+/// in `read`, with at most `max_mismatches` mismatches each.
+fn found_in_order(pieces: &[&[u8]], read: &[u8], max_mismatches: u32) -> bool {
+    let mut from = 0;
+    for p in pieces {
+        let found = (from..=read.len().saturating_sub(p.len()))
+            .filter(|&i| i + p.len() <= read.len())
+            .find(|&i| {
+                read[i..i + p.len()]
+                    .iter()
+                    .zip(p.iter())
+                    .filter(|(a, b)| a != b)
+                    .count() as u32
+                    <= max_mismatches
+            });
+        match found {
+            Some(i) => from = i + p.len(),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Checks the transformed read `out` against the input read `input` (see
+/// the module documentation).
+fn check_read(
+    out: &[u8],
+    header: &[u8],
+    input: &[u8],
+    cginfo: &[GeomPiece],
+    padding: PaddingScheme,
+    max_mismatches: u32,
+    rc: &mut Vec<u8>,
+) -> std::result::Result<(), String> {
+    let pieces = cut_pieces(out, cginfo, padding, lens_tag(header))?;
+    if found_in_order(&pieces, input, max_mismatches) {
+        return Ok(());
+    }
+    reverse_complement_into(input, rc);
+    if found_in_order(&pieces, rc, max_mismatches) {
+        return Ok(());
+    }
+    Err(String::from(
+        "its captured pieces are not found, in order, in the input read",
+    ))
+}
+
+/// Checks the outputs `out1` and `out2` (either of which may be omitted if
+/// the geometry discards the whole read) of the transformation of the input
+/// files `r1` and `r2` with `geo_re` (see the module documentation).  Errors
+/// are returned for the inputs and outputs that cannot be read, or whose
+/// read 1 and read 2 records do not correspond, while the records failing
+/// verification are listed in the report.
+pub fn verify_transformation(
+    geo_re: &FragmentRegexDesc,
+    r1: &[PathBuf],
+    r2: &[PathBuf],
+    out1: Option<&Path>,
+    out2: Option<&Path>,
+    opts: &VerifyOptions,
+) -> Result<VerifyReport> {
+    if out1.is_none() && out2.is_none() {
+        bail!("at least one of the read 1 and read 2 outputs must be given");
+    }
+    let mut outputs = [
+        out1.map(|p| open_fastx(p, None, None, &opts.ora_decoder))
+            .transpose()?,
+        out2.map(|p| open_fastx(p, None, None, &opts.ora_decoder))
+            .transpose()?,
+    ];
+    let cginfo = [&geo_re.r1_cginfo, &geo_re.r2_cginfo];
+    let mut report = VerifyReport::default();
+    let mut pending = next_output(&mut outputs, 1)?;
+    let mut rc = vec![];
+    for (files1, files2) in input_groups(r1, r2, opts.input_format)? {
+        let mut batches = read_input_group(
+            files1,
+            files2,
+            None,
+            None,
+            OnError::Abort,
+            &opts.ora_decoder,
+        )?;
+        while let Some(batch) = batches.next_batch() {
+            let batch = batch?;
+            for frag in batch.iter() {
+                report.input_fragments += 1;
+                let Some(rec) = &pending else {
+                    continue;
+                };
+                if record_name(frag.ids[0]) != record_name(&rec.headers[0]) {
+                    continue;
+                }
+                report.output_records += 1;
+                let n = report.output_records;
+                for (i, seq) in rec.seqs.iter().enumerate() {
+                    let Some(seq) = seq else {
+                        continue;
+                    };
+                    let res = check_read(
+                        seq,
+                        &rec.headers[i],
+                        frag.seqs[i],
+                        cginfo[i],
+                        geo_re.padding(),
+                        opts.max_mismatches,
+                        &mut rc,
+                    );
+                    if let Err(reason) = res {
+                        report.fail(n, &rec.headers[i], format!("read {}: {}", i + 1, reason));
+                        break;
+                    }
+                }
+                pending = next_output(&mut outputs, n + 1)?;
+            }
+            batches.recycle(batch);
+        }
+    }
+    if let Some(rec) = pending {
+        report.output_records += 1;
+        let n = report.output_records;
+        report.fail(
+            n,
+            record_name(&rec.headers[0]),
+            String::from("no input fragment of this name follows the previous record"),
+        );
+        while next_output(&mut outputs, n + 1)?.is_some() {
+            report.output_records += 1;
+            report.failed_records += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, SeqPair, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+
+    fn geometry(padding: PaddingScheme) -> FragmentRegexDesc {
+        let mut geo_re = FragmentGeomDesc::try_from("1{b[4-5]f[CAGAGC]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        geo_re.set_padding(padding);
+        geo_re
+    }
+
+    #[test]
+    fn headers_are_parsed() {
+        assert_eq!(record_name(b"a x=1"), b"a");
+        assert_eq!(record_name(b"a\tx=1"), b"a");
+        assert_eq!(record_name(b"a"), b"a");
+        assert_eq!(lens_tag(b"a feature=x lens=4,12"), Some(vec![4, 12]));
+        assert_eq!(lens_tag(b"a lens=4,x"), None);
+        assert_eq!(lens_tag(b"a xlens=4"), None);
+        assert_eq!(lens_tag(b"a"), None);
+    }
+
+    #[test]
+    fn transformed_reads_are_cut_into_their_pieces() {
+        let mut sp = SeqPair::new();
+        let mut geo_re = geometry(PaddingScheme::Distinct);
+        let cginfo = geo_re.r1_cginfo.clone();
+        let cut =
+            |out: &[u8], padding, lens| cut_pieces(out, &cginfo, padding, lens).map(|p| p.concat());
+        for read in [&b"ACGTTCAGAGCGGA"[..], b"ACGTCAGAGCTTAC"] {
+            assert!(geo_re.parse_into(read, b"CCCC", &mut sp));
+            let pieces = cut(&sp.s1, PaddingScheme::Distinct, None).unwrap();
+            assert!(read.starts_with(&pieces[..pieces.len() - 2]));
+        }
+        // ACGT is padded as ACGTAC, with the code of its length last
+        assert_eq!(sp.s1, b"ACGTACTT");
+        let mut padded = sp.s1.clone();
+        padded[5] = LEN_CODE_DIGITS[2];
+        assert!(cut(&padded, PaddingScheme::Distinct, None)
+            .unwrap_err()
+            .contains("out of the range"));
+        padded[5] = b'N';
+        assert!(cut(&padded, PaddingScheme::Distinct, None)
+            .unwrap_err()
+            .contains("invalid length code"));
+        padded[4] = b'G';
+        padded[5] = LEN_CODE_DIGITS[1];
+        assert!(cut(&padded, PaddingScheme::Distinct, None)
+            .unwrap_err()
+            .contains("not made of As"));
+        assert!(cut(&sp.s1[..7], PaddingScheme::Distinct, None)
+            .unwrap_err()
+            .contains("shorter"));
+        assert!(cut(&sp.s1[..3], PaddingScheme::Distinct, None)
+            .unwrap_err()
+            .contains("shorter"));
+        let longer = [&sp.s1[..], b"A"].concat();
+        assert!(cut(&longer, PaddingScheme::Distinct, None)
+            .unwrap_err()
+            .contains("1 bases longer"));
+
+        // with the maximum length padding, the lengths are those of the tag
+        let mut geo_re = geometry(PaddingScheme::MaxLen);
+        assert!(geo_re.parse_into(b"ACGTCAGAGCTTAC", b"CCCC", &mut sp));
+        assert_eq!(sp.s1, b"ACGTATT");
+        let cut = |lens| cut(b"ACGTATT", PaddingScheme::MaxLen, lens);
+        assert_eq!(cut(Some(vec![4])).unwrap(), b"ACGTTT");
+        assert!(cut(Some(vec![3])).is_err());
+        assert!(cut(Some(vec![])).is_err());
+        assert!(cut(None).unwrap_err().contains("no lens= tag"));
+    }
+
+    #[test]
+    fn pieces_are_found_in_order() {
+        assert!(found_in_order(&[b"ACG", b"TT"], b"GACGATTC", 0));
+        assert!(!found_in_order(&[b"TT", b"ACG"], b"GACGATTC", 0));
+        // the pieces may not overlap
+        assert!(!found_in_order(&[b"ACG", b"GAT"], b"ACGAT", 0));
+        assert!(found_in_order(&[b"ACC", b"AA"], b"ACGAT", 1));
+        assert!(!found_in_order(&[b"ACGATT"], b"ACGAT", 1));
+        assert!(found_in_order(&[], b"", 0));
+
+        let mut rc = vec![];
+        let cginfo = geometry(PaddingScheme::MaxLen).r2_cginfo;
+        let check = |out: &[u8], input: &[u8], rc: &mut Vec<u8>| {
+            check_read(out, b"a", input, &cginfo, PaddingScheme::MaxLen, 0, rc)
+        };
+        assert!(check(b"ACCA", b"ACCA", &mut rc).is_ok());
+        // the reads matched in the reverse orientation
+        assert!(check(b"ACCA", b"TGGT", &mut rc).is_ok());
+        assert!(check(b"ACCA", b"TGGA", &mut rc).is_err());
+    }
+
+    #[test]
+    fn transformations_are_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = |name: &str| dir.path().join(name);
+        let opts = VerifyOptions::default();
+        for padding in [PaddingScheme::Distinct, PaddingScheme::MaxLen] {
+            let geo_re = geometry(padding);
+            xform_in_dir(
+                dir.path(),
+                geo_re.clone(),
+                ">a\nACGTTCAGAGCGGA\n>b\nNN\n>c\nACGTCAGAGCTTAC\n",
+                ">a\nCCCC\n>b\nTTTT\n>c\nGGGTT\n",
+                &XformOptions::default(),
+            )
+            .unwrap();
+            let verify = |o1: Option<&str>, o2: Option<&str>| {
+                verify_transformation(
+                    &geo_re,
+                    &[p("r1.fa")],
+                    &[p("r2.fa")],
+                    o1.map(p).as_deref(),
+                    o2.map(p).as_deref(),
+                    &opts,
+                )
+            };
+            let report = verify(Some("o1.fa"), Some("o2.fa")).unwrap();
+            assert!(report.passed(), "{:?}", report);
+            assert_eq!((report.input_fragments, report.output_records), (3, 2));
+            assert!(verify(None, Some("o2.fa")).unwrap().passed());
+            assert!(verify(None, None).is_err());
+
+            // a piece that is not in the input
+            let o1 = std::fs::read_to_string(p("o1.fa")).unwrap();
+            std::fs::write(p("bad.fa"), o1.replacen("GG", "CC", 1)).unwrap();
+            let report = verify(Some("bad.fa"), Some("o2.fa")).unwrap();
+            assert_eq!(report.failed_records, 1);
+            assert!(report.failures[0].reason.contains("not found"));
+            // nor can the read 1 and read 2 outputs differ
+            std::fs::write(p("short.fa"), ">a\nCCCC\n").unwrap();
+            assert!(verify(Some("o1.fa"), Some("short.fa")).is_err());
+        }
+
+        // records out of the order of the inputs
+        std::fs::write(p("o1.fa"), ">c\nACGTAC\n>a\nACGTTA\n").unwrap();
+        std::fs::write(p("o2.fa"), ">c\nGGGTT\n>a\nCCCC\n").unwrap();
+        let geo_re = FragmentGeomDesc::try_from("1{b[4]u[2]x:}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let report = verify_transformation(
+            &geo_re,
+            &[p("r1.fa")],
+            &[p("r2.fa")],
+            Some(&p("o1.fa")),
+            Some(&p("o2.fa")),
+            &opts,
+        )
+        .unwrap();
+        assert!(!report.passed());
+        assert_eq!((report.output_records, report.failed_records), (2, 1));
+        assert_eq!(
+            report.failures[0].to_string(),
+            "record 2 (a): no input fragment of this name follows the previous record"
+        );
+    }
+}
@@ -0,0 +1,1127 @@
+//! Correction of cell barcodes against a permit list (whitelist).
+//!
+//! When a [`Whitelist`] is given (through [`crate::XformOptions::whitelist`]),
+//! the barcodes of every transformed fragment are extracted (concatenated, and
+//! including their padding, exactly as they appear in the output) and looked
+//! up in the list.  A barcode found in the list is kept as is, a barcode at
+//! Hamming distance 1 from a single barcode of the list is rewritten to that
+//! barcode in the transformed reads, and the fragments whose barcode cannot be
+//! corrected (because it is further away from the list, or at distance 1 from
+//! several of its barcodes) fail to parse.  The outcomes are counted in
+//! [`crate::XformStats::whitelist`].  Other correction strategies can be
+//! chosen with [`crate::XformOptions::barcode_corrector`] (see the
+//! [`crate::correction`] module).
+//!
+//! Padding maps barcodes of different captured lengths into the same fixed
+//! length space, in which a barcode may end up at distance 1 from a padded
+//! barcode of another length.  With
+//! [`crate::XformOptions::length_aware_whitelist`], the barcodes are instead
+//! compared by their captured (unpadded) sequence, using the captured length
+//! of each variable length barcode piece, against a whitelist of the true
+//! barcodes, which may then have several lengths (e.g. the 9 and 10 base RT
+//! barcodes of sci-RNA-seq3): a barcode is only ever corrected to a barcode
+//! of the same length, and only its captured bases are rewritten, so that
+//! its padding still records its length.
+//!
+//! A whitelist of the true barcodes given without
+//! [`crate::XformOptions::length_aware_whitelist`] would match none of the
+//! padded barcodes.  Such a whitelist is detected before any fragment is
+//! read: if the barcode pieces include a single variable length piece, its
+//! barcodes are padded with the padding scheme of the geometry (so that
+//! they are compared with the padded barcodes as they appear in the
+//! output), and otherwise the barcodes are compared by their captured
+//! length, with a warning in both cases.
+//!
+//! The barcodes of a whitelist are held in a hash set, which takes several
+//! tens of bytes per barcode: too much for the combinatorial whitelists of
+//! hundreds of millions of barcodes.  [`Whitelist::from_path_on_disk`]
+//! instead keeps the barcodes, packed in 8 bytes each, sorted in a temporary
+//! file (built by sorting chunks of [`DiskWhitelistOptions::chunk_size`]
+//! barcodes and merging them), of which only every [`DISK_BLOCK_LEN`]-th
+//! barcode is held in memory.  Looking up a barcode reads a single block of
+//! the file, which the operating system caches as memory allows (correcting
+//! a barcode looks up each of its neighbors).  The
+//! barcodes of such a whitelist must all have the same length, of at most 32
+//! bases, and are only compared as they appear in the output (they cannot be
+//! padded, see above).
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use seq_geom_parser::{GeomLen, GeomPiece};
+
+use tracing::warn;
+
+use crate::correction::BarcodeCorrector;
+use crate::{extract_spans, FragmentRegexDesc, PieceSpan, SeqPair};
+
+/// The number of barcodes in each block of a whitelist kept on disk, of
+/// which only the first is held in memory.
+pub const DISK_BLOCK_LEN: usize = 512;
+
+/// A list of permitted barcodes, all of the same length unless built with
+/// [`Whitelist::new_variable_length`].
+#[derive(Debug, Clone)]
+pub struct Whitelist {
+    barcodes: Barcodes,
+    /// The distinct lengths of the barcodes, in increasing order.
+    lengths: Vec<usize>,
+}
+
+/// Where the barcodes of a [`Whitelist`] are kept.
+#[derive(Debug, Clone)]
+enum Barcodes {
+    Memory(HashSet<Vec<u8>>),
+    Disk(Arc<DiskBarcodes>),
+}
+
+/// Options for keeping the barcodes of a whitelist on disk (see the module
+/// documentation).
+#[derive(Debug, Clone)]
+pub struct DiskWhitelistOptions {
+    /// The number of barcodes sorted in memory at a time while the file of
+    /// the barcodes is built.
+    pub chunk_size: usize,
+    /// The directory in which the file of the barcodes is created, rather
+    /// than the default temporary directory.
+    pub tmp_dir: Option<PathBuf>,
+}
+
+impl DiskWhitelistOptions {
+    /// The default number of barcodes sorted in memory (256 MiB of them).
+    pub const DEFAULT_CHUNK_SIZE: usize = 1 << 25;
+}
+
+impl Default for DiskWhitelistOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: Self::DEFAULT_CHUNK_SIZE,
+            tmp_dir: None,
+        }
+    }
+}
+
+/// The outcome of looking up a barcode in a [`Whitelist`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BarcodeMatch {
+    /// The barcode is in the list.
+    Exact,
+    /// The barcode is at Hamming distance 1 from this barcode of the list,
+    /// and from no other.
+    Corrected(Vec<u8>),
+    /// The barcode is neither in the list nor at Hamming distance 1 from a
+    /// single barcode of the list.
+    Uncorrectable,
+}
+
+impl Whitelist {
+    /// Create a new `Whitelist` from its barcodes, which must be non-empty, of
+    /// the same length and consist only of `ACGT` (case-insensitive).
+    /// Repeated barcodes are only kept once.
+    pub fn new(barcodes: Vec<Vec<u8>>) -> Result<Self> {
+        Self::build(barcodes, false)
+    }
+
+    /// Create a new `Whitelist` from its barcodes, which must be non-empty
+    /// and consist only of `ACGT` (case-insensitive), but may have different
+    /// lengths.  Repeated barcodes are only kept once.
+    pub fn new_variable_length(barcodes: Vec<Vec<u8>>) -> Result<Self> {
+        Self::build(barcodes, true)
+    }
+
+    fn build(barcodes: Vec<Vec<u8>>, variable_length: bool) -> Result<Self> {
+        let Some(barcode_len) = barcodes.first().map(|bc| bc.len()) else {
+            bail!("the whitelist contains no barcodes");
+        };
+        let mut set = HashSet::with_capacity(barcodes.len());
+        let mut lengths = vec![];
+        for mut bc in barcodes {
+            check_barcode(&mut bc)?;
+            if bc.len() != barcode_len && !variable_length {
+                bail!(
+                    "the whitelist barcodes must all have the same length, but {} has length {} rather than {}",
+                    String::from_utf8_lossy(&bc),
+                    bc.len(),
+                    barcode_len
+                );
+            }
+            if let Err(i) = lengths.binary_search(&bc.len()) {
+                lengths.insert(i, bc.len());
+            }
+            set.insert(bc);
+        }
+        Ok(Self {
+            barcodes: Barcodes::Memory(set),
+            lengths,
+        })
+    }
+
+    /// Reads a whitelist from the file `p`, which is decompressed if its name
+    /// ends with `.gz` (as for the whitelists distributed with Cell Ranger).
+    /// See [`Whitelist::from_reader`] for the format.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        Self::from_reader(open_list(p)?)
+            .with_context(|| format!("could not parse whitelist {}", p.display()))
+    }
+
+    /// Reads a whitelist from the file `p` as [`Whitelist::from_path`] does,
+    /// but keeps its barcodes on disk rather than in memory (see the module
+    /// documentation), as configured by `opts`.
+    pub fn from_path_on_disk<P: AsRef<Path>>(p: P, opts: &DiskWhitelistOptions) -> Result<Self> {
+        let p = p.as_ref();
+        Self::from_reader_on_disk(open_list(p)?, opts)
+            .with_context(|| format!("could not parse whitelist {}", p.display()))
+    }
+
+    /// Reads a whitelist as [`Whitelist::from_reader`] does, but keeps its
+    /// barcodes on disk rather than in memory (see the module documentation),
+    /// as configured by `opts`.  The barcodes must all have the same length,
+    /// of at most 32 bases.
+    pub fn from_reader_on_disk<R: BufRead>(r: R, opts: &DiskWhitelistOptions) -> Result<Self> {
+        let mut builder = DiskBuilder::new(opts)?;
+        for_each_barcode(r, |bc| builder.push(bc))?;
+        let (barcodes, barcode_len) = builder.finish()?;
+        Ok(Self {
+            barcodes: Barcodes::Disk(Arc::new(barcodes)),
+            lengths: vec![barcode_len],
+        })
+    }
+
+    /// Whether the barcodes of the list are kept on disk (see
+    /// [`Whitelist::from_path_on_disk`]).
+    pub fn is_on_disk(&self) -> bool {
+        matches!(self.barcodes, Barcodes::Disk(_))
+    }
+
+    /// Reads a whitelist with one barcode per line.  Only the first (tab or
+    /// space separated) field of each line is read, so that, e.g., the
+    /// barcode translation tables of Cell Ranger can be read as well.  Empty
+    /// lines and lines starting with `#` are ignored.  The barcodes may have
+    /// different lengths (see [`Whitelist::new_variable_length`]).
+    pub fn from_reader<R: BufRead>(r: R) -> Result<Self> {
+        let mut barcodes = vec![];
+        for_each_barcode(r, |bc| {
+            barcodes.push(bc);
+            Ok(())
+        })?;
+        Self::new_variable_length(barcodes)
+    }
+
+    /// The number of (distinct) barcodes in the list.
+    pub fn len(&self) -> usize {
+        match &self.barcodes {
+            Barcodes::Memory(set) => set.len(),
+            Barcodes::Disk(d) => d.len,
+        }
+    }
+
+    /// Returns true if the list holds no barcode (which cannot happen for
+    /// a list built by [`Whitelist::new`]).
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The length of the barcodes of the list (the longest, if they have
+    /// several lengths).
+    pub fn barcode_len(&self) -> usize {
+        self.lengths.last().copied().unwrap_or(0)
+    }
+
+    /// The distinct lengths of the barcodes of the list, in increasing order.
+    pub fn lengths(&self) -> &[usize] {
+        &self.lengths
+    }
+
+    /// Looks up the barcode `bc` in the list, correcting it if it is at
+    /// Hamming distance 1 from a single barcode of the list.  A barcode
+    /// that is not in a list kept on disk is corrected by looking up each
+    /// of its `3 * bc.len()` neighbors, each reading a block of the file
+    /// (48 reads for a 16 base barcode, mostly served from the page cache).
+    /// Fails if the barcodes kept on disk cannot be read.
+    pub fn lookup(&self, bc: &[u8]) -> Result<BarcodeMatch> {
+        if self.contains(bc)? {
+            return Ok(BarcodeMatch::Exact);
+        }
+        let mut found: Option<Vec<u8>> = None;
+        let mut neighbor = bc.to_vec();
+        for i in 0..bc.len() {
+            for &b in b"ACGT" {
+                if b == bc[i] {
+                    continue;
+                }
+                neighbor[i] = b;
+                if self.contains(&neighbor)? {
+                    if found.is_some() {
+                        return Ok(BarcodeMatch::Uncorrectable);
+                    }
+                    found = Some(neighbor.clone());
+                }
+            }
+            neighbor[i] = bc[i];
+        }
+        Ok(found.map_or(BarcodeMatch::Uncorrectable, BarcodeMatch::Corrected))
+    }
+
+    /// Whether the barcode `bc` is in the list.  Fails if the barcodes kept
+    /// on disk cannot be read.
+    pub fn contains(&self, bc: &[u8]) -> Result<bool> {
+        Ok(match &self.barcodes {
+            Barcodes::Memory(set) => set.contains(bc),
+            Barcodes::Disk(d) => {
+                // packed barcodes of different lengths may be equal
+                match pack(bc).filter(|_| bc.len() == self.lengths[0]) {
+                    Some(key) => d
+                        .contains(key)
+                        .context("could not read the whitelist barcodes kept on disk")?,
+                    None => false,
+                }
+            }
+        })
+    }
+}
+
+/// Checks that `bc` is a valid whitelist barcode, making it uppercase.
+fn check_barcode(bc: &mut [u8]) -> Result<()> {
+    bc.make_ascii_uppercase();
+    if bc.is_empty() || !bc.iter().all(|c| b"ACGT".contains(c)) {
+        bail!(
+            "invalid whitelist barcode {:?}",
+            String::from_utf8_lossy(bc)
+        );
+    }
+    Ok(())
+}
+
+/// Opens the whitelist file `p`, decompressing it if its name ends with
+/// `.gz`.
+fn open_list(p: &Path) -> Result<BufReader<Box<dyn Read>>> {
+    let f = File::open(p).with_context(|| format!("could not open whitelist {}", p.display()))?;
+    let r: Box<dyn Read> = if p.extension().is_some_and(|e| e == "gz") {
+        Box::new(flate2::read::MultiGzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+    Ok(BufReader::new(r))
+}
+
+/// Calls `f` with the barcode of each line of the whitelist `r` (see
+/// [`Whitelist::from_reader`]).
+fn for_each_barcode<R: BufRead>(r: R, mut f: impl FnMut(Vec<u8>) -> Result<()>) -> Result<()> {
+    for l in r.lines() {
+        let l = l?;
+        if let Some(bc) = l.split_whitespace().next().filter(|f| !f.starts_with('#')) {
+            f(bc.as_bytes().to_vec())?;
+        }
+    }
+    Ok(())
+}
+
+/// Packs the barcode `bc` into 2 bits per base, or returns `None` if it is
+/// longer than 32 bases or holds other bases than `ACGT`.  Packed barcodes of
+/// the same length compare as the barcodes do.
+fn pack(bc: &[u8]) -> Option<u64> {
+    if bc.len() > 32 {
+        return None;
+    }
+    bc.iter().try_fold(0_u64, |key, c| {
+        let code = match c {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return None,
+        };
+        Some(key << 2 | code)
+    })
+}
+
+/// The packed barcodes of a whitelist kept on disk, sorted in a temporary
+/// file, along with the first barcode of each block of [`DISK_BLOCK_LEN`]
+/// barcodes of the file.
+#[derive(Debug)]
+struct DiskBarcodes {
+    file: File,
+    len: usize,
+    index: Vec<u64>,
+}
+
+impl DiskBarcodes {
+    fn contains(&self, key: u64) -> io::Result<bool> {
+        let block = match self.index.binary_search(&key) {
+            Ok(_) => return Ok(true),
+            Err(0) => return Ok(false),
+            Err(i) => i - 1,
+        };
+        let start = block * DISK_BLOCK_LEN;
+        let n = DISK_BLOCK_LEN.min(self.len - start);
+        let mut buf = [0_u8; DISK_BLOCK_LEN * 8];
+        read_exact_at(&self.file, &mut buf[..n * 8], start as u64 * 8)?;
+        let keys = buf[..n * 8]
+            .chunks_exact(8)
+            .map(|k| u64::from_le_bytes(k.try_into().unwrap()));
+        // the blocks are small, and a linear scan of a block is cheap next to
+        // the read
+        Ok(keys.take_while(|&k| k <= key).any(|k| k == key))
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(f, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(f: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(f, buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds the file of the barcodes of a whitelist kept on disk: the barcodes
+/// are sorted in chunks, each spilled to a temporary file, which are merged
+/// into the file of the barcodes.
+struct DiskBuilder {
+    opts: DiskWhitelistOptions,
+    chunk: Vec<u64>,
+    runs: Vec<File>,
+    barcode_len: Option<usize>,
+}
+
+impl DiskBuilder {
+    fn new(opts: &DiskWhitelistOptions) -> Result<Self> {
+        if opts.chunk_size == 0 {
+            bail!("the chunk size of the whitelist must be positive");
+        }
+        Ok(Self {
+            opts: opts.clone(),
+            chunk: vec![],
+            runs: vec![],
+            barcode_len: None,
+        })
+    }
+
+    fn tempfile(&self) -> io::Result<File> {
+        match &self.opts.tmp_dir {
+            Some(d) => tempfile::tempfile_in(d),
+            None => tempfile::tempfile(),
+        }
+    }
+
+    fn push(&mut self, mut bc: Vec<u8>) -> Result<()> {
+        check_barcode(&mut bc)?;
+        let len = *self.barcode_len.get_or_insert(bc.len());
+        if bc.len() != len {
+            bail!(
+                "the barcodes of a whitelist kept on disk must all have the same length, but {} has length {} rather than {}",
+                String::from_utf8_lossy(&bc),
+                bc.len(),
+                len
+            );
+        }
+        let Some(key) = pack(&bc) else {
+            bail!("the barcodes of a whitelist kept on disk must have at most 32 bases");
+        };
+        self.chunk.push(key);
+        if self.chunk.len() >= self.opts.chunk_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the current chunk, sorted, to a temporary file.
+    fn spill(&mut self) -> Result<()> {
+        self.chunk.sort_unstable();
+        self.chunk.dedup();
+        let mut w = BufWriter::new(
+            self.tempfile()
+                .context("could not create a temporary file for the whitelist")?,
+        );
+        for k in &self.chunk {
+            w.write_all(&k.to_le_bytes())?;
+        }
+        let mut f = w.into_inner().map_err(|e| e.into_error())?;
+        io::Seek::rewind(&mut f)?;
+        self.runs.push(f);
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Merges the chunks into the file of the barcodes.
+    fn finish(mut self) -> Result<(DiskBarcodes, usize)> {
+        let Some(barcode_len) = self.barcode_len else {
+            bail!("the whitelist contains no barcodes");
+        };
+        if !self.chunk.is_empty() {
+            self.spill()?;
+        }
+        let mut runs: Vec<BufReader<File>> = self.runs.drain(..).map(BufReader::new).collect();
+        let next = |r: &mut BufReader<File>| -> io::Result<Option<u64>> {
+            let mut k = [0_u8; 8];
+            match r.read_exact(&mut k) {
+                Ok(()) => Ok(Some(u64::from_le_bytes(k))),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (i, r) in runs.iter_mut().enumerate() {
+            if let Some(k) = next(r)? {
+                heap.push(Reverse((k, i)));
+            }
+        }
+        let mut w = BufWriter::new(
+            self.tempfile()
+                .context("could not create a temporary file for the whitelist")?,
+        );
+        let (mut len, mut index, mut last) = (0, vec![], None);
+        while let Some(Reverse((k, i))) = heap.pop() {
+            if let Some(k) = next(&mut runs[i])? {
+                heap.push(Reverse((k, i)));
+            }
+            // the same barcode may appear in several chunks
+            if last == Some(k) {
+                continue;
+            }
+            if len % DISK_BLOCK_LEN == 0 {
+                index.push(k);
+            }
+            w.write_all(&k.to_le_bytes())?;
+            len += 1;
+            last = Some(k);
+        }
+        let file = w.into_inner().map_err(|e| e.into_error())?;
+        Ok((DiskBarcodes { file, len, index }, barcode_len))
+    }
+}
+
+/// The number of transformed fragments by the outcome of looking up their
+/// barcode in the whitelist.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhitelistCounts {
+    /// Fragments whose barcode is in the whitelist.
+    pub exact: u64,
+    /// Fragments whose barcode was corrected to a barcode of the whitelist.
+    pub corrected: u64,
+    /// Fragments whose barcode could not be corrected, which are counted as
+    /// failing to parse.
+    pub failed: u64,
+}
+
+/// Extracts the (concatenated) barcodes of the fragments transformed by a
+/// given geometry, has them corrected against a whitelist, and rewrites the
+/// corrected barcodes in the fragments.
+#[derive(Debug)]
+pub(crate) struct BarcodeRewriter {
+    spans: Vec<PieceSpan>,
+    /// If the barcodes are compared by their captured length, the index of
+    /// each variable length barcode piece in the captured lengths of its
+    /// read.
+    ranged: Option<Vec<Option<usize>>>,
+    buf: Vec<u8>,
+    /// The captured spans of the barcode pieces of the current fragment.
+    captured: Vec<PieceSpan>,
+    /// The barcodes of the whitelist padded as the geometry pads the
+    /// barcodes, if the whitelist holds the captured barcodes (see
+    /// [`padded_whitelist`]).
+    padded: Option<Whitelist>,
+}
+
+impl BarcodeRewriter {
+    /// Create a new `BarcodeRewriter` for the fragments transformed by
+    /// `geo_re`, whose barcode pieces must lie at fixed offsets in the
+    /// transformed fragments, and add up to the length of the barcodes of
+    /// `whitelist`.  If `length_aware` is true, the barcodes are compared by
+    /// their captured sequence, which must be able to have the length of
+    /// some of the barcodes of `whitelist`.  Otherwise, if the barcodes of
+    /// `whitelist` have the lengths of the captured rather than of the
+    /// padded barcodes, they are padded if [`padded_whitelist`] can pad them,
+    /// or compared by their captured sequence.
+    pub(crate) fn new(
+        geo_re: &FragmentRegexDesc,
+        whitelist: &Whitelist,
+        length_aware: bool,
+    ) -> Result<Self> {
+        let spans = geo_re
+            .piece_spans(|gp| matches!(gp, GeomPiece::Barcode(_)))
+            .map_err(|gp| {
+                anyhow!(
+                    "correcting barcodes requires the barcode pieces of the geometry to be \
+                     of bounded length and preceded only by bounded pieces, but found {:?}",
+                    gp
+                )
+            })?;
+        let len: usize = spans.iter().map(|&(_, _, len)| len).sum();
+        if spans.is_empty() {
+            bail!("correcting barcodes requires the geometry to have a barcode (b) piece");
+        }
+        // the index of each variable length barcode piece among the variable
+        // length pieces of its read, and the range of the captured lengths
+        let mut ranged = vec![];
+        let (mut min_len, mut max_len) = (0, 0);
+        for pieces in [&geo_re.r1_cginfo, &geo_re.r2_cginfo] {
+            let mut i = 0;
+            for gp in pieces {
+                match gp {
+                    GeomPiece::Barcode(GeomLen::LenRange(l, h)) => {
+                        ranged.push(Some(i));
+                        (min_len, max_len) = (min_len + *l as usize, max_len + *h as usize);
+                    }
+                    GeomPiece::Barcode(GeomLen::FixedLen(x)) => {
+                        ranged.push(None);
+                        (min_len, max_len) = (min_len + *x as usize, max_len + *x as usize);
+                    }
+                    _ => {}
+                }
+                if let GeomPiece::Barcode(GeomLen::LenRange(..))
+                | GeomPiece::Umi(GeomLen::LenRange(..))
+                | GeomPiece::ReadSeq(GeomLen::LenRange(..)) = gp
+                {
+                    i += 1;
+                }
+            }
+        }
+        let (capturable, uncapturable): (Vec<usize>, Vec<usize>) = whitelist
+            .lengths()
+            .iter()
+            .partition(|l| (min_len..=max_len).contains(l));
+        if !length_aware {
+            if whitelist.lengths() == [len] {
+                return Ok(Self {
+                    spans,
+                    ranged: None,
+                    buf: Vec::with_capacity(len),
+                    captured: vec![],
+                    padded: None,
+                });
+            }
+            if uncapturable.is_empty() {
+                // the whitelist holds the barcodes as they are captured
+                if let Some(padded) = padded_whitelist(geo_re, whitelist) {
+                    warn!(
+                        "the whitelist barcodes have length {}, that of the captured rather than \
+                         of the padded barcodes ({}): padding them with the {} padding scheme of \
+                         the geometry",
+                        lengths_string(whitelist.lengths()),
+                        len,
+                        geo_re.padding()
+                    );
+                    return Ok(Self {
+                        spans,
+                        ranged: None,
+                        buf: Vec::with_capacity(len),
+                        captured: vec![],
+                        padded: Some(padded),
+                    });
+                }
+            }
+            if capturable.is_empty() {
+                bail!(
+                    "the transformed barcodes have length {}, but the whitelist barcodes have \
+                     length {}",
+                    len,
+                    lengths_string(whitelist.lengths())
+                );
+            }
+            warn!(
+                "the whitelist barcodes have length {}, that of the captured rather than of the \
+                 padded barcodes ({}): comparing the barcodes by their captured length",
+                lengths_string(whitelist.lengths()),
+                len
+            );
+        }
+        if capturable.is_empty() {
+            bail!(
+                "the captured barcodes have length {}-{}, but the whitelist barcodes have length {}",
+                min_len,
+                max_len,
+                lengths_string(whitelist.lengths())
+            );
+        }
+        if !uncapturable.is_empty() {
+            warn!(
+                "the captured barcodes have length {}-{}, so that the whitelist barcodes of \
+                 length {} can never be matched",
+                min_len,
+                max_len,
+                lengths_string(&uncapturable)
+            );
+        }
+        Ok(Self {
+            captured: Vec::with_capacity(spans.len()),
+            spans,
+            ranged: Some(ranged),
+            buf: Vec::with_capacity(max_len),
+            padded: None,
+        })
+    }
+
+    /// The read (`1` or `2`) holding the first barcode piece.
+    pub(crate) fn read(&self) -> usize {
+        self.spans[0].0 + 1
+    }
+
+    /// Has `corrector` look up the barcode of `frag`, whose variable length
+    /// pieces were captured with the lengths `lens`, in `whitelist`,
+    /// recording the outcome in `counts`.  Returns the fragment to write:
+    /// `frag` itself if its barcode is in the list, `corrected` (set to
+    /// `frag` with its barcode rewritten) if it was corrected, or `None` if
+    /// it cannot be corrected.
+    pub(crate) fn correct<'a>(
+        &mut self,
+        corrector: &mut dyn BarcodeCorrector,
+        whitelist: &Whitelist,
+        frag: &'a SeqPair,
+        lens: [&[u32]; 2],
+        corrected: &'a mut SeqPair,
+        counts: &mut WhitelistCounts,
+    ) -> Result<Option<&'a SeqPair>> {
+        self.captured.clear();
+        match &self.ranged {
+            // only the captured bases of the variable length pieces, which
+            // precede their padding, are compared
+            Some(ranged) => {
+                for (&(read, start, len), i) in self.spans.iter().zip(ranged) {
+                    let len = match i.and_then(|i| lens[read].get(i)) {
+                        Some(&l) => (l as usize).min(len),
+                        None => len,
+                    };
+                    self.captured.push((read, start, len));
+                }
+            }
+            None => self.captured.extend_from_slice(&self.spans),
+        }
+        extract_spans(frag, &self.captured, &mut self.buf);
+        let whitelist = self.padded.as_ref().unwrap_or(whitelist);
+        let new_bc = match corrector.correct(whitelist, &self.buf)? {
+            BarcodeMatch::Exact => {
+                counts.exact += 1;
+                return Ok(Some(frag));
+            }
+            BarcodeMatch::Uncorrectable => {
+                counts.failed += 1;
+                return Ok(None);
+            }
+            BarcodeMatch::Corrected(bc) => bc,
+        };
+        if new_bc.len() != self.buf.len() {
+            bail!(
+                "the barcode {} was corrected to {}, of another length",
+                String::from_utf8_lossy(&self.buf),
+                String::from_utf8_lossy(&new_bc)
+            );
+        }
+        counts.corrected += 1;
+        corrected.s1.clone_from(&frag.s1);
+        corrected.s2.clone_from(&frag.s2);
+        let mut pos = 0;
+        for &(read, start, len) in &self.captured {
+            let s = if read == 0 {
+                &mut corrected.s1
+            } else {
+                &mut corrected.s2
+            };
+            s[start..start + len].copy_from_slice(&new_bc[pos..pos + len]);
+            pos += len;
+        }
+        Ok(Some(corrected))
+    }
+}
+
+/// Returns the barcodes of `whitelist`, taken as the captured barcodes of
+/// the fragments transformed by `geo_re`, padded as `geo_re` pads them.
+/// Returns `None` if this is ambiguous, because the barcode pieces of
+/// `geo_re` include several variable length pieces (so that the captured
+/// length of each piece is not known), or impossible, because some barcode
+/// has a length the barcode pieces cannot capture.
+fn padded_whitelist(geo_re: &FragmentRegexDesc, whitelist: &Whitelist) -> Option<Whitelist> {
+    let pieces: Vec<&GeomLen> = geo_re
+        .r1_cginfo
+        .iter()
+        .chain(&geo_re.r2_cginfo)
+        .filter_map(|gp| match gp {
+            GeomPiece::Barcode(gl) => Some(gl),
+            _ => None,
+        })
+        .collect();
+    let mut ranged = pieces
+        .iter()
+        .filter(|gl| matches!(gl, GeomLen::LenRange(..)));
+    let (Some(_), None) = (ranged.next(), ranged.next()) else {
+        return None;
+    };
+    let fixed: usize = pieces
+        .iter()
+        .map(|gl| match gl {
+            GeomLen::FixedLen(x) => *x as usize,
+            _ => 0,
+        })
+        .sum();
+    // the barcodes kept on disk are not padded
+    let Barcodes::Memory(set) = &whitelist.barcodes else {
+        return None;
+    };
+    let mut barcodes = Vec::with_capacity(set.len());
+    let mut out = Vec::new();
+    for bc in set {
+        // the captured length of the variable length piece
+        let len = bc.len().checked_sub(fixed)?;
+        out.clear();
+        let mut pos = 0;
+        for gl in &pieces {
+            let (captured, range) = match gl {
+                GeomLen::FixedLen(x) => (*x as usize, None),
+                GeomLen::LenRange(l, h) => (len, Some((*l as usize, *h as usize))),
+                _ => return None,
+            };
+            out.extend_from_slice(bc.get(pos..pos + captured)?);
+            pos += captured;
+            if let Some((l, h)) = range {
+                if !geo_re.padding.push_padding(l, h, len, &mut out) {
+                    return None;
+                }
+            }
+        }
+        barcodes.push(out.clone());
+    }
+    Whitelist::new(barcodes).ok()
+}
+
+/// Formats the lengths `lengths` as e.g. `9` or `9, 10`.
+fn lengths_string(lengths: &[usize]) -> String {
+    lengths
+        .iter()
+        .map(|l| l.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::correction::OneMismatchCorrector;
+    use crate::test_utils::xform_in_dir;
+    use crate::{FragmentGeomDescExt, XformOptions};
+    use seq_geom_parser::FragmentGeomDesc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn barcodes_are_corrected_against_the_whitelist() {
+        let wl = Whitelist::from_reader(&b"# 10x\nAACC\nAAGG\tx\n\nTTTT\n"[..]).unwrap();
+        assert_eq!((wl.len(), wl.barcode_len()), (3, 4));
+        assert_eq!(wl.lookup(b"AACC").unwrap(), BarcodeMatch::Exact);
+        assert_eq!(
+            wl.lookup(b"ATCC").unwrap(),
+            BarcodeMatch::Corrected(b"AACC".to_vec())
+        );
+        assert_eq!(
+            wl.lookup(b"NTTT").unwrap(),
+            BarcodeMatch::Corrected(b"TTTT".to_vec())
+        );
+        // at distance 1 from both AACC and AAGG
+        assert_eq!(wl.lookup(b"AACG").unwrap(), BarcodeMatch::Uncorrectable);
+        assert_eq!(wl.lookup(b"GGGG").unwrap(), BarcodeMatch::Uncorrectable);
+        assert!(Whitelist::new(vec![b"AAC".to_vec(), b"AACC".to_vec()]).is_err());
+        assert!(Whitelist::from_reader(&b"AACC\nAAXC\n"[..]).is_err());
+        // barcodes are made uppercase
+        assert_eq!(
+            Whitelist::from_reader(&b"aacc\n"[..])
+                .unwrap()
+                .lookup(b"AACC")
+                .unwrap(),
+            BarcodeMatch::Exact
+        );
+    }
+
+    /// Has `rewriter` correct the barcode of the fragment `frag` against
+    /// `wl`, returning the fragment written, if any.
+    fn rewrite(
+        rewriter: &mut BarcodeRewriter,
+        wl: &Whitelist,
+        frag: [&[u8]; 2],
+        lens: [&[u32]; 2],
+        counts: &mut WhitelistCounts,
+    ) -> Option<SeqPair> {
+        let frag = SeqPair {
+            s1: frag[0].to_vec(),
+            s2: frag[1].to_vec(),
+        };
+        let mut corrected = SeqPair::new();
+        rewriter
+            .correct(
+                &mut OneMismatchCorrector,
+                wl,
+                &frag,
+                lens,
+                &mut corrected,
+                counts,
+            )
+            .unwrap()
+            .cloned()
+    }
+
+    #[test]
+    fn barcodes_are_rewritten_in_the_fragments() {
+        let wl = Whitelist::from_reader(&b"AACC\nAAGG\nTTTT\n"[..]).unwrap();
+        // the barcode pieces are split around an anchor, which is not
+        // transformed
+        let geo_re = FragmentGeomDesc::try_from("1{b[2]f[GT]b[2]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut rewriter = BarcodeRewriter::new(&geo_re, &wl, false).unwrap();
+        assert_eq!(rewriter.read(), 1);
+        let mut counts = WhitelistCounts::default();
+        let frag = rewrite(
+            &mut rewriter,
+            &wl,
+            [b"AACCTT", b"CA"],
+            [&[], &[]],
+            &mut counts,
+        );
+        assert_eq!(frag.unwrap().s1, b"AACCTT");
+        let frag = rewrite(
+            &mut rewriter,
+            &wl,
+            [b"ATCCGG", b"CC"],
+            [&[], &[]],
+            &mut counts,
+        );
+        let frag = frag.unwrap();
+        assert_eq!((&frag.s1[..], &frag.s2[..]), (&b"AACCGG"[..], &b"CC"[..]));
+        assert!(rewrite(
+            &mut rewriter,
+            &wl,
+            [b"AACGTT", b"CT"],
+            [&[], &[]],
+            &mut counts
+        )
+        .is_none());
+        assert_eq!(
+            counts,
+            WhitelistCounts {
+                exact: 1,
+                corrected: 1,
+                failed: 1
+            }
+        );
+
+        // barcodes split across both reads are concatenated
+        let geo_re = FragmentGeomDesc::try_from("1{u[2]b[2]}2{b[2]r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut rewriter = BarcodeRewriter::new(&geo_re, &wl, false).unwrap();
+        let frag = rewrite(
+            &mut rewriter,
+            &wl,
+            [b"GGTT", b"TAGCA"],
+            [&[], &[]],
+            &mut counts,
+        );
+        let frag = frag.unwrap();
+        assert_eq!((&frag.s1[..], &frag.s2[..]), (&b"GGTT"[..], &b"TTGCA"[..]));
+    }
+
+    #[test]
+    fn barcodes_must_be_found_where_the_whitelist_expects_them() {
+        let wl = Whitelist::from_reader(&b"AACC\n"[..]).unwrap();
+        let rewriter = |geom: &str| {
+            let geo_re = FragmentGeomDesc::try_from(geom)
+                .unwrap()
+                .as_regex()
+                .unwrap();
+            BarcodeRewriter::new(&geo_re, &wl, false)
+        };
+        rewriter("1{x[3]b[4]x:}2{r:}").unwrap();
+        // no barcode to correct
+        assert!(rewriter("1{u[4]x:}2{r:}").is_err());
+        // a barcode of no fixed length
+        assert!(rewriter("1{b:}2{r:}")
+            .unwrap_err()
+            .to_string()
+            .contains("bounded"));
+        // nor of the length of the whitelist barcodes
+        assert!(rewriter("1{b[6]}2{r:}").is_err());
+    }
+
+    #[test]
+    fn barcodes_are_corrected_while_transforming() {
+        let wl = Whitelist::from_reader(&b"AACC\nAAGG\nTTTT\n"[..]).unwrap();
+        let geo_re = FragmentGeomDesc::try_from("1{b[2]f[GT]b[2]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let opts = XformOptions {
+            whitelist: Some(wl),
+            ..XformOptions::default()
+        };
+        let out = xform_in_dir(
+            dir.path(),
+            geo_re,
+            ">a\nAAGTCCTT\n>b\nATGTCCTT\n>c\nGGGTGGTT\n>d\nAAGTCGTT\n",
+            ">a\nCA\n>b\nCC\n>c\nCG\n>d\nCT\n",
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(
+            out.stats.whitelist,
+            Some(WhitelistCounts {
+                exact: 1,
+                corrected: 1,
+                failed: 2
+            })
+        );
+        // the fragments whose barcode cannot be corrected fail to parse
+        assert_eq!(
+            (out.stats.total_fragments, out.stats.failed_parsing),
+            (4, 2)
+        );
+        assert_eq!(out.out1, ">a\nAACCTT\n>b\nAACCTT\n");
+    }
+
+    #[test]
+    fn barcodes_are_looked_up_on_disk() {
+        let mut list = String::new();
+        for i in 0..2000_u32 {
+            let bc: String = (0..8)
+                .map(|j| ['A', 'C', 'G', 'T'][(i >> (2 * j)) as usize & 3])
+                .collect();
+            list += &format!("{}\n", bc);
+        }
+        // a barcode repeated in another chunk
+        list += "CAAAAAAA\n";
+        let opts = DiskWhitelistOptions {
+            chunk_size: 300,
+            tmp_dir: None,
+        };
+        let wl = Whitelist::from_reader_on_disk(list.as_bytes(), &opts).unwrap();
+        assert!(wl.is_on_disk());
+        assert_eq!((wl.len(), wl.barcode_len()), (2000, 8));
+        let in_memory = Whitelist::from_reader(list.as_bytes()).unwrap();
+        for bc in [
+            &b"AAAAAAAA"[..],
+            b"TTTTTTTT",
+            b"GTAAAAAA",
+            b"GTACAAAT",
+            b"NAAAAAAA",
+            b"AAAA",
+        ] {
+            assert_eq!(wl.lookup(bc).unwrap(), in_memory.lookup(bc).unwrap());
+        }
+        assert_eq!(wl.lookup(b"AAAAAAAA").unwrap(), BarcodeMatch::Exact);
+        // the barcodes of the list all end with AA
+        assert_eq!(
+            wl.lookup(b"CGTAAAAG").unwrap(),
+            BarcodeMatch::Corrected(b"CGTAAAAA".to_vec())
+        );
+        assert!(Whitelist::from_reader_on_disk(&b"AAAA\nCCC\n"[..], &opts).is_err());
+    }
+
+    #[test]
+    fn variable_length_barcodes_are_corrected_by_their_captured_length() {
+        let wl = Whitelist::from_reader(&b"AAA\nGT\nCCG\n"[..]).unwrap();
+        assert_eq!(wl.lengths(), &[2, 3]);
+        assert_eq!(wl.barcode_len(), 3);
+        // barcodes are only corrected to barcodes of the same length
+        assert_eq!(wl.lookup(b"AA").unwrap(), BarcodeMatch::Uncorrectable);
+        assert_eq!(
+            wl.lookup(b"GA").unwrap(),
+            BarcodeMatch::Corrected(b"GT".to_vec())
+        );
+
+        // the whitelist barcodes are padded as the captured barcodes are, and
+        // the padding of AA (AAAC) is at distance 1 from that of AAA (AAAA)
+        let geo_re = FragmentGeomDesc::try_from("1{b[2-3]u[2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        let mut rewriter = BarcodeRewriter::new(&geo_re, &wl, false).unwrap();
+        let mut counts = WhitelistCounts::default();
+        let frag = rewrite(
+            &mut rewriter,
+            &wl,
+            [b"AAACTT", b"CG"],
+            [&[2], &[]],
+            &mut counts,
+        );
+        assert_eq!(frag.unwrap().s1, b"AAAATT");
+        // but AA is not corrected once compared by its captured length,
+        // while GA still is
+        let mut rewriter = BarcodeRewriter::new(&geo_re, &wl, true).unwrap();
+        let frag = rewrite(
+            &mut rewriter,
+            &wl,
+            [b"AAACTT", b"CG"],
+            [&[2], &[]],
+            &mut counts,
+        );
+        assert!(frag.is_none());
+        let frag = rewrite(
+            &mut rewriter,
+            &wl,
+            [b"GAACTT", b"CC"],
+            [&[2], &[]],
+            &mut counts,
+        );
+        assert_eq!(frag.unwrap().s1, b"GTACTT");
+        assert_eq!(
+            counts,
+            WhitelistCounts {
+                exact: 0,
+                corrected: 2,
+                failed: 1
+            }
+        );
+
+        // the barcodes of two variable length pieces are compared by their
+        // captured length
+        let geo2 = FragmentGeomDesc::try_from("1{b[1-2]b[1-2]}2{r:}")
+            .unwrap()
+            .as_regex()
+            .unwrap();
+        assert!(padded_whitelist(&geo2, &wl).is_none());
+        assert!(BarcodeRewriter::new(&geo2, &wl, false)
+            .unwrap()
+            .ranged
+            .is_some());
+        let short = Whitelist::from_reader(&b"A\n"[..]).unwrap();
+        assert!(BarcodeRewriter::new(&geo2, &short, false).is_err());
+
+        let dir = tempdir().unwrap();
+        let out = xform_in_dir(
+            dir.path(),
+            geo_re,
+            ">a\nAAATT\n>b\nGATT\n>c\nAATT\n>d\nCCATT\n",
+            ">a\nCA\n>b\nCC\n>c\nCG\n>d\nCT\n",
+            &XformOptions {
+                whitelist: Some(wl),
+                length_aware_whitelist: true,
+                ..XformOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            out.stats.whitelist,
+            Some(WhitelistCounts {
+                exact: 1,
+                corrected: 2,
+                failed: 1
+            })
+        );
+        assert_eq!(out.out1, ">a\nAAAATT\n>b\nGTACTT\n>d\nCCGATT\n");
+    }
+}